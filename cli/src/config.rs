@@ -10,22 +10,30 @@ use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoincore_rpc_async as rpc;
 
 use directories::BaseDirs;
-use emulator_connect::connections::federated::FederatedEmulatorConnection;
+use emulator_connect::connections::cache::CachedEmulator;
+use emulator_connect::connections::federated::{
+    FederatedEmulatorConfig, FederatedEmulatorConnection,
+};
 use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::connections::local::LocalEmulator;
+use emulator_connect::connections::transport::Transport;
 use emulator_connect::CTVEmulator;
+use sapio_psbt::external_signer::HwiConfig;
 use schemars::JsonSchema;
 use serde::*;
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
-use std::net::ToSocketAddrs;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use tokio::{io::BufReader, runtime::Handle};
 /// EmulatorConfig is used to determine how this sapio-cli instance should stub
 /// out CTV. Emulators are specified by EPK and interface address. Threshold
 /// should be <= emulators.len().
+///
+/// Alternatively, a `federation` of oracles known by their keys may be given,
+/// which is used in place of `emulators`, or for development a `dev_seed` or
+/// `dev_descriptor`.
 #[derive(Serialize, Deserialize, Debug, Clone, JsonSchema)]
 pub struct EmulatorConfig {
     /// if the emulator should be used or not. We tag explicitly for convenience
@@ -36,13 +44,69 @@ pub struct EmulatorConfig {
     pub emulators: Vec<(ExtendedPubKey, String)>,
     /// threshold could be larger than u8, but that seems very unlikely/an error.
     pub threshold: u8,
+    /// a federation to use instead of `emulators` and `threshold`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub federation: Option<FederatedEmulatorConfig>,
+    /// DEV ONLY: the hex seed of an in-process `LocalEmulator` to use instead
+    /// of any oracles, e.g. for regtest. Not permitted on mainnet.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_seed: Option<String>,
+    /// DEV ONLY: a descriptor with the extended private key of an in-process
+    /// `LocalEmulator`, e.g. one of a bitcoind wallet's from `listdescriptors
+    /// true`, used as `dev_seed` is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dev_descriptor: Option<String>,
+    /// how to connect to `emulators`, e.g. through Tor
+    #[serde(default)]
+    pub transport: Transport,
+    /// a file to cache the emulator's responses in across runs
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_file: Option<String>,
 }
 
 impl EmulatorConfig {
     /// Converts a config instance into an emulator trait object. Intenrally, we
     /// are using a Federated Emulator Connection if emulators.len() > 1, or a
-    /// bare HDOracleEmulatorConnection if emulators.len() == 1
-    pub fn get_emulator(&self) -> Result<Arc<dyn CTVEmulator>, Box<dyn std::error::Error>> {
+    /// bare HDOracleEmulatorConnection if emulators.len() == 1, wrapped in a
+    /// CachedEmulator if there is a `cache_file`.
+    pub async fn get_emulator(&self) -> Result<Arc<dyn CTVEmulator>, Box<dyn std::error::Error>> {
+        let emulator = self.get_uncached_emulator().await?;
+        Ok(match &self.cache_file {
+            Some(path) => {
+                // the oracles are identified by everything which configures them
+                let oracles = serde_json::to_vec(&(
+                    &self.emulators,
+                    self.threshold,
+                    &self.federation,
+                    &self.dev_seed,
+                    &self.dev_descriptor,
+                ))?;
+                let oracle_set = bitcoin::hashes::sha256::Hash::hash(&oracles).to_hex();
+                Arc::new(CachedEmulator::persistent(
+                    emulator,
+                    oracle_set,
+                    path.into(),
+                )?)
+            }
+            None => emulator,
+        })
+    }
+    async fn get_uncached_emulator(
+        &self,
+    ) -> Result<Arc<dyn CTVEmulator>, Box<dyn std::error::Error>> {
+        if let Some(seed) = &self.dev_seed {
+            let seed: Vec<u8> = FromHex::from_hex(seed)?;
+            return Ok(Arc::new(LocalEmulator::from_seed(
+                bitcoin::Network::Regtest,
+                &seed,
+            )?));
+        }
+        if let Some(descriptor) = &self.dev_descriptor {
+            return Ok(Arc::new(LocalEmulator::from_descriptor(descriptor)?));
+        }
+        if let Some(federation) = &self.federation {
+            return Ok(Arc::new(federation.connect()?));
+        }
         if self.emulators.len() < self.threshold as usize {
             Err(String::from("Too High Thresh"))?;
         } else if self.emulators.is_empty() {
@@ -53,31 +117,28 @@ impl EmulatorConfig {
             .err()
             .map(|_e| Arc::new(tokio::runtime::Runtime::new().unwrap()));
         let secp = Arc::new(bitcoin::secp256k1::Secp256k1::new());
-        let mut it =
-            self.emulators
-                .iter()
-                .map(|(epk, host)| -> Result<_, Box<dyn std::error::Error>> {
-                    let handle = Handle::try_current().unwrap_or_else(|_e| {
-                        rt.as_ref().expect("must have own runtime").handle().clone()
-                    });
-                    Ok(HDOracleEmulatorConnection {
-                        handle,
-                        runtime: rt.clone(),
-                        connection: Mutex::new(None),
-                        reconnect: host.to_socket_addrs()?.next().unwrap(),
-                        root: *epk,
-                        secp: secp.clone(),
-                    })
-                });
-        Ok(if self.emulators.len() == 1 {
-            Arc::new(it.next().unwrap()?)
+        let mut connections = vec![];
+        for (epk, host) in self.emulators.iter() {
+            // not resolved here, so that a proxy in `transport` does
+            connections.push(
+                HDOracleEmulatorConnection::new(
+                    host.clone(),
+                    *epk,
+                    rt.clone(),
+                    secp.clone(),
+                    self.transport.clone(),
+                )
+                .await?,
+            );
+        }
+        Ok(if connections.len() == 1 {
+            Arc::new(connections.pop().unwrap())
         } else {
             Arc::new(FederatedEmulatorConnection::new(
-                it.map(|n| -> Result<_, Box<dyn std::error::Error>> {
-                    let b: Arc<dyn CTVEmulator> = Arc::new(n?);
-                    Ok(b)
-                })
-                .collect::<Result<Vec<_>, _>>()?,
+                connections
+                    .into_iter()
+                    .map(|n| -> Arc<dyn CTVEmulator> { Arc::new(n) })
+                    .collect(),
                 self.threshold,
             ))
         })
@@ -156,6 +217,10 @@ pub struct NetworkConfig {
     /// mapping of name:module hash for translation during compilation
     #[serde(skip_serializing_if = "Option::is_none", default)]
     pub plugin_map: Option<BTreeMap<String, WasmerCacheHash>>,
+    /// hardware signers, by fingerprint and the derivation paths of their
+    /// keys, to request signatures from through HWI
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub hardware_signers: Option<HwiConfig>,
 }
 
 impl From<WasmerCacheHash> for [u8; 32] {
@@ -171,6 +236,7 @@ impl From<WasmerCacheHash> for [u8; 32] {
 pub struct WasmerCacheHash([u8; 32]);
 
 use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::hashes::Hash;
 impl From<WasmerCacheHash> for String {
     fn from(x: WasmerCacheHash) -> Self {
         ToHex::to_hex(&x.0[..])
@@ -319,7 +385,15 @@ impl ConfigVerifier {
     pub fn check(self) -> Result<NetworkConfig, ConfigError> {
         match self.get_n() {
             1 => Err(ConfigError::NoActiveConfig),
-            3 => Ok(self.main.unwrap()),
+            3 => {
+                let main = self.main.unwrap();
+                match &main.emulator_nodes {
+                    Some(e) if e.dev_seed.is_some() || e.dev_descriptor.is_some() => {
+                        Err(ConfigError::DevEmulatorOnMainnet)
+                    }
+                    _ => Ok(main),
+                }
+            }
             5 => Ok(self.signet.unwrap()),
             7 => Ok(self.regtest.unwrap()),
             11 => Ok(self.testnet.unwrap()),
@@ -439,8 +513,14 @@ impl ConfigVerifier {
                 threshold: 1u8,
                 emulators: vec![(ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4Wf398td3H8YhWBsXx9Sxa4W3cQWkNW3N3DHSNB2qtPoUMXrA6JNaPxodQfRpoZNE5tGM9iZ4xfUEFRJEJvfs8W5paUagYCE").unwrap(),
                     "example.please.change.this.before.using:8367".into())],
+                federation: None,
+                dev_seed: None,
+                dev_descriptor: None,
+                transport: Default::default(),
+                cache_file: None,
             }),
             plugin_map: None,
+            hardware_signers: None,
         };
         let cv: ConfigVerifier = Config { network, active }.into();
         println!(
@@ -459,6 +539,9 @@ pub enum ConfigError {
     TooManyActiveNetworks,
     /// One network must be active
     NoActiveConfig,
+    /// `EmulatorConfig::dev_seed` and `dev_descriptor` are only for test
+    /// networks
+    DevEmulatorOnMainnet,
 }
 use std::fmt;
 impl fmt::Display for ConfigError {
@@ -486,8 +569,14 @@ impl std::default::Default for ConfigVerifier {
                 threshold: 1u8,
                 emulators: vec![(ExtendedPubKey::from_str("tpubD6NzVbkrYhZ4Wf398td3H8YhWBsXx9Sxa4W3cQWkNW3N3DHSNB2qtPoUMXrA6JNaPxodQfRpoZNE5tGM9iZ4xfUEFRJEJvfs8W5paUagYCE").unwrap(),
                     "ctv.d31373.org:8367".into())],
+                federation: None,
+                dev_seed: None,
+                dev_descriptor: None,
+                transport: Default::default(),
+                cache_file: None,
             }),
             plugin_map: None,
+            hardware_signers: None,
         };
         ConfigVerifier {
             main: None,
@@ -8,19 +8,15 @@ use bitcoincore_rpc_async as rpc;
 use bitcoincore_rpc_async::RpcApi;
 use emulator_connect::{CTVAvailable, CTVEmulator};
 use sapio::{
-    contract::{
-        object::{LinkedPSBT, ObjectMetadata, Program, SapioStudioObject},
-        Compiled,
-    },
-    template::{OutputMeta, TemplateMetadata},
+    contract::{object::Program, Compiled},
     util::extended_address::ExtendedAddress,
     Context,
 };
 use sapio_base::{
     effects::{MapEffectDB, PathFragment},
-    serialization_helpers::SArc,
     txindex::{TxIndex, TxIndexLogger},
 };
+use sapio_tools::fund::{add_funding, bind_with_rpc};
 use sapio_wasm_plugin::{
     host::{plugin_handle::ModuleLocator, PluginHandle, WasmPluginHandle},
     CreateArgs, API,
@@ -58,6 +54,15 @@ pub struct ListReturn {
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct Call {
     pub params: serde_json::Value,
+    /// fail if the compiled contract has any warning diagnostics
+    #[serde(default)]
+    pub deny_warnings: bool,
+    /// return the compiled contract with each distinct object stored once
+    #[serde(default)]
+    pub dedup: bool,
+    /// profile the compilation, printing this many of the slowest contracts
+    #[serde(default)]
+    pub profile: Option<usize>,
 }
 #[derive(Serialize, Deserialize, JsonSchema)]
 pub struct CallReturn {
@@ -72,6 +77,9 @@ pub struct Bind {
     pub use_mock: bool,
     pub outpoint: Option<OutPoint>,
     pub use_txn: Option<String>,
+    /// find or fund the coin with the node's wallet, see `bind_with_rpc`
+    #[serde(default)]
+    pub use_rpc: bool,
     pub compiled: Compiled,
 }
 pub type BindReturn = Program;
@@ -149,7 +157,7 @@ impl Request {
     async fn get_emulator(&self) -> ResultT<Arc<dyn CTVEmulator>> {
         let emulator: Arc<dyn CTVEmulator> = if let Some(emcfg) = &self.context.emulator {
             if emcfg.enabled {
-                emcfg.get_emulator()?
+                emcfg.get_emulator().await?
             } else {
                 Arc::new(CTVAvailable)
             }
@@ -215,9 +223,29 @@ impl Request {
                     let v: Vec<_> = it.map(|e| e.to_string()).collect();
                     Err(RequestError(v.into()))?;
                 }
-                let create_args: CreateArgs<serde_json::Value> = serde_json::from_value(params)?;
+                let mut create_args: CreateArgs<serde_json::Value> =
+                    serde_json::from_value(params)?;
+                create_args.context.profile |= call.profile.is_some();
                 let v = sph.call(&PathFragment::Root.into(), &create_args)?;
-                Ok(CommandReturn::Call(CallReturn { result: v }))
+                let compiled: Compiled = serde_json::from_value(v.clone())?;
+                let diagnostics = compiled.all_diagnostics();
+                for d in diagnostics.iter() {
+                    eprintln!("{}", d);
+                }
+                if let (Some(n), Some(profile)) = (call.profile, compiled.profile.as_ref()) {
+                    eprint!("{}", profile.render(n));
+                }
+                if call.deny_warnings {
+                    if let Err(e) = compiled.deny_warnings() {
+                        Err(RequestError(serde_json::to_value(&e)?))?;
+                    }
+                }
+                let result = if call.dedup {
+                    serde_json::to_value(compiled.dedup()?)?
+                } else {
+                    v
+                };
+                Ok(CommandReturn::Call(CallReturn { result }))
             }
             Command::Bind(bind) => Ok(CommandReturn::Bind(bind.call(net, emulator).await?)),
             Command::Api(_api) => {
@@ -269,6 +297,7 @@ impl Bind {
             use_base64: _,
             use_mock,
             use_txn,
+            use_rpc,
             compiled,
             outpoint,
         } = self;
@@ -278,6 +307,11 @@ impl Bind {
             .map(|b| PartiallySignedTransaction::consensus_decode(&b[..]))
             .transpose()?;
         let client = rpc::Client::new(client_url, client_auth).await?;
+        if use_rpc {
+            return Ok(bind_with_rpc(&client, &compiled, net, emulator.as_ref())
+                .await?
+                .program);
+        }
         let (tx, vout) = if use_mock {
             let ctx = Context::new(
                 net,
@@ -341,30 +375,7 @@ impl Bind {
             emulator.as_ref(),
         )?;
         if outpoint.is_none() {
-            let added_output_metadata = vec![OutputMeta::default(); tx.output.len()];
-            let output_metadata = vec![ObjectMetadata::default(); tx.output.len()];
-            let out = tx.input[0].previous_output;
-            let psbt = PartiallySignedTransaction::from_unsigned_tx(tx)?;
-            bound.program.insert(
-                SArc(Arc::new("funding".try_into()?)),
-                SapioStudioObject {
-                    metadata: Default::default(),
-                    out,
-                    continue_apis: Default::default(),
-                    txs: vec![LinkedPSBT {
-                        psbt,
-                        metadata: TemplateMetadata {
-                            label: Some("funding".into()),
-                            color: Some("pink".into()),
-                            extra: BTreeMap::new(),
-                            simp: Default::default(),
-                        },
-                        output_metadata,
-                        added_output_metadata,
-                    }
-                    .into()],
-                },
-            );
+            add_funding(&mut bound, tx)?;
         }
         Ok(bound)
     }
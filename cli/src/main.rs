@@ -29,13 +29,16 @@ use clap::clap_app;
 use clap::ArgMatches;
 use config::*;
 use emulator_connect::servers::hd::HDOracleEmulator;
+use emulator_connect::servers::log::AuditLog;
 use emulator_connect::CTVAvailable;
 use emulator_connect::CTVEmulator;
 use sapio::contract::Compiled;
 use sapio_base::util::CTVHash;
+use sapio_psbt::external_signer::{ExternalSigner, SpendPath};
 use sapio_wasm_plugin::host::plugin_handle::ModuleLocator;
 use schemars::schema_for;
 use serde_json::Deserializer;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::str::FromStr;
 use std::sync::Arc;
@@ -126,6 +129,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
      (@subcommand server =>
       (about: "run an emulation server")
       (@arg sync: --sync  "Run in Synchronous mode")
+      (@arg audit_log: --audit_log +takes_value "A file to append a log of everything signed to, which clients may audit")
+      (@arg audit_http: --audit_http +takes_value requires[audit_log] "An interface to serve the audit log over HTTP on, at /audit-log?start=N&end=N")
       (@arg seed: +takes_value +required {check_file} "The file containing the Seed")
       (@arg interface: +required +takes_value "The Interface to Bind")
      )
@@ -136,6 +141,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
       (@subcommand finalize =>
        (about: "finalize and extract this psbt to transaction hex")
        (@arg psbt: --psbt +takes_value "psbt as base64, otherwise read from stdin")
+       (@arg hwi: --hwi "First request the signatures it needs from the configured hardware signers")
+       (@arg spend: --spend +takes_value ... requires[hwi] "How to spend an input the hardware signers sign, as <input>:key or <input>:<leaf hash>")
       )
      )
      (@subcommand contract =>
@@ -148,9 +155,32 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             (@arg outpoint: --outpoint +takes_value "Use this specific outpoint")
             (@arg txn: --txn +takes_value "Use this specific transaction ")
             (@arg mock: --mock "Create a fake output for this txn.")
+            (@arg rpc: --rpc "Find or fund an output with the node's wallet, checking it pays the contract")
        )
        (@arg json: "JSON to Bind")
       )
+      (@subcommand graph =>
+       (about: "Draw a compiled contract's transaction tree as a Graphviz DOT graph")
+       (@arg mermaid: --mermaid "Draw a Mermaid flowchart instead")
+       (@arg json: "JSON of the compiled contract, otherwise read from stdin")
+      )
+      (@subcommand diff =>
+       (about: "Show what changed between two compilations of a contract")
+       (@arg json: --json "Print the changes as JSON")
+       (@arg no_color: --("no-color") "Don't color the changes")
+       (@arg old: +required {check_file} "The file containing the old compiled contract's JSON")
+       (@arg new: +required {check_file} "The file containing the new compiled contract's JSON")
+      )
+      (@subcommand keys =>
+       (about: "List every key which can spend each node of a compiled contract")
+       (@arg json: --json "Print the report as JSON")
+       (@arg file: +required {check_file} "The file containing the compiled contract's JSON")
+      )
+      (@subcommand liquidity =>
+       (about: "Show the least and most each node of a compiled contract can hold, flagging dust")
+       (@arg json: --json "Print the report as JSON")
+       (@arg file: +required {check_file} "The file containing the compiled contract's JSON")
+      )
       (@subcommand create =>
        (about: "create a contract to a specific UTXO")
        (@arg workspace: -w --workspace +takes_value "Where to search for the cache / copy the contract file")
@@ -159,6 +189,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         (@arg key:  -k --key +takes_value "Which Contract to Create, given a WASM Hash")
        )
        (@arg json: "JSON of args")
+       (@arg deny_warnings: --("deny-warnings") "Fail if compiling the contract produces any warning")
+       (@arg dedup: --dedup "Store each distinct object in the compiled contract once")
+       (@arg profile: --profile +takes_value {check_count} "Profile the compilation, printing the given number of the slowest contracts")
       )
       (@subcommand load =>
        (about: "Load a wasm contract module, returns the hex sha3 hash key")
@@ -292,7 +325,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             let emulator: Arc<dyn CTVEmulator> = if let Some(emcfg) = &config.active.emulator_nodes
             {
                 if emcfg.enabled {
-                    emcfg.get_emulator()?
+                    emcfg.get_emulator().await?
                 } else {
                     Arc::new(CTVAvailable)
                 }
@@ -333,12 +366,24 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     let pk_root = ExtendedPubKey::from_priv(&Secp256k1::new(), &root);
                     let sync_mode = args.is_present("sync");
                     let oracle = HDOracleEmulator::new(root, sync_mode);
+                    let audit_log = args.value_of("audit_log");
+                    let oracle = match audit_log {
+                        Some(path) => oracle.with_audit_log(Arc::new(AuditLog::open(path.into())?)),
+                        None => oracle,
+                    };
+                    let audit_http = args.value_of("audit_http");
+                    if let Some(http) = audit_http {
+                        let listener = tokio::net::TcpListener::bind(http).await?;
+                        tokio::spawn(oracle.clone().listen_audit_log(listener));
+                    }
                     let interface = args.value_of("interface").unwrap();
                     let server = oracle.bind(interface);
                     let status = serde_json::json! {{
                         "interface": interface,
                         "pk": pk_root,
                         "sync": sync_mode,
+                        "audit_log": audit_log,
+                        "audit_http": audit_http,
                     }};
                     println!("{}", serde_json::to_string_pretty(&status).unwrap());
                     server.await?;
@@ -350,7 +395,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             Some(("finalize", args)) => {
                 let psbt_str = args.value_of("psbt");
 
-                let psbt = get_psbt_from(psbt_str).await?;
+                let mut psbt = get_psbt_from(psbt_str).await?;
+                if args.is_present("hwi") {
+                    let config = config(custom_config).await?;
+                    let devices = config
+                        .active
+                        .hardware_signers
+                        .as_ref()
+                        .ok_or("No hardware_signers are configured")?
+                        .signers(config.network);
+                    let signers: Vec<&dyn ExternalSigner> =
+                        devices.iter().map(|d| d as &dyn ExternalSigner).collect();
+                    let mut paths = BTreeMap::new();
+                    for spend in args.values_of("spend").into_iter().flatten() {
+                        let (input, path) = spend
+                            .split_once(':')
+                            .ok_or("--spend must be <input>:key or <input>:<leaf hash>")?;
+                        paths.insert(input.parse::<usize>()?, path.parse::<SpendPath>()?);
+                    }
+                    psbt = sapio_psbt::external_signer::request_signatures(psbt, &signers, &paths)?;
+                }
                 let js = sapio_psbt::external_api::finalize_psbt_format_api(psbt);
                 println!("{}", serde_json::to_string_pretty(&js)?);
             }
@@ -374,6 +438,56 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             _ => unreachable!(),
         },
         Some(("contract", matches)) => {
+            if let Some(("graph", args)) = matches.subcommand() {
+                let compiled: Compiled = if let Some(json) = args.value_of("json") {
+                    serde_json::from_str(json)?
+                } else {
+                    let mut s = String::new();
+                    tokio::io::stdin().read_to_string(&mut s).await?;
+                    serde_json::from_str(&s)?
+                };
+                if args.is_present("mermaid") {
+                    print!("{}", compiled.to_mermaid());
+                } else {
+                    print!("{}", compiled.to_dot());
+                }
+                return Ok(());
+            }
+            if let Some(("diff", args)) = matches.subcommand() {
+                let read = |arg: &str| -> Result<Compiled, Box<dyn std::error::Error>> {
+                    let s = std::fs::read_to_string(args.value_of_os(arg).unwrap())?;
+                    Ok(serde_json::from_str(&s)?)
+                };
+                let d = sapio::analysis::diff(&read("old")?, &read("new")?);
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&d)?);
+                } else {
+                    print!("{}", d.render(!args.is_present("no_color")));
+                }
+                return Ok(());
+            }
+            if let Some(("keys", args)) = matches.subcommand() {
+                let s = std::fs::read_to_string(args.value_of_os("file").unwrap())?;
+                let compiled: Compiled = serde_json::from_str(&s)?;
+                let report = compiled.key_report();
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print!("{}", report.render());
+                }
+                return Ok(());
+            }
+            if let Some(("liquidity", args)) = matches.subcommand() {
+                let s = std::fs::read_to_string(args.value_of_os("file").unwrap())?;
+                let compiled: Compiled = serde_json::from_str(&s)?;
+                let report = compiled.liquidity_report();
+                if args.is_present("json") {
+                    println!("{}", serde_json::to_string_pretty(&report)?);
+                } else {
+                    print!("{}", report.render());
+                }
+                return Ok(());
+            }
             let config = config(custom_config).await?;
             let module_path = |args: &clap::ArgMatches| {
                 let mut p = args
@@ -434,7 +548,12 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                     };
                     Request {
                         context: context(args)?,
-                        command: Command::Call(Call { params }),
+                        command: Command::Call(Call {
+                            params,
+                            deny_warnings: args.is_present("deny_warnings"),
+                            dedup: args.is_present("dedup"),
+                            profile: args.value_of("profile").map(str::parse).transpose()?,
+                        }),
                     }
                 }
                 Some(("api", args)) => Request {
@@ -505,6 +624,7 @@ async fn bind_command(
         .map(serde_json::from_str)
         .transpose()?;
     let use_txn = args.value_of("txn").map(String::from);
+    let use_rpc = args.is_present("rpc");
     let compiled: Compiled = if let Some(json) = args.value_of("json") {
         serde_json::from_str(json)?
     } else {
@@ -519,6 +639,7 @@ async fn bind_command(
         use_mock,
         outpoint,
         use_txn,
+        use_rpc,
         compiled,
     }))
 }
@@ -26,6 +26,12 @@ pub fn check_file_not(p: &str) -> Result<(), String> {
     }
     Ok(())
 }
+/// Checks that an argument is a count during argument parsing
+pub fn check_count(n: &str) -> Result<(), String> {
+    n.parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| String::from("Expected a count"))
+}
 
 /// Reads a PSBT from a file and checks that it is correctly formatted
 pub fn decode_psbt_file(
@@ -0,0 +1,331 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Auditing an oracle's log of what it signed, see `servers::log`
+
+use crate::servers::log::{genesis, AuditRange, LogEntry};
+use crate::SECP;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::{OutPoint, XOnlyPublicKey};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+/// Why an oracle's log failed an audit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditError {
+    /// the range doesn't start where the checkpoint ends, or is missing
+    /// entries up to the log's length
+    Incomplete,
+    /// the entry at this index doesn't follow on from the entries before it,
+    /// e.g. because the log was rewritten since the checkpoint
+    Forked(u64),
+    /// the range's entries don't end at the head it claims
+    WrongHead,
+    /// the head isn't signed by the oracle's key
+    BadSignature,
+    /// the oracle signed two different templates spending the same coin
+    DoubleSigned {
+        /// the coin both templates spend
+        outpoint: OutPoint,
+        /// the index of the first entry
+        first: u64,
+        /// the index of the conflicting entry
+        second: u64,
+    },
+}
+
+impl std::fmt::Display for AuditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for AuditError {}
+
+/// How much of an oracle's log a client has verified, and the hash it ended
+/// at. Clients keep their latest checkpoint, so that a log rewritten behind
+/// it is detected the next time they extend it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditCheckpoint {
+    /// how many entries have been verified
+    pub length: u64,
+    /// the hash of the last of them
+    pub head: Sha256,
+}
+
+impl Default for AuditCheckpoint {
+    fn default() -> Self {
+        AuditCheckpoint {
+            length: 0,
+            head: genesis(),
+        }
+    }
+}
+
+impl AuditCheckpoint {
+    /// Checks that `range`, fetched from this checkpoint's length to the end
+    /// of the log, extends the log this checkpoint verified, serving as the
+    /// proof that the log is consistent with it, and that its head is signed
+    /// by `oracle`, the key the oracle is known by. Returns the checkpoint at
+    /// the end of the range.
+    pub fn extend(
+        &self,
+        range: &AuditRange,
+        oracle: &XOnlyPublicKey,
+    ) -> Result<AuditCheckpoint, AuditError> {
+        let digest = AuditRange::digest(range.length, range.head);
+        SECP.with(|secp| secp.verify_schnorr(&range.signature, &digest, oracle))
+            .map_err(|_| AuditError::BadSignature)?;
+        if range.start != self.length || range.start + range.entries.len() as u64 != range.length {
+            return Err(AuditError::Incomplete);
+        }
+        let mut head = self.head;
+        for (entry, index) in range.entries.iter().zip(range.start..) {
+            if entry.index != index || entry.previous != head {
+                return Err(AuditError::Forked(index));
+            }
+            head = entry.hash();
+        }
+        if head != range.head {
+            return Err(AuditError::WrongHead);
+        }
+        Ok(AuditCheckpoint {
+            length: range.length,
+            head,
+        })
+    }
+}
+
+/// Checks that no two of `entries` sign different templates spending the
+/// same coin
+pub fn check_double_signing<'a>(
+    entries: impl IntoIterator<Item = &'a LogEntry>,
+) -> Result<(), AuditError> {
+    let mut seen: BTreeMap<OutPoint, &LogEntry> = BTreeMap::new();
+    for entry in entries {
+        match seen.get(&entry.outpoint) {
+            Some(first) if first.template_hash != entry.template_hash => {
+                return Err(AuditError::DoubleSigned {
+                    outpoint: entry.outpoint,
+                    first: first.index,
+                    second: entry.index,
+                })
+            }
+            Some(_) => {}
+            None => {
+                seen.insert(entry.outpoint, entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetches the range of the audit log from `start` up to `end` served at
+/// `address`, a `host:port`, by `servers::log::serve_http`
+pub fn fetch_http(address: &str, start: u64, end: u64) -> Result<AuditRange, std::io::Error> {
+    let mut t = std::net::TcpStream::connect(address)?;
+    write!(
+        t,
+        "GET /audit-log?start={}&end={} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        start, end, address
+    )?;
+    let mut response = vec![];
+    t.read_to_end(&mut response)?;
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| crate::input_err("Malformed HTTP Response"))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    if !head.starts_with("HTTP/1.1 200") {
+        return Err(crate::input_err(&format!(
+            "Audit Log Request Failed: {}",
+            head.lines().next().unwrap_or_default()
+        )));
+    }
+    Ok(serde_json::from_slice(&response[split + 4..])?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connections::key::KeyOracleEmulatorConnection;
+    use crate::servers::key::KeyOracleEmulator;
+    use crate::servers::log::AuditLog;
+    use crate::*;
+    use bitcoin::{KeyPair, Script, Transaction, TxIn, TxOut};
+
+    /// an unsigned PSBT spending `outpoint` to an output of `value`
+    fn spending(outpoint: OutPoint, value: u64) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        b.inputs[0].witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: Script::new(),
+        });
+        b
+    }
+
+    fn coin(vout: u32) -> OutPoint {
+        OutPoint {
+            vout,
+            ..Default::default()
+        }
+    }
+
+    /// the key the oracle of these tests is known by
+    fn oracle() -> KeyPair {
+        SECP.with(|secp| KeyPair::from_seckey_slice(secp, &[7; 32]).unwrap())
+    }
+
+    #[test]
+    fn forked_log() {
+        let key = oracle();
+        let pk = key.x_only_public_key().0;
+        let log = AuditLog::new();
+        log.append("sign_psbt", &spending(coin(0), 1), &spending(coin(0), 1))
+            .unwrap();
+        let checkpoint = AuditCheckpoint::default()
+            .extend(&log.range(0, 10, &key), &pk)
+            .unwrap();
+        assert_eq!(checkpoint.length, 1);
+        log.append("sign_psbt", &spending(coin(1), 1), &spending(coin(1), 1))
+            .unwrap();
+        let extended = checkpoint.extend(&log.range(1, 10, &key), &pk).unwrap();
+        assert_eq!(extended.length, 2);
+        assert!(check_double_signing(&log.range(0, 10, &key).entries).is_ok());
+        // a range which stops short proves nothing about the head
+        assert_eq!(
+            AuditCheckpoint::default().extend(&log.range(0, 1, &key), &pk),
+            Err(AuditError::Incomplete)
+        );
+        // nor does a head the oracle didn't sign
+        let other = SECP.with(|secp| KeyPair::from_seckey_slice(secp, &[8; 32]).unwrap());
+        assert_eq!(
+            AuditCheckpoint::default().extend(&log.range(0, 10, &other), &pk),
+            Err(AuditError::BadSignature)
+        );
+        let mut moved = log.range(0, 10, &key);
+        moved.head = moved.entries[0].hash();
+        moved.entries.truncate(1);
+        moved.length = 1;
+        assert_eq!(
+            AuditCheckpoint::default().extend(&moved, &pk),
+            Err(AuditError::BadSignature)
+        );
+
+        // the same log with its first entry rewritten
+        let fork = AuditLog::new();
+        fork.append("sign_psbt", &spending(coin(0), 2), &spending(coin(0), 2))
+            .unwrap();
+        fork.append("sign_psbt", &spending(coin(1), 1), &spending(coin(1), 1))
+            .unwrap();
+        assert_eq!(
+            checkpoint.extend(&fork.range(1, 10, &key), &pk),
+            Err(AuditError::Forked(1))
+        );
+        // with both templates for coin 0 signed
+        let entries: Vec<_> = log
+            .range(0, 1, &key)
+            .entries
+            .into_iter()
+            .chain(fork.range(0, 1, &key).entries)
+            .collect();
+        assert!(matches!(
+            check_double_signing(&entries),
+            Err(AuditError::DoubleSigned {
+                first: 0,
+                second: 0,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn canonical_entries() {
+        let entry = LogEntry {
+            index: 1,
+            timestamp: 2,
+            request: "sign_psbt".into(),
+            outpoint: coin(3),
+            template_hash: Sha256::from_inner([4; 32]),
+            signatures: vec!["ab".into()],
+            previous: Sha256::from_inner([5; 32]),
+        };
+        let encoded = entry.encode();
+        assert_eq!(encoded.len(), 8 + 8 + 8 + 9 + 36 + 32 + 8 + 8 + 2 + 32);
+        assert_eq!(
+            &encoded[..16],
+            &[0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]
+        );
+        assert_eq!(&encoded[encoded.len() - 32..], &[5; 32]);
+        assert_eq!(entry.hash(), Sha256::hash(&encoded));
+        // the hash doesn't depend on how the entry is serialized
+        let json = serde_json::to_string(&entry).unwrap();
+        let reparsed: LogEntry = serde_json::from_str(&json.replace(",", ", ")).unwrap();
+        assert_eq!(reparsed.hash(), entry.hash());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fetched_log() {
+        let key = oracle();
+        let log = Arc::new(AuditLog::new());
+        let oracle = KeyOracleEmulator::new(key, false).with_audit_log(log.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_address = http.local_addr().unwrap().to_string();
+        let connection = KeyOracleEmulatorConnection::new(
+            listener.local_addr().unwrap().to_string(),
+            oracle.public_key(),
+            Default::default(),
+            None,
+            Arc::new(Secp256k1::new()),
+        )
+        .unwrap();
+        tokio::spawn(oracle.clone().listen(listener));
+        tokio::spawn(oracle.clone().listen_audit_log(http));
+        connection.sign(spending(coin(0), 1)).unwrap();
+        connection.sign(spending(coin(1), 1)).unwrap();
+        let range = connection.audit_log(0, 10).unwrap();
+        assert_eq!(range, log.range(0, 10, &key));
+        assert_eq!(range.entries[1].request, "sign_psbt");
+        assert_eq!(
+            range.entries[1].template_hash,
+            spending(coin(1), 1).unsigned_tx.get_ctv_hash(0)
+        );
+        let pk = oracle.public_key();
+        assert_eq!(
+            AuditCheckpoint::default()
+                .extend(&range, &pk)
+                .unwrap()
+                .length,
+            2
+        );
+        // the same range is served over HTTP
+        let fetched = tokio::task::block_in_place(|| fetch_http(&http_address, 0, 10)).unwrap();
+        assert_eq!(fetched, range);
+        let bad = tokio::task::block_in_place(|| {
+            let mut t = std::net::TcpStream::connect(&http_address)?;
+            t.write_all(b"GET /other HTTP/1.1\r\n\r\n")?;
+            let mut response = String::new();
+            t.read_to_string(&mut response)?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .unwrap();
+        assert!(bad.starts_with("HTTP/1.1 400"));
+    }
+}
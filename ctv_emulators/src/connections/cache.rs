@@ -0,0 +1,527 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Caching an emulator's responses, so that recompiling the same contract
+//! doesn't ask its oracles again
+
+use super::*;
+use bitcoin::util::psbt::Input;
+use bitcoin::util::sighash::{Prevouts, SighashCache};
+use bitcoin::TxOut;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// What an emulator responded for one template
+#[derive(Clone, Default)]
+struct CacheEntry {
+    /// the clause `get_signer_for` returned. It is kept in memory only, as a
+    /// clause read back from the file could lock funds to anyone who can
+    /// edit it.
+    signer: Option<Clause>,
+    /// the signatures `sign` added to the template's first input
+    signed: Option<Input>,
+}
+
+/// The signatures for one template, a line of a cache's file
+#[derive(Serialize, Deserialize)]
+struct Record {
+    oracle_set: String,
+    hash: Sha256,
+    signed: Input,
+}
+
+/// The cached entries of each set of oracles, by template hash
+type Entries = BTreeMap<String, BTreeMap<Sha256, CacheEntry>>;
+
+/// Wraps an emulator, remembering its responses by template hash and the
+/// set of oracles it stands for, optionally in a file so that they outlive
+/// the process.
+///
+/// Cached signatures are checked against the PSBT being signed, and against
+/// the keys of the wrapped emulator's clause for it, before being reused, so
+/// a corrupted entry, one for a PSBT spending different coins, or one by
+/// another key, is asked of the wrapped emulator again. Clauses are only
+/// remembered in memory.
+///
+/// Requests it can't answer are passed on to the wrapped emulator together
+/// with `CTVEmulator::sign_batch`, and the signatures they get are appended
+/// to the file in one write.
+pub struct CachedEmulator {
+    inner: Arc<dyn CTVEmulator>,
+    oracle_set: String,
+    entries: Mutex<Entries>,
+    path: Option<PathBuf>,
+}
+
+impl CachedEmulator {
+    /// Cache `inner`'s responses in memory. `oracle_set` identifies the
+    /// oracles `inner` asks, e.g. a hash of its configuration.
+    pub fn new(inner: Arc<dyn CTVEmulator>, oracle_set: String) -> Self {
+        CachedEmulator {
+            inner,
+            oracle_set,
+            entries: Default::default(),
+            path: None,
+        }
+    }
+    /// Cache `inner`'s responses in the file at `path`, loading the ones
+    /// already there. Other oracle sets' entries in the file are kept.
+    ///
+    /// The file has a line for each response, the later of two for the same
+    /// template winning. A line which doesn't parse, e.g. one cut short by a
+    /// crash, is skipped.
+    pub fn persistent(
+        inner: Arc<dyn CTVEmulator>,
+        oracle_set: String,
+        path: PathBuf,
+    ) -> Result<Self, std::io::Error> {
+        let mut entries = Entries::new();
+        match std::fs::read(&path) {
+            Ok(v) => {
+                for line in v.split(|b| *b == b'\n') {
+                    if let Ok(r) = serde_json::from_slice::<Record>(line) {
+                        entries
+                            .entry(r.oracle_set)
+                            .or_default()
+                            .entry(r.hash)
+                            .or_default()
+                            .signed = Some(r.signed);
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        };
+        Ok(CachedEmulator {
+            inner,
+            oracle_set,
+            entries: Mutex::new(entries),
+            path: Some(path),
+        })
+    }
+    fn cached(&self, h: Sha256) -> CacheEntry {
+        self.entries
+            .lock()
+            .unwrap()
+            .get(&self.oracle_set)
+            .and_then(|m| m.get(&h))
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// `b` with its cached signatures, if they are still good for it
+    fn cached_signatures(
+        &self,
+        b: &PartiallySignedTransaction,
+    ) -> Option<PartiallySignedTransaction> {
+        let cached = self.cached(b.unsigned_tx.get_ctv_hash(0));
+        with_signatures(b, cached.signed.as_ref()?, cached.signer.as_ref()?)
+    }
+    /// remember the clauses and the signatures on the first inputs of
+    /// `responses`, appending the signatures to the file if persistent
+    fn remember(&self, responses: &[(Sha256, &EmulatorResponse)]) -> Result<(), std::io::Error> {
+        let mut entries = self.entries.lock().unwrap();
+        let entries = entries.entry(self.oracle_set.clone()).or_default();
+        let mut lines = vec![];
+        for (h, response) in responses {
+            let entry = entries.entry(*h).or_default();
+            match response {
+                EmulatorResponse::SignerFor(c) => entry.signer = Some(c.clone()),
+                EmulatorResponse::Sign(b) => {
+                    if let Some(input) = b.inputs.first() {
+                        let signed = Input {
+                            tap_key_sig: input.tap_key_sig,
+                            tap_script_sigs: input.tap_script_sigs.clone(),
+                            ..Default::default()
+                        };
+                        entry.signed = Some(signed.clone());
+                        serde_json::to_writer(
+                            &mut lines,
+                            &Record {
+                                oracle_set: self.oracle_set.clone(),
+                                hash: *h,
+                                signed,
+                            },
+                        )?;
+                        lines.push(b'\n');
+                    }
+                }
+            }
+        }
+        if let (Some(path), false) = (&self.path, lines.is_empty()) {
+            use std::io::Write;
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?
+                .write_all(&lines)?;
+        }
+        Ok(())
+    }
+    /// pass `requests` on to the wrapped emulator in one batch, remembering
+    /// what it responds. Responses are put at the index of their request in
+    /// `responses`, if it has one.
+    fn forward(
+        &self,
+        requests: Vec<(Option<usize>, EmulatorRequest)>,
+        responses: &mut [Option<Result<EmulatorResponse, EmulatorError>>],
+    ) -> Result<(), EmulatorError> {
+        if requests.is_empty() {
+            return Ok(());
+        }
+        let (indexes, requests): (Vec<_>, Vec<_>) = requests.into_iter().unzip();
+        let answered = self.inner.sign_batch(&requests)?;
+        if answered.len() != requests.len() {
+            return Err(input_err("Wrong Number of Responses").into());
+        }
+        self.remember(
+            &requests
+                .iter()
+                .zip(answered.iter())
+                .filter_map(|(r, a)| Some((template(r), a.as_ref().ok()?)))
+                .collect::<Vec<_>>(),
+        )?;
+        for (i, a) in indexes.into_iter().zip(answered) {
+            if let Some(i) = i {
+                responses[i] = Some(a);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the hash of the template `r` is about
+fn template(r: &EmulatorRequest) -> Sha256 {
+    match r {
+        EmulatorRequest::SignerFor(h) => *h,
+        EmulatorRequest::Sign(b) => b.unsigned_tx.get_ctv_hash(0),
+    }
+}
+
+/// `b` with the signatures in `cached` added, if there are any and each is
+/// valid for `b`, with those for scripts by one of the keys of `signer`
+fn with_signatures(
+    b: &PartiallySignedTransaction,
+    cached: &Input,
+    signer: &Clause,
+) -> Option<PartiallySignedTransaction> {
+    if cached.tap_key_sig.is_none() && cached.tap_script_sigs.is_empty() {
+        return None;
+    }
+    let tx = &b.unsigned_tx;
+    let utxos: Vec<TxOut> = b
+        .inputs
+        .iter()
+        .map(|i| i.witness_utxo.clone())
+        .collect::<Option<_>>()?;
+    let prevouts = Prevouts::All(&utxos);
+    let mut sighashes = SighashCache::new(tx);
+    SECP.with(|secp| {
+        let verify = |sighash: &[u8], sig: &bitcoin::SchnorrSig, key: &XOnlyPublicKey| {
+            let msg = bitcoin::secp256k1::Message::from_digest_slice(sighash).ok()?;
+            secp.verify_schnorr(&sig.sig, &msg, key).ok()
+        };
+        if let Some(sig) = &cached.tap_key_sig {
+            let spk = &utxos.first()?.script_pubkey;
+            if !spk.is_v1_p2tr() {
+                return None;
+            }
+            let key = XOnlyPublicKey::from_slice(&spk[2..]).ok()?;
+            let sighash = sighashes
+                .taproot_key_spend_signature_hash(0, &prevouts, sig.hash_ty)
+                .ok()?;
+            verify(&sighash[..], sig, &key)?;
+        }
+        let keys = signer.keys();
+        for ((key, leaf), sig) in cached.tap_script_sigs.iter() {
+            if !keys.contains(&key) {
+                return None;
+            }
+            let sighash = sighashes
+                .taproot_script_spend_signature_hash(0, &prevouts, *leaf, sig.hash_ty)
+                .ok()?;
+            verify(&sighash[..], sig, key)?;
+        }
+        Some(())
+    })?;
+    let mut signed = b.clone();
+    let input = signed.inputs.first_mut()?;
+    if cached.tap_key_sig.is_some() {
+        input.tap_key_sig = cached.tap_key_sig;
+    }
+    input
+        .tap_script_sigs
+        .extend(cached.tap_script_sigs.iter().map(|(k, v)| (*k, *v)));
+    Some(signed)
+}
+
+impl CTVEmulator for CachedEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        match self.sign_batch(&[EmulatorRequest::SignerFor(h)])?.pop() {
+            Some(Ok(EmulatorResponse::SignerFor(c))) => Ok(c),
+            Some(Err(e)) => Err(e),
+            _ => Err(input_err("Wrong Response").into()),
+        }
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        match self.sign_batch(&[EmulatorRequest::Sign(b)])?.pop() {
+            Some(Ok(EmulatorResponse::Sign(b))) => Ok(b),
+            Some(Err(e)) => Err(e),
+            _ => Err(input_err("Wrong Response").into()),
+        }
+    }
+    /// Answers what it can from the cache, and passes the rest on in one
+    /// batch, or two if cached signatures turn out to be bad
+    fn sign_batch(
+        &self,
+        requests: &[EmulatorRequest],
+    ) -> Result<Vec<Result<EmulatorResponse, EmulatorError>>, EmulatorError> {
+        let mut responses: Vec<_> = requests.iter().map(|_| None).collect();
+        // the requests not cached, and the clauses needed to check cached
+        // signatures
+        let mut first = vec![];
+        for (i, r) in requests.iter().enumerate() {
+            let cached = self.cached(template(r));
+            match (r, cached.signer, cached.signed) {
+                (EmulatorRequest::SignerFor(_), Some(c), _) => {
+                    responses[i] = Some(Ok(EmulatorResponse::SignerFor(c)))
+                }
+                (EmulatorRequest::Sign(_), None, Some(_)) => {
+                    first.push((None, EmulatorRequest::SignerFor(template(r))))
+                }
+                (EmulatorRequest::Sign(_), Some(_), Some(_)) => {}
+                _ => first.push((Some(i), r.clone())),
+            }
+        }
+        self.forward(first, &mut responses)?;
+        // the PSBTs whose cached signatures are bad
+        let mut second = vec![];
+        for (i, r) in requests.iter().enumerate() {
+            if let (EmulatorRequest::Sign(b), None) = (r, &responses[i]) {
+                match self.cached_signatures(b) {
+                    Some(signed) => responses[i] = Some(Ok(EmulatorResponse::Sign(signed))),
+                    None => second.push((Some(i), r.clone())),
+                }
+            }
+        }
+        self.forward(second, &mut responses)?;
+        Ok(responses
+            .into_iter()
+            .map(|r| r.expect("a response for each request"))
+            .collect())
+    }
+    fn describe(&self) -> Option<String> {
+        self.inner.describe()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connections::local::LocalEmulator;
+    use bitcoin::{Script, Transaction, TxIn};
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    /// a `LocalEmulator` counting how often it is asked anything, and in how
+    /// many batches
+    #[derive(Default)]
+    struct Counting(AtomicU32, AtomicU32);
+    impl CTVEmulator for Counting {
+        fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            LocalEmulator::for_tests().get_signer_for(h)
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            LocalEmulator::for_tests().sign(b)
+        }
+        fn sign_batch(
+            &self,
+            requests: &[EmulatorRequest],
+        ) -> Result<Vec<Result<EmulatorResponse, EmulatorError>>, EmulatorError> {
+            self.1.fetch_add(1, Ordering::Relaxed);
+            Ok(requests
+                .iter()
+                .map(|r| match r {
+                    EmulatorRequest::SignerFor(h) => {
+                        self.get_signer_for(*h).map(EmulatorResponse::SignerFor)
+                    }
+                    EmulatorRequest::Sign(b) => self.sign(b.clone()).map(EmulatorResponse::Sign),
+                })
+                .collect())
+        }
+    }
+
+    /// a PSBT spending a coin sent to the test oracle's key for it
+    fn spending() -> (Sha256, PartiallySignedTransaction) {
+        spending_to(1000)
+    }
+
+    /// as `spending`, with an output of `value`
+    fn spending_to(value: u64) -> (Sha256, PartiallySignedTransaction) {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let h = tx.get_ctv_hash(0);
+        let key = match LocalEmulator::for_tests().get_signer_for(h).unwrap() {
+            Clause::Key(key) => key,
+            c => panic!("expected a key, got {}", c),
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        b.inputs[0].witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: SECP.with(|secp| Script::new_v1_p2tr(secp, key, None)),
+        });
+        (h, b)
+    }
+
+    #[test]
+    fn cached_responses() {
+        let path = std::env::temp_dir().join(format!("emulator-cache-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let inner = Arc::new(Counting::default());
+        let cached =
+            CachedEmulator::persistent(inner.clone(), "test".into(), path.clone()).unwrap();
+        let (h, b) = spending();
+        let clause = cached.get_signer_for(h).unwrap();
+        let signed = cached.sign(b.clone()).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        assert_eq!(cached.get_signer_for(h).unwrap(), clause);
+        assert_eq!(cached.sign(b.clone()).unwrap(), signed);
+        assert_eq!(inner.0.load(Ordering::Relaxed), 2);
+
+        // a restart reads the signatures from the file, but asks for the
+        // clause again rather than trusting the file's
+        let restarted =
+            CachedEmulator::persistent(inner.clone(), "test".into(), path.clone()).unwrap();
+        assert_eq!(restarted.get_signer_for(h).unwrap(), clause);
+        assert_eq!(restarted.sign(b.clone()).unwrap(), signed);
+        assert_eq!(inner.0.load(Ordering::Relaxed), 3);
+        // but another set of oracles doesn't share its entries
+        let other =
+            CachedEmulator::persistent(inner.clone(), "other".into(), path.clone()).unwrap();
+        other.get_signer_for(h).unwrap();
+        assert_eq!(inner.0.load(Ordering::Relaxed), 4);
+
+        // a corrupted signature is asked for again
+        {
+            let mut entries = restarted.entries.lock().unwrap();
+            let entry = entries.get_mut("test").unwrap().get_mut(&h).unwrap();
+            let sig = entry.signed.as_mut().unwrap().tap_key_sig.as_mut().unwrap();
+            let mut bytes = sig.sig.as_ref().to_vec();
+            bytes[0] ^= 1;
+            sig.sig = bitcoin::secp256k1::schnorr::Signature::from_slice(&bytes).unwrap();
+        }
+        assert_eq!(restarted.sign(b.clone()).unwrap(), signed);
+        assert_eq!(inner.0.load(Ordering::Relaxed), 5);
+
+        // as is a valid signature by a key other than the oracle's
+        {
+            let leaf = bitcoin::util::taproot::TapLeafHash::from_inner([1; 32]);
+            let utxos = [b.inputs[0].witness_utxo.clone().unwrap()];
+            let sighash = SighashCache::new(&b.unsigned_tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &Prevouts::All(&utxos),
+                    leaf,
+                    bitcoin::SchnorrSighashType::Default,
+                )
+                .unwrap();
+            let (key, sig) = SECP.with(|secp| {
+                let pair = bitcoin::KeyPair::from_seckey_slice(secp, &[7; 32]).unwrap();
+                let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+                (
+                    pair.x_only_public_key().0,
+                    secp.sign_schnorr_no_aux_rand(&msg, &pair),
+                )
+            });
+            let mut entries = restarted.entries.lock().unwrap();
+            let entry = entries.get_mut("test").unwrap().get_mut(&h).unwrap();
+            entry.signed.as_mut().unwrap().tap_script_sigs.insert(
+                (key, leaf),
+                bitcoin::SchnorrSig {
+                    sig,
+                    hash_ty: bitcoin::SchnorrSighashType::Default,
+                },
+            );
+        }
+        assert_eq!(restarted.sign(b).unwrap(), signed);
+        assert_eq!(inner.0.load(Ordering::Relaxed), 6);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn batched_responses() {
+        let path = std::env::temp_dir().join(format!("emulator-batch-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let inner = Arc::new(Counting::default());
+        let cached =
+            CachedEmulator::persistent(inner.clone(), "test".into(), path.clone()).unwrap();
+        let psbts: Vec<_> = (1..4).map(|v| spending_to(v * 1000)).collect();
+        let requests: Vec<_> = psbts
+            .iter()
+            .flat_map(|(h, b)| {
+                vec![
+                    EmulatorRequest::SignerFor(*h),
+                    EmulatorRequest::Sign(b.clone()),
+                ]
+            })
+            .collect();
+        let responses = cached.sign_batch(&requests).unwrap();
+        assert_eq!(responses.len(), 6);
+        assert_eq!(inner.1.load(Ordering::Relaxed), 1);
+        // each response in a line of its own
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 3);
+
+        // all answered from the cache
+        let again = cached.sign_batch(&requests).unwrap();
+        assert_eq!(inner.1.load(Ordering::Relaxed), 1);
+        for (a, b) in responses.iter().zip(again.iter()) {
+            match (a, b) {
+                (Ok(EmulatorResponse::SignerFor(a)), Ok(EmulatorResponse::SignerFor(b))) => {
+                    assert_eq!(a, b)
+                }
+                (Ok(EmulatorResponse::Sign(a)), Ok(EmulatorResponse::Sign(b))) => {
+                    assert_eq!(a, b)
+                }
+                r => panic!("unexpected responses {:?}", r),
+            }
+        }
+
+        // a restart asks for the clauses to check the signatures with, all
+        // in one batch, and a line cut short is skipped
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| std::io::Write::write_all(&mut f, b"{\"oracle_set\":"))
+            .unwrap();
+        let restarted =
+            CachedEmulator::persistent(inner.clone(), "test".into(), path.clone()).unwrap();
+        let signs: Vec<_> = requests
+            .iter()
+            .filter(|r| matches!(r, EmulatorRequest::Sign(_)))
+            .cloned()
+            .collect();
+        let asked = inner.0.load(Ordering::Relaxed);
+        assert_eq!(restarted.sign_batch(&signs).unwrap().len(), 3);
+        assert_eq!(inner.1.load(Ordering::Relaxed), 2);
+        assert_eq!(inner.0.load(Ordering::Relaxed), asked + 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -6,7 +6,15 @@
 
 //! join together CTVEmulators as a multisig
 
+use super::hd::HDOracleEmulatorConnection;
+use super::key::KeyOracleEmulatorConnection;
 use super::*;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::runtime::Handle;
+
 /// Creates a multi-condition emulator with a certain threshold.
 /// It implements CTVEmulator so that it itself can be used as a trait object.
 pub struct FederatedEmulatorConnection {
@@ -42,4 +50,403 @@ impl CTVEmulator for FederatedEmulatorConnection {
         }
         Ok(b)
     }
+    /// Sends each emulator one batch, with the PSBTs the previous one signed.
+    /// A request fails with the first error any emulator gives for it.
+    fn sign_batch(
+        &self,
+        requests: &[EmulatorRequest],
+    ) -> Result<Vec<Result<EmulatorResponse, EmulatorError>>, EmulatorError> {
+        let mut requests = requests.to_vec();
+        let mut signers = vec![vec![]; requests.len()];
+        let mut failed: Vec<Option<EmulatorError>> = requests.iter().map(|_| None).collect();
+        for emulator in self.emulators.iter() {
+            let responses = emulator.sign_batch(&requests)?;
+            if responses.len() != requests.len() {
+                return Err(input_err("Wrong Number of Responses").into());
+            }
+            for (i, response) in responses.into_iter().enumerate() {
+                match response {
+                    Ok(EmulatorResponse::SignerFor(c)) => signers[i].push(c),
+                    Ok(EmulatorResponse::Sign(b)) => requests[i] = EmulatorRequest::Sign(b),
+                    Err(e) => {
+                        failed[i].get_or_insert(e);
+                    }
+                }
+            }
+        }
+        Ok(requests
+            .into_iter()
+            .zip(signers)
+            .zip(failed)
+            .map(|((request, signers), failed)| match (failed, request) {
+                (Some(e), _) => Err(e),
+                (None, EmulatorRequest::SignerFor(_)) => Ok(EmulatorResponse::SignerFor(
+                    Clause::Threshold(self.threshold as usize, signers),
+                )),
+                (None, EmulatorRequest::Sign(b)) => Ok(EmulatorResponse::Sign(b)),
+            })
+            .collect())
+    }
+    fn describe(&self) -> Option<String> {
+        describe_threshold(
+            self.threshold as usize,
+            self.emulators.iter().map(|e| e.describe()),
+        )
+    }
+}
+
+/// a threshold of `members`, if they all describe themselves
+fn describe_threshold(
+    threshold: usize,
+    members: impl Iterator<Item = Option<String>>,
+) -> Option<String> {
+    let members = members.collect::<Option<Vec<String>>>()?;
+    Some(format!("{} of [{}]", threshold, members.join(", ")))
+}
+
+/// How to reach a federation of `KeyOracleEmulator`s, any `threshold` of
+/// which must sign for each template, e.g. from a sapio-cli config file.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct FederatedEmulatorConfig {
+    /// each member's address and key, as `host:port`, which may be a v3
+    /// onion address if the `transport` has a proxy
+    #[schemars(with = "Vec<(String, String)>")]
+    pub members: Vec<(String, XOnlyPublicKey)>,
+    /// how many members must sign, at least 1 and at most `members.len()`
+    pub threshold: usize,
+    /// how long to wait for each member to sign, in milliseconds
+    #[serde(default = "FederatedEmulatorConfig::default_timeout_ms")]
+    pub timeout_ms: u64,
+    /// how to connect to the members
+    #[serde(default)]
+    pub transport: Transport,
+}
+
+impl FederatedEmulatorConfig {
+    fn default_timeout_ms() -> u64 {
+        10_000
+    }
+    /// Checks each member's address, without connecting to them. A runtime
+    /// is created for the connections if not called from within one.
+    pub fn connect(&self) -> Result<QuorumEmulatorConnection, std::io::Error> {
+        if self.threshold == 0 || self.threshold > self.members.len() {
+            return Err(input_err(
+                "Threshold Must Be Between 1 and the Number of Members",
+            ));
+        }
+        let runtime = match Handle::try_current() {
+            Ok(_) => None,
+            Err(_) => Some(Arc::new(tokio::runtime::Runtime::new()?)),
+        };
+        let secp = Arc::new(Secp256k1::new());
+        let members = self
+            .members
+            .iter()
+            .map(|(url, key)| {
+                let connection = KeyOracleEmulatorConnection::new(
+                    url.clone(),
+                    *key,
+                    self.transport.clone(),
+                    runtime.clone(),
+                    secp.clone(),
+                )?;
+                Ok((url.clone(), Arc::new(connection)))
+            })
+            .collect::<Result<Vec<_>, std::io::Error>>()?;
+        Ok(QuorumEmulatorConnection {
+            handle: members[0].1.handle.clone(),
+            members,
+            threshold: self.threshold,
+            timeout: Duration::from_millis(self.timeout_ms),
+        })
+    }
+}
+
+/// Which members of a federation signed a PSBT, see
+/// `QuorumEmulatorConnection::sign_with_report`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QuorumReport {
+    /// how many members had to sign
+    pub threshold: usize,
+    /// the members which signed, by address
+    pub responded: Vec<String>,
+    /// the members which didn't, by address, and why
+    pub failed: Vec<(String, String)>,
+    /// the members not waited for once the threshold had signed, or could
+    /// no longer, by address
+    pub unheard: Vec<String>,
+}
+
+impl std::fmt::Display for QuorumReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} of {} members signed, {} required; responded: [{}]",
+            self.responded.len(),
+            self.responded.len() + self.failed.len() + self.unheard.len(),
+            self.threshold,
+            self.responded.join(", ")
+        )?;
+        for (url, e) in self.failed.iter() {
+            write!(f, "; {} failed: {}", url, e)?;
+        }
+        if !self.unheard.is_empty() {
+            write!(f, "; not waited for: [{}]", self.unheard.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A connection to a `FederatedEmulatorConfig`'s members, which signs a
+/// template with `Threshold(k, [member keys])`, each member's key committed
+/// to the template's hash.
+///
+/// Members are asked to sign concurrently, and signing succeeds so long as
+/// no more than n - k of them fail or time out. Once k have signed, or more
+/// than n - k have failed, the rest aren't waited for.
+pub struct QuorumEmulatorConnection {
+    members: Vec<(String, Arc<KeyOracleEmulatorConnection>)>,
+    threshold: usize,
+    timeout: Duration,
+    handle: Handle,
+}
+
+impl QuorumEmulatorConnection {
+    /// each member's signatures for `b`, or why they couldn't sign, or
+    /// `None` for those not waited for
+    fn sign_all(
+        &self,
+        b: &PartiallySignedTransaction,
+    ) -> Vec<Option<Result<PartiallySignedTransaction, std::io::Error>>> {
+        tokio::task::block_in_place(|| {
+            self.handle.block_on(async {
+                // dropped with the members not waited for, which aborts them
+                let mut pending = tokio::task::JoinSet::new();
+                let ids: BTreeMap<_, _> = self
+                    .members
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, member))| {
+                        let (member, b, timeout) = (member.clone(), b.clone(), self.timeout);
+                        let task = pending.spawn(async move {
+                            tokio::time::timeout(timeout, member.sign_async(b))
+                                .await
+                                .unwrap_or_else(|_| {
+                                    Err(std::io::Error::new(
+                                        std::io::ErrorKind::TimedOut,
+                                        "Timed Out",
+                                    ))
+                                })
+                        });
+                        (task.id(), i)
+                    })
+                    .collect();
+                let mut signed: Vec<_> = self.members.iter().map(|_| None).collect();
+                let (mut responded, mut failed) = (0, 0);
+                while responded < self.threshold && failed <= self.members.len() - self.threshold {
+                    let (id, result) = match pending.join_next_with_id().await {
+                        Some(Ok((id, result))) => (id, result),
+                        Some(Err(e)) => (e.id(), Err(input_err(&e.to_string()))),
+                        None => break,
+                    };
+                    if result.is_ok() {
+                        responded += 1;
+                    } else {
+                        failed += 1;
+                    }
+                    signed[ids[&id]] = Some(result);
+                }
+                signed
+            })
+        })
+    }
+    /// Signs as `CTVEmulator::sign` does, with a report of which members
+    /// signed for this call whether or not the threshold was reached
+    pub fn sign_with_report(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> (
+        Result<PartiallySignedTransaction, EmulatorError>,
+        QuorumReport,
+    ) {
+        let mut report = QuorumReport {
+            threshold: self.threshold,
+            responded: vec![],
+            failed: vec![],
+            unheard: vec![],
+        };
+        let mut combined = Ok(b.clone());
+        for ((url, _), signed) in self.members.iter().zip(self.sign_all(&b)) {
+            match signed {
+                Some(Ok(signed)) => {
+                    combined =
+                        combined.and_then(|c| HDOracleEmulatorConnection::combine(c, signed));
+                    report.responded.push(url.clone());
+                }
+                Some(Err(e)) => report.failed.push((url.clone(), e.to_string())),
+                None => report.unheard.push(url.clone()),
+            }
+        }
+        let result = match combined {
+            Err(e) => Err(e.into()),
+            Ok(_) if report.responded.len() < self.threshold => {
+                Err(input_err(&format!("Quorum Not Reached: {}", report)).into())
+            }
+            Ok(combined) => Ok(combined),
+        };
+        (result, report)
+    }
+}
+
+impl CTVEmulator for QuorumEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        let keys = self
+            .members
+            .iter()
+            .map(|(_, m)| m.get_signer_for(h))
+            .collect::<Result<Vec<Clause>, EmulatorError>>()?;
+        Ok(Clause::Threshold(self.threshold, keys))
+    }
+    /// Fails if fewer than the threshold of members sign, with a
+    /// `QuorumReport` of which did in the error
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        self.sign_with_report(b).0
+    }
+    fn describe(&self) -> Option<String> {
+        describe_threshold(
+            self.threshold,
+            self.members.iter().map(|(_, m)| m.describe()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::servers::key::KeyOracleEmulator;
+    use bitcoin::{KeyPair, Script, Transaction, TxIn, TxOut};
+
+    fn keypair(seed: u8) -> KeyPair {
+        SECP.with(|secp| KeyPair::from_seckey_slice(secp, &[seed; 32]).unwrap())
+    }
+
+    /// a running member with a key from `seed`
+    async fn up(seed: u8) -> (String, XOnlyPublicKey) {
+        let oracle = KeyOracleEmulator::new(keypair(seed), false);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let member = (
+            listener.local_addr().unwrap().to_string(),
+            oracle.public_key(),
+        );
+        tokio::spawn(oracle.listen(listener));
+        member
+    }
+
+    /// a member with a key from `seed` which isn't running
+    fn down(seed: u8) -> (String, XOnlyPublicKey) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = listener.local_addr().unwrap().to_string();
+        (url, XOnlyPublicKey::from_keypair(&keypair(seed)).0)
+    }
+
+    /// a PSBT spending a coin sent to `key`
+    fn spending(key: XOnlyPublicKey) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        b.inputs[0].witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: SECP.with(|secp| Script::new_v1_p2tr(secp, key, None)),
+        });
+        b
+    }
+
+    fn config(members: Vec<(String, XOnlyPublicKey)>) -> FederatedEmulatorConfig {
+        serde_json::from_value(serde_json::json!({
+            "members": members,
+            "threshold": 2,
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quorum_reached() {
+        let members = vec![up(1).await, up(2).await, down(3)];
+        let federation = config(members.clone()).connect().unwrap();
+        let h = spending(members[0].1).extract_tx().get_ctv_hash(0);
+        let keys: Vec<_> = members
+            .iter()
+            .map(|(_, k)| SECP.with(|secp| Clause::Key(template_key(k, h, secp).unwrap())))
+            .collect();
+        assert_eq!(
+            federation.get_signer_for(h).unwrap(),
+            Clause::Threshold(2, keys.clone())
+        );
+        let first = match keys[0] {
+            Clause::Key(k) => k,
+            _ => unreachable!(),
+        };
+        let (signed, report) = federation.sign_with_report(spending(first));
+        assert!(signed.unwrap().inputs[0].tap_key_sig.is_some());
+        assert_eq!(
+            report.responded,
+            vec![members[0].0.clone(), members[1].0.clone()]
+        );
+        // the member which is down may or may not have failed by the time
+        // the others signed
+        let others: Vec<_> = report
+            .failed
+            .iter()
+            .map(|(url, _)| url)
+            .chain(report.unheard.iter())
+            .collect();
+        assert_eq!(others, vec![&members[2].0]);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quorum_stops_waiting() {
+        // a member which accepts connections but never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent = (
+            listener.local_addr().unwrap().to_string(),
+            XOnlyPublicKey::from_keypair(&keypair(9)).0,
+        );
+        let members = vec![up(7).await, silent.clone(), up(8).await];
+        let mut config = config(members.clone());
+        config.timeout_ms = 60_000;
+        let federation = config.connect().unwrap();
+        let h = spending(members[0].1).extract_tx().get_ctv_hash(0);
+        let first = SECP.with(|secp| template_key(&members[0].1, h, secp).unwrap());
+        let started = std::time::Instant::now();
+        let (signed, report) = federation.sign_with_report(spending(first));
+        assert!(signed.is_ok());
+        assert!(started.elapsed() < Duration::from_millis(config.timeout_ms));
+        assert_eq!(report.unheard, vec![silent.0]);
+        assert!(report.failed.is_empty());
+        drop(listener);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn quorum_not_reached() {
+        let members = vec![up(4).await, down(5), down(6)];
+        let federation = config(members.clone()).connect().unwrap();
+        let (signed, report) = federation.sign_with_report(spending(members[0].1));
+        assert!(signed.is_err());
+        // two members down is enough to know the quorum can't be reached
+        assert_eq!(report.failed.len(), 2);
+        assert_eq!(report.responded.len() + report.unheard.len(), 1);
+        let mut too_high = config(members);
+        too_high.threshold = 4;
+        assert!(too_high.connect().is_err());
+    }
 }
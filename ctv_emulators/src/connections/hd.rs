@@ -7,6 +7,7 @@
 //! Hierarchical Deterministic Emulator Connection
 
 use super::*;
+use crate::servers::log::AuditRange;
 /// HDOracleEmulatorConnection wraps a tokio runtime and a TCPStream
 /// with a key to be able to talk to an Oracle server.
 ///
@@ -20,12 +21,15 @@ pub struct HDOracleEmulatorConnection {
     pub runtime: Option<Arc<tokio::runtime::Runtime>>,
     /// handle to either current_runtime or the runtime owned above
     pub handle: tokio::runtime::Handle,
-    /// connection to the reconnect SocketAddr
+    /// connection to the oracle at `address`
     pub connection: Mutex<Option<TcpStream>>,
-    /// resolved address to the oracle
-    pub reconnect: SocketAddr,
+    /// the oracle's `host:port`, resolved each time it is connected to, by
+    /// the proxy if `transport` has one
+    pub address: String,
     /// the root key signatures will come from
     pub root: ExtendedPubKey,
+    /// how to connect to the oracle
+    pub transport: Transport,
     /// a secp context
     pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
 }
@@ -36,33 +40,28 @@ impl HDOracleEmulatorConnection {
         let c = hash_to_child_vec(h);
         self.root.derive_pub(&self.secp, &c)
     }
-    /// Creates a new instance of a HDOracleEmulatorConnection.
+    /// Creates a new instance of a HDOracleEmulatorConnection, checking that
+    /// `transport` can reach `address`.
     ///
     /// Note that the runtime and secp can be shared with other instances as it is Arc.
     ///
-    /// `new` does not connect to the address passed in immediately, but it does
-    /// use tokio::net::lookup_host to resolve the address. A connection is not
-    /// opened to the server until a call to the `sign` method is made. This is
-    /// purposeful so that connections are not opened until they are actually needed.
-    ///
-    /// Note that as a consequence of new doing the host resolving, if DNS
-    /// records change, then a new HDOracleEmulatorConnection would need to be
-    /// created to observe it.
+    /// `new` does not connect to or resolve the address passed in
+    /// immediately. A connection is not opened to the server until a call to
+    /// the `sign` method is made. This is purposeful so that connections are
+    /// not opened until they are actually needed, and so that an address
+    /// only a proxy can resolve, e.g. a `.onion`, isn't looked up locally.
     pub async fn new<A: ToSocketAddrs + std::fmt::Display + Clone>(
         address: A,
         root: ExtendedPubKey,
         runtime: Option<Arc<tokio::runtime::Runtime>>,
         secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+        transport: Transport,
     ) -> Result<Self, std::io::Error> {
+        let address = address.to_string();
+        transport.validate(&address)?;
         Ok(HDOracleEmulatorConnection {
             connection: Mutex::new(None),
-            reconnect: tokio::net::lookup_host(address.clone())
-                .await?
-                .next()
-                .ok_or_else(|| {
-                    input_error::<()>(&format!("Bad Lookup Could Not Resolve Address {}", address))
-                        .unwrap_err()
-                })?,
+            address,
             handle: Handle::try_current().unwrap_or_else(|_e| {
                 runtime
                     .as_ref()
@@ -72,29 +71,10 @@ impl HDOracleEmulatorConnection {
             }),
             runtime,
             root,
+            transport,
             secp,
         })
     }
-
-    /// make a request via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
-        let v = serde_json::to_vec(r)?;
-        t.write_u32(v.len() as u32).await?;
-        t.write_all(&v[..]).await
-    }
-    /// receive a response via the tcpstream.
-    /// wire format: length:u32 data:[u8;length]
-    ///
-    /// TODO: secure response by limiting the length to a max value.
-    /// This is not super critical because presumably the oracles are not trying to OOM your system.
-    async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
-        let l = t.read_u32().await? as usize;
-        let mut v = vec![0u8; l];
-        t.read_exact(&mut v[..]).await?;
-        let t: T = serde_json::from_slice::<T>(&v[..])?;
-        Ok(t)
-    }
 }
 
 use tokio::{runtime::Handle, sync::Mutex};
@@ -104,27 +84,160 @@ impl CTVEmulator for HDOracleEmulatorConnection {
     }
     fn sign(
         &self,
-        mut b: PartiallySignedTransaction,
+        b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError> {
-        let inp: Result<PartiallySignedTransaction, std::io::Error> =
-            tokio::task::block_in_place(|| {
-                self.handle.block_on(async {
-                    let mut mconn = self.connection.lock().await;
-                    loop {
-                        if let Some(conn) = &mut *mconn {
-                            Self::request(conn, &msgs::Request::SignPSBT(msgs::PSBT(b.clone())))
-                                .await?;
-                            conn.flush().await?;
-                            return Ok(Self::response::<msgs::PSBT>(conn).await?.0);
-                        } else {
-                            *mconn = Some(TcpStream::connect(&self.reconnect).await?);
-                        }
-                    }
-                })
-            });
+        let signed: msgs::PSBT =
+            self.round_trip(&msgs::Request::SignPSBT(msgs::PSBT(b.clone())))?;
+        Ok(Self::combine(b, signed.0)?)
+    }
+    fn describe(&self) -> Option<String> {
+        Some(format!("hd oracle {} at {}", self.root, self.address))
+    }
+    /// Gets the Clauses locally, and the signatures in one round trip for
+    /// each `msgs::MAX_BATCH` PSBTs
+    fn sign_batch(
+        &self,
+        requests: &[EmulatorRequest],
+    ) -> Result<Vec<Result<EmulatorResponse, EmulatorError>>, EmulatorError> {
+        let unsigned: Vec<_> = requests
+            .iter()
+            .filter_map(|r| match r {
+                EmulatorRequest::Sign(b) => Some(msgs::PSBT(b.clone())),
+                EmulatorRequest::SignerFor(_) => None,
+            })
+            .collect();
+        let mut signed: msgs::BatchResponse = vec![];
+        for chunk in unsigned.chunks(msgs::MAX_BATCH) {
+            let response: msgs::BatchResponse =
+                self.round_trip(&msgs::Request::SignBatch(chunk.to_vec()))?;
+            if response.len() != chunk.len() {
+                return Err(input_err("Wrong Number of Signed PSBTs").into());
+            }
+            signed.extend(response);
+        }
+        let mut signed = signed.into_iter();
+        Ok(requests
+            .iter()
+            .map(|r| match r {
+                EmulatorRequest::SignerFor(h) => {
+                    self.get_signer_for(*h).map(EmulatorResponse::SignerFor)
+                }
+                EmulatorRequest::Sign(b) => signed
+                    .next()
+                    .expect("one for each PSBT")
+                    .map_err(|e| input_err(&e).into())
+                    .and_then(|s| Ok(Self::combine(b.clone(), s.0)?))
+                    .map(EmulatorResponse::Sign),
+            })
+            .collect())
+    }
+}
 
-        b.combine(inp?)
+impl HDOracleEmulatorConnection {
+    /// send a request and receive its response, connecting first if needed
+    fn round_trip<T: DeserializeOwned + Clone>(
+        &self,
+        r: &msgs::Request,
+    ) -> Result<T, std::io::Error> {
+        tokio::task::block_in_place(|| {
+            self.handle.block_on(round_trip(
+                &self.connection,
+                &self.transport,
+                &self.address,
+                r,
+            ))
+        })
+    }
+    /// the oracle's audit log from `start` up to `end`, see
+    /// `connections::audit`
+    pub fn audit_log(&self, start: u64, end: u64) -> Result<AuditRange, std::io::Error> {
+        let range: msgs::AuditLogResponse =
+            self.round_trip(&msgs::Request::AuditLog { start, end })?;
+        range.map_err(|e| input_err(&e))
+    }
+    /// add the oracle's signatures to `b`
+    pub(crate) fn combine(
+        mut b: PartiallySignedTransaction,
+        signed: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        b.combine(signed)
             .or_else(|_e| input_error("Fault Signed PSBT"))?;
         Ok(b)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::servers::hd::HDOracleEmulator;
+    use bitcoin::{Network, Script, Transaction, TxIn, TxOut};
+
+    /// a PSBT to a `value` output, spending a coin sent to `oracle`'s key for
+    /// it
+    fn spending(oracle: &HDOracleEmulatorConnection, value: u64) -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let key = match oracle.get_signer_for(tx.get_ctv_hash(0)).unwrap() {
+            Clause::Key(key) => key,
+            c => panic!("expected a key, got {}", c),
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        b.inputs[0].witness_utxo = Some(TxOut {
+            value: value + 1000,
+            script_pubkey: Script::new_v1_p2tr(&oracle.secp, key, None),
+        });
+        b
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn batch_round_trip() {
+        let root = ExtendedPrivKey::new_master(Network::Regtest, &[7; 32]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(HDOracleEmulator::new(root, false).listen(listener));
+        let secp = Arc::new(Secp256k1::new());
+        let connection = HDOracleEmulatorConnection::new(
+            address,
+            ExtendedPubKey::from_priv(&secp, &root),
+            None,
+            secp.clone(),
+            Transport::default(),
+        )
+        .await
+        .unwrap();
+        // more PSBTs than fit in one batch, one of which can't be signed,
+        // and a clause
+        let mut requests: Vec<_> = (0..msgs::MAX_BATCH as u64 + 2)
+            .map(|i| EmulatorRequest::Sign(spending(&connection, 1000 + i)))
+            .collect();
+        let mut unsignable = spending(&connection, 1);
+        unsignable.inputs[0].witness_utxo = None;
+        requests.insert(3, EmulatorRequest::Sign(unsignable));
+        let h = spending(&connection, 1000).unsigned_tx.get_ctv_hash(0);
+        requests.push(EmulatorRequest::SignerFor(h));
+        let responses = connection.sign_batch(&requests).unwrap();
+        assert_eq!(responses.len(), requests.len());
+        for (i, response) in responses.into_iter().enumerate() {
+            match response {
+                Err(_) => assert_eq!(i, 3),
+                Ok(EmulatorResponse::Sign(b)) => assert!(b.inputs[0].tap_key_sig.is_some()),
+                Ok(EmulatorResponse::SignerFor(c)) => {
+                    assert_eq!(i, requests.len() - 1);
+                    assert_eq!(c, connection.get_signer_for(h).unwrap());
+                }
+            }
+        }
+        // a server refuses a batch larger than that
+        let too_large = vec![msgs::PSBT(spending(&connection, 1000)); msgs::MAX_BATCH + 1];
+        assert!(connection
+            .round_trip::<msgs::BatchResponse>(&msgs::Request::SignBatch(too_large))
+            .is_err());
+    }
+}
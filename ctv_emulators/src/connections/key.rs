@@ -0,0 +1,310 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Connection to an oracle known by a fixed key
+
+use super::*;
+use crate::servers::key::{KeyValidity, SignedKeys};
+use crate::servers::log::AuditRange;
+use tokio::{runtime::Handle, sync::Mutex};
+
+/// KeyOracleEmulatorConnection talks to a `KeyOracleEmulator`, which signs
+/// for each template with its key tweaked by the template's hash.
+///
+/// The oracle may rotate its key, so the keys it gives for the time of each
+/// compilation are used, see `KeyOracleEmulatorConnection::signer_at`.
+///
+/// Like `HDOracleEmulatorConnection` it blocks on its runtime internally, and
+/// only connects once it is first asked to sign.
+pub struct KeyOracleEmulatorConnection {
+    /// the Oracle's runtime, if not executing in a runtime already
+    pub runtime: Option<Arc<tokio::runtime::Runtime>>,
+    /// handle to either current_runtime or the runtime owned above
+    pub handle: tokio::runtime::Handle,
+    /// connection to the oracle at `address`
+    pub connection: Mutex<Option<TcpStream>>,
+    /// the oracle's `host:port`, resolved each time it is connected to, by
+    /// the proxy if `transport` has one
+    pub address: String,
+    /// how to connect to the oracle
+    pub transport: Transport,
+    /// the key the oracle is known by, which signatures come from, before
+    /// tweaking, until it is rotated
+    pub key: XOnlyPublicKey,
+    /// the time to choose the oracle's keys for, in seconds since the unix
+    /// epoch, or now if None
+    pub compile_time: Option<u64>,
+    /// a secp context
+    pub secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    /// the oracle's keys, once fetched
+    keys: std::sync::Mutex<Option<Vec<KeyValidity>>>,
+}
+
+impl KeyOracleEmulatorConnection {
+    /// Creates a new instance of a KeyOracleEmulatorConnection, checking that
+    /// `transport` can reach `address` without connecting to it yet, see
+    /// `HDOracleEmulatorConnection::new`
+    pub fn new(
+        address: String,
+        key: XOnlyPublicKey,
+        transport: Transport,
+        runtime: Option<Arc<tokio::runtime::Runtime>>,
+        secp: Arc<bitcoin::secp256k1::Secp256k1<bitcoin::secp256k1::All>>,
+    ) -> Result<Self, std::io::Error> {
+        transport.validate(&address)?;
+        Ok(KeyOracleEmulatorConnection {
+            connection: Mutex::new(None),
+            address,
+            transport,
+            handle: Handle::try_current().unwrap_or_else(|_e| {
+                runtime
+                    .as_ref()
+                    .expect("Must pass a runtime if not in async context")
+                    .handle()
+                    .clone()
+            }),
+            runtime,
+            key,
+            compile_time: None,
+            secp,
+            keys: Default::default(),
+        })
+    }
+    /// The oracle's keys, fetched the first time they are needed and kept
+    /// until `refresh_keys` is called. An oracle which can't be reached is
+    /// assumed to have only the key it is known by, e.g. so that a
+    /// federation can compile with a member down, but is asked again the
+    /// next time.
+    pub fn get_keys(&self) -> Result<Vec<KeyValidity>, std::io::Error> {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(keys) = keys.as_ref() {
+            return Ok(keys.clone());
+        }
+        match self.fetch_keys() {
+            Ok(fetched) => Ok(keys.insert(fetched).clone()),
+            Err(FetchError::Unreachable) => Ok(vec![KeyValidity {
+                key: self.key,
+                valid_from: 0,
+                valid_until: None,
+            }]),
+            Err(FetchError::Invalid(e)) => Err(e),
+        }
+    }
+    /// fetch the oracle's keys again, e.g. after it rotates them
+    pub fn refresh_keys(&self) -> Result<Vec<KeyValidity>, std::io::Error> {
+        *self.keys.lock().unwrap() = None;
+        self.get_keys()
+    }
+    /// the oracle's keys, checking they are signed by the key it is known by
+    fn fetch_keys(&self) -> Result<Vec<KeyValidity>, FetchError> {
+        let fetched: msgs::KeysResponse = tokio::task::block_in_place(|| {
+            self.handle.block_on(round_trip(
+                &self.connection,
+                &self.transport,
+                &self.address,
+                &msgs::Request::GetKeys,
+            ))
+        })
+        .map_err(|_| FetchError::Unreachable)?;
+        match fetched {
+            Ok(signed) => {
+                self.secp
+                    .verify_schnorr(
+                        &signed.signature,
+                        &SignedKeys::digest(&signed.keys),
+                        &self.key,
+                    )
+                    .map_err(|_| {
+                        FetchError::Invalid(input_err("Oracle's Keys Not Signed by Its Key"))
+                    })?;
+                Ok(signed.keys)
+            }
+            Err(e) => Err(FetchError::Invalid(input_err(&e))),
+        }
+    }
+    /// The clause for the template with hash `h` compiled at `time`: the
+    /// oracle's key for it, or any of them while keys overlap
+    pub fn signer_at(&self, h: Sha256, time: u64) -> Result<Clause, EmulatorError> {
+        let mut keys = self
+            .get_keys()?
+            .into_iter()
+            .filter(|k| k.valid_at(time))
+            .map(|k| Ok(Clause::Key(template_key(&k.key, h, &self.secp)?)))
+            .collect::<Result<Vec<Clause>, std::io::Error>>()?;
+        match keys.len() {
+            0 => Err(input_err(&format!("No Oracle Key Valid at {}", time)).into()),
+            1 => Ok(keys.remove(0)),
+            _ => Ok(Clause::Threshold(1, keys)),
+        }
+    }
+    /// the oracle's audit log from `start` up to `end`, see
+    /// `connections::audit`
+    pub fn audit_log(&self, start: u64, end: u64) -> Result<AuditRange, std::io::Error> {
+        let range: msgs::AuditLogResponse = tokio::task::block_in_place(|| {
+            self.handle.block_on(round_trip(
+                &self.connection,
+                &self.transport,
+                &self.address,
+                &msgs::Request::AuditLog { start, end },
+            ))
+        })?;
+        range.map_err(|e| input_err(&e))
+    }
+    /// the oracle's signatures for `b`, added to it
+    pub(crate) async fn sign_async(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, std::io::Error> {
+        let signed: msgs::PSBT = round_trip(
+            &self.connection,
+            &self.transport,
+            &self.address,
+            &msgs::Request::SignPSBT(msgs::PSBT(b.clone())),
+        )
+        .await?;
+        hd::HDOracleEmulatorConnection::combine(b, signed.0)
+    }
+}
+
+/// Why an oracle's keys couldn't be fetched
+enum FetchError {
+    /// the oracle couldn't be asked
+    Unreachable,
+    /// the oracle answered with an error, or keys it didn't sign
+    Invalid(std::io::Error),
+}
+
+impl CTVEmulator for KeyOracleEmulatorConnection {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        let time = self.compile_time.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs())
+        });
+        self.signer_at(h, time)
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Ok(tokio::task::block_in_place(|| {
+            self.handle.block_on(self.sign_async(b))
+        })?)
+    }
+    fn describe(&self) -> Option<String> {
+        Some(format!("key oracle {} at {}", self.key, self.address))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::servers::key::{KeyOracleEmulator, SignedRecord};
+    use bitcoin::psbt::PartiallySignedTransaction;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{KeyPair, Script, Transaction, TxIn, TxOut};
+
+    fn keypair(seed: u8) -> KeyPair {
+        SECP.with(|secp| KeyPair::from_seckey_slice(secp, &[seed; 32]).unwrap())
+    }
+
+    /// a PSBT spending a coin sent to `key` to a `value` output
+    fn spending(key: &Clause, value: u64) -> PartiallySignedTransaction {
+        let key = match key {
+            Clause::Key(k) => *k,
+            _ => unreachable!(),
+        };
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        b.inputs[0].witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: SECP.with(|secp| Script::new_v1_p2tr(secp, key, None)),
+        });
+        b
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn rotated_keys() {
+        let oracle = KeyOracleEmulator::new(keypair(1), false);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(oracle.clone().listen(listener));
+        let connection = KeyOracleEmulatorConnection::new(
+            address,
+            oracle.public_key(),
+            Transport::default(),
+            None,
+            Arc::new(Secp256k1::new()),
+        )
+        .unwrap();
+        let h = spending(&Clause::Key(oracle.public_key()), 1000)
+            .extract_tx()
+            .get_ctv_hash(0);
+        let old = connection.signer_at(h, 100).unwrap();
+        assert!(connection.sign(spending(&old, 1000)).unwrap().inputs[0]
+            .tap_key_sig
+            .is_some());
+        oracle.rotate(keypair(2), 200, 100);
+        // the first key is cached until it is refreshed
+        assert_eq!(connection.signer_at(h, 350).unwrap(), old);
+        assert_eq!(connection.refresh_keys().unwrap().len(), 2);
+        assert_eq!(connection.signer_at(h, 100).unwrap(), old);
+        let new = connection.signer_at(h, 350).unwrap();
+        assert_ne!(new, old);
+        assert_eq!(
+            connection.signer_at(h, 250).unwrap(),
+            Clause::Threshold(1, vec![old.clone(), new.clone()])
+        );
+        // the retired key still signs anything, as contracts compiled before
+        // the rotation may not have been signed yet
+        for key in [&old, &new] {
+            let signed = connection.sign(spending(key, 1000)).unwrap();
+            assert!(signed.inputs[0].tap_key_sig.is_some());
+        }
+        let other = spending(&old, 500).extract_tx().get_ctv_hash(0);
+        let old_other = connection.signer_at(other, 100).unwrap();
+        let signed = connection.sign(spending(&old_other, 500)).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        // once revoked, it only signs what it signed before
+        oracle.revoke(oracle.public_key());
+        let signed = connection.sign(spending(&old, 1000)).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+        let third = spending(&old, 400).extract_tx().get_ctv_hash(0);
+        let old_third = connection.signer_at(third, 100).unwrap();
+        let signed = connection.sign(spending(&old_third, 400)).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_none());
+        let new_third = connection.signer_at(third, 350).unwrap();
+        let signed = connection.sign(spending(&new_third, 400)).unwrap();
+        assert!(signed.inputs[0].tap_key_sig.is_some());
+    }
+
+    #[test]
+    fn signed_record_survives_restarts() {
+        let path = std::env::temp_dir().join(format!(
+            "sapio-signed-record-{}-{}",
+            std::process::id(),
+            rand::random::<u64>()
+        ));
+        let h = Sha256::hash(b"template");
+        let key = XOnlyPublicKey::from_keypair(&keypair(1)).0;
+        let record = SignedRecord::open(path.clone()).unwrap();
+        assert!(!record.contains(&key, h));
+        record.insert(key, h).unwrap();
+        record.insert(key, h).unwrap();
+        let reopened = SignedRecord::open(path.clone()).unwrap();
+        assert!(reopened.contains(&key, h));
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+}
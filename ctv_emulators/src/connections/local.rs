@@ -0,0 +1,173 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An in-process emulator, for developing against regtest without running
+//! an oracle server
+
+use super::*;
+use crate::servers::hd::HDOracleEmulator;
+use bitcoin::Network;
+use miniscript::descriptor::{Descriptor, DescriptorSecretKey};
+
+/// A hot wallet emulator: signs templates directly with a key held in this
+/// process, the same keys an `HDOracleEmulator` with the same root would.
+///
+/// DEV ONLY. Anyone with the seed can sign any template, so it refuses to be
+/// created for mainnet.
+pub struct LocalEmulator {
+    oracle: HDOracleEmulator,
+    root: ExtendedPubKey,
+}
+
+impl LocalEmulator {
+    /// the seed `LocalEmulator::for_tests` uses
+    pub const TEST_SEED: [u8; 32] = [0x5a; 32];
+    /// An emulator with the master key of `seed`, e.g. the contents of an
+    /// `emulator server` seed file
+    pub fn from_seed(network: Network, seed: &[u8]) -> Result<Self, EmulatorError> {
+        Self::from_xpriv(ExtendedPrivKey::new_master(network, seed)?)
+    }
+    /// An emulator with the extended private key of a descriptor, e.g. one
+    /// of a bitcoind wallet's from `listdescriptors true`, derived along its
+    /// path up to any wildcard. The descriptor must have exactly one.
+    pub fn from_descriptor(descriptor: &str) -> Result<Self, EmulatorError> {
+        let (_, keys) = SECP
+            .with(|secp| Descriptor::parse_descriptor(secp, descriptor))
+            .map_err(|e| input_err(&e.to_string()))?;
+        let mut xprvs = keys.into_values().filter_map(|k| match k {
+            DescriptorSecretKey::XPrv(x) => Some(x),
+            DescriptorSecretKey::SinglePriv(_) => None,
+        });
+        match (xprvs.next(), xprvs.next()) {
+            (Some(x), None) => {
+                Self::from_xpriv(SECP.with(|secp| x.xkey.derive_priv(secp, &x.derivation_path))?)
+            }
+            _ => Err(input_err("Expected One Extended Private Key in the Descriptor").into()),
+        }
+    }
+    /// An emulator with the root `xpriv`
+    pub fn from_xpriv(xpriv: ExtendedPrivKey) -> Result<Self, EmulatorError> {
+        if xpriv.network == Network::Bitcoin {
+            return Err(
+                input_err("The Local Emulator is for Development Only, not Mainnet").into(),
+            );
+        }
+        let root = SECP.with(|secp| ExtendedPubKey::from_priv(secp, &xpriv));
+        Ok(LocalEmulator {
+            oracle: HDOracleEmulator::new(xpriv, false),
+            root,
+        })
+    }
+    /// An emulator with the fixed `TEST_SEED` on regtest, so that tests
+    /// compile to the same addresses on every run
+    pub fn for_tests() -> Self {
+        Self::from_seed(Network::Regtest, &Self::TEST_SEED).expect("Regtest is not Mainnet")
+    }
+    /// the root key, which an `HDOracleEmulatorConnection` to an oracle with
+    /// the same seed would be created with
+    pub fn root(&self) -> ExtendedPubKey {
+        self.root
+    }
+}
+
+impl CTVEmulator for LocalEmulator {
+    fn get_signer_for(&self, h: Sha256) -> Result<Clause, EmulatorError> {
+        SECP.with(|secp| {
+            Ok(Clause::Key(
+                self.root
+                    .derive_pub(secp, &hash_to_child_vec(h))?
+                    .to_x_only_pub(),
+            ))
+        })
+    }
+    fn sign(
+        &self,
+        b: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, EmulatorError> {
+        Ok(SECP.with(|secp| servers::sign(&self.oracle, b, secp))?)
+    }
+    fn describe(&self) -> Option<String> {
+        Some(format!("local emulator {}", self.root))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::util::sighash::{Prevouts, SighashCache};
+    use bitcoin::{Script, Transaction, TxIn, TxOut};
+
+    #[test]
+    fn local_emulator_from_descriptor() {
+        // a tprv is read back as testnet's
+        let xpriv = ExtendedPrivKey::new_master(Network::Testnet, &[1; 32]).unwrap();
+        let path: DerivationPath = "m/86'/1'/0'/0".parse().unwrap();
+        let derived = SECP.with(|secp| xpriv.derive_priv(secp, &path)).unwrap();
+        let expected = LocalEmulator::from_xpriv(derived).unwrap().root();
+        let descriptor = format!("tr({}/86'/1'/0'/0/*)", xpriv);
+        assert_eq!(
+            LocalEmulator::from_descriptor(&descriptor).unwrap().root(),
+            expected
+        );
+        // a bitcoind wallet's, with origin and checksum
+        let (parsed, keys) = SECP
+            .with(|secp| Descriptor::parse_descriptor(secp, &descriptor))
+            .unwrap();
+        let with_checksum = parsed.to_string_with_secret(&keys);
+        assert!(with_checksum.contains('#'));
+        assert_eq!(
+            LocalEmulator::from_descriptor(&with_checksum)
+                .unwrap()
+                .root(),
+            expected
+        );
+        // only public keys, two private keys, or a mainnet key are refused
+        let xpub = SECP.with(|secp| ExtendedPubKey::from_priv(secp, &xpriv));
+        assert!(LocalEmulator::from_descriptor(&format!("tr({}/0/*)", xpub)).is_err());
+        let other = ExtendedPrivKey::new_master(Network::Testnet, &[2; 32]).unwrap();
+        let two = format!("wsh(multi(1,{}/0/*,{}/0/*))", xpriv, other);
+        assert!(LocalEmulator::from_descriptor(&two).is_err());
+        let mainnet = ExtendedPrivKey::new_master(Network::Bitcoin, &[1; 32]).unwrap();
+        assert!(LocalEmulator::from_descriptor(&format!("tr({}/0/*)", mainnet)).is_err());
+    }
+
+    #[test]
+    fn local_emulator_signs() {
+        let emulator = LocalEmulator::for_tests();
+        assert_eq!(emulator.root(), LocalEmulator::for_tests().root());
+        assert!(LocalEmulator::from_seed(Network::Bitcoin, &LocalEmulator::TEST_SEED).is_err());
+
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let key = match emulator.get_signer_for(tx.get_ctv_hash(0)).unwrap() {
+            Clause::Key(key) => key,
+            c => panic!("expected a key, got {}", c),
+        };
+        let utxo = TxOut {
+            value: 2000,
+            script_pubkey: SECP.with(|secp| Script::new_v1_p2tr(secp, key, None)),
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx.clone()).unwrap();
+        b.inputs[0].witness_utxo = Some(utxo.clone());
+        let b = emulator.sign(b).unwrap();
+
+        let sig = b.inputs[0].tap_key_sig.expect("signed for the key path");
+        let sighash = SighashCache::new(&tx)
+            .taproot_key_spend_signature_hash(0, &Prevouts::All(&[utxo.clone()]), sig.hash_ty)
+            .unwrap();
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..]).unwrap();
+        let output_key = XOnlyPublicKey::from_slice(&utxo.script_pubkey[2..]).unwrap();
+        SECP.with(|secp| secp.verify_schnorr(&sig.sig, &msg, &output_key))
+            .expect("signature is valid for the coin's key");
+    }
+}
@@ -7,5 +7,60 @@
 //! Connections to emulators
 
 use super::*;
+pub mod audit;
+pub mod cache;
 pub mod federated;
 pub mod hd;
+pub mod key;
+pub mod local;
+pub mod transport;
+use transport::Transport;
+
+/// send a request via `connection` and receive its response, connecting to
+/// `address` over `transport` first if needed. A connection which fails is
+/// dropped, so that the next request reconnects.
+async fn round_trip<T: DeserializeOwned + Clone>(
+    connection: &tokio::sync::Mutex<Option<TcpStream>>,
+    transport: &Transport,
+    address: &str,
+    r: &msgs::Request,
+) -> Result<T, std::io::Error> {
+    let mut mconn = connection.lock().await;
+    loop {
+        if let Some(conn) = &mut *mconn {
+            let response = async {
+                request(conn, r).await?;
+                conn.flush().await?;
+                response::<T>(conn).await
+            }
+            .await;
+            if response.is_err() {
+                *mconn = None;
+            }
+            return response;
+        } else {
+            *mconn = Some(transport.connect(address).await?);
+        }
+    }
+}
+
+/// make a request via the tcpstream.
+/// wire format: length:u32 data:[u8;length]
+async fn request(t: &mut TcpStream, r: &msgs::Request) -> Result<(), std::io::Error> {
+    let v = serde_json::to_vec(r)?;
+    t.write_u32(v.len() as u32).await?;
+    t.write_all(&v[..]).await
+}
+
+/// receive a response via the tcpstream.
+/// wire format: length:u32 data:[u8;length]
+///
+/// TODO: secure response by limiting the length to a max value.
+/// This is not super critical because presumably the oracles are not trying to OOM your system.
+async fn response<T: DeserializeOwned + Clone>(t: &mut TcpStream) -> Result<T, std::io::Error> {
+    let l = t.read_u32().await? as usize;
+    let mut v = vec![0u8; l];
+    t.read_exact(&mut v[..]).await?;
+    let t: T = serde_json::from_slice::<T>(&v[..])?;
+    Ok(t)
+}
@@ -0,0 +1,333 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! How connections to oracles are made, directly or through a SOCKS5 proxy
+//! such as Tor
+
+use super::*;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How to connect to an oracle, e.g. from a sapio-cli config file
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Transport {
+    /// a SOCKS5 proxy to connect through, e.g. Tor's `127.0.0.1:9050`.
+    /// Required for `.onion` addresses.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub socks5: Option<String>,
+    /// if set, connections are only made through `socks5`, and a proxy is
+    /// required
+    #[serde(default)]
+    pub tor_only: bool,
+    /// if set, and not `tor_only`, connect directly when `socks5` can't be
+    /// reached, rather than failing. Off by default, as it reveals the
+    /// oracles used to the network.
+    #[serde(default)]
+    pub clearnet_fallback: bool,
+    /// how long to wait for each attempt to connect, in milliseconds
+    #[serde(default = "Transport::default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// how many more attempts to make if connecting fails
+    #[serde(default)]
+    pub retries: u32,
+}
+
+impl Default for Transport {
+    fn default() -> Self {
+        Transport {
+            socks5: None,
+            tor_only: false,
+            clearnet_fallback: false,
+            connect_timeout_ms: Self::default_connect_timeout_ms(),
+            retries: 0,
+        }
+    }
+}
+
+impl Transport {
+    fn default_connect_timeout_ms() -> u64 {
+        10_000
+    }
+    /// Checks that `address` is a `host:port` this transport can connect
+    /// to. Onion addresses must be v3 and need a `socks5` proxy, and
+    /// `tor_only` needs one too.
+    pub fn validate(&self, address: &str) -> Result<(), std::io::Error> {
+        let (host, _) = split_address(address)?;
+        if self.tor_only && self.socks5.is_none() {
+            return Err(input_err("tor_only Requires a SOCKS5 Proxy"));
+        }
+        if let Some(name) = host.strip_suffix(".onion") {
+            let v3 = name.len() == 56
+                && name
+                    .bytes()
+                    .all(|c| c.is_ascii_lowercase() || (b'2'..=b'7').contains(&c));
+            if !v3 {
+                return Err(input_err(&format!("Not a v3 Onion Address: {}", address)));
+            }
+            if self.socks5.is_none() {
+                return Err(input_err(&format!(
+                    "Onion Address {} Requires a SOCKS5 Proxy",
+                    address
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// connect to `address`, retrying and timing out as configured
+    pub(crate) async fn connect(&self, address: &str) -> Result<TcpStream, std::io::Error> {
+        let timeout = Duration::from_millis(self.connect_timeout_ms);
+        let mut attempts = 0;
+        loop {
+            let connected = tokio::time::timeout(timeout, self.connect_once(address))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "Timed Out Connecting",
+                    ))
+                });
+            match connected {
+                Err(_) if attempts < self.retries => attempts += 1,
+                connected => return connected,
+            }
+        }
+    }
+    async fn connect_once(&self, address: &str) -> Result<TcpStream, std::io::Error> {
+        let (host, port) = split_address(address)?;
+        match &self.socks5 {
+            Some(proxy) => match socks5_connect(proxy, host, port).await {
+                Err(_) if self.clearnet_fallback && !self.tor_only && !host.ends_with(".onion") => {
+                    TcpStream::connect(address).await
+                }
+                Err(e) => Err(e),
+                connected => connected,
+            },
+            None if self.tor_only => Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "Refusing to Connect Other Than Through Tor",
+            )),
+            None => TcpStream::connect(address).await,
+        }
+    }
+}
+
+/// the host and port of a `host:port` address
+fn split_address(address: &str) -> Result<(&str, u16), std::io::Error> {
+    let (host, port) = address
+        .rsplit_once(':')
+        .ok_or_else(|| input_err(&format!("No Port in Address {}", address)))?;
+    let port = port
+        .parse()
+        .map_err(|_| input_err(&format!("Bad Port in Address {}", address)))?;
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    Ok((host, port))
+}
+
+/// connect to `host:port` through the SOCKS5 proxy at `proxy`, which
+/// resolves `host` itself
+async fn socks5_connect(proxy: &str, host: &str, port: u16) -> Result<TcpStream, std::io::Error> {
+    let mut s = TcpStream::connect(proxy).await?;
+    // version 5, offering only no authentication
+    s.write_all(&[5, 1, 0]).await?;
+    let mut chosen = [0u8; 2];
+    s.read_exact(&mut chosen).await?;
+    if chosen != [5, 0] {
+        return Err(input_err("SOCKS5 Proxy Refused No Authentication"));
+    }
+    if host.len() > 255 {
+        return Err(input_err("Host Too Long for SOCKS5"));
+    }
+    // CONNECT to a domain name
+    let mut request = vec![5, 1, 0, 3, host.len() as u8];
+    request.extend(host.as_bytes());
+    request.extend(port.to_be_bytes());
+    s.write_all(&request).await?;
+    let mut reply = [0u8; 4];
+    s.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("SOCKS5 Proxy Could Not Connect, Reply {}", reply[1]),
+        ));
+    }
+    // skip the address the proxy bound
+    let bound = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => s.read_u8().await? as usize,
+        _ => return Err(input_err("Bad SOCKS5 Reply")),
+    };
+    let mut skipped = vec![0u8; bound + 2];
+    s.read_exact(&mut skipped).await?;
+    Ok(s)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::connections::hd::HDOracleEmulatorConnection;
+    use crate::connections::key::KeyOracleEmulatorConnection;
+    use crate::servers::key::KeyOracleEmulator;
+    use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+    use bitcoin::{KeyPair, Script, Transaction, TxIn, TxOut};
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use tokio::net::TcpListener;
+
+    const ONION: &str = "abcdefghijklmnopqrstuvwxyz234567abcdefghijklmnopqrstuvwx.onion";
+
+    /// a SOCKS5 proxy which records each CONNECT target, connecting every
+    /// one of them to `upstream`
+    async fn proxy(upstream: SocketAddr) -> (String, Arc<Mutex<Vec<(String, u16)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let targets: Arc<Mutex<Vec<(String, u16)>>> = Default::default();
+        let recorded = targets.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut s, _) = listener.accept().await.unwrap();
+                let targets = targets.clone();
+                tokio::spawn(async move {
+                    let mut greeting = [0u8; 3];
+                    s.read_exact(&mut greeting).await.unwrap();
+                    s.write_all(&[5, 0]).await.unwrap();
+                    let mut request = [0u8; 5];
+                    s.read_exact(&mut request).await.unwrap();
+                    assert_eq!(request[..4], [5, 1, 0, 3]);
+                    let mut host = vec![0u8; request[4] as usize];
+                    s.read_exact(&mut host).await.unwrap();
+                    let port = s.read_u16().await.unwrap();
+                    targets
+                        .lock()
+                        .unwrap()
+                        .push((String::from_utf8(host).unwrap(), port));
+                    s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+                    let mut oracle = TcpStream::connect(upstream).await.unwrap();
+                    let _ = tokio::io::copy_bidirectional(&mut s, &mut oracle).await;
+                });
+            }
+        });
+        (address, recorded)
+    }
+
+    /// a running oracle, and its key
+    async fn oracle() -> (SocketAddr, XOnlyPublicKey) {
+        let key = SECP.with(|secp| KeyPair::from_seckey_slice(secp, &[9; 32]).unwrap());
+        let oracle = KeyOracleEmulator::new(key, false);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let running = (listener.local_addr().unwrap(), oracle.public_key());
+        tokio::spawn(oracle.listen(listener));
+        running
+    }
+
+    fn unsigned() -> PartiallySignedTransaction {
+        let tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: 1000,
+                script_pubkey: Script::new(),
+            }],
+        };
+        let mut b = PartiallySignedTransaction::from_unsigned_tx(tx).unwrap();
+        b.inputs[0].witness_utxo = Some(TxOut {
+            value: 2000,
+            script_pubkey: Script::new(),
+        });
+        b
+    }
+
+    fn connection(
+        address: &str,
+        key: XOnlyPublicKey,
+        transport: Transport,
+    ) -> Result<KeyOracleEmulatorConnection, std::io::Error> {
+        KeyOracleEmulatorConnection::new(
+            address.into(),
+            key,
+            transport,
+            None,
+            Arc::new(Secp256k1::new()),
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn socks5_transport() {
+        let (upstream, key) = oracle().await;
+        let (socks5, targets) = proxy(upstream).await;
+        let tor = Transport {
+            socks5: Some(socks5.clone()),
+            tor_only: true,
+            ..Default::default()
+        };
+        let onion = format!("{}:8367", ONION);
+        connection(&onion, key, tor.clone())
+            .unwrap()
+            .sign(unsigned())
+            .unwrap();
+        assert_eq!(*targets.lock().unwrap(), vec![(ONION.to_string(), 8367)]);
+
+        // onions need a proxy, and must be v3
+        assert!(connection(&onion, key, Transport::default()).is_err());
+        assert!(connection("short.onion:8367", key, tor.clone()).is_err());
+        // which an HD oracle is given up front too
+        let secp = Arc::new(Secp256k1::new());
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[1; 32]).unwrap();
+        let root = ExtendedPubKey::from_priv(&secp, &xpriv);
+        let hd = |transport| {
+            HDOracleEmulatorConnection::new(onion.clone(), root, None, secp.clone(), transport)
+        };
+        assert!(hd(tor.clone()).await.is_ok());
+        assert!(hd(Transport::default()).await.is_err());
+        // as does tor_only
+        let no_proxy = Transport {
+            tor_only: true,
+            ..Default::default()
+        };
+        assert!(connection(&upstream.to_string(), key, no_proxy).is_err());
+
+        // with the proxy down, clear-net is only used if asked for, and not
+        // with tor_only
+        let down = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        for refused in [
+            Transport {
+                socks5: Some(down.clone()),
+                tor_only: true,
+                clearnet_fallback: true,
+                retries: 1,
+                ..Default::default()
+            },
+            Transport {
+                socks5: Some(down.clone()),
+                ..Default::default()
+            },
+        ] {
+            assert!(connection(&upstream.to_string(), key, refused)
+                .unwrap()
+                .sign(unsigned())
+                .is_err());
+        }
+        let fallback = Transport {
+            socks5: Some(down),
+            clearnet_fallback: true,
+            ..Default::default()
+        };
+        connection(&upstream.to_string(), key, fallback)
+            .unwrap()
+            .sign(unsigned())
+            .unwrap();
+        assert_eq!(targets.lock().unwrap().len(), 1);
+    }
+}
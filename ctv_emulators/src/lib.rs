@@ -8,19 +8,21 @@
 //! concrete emulators for CTV
 
 use bitcoin::hashes::sha256::Hash as Sha256;
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::{Hash, HashEngine};
 use bitcoin::util::bip32::*;
 use sapio_ctv_emulator_trait::Clause;
-pub use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError, NullEmulator};
+pub use sapio_ctv_emulator_trait::{
+    CTVAvailable, CTVEmulator, EmulatorError, EmulatorRequest, EmulatorResponse, NullEmulator,
+};
 use serde::de::DeserializeOwned;
 use serde::Serialize;
 
-use std::net::SocketAddr;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
 
-use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::secp256k1::{All, Scalar, Secp256k1};
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::XOnlyPublicKey;
 
 use sapio_base::CTVHash;
 use std::sync::Arc;
@@ -43,6 +45,31 @@ fn input_err(s: &str) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::InvalidInput, s)
 }
 
+/// The tweak which commits a fixed oracle key to the template with hash `h`,
+/// so that the oracle's key for each template is distinct.
+///
+/// This is the `KeyOracleEmulator`'s counterpart to `hash_to_child_vec`, for
+/// oracles known by an `XOnlyPublicKey` rather than an `ExtendedPubKey`.
+fn template_tweak(key: &XOnlyPublicKey, h: Sha256) -> Result<Scalar, std::io::Error> {
+    let mut engine = Sha256::engine();
+    engine.input(&key.serialize());
+    engine.input(&h.into_inner());
+    Scalar::from_be_bytes(Sha256::from_engine(engine).into_inner())
+        .map_err(|_| input_err("Template Tweak Out of Range"))
+}
+
+/// The key `key`'s oracle signs for the template with hash `h` with
+fn template_key(
+    key: &XOnlyPublicKey,
+    h: Sha256,
+    secp: &Secp256k1<All>,
+) -> Result<XOnlyPublicKey, std::io::Error> {
+    Ok(key
+        .add_tweak(secp, &template_tweak(key, h)?)
+        .map_err(|_| input_err("Could Not Tweak Key"))?
+        .0)
+}
+
 /// Compute a derivation path from a sha256 hash.
 ///
 /// Format is a bit peculiar, it's 9 u32's with the top bit as 0 (for unhardened
@@ -23,8 +23,32 @@ pub struct PSBT(pub PartiallySignedTransaction);
 #[derive(Serialize, Deserialize)]
 pub enum Request {
     SignPSBT(PSBT),
+    /// sign each of the PSBTs, answered with a `BatchResponse`
+    SignBatch(Vec<PSBT>),
+    /// the keys the oracle signs with, answered with a `KeysResponse`
+    GetKeys,
+    /// the audit log's entries from `start` up to `end`, answered with an
+    /// `AuditLogResponse`
+    AuditLog {
+        start: u64,
+        end: u64,
+    },
 }
 
+/// The most PSBTs a `Request::SignBatch` may have. Clients split larger
+/// batches, and servers refuse them.
+pub const MAX_BATCH: usize = 64;
+
+/// The response to a `Request::SignBatch`, with each PSBT signed or why it
+/// couldn't be
+pub type BatchResponse = Vec<Result<PSBT, String>>;
+
+/// The response to a `Request::GetKeys`
+pub type KeysResponse = Result<crate::servers::key::SignedKeys, String>;
+
+/// The response to a `Request::AuditLog`
+pub type AuditLogResponse = Result<crate::servers::log::AuditRange, String>;
+
 /// A visitor tage for a SafePSBT type that is size limited
 /// Serialized/deserialized with a size tag internally.
 struct SafePSBT(usize);
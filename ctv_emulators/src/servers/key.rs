@@ -0,0 +1,248 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! an oracle server with fixed keys, for members of a federation
+use super::*;
+use bitcoin::secp256k1::schnorr::Signature;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// When clients should put one of an oracle's keys in new contracts, in
+/// seconds since the unix epoch
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyValidity {
+    /// the key, before it is tweaked for each template
+    pub key: XOnlyPublicKey,
+    /// the first time it should be used
+    pub valid_from: u64,
+    /// the time it should no longer be used from, if it has been retired
+    pub valid_until: Option<u64>,
+}
+
+impl KeyValidity {
+    /// if the key should be used for contracts compiled at `time`
+    pub fn valid_at(&self, time: u64) -> bool {
+        self.valid_from <= time && self.valid_until.is_none_or(|until| time < until)
+    }
+}
+
+/// An oracle's keys, signed by the key it is known by, as returned for
+/// `Request::GetKeys`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct SignedKeys {
+    /// every key the oracle has had, oldest first
+    pub keys: Vec<KeyValidity>,
+    /// the signature of the key the oracle is known by on `SignedKeys::digest`
+    pub signature: Signature,
+}
+
+impl SignedKeys {
+    /// the message `signature` signs
+    pub fn digest(keys: &[KeyValidity]) -> bitcoin::secp256k1::Message {
+        let h = Sha256::hash(&serde_json::to_vec(keys).expect("keys always serialize"));
+        bitcoin::secp256k1::Message::from_digest_slice(&h[..]).expect("Size must be correct.")
+    }
+}
+
+/// An oracle emulator known by a single `XOnlyPublicKey`, signing for each
+/// template with its key tweaked by the template's hash.
+///
+/// Its key may be rotated, after which it tells clients to use the new key
+/// for new contracts. A retired key keeps signing any template, as
+/// contracts compiled for it before the rotation may not have been signed
+/// yet. If a key has leaked it can be revoked, after which it only signs the
+/// templates it signed before, as kept in the `SignedRecord`.
+#[derive(Clone)]
+pub struct KeyOracleEmulator {
+    key: KeyPair,
+    debug: bool,
+    log: Option<Arc<AuditLog>>,
+    rotations: Arc<Mutex<Vec<(KeyPair, KeyValidity)>>>,
+    revoked: Arc<Mutex<BTreeSet<XOnlyPublicKey>>>,
+    signed: Arc<SignedRecord>,
+}
+
+/// Which templates each of an oracle's keys has signed, kept in memory and
+/// optionally appended to a file, one JSON `(key, template hash)` per line
+#[derive(Default)]
+pub struct SignedRecord {
+    signed: Mutex<BTreeSet<(XOnlyPublicKey, Sha256)>>,
+    path: Option<PathBuf>,
+}
+
+impl SignedRecord {
+    /// a record kept only in memory
+    pub fn new() -> Self {
+        Default::default()
+    }
+    /// a record appended to the file at `path`, continuing what is already in
+    /// it
+    pub fn open(path: PathBuf) -> Result<Self, std::io::Error> {
+        let signed = match std::fs::read_to_string(&path) {
+            Ok(s) => s
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeSet::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(SignedRecord {
+            signed: Mutex::new(signed),
+            path: Some(path),
+        })
+    }
+    /// if `key` has signed the template with hash `h`
+    pub fn contains(&self, key: &XOnlyPublicKey, h: Sha256) -> bool {
+        self.signed.lock().unwrap().contains(&(*key, h))
+    }
+    /// record that `key` signed the template with hash `h`
+    pub fn insert(&self, key: XOnlyPublicKey, h: Sha256) -> Result<(), std::io::Error> {
+        let mut signed = self.signed.lock().unwrap();
+        if signed.contains(&(key, h)) {
+            return Ok(());
+        }
+        if let Some(path) = &self.path {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&(key, h))?)?;
+        }
+        signed.insert((key, h));
+        Ok(())
+    }
+}
+
+impl KeyOracleEmulator {
+    /// create a new KeyOracleEmulator
+    ///
+    /// if debug is set, runs in a "single threaded" mode where we can observe errors on connections rather than ignoring them.
+    pub fn new(key: KeyPair, debug: bool) -> Self {
+        let validity = KeyValidity {
+            key: XOnlyPublicKey::from_keypair(&key).0,
+            valid_from: 0,
+            valid_until: None,
+        };
+        KeyOracleEmulator {
+            key,
+            debug,
+            log: None,
+            rotations: Arc::new(Mutex::new(vec![(key, validity)])),
+            revoked: Default::default(),
+            signed: Default::default(),
+        }
+    }
+    /// keep which templates each key signed in `record`, so that it survives
+    /// restarts for keys revoked later
+    pub fn with_signed_record(mut self, record: Arc<SignedRecord>) -> Self {
+        self.signed = record;
+        self
+    }
+    /// record everything signed in `log`, which clients can fetch ranges of
+    /// to audit this oracle, see `connections::audit`
+    pub fn with_audit_log(mut self, log: Arc<AuditLog>) -> Self {
+        self.log = Some(log);
+        self
+    }
+    /// the key clients should know this oracle by
+    pub fn public_key(&self) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_keypair(&self.key).0
+    }
+    /// Start using `key` for contracts compiled from `at`, retiring the
+    /// current key `overlap` seconds later. Until then clients use either.
+    ///
+    /// Takes effect for every clone of this oracle, including a running one.
+    pub fn rotate(&self, key: KeyPair, at: u64, overlap: u64) {
+        let mut rotations = self.rotations.lock().unwrap();
+        if let Some((_, current)) = rotations.last_mut() {
+            current.valid_until = Some(at + overlap);
+        }
+        let validity = KeyValidity {
+            key: XOnlyPublicKey::from_keypair(&key).0,
+            valid_from: at,
+            valid_until: None,
+        };
+        rotations.push((key, validity));
+    }
+    /// Stop `key` signing any template it has not already signed, e.g. if it
+    /// has leaked. Contracts compiled for it but not yet signed can no longer
+    /// be spent through this oracle.
+    ///
+    /// Takes effect for every clone of this oracle, including a running one.
+    pub fn revoke(&self, key: XOnlyPublicKey) {
+        self.revoked.lock().unwrap().insert(key);
+    }
+    /// binds a KeyOracleEmulator to a socket interface and runs the server,
+    /// see `HDOracleEmulator::bind`
+    pub async fn bind<A: ToSocketAddrs>(self, a: A) -> std::io::Result<()> {
+        self.listen(TcpListener::bind(a).await?).await
+    }
+    /// runs the server on an already bound `listener`
+    pub async fn listen(self, listener: TcpListener) -> std::io::Result<()> {
+        let debug = self.debug;
+        serve(self, debug, listener).await
+    }
+    /// serves the audit log over HTTP on `listener`, see `log::serve_http`
+    pub async fn listen_audit_log(self, listener: TcpListener) -> std::io::Result<()> {
+        let log = self.log.ok_or_else(|| input_err("No Audit Log Kept"))?;
+        log::serve_http(log, self.key, listener).await
+    }
+}
+
+impl TemplateKeys for KeyOracleEmulator {
+    fn keypairs_for(
+        &self,
+        h: Sha256,
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<KeyPair>, std::io::Error> {
+        let revoked = self.revoked.lock().unwrap();
+        self.rotations
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, validity)| {
+                !revoked.contains(&validity.key) || self.signed.contains(&validity.key, h)
+            })
+            .map(|(key, validity)| {
+                key.add_xonly_tweak(secp, &template_tweak(&validity.key, h)?)
+                    .map_err(|_| input_err("Could Not Tweak Key"))
+            })
+            .collect()
+    }
+    fn record_signed(&self, h: Sha256) -> Result<(), std::io::Error> {
+        let revoked = self.revoked.lock().unwrap();
+        let keys: Vec<XOnlyPublicKey> = self
+            .rotations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, validity)| validity.key)
+            .filter(|key| !revoked.contains(key))
+            .collect();
+        keys.into_iter()
+            .try_for_each(|key| self.signed.insert(key, h))
+    }
+    fn signed_keys(&self, secp: &Secp256k1<All>) -> Option<SignedKeys> {
+        let keys: Vec<KeyValidity> = self
+            .rotations
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(_, v)| *v)
+            .collect();
+        let signature = secp.sign_schnorr_no_aux_rand(&SignedKeys::digest(&keys), &self.key);
+        Some(SignedKeys { keys, signature })
+    }
+    fn audit_log(&self) -> Option<&AuditLog> {
+        self.log.as_deref()
+    }
+    fn log_key(&self, _secp: &Secp256k1<All>) -> KeyPair {
+        self.key
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! An append-only log of everything an oracle signs, chained by hash so that
+//! clients can check it is never rewritten, see `connections::audit`
+use super::*;
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::secp256k1::Message;
+use bitcoin::OutPoint;
+use serde_derive::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// One template an oracle signed for
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogEntry {
+    /// the entry's position in the log, from 0
+    pub index: u64,
+    /// when it was signed, in seconds since the unix epoch
+    pub timestamp: u64,
+    /// the kind of request it was signed for, `sign_psbt` or `sign_batch`
+    pub request: String,
+    /// the coin the template spends
+    pub outpoint: OutPoint,
+    /// the template's hash
+    pub template_hash: Sha256,
+    /// the signatures the oracle gave, hex encoded
+    pub signatures: Vec<String>,
+    /// the `hash` of the entry before it, or all zeros for the first
+    pub previous: Sha256,
+}
+
+impl LogEntry {
+    /// The bytes `hash` is taken of: the index and timestamp as big endian
+    /// u64s, the request and each signature as a big endian u64 length and
+    /// its UTF-8, the outpoint in its consensus encoding, the template hash,
+    /// the number of signatures as a big endian u64, and the previous hash.
+    pub fn encode(&self) -> Vec<u8> {
+        let string = |out: &mut Vec<u8>, s: &str| {
+            out.extend((s.len() as u64).to_be_bytes());
+            out.extend(s.as_bytes());
+        };
+        let mut out = vec![];
+        out.extend(self.index.to_be_bytes());
+        out.extend(self.timestamp.to_be_bytes());
+        string(&mut out, &self.request);
+        self.outpoint
+            .consensus_encode(&mut out)
+            .expect("writing to a vec can't fail");
+        out.extend(self.template_hash.into_inner());
+        out.extend((self.signatures.len() as u64).to_be_bytes());
+        for signature in &self.signatures {
+            string(&mut out, signature);
+        }
+        out.extend(self.previous.into_inner());
+        out
+    }
+    /// the hash committing to this entry and, through `previous`, to every
+    /// entry before it
+    pub fn hash(&self) -> Sha256 {
+        Sha256::hash(&self.encode())
+    }
+}
+
+/// What `previous` is for the first entry
+pub fn genesis() -> Sha256 {
+    Sha256::from_inner([0; 32])
+}
+
+/// The entries of an oracle's log from `start`, with the head of the log at
+/// the time, as returned for `Request::AuditLog`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditRange {
+    /// the index of the first of `entries`
+    pub start: u64,
+    /// the entries requested, which may end before `length`
+    pub entries: Vec<LogEntry>,
+    /// how many entries the whole log has
+    pub length: u64,
+    /// the hash of the log's last entry
+    pub head: Sha256,
+    /// the signature of the key the oracle is known by on
+    /// `AuditRange::digest`, so that the oracle can't deny the head it gave
+    pub signature: Signature,
+}
+
+impl AuditRange {
+    /// the message `signature` signs, committing to the log's length and head
+    pub fn digest(length: u64, head: Sha256) -> Message {
+        let mut engine = Sha256::engine();
+        engine.input(b"sapio/audit-log-head");
+        engine.input(&length.to_be_bytes());
+        engine.input(&head.into_inner());
+        Message::from_digest_slice(&Sha256::from_engine(engine)[..]).expect("Size must be correct.")
+    }
+}
+
+/// An oracle's log, kept in memory and optionally appended to a file, one
+/// JSON entry per line
+pub struct AuditLog {
+    entries: Mutex<Vec<LogEntry>>,
+    path: Option<PathBuf>,
+}
+
+impl AuditLog {
+    /// a log kept only in memory
+    pub fn new() -> Self {
+        AuditLog {
+            entries: Default::default(),
+            path: None,
+        }
+    }
+    /// a log appended to the file at `path`, continuing the entries already
+    /// in it, which must be an unbroken chain
+    pub fn open(path: PathBuf) -> Result<Self, std::io::Error> {
+        let entries: Vec<LogEntry> = match std::fs::read_to_string(&path) {
+            Ok(s) => s
+                .lines()
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e),
+        };
+        let mut previous = genesis();
+        for (i, e) in entries.iter().enumerate() {
+            if e.index != i as u64 || e.previous != previous {
+                return Err(input_err(&format!("Audit Log Broken at Entry {}", i)));
+            }
+            previous = e.hash();
+        }
+        Ok(AuditLog {
+            entries: Mutex::new(entries),
+            path: Some(path),
+        })
+    }
+    /// record that `signed` was signed for a `request`
+    pub fn append(
+        &self,
+        request: &str,
+        unsigned: &PartiallySignedTransaction,
+        signed: &PartiallySignedTransaction,
+    ) -> Result<LogEntry, std::io::Error> {
+        let before = &unsigned.inputs[0];
+        let after = &signed.inputs[0];
+        let signatures = after
+            .tap_key_sig
+            .iter()
+            .filter(|_| before.tap_key_sig.is_none())
+            .chain(
+                after
+                    .tap_script_sigs
+                    .iter()
+                    .filter(|(k, _)| !before.tap_script_sigs.contains_key(k))
+                    .map(|(_, s)| s),
+            )
+            .map(|s| s.to_vec().to_hex())
+            .collect();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = LogEntry {
+            index: entries.len() as u64,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            request: request.into(),
+            outpoint: signed.unsigned_tx.input[0].previous_output,
+            template_hash: signed.unsigned_tx.get_ctv_hash(0),
+            signatures,
+            previous: entries.last().map_or_else(genesis, LogEntry::hash),
+        };
+        if let Some(path) = &self.path {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)?;
+            writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        }
+        entries.push(entry.clone());
+        Ok(entry)
+    }
+    /// the entries from `start` up to `end`, with the head signed by `key`,
+    /// the key the oracle is known by
+    pub fn range(&self, start: u64, end: u64, key: &KeyPair) -> AuditRange {
+        let entries = self.entries.lock().unwrap();
+        let length = entries.len() as u64;
+        let (from, to) = (start.min(length), end.min(length).max(start.min(length)));
+        let head = entries.last().map_or_else(genesis, LogEntry::hash);
+        let signature =
+            SECP.with(|secp| secp.sign_schnorr_no_aux_rand(&AuditRange::digest(length, head), key));
+        AuditRange {
+            start,
+            entries: entries[from as usize..to as usize].to_vec(),
+            length,
+            head,
+            signature,
+        }
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Serves `GET /audit-log?start=<index>&end=<index>` over HTTP/1.1 on
+/// `listener`, responding with the JSON `AuditRange` of `log` signed by `key`.
+///
+/// Each connection is answered once and closed.
+pub async fn serve_http(
+    log: Arc<AuditLog>,
+    key: KeyPair,
+    listener: TcpListener,
+) -> std::io::Result<()> {
+    loop {
+        let (socket, _) = listener.accept().await?;
+        let log = log.clone();
+        tokio::spawn(async move { respond_http(&log, &key, socket).await });
+    }
+}
+
+/// answers one HTTP request for `serve_http`
+async fn respond_http(log: &AuditLog, key: &KeyPair, mut t: TcpStream) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = t.read(&mut buf).await?;
+        if n == 0 || request.len() + n > 8 * 1024 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let (status, body) = match parse_http(&request) {
+        Some((start, end)) => ("200 OK", serde_json::to_vec(&log.range(start, end, key))?),
+        None => (
+            "400 Bad Request",
+            b"expected GET /audit-log?start=N&end=N".to_vec(),
+        ),
+    };
+    let content = if status == "200 OK" {
+        "application/json"
+    } else {
+        "text/plain"
+    };
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content,
+        body.len()
+    );
+    t.write_all(head.as_bytes()).await?;
+    t.write_all(&body).await?;
+    t.shutdown().await
+}
+
+/// the range requested by the request line of `request`, if it is a
+/// `GET /audit-log` with a `start` and `end`
+fn parse_http(request: &[u8]) -> Option<(u64, u64)> {
+    let line = std::str::from_utf8(request).ok()?.lines().next()?;
+    let mut parts = line.split(' ');
+    let (method, target) = (parts.next()?, parts.next()?);
+    let query = target
+        .strip_prefix("/audit-log?")
+        .filter(|_| method == "GET")?;
+    let (mut start, mut end) = (None, None);
+    for pair in query.split('&') {
+        match pair.split_once('=')? {
+            ("start", v) => start = Some(v.parse().ok()?),
+            ("end", v) => end = Some(v.parse().ok()?),
+            _ => {}
+        }
+    }
+    Some((start?, end?))
+}
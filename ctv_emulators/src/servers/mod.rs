@@ -7,4 +7,221 @@
 //! server for an emulator
 
 use super::*;
+use bitcoin::util::sighash::Prevouts;
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::util::taproot::TapSighashHash;
+use bitcoin::KeyPair;
+use bitcoin::SchnorrSig;
+use bitcoin::Script;
+use bitcoin::TxOut;
+use bitcoin::XOnlyPublicKey;
 pub mod hd;
+pub mod key;
+pub mod log;
+use log::AuditLog;
+
+/// How an oracle server finds the keys to sign a template with
+pub(crate) trait TemplateKeys: Clone + Send + Sync + 'static {
+    /// the keys to sign for the template with hash `h`
+    fn keypairs_for(
+        &self,
+        h: Sha256,
+        secp: &Secp256k1<All>,
+    ) -> Result<Vec<KeyPair>, std::io::Error>;
+    /// called once a signature has been produced for the template with hash
+    /// `h`, for servers which remember what they signed
+    fn record_signed(&self, _h: Sha256) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+    /// the keys clients may currently be using, signed, if the server has
+    /// any other than those derived per template
+    fn signed_keys(&self, _secp: &Secp256k1<All>) -> Option<key::SignedKeys> {
+        None
+    }
+    /// the log to record everything signed in, if any
+    fn audit_log(&self) -> Option<&AuditLog>;
+    /// the key the oracle is known by, which signs the head of its audit log
+    fn log_key(&self, secp: &Secp256k1<All>) -> KeyPair;
+}
+
+/// runs an oracle server on `listener`, signing with `keys`
+///
+/// This will only return when debug = false if The TcpListener fails.
+/// When debug = true, then we join each connection one at a time and return
+/// any errors.
+async fn serve<K: TemplateKeys>(
+    keys: K,
+    debug: bool,
+    listener: TcpListener,
+) -> std::io::Result<()> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        {
+            let keys = keys.clone();
+            let j: tokio::task::JoinHandle<Result<(), std::io::Error>> = tokio::spawn(async move {
+                loop {
+                    socket.readable().await?;
+                    handle(&keys, &mut socket).await?;
+                }
+            });
+            if debug {
+                tokio::join!(j).0??;
+            }
+        }
+    }
+}
+
+/// Signs a PSBT with the keys for its template.
+///
+/// Always signs for spending index 0. With more than one key, each only
+/// signs the leaves which contain it.
+///
+/// May fail to sign if the PSBT is not properly formatted
+pub(crate) fn sign<K: TemplateKeys>(
+    keys: &K,
+    mut b: PartiallySignedTransaction,
+    secp: &Secp256k1<All>,
+) -> Result<PartiallySignedTransaction, std::io::Error> {
+    let tx = b.clone().extract_tx();
+    let h = tx.get_ctv_hash(0);
+    let utxos: Vec<TxOut> = b
+        .inputs
+        .iter()
+        .map(|o| o.witness_utxo.clone())
+        .collect::<Option<Vec<TxOut>>>()
+        .ok_or_else(|| input_err("Could not find one of the UTXOs to be signed over"))?;
+    let keypairs = keys.keypairs_for(h, secp)?;
+    let mut sighash = bitcoin::util::sighash::SighashCache::new(&tx);
+    let input_zero = &mut b.inputs[0];
+    use bitcoin::schnorr::TapTweak;
+    let hash_ty = bitcoin::util::sighash::SchnorrSighashType::All;
+    let prevouts = &Prevouts::All(&utxos);
+    let mut produced = false;
+    let mut get_sig = |path, kp: &KeyPair| {
+        let annex = None;
+        let sighash: TapSighashHash = sighash
+            .taproot_signature_hash(0, prevouts, annex, path, hash_ty)
+            .expect("Signature hash cannot fail...");
+        let msg = bitcoin::secp256k1::Message::from_digest_slice(&sighash[..])
+            .expect("Size must be correct.");
+        let sig = secp.sign_schnorr_no_aux_rand(&msg, kp);
+        produced = true;
+        SchnorrSig { sig, hash_ty }
+    };
+    for untweaked in keypairs.iter() {
+        let pk = XOnlyPublicKey::from_keypair(untweaked);
+        let tweaked = untweaked
+            .tap_tweak(secp, input_zero.tap_merkle_root)
+            .into_inner();
+        let tweaked_pk = tweaked.public_key();
+        if let Some(true) = input_zero.witness_utxo.as_ref().map(|v| {
+            v.script_pubkey
+                == Script::new_v1_p2tr_tweaked(
+                    XOnlyPublicKey::from(tweaked_pk).dangerous_assume_tweaked(),
+                )
+        }) {
+            let sig = get_sig(None, &tweaked);
+            input_zero.tap_key_sig = Some(sig);
+        }
+        let serialized = pk.0.serialize();
+        for tlh in input_zero
+            .tap_scripts
+            .values()
+            .filter(|(script, _)| {
+                keypairs.len() == 1
+                    || script
+                        .as_bytes()
+                        .windows(serialized.len())
+                        .any(|w| w == serialized)
+            })
+            .map(|(script, ver)| TapLeafHash::from_script(script, *ver))
+        {
+            let sig = get_sig(Some((tlh, 0xffffffff)), untweaked);
+            input_zero.tap_script_sigs.insert((pk.0, tlh), sig);
+        }
+    }
+    if produced {
+        keys.record_signed(h)?;
+    }
+    Ok(b)
+}
+
+/// signs `unsigned`, recording it in the audit log if there is one
+fn sign_logged<K: TemplateKeys>(
+    keys: &K,
+    request: &str,
+    unsigned: PartiallySignedTransaction,
+) -> Result<PartiallySignedTransaction, std::io::Error> {
+    let signed = SECP.with(|secp| sign(keys, unsigned.clone(), secp))?;
+    if let Some(log) = keys.audit_log() {
+        log.append(request, &unsigned, &signed)?;
+    }
+    Ok(signed)
+}
+
+/// the main server business logic.
+///
+/// - on receiving Request::SignPSBT, signs the PSBT.
+/// - on receiving Request::SignBatch, signs each PSBT, responding with
+///   an error for any which can't be signed. A batch of more than
+///   `msgs::MAX_BATCH` is refused.
+/// - on receiving Request::GetKeys, responds with the keys the server
+///   signs with and when clients should use them, if they are fixed.
+/// - on receiving Request::AuditLog, responds with the requested range of
+///   the audit log, or an error if the server keeps none.
+async fn handle<K: TemplateKeys>(keys: &K, t: &mut TcpStream) -> Result<(), std::io::Error> {
+    let request = requested(t).await?;
+    match request {
+        msgs::Request::SignPSBT(msgs::PSBT(unsigned)) => {
+            let psbt = sign_logged(keys, "sign_psbt", unsigned)?;
+            respond(t, &msgs::PSBT(psbt)).await
+        }
+        msgs::Request::SignBatch(unsigned) if unsigned.len() > msgs::MAX_BATCH => {
+            input_error("Batch Too Large")
+        }
+        msgs::Request::SignBatch(unsigned) => {
+            let signed: msgs::BatchResponse = unsigned
+                .into_iter()
+                .map(|msgs::PSBT(b)| {
+                    sign_logged(keys, "sign_batch", b)
+                        .map(msgs::PSBT)
+                        .map_err(|e| e.to_string())
+                })
+                .collect();
+            respond(t, &signed).await
+        }
+        msgs::Request::GetKeys => {
+            let keys: msgs::KeysResponse = SECP
+                .with(|secp| keys.signed_keys(secp))
+                .ok_or_else(|| "No Fixed Keys".to_string());
+            respond(t, &keys).await
+        }
+        msgs::Request::AuditLog { start, end } => {
+            let range: msgs::AuditLogResponse = keys
+                .audit_log()
+                .map(|log| log.range(start, end, &SECP.with(|secp| keys.log_key(secp))))
+                .ok_or_else(|| "No Audit Log Kept".to_string());
+            respond(t, &range).await
+        }
+    }
+}
+
+/// receive a request via the tcpstream.
+/// wire format: length:u32 data:[u8;length]
+///
+/// TODO: DoS Critical: limit the allowed max length we will attempt to derserialize
+async fn requested(t: &mut TcpStream) -> Result<msgs::Request, std::io::Error> {
+    let l = t.read_u32().await? as usize;
+    let mut v = vec![0u8; l];
+    t.read_exact(&mut v[..]).await?;
+    Ok(serde_json::from_slice(&v[..])?)
+}
+
+/// respond via the tcpstream.
+/// wire format: length:u32 data:[u8;length]
+async fn respond<T: Serialize>(t: &mut TcpStream, r: &T) -> Result<(), std::io::Error> {
+    let v = serde_json::to_vec(r)?;
+    t.write_u32(v.len() as u32).await?;
+    t.write_all(&v[..]).await?;
+    t.flush().await
+}
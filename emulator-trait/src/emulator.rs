@@ -38,6 +38,24 @@ impl From<bitcoin::util::bip32::Error> for EmulatorError {
     }
 }
 
+/// One of the requests in a `CTVEmulator::sign_batch`
+#[derive(Clone, Debug)]
+pub enum EmulatorRequest {
+    /// see `CTVEmulator::get_signer_for`
+    SignerFor(sha256::Hash),
+    /// see `CTVEmulator::sign`
+    Sign(PartiallySignedTransaction),
+}
+
+/// The response to an `EmulatorRequest` of the same kind
+#[derive(Clone, Debug)]
+pub enum EmulatorResponse {
+    /// the Clause the Emulator would satisfy
+    SignerFor(Clause),
+    /// the PSBT with the Emulators signature added
+    Sign(PartiallySignedTransaction),
+}
+
 /// `CTVEmulator` trait is used to make the method in which CheckTemplateVerify
 /// is stubbed out with.
 pub trait CTVEmulator: Sync + Send {
@@ -49,6 +67,31 @@ pub trait CTVEmulator: Sync + Send {
         &self,
         b: PartiallySignedTransaction,
     ) -> Result<PartiallySignedTransaction, EmulatorError>;
+    /// Handle many requests at once, e.g. in one round trip to a remote
+    /// Emulator, with a response for each request in order. A request which
+    /// fails on its own gets an error in its place, while an error for the
+    /// whole batch is returned as such.
+    ///
+    /// By default each request is handled one at a time.
+    fn sign_batch(
+        &self,
+        requests: &[EmulatorRequest],
+    ) -> Result<Vec<Result<EmulatorResponse, EmulatorError>>, EmulatorError> {
+        Ok(requests
+            .iter()
+            .map(|r| match r {
+                EmulatorRequest::SignerFor(h) => {
+                    self.get_signer_for(*h).map(EmulatorResponse::SignerFor)
+                }
+                EmulatorRequest::Sign(b) => self.sign(b.clone()).map(EmulatorResponse::Sign),
+            })
+            .collect())
+    }
+    /// Who signs for this Emulator, e.g. its oracle's key, recorded in the
+    /// contracts compiled with it for a subtree, see `Context::with_emulator`
+    fn describe(&self) -> Option<String> {
+        None
+    }
 }
 
 /// A wrapper for an optional internal emulator trait object. If no emulator is
@@ -10,6 +10,7 @@ use bitcoin::util::bip32::*;
 use bitcoin::Script;
 use bitcoin::TxOut;
 use emulator_connect::connections::hd::HDOracleEmulatorConnection;
+use emulator_connect::connections::transport::Transport;
 use emulator_connect::servers::hd::HDOracleEmulator;
 use emulator_connect::*;
 use sapio::contract::*;
@@ -89,8 +90,9 @@ fn test_connect() {
         HDOracleEmulatorConnection::new(
             "127.0.0.1:8080",
             pk_root,
-            rt2.clone(),
+            Some(rt2.clone()),
             Arc::new(Secp256k1::new()),
+            Transport::default(),
         )
         .await
         .unwrap()
@@ -61,6 +61,11 @@ impl Callable for Wrapper {
         let create_args: CreateArgs<GetClause> = CreateArgs {
             context: ContextualArguments {
                 amount: ctx.funds(),
+                feerate: ctx.feerate(),
+                entropy_seed: ctx.entropy_seed(),
+                tip_height: ctx.tip_height().ok(),
+                median_time: ctx.median_time().ok(),
+                profile: ctx.profiling(),
                 network: ctx.network,
                 effects: unsafe { ctx.get_effects_internal() }.as_ref().clone(),
             },
@@ -22,7 +22,6 @@ use schemars::*;
 use serde::*;
 use std::convert::TryFrom;
 
-
 use std::sync::Arc;
 
 /// # Dutch Auction Data
@@ -123,7 +122,7 @@ impl TryFrom<Versions> for NFTDutchAuction {
     }
 }
 
-REGISTER![[NFTDutchAuction, Versions], "logo.png"];
+REGISTER![[NFTDutchAuction, Versions], "logo.png"; implements NFT_Sale_Trait_Version_0_1_0];
 
 impl NFTDutchAuction {
     /// # signed
@@ -160,6 +159,11 @@ impl NFTDutchAuction {
             let create_args = CreateArgs {
                 context: ContextualArguments {
                     amount: ctx.funds(),
+                    feerate: ctx.feerate(),
+                    entropy_seed: ctx.entropy_seed(),
+                    tip_height: ctx.tip_height().ok(),
+                    median_time: ctx.median_time().ok(),
+                    profile: ctx.profiling(),
                     network: ctx.network,
                     effects: unsafe { ctx.get_effects_internal() }.as_ref().clone(),
                 },
@@ -186,8 +190,7 @@ impl NFTDutchAuction {
                 // only active at the set time
                 .set_lock_time(sched.0.into())?;
             let t = if let Some(artist) = self.main.data.ipfs_nft.artist {
-                let artist_gets = self.main.data.compute_royalty_for_artist(price);
-                let seller_gets = price - artist_gets;
+                let (artist_gets, seller_gets) = self.main.data.split_sale(price)?;
                 // Pay Sale to Seller
                 tmpl.add_output(seller_gets, &self.main.data.owner, None)?
                     // Pay Royalty to Creator
@@ -10,6 +10,7 @@
 use bitcoin::Amount;
 use sapio::contract::CompilationError;
 use sapio::contract::Contract;
+use sapio::template::OutputMeta;
 use sapio::*;
 use sapio_wasm_nft_trait::*;
 use sapio_wasm_plugin::client::*;
@@ -43,7 +44,7 @@ impl From<Versions> for SimpleNFTSale {
     }
 }
 
-REGISTER![[SimpleNFTSale, Versions], "logo.png"];
+REGISTER![[SimpleNFTSale, Versions], "logo.png"; implements NFT_Sale_Trait_Version_0_1_0];
 
 impl SimpleNFTSale {
     /// # transfer
@@ -66,6 +67,11 @@ impl SimpleNFTSale {
         let new_nft_args = CreateArgs {
             context: ContextualArguments {
                 amount: ctx.funds(),
+                feerate: ctx.feerate(),
+                entropy_seed: ctx.entropy_seed(),
+                tip_height: ctx.tip_height().ok(),
+                median_time: ctx.median_time().ok(),
+                profile: ctx.profiling(),
                 network: ctx.network,
                 effects: unsafe { ctx.get_effects_internal() }.as_ref().clone(),
             },
@@ -83,15 +89,18 @@ impl SimpleNFTSale {
         // a purchase.
         if let Some(artist) = self.0.data.ipfs_nft.artist {
             let price: Amount = self.0.price.into();
-            let artist_gets = self.0.data.compute_royalty_for_artist(price);
-            let seller_gets = price - artist_gets;
+            let (artist_gets, seller_gets) = self.0.data.split_sale(price)?;
             ctx.template()
                 .add_amount(self.0.price.into())
                 .add_output(amt, &new_nft_contract, None)?
                 .add_sequence()
                 .add_output(seller_gets, &self.0.data.owner, None)?
                 // Pay Royalty to Creator
-                .add_output(artist_gets, &artist, None)?
+                .add_output_with_meta(
+                    artist_gets,
+                    &artist,
+                    OutputMeta::default().with_label("artist royalty"),
+                )?
                 // note: what would happen if we had another output that
                 // had a percentage-of-sale royalty to some creator's key?
                 .into()
@@ -1,5 +1,5 @@
 use bitcoin::Amount;
-use sapio::contract::Contract;
+use sapio::contract::{CompilationError, Contract};
 use sapio::contract::StatefulArgumentsTrait;
 use sapio::decl_continuation;
 use sapio::util::amountrange::AmountU64;
@@ -33,8 +33,13 @@ pub struct Mint_NFT_Trait_Version_0_1_0 {
 
 const PRECISION: u64 = 1000000;
 impl Mint_NFT_Trait_Version_0_1_0 {
-    pub fn compute_royalty_for_artist(&self, amount: Amount) -> Amount {
-        (amount * (PRECISION as f64 * self.royalty).round() as u64) / PRECISION
+    /// Splits a sale `price` into the `(artist, seller)` amounts. The
+    /// royalty rounds down, with the remainder going to the seller.
+    pub fn split_sale(&self, price: Amount) -> Result<(Amount, Amount), CompilationError> {
+        let (artist, seller) = AmountU64::from(price)
+            .checked_split_ratio((PRECISION as f64 * self.royalty).round() as u64, PRECISION)
+            .ok_or(CompilationError::AmountOverflow)?;
+        Ok((artist.into(), seller.into()))
     }
 }
 
@@ -92,7 +97,7 @@ pub struct NFT_Sale_Trait_Version_0_1_0 {
     #[schemars(with = "bitcoin::hashes::sha256::Hash")]
     pub sell_to: bitcoin::XOnlyPublicKey,
     /// # Price
-    /// The price in Sats
+    /// The price in Sats, or a string with a unit like `"0.015 BTC"`
     pub price: AmountU64,
     /// # NFT
     /// The NFT's Current Info
@@ -148,7 +153,7 @@ pub struct NFT_Sale_Trait_Version_0_1_0_Partial {
     #[schemars(with = "bitcoin::hashes::sha256::Hash")]
     pub sell_to: bitcoin::XOnlyPublicKey,
     /// # Price
-    /// The price in Sats
+    /// The price in Sats, or a string with a unit like `"0.015 BTC"`
     pub price: AmountU64,
     /// # Sale Time
     /// When the sale should be possible after
@@ -3,6 +3,7 @@ use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::util::amount::Amount;
 use bitcoin::XOnlyPublicKey;
+use sapio::contract::actions::ConditionalCompileType;
 use sapio::contract::empty;
 use sapio::contract::object::ObjectMetadata;
 use sapio::contract::CompilationError;
@@ -29,7 +30,7 @@ pub struct SimpleNFT {
 /// # The SimpleNFT Contract
 impl Contract for SimpleNFT {
     // NFTs... only good for selling?
-    declare! {updatable<Sell>, Self::sell}
+    declare! {updatable<serde_json::Value>, Self::sell}
     // embeds metadata
     declare! {finish, Self::metadata_commit}
     fn metadata(&self, _ctx: Context) -> Result<ObjectMetadata, CompilationError> {
@@ -62,13 +63,22 @@ impl SimpleNFT {
     fn signed(self, _ctx: Context) {
         Clause::Key(self.data.owner.clone())
     }
+    /// # hold_is_nullable
+    /// Holding the NFT doesn't need any transactions, so don't bother calling
+    /// `sell` for it.
+    fn hold_is_nullable(&self, _ctx: &Context, sale: &Sell) -> ConditionalCompileType {
+        match sale {
+            Sell::Hold => ConditionalCompileType::Nullable,
+            Sell::MakeSale { .. } => ConditionalCompileType::NoConstraint,
+        }
+    }
 }
-fn default_coerce(k: <SimpleNFT as Contract>::StatefulArguments) -> Result<Sell, CompilationError> {
-    Ok(k)
-}
-
 impl SellableNFT for SimpleNFT {
-    #[continuation(guarded_by = "[Self::signed]", web_api, coerce_args = "default_coerce")]
+    #[continuation(
+        guarded_by = "[Self::signed]",
+        compile_if_args = "[Self::hold_is_nullable]",
+        web_api
+    )]
     fn sell(self, mut ctx: Context, sale: Sell) {
         if let Sell::MakeSale {
             sale_info_partial,
@@ -81,6 +91,11 @@ impl SellableNFT for SimpleNFT {
             let create_args: CreateArgs<sale_impl::Versions> = CreateArgs {
                 context: ContextualArguments {
                     amount: ctx.funds(),
+                    feerate: ctx.feerate(),
+                    entropy_seed: ctx.entropy_seed(),
+                    tip_height: ctx.tip_height().ok(),
+                    median_time: ctx.median_time().ok(),
+                    profile: ctx.profiling(),
                     network: ctx.network,
                     effects: unsafe { ctx.get_effects_internal() }.as_ref().clone(),
                 },
@@ -138,4 +153,4 @@ impl TryFrom<Versions> for SimpleNFT {
         }
     }
 }
-REGISTER![[SimpleNFT, Versions], "logo.png"];
+REGISTER![[SimpleNFT, Versions], "logo.png"; implements Mint_NFT_Trait_Version_0_1_0];
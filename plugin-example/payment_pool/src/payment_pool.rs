@@ -14,7 +14,6 @@ use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::secp256k1::schnorr::Signature;
 use bitcoin::secp256k1::Message;
-use bitcoin::secp256k1::Secp256k1;
 use bitcoin::util::amount::Amount;
 use bitcoin::Address;
 
@@ -150,10 +149,11 @@ impl PaymentPool {
         // collect members with updated balances here
         let mut new_members = self.members.clone();
         // verification context
-        let secp = Secp256k1::new();
+        let secp = ctx.secp();
         // collect all the payments
         let mut all_payments = vec![];
         let mut spent = Amount::from_sat(0);
+        let mut fees = Amount::from_sat(0);
         // for each payment...
         for (
             from,
@@ -187,6 +187,7 @@ impl PaymentPool {
                 new_members.remove(from);
             }
 
+            fees += Amount::from(*fee);
             // collect all the payment
             for (address, amt) in payments.iter() {
                 spent += Amount::from(*amt);
@@ -220,7 +221,10 @@ impl PaymentPool {
             sequence: self.sequence + 1,
             sig_needed: self.sig_needed,
         };
-        let mut tmpl = ctx.template().add_output(change.total(), &change, None)?;
+        let mut tmpl = ctx
+            .template()
+            .add_fees(fees)?
+            .add_output(change.total(), &change, None)?;
         if all_payments.len() > 4 {
             // We'll use the contract from our last post to make the state
             // transitions more efficient!
@@ -39,6 +39,11 @@ impl TrampolinePay {
             &CreateArgs {
                 context: ContextualArguments {
                     amount: ctx.funds(),
+                    feerate: ctx.feerate(),
+                    entropy_seed: ctx.entropy_seed(),
+                    tip_height: ctx.tip_height().ok(),
+                    median_time: ctx.median_time().ok(),
+                    profile: ctx.profiling(),
                     network: ctx.network,
                     effects: unsafe { ctx.get_effects_internal() }.as_ref().clone(),
                 },
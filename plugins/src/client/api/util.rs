@@ -7,7 +7,8 @@
 //! Various utils for working with modules
 use super::*;
 
-use sapio::contract::CompilationError;
+use crate::plugin_handle::ModuleFailure;
+use sapio::contract::{CompilationError, ErrorReport};
 use sapio_base::effects::EffectPath;
 
 /// Print a &str to the parent's console.
@@ -42,9 +43,18 @@ where
     };
     if p != 0 {
         let cs = unsafe { CString::from_raw(p as *mut c_char) };
-        let res: Result<T, String> = serde_json::from_slice(cs.as_bytes())
+        let res: Result<T, ModuleFailure> = serde_json::from_slice(cs.as_bytes())
             .map_err(CompilationError::DeserializationError)?;
-        res.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+        // the host names the module in its errors already
+        res.map_err(|e| match ErrorReport::from(e) {
+            ErrorReport::ModuleError { module, inner } => {
+                CompilationError::ModuleError { module, inner }
+            }
+            inner => CompilationError::ModuleError {
+                module: bitcoin::hashes::hex::ToHex::to_hex(&key[..]),
+                inner: Box::new(inner),
+            },
+        })
     } else {
         Err(CompilationError::InternalModuleError("Unknown".into()))
     }
@@ -148,12 +158,22 @@ pub fn lookup_this_module_name() -> Option<[u8; 32]> {
     }
 }
 
-/// Given a human readable name, create a new contract instance
+/// Given a human readable name, create a new contract instance. A dry run
+/// doesn't call the module, see `Context::stub_call`.
 pub fn create_contract<S: Serialize>(
     context: Context,
     key: &str,
     args: CreateArgs<S>,
 ) -> Result<Compiled, CompilationError> {
+    if args.context.network != context.network() {
+        return Err(CompilationError::WrongNetwork(
+            context.network(),
+            format!("arguments for module `{}` on {}", key, args.context.network),
+        ));
+    }
+    if context.is_dry_run() {
+        return Ok(context.stub_call(&format!("module `{}`", key)));
+    }
     let key = lookup_module_name(key).ok_or(CompilationError::UnknownModule)?;
     call(context, &key, args)
 }
@@ -83,3 +83,18 @@ unsafe extern "C" fn sapio_v1_wasm_plugin_client_get_logo() -> *mut c_char {
         .unwrap()
         .into_raw()
 }
+
+pub(crate) static mut SAPIO_PLUGIN_VERSION: Option<&str> = None;
+pub(crate) static mut SAPIO_PLUGIN_TRAITS: &[&str] = &[];
+/// Gets the version and the traits the plugin declared.
+/// host must drop the returned pointer.
+#[no_mangle]
+unsafe extern "C" fn sapio_v1_wasm_plugin_client_get_info() -> *mut c_char {
+    let info = PluginInfo {
+        version: SAPIO_PLUGIN_VERSION.map(String::from),
+        traits: SAPIO_PLUGIN_TRAITS.iter().map(|t| t.to_string()).collect(),
+    };
+    CString::new(serde_json::to_vec(&info).unwrap())
+        .unwrap()
+        .into_raw()
+}
@@ -13,6 +13,8 @@ extern "C" {
     pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(hash: i32) -> i32;
     /// use the hosts stdout to log a string. The host may make this a no-op.
     pub fn sapio_v1_wasm_plugin_debug_log_string(a: i32, len: i32);
+    /// the nanoseconds since the module was loaded, from the host's clock
+    pub fn sapio_v1_wasm_plugin_clock_ns() -> i64;
     /// Create an instance of a contract by "trampolining" through the host to use another
     /// plugin identified by key.
     pub fn sapio_v1_wasm_plugin_create_contract(
@@ -6,7 +6,7 @@
 
 //! binding for making a type into a plugin
 use super::*;
-use sapio::contract::CompilationError;
+use sapio::contract::{CompilationError, ErrorReport, ResourceLimits};
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
 use sapio_base::serialization_helpers::SArc;
@@ -55,7 +55,7 @@ where
 
     /// creates an instance of the plugin from a json pointer and outputs a result pointer
     unsafe fn create(p: *mut c_char, c: *mut c_char) -> *mut c_char {
-        let res = Self::create_result(p, c).map_err(|e| e.to_string());
+        let res = Self::create_result(p, c).map_err(|e| ErrorReport::from(&e));
         encode_json(&res)
     }
 
@@ -66,15 +66,6 @@ where
     ) -> Result<Self::Output, CompilationError> {
         let s = CString::from_raw(c);
         let path = CString::from_raw(p);
-        let CreateArgs::<Self::InputWrapper> {
-            arguments,
-            context:
-                ContextualArguments {
-                    network,
-                    amount,
-                    effects,
-                },
-        } = serde_json::from_slice(s.to_bytes()).map_err(CompilationError::DeserializationError)?;
         // TODO: In theory, these trampoline bounds are robust/serialization safe...
         // But the API needs stiching to the parent in a sane way...
         let caller = lookup_this_module_name()
@@ -97,6 +88,23 @@ where
             )),
             PathFragment::Named(SArc(Arc::new(caller))),
         );
+        let CreateArgs::<Self::InputWrapper> {
+            arguments,
+            context:
+                ContextualArguments {
+                    network,
+                    amount,
+                    feerate,
+                    entropy_seed,
+                    tip_height,
+                    median_time,
+                    profile,
+                    effects,
+                },
+        } = serde_json::from_slice(s.to_bytes()).map_err(|e| CompilationError::SchemaError {
+            path: path.clone(),
+            message: e.to_string(),
+        })?;
 
         let ctx = Context::new(
             network,
@@ -105,7 +113,13 @@ where
             path,
             // TODO: load database?
             Arc::new(effects),
-        );
+        )
+        .with_feerate(feerate)
+        .with_entropy_seed(entropy_seed)
+        .with_chain_tip(tip_height, median_time)
+        .with_resource_limits(ResourceLimits::sandboxed())
+        .with_profiling(profile)
+        .with_clock(|| unsafe { sapio_v1_wasm_plugin_clock_ns() } as u64);
         let converted = Self::try_from(arguments)?;
         converted.call(ctx)
     }
@@ -118,6 +132,12 @@ where
             SAPIO_PLUGIN_LOGO = logo;
         }
     }
+    /// declares the plugin's version and the traits it implements, for the
+    /// host to read with `WasmPluginHandle::get_info`
+    unsafe fn register_info(version: &'static str, traits: &'static [&'static str]) {
+        SAPIO_PLUGIN_VERSION = Some(version);
+        SAPIO_PLUGIN_TRAITS = traits;
+    }
 }
 
 /// Helper function for encoding a JSON into WASM linear memory
@@ -132,13 +152,17 @@ fn encode_json<S: Serialize>(s: &S) -> *mut c_char {
 /// A helper macro to implement the plugin interface for a plugin-type
 /// and register it to the plugin entry point.
 ///
+/// The traits the plugin implements may be declared after the logo, e.g.
+/// `REGISTER![[SimpleNFT, Versions], "logo.png"; implements Mint_NFT_Trait_Version_0_1_0]`,
+/// and are reported with the version of the plugin's crate.
+///
 /// U.B. to call REGISTER more than once because of the internal #[no_mangle]
 #[macro_export]
 macro_rules! REGISTER {
-    [$plugin:ident$(, $logo:expr)?] => {
-        REGISTER![[$plugin, $plugin]$(, $logo)*];
+    [$plugin:ident$(, $logo:expr)?$(; implements $($trait:ident),+)?] => {
+        REGISTER![[$plugin, $plugin]$(, $logo)*$(; implements $($trait),+)*];
     };
-    [[$to:ident,$wrapper:ident]$(, $logo:expr)?] => {
+    [[$to:ident,$wrapper:ident]$(, $logo:expr)?$(; implements $($trait:ident),+)?] => {
         const _ : () = {
             use sapio_wasm_plugin::client::Plugin;
             use sapio_wasm_plugin::client::plugin::Callable;
@@ -171,6 +195,10 @@ macro_rules! REGISTER {
             #[no_mangle]
             unsafe fn sapio_v1_wasm_plugin_entry_point() {
                 SapioInternalWrapperAroundCallable::register(stringify!($to), optional_logo!($($logo)*));
+                SapioInternalWrapperAroundCallable::register_info(
+                    env!("CARGO_PKG_VERSION"),
+                    &[$($(stringify!($trait)),+)*],
+                );
             }
         };
     };
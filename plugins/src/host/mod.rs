@@ -11,13 +11,14 @@ use bitcoin::hashes::sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::util::psbt::PartiallySignedTransaction;
 pub use plugin_handle::WasmPluginHandle;
-use sapio::contract::CompilationError;
+use sapio::contract::{CompilationError, ErrorReport};
 use sapio_base::plugin_args::CreateArgs;
 use sapio_ctv_emulator_trait::CTVEmulator;
 use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::io::Write;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
 use std::sync::{Arc, Mutex};
 use wasmer::*;
 
@@ -38,8 +39,16 @@ pub struct HostEnvironmentInner {
     pub store: Arc<Mutex<Store>>,
     /// which network the contract is being built for
     pub net: bitcoin::Network,
-    /// an emulator plugin for CTV functionality
+    /// an emulator plugin for CTV functionality, unless a call chose its own
     pub emulator: Arc<dyn CTVEmulator>,
+    /// the emulators chosen by the calls in progress, innermost last, see
+    /// `WasmPluginHandle::call_with_emulator`
+    pub call_emulators: Vec<Arc<dyn CTVEmulator>>,
+    /// set to interrupt the module, and any it creates contracts with, see
+    /// `WasmPluginHandle::with_cancellation`
+    pub cancelled: Option<Arc<AtomicBool>>,
+    /// when the module was loaded, see `sapio_v1_wasm_plugin_clock_ns`
+    pub started: std::time::Instant,
     /// reference to the environment's memory space
     #[wasmer(export)]
     pub memory: LazyInit<Memory>,
@@ -55,6 +64,10 @@ pub struct HostEnvironmentInner {
     /// reference to get_logo function
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_get_logo"))]
     pub get_logo: LazyInit<NativeFunc<(), i32>>,
+    /// reference to get_info function, which modules built before it was
+    /// added don't have
+    #[wasmer(export(optional = true, name = "sapio_v1_wasm_plugin_client_get_info"))]
+    pub get_info: LazyInit<NativeFunc<(), i32>>,
     /// reference to allocation drop function
     #[wasmer(export(name = "sapio_v1_wasm_plugin_client_drop_allocation"))]
     pub forget: LazyInit<NativeFunc<i32, ()>>,
@@ -66,6 +79,13 @@ pub struct HostEnvironmentInner {
     pub init: LazyInit<NativeFunc<(), ()>>,
 }
 
+impl HostEnvironmentInner {
+    /// the emulator of the innermost call in progress, or else the module's
+    pub fn current_emulator(&self) -> &Arc<dyn CTVEmulator> {
+        self.call_emulators.last().unwrap_or(&self.emulator)
+    }
+}
+
 /// Wrapped Plugin Env so that we don't duplicate state for each function.
 /// We must be careful to ensure we don't see deadlocks.
 ///
@@ -236,7 +256,9 @@ mod exports {
                 create_args.and_then(|c| effectpath.map(|e| InternalAction::Create(c, e)))
             }
         };
-        let emulator = env.emulator.clone();
+        // the module creates contracts with the emulator of the call it is in
+        let emulator = env.current_emulator().clone();
+        let cancelled = env.cancelled.clone();
         let mmap = env.module_map.clone();
         let path = env.path.clone();
         let net = env.net;
@@ -252,6 +274,10 @@ mod exports {
             )
         }) {
             Ok(Ok(sph)) => {
+                let sph = match cancelled {
+                    Some(cancelled) => sph.with_cancellation(cancelled),
+                    None => sph,
+                };
                 let comp_s = (move || -> Result<serde_json::Value, CompilationError> {
                     let value = match action_to_take? {
                         InternalAction::GetName => Ok(sph.get_name().and_then(|m| {
@@ -274,7 +300,7 @@ mod exports {
                 })();
                 (move || -> Result<i32, CompilationError> {
                     // serialize the reuslt, not just the output.
-                    let comp_s = serde_json::to_string(&comp_s.map_err(|s| s.to_string()))
+                    let comp_s = serde_json::to_string(&comp_s.map_err(|e| ErrorReport::from(&e)))
                         .map_err(CompilationError::SerializationError)?;
                     let bytes: i32 = env
                         .allocate_wasm_bytes_ref()
@@ -317,6 +343,12 @@ mod exports {
         w.write_all("\n".as_bytes()).unwrap();
     }
 
+    /// the nanoseconds since the module was loaded, as a clock for the
+    /// module's profiling, see `sapio::Context::with_clock`
+    pub fn sapio_v1_wasm_plugin_clock_ns(env: &HostEnvironment) -> i64 {
+        env.lock().unwrap().started.elapsed().as_nanos() as i64
+    }
+
     /// for the provided hash value, get the clause the oracle will satisfy
     pub fn sapio_v1_wasm_plugin_ctv_emulator_signer_for(env: &HostEnvironment, hash: i32) -> i32 {
         let env = env.lock().unwrap();
@@ -332,7 +364,7 @@ mod exports {
             }
             buf
         });
-        let clause = env.emulator.get_signer_for(h).unwrap();
+        let clause = env.current_emulator().get_signer_for(h).unwrap();
         let s = serde_json::to_string_pretty(&clause).unwrap();
         let bytes = env
             .allocate_wasm_bytes_ref()
@@ -365,7 +397,7 @@ mod exports {
             *dst = src;
         }
         let psbt: PartiallySignedTransaction = serde_json::from_slice(&buf[..]).unwrap();
-        let psbt = env.emulator.sign(psbt).unwrap();
+        let psbt = env.current_emulator().sign(psbt).unwrap();
         buf.clear();
         let s = serde_json::to_string_pretty(&psbt).unwrap();
         let bytes = env
@@ -9,8 +9,8 @@ use super::*;
 use crate::host::exports::*;
 use crate::host::wasm_cache::get_all_keys_from_fs;
 use crate::host::{HostEnvironment, HostEnvironmentInner};
-use crate::plugin_handle::PluginHandle;
-use crate::API;
+use crate::plugin_handle::{ModuleFailure, PluginHandle};
+use crate::{PluginInfo, API};
 use sapio::contract::CompilationError;
 use sapio_base::effects::EffectPath;
 use sapio_ctv_emulator_trait::CTVEmulator;
@@ -19,7 +19,29 @@ use serde::{Deserialize, Serialize};
 use std::error::Error;
 use std::marker::PhantomData;
 use std::path::PathBuf;
-use wasmer::Memory;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use wasmer::{CompilerConfig, Cranelift, Memory, Universal};
+use wasmer_middlewares::Metering;
+
+/// the global `Metering` counts down the points a module has left in
+const REMAINING_POINTS: &str = "wasmer_metering_remaining_points";
+
+/// A store whose modules are metered, so that they can be interrupted by
+/// taking the points they have left away. Every operation costs a point, but
+/// modules are given as many as they could use.
+fn metered_store() -> Store {
+    let metering = Arc::new(Metering::new(u64::MAX, |_| 1));
+    let mut compiler = Cranelift::default();
+    compiler.push_middleware(metering);
+    Store::new(&Universal::new(compiler).engine())
+}
+
+/// was `module` compiled by a `metered_store`? Modules cached by a host which
+/// didn't meter them weren't.
+fn is_metered(module: &Module) -> bool {
+    module.exports().any(|e| e.name() == REMAINING_POINTS)
+}
 
 /// Helper to resolve modules
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -148,14 +170,14 @@ impl<Output> WasmPluginHandle<Output> {
         net: bitcoin::Network,
         plugin_map: Option<BTreeMap<Vec<u8>, [u8; 32]>>,
     ) -> Result<Self, Box<dyn Error>> {
-        let store = Store::default();
+        let store = metered_store();
 
         let (module, key) = match module_locator {
             SyncModuleLocator::Bytes(wasm_bytes) => {
                 match wasm_cache::load_module(path.clone(), &store, &wasm_bytes[..]) {
-                    Ok(module) => module,
-                    Err(_) => {
-                        let store = Store::default();
+                    Ok(module) if is_metered(&module.0) => module,
+                    _ => {
+                        let store = metered_store();
                         let module = Module::new(&store, &wasm_bytes)?;
                         let key = wasm_cache::store_module(path.clone(), &module, &wasm_bytes)?;
                         (module, key)
@@ -184,10 +206,14 @@ impl<Output> WasmPluginHandle<Output> {
             store: Arc::new(Mutex::new(store.clone())),
             net,
             emulator: emulator.clone(),
+            call_emulators: vec![],
+            cancelled: None,
+            started: std::time::Instant::now(),
             memory: LazyInit::new(),
             get_api: LazyInit::new(),
             get_name: LazyInit::new(),
             get_logo: LazyInit::new(),
+            get_info: LazyInit::new(),
             forget: LazyInit::new(),
             create: LazyInit::new(),
             init: LazyInit::new(),
@@ -199,6 +225,7 @@ impl<Output> WasmPluginHandle<Output> {
             sapio_v1_wasm_plugin_ctv_emulator_signer_for,
             sapio_v1_wasm_plugin_ctv_emulator_sign,
             sapio_v1_wasm_plugin_debug_log_string,
+            sapio_v1_wasm_plugin_clock_ns,
             sapio_v1_wasm_plugin_create_contract,
             sapio_v1_wasm_plugin_get_api,
             sapio_v1_wasm_plugin_get_name,
@@ -229,6 +256,59 @@ impl<Output> WasmPluginHandle<Output> {
         })
     }
 
+    /// Interrupt calls into the module once `cancelled` is set, e.g. the
+    /// flag of `Context::with_cancellation`, including those of modules it
+    /// creates contracts with. A module loaded from a cache by key which was
+    /// compiled without metering can only be stopped between calls.
+    pub fn with_cancellation(self, cancelled: Arc<AtomicBool>) -> Self {
+        self.env.lock().unwrap().cancelled = Some(cancelled);
+        self
+    }
+
+    /// make a call into the module with `f`, taking away the points it has
+    /// left if it is cancelled while running so that it traps, see
+    /// `with_cancellation`. The error is `Cancelled` if it was.
+    fn interruptible<R, E>(
+        &self,
+        path: &EffectPath,
+        f: impl FnOnce() -> Result<R, E>,
+    ) -> Result<Result<R, E>, CompilationError> {
+        let cancelled = self.env.lock().unwrap().cancelled.clone();
+        let cancelled = match cancelled {
+            Some(cancelled) => cancelled,
+            None => return Ok(f()),
+        };
+        let is_cancelled = || cancelled.load(Ordering::Relaxed);
+        if is_cancelled() {
+            return Err(CompilationError::Cancelled { path: path.clone() });
+        }
+        let points = self.instance.exports.get_global(REMAINING_POINTS).ok();
+        let done = &AtomicBool::new(false);
+        let result = std::thread::scope(|s| {
+            if let Some(points) = points {
+                s.spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        if is_cancelled() {
+                            // traps when the module next checks them. Set
+                            // until it does, as the module may write back
+                            // what it had left as it takes the last point.
+                            let _ = points.set(wasmer::Val::I64(0));
+                        }
+                        std::thread::sleep(Duration::from_millis(10));
+                    }
+                });
+            }
+            let result = f();
+            done.store(true, Ordering::Relaxed);
+            result
+        });
+        if is_cancelled() {
+            Err(CompilationError::Cancelled { path: path.clone() })
+        } else {
+            Ok(result)
+        }
+    }
+
     /// forget an allocated pointer
     pub fn forget(&self, p: i32) -> Result<(), CompilationError> {
         self.env
@@ -293,6 +373,42 @@ impl<Output> WasmPluginHandle<Output> {
     }
 }
 
+impl<GOutput> WasmPluginHandle<GOutput>
+where
+    GOutput: for<'a> Deserialize<'a>,
+{
+    /// The version and traits the module declares, or none for a module
+    /// built before they could be declared
+    pub fn get_info(&self) -> Result<PluginInfo, CompilationError> {
+        let get_info = self.env.lock().unwrap().get_info_ref().cloned();
+        let p = match get_info {
+            Some(f) => f
+                .call()
+                .map_err(|e| CompilationError::ModuleCouldNotGetInfo(e.into()))?,
+            None => return Ok(PluginInfo::default()),
+        };
+        let v = self.read_to_vec(p)?;
+        self.forget(p)?;
+        serde_json::from_slice(&v).map_err(CompilationError::DeserializationError)
+    }
+    /// Call the module's main function with `emulator` rather than the one
+    /// this handle was created with, e.g. `Context::emulator` for a subtree
+    /// chosen with `Context::with_emulator`. The emulator is only used for
+    /// this call, and passed on through the host's create import to any
+    /// modules it creates contracts with.
+    pub fn call_with_emulator(
+        &self,
+        path: &EffectPath,
+        c: &CreateArgs<serde_json::Value>,
+        emulator: Arc<dyn CTVEmulator>,
+    ) -> Result<GOutput, CompilationError> {
+        self.env.lock().unwrap().call_emulators.push(emulator);
+        let result = self.call(path, c);
+        self.env.lock().unwrap().call_emulators.pop();
+        result
+    }
+}
+
 impl<GOutput> PluginHandle for WasmPluginHandle<GOutput>
 where
     GOutput: for<'a> Deserialize<'a>,
@@ -308,18 +424,26 @@ where
             let env = self.env.lock().unwrap();
             env.create.clone()
         };
-        let result_ptr = create_func
+        let create = create_func
             .get_ref()
-            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("create".into()))?
-            .call(path_ptr, args_ptr)
+            .ok_or_else(|| CompilationError::ModuleCouldNotFindFunction("create".into()))?;
+        let result_ptr = self
+            .interruptible(path, || create.call(path_ptr, args_ptr))?
             .map_err(|e| {
-                CompilationError::ModuleCouldNotCreateContract(path.clone(), c.clone(), e.into())
+                CompilationError::ModuleCouldNotCreateContract(
+                    path.clone(),
+                    Box::new(c.clone()),
+                    e.into(),
+                )
             })?;
         let buf = self.read_to_vec(result_ptr)?;
         self.forget(result_ptr)?;
-        let v: Result<Self::Output, String> =
+        let v: Result<Self::Output, ModuleFailure> =
             serde_json::from_slice(&buf).map_err(CompilationError::DeserializationError)?;
-        v.map_err(CompilationError::ModuleCompilationErrorUnsendable)
+        v.map_err(|e| CompilationError::ModuleError {
+            module: self.key.to_string(),
+            inner: Box::new(e.into()),
+        })
     }
     fn get_api(&self) -> Result<API<Self::Input, Self::Output>, CompilationError> {
         let p = self
@@ -362,3 +486,132 @@ where
         Ok(String::from_utf8_lossy(&v).to_string())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ContextualArguments;
+    use bitcoin::hashes::sha256;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::util::psbt::PartiallySignedTransaction;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::{CTVAvailable, EmulatorError};
+    use std::convert::TryFrom;
+    use std::sync::atomic::AtomicUsize;
+
+    /// a module whose `create` never returns
+    const FOREVER: &str = r#"(module
+        (memory (export "memory") 1)
+        (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32)
+            i32.const 1024)
+        (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32)
+            i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+        (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32)
+            (loop $forever (br $forever))
+            i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_entry_point")))"#;
+
+    /// a module whose `create` asks the host for the signer of a template
+    const SIGNER_FOR: &str = r#"(module
+        (import "env" "sapio_v1_wasm_plugin_ctv_emulator_signer_for" (func $signer_for (param i32) (result i32)))
+        (memory (export "memory") 1)
+        (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32)
+            i32.const 1024)
+        (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32)
+            i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+        (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32)
+            (drop (call $signer_for (i32.const 2048)))
+            i32.const 0)
+        (func (export "sapio_v1_wasm_plugin_entry_point")))"#;
+
+    /// counts the signers asked of it
+    #[derive(Default)]
+    struct Counting(AtomicUsize);
+    impl CTVEmulator for Counting {
+        fn get_signer_for(&self, h: sha256::Hash) -> Result<Clause, EmulatorError> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            CTVAvailable.get_signer_for(h)
+        }
+        fn sign(
+            &self,
+            b: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+    }
+
+    fn args() -> CreateArgs<serde_json::Value> {
+        CreateArgs {
+            arguments: serde_json::Value::Null,
+            context: ContextualArguments {
+                network: bitcoin::Network::Regtest,
+                amount: Amount::from_sat(100_000),
+                feerate: None,
+                entropy_seed: None,
+                tip_height: None,
+                median_time: None,
+                profile: false,
+                effects: MapEffectDB::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn calls_use_their_emulator() {
+        let dir = std::env::temp_dir().join(format!("sapio-call-emulator-{}", std::process::id()));
+        let default = Arc::new(Counting::default());
+        let chosen = Arc::new(Counting::default());
+        let handle = WasmPluginHandle::<serde_json::Value>::new(
+            dir.clone(),
+            &(default.clone() as Arc<dyn CTVEmulator>),
+            SyncModuleLocator::Bytes(SIGNER_FOR.as_bytes().to_vec()),
+            bitcoin::Network::Regtest,
+            None,
+        )
+        .unwrap();
+        let path = EffectPath::try_from("signer").unwrap();
+        // the module returns nothing parseable, only the signers asked matter
+        let _ = handle.call_with_emulator(&path, &args(), chosen.clone());
+        assert_eq!(chosen.0.load(Ordering::Relaxed), 1);
+        assert_eq!(default.0.load(Ordering::Relaxed), 0);
+        let _ = handle.call(&path, &args());
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(chosen.0.load(Ordering::Relaxed), 1);
+        assert_eq!(default.0.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn cancelled_calls_are_interrupted() {
+        let dir = std::env::temp_dir().join(format!("sapio-interrupt-{}", std::process::id()));
+        let emulator: Arc<dyn CTVEmulator> = Arc::new(CTVAvailable);
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let handle = WasmPluginHandle::<serde_json::Value>::new(
+            dir.clone(),
+            &emulator,
+            SyncModuleLocator::Bytes(FOREVER.as_bytes().to_vec()),
+            bitcoin::Network::Regtest,
+            None,
+        )
+        .unwrap()
+        .with_cancellation(cancelled.clone());
+        let args = args();
+        std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            cancelled.store(true, Ordering::Relaxed);
+        });
+        let path = EffectPath::try_from("forever").unwrap();
+        let result = handle.call(&path, &args);
+        std::fs::remove_dir_all(dir).unwrap();
+        match result.as_ref().map_err(CompilationError::root_cause) {
+            Err(CompilationError::Cancelled { path: at }) => assert_eq!(*at, path),
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+    }
+}
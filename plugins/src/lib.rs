@@ -21,6 +21,7 @@ pub mod client;
 #[cfg(feature = "host")]
 pub mod host;
 pub mod plugin_handle;
+pub mod reopen;
 
 /// A bundle of input/output types
 #[derive(Serialize, Deserialize, JsonSchema)]
@@ -54,3 +55,14 @@ where
         &self.returns
     }
 }
+
+/// What a module declares about itself, besides its API
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PluginInfo {
+    /// the version of the crate the module was built from
+    #[serde(default)]
+    pub version: Option<String>,
+    /// the traits the module implements, e.g. `Mint_NFT_Trait_Version_0_1_0`
+    #[serde(default)]
+    pub traits: Vec<String>,
+}
@@ -7,8 +7,9 @@
 //! generic plugin handle interface available to client and host
 
 use crate::API;
-use sapio::contract::CompilationError;
+use sapio::contract::{CompilationError, ErrorReport};
 use sapio_base::effects::EffectPath;
+use serde::Deserialize;
 
 /// Generic plugin handle interface.
 ///
@@ -27,3 +28,23 @@ pub trait PluginHandle {
     /// get logo metadata
     fn get_logo(&self) -> Result<String, CompilationError>;
 }
+
+/// The error a module returned from creating a contract, as an `ErrorReport`,
+/// or as just a message from a module built before errors were structured
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ModuleFailure {
+    /// a structured error
+    Report(ErrorReport),
+    /// an error message
+    Message(String),
+}
+
+impl From<ModuleFailure> for ErrorReport {
+    fn from(f: ModuleFailure) -> Self {
+        match f {
+            ModuleFailure::Report(r) => r,
+            ModuleFailure::Message(m) => ErrorReport::Custom(m),
+        }
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reopening a contract compiled by a module earlier, to keep updating it
+//! with new effects without the process which created it
+use crate::plugin_handle::PluginHandle;
+use crate::CreateArgs;
+use sapio::analysis::{diff, Change, ContractDiff, Field, NodeKind};
+use sapio::contract::{CompilationError, Compiled};
+use sapio_base::effects::{EditableMapEffectDB, EffectPath};
+use sapio_base::serialization_helpers::SArc;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::error::Error;
+use std::fmt::Display;
+
+/// A contract saved with what created it, to be reopened with `reopen`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SavedContract {
+    /// the module which created the contract, e.g. its hash, for the caller
+    /// to find it by
+    pub module: String,
+    /// the path the module was called at
+    pub path: EffectPath,
+    /// the arguments the module was called with, including every effect
+    pub args: CreateArgs<Value>,
+    /// what the module returned
+    pub compiled: Compiled,
+}
+
+/// Why a saved contract couldn't be reopened
+#[derive(Debug)]
+pub enum ReopenError {
+    /// the module failed to recompile the contract
+    Compilation(CompilationError),
+    /// recompiling the contract's arguments gives a different contract, e.g.
+    /// as the module's version is not the one which created it
+    Drift {
+        /// the module
+        module: String,
+        /// the saved contract's address and the recompiled one's, if they
+        /// differ
+        root: Option<(String, String)>,
+        /// how the recompiled contract differs from the saved one
+        changes: ContractDiff,
+    },
+}
+
+impl Display for ReopenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReopenError::Compilation(e) => write!(f, "couldn't recompile the contract: {}", e),
+            ReopenError::Drift {
+                module,
+                root,
+                changes,
+            } => {
+                write!(
+                    f,
+                    "module {} doesn't reproduce the saved contract, and may not be the version which created it: ",
+                    module
+                )?;
+                if let Some((saved, recompiled)) = root {
+                    write!(f, "its address {} is now {}; ", saved, recompiled)?;
+                }
+                write!(f, "{}", changes.summary().join(", "))
+            }
+        }
+    }
+}
+impl Error for ReopenError {}
+
+impl From<CompilationError> for ReopenError {
+    fn from(e: CompilationError) -> Self {
+        ReopenError::Compilation(e)
+    }
+}
+
+/// A saved contract reopened with the module which created it, see `reopen`
+pub struct ReopenedContract<'a, H> {
+    handle: &'a H,
+    saved: SavedContract,
+}
+
+impl<'a, H> ReopenedContract<'a, H>
+where
+    H: PluginHandle<Input = CreateArgs<Value>, Output = Compiled>,
+{
+    /// the contract as last compiled
+    pub fn compiled(&self) -> &Compiled {
+        &self.saved.compiled
+    }
+    /// the contract with the arguments it was last compiled with, to save
+    pub fn saved(&self) -> &SavedContract {
+        &self.saved
+    }
+    /// the contract with the arguments it was last compiled with, to save
+    pub fn into_saved(self) -> SavedContract {
+        self.saved
+    }
+    /// Set the effect `name` for the continuation point at `path` to `args`,
+    /// in place of any set before under that name, and recompile the contract
+    /// with it, returning how it changed.
+    pub fn update(
+        &mut self,
+        path: SArc<EffectPath>,
+        name: SArc<String>,
+        args: Value,
+    ) -> Result<ContractDiff, CompilationError> {
+        let mut create = self.saved.args.clone();
+        let mut effects = EditableMapEffectDB::from(create.context.effects);
+        effects.effects.entry(path).or_default().insert(name, args);
+        create.context.effects = effects.into();
+        let compiled = self.handle.call(&self.saved.path, &create)?;
+        let changes = diff(&self.saved.compiled, &compiled);
+        self.saved.args = create;
+        self.saved.compiled = compiled;
+        Ok(changes)
+    }
+}
+
+/// Reopen `saved` with `handle`, which must be the module which created it,
+/// for updating.
+///
+/// The contract is recompiled from its saved arguments, which must give the
+/// saved contract exactly, or else updates would be made to a contract
+/// other than the one shared, and the changes are returned as an error.
+pub fn reopen<H>(handle: &H, saved: SavedContract) -> Result<ReopenedContract<'_, H>, ReopenError>
+where
+    H: PluginHandle<Input = CreateArgs<Value>, Output = Compiled>,
+{
+    let recompiled = handle.call(&saved.path, &saved.args)?;
+    let changes = diff(&saved.compiled, &recompiled);
+    if !changes.is_empty() {
+        let path = String::from(saved.compiled.root_path.0.as_ref().clone());
+        let root = changes.changes.iter().find_map(|c| match c {
+            Change::Changed {
+                kind: NodeKind::Object,
+                path: p,
+                field: Field::Address,
+                old,
+                new,
+            } if *p == path => Some((old.clone(), new.clone())),
+            _ => None,
+        });
+        return Err(ReopenError::Drift {
+            module: saved.module,
+            root,
+            changes,
+        });
+    }
+    Ok(ReopenedContract { handle, saved })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::API;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::{KeyPair, Network, XOnlyPublicKey};
+    use sapio::contract::{empty, Compilable, Context, Contract, TxTmplIt};
+    use sapio::*;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use serde_json::json;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn key(seed: u8) -> XOnlyPublicKey {
+        let kp = KeyPair::from_seckey_slice(&Secp256k1::new(), &[seed; 32]).unwrap();
+        XOnlyPublicKey::from_keypair(&kp).0
+    }
+    fn default_coerce(k: Option<u8>) -> Result<Option<u8>, CompilationError> {
+        Ok(k)
+    }
+
+    /// pays `owner` less a fee, or whoever the owner redirects it to
+    struct Payout {
+        owner: u8,
+        fee: u64,
+    }
+    impl Payout {
+        fn pay_to(&self, ctx: Context, seed: u8) -> TxTmplIt {
+            let amount = ctx.funds() - Amount::from_sat(self.fee);
+            ctx.template()
+                .add_fees(Amount::from_sat(self.fee))?
+                .add_output(amount, &key(seed), None)?
+                .into()
+        }
+        #[guard]
+        fn signed(self, _ctx: Context) {
+            Clause::Key(key(self.owner))
+        }
+        #[then]
+        fn pay(self, ctx: Context) {
+            self.pay_to(ctx, self.owner)
+        }
+        #[continuation(guarded_by = "[Self::signed]", web_api, coerce_args = "default_coerce")]
+        fn redirect(self, ctx: Context, to: Option<u8>) {
+            match to {
+                Some(seed) => self.pay_to(ctx, seed),
+                None => empty(),
+            }
+        }
+    }
+    impl Contract for Payout {
+        declare! {then, Self::pay}
+        declare! {updatable<Option<u8>>, Self::redirect}
+    }
+
+    /// a module compiling `Payout` for the owner in its arguments, with a fee
+    /// which a later version changes
+    struct Module {
+        fee: u64,
+    }
+    impl PluginHandle for Module {
+        type Input = CreateArgs<Value>;
+        type Output = Compiled;
+        fn call(&self, path: &EffectPath, c: &Self::Input) -> Result<Compiled, CompilationError> {
+            let owner = serde_json::from_value(c.arguments.clone())
+                .map_err(CompilationError::DeserializationError)?;
+            Payout {
+                owner,
+                fee: self.fee,
+            }
+            .compile(Context::new(
+                c.context.network,
+                c.context.amount,
+                Arc::new(CTVAvailable),
+                path.clone(),
+                Arc::new(c.context.effects.clone()),
+            ))
+        }
+        fn get_api(&self) -> Result<API<Self::Input, Self::Output>, CompilationError> {
+            Err(CompilationError::TerminateCompilation)
+        }
+        fn get_name(&self) -> Result<String, CompilationError> {
+            Ok("payout".into())
+        }
+        fn get_logo(&self) -> Result<String, CompilationError> {
+            Ok("".into())
+        }
+    }
+
+    fn saved(module: &Module) -> SavedContract {
+        let path = EffectPath::try_from("payout").unwrap();
+        let args = CreateArgs {
+            arguments: json!(1),
+            context: crate::ContextualArguments {
+                network: Network::Regtest,
+                amount: Amount::from_sat(100_000),
+                feerate: None,
+                entropy_seed: None,
+                tip_height: None,
+                median_time: None,
+                profile: false,
+                effects: MapEffectDB::default(),
+            },
+        };
+        let compiled = module.call(&path, &args).unwrap();
+        SavedContract {
+            module: "payout".into(),
+            path,
+            args,
+            compiled,
+        }
+    }
+
+    #[test]
+    fn reopen_identical_and_drifted_modules() {
+        let module = Module { fee: 1000 };
+        let saved = saved(&module);
+        // shipped as JSON and reopened elsewhere
+        let saved: SavedContract =
+            serde_json::from_value(serde_json::to_value(&saved).unwrap()).unwrap();
+        let mut reopened = reopen(&module, saved.clone()).unwrap();
+        let points = reopened.compiled().continuation_points();
+        assert_eq!(points[0].name, "payout/redirect");
+        let changes = reopened
+            .update(
+                SArc(points[0].point.path.clone()),
+                SArc(Arc::new("to_two".into())),
+                json!(2),
+            )
+            .unwrap();
+        assert!(changes.changes.iter().any(|c| matches!(
+            c,
+            Change::Added {
+                kind: NodeKind::Template,
+                ..
+            }
+        )));
+        let script = |c: &Compiled| bitcoin::Script::from(c.address.clone());
+        assert_eq!(script(reopened.compiled()), script(&saved.compiled));
+        assert_eq!(reopened.compiled().suggested_txs.len(), 1);
+        // the update is kept, so the updated contract can be reopened too
+        let updated = reopened.into_saved();
+        assert!(reopen(&module, updated).is_ok());
+
+        let error = reopen(&Module { fee: 2000 }, saved.clone()).err().unwrap();
+        match &error {
+            ReopenError::Drift { module, root, .. } => {
+                assert_eq!(module, "payout");
+                let (old, new) = root.as_ref().unwrap();
+                assert_ne!(old, new);
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        let message = error.to_string();
+        assert!(message.contains("doesn't reproduce the saved contract"));
+        assert!(message.contains("changed CTV hash"));
+    }
+}
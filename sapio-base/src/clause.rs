@@ -0,0 +1,1018 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers for constructing `Clause`s
+use crate::musig::{MuSigError, MuSigKeySet};
+use crate::timelocks::{
+    check_compatible, AbsHeight, AbsTime, CombinedLocks, LockMixError, Sequence, START_OF_TIME,
+};
+use crate::Clause;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::XOnlyPublicKey;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// Errors from building a weighted threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WeightedThresholdError {
+    /// The threshold must be at least 1
+    ZeroThreshold,
+    /// The threshold (first) is more than the total weight (second)
+    Unreachable(usize, usize),
+    /// The weights differ and there are more than `MAX_WEIGHTED_SUBS`
+    /// `subs` (the number given) to expand
+    TooManySubs(usize),
+}
+
+/// The most `subs` with differing weights a `WeightedThreshold` expands, as
+/// the expansion is exponential in their number
+pub const MAX_WEIGHTED_SUBS: usize = 16;
+
+impl fmt::Display for WeightedThresholdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for WeightedThresholdError {}
+
+/// Construct thresholds where each `Clause` may count more than once.
+pub trait WeightedThreshold: Sized {
+    /// Create a Clause satisfied when the weights of the satisfied `subs` sum
+    /// to at least `k`. Zero weight `subs` are ignored.
+    ///
+    /// If all weights are 1 this is just `Threshold(k, subs)`, otherwise it is
+    /// expanded to the `Or` of every minimal set of `subs` reaching `k`, which
+    /// is deterministic in the order of `subs` but may be exponential in their
+    /// number, so at most `MAX_WEIGHTED_SUBS` are.
+    fn weighted_threshold(k: usize, subs: &[(Self, usize)])
+        -> Result<Self, WeightedThresholdError>;
+}
+
+impl WeightedThreshold for Clause {
+    fn weighted_threshold(
+        k: usize,
+        subs: &[(Self, usize)],
+    ) -> Result<Self, WeightedThresholdError> {
+        let subs: Vec<_> = subs.iter().filter(|(_, w)| *w > 0).collect();
+        let total: usize = subs.iter().map(|(_, w)| w).sum();
+        if k == 0 {
+            return Err(WeightedThresholdError::ZeroThreshold);
+        }
+        if k > total {
+            return Err(WeightedThresholdError::Unreachable(k, total));
+        }
+        if subs.iter().all(|(_, w)| *w == 1) {
+            return Ok(Clause::Threshold(
+                k,
+                subs.into_iter().map(|(c, _)| c.clone()).collect(),
+            ));
+        }
+        if subs.len() > MAX_WEIGHTED_SUBS {
+            return Err(WeightedThresholdError::TooManySubs(subs.len()));
+        }
+        // every set reaching k which no longer does if its lightest member is
+        // removed, in order of the bitmask of members
+        let mut minimal: Vec<Clause> = (1u64..(1 << subs.len()))
+            .filter_map(|mask| {
+                let members: Vec<_> = subs
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask & (1 << i) != 0)
+                    .map(|(_, s)| s)
+                    .collect();
+                let weight: usize = members.iter().map(|(_, w)| w).sum();
+                let lightest = members.iter().map(|(_, w)| *w).min()?;
+                if weight >= k && weight - lightest < k {
+                    let mut clauses: Vec<_> = members.into_iter().map(|(c, _)| c.clone()).collect();
+                    Some(if clauses.len() == 1 {
+                        clauses.remove(0)
+                    } else {
+                        Clause::And(clauses)
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Ok(if minimal.len() == 1 {
+            minimal.remove(0)
+        } else {
+            Clause::Threshold(1, minimal)
+        })
+    }
+}
+
+/// Construct an N-of-N of keys as a single key.
+pub trait MuSig: Sized {
+    /// A `Clause::Key` for the BIP-327 MuSig2 aggregate of `keys`, sorted
+    /// first so that their order doesn't matter. Signers need the original
+    /// `keys` to sign, so guards using this should also attach the
+    /// [`MuSigKeySet`] as metadata.
+    fn musig(keys: &[XOnlyPublicKey]) -> Result<Self, MuSigError>;
+}
+
+impl MuSig for Clause {
+    fn musig(keys: &[XOnlyPublicKey]) -> Result<Self, MuSigError> {
+        Ok(MuSigKeySet::new(keys)?.clause())
+    }
+}
+
+/// Find `Clause`s which can't be satisfied because a single satisfaction
+/// needs a lock time in both blocks and seconds, as a transaction's nLockTime
+/// and an input's sequence can each only be one or the other.
+pub trait MixedTimeLocks {
+    /// A height and a time lock of the same kind, absolute or relative, which
+    /// some satisfaction needs together, if any. Locks in different branches
+    /// of an `Or` are never mixed.
+    fn mixed_time_locks(&self) -> Option<LockMixError>;
+}
+
+/// The largest lock of each kind some satisfaction of a `Clause` may need,
+/// and the first pair needed together
+#[derive(Default)]
+struct PathLocks {
+    locks: CombinedLocks,
+    mixed: Option<LockMixError>,
+}
+
+impl PathLocks {
+    /// `together` if the satisfaction of one sub may be combined with
+    /// that of another
+    fn of_subs<'a, I: Iterator<Item = &'a Clause>>(subs: I, together: bool) -> PathLocks {
+        let mut acc = PathLocks::default();
+        for sub in subs.map(PathLocks::of) {
+            acc.mixed = acc.mixed.or(sub.mixed);
+            if together && acc.mixed.is_none() {
+                acc.mixed = acc
+                    .locks
+                    .iter()
+                    .flat_map(|a| sub.locks.iter().map(move |b| [a, b]))
+                    .find_map(|pair| check_compatible(&pair).err());
+            }
+            acc.locks.merge(&sub.locks);
+        }
+        acc
+    }
+    fn of(clause: &Clause) -> PathLocks {
+        let mut locks = CombinedLocks::default();
+        match clause {
+            Clause::After(n) if *n < START_OF_TIME.get() => {
+                locks.abs_height = AbsHeight::try_from(*n).ok()
+            }
+            Clause::After(n) => locks.abs_time = AbsTime::try_from(*n).ok(),
+            Clause::Older(n) => {
+                if let Some(lock) = Sequence(*n).relative_lock() {
+                    locks.add(lock.into())
+                }
+            }
+            Clause::And(subs) => return PathLocks::of_subs(subs.iter(), true),
+            Clause::Or(subs) => return PathLocks::of_subs(subs.iter().map(|(_, s)| s), false),
+            Clause::Threshold(k, subs) => return PathLocks::of_subs(subs.iter(), *k > 1),
+            _ => {}
+        }
+        PathLocks { locks, mixed: None }
+    }
+}
+
+impl MixedTimeLocks for Clause {
+    fn mixed_time_locks(&self) -> Option<LockMixError> {
+        PathLocks::of(self).mixed
+    }
+}
+
+/// Rewrite `Clause`s into a smaller, canonical form with the same
+/// satisfactions.
+pub trait Simplify {
+    /// Flatten nested `And`s and `Or`s, drop duplicate sub-clauses, fold
+    /// `Trivial` and `Unsatisfiable` sub-clauses, and sort sub-clauses so that
+    /// clauses which differ only in order simplify identically.
+    ///
+    /// `And`s and `Or`s are rebuilt with two sub-clauses each, as miniscript
+    /// requires. Flattening an `Or` keeps the probability of each sub-clause
+    /// that its weights imply, and the weights of duplicates are summed.
+    fn simplify(&self) -> Self;
+}
+
+impl Simplify for Clause {
+    fn simplify(&self) -> Self {
+        binary(flat(self))
+    }
+}
+
+/// simplify `clause`, leaving `And`s and `Or`s with any number of
+/// sub-clauses
+fn flat(clause: &Clause) -> Clause {
+    match clause {
+        Clause::And(subs) => {
+            let mut terms = vec![];
+            for sub in subs {
+                match flat(sub) {
+                    Clause::Unsatisfiable => return Clause::Unsatisfiable,
+                    Clause::Trivial => {}
+                    Clause::And(subs) => terms.extend(subs),
+                    sub => terms.push(sub),
+                }
+            }
+            terms.sort();
+            terms.dedup();
+            match terms.len() {
+                0 => Clause::Trivial,
+                1 => terms.remove(0),
+                _ => Clause::And(terms),
+            }
+        }
+        Clause::Or(subs) => {
+            let mut subs_terms = vec![];
+            for (w, sub) in subs {
+                match flat(sub) {
+                    Clause::Trivial => return Clause::Trivial,
+                    Clause::Unsatisfiable => {}
+                    Clause::Or(subs) => subs_terms.push((*w, subs)),
+                    sub => subs_terms.push((*w, vec![(1, sub)])),
+                }
+            }
+            // weights too large to scale leave the nested `Or`s as they are
+            let mut terms = scale_or_terms(&subs_terms).unwrap_or_else(|| {
+                subs_terms
+                    .into_iter()
+                    .map(|(w, mut terms)| match terms.len() {
+                        1 => (w, terms.remove(0).1),
+                        _ => (w, Clause::Or(terms)),
+                    })
+                    .collect()
+            });
+            terms.sort_by(|a, b| a.1.cmp(&b.1));
+            let mut merged: Vec<(usize, Clause)> = vec![];
+            for (w, t) in terms {
+                match merged.last_mut() {
+                    Some((mw, mt)) if *mt == t => *mw = mw.saturating_add(w),
+                    _ => merged.push((w, t)),
+                }
+            }
+            let divisor = merged.iter().fold(0, |d, (w, _)| gcd(d, *w)).max(1);
+            match merged.len() {
+                0 => Clause::Unsatisfiable,
+                1 => merged.remove(0).1,
+                _ => Clause::Or(merged.into_iter().map(|(w, t)| (w / divisor, t)).collect()),
+            }
+        }
+        Clause::Threshold(k, subs) => {
+            let subs: Vec<_> = subs.iter().map(flat).collect();
+            let trivial = subs.iter().filter(|s| **s == Clause::Trivial).count();
+            let mut subs: Vec<_> = subs
+                .into_iter()
+                .filter(|s| *s != Clause::Trivial && *s != Clause::Unsatisfiable)
+                .collect();
+            match k.saturating_sub(trivial) {
+                0 => Clause::Trivial,
+                k if k > subs.len() => Clause::Unsatisfiable,
+                k if k == subs.len() => flat(&Clause::And(subs)),
+                1 => flat(&Clause::Or(subs.into_iter().map(|s| (1, s)).collect())),
+                k => {
+                    subs.sort();
+                    Clause::Threshold(k, subs)
+                }
+            }
+        }
+        _ => clause.clone(),
+    }
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+fn lcm(a: usize, b: usize) -> Option<usize> {
+    (a / gcd(a, b)).checked_mul(b)
+}
+
+/// scale the weights of each sub-clause's terms to a common total, so that
+/// each term is as likely as it was before flattening, or `None` if the
+/// scaled weights don't fit in a `usize`
+fn scale_or_terms(subs_terms: &[(usize, Vec<(usize, Clause)>)]) -> Option<Vec<(usize, Clause)>> {
+    let total = |terms: &[(usize, Clause)]| {
+        terms
+            .iter()
+            .try_fold(0usize, |t, (w, _)| t.checked_add(*w))
+            .map(|t| t.max(1))
+    };
+    let common = subs_terms
+        .iter()
+        .try_fold(1, |l, (_, terms)| lcm(l, total(terms)?))?;
+    let mut scaled = vec![];
+    for (w, terms) in subs_terms {
+        let scale = w.checked_mul(common / total(terms)?)?;
+        for (v, t) in terms {
+            scaled.push((v.checked_mul(scale)?, t.clone()));
+        }
+    }
+    Some(scaled)
+}
+
+/// nest the sub-clauses of `And`s and `Or`s into pairs, the weight of each
+/// nested `Or` being the sum of its sub-clauses'
+fn binary(clause: Clause) -> Clause {
+    match clause {
+        Clause::And(subs) => {
+            let mut subs = subs.into_iter().rev().map(binary);
+            let last = subs.next().unwrap_or(Clause::Trivial);
+            subs.fold(last, |acc, s| Clause::And(vec![s, acc]))
+        }
+        Clause::Or(subs) => {
+            let mut subs = subs.into_iter().rev().map(|(w, s)| (w, binary(s)));
+            let last = subs.next().unwrap_or((1, Clause::Unsatisfiable));
+            subs.fold(last, |(acc_w, acc), (w, s)| {
+                (
+                    acc_w.saturating_add(w),
+                    Clause::Or(vec![(w, s), (acc_w, acc)]),
+                )
+            })
+            .1
+        }
+        Clause::Threshold(k, subs) => Clause::Threshold(k, subs.into_iter().map(binary).collect()),
+        clause => clause,
+    }
+}
+
+/// Estimate the witness needed to satisfy a `Clause` without compiling it,
+/// in weight units (witness bytes, including their length prefixes).
+///
+/// Signatures are 64 byte schnorr signatures, plus a sighash byte for the
+/// maximum, and hash preimages are 32 bytes. Time locks and templates add
+/// nothing to the witness. A `None` means the `Clause` is unsatisfiable.
+pub trait SatisfactionWeight {
+    /// An upper bound on the weight of any satisfaction, counting an element
+    /// to dissatisfy or not select each unused branch of an `Or` or
+    /// `Threshold`
+    fn max_satisfaction_weight(&self) -> Option<usize>;
+    /// A lower bound on the weight of any satisfaction, which doesn't count
+    /// anything to dissatisfy or not select unused branches
+    fn min_satisfaction_weight(&self) -> Option<usize>;
+}
+
+/// the most to dissatisfy `clause`, or to not select it if it can't be
+/// dissatisfied
+fn max_dissatisfaction_weight(clause: &Clause) -> usize {
+    match clause {
+        Clause::Sha256(_) | Clause::Hash256(_) | Clause::Ripemd160(_) | Clause::Hash160(_) => 33,
+        Clause::And(subs) | Clause::Threshold(_, subs) => {
+            subs.iter().map(max_dissatisfaction_weight).sum()
+        }
+        Clause::Or(subs) => subs
+            .iter()
+            .map(|(_, s)| max_dissatisfaction_weight(s))
+            .sum(),
+        _ => 1,
+    }
+}
+
+impl SatisfactionWeight for Clause {
+    fn max_satisfaction_weight(&self) -> Option<usize> {
+        match self {
+            Clause::Unsatisfiable => None,
+            Clause::Key(_) => Some(1 + 64 + 1),
+            Clause::Sha256(_) | Clause::Hash256(_) | Clause::Ripemd160(_) | Clause::Hash160(_) => {
+                Some(1 + 32)
+            }
+            Clause::And(subs) => subs.iter().map(Self::max_satisfaction_weight).sum(),
+            Clause::Or(subs) => {
+                let subs: Vec<_> = subs.iter().map(|(_, s)| s.clone()).collect();
+                Clause::Threshold(1, subs).max_satisfaction_weight()
+            }
+            // the k children which cost the most more to satisfy than to
+            // dissatisfy, with the rest dissatisfied
+            Clause::Threshold(k, subs) => {
+                let mut extra = subs
+                    .iter()
+                    .filter_map(|s| {
+                        let sat = s.max_satisfaction_weight()?;
+                        Some(sat.saturating_sub(max_dissatisfaction_weight(s)))
+                    })
+                    .collect::<Vec<_>>();
+                if extra.len() < *k {
+                    return None;
+                }
+                extra.sort_unstable_by(|a, b| b.cmp(a));
+                let dissatisfied: usize = subs.iter().map(max_dissatisfaction_weight).sum();
+                Some(dissatisfied + extra[..*k].iter().sum::<usize>())
+            }
+            _ => Some(0),
+        }
+    }
+    fn min_satisfaction_weight(&self) -> Option<usize> {
+        match self {
+            Clause::Unsatisfiable => None,
+            Clause::Key(_) => Some(1 + 64),
+            Clause::Sha256(_) | Clause::Hash256(_) | Clause::Ripemd160(_) | Clause::Hash160(_) => {
+                Some(1 + 32)
+            }
+            Clause::And(subs) => subs.iter().map(Self::min_satisfaction_weight).sum(),
+            Clause::Or(subs) => subs
+                .iter()
+                .filter_map(|(_, s)| s.min_satisfaction_weight())
+                .min(),
+            // the k children which cost the least to satisfy
+            Clause::Threshold(k, subs) => {
+                let mut sats = subs
+                    .iter()
+                    .filter_map(Self::min_satisfaction_weight)
+                    .collect::<Vec<_>>();
+                if sats.len() < *k {
+                    return None;
+                }
+                sats.sort_unstable();
+                Some(sats[..*k].iter().sum())
+            }
+            _ => Some(0),
+        }
+    }
+}
+
+/// Names to print in place of keys, e.g. `pk(alice)` instead of its hex.
+pub type KeyAliases = BTreeMap<XOnlyPublicKey, String>;
+
+/// Print a `Clause` in miniscript's concrete policy syntax, e.g.
+/// `thresh(2,pk(A),pk(B),after(100))`.
+///
+/// Keys are printed as hex unless an alias is given for them, `Or` weights of
+/// 1 are left out and `And`, `Or` and `Threshold` may have any number of
+/// children. Without aliases the output can be parsed back with
+/// [`ParsedClause`].
+pub trait ToPolicyString {
+    /// Print with every key as hex
+    fn to_policy_string(&self) -> String;
+    /// Print with the keys in `aliases` replaced by their names
+    fn to_policy_string_with_aliases(&self, aliases: &KeyAliases) -> String;
+}
+
+impl ToPolicyString for Clause {
+    fn to_policy_string(&self) -> String {
+        PolicyString(self, None).to_string()
+    }
+    fn to_policy_string_with_aliases(&self, aliases: &KeyAliases) -> String {
+        PolicyString(self, Some(aliases)).to_string()
+    }
+}
+
+struct PolicyString<'a>(&'a Clause, Option<&'a KeyAliases>);
+
+impl PolicyString<'_> {
+    fn sub<'b>(&'b self, clause: &'b Clause) -> PolicyString<'b> {
+        PolicyString(clause, self.1)
+    }
+}
+
+impl fmt::Display for PolicyString<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Clause::Unsatisfiable => f.write_str("UNSATISFIABLE"),
+            Clause::Trivial => f.write_str("TRIVIAL"),
+            Clause::Key(k) => match self.1.and_then(|a| a.get(k)) {
+                Some(alias) => write!(f, "pk({})", alias),
+                None => write!(f, "pk({})", k),
+            },
+            Clause::After(n) => write!(f, "after({})", n),
+            Clause::Older(n) => write!(f, "older({})", n),
+            Clause::Sha256(h) => write!(f, "sha256({})", h),
+            Clause::Hash256(h) => write!(f, "hash256({})", h),
+            Clause::Ripemd160(h) => write!(f, "ripemd160({})", h),
+            Clause::Hash160(h) => write!(f, "hash160({})", h),
+            Clause::TxTemplate(h) => write!(f, "txtmpl({})", h),
+            Clause::And(subs) => {
+                f.write_str("and(")?;
+                for (i, sub) in subs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{}", self.sub(sub))?;
+                }
+                f.write_str(")")
+            }
+            Clause::Or(subs) => {
+                f.write_str("or(")?;
+                for (i, (w, sub)) in subs.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+                    if *w != 1 {
+                        write!(f, "{}@", w)?;
+                    }
+                    write!(f, "{}", self.sub(sub))?;
+                }
+                f.write_str(")")
+            }
+            Clause::Threshold(k, subs) => {
+                write!(f, "thresh({}", k)?;
+                for sub in subs {
+                    write!(f, ",{}", self.sub(sub))?;
+                }
+                f.write_str(")")
+            }
+        }
+    }
+}
+
+/// Error parsing a policy string, with the byte offset it was found at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PolicyParseError(pub usize, pub String);
+
+impl fmt::Display for PolicyParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid policy at {}: {}", self.0, self.1)
+    }
+}
+
+impl std::error::Error for PolicyParseError {}
+
+/// A `Clause` parsed from the syntax printed by [`ToPolicyString`], which
+/// unlike miniscript's own parser allows any number of children for `and`
+/// and `or`. Aliases can't be parsed back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedClause(pub Clause);
+
+impl FromStr for ParsedClause {
+    type Err = PolicyParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = PolicyParser { s, at: 0 };
+        let clause = parser.clause()?;
+        if parser.at != s.len() {
+            return Err(parser.error("trailing characters"));
+        }
+        Ok(ParsedClause(clause))
+    }
+}
+
+struct PolicyParser<'a> {
+    s: &'a str,
+    at: usize,
+}
+
+impl PolicyParser<'_> {
+    fn error(&self, why: &str) -> PolicyParseError {
+        PolicyParseError(self.at, why.into())
+    }
+    /// the next run of characters up to a delimiter
+    fn token(&mut self) -> &str {
+        let rest = &self.s[self.at..];
+        let len = rest.find(['(', ')', ',', '@']).unwrap_or(rest.len());
+        self.at += len;
+        &rest[..len]
+    }
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.s[self.at..].starts_with(c);
+        if found {
+            self.at += c.len_utf8();
+        }
+        found
+    }
+    fn expect(&mut self, c: char) -> Result<(), PolicyParseError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", c)))
+        }
+    }
+    fn parse<T: FromStr>(&mut self, what: &str) -> Result<T, PolicyParseError> {
+        let start = self.at;
+        self.token()
+            .parse()
+            .map_err(|_| PolicyParseError(start, format!("invalid {}", what)))
+    }
+    fn hash<T: FromHex>(&mut self) -> Result<T, PolicyParseError> {
+        let start = self.at;
+        T::from_hex(self.token()).map_err(|_| PolicyParseError(start, "invalid hash".into()))
+    }
+    fn clause(&mut self) -> Result<Clause, PolicyParseError> {
+        let start = self.at;
+        let name = self.token().to_string();
+        if name == "TRIVIAL" {
+            return Ok(Clause::Trivial);
+        }
+        if name == "UNSATISFIABLE" {
+            return Ok(Clause::Unsatisfiable);
+        }
+        self.expect('(')?;
+        let clause = match name.as_str() {
+            "pk" => Clause::Key(self.parse("key")?),
+            "after" => Clause::After(self.parse("lock time")?),
+            "older" => Clause::Older(self.parse("sequence")?),
+            "sha256" => Clause::Sha256(self.hash()?),
+            "hash256" => Clause::Hash256(self.hash()?),
+            "ripemd160" => Clause::Ripemd160(self.hash()?),
+            "hash160" => Clause::Hash160(self.hash()?),
+            "txtmpl" => Clause::TxTemplate(self.hash()?),
+            "and" => Clause::And(self.subs()?),
+            "thresh" => {
+                let k = self.parse("threshold")?;
+                self.expect(',')?;
+                Clause::Threshold(k, self.subs()?)
+            }
+            "or" => {
+                let mut subs = vec![];
+                loop {
+                    let before = self.at;
+                    let weight = match self.token().parse() {
+                        Ok(w) if self.eat('@') => w,
+                        _ => {
+                            self.at = before;
+                            1
+                        }
+                    };
+                    subs.push((weight, self.clause()?));
+                    if !self.eat(',') {
+                        break;
+                    }
+                }
+                Clause::Or(subs)
+            }
+            _ => {
+                return Err(PolicyParseError(
+                    start,
+                    format!("unknown fragment `{}`", name),
+                ))
+            }
+        };
+        self.expect(')')?;
+        Ok(clause)
+    }
+    /// one or more comma separated `Clause`s
+    fn subs(&mut self) -> Result<Vec<Clause>, PolicyParseError> {
+        let mut subs = vec![self.clause()?];
+        while self.eat(',') {
+            subs.push(self.clause()?);
+        }
+        Ok(subs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timelocks::{RelHeight, RelTime};
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{KeyPair, XOnlyPublicKey};
+    use miniscript::policy::Liftable;
+    fn key(k: u8) -> Clause {
+        let secp = Secp256k1::new();
+        let kp = KeyPair::from_seckey_slice(&secp, &[k; 32]).unwrap();
+        Clause::Key(XOnlyPublicKey::from_keypair(&kp).0)
+    }
+    #[test]
+    fn unit_weights_are_threshold() {
+        let keys: Vec<_> = (1..=5).map(key).collect();
+        let weighted: Vec<_> = keys.iter().cloned().map(|k| (k, 1)).collect();
+        assert_eq!(
+            Clause::weighted_threshold(3, &weighted),
+            Ok(Clause::Threshold(3, keys))
+        );
+    }
+    #[test]
+    fn weights_expand_to_minimal_sets() {
+        let (a, b, c) = (key(1), key(2), key(3));
+        let weighted = [(a.clone(), 2), (b.clone(), 1), (c.clone(), 1)];
+        let expected = Clause::Threshold(
+            1,
+            vec![
+                Clause::And(vec![a.clone(), b]),
+                Clause::And(vec![a.clone(), c]),
+            ],
+        );
+        assert_eq!(
+            Clause::weighted_threshold(3, &weighted),
+            Ok(expected.clone())
+        );
+        // deterministic
+        assert_eq!(Clause::weighted_threshold(3, &weighted), Ok(expected));
+        // a alone suffices at 2
+        assert_eq!(
+            Clause::weighted_threshold(2, &weighted).unwrap(),
+            Clause::Threshold(1, vec![a, Clause::And(vec![key(2), key(3)])])
+        );
+    }
+    #[test]
+    fn unreachable_threshold() {
+        let weighted = [(key(1), 2), (key(2), 1), (key(3), 0)];
+        assert_eq!(
+            Clause::weighted_threshold(4, &weighted),
+            Err(WeightedThresholdError::Unreachable(4, 3))
+        );
+        assert_eq!(
+            Clause::weighted_threshold(0, &weighted),
+            Err(WeightedThresholdError::ZeroThreshold)
+        );
+        // too many differing weights to expand, but any number of equal ones
+        let many: Vec<_> = (1..=64).map(|k| (key(k), 1 + k as usize % 2)).collect();
+        assert_eq!(
+            Clause::weighted_threshold(3, &many),
+            Err(WeightedThresholdError::TooManySubs(64))
+        );
+        let many: Vec<_> = (1..=64).map(|k| (key(k), 1)).collect();
+        assert!(Clause::weighted_threshold(3, &many).is_ok());
+    }
+    #[test]
+    fn mixed_time_locks() {
+        let (height, time) = (Clause::After(700_000), Clause::After(1_600_000_000));
+        let mixed = Some(LockMixError::Absolute(
+            AbsHeight::try_from(700_000).unwrap(),
+            AbsTime::try_from(1_600_000_000).unwrap(),
+        ));
+        assert_eq!(
+            Clause::And(vec![key(1), height.clone(), time.clone()]).mixed_time_locks(),
+            mixed
+        );
+        // either path alone is fine
+        let either = Clause::Or(vec![(1, height.clone()), (1, time.clone())]);
+        assert_eq!(either.mixed_time_locks(), None);
+        // but not when combined with a lock of the other kind
+        assert_eq!(
+            Clause::And(vec![either, Clause::After(600_000)]).mixed_time_locks(),
+            Some(LockMixError::Absolute(
+                AbsHeight::try_from(600_000).unwrap(),
+                AbsTime::try_from(1_600_000_000).unwrap()
+            ))
+        );
+        assert_eq!(
+            Clause::Threshold(2, vec![height.clone(), key(1), time.clone()]).mixed_time_locks(),
+            mixed
+        );
+        assert_eq!(
+            Clause::Threshold(1, vec![height.clone(), time]).mixed_time_locks(),
+            None
+        );
+        // nor may a relative height and time lock be needed together
+        let relative = Some(LockMixError::Relative(
+            RelHeight::from(144),
+            RelTime::from(1),
+        ));
+        assert_eq!(
+            Clause::And(vec![
+                Clause::Older(144),
+                Clause::Older(4_194_305),
+                height.clone()
+            ])
+            .mixed_time_locks(),
+            relative
+        );
+        assert_eq!(
+            Clause::Or(vec![(1, Clause::Older(144)), (1, Clause::Older(4_194_305))])
+                .mixed_time_locks(),
+            None
+        );
+        // but absolute and relative locks combine freely
+        assert_eq!(
+            Clause::And(vec![Clause::Older(4_194_305), height]).mixed_time_locks(),
+            None
+        );
+    }
+    /// xorshift, so that failures are reproducible
+    fn next(seed: &mut u64, n: u64) -> usize {
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 7;
+        *seed ^= *seed << 17;
+        (*seed % n) as usize
+    }
+    /// a random clause from a few atoms, so that duplicates are common
+    fn random_clause(seed: &mut u64, depth: u8) -> Clause {
+        match next(seed, if depth == 0 { 6 } else { 9 }) {
+            0 => key(1),
+            1 => key(2),
+            2 => Clause::After(10),
+            3 => Clause::Older(5),
+            4 => Clause::Trivial,
+            5 => Clause::Unsatisfiable,
+            6 => Clause::And(vec![
+                random_clause(seed, depth - 1),
+                random_clause(seed, depth - 1),
+            ]),
+            7 => Clause::Or(vec![
+                (1 + next(seed, 3), random_clause(seed, depth - 1)),
+                (1 + next(seed, 3), random_clause(seed, depth - 1)),
+            ]),
+            _ => {
+                let n = 2 + next(seed, 2);
+                let k = 1 + next(seed, n as u64);
+                let subs = (0..n).map(|_| random_clause(seed, depth - 1)).collect();
+                Clause::Threshold(k, subs)
+            }
+        }
+    }
+    #[test]
+    fn simplify_preserves_satisfactions() {
+        let mut seed = 0x5a9105eed;
+        for _ in 0..300 {
+            let clause = random_clause(&mut seed, 3);
+            let simple = clause.simplify();
+            let (a, b) = (clause.lift().unwrap(), simple.lift().unwrap());
+            assert!(
+                a.clone().entails(b.clone()).unwrap(),
+                "{} => {}",
+                clause,
+                simple
+            );
+            assert!(b.entails(a).unwrap(), "{} => {}", simple, clause);
+            // canonical, and compilable whenever the original was
+            assert_eq!(simple.simplify(), simple);
+            if clause.is_valid().is_ok() {
+                assert!(simple.is_valid().is_ok(), "{}", simple);
+            }
+        }
+    }
+    #[test]
+    fn simplify() {
+        let (x, y) = (key(1), key(2));
+        assert_eq!(
+            Clause::And(vec![x.clone(), Clause::And(vec![x.clone(), y.clone()])]).simplify(),
+            Clause::And(vec![x.clone(), y.clone()]).simplify()
+        );
+        assert_eq!(
+            Clause::Or(vec![
+                (1, x.clone()),
+                (1, Clause::Or(vec![(1, y.clone()), (1, x.clone())]))
+            ])
+            .simplify(),
+            Clause::Or(vec![(3, x.clone()), (1, y.clone())]).simplify()
+        );
+        assert_eq!(
+            Clause::Or(vec![(1, x.clone()), (1, Clause::Unsatisfiable)]).simplify(),
+            x
+        );
+        assert_eq!(
+            Clause::And(vec![
+                x.clone(),
+                Clause::Or(vec![(1, y), (1, Clause::Trivial)])
+            ])
+            .simplify(),
+            x
+        );
+        assert_eq!(
+            Clause::Threshold(2, vec![x.clone(), Clause::Trivial, Clause::Unsatisfiable])
+                .simplify(),
+            x
+        );
+    }
+    #[test]
+    fn simplify_large_weights() {
+        let (x, y, z) = (key(1), key(2), key(3));
+        let big = usize::MAX / 2;
+        // scaling to a common total would overflow, so the nested `Or` stays
+        let clause = Clause::Or(vec![
+            (big, x.clone()),
+            (1, Clause::Or(vec![(big, y.clone()), (big - 1, z.clone())])),
+        ]);
+        let simple = clause.simplify();
+        let (a, b) = (clause.lift().unwrap(), simple.lift().unwrap());
+        assert!(a.clone().entails(b.clone()).unwrap());
+        assert!(b.entails(a).unwrap());
+        assert_eq!(
+            simple,
+            Clause::Or(vec![
+                (big, x.clone()),
+                (1, Clause::Or(vec![(big, y.clone()), (big - 1, z.clone())]))
+            ])
+        );
+        // small enough weights are still flattened exactly
+        assert_eq!(
+            Clause::Or(vec![
+                (2, x.clone()),
+                (1, Clause::Or(vec![(3, y.clone()), (1, z.clone())]))
+            ])
+            .simplify(),
+            Clause::Or(vec![(8, x), (3, y), (1, z)]).simplify()
+        );
+    }
+    fn preimage(i: u8) -> Clause {
+        Clause::Sha256(bitcoin::hashes::sha256::Hash::hash(&[i]))
+    }
+    #[test]
+    fn satisfaction_weights() {
+        let (a, b, c) = (key(1), key(2), key(3));
+        let weights = |clause: Clause| {
+            (
+                clause.min_satisfaction_weight(),
+                clause.max_satisfaction_weight(),
+            )
+        };
+        assert_eq!(weights(a.clone()), (Some(65), Some(66)));
+        assert_eq!(
+            weights(Clause::And(vec![a.clone(), b.clone()])),
+            (Some(130), Some(132))
+        );
+        // an empty signature dissatisfies the unused key
+        assert_eq!(
+            weights(Clause::Or(vec![(1, a.clone()), (1, b.clone())])),
+            (Some(65), Some(67))
+        );
+        assert_eq!(
+            weights(Clause::Threshold(2, vec![a.clone(), b.clone(), c])),
+            (Some(130), Some(133))
+        );
+        assert_eq!(
+            weights(Clause::And(vec![
+                a.clone(),
+                Clause::After(100),
+                preimage(1)
+            ])),
+            (Some(98), Some(99))
+        );
+        assert_eq!(
+            weights(Clause::Or(vec![
+                (1, a.clone()),
+                (1, Clause::And(vec![b, Clause::Older(10)]))
+            ])),
+            (Some(65), Some(68))
+        );
+        assert_eq!(
+            weights(Clause::Threshold(2, vec![a, Clause::Unsatisfiable])),
+            (None, None)
+        );
+        // these match what miniscript computes once compiled, except that it
+        // counts each signature as 73 bytes rather than 66
+        for (clause, signatures) in [
+            (key(1), 1),
+            (Clause::Or(vec![(1, key(1)), (1, key(2))]), 1),
+            (
+                Clause::And(vec![key(1), Clause::And(vec![preimage(1), preimage(2)])]),
+                1,
+            ),
+            (
+                Clause::And(vec![
+                    key(1),
+                    Clause::And(vec![preimage(1), Clause::Older(10)]),
+                ]),
+                1,
+            ),
+            (Clause::And(vec![key(1), key(2)]), 2),
+        ] {
+            let ms: miniscript::Miniscript<_, miniscript::Tap> = clause.compile().unwrap();
+            assert_eq!(
+                clause.max_satisfaction_weight().map(|w| w + 7 * signatures),
+                ms.max_satisfaction_size().ok(),
+                "{}",
+                clause
+            );
+        }
+    }
+    #[test]
+    fn policy_strings() {
+        let (a, b) = match (key(1), key(2)) {
+            (Clause::Key(a), Clause::Key(b)) => (a, b),
+            _ => unreachable!(),
+        };
+        let clause = Clause::Or(vec![
+            (
+                3,
+                Clause::Threshold(2, vec![key(1), key(2), Clause::After(100)]),
+            ),
+            (1, Clause::And(vec![key(2), Clause::Older(6), preimage(1)])),
+        ]);
+        let printed = clause.to_policy_string();
+        let preimage = match preimage(1) {
+            Clause::Sha256(h) => h,
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            printed,
+            format!(
+                "or(3@thresh(2,pk({a}),pk({b}),after(100)),and(pk({b}),older(6),sha256({h})))",
+                a = a,
+                b = b,
+                h = preimage
+            )
+        );
+        assert_eq!(printed.parse(), Ok(ParsedClause(clause.clone())));
+
+        let aliases: KeyAliases = vec![(a, "alice".into())].into_iter().collect();
+        assert!(clause
+            .to_policy_string_with_aliases(&aliases)
+            .starts_with(&format!("or(3@thresh(2,pk(alice),pk({}),after(100))", b)));
+
+        let mut seed = 0x9a75e;
+        for _ in 0..300 {
+            let clause = random_clause(&mut seed, 3);
+            let parsed: ParsedClause = clause.to_policy_string().parse().unwrap();
+            assert_eq!(parsed.0, clause);
+        }
+
+        for bad in &[
+            "pk(alice)",
+            "and(TRIVIAL",
+            "or(TRIVIAL,)",
+            "thresh(x,TRIVIAL)",
+            "nope()",
+            "TRIVIAL)",
+        ] {
+            assert!(bad.parse::<ParsedClause>().is_err(), "{}", bad);
+        }
+    }
+}
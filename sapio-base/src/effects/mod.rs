@@ -21,6 +21,23 @@ pub use reverse_path::*;
 /// Convenience type name for an EffectPath
 pub type EffectPath = ReversePath<PathFragment>;
 
+impl EffectPath {
+    /// A human readable rendering of the path with only the root and the
+    /// named fragments, e.g. `root/branch_a/leaf`
+    pub fn label(&self) -> String {
+        let mut names: Vec<&str> = self
+            .iter()
+            .filter_map(|fragment| match fragment {
+                PathFragment::Root => Some("root"),
+                PathFragment::Named(SArc(name)) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        names.reverse();
+        names.join("/")
+    }
+}
+
 /// Error types for EffectDB Accesses
 #[derive(Debug)]
 pub enum EffectDBError {
@@ -81,6 +98,10 @@ impl MapEffectDB {
     pub fn skip_serializing(&self) -> bool {
         self.effects.is_empty()
     }
+    /// all the paths which have effects to process
+    pub fn paths(&self) -> impl Iterator<Item = &Arc<EffectPath>> {
+        self.effects.keys().map(|k| &k.0)
+    }
 }
 
 impl EffectDB for MapEffectDB {
@@ -118,6 +118,13 @@ impl<T, Y> ReversePath<T, Y> {
     pub fn iter(&self) -> RPI<'_, T, Y> {
         RPI { inner: Some(self) }
     }
+    /// is `prefix` this path or one of its ancestors?
+    pub fn has_prefix(&self, prefix: &ReversePath<T, Y>) -> bool
+    where
+        Self: PartialEq,
+    {
+        self == prefix || self.past.as_ref().is_some_and(|p| p.has_prefix(prefix))
+    }
 }
 
 #[cfg(test)]
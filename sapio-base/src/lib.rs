@@ -20,6 +20,10 @@ pub mod txindex;
 
 pub mod effects;
 pub use effects::reverse_path;
+#[cfg(feature = "cbor")]
+pub mod cbor;
+pub mod clause;
+pub mod musig;
 pub mod serialization_helpers;
 
 /// Concrete Instantiation of Miniscript Policy. Because we need to be able to generate exact
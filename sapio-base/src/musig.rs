@@ -0,0 +1,236 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! BIP-327 MuSig2 key aggregation, so that an N-of-N set of signers can
+//! guard a branch with a single key instead of an N key threshold.
+use crate::simp::{CompiledObjectLT, GuardLT, SIMPAttachableAt, SIMP};
+use crate::Clause;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::{Hash, HashEngine};
+use bitcoin::secp256k1::{Parity, PublicKey, Scalar, Secp256k1, XOnlyPublicKey};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Errors from aggregating keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuSigError {
+    /// There must be at least one key
+    NoKeys,
+    /// The keys sum to the point at infinity
+    InfiniteAggregate,
+}
+
+impl fmt::Display for MuSigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for MuSigError {}
+
+/// the order of the secp256k1 group
+const N: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+    0xba, 0xae, 0xdc, 0xe6, 0xaf, 0x48, 0xa0, 0x3b, 0xbf, 0xd2, 0x5e, 0x8c, 0xd0, 0x36, 0x41, 0x41,
+];
+
+fn tagged_hash(tag: &str, data: &[&[u8]]) -> [u8; 32] {
+    let tag = sha256::Hash::hash(tag.as_bytes());
+    let mut engine = sha256::Hash::engine();
+    engine.input(&tag[..]);
+    engine.input(&tag[..]);
+    for d in data {
+        engine.input(d);
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// reduce a hash modulo `N`, which needs at most one subtraction
+fn to_scalar(mut bytes: [u8; 32]) -> Scalar {
+    if bytes >= N {
+        let mut borrow = 0;
+        for i in (0..32).rev() {
+            let (d, b1) = bytes[i].overflowing_sub(N[i]);
+            let (d, b2) = d.overflowing_sub(borrow);
+            bytes[i] = d;
+            borrow = (b1 || b2) as u8;
+        }
+    }
+    Scalar::from_be_bytes(bytes).expect("reduced modulo N")
+}
+
+/// Sort `keys` by their compressed serialization (BIP-327 `KeySort`)
+pub fn key_sort(keys: &[PublicKey]) -> Vec<PublicKey> {
+    let mut keys = keys.to_vec();
+    keys.sort_by_key(|k| k.serialize());
+    keys
+}
+
+/// Aggregate `keys` in the order given (BIP-327 `KeyAgg`), returning the
+/// x-only aggregate key.
+///
+/// The aggregate depends on the order of `keys`, see [`key_sort`].
+pub fn key_agg(keys: &[PublicKey]) -> Result<XOnlyPublicKey, MuSigError> {
+    let first = keys.first().ok_or(MuSigError::NoKeys)?;
+    let serialized: Vec<[u8; 33]> = keys.iter().map(PublicKey::serialize).collect();
+    let list = tagged_hash(
+        "KeyAgg list",
+        &serialized.iter().map(|k| &k[..]).collect::<Vec<_>>(),
+    );
+    // the first key which differs from the first has a coefficient of 1
+    let second = keys.iter().find(|k| *k != first);
+    let secp = Secp256k1::verification_only();
+    let terms = keys
+        .iter()
+        .zip(serialized.iter())
+        .map(|(k, s)| {
+            if Some(k) == second {
+                Ok(*k)
+            } else {
+                let coefficient = to_scalar(tagged_hash("KeyAgg coefficient", &[&list, s]));
+                k.mul_tweak(&secp, &coefficient)
+                    .map_err(|_| MuSigError::InfiniteAggregate)
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let aggregate = PublicKey::combine_keys(&terms.iter().collect::<Vec<_>>())
+        .map_err(|_| MuSigError::InfiniteAggregate)?;
+    Ok(aggregate.x_only_public_key().0)
+}
+
+/// The signers behind a MuSig2 aggregate key, which a `Guard` can attach as
+/// metadata so that they can later find each other to sign.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct MuSigKeySet {
+    /// The keys, in the order they were aggregated
+    #[schemars(with = "Vec<String>")]
+    pub keys: Vec<XOnlyPublicKey>,
+    /// The aggregate of `keys`
+    #[schemars(with = "String")]
+    pub aggregate: XOnlyPublicKey,
+}
+
+impl MuSigKeySet {
+    /// Aggregate `keys` in sorted-keys mode, so any order of the same keys
+    /// gives the same aggregate. Each x-only key is taken with an even y.
+    pub fn new(keys: &[XOnlyPublicKey]) -> Result<Self, MuSigError> {
+        let keys: Vec<PublicKey> = keys
+            .iter()
+            .map(|k| PublicKey::from_x_only_public_key(*k, Parity::Even))
+            .collect();
+        let keys = key_sort(&keys);
+        Ok(MuSigKeySet {
+            aggregate: key_agg(&keys)?,
+            keys: keys.iter().map(|k| k.x_only_public_key().0).collect(),
+        })
+    }
+    /// A `Clause` requiring a signature from the aggregate key
+    pub fn clause(&self) -> Clause {
+        Clause::Key(self.aggregate)
+    }
+}
+
+impl SIMP for MuSigKeySet {
+    fn static_get_protocol_number() -> i64 {
+        -327
+    }
+    fn get_protocol_number(&self) -> i64 {
+        Self::static_get_protocol_number()
+    }
+    fn to_json(&self) -> Result<serde_json::Value, serde_json::Error> {
+        serde_json::to_value(self)
+    }
+    fn from_json(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        serde_json::from_value(value)
+    }
+}
+
+impl SIMPAttachableAt<GuardLT> for MuSigKeySet {}
+impl SIMPAttachableAt<CompiledObjectLT> for MuSigKeySet {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::str::FromStr;
+    fn pk(s: &str) -> PublicKey {
+        PublicKey::from_str(s).unwrap()
+    }
+    fn x(s: &str) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_str(s).unwrap()
+    }
+    /// from BIP-327's key_agg_vectors.json
+    #[test]
+    fn key_agg_vectors() {
+        let keys = [
+            pk("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            pk("03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659"),
+            pk("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"),
+        ];
+        for (indices, expected) in &[
+            (
+                &[0, 1, 2][..],
+                "90539EEDE565F5D054F32CC0C220126889ED1E5D193BAF15AEF344FE59D4610C",
+            ),
+            (
+                &[2, 1, 0][..],
+                "6204DE8B083426DC6EAF9502D27024D53FC826BF7D2012148A0575435DF54B2B",
+            ),
+            (
+                &[0, 0, 0][..],
+                "B436E3BAD62B8CD409969A224731C193D051162D8C5AE8B109306127DA3AA935",
+            ),
+            (
+                &[0, 0, 1, 1][..],
+                "69BC22BFA5D106306E48A20679DE1D7389386124D07571D0D872686028C26A3E",
+            ),
+        ] {
+            let keys: Vec<_> = indices.iter().map(|i| keys[*i]).collect();
+            assert_eq!(key_agg(&keys), Ok(x(expected)));
+        }
+        assert_eq!(key_agg(&[]), Err(MuSigError::NoKeys));
+    }
+    /// from BIP-327's key_sort_vectors.json
+    #[test]
+    fn key_sort_vectors() {
+        let keys = [
+            "02DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+            "02DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EFF",
+            "02DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+        ];
+        let sorted = [
+            "023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66",
+            "02DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+            "02DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+            "02DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EFF",
+            "02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "03DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+        ];
+        assert_eq!(
+            key_sort(&keys.iter().map(|k| pk(k)).collect::<Vec<_>>()),
+            sorted.iter().map(|k| pk(k)).collect::<Vec<_>>()
+        );
+    }
+    #[test]
+    fn sorted_key_sets() {
+        let a = x("F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9");
+        let b = x("3590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66");
+        let set = MuSigKeySet::new(&[a, b]).unwrap();
+        assert_eq!(set, MuSigKeySet::new(&[b, a]).unwrap());
+        assert_eq!(set.keys, vec![b, a]);
+        assert_eq!(
+            Ok(set.aggregate),
+            key_agg(&[
+                pk("023590A94E768F8E1815C2F24B4D80A8E3149316C3518CE7B7AD338368D038CA66"),
+                pk("02F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9"),
+            ])
+        );
+        assert_eq!(MuSigKeySet::from_json(set.to_json().unwrap()).unwrap(), set);
+    }
+}
@@ -6,6 +6,7 @@
 
 //! arguments for passing into a sapio module
 use crate::effects::MapEffectDB;
+use crate::timelocks::{AbsHeight, AbsTime};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
@@ -44,8 +45,57 @@ pub struct ContextualArguments {
     #[schemars(with = "u64")]
     /// # The Amount of Funds Available to the Contract as Bitcoin.
     pub amount: bitcoin::util::amount::Amount,
+    /// # The Feerate to pay fees with in sats per vbyte, if any.
+    #[serde(
+        with = "bitcoin::util::amount::serde::as_sat::opt",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    #[schemars(with = "Option<u64>")]
+    pub feerate: Option<bitcoin::util::amount::Amount>,
+    /// # Seed for deterministically deriving entropy, if any.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    #[schemars(with = "Option<String>")]
+    pub entropy_seed: Option<bitcoin::hashes::sha256::Hash>,
+    /// # Height of the chain tip, if known
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tip_height: Option<AbsHeight>,
+    /// # Median time past of the chain tip, if known
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub median_time: Option<AbsTime>,
+    /// # Whether to profile the compilation, see `Context::with_profiling`
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub profile: bool,
 
     /// # Effects to augment compilations with
     #[serde(skip_serializing_if = "MapEffectDB::skip_serializing", default)]
     pub effects: MapEffectDB,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    #[test]
+    fn network_round_trips() {
+        for network in [Network::Bitcoin, Network::Signet, Network::Regtest] {
+            let args = CreateArgs {
+                arguments: (),
+                context: ContextualArguments {
+                    network,
+                    amount: Amount::from_sat(1),
+                    feerate: None,
+                    entropy_seed: None,
+                    tip_height: None,
+                    median_time: None,
+                    profile: false,
+                    effects: Default::default(),
+                },
+            };
+            let json = serde_json::to_value(&args).unwrap();
+            let back: CreateArgs<()> = serde_json::from_value(json).unwrap();
+            assert_eq!(back.context.network, network);
+        }
+    }
+}
@@ -24,6 +24,13 @@ pub enum LockTimeError {
     HeightTooHigh(u32),
     /// sequence type is unknown
     UnknownSeqType(u32),
+    /// relative lock is more than the 65535 blocks or 512 second units a
+    /// sequence can encode
+    RelativeTooLarge(u32),
+    /// date (in unix seconds) is before the genesis block
+    BeforeGenesis(i64),
+    /// string is not a lock time, see [`AnyAbsTimeLock`]'s `FromStr`
+    InvalidSyntax(String),
 }
 
 /// Type Tags used for creating lock time variants. The module lets us keep them
@@ -41,29 +48,42 @@ pub mod type_tags {
     }
     use super::*;
     /// Type Tag for Realtive
-    #[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug,
+    )]
     pub struct Rel;
     /// Type Tag for Absolute
-    #[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug,
+    )]
     pub struct Abs;
     /// Type Tag for Height
-    #[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug,
+    )]
     pub struct Height;
     /// Type Tag for Median Time Passed
-    #[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+    #[derive(
+        JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug,
+    )]
     pub struct MTP;
 }
 use type_tags::*;
 
 /// LockTime represents either a nLockTime or a Sequence field.
 /// They are represented generically in the same type
-#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
-#[serde(transparent)]
+///
+/// Serialized as the raw `u32`, but absolute lock times may also be
+/// deserialized from the strings accepted by their `FromStr`.
+#[derive(Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug)]
+#[serde(try_from = "parse::LockTimeRepr")]
 pub struct LockTime<RelOrAbs: Absolutivity, HeightOrTime: TimeType>(
     u32,
     #[serde(skip)] PhantomData<(RelOrAbs, HeightOrTime)>,
 );
-#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+#[derive(
+    JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug,
+)]
 /// # Any Relative Time Lock
 /// Represents a type which can be either type of relative lock
 pub enum AnyRelTimeLock {
@@ -75,9 +95,13 @@ pub enum AnyRelTimeLock {
     RT(RelTime),
 }
 
-#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq)]
+#[derive(Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug)]
+#[serde(try_from = "parse::AnyAbsTimeLockRepr")]
 /// # Any Absolute Time Lock
 /// Represents a type which can be either type of absolute lock
+///
+/// Also deserializes from a plain integer or any string accepted by its
+/// `FromStr`.
 pub enum AnyAbsTimeLock {
     /// # Absolute Height
     /// in exact block height
@@ -86,7 +110,7 @@ pub enum AnyAbsTimeLock {
     /// in unix time stamp since epoch
     AT(AbsTime),
 }
-#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone)]
+#[derive(JsonSchema, Serialize, Deserialize, Copy, Clone, Eq, PartialEq, Debug)]
 /// # Any Time Lock (Relative, Absolute) x (Height, Time)
 /// Represents a type which can be any type of lock
 pub enum AnyTimeLock {
@@ -96,6 +120,42 @@ pub enum AnyTimeLock {
     A(AnyAbsTimeLock),
 }
 
+/// # Sequence
+/// A raw nSequence value, for inputs which need something other than a
+/// relative lock time, e.g. to opt in or out of BIP125 replaceability.
+#[derive(
+    JsonSchema, Serialize, Deserialize, Copy, Clone, PartialOrd, Ord, Eq, PartialEq, Debug,
+)]
+#[serde(transparent)]
+pub struct Sequence(pub u32);
+
+impl Sequence {
+    /// Final: no relative lock time and not replaceable
+    pub const MAX: Sequence = Sequence(0xFFFF_FFFF);
+    /// Replaceable per BIP125, with no relative lock time
+    pub const ENABLE_RBF_NO_LOCKTIME: Sequence = Sequence(0xFFFF_FFFD);
+    /// Does this sequence signal replaceability per BIP125?
+    pub fn signals_rbf(&self) -> bool {
+        self.0 < 0xFFFF_FFFE
+    }
+    /// The relative lock time this sequence enforces per BIP68, if any
+    pub fn relative_lock(&self) -> Option<AnyRelTimeLock> {
+        if self.0 & (1 << 31) != 0 {
+            None
+        } else if self.0 & (1 << 22) != 0 {
+            Some(AnyRelTimeLock::RT(RelTime::from(self.0 as u16)))
+        } else {
+            Some(AnyRelTimeLock::RH(RelHeight::from(self.0 as u16)))
+        }
+    }
+}
+
+impl From<AnyRelTimeLock> for Sequence {
+    fn from(lt: AnyRelTimeLock) -> Self {
+        Sequence(lt.get())
+    }
+}
+
 /// Helpful Aliases for specific concrete lock times
 mod alias {
     use super::*;
@@ -111,6 +171,8 @@ mod alias {
     pub const BIG_PAST_DATE: AbsTime = LockTime(1_600_000_000u32, PhantomData);
     /// Minimum Date
     pub const START_OF_TIME: AbsTime = LockTime(500_000_000, PhantomData);
+    /// Timestamp of the genesis block, the earliest date parsed from a string
+    pub const GENESIS_TIME: AbsTime = LockTime(1_231_006_505, PhantomData);
 }
 pub use alias::*;
 
@@ -139,6 +201,34 @@ mod trait_impls {
             self.0
         }
     }
+    impl<TT: TimeType> LockTime<Rel, TT> {
+        /// The longest lock a sequence can encode, 65535 blocks or 65535
+        /// units of 512 seconds
+        pub const fn max_consensus() -> Self {
+            Self::from_units(u16::MAX)
+        }
+        const fn from_units(u: u16) -> Self {
+            // bit 22 specifies relative time
+            let flag = if TT::IS_HEIGHT { 0 } else { 1 << 22 };
+            Self(u as u32 | flag, PhantomData)
+        }
+        /// the number of blocks, or of 512 second units, without the type
+        /// flag
+        pub fn units(&self) -> u16 {
+            self.0 as u16
+        }
+        /// Add two locks, or `None` if the sum is more than
+        /// [`Self::max_consensus`]
+        pub fn checked_add(self, other: Self) -> Option<Self> {
+            self.units()
+                .checked_add(other.units())
+                .map(Self::from_units)
+        }
+        /// Add two locks, clamping the sum to [`Self::max_consensus`]
+        pub fn saturating_add(self, other: Self) -> Self {
+            Self::from_units(self.units().saturating_add(other.units()))
+        }
+    }
     impl AnyRelTimeLock {
         /// get inner representation
         pub fn get(&self) -> u32 {
@@ -215,21 +305,40 @@ mod trait_impls {
     }
     impl From<u16> for RelTime {
         fn from(u: u16) -> Self {
-            // cast to wider type and then set bit 22 to specify relative time
-            Self((u as u32) | (1 << 22), Default::default())
+            Self::from_units(u)
         }
     }
     impl From<u16> for RelHeight {
         fn from(u: u16) -> Self {
-            // no bit setting required, direct cast to u32
-            Self(u as u32, Default::default())
+            Self::from_units(u)
+        }
+    }
+    impl TryFrom<u32> for RelTime {
+        type Error = LockTimeError;
+        /// from a number of 512 second units
+        fn try_from(u: u32) -> Result<Self, Self::Error> {
+            u16::try_from(u)
+                .or(Err(LockTimeError::RelativeTooLarge(u)))
+                .map(From::from)
+        }
+    }
+    impl TryFrom<u32> for RelHeight {
+        type Error = LockTimeError;
+        fn try_from(u: u32) -> Result<Self, Self::Error> {
+            u16::try_from(u)
+                .or(Err(LockTimeError::RelativeTooLarge(u)))
+                .map(From::from)
         }
     }
 
     impl TryFrom<Duration> for RelTime {
         type Error = LockTimeError;
+        /// Rounds up to the next multiple of 512 seconds, so the lock is
+        /// never shorter than `u`. Errors if that is more than
+        /// [`RelTime::max_consensus`].
         fn try_from(u: Duration) -> Result<Self, Self::Error> {
-            u16::try_from(u.as_secs() / 512)
+            let units = u.as_nanos().div_ceil(512_000_000_000);
+            u16::try_from(units)
                 .or(Err(LockTimeError::DurationTooLong(u)))
                 .map(From::from)
         }
@@ -302,3 +411,567 @@ mod trait_impls {
         }
     }
 }
+
+/// The largest lock of each kind needed by one satisfaction path, see
+/// [`check_compatible`]
+#[derive(Default, Copy, Clone, Eq, PartialEq, Debug)]
+pub struct CombinedLocks {
+    /// the largest absolute height
+    pub abs_height: Option<AbsHeight>,
+    /// the largest absolute time
+    pub abs_time: Option<AbsTime>,
+    /// the largest relative height
+    pub rel_height: Option<RelHeight>,
+    /// the largest relative time
+    pub rel_time: Option<RelTime>,
+}
+
+/// Locks which can't be satisfied together, as an nLockTime (or a sequence)
+/// is either a height or a time. Holds the largest lock of each kind.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum LockMixError {
+    /// both an absolute height and an absolute time
+    Absolute(AbsHeight, AbsTime),
+    /// both a relative height and a relative time
+    Relative(RelHeight, RelTime),
+}
+
+impl fmt::Display for LockMixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for LockMixError {}
+
+impl CombinedLocks {
+    /// Include `lock`, keeping the largest of each kind
+    pub fn add(&mut self, lock: AnyTimeLock) {
+        match lock {
+            AnyTimeLock::A(AnyAbsTimeLock::AH(h)) => self.abs_height = self.abs_height.max(Some(h)),
+            AnyTimeLock::A(AnyAbsTimeLock::AT(t)) => self.abs_time = self.abs_time.max(Some(t)),
+            AnyTimeLock::R(AnyRelTimeLock::RH(h)) => self.rel_height = self.rel_height.max(Some(h)),
+            AnyTimeLock::R(AnyRelTimeLock::RT(t)) => self.rel_time = self.rel_time.max(Some(t)),
+        }
+    }
+    /// Include every lock of `other`
+    pub fn merge(&mut self, other: &CombinedLocks) {
+        for lock in other.iter() {
+            self.add(lock)
+        }
+    }
+    /// The locks, at most one of each kind
+    pub fn iter(&self) -> impl Iterator<Item = AnyTimeLock> {
+        let abs_height = self.abs_height.map(|h| AnyTimeLock::A(h.into()));
+        let abs_time = self.abs_time.map(|t| AnyTimeLock::A(t.into()));
+        let rel_height = self.rel_height.map(|h| AnyTimeLock::R(h.into()));
+        let rel_time = self.rel_time.map(|t| AnyTimeLock::R(t.into()));
+        abs_height
+            .into_iter()
+            .chain(abs_time)
+            .chain(rel_height)
+            .chain(rel_time)
+    }
+    /// The kinds which can't be satisfied together, if any. Absolute mixes
+    /// are reported before relative ones.
+    pub fn conflict(&self) -> Option<LockMixError> {
+        match (
+            self.abs_height,
+            self.abs_time,
+            self.rel_height,
+            self.rel_time,
+        ) {
+            (Some(h), Some(t), _, _) => Some(LockMixError::Absolute(h, t)),
+            (_, _, Some(h), Some(t)) => Some(LockMixError::Relative(h, t)),
+            _ => None,
+        }
+    }
+}
+
+/// Check that `locks` can all be satisfied on one path, returning the
+/// largest of each kind, which is the lock the path effectively needs.
+///
+/// Absolute heights can't be mixed with absolute times, nor relative
+/// heights with relative times, but absolute and relative locks combine
+/// freely.
+pub fn check_compatible(locks: &[AnyTimeLock]) -> Result<CombinedLocks, LockMixError> {
+    let mut combined = CombinedLocks::default();
+    for lock in locks {
+        combined.add(*lock);
+    }
+    match combined.conflict() {
+        Some(e) => Err(e),
+        None => Ok(combined),
+    }
+}
+
+/// Human readable absolute lock times, and the serde and JsonSchema impls
+/// which accept them
+mod parse {
+    use super::*;
+    use chrono::{DateTime, SecondsFormat, Utc};
+    use schemars::gen::SchemaGenerator;
+    use schemars::schema::{
+        InstanceType, NumberValidation, Schema, SchemaObject, StringValidation, SubschemaValidation,
+    };
+    use std::str::FromStr;
+
+    /// unix seconds of an RFC3339 date, with fractional seconds rounded up
+    fn parse_rfc3339(s: &str) -> Option<i64> {
+        let t = DateTime::parse_from_rfc3339(s).ok()?;
+        Some(t.timestamp() + (t.timestamp_subsec_nanos() > 0) as i64)
+    }
+
+    fn parse_u32(s: &str) -> Result<u32, LockTimeError> {
+        s.parse()
+            .map_err(|_| LockTimeError::InvalidSyntax(s.into()))
+    }
+
+    /// a plain integer, disambiguated by [`START_OF_TIME`]
+    fn from_u32(n: u32) -> Result<AnyAbsTimeLock, LockTimeError> {
+        Ok(if n < START_OF_TIME.get() {
+            AnyAbsTimeLock::AH(AbsHeight::try_from(n)?)
+        } else {
+            AnyAbsTimeLock::AT(AbsTime::try_from(n)?)
+        })
+    }
+
+    /// the value of `lock`, if it is of the kind `TT`
+    fn of_kind<TT: TimeType>(lock: AnyAbsTimeLock) -> Result<u32, LockTimeError> {
+        match (TT::IS_HEIGHT, lock) {
+            (true, AnyAbsTimeLock::AH(h)) => Ok(h.get()),
+            (false, AnyAbsTimeLock::AT(t)) => Ok(t.get()),
+            (true, AnyAbsTimeLock::AT(t)) => Err(LockTimeError::HeightTooHigh(t.get())),
+            (false, AnyAbsTimeLock::AH(h)) => Err(LockTimeError::TimeTooFarInPast(
+                Duration::from_secs(h.get() as u64),
+            )),
+        }
+    }
+
+    impl FromStr for AnyAbsTimeLock {
+        type Err = LockTimeError;
+        /// Parses any of:
+        /// - `height:850000`, a block height
+        /// - `2025-06-01T00:00:00Z`, an RFC3339 date, which must not be
+        ///   before [`GENESIS_TIME`]. Fractional seconds round up.
+        /// - a plain integer, a height if below 500,000,000 and a unix
+        ///   timestamp otherwise, as in nLockTime
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            if let Some(h) = s.strip_prefix("height:") {
+                return Ok(AnyAbsTimeLock::AH(AbsHeight::try_from(parse_u32(h)?)?));
+            }
+            if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+                return from_u32(parse_u32(s)?);
+            }
+            let t = parse_rfc3339(s).ok_or_else(|| LockTimeError::InvalidSyntax(s.into()))?;
+            if t < GENESIS_TIME.get() as i64 {
+                return Err(LockTimeError::BeforeGenesis(t));
+            }
+            let t = u32::try_from(t).or(Err(LockTimeError::DurationTooLong(
+                Duration::from_secs(t as u64),
+            )))?;
+            Ok(AnyAbsTimeLock::AT(AbsTime::try_from(t)?))
+        }
+    }
+
+    impl<TT: TimeType> FromStr for LockTime<Abs, TT> {
+        type Err = LockTimeError;
+        /// as for [`AnyAbsTimeLock`], but only accepting locks of this kind
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            Ok(LockTime(of_kind::<TT>(s.parse()?)?, PhantomData))
+        }
+    }
+
+    impl<TT: TimeType> fmt::Display for LockTime<Abs, TT> {
+        /// `height:850000` for heights and RFC3339 for times, which `FromStr`
+        /// parses back
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if TT::IS_HEIGHT {
+                return write!(f, "height:{}", self.0);
+            }
+            match DateTime::<Utc>::from_timestamp(self.0 as i64, 0) {
+                Some(t) => write!(f, "{}", t.to_rfc3339_opts(SecondsFormat::Secs, true)),
+                None => Err(fmt::Error),
+            }
+        }
+    }
+
+    impl fmt::Display for AnyAbsTimeLock {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                AnyAbsTimeLock::AH(h) => h.fmt(f),
+                AnyAbsTimeLock::AT(t) => t.fmt(f),
+            }
+        }
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    pub(super) enum LockTimeRepr {
+        Number(u32),
+        Text(String),
+    }
+
+    impl<A: Absolutivity, TT: TimeType> TryFrom<LockTimeRepr> for LockTime<A, TT> {
+        type Error = LockTimeError;
+        fn try_from(r: LockTimeRepr) -> Result<Self, Self::Error> {
+            // relative locks are left as the raw sequence bits
+            let n = match (A::IS_ABSOLUTE, r) {
+                (false, LockTimeRepr::Number(n)) => n,
+                (false, LockTimeRepr::Text(s)) => parse_u32(&s)?,
+                (true, LockTimeRepr::Number(n)) => of_kind::<TT>(from_u32(n)?)?,
+                (true, LockTimeRepr::Text(s)) => of_kind::<TT>(s.parse()?)?,
+            };
+            Ok(LockTime(n, PhantomData))
+        }
+    }
+
+    impl<A: Absolutivity, TT: TimeType> Serialize for LockTime<A, TT> {
+        fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            self.0.serialize(s)
+        }
+    }
+
+    /// the serialized form of [`AnyAbsTimeLock`]
+    #[derive(Deserialize, JsonSchema)]
+    #[schemars(rename = "TaggedAbsTimeLock")]
+    pub(super) enum Tagged {
+        /// # Absolute Height
+        /// in exact block height
+        AH(AbsHeight),
+        /// # Absolute Time
+        /// in unix time stamp since epoch
+        AT(AbsTime),
+    }
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    pub(super) enum AnyAbsTimeLockRepr {
+        Tagged(Tagged),
+        Number(u32),
+        Text(String),
+    }
+
+    impl TryFrom<AnyAbsTimeLockRepr> for AnyAbsTimeLock {
+        type Error = LockTimeError;
+        fn try_from(r: AnyAbsTimeLockRepr) -> Result<Self, Self::Error> {
+            match r {
+                AnyAbsTimeLockRepr::Tagged(Tagged::AH(h)) => Ok(AnyAbsTimeLock::AH(h)),
+                AnyAbsTimeLockRepr::Tagged(Tagged::AT(t)) => Ok(AnyAbsTimeLock::AT(t)),
+                AnyAbsTimeLockRepr::Number(n) => from_u32(n),
+                AnyAbsTimeLockRepr::Text(s) => s.parse(),
+            }
+        }
+    }
+
+    fn any_of(schemas: Vec<Schema>) -> SchemaObject {
+        SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(schemas),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    impl<A, TT> JsonSchema for LockTime<A, TT>
+    where
+        A: Absolutivity + JsonSchema,
+        TT: TimeType + JsonSchema,
+    {
+        fn schema_name() -> String {
+            format!(
+                "LockTime_for_{}_and_{}",
+                A::schema_name(),
+                TT::schema_name()
+            )
+        }
+        /// absolute lock times also allow a `height:` string or an RFC3339
+        /// `date-time`, so that a UI can offer a date picker
+        fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+            if !A::IS_ABSOLUTE {
+                return gen.subschema_for::<u32>();
+            }
+            let (minimum, maximum) = if TT::IS_HEIGHT {
+                (0, START_OF_TIME.get() - 1)
+            } else {
+                (START_OF_TIME.get(), u32::MAX)
+            };
+            let number = SchemaObject {
+                instance_type: Some(InstanceType::Integer.into()),
+                format: Some("uint32".into()),
+                number: Some(Box::new(NumberValidation {
+                    minimum: Some(minimum as f64),
+                    maximum: Some(maximum as f64),
+                    ..Default::default()
+                })),
+                ..Default::default()
+            };
+            let text = if TT::IS_HEIGHT {
+                SchemaObject {
+                    instance_type: Some(InstanceType::String.into()),
+                    string: Some(Box::new(StringValidation {
+                        pattern: Some("^height:[0-9]+$".into()),
+                        ..Default::default()
+                    })),
+                    ..Default::default()
+                }
+            } else {
+                SchemaObject {
+                    instance_type: Some(InstanceType::String.into()),
+                    format: Some("date-time".into()),
+                    ..Default::default()
+                }
+            };
+            any_of(vec![number.into(), text.into()]).into()
+        }
+    }
+
+    impl JsonSchema for AnyAbsTimeLock {
+        fn schema_name() -> String {
+            "AnyAbsTimeLock".into()
+        }
+        fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+            let mut schema = any_of(vec![
+                gen.subschema_for::<Tagged>(),
+                gen.subschema_for::<AbsHeight>(),
+                gen.subschema_for::<AbsTime>(),
+            ]);
+            let metadata = schema.metadata();
+            metadata.title = Some("Any Absolute Time Lock".into());
+            metadata.description =
+                Some("Represents a type which can be either type of absolute lock".into());
+            schema.into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn relative_arithmetic() {
+        let max = RelHeight::max_consensus();
+        assert_eq!(max.get(), 65535);
+        assert_eq!(RelTime::max_consensus().get(), 65535 | 1 << 22);
+        assert_eq!(RelTime::max_consensus().units(), 65535);
+        assert_eq!(
+            RelHeight::from(65534).checked_add(RelHeight::from(1)),
+            Some(max)
+        );
+        assert_eq!(RelHeight::from(65535).checked_add(RelHeight::from(1)), None);
+        assert_eq!(
+            RelTime::from(1).checked_add(RelTime::from(2)),
+            Some(RelTime::from(3))
+        );
+        assert_eq!(max.saturating_add(RelHeight::from(10)), max);
+        assert!(RelHeight::from(2) > RelHeight::from(1));
+        assert!(RelTime::from(1) < RelTime::max_consensus());
+        assert_eq!(
+            RelHeight::try_from(65535u32).map(|h| h.get()).ok(),
+            Some(65535)
+        );
+        assert!(matches!(
+            RelHeight::try_from(65536u32),
+            Err(LockTimeError::RelativeTooLarge(65536))
+        ));
+        assert!(matches!(
+            RelTime::try_from(65536u32),
+            Err(LockTimeError::RelativeTooLarge(65536))
+        ));
+    }
+    #[test]
+    fn durations_round_up() {
+        let units = |d: Duration| RelTime::try_from(d).map(|t| t.units()).ok();
+        assert_eq!(units(Duration::from_secs(0)), Some(0));
+        assert_eq!(units(Duration::from_nanos(1)), Some(1));
+        assert_eq!(units(Duration::from_secs(511)), Some(1));
+        assert_eq!(units(Duration::from_secs(512)), Some(1));
+        assert_eq!(
+            units(Duration::from_secs(512) + Duration::from_nanos(1)),
+            Some(2)
+        );
+        assert_eq!(units(Duration::from_secs(65535 * 512)), Some(65535));
+        assert_eq!(units(Duration::from_secs(65535 * 512 + 1)), None);
+    }
+    #[test]
+    fn parse_abs_lock_times() {
+        let date = "2025-06-01T00:00:00Z";
+        for (s, lock) in &[
+            (
+                "height:850000",
+                AnyAbsTimeLock::AH(AbsHeight::try_from(850_000).unwrap()),
+            ),
+            (
+                "850000",
+                AnyAbsTimeLock::AH(AbsHeight::try_from(850_000).unwrap()),
+            ),
+            (
+                date,
+                AnyAbsTimeLock::AT(AbsTime::try_from(1_748_736_000).unwrap()),
+            ),
+            (
+                "1748736000",
+                AnyAbsTimeLock::AT(AbsTime::try_from(1_748_736_000).unwrap()),
+            ),
+            (
+                "2025-06-01T02:00:00+02:00",
+                AnyAbsTimeLock::AT(AbsTime::try_from(1_748_736_000).unwrap()),
+            ),
+            (
+                "2025-05-31T23:59:59.001Z",
+                AnyAbsTimeLock::AT(AbsTime::try_from(1_748_736_000).unwrap()),
+            ),
+        ] {
+            assert_eq!(s.parse::<AnyAbsTimeLock>().ok(), Some(*lock), "{}", s);
+            assert_eq!(lock.to_string().parse::<AnyAbsTimeLock>().ok(), Some(*lock));
+        }
+        assert_eq!(AbsTime::try_from(1_748_736_000).unwrap().to_string(), date);
+        assert_eq!(
+            AbsHeight::try_from(850_000).unwrap().to_string(),
+            "height:850000"
+        );
+        assert_eq!(GENESIS_TIME.to_string(), "2009-01-03T18:15:05Z");
+        assert_eq!(
+            date.parse::<AbsTime>().map(|t| t.get()).ok(),
+            Some(1_748_736_000)
+        );
+        assert_eq!(
+            "850000".parse::<AbsHeight>().map(|h| h.get()).ok(),
+            Some(850_000)
+        );
+
+        assert!(matches!(
+            "2009-01-03T18:15:04Z".parse::<AnyAbsTimeLock>(),
+            Err(LockTimeError::BeforeGenesis(1_231_006_504))
+        ));
+        assert!("2009-01-03T18:15:05Z".parse::<AnyAbsTimeLock>().is_ok());
+        assert!(matches!(
+            "height:500000000".parse::<AnyAbsTimeLock>(),
+            Err(LockTimeError::HeightTooHigh(500_000_000))
+        ));
+        assert!(matches!(
+            date.parse::<AbsHeight>(),
+            Err(LockTimeError::HeightTooHigh(1_748_736_000))
+        ));
+        assert!(matches!(
+            "850000".parse::<AbsTime>(),
+            Err(LockTimeError::TimeTooFarInPast(_))
+        ));
+        for bad in &[
+            "",
+            "height:",
+            "height:-1",
+            "2025-02-29T00:00:00Z",
+            "2025-06-01T24:00:00Z",
+            "2025-06-01",
+            "soon",
+        ] {
+            assert!(
+                matches!(
+                    bad.parse::<AnyAbsTimeLock>(),
+                    Err(LockTimeError::InvalidSyntax(_))
+                ),
+                "{}",
+                bad
+            );
+        }
+    }
+    #[test]
+    fn abs_lock_time_serde() {
+        let height = AnyAbsTimeLock::AH(AbsHeight::try_from(850_000).unwrap());
+        let time = AnyAbsTimeLock::AT(AbsTime::try_from(1_748_736_000).unwrap());
+        // serialization is unchanged, but strings and integers are accepted
+        assert_eq!(
+            serde_json::to_value(height).unwrap(),
+            serde_json::json!({"AH": 850000})
+        );
+        assert_eq!(
+            serde_json::to_value(AbsHeight::try_from(850_000).unwrap()).unwrap(),
+            serde_json::json!(850000)
+        );
+        for (json, lock) in &[
+            (serde_json::json!({"AH": 850000}), height),
+            (serde_json::json!({"AH": "height:850000"}), height),
+            (serde_json::json!(850000), height),
+            (serde_json::json!("height:850000"), height),
+            (serde_json::json!({"AT": "2025-06-01T00:00:00Z"}), time),
+            (serde_json::json!("2025-06-01T00:00:00Z"), time),
+            (serde_json::json!(1748736000), time),
+        ] {
+            assert_eq!(
+                serde_json::from_value::<AnyAbsTimeLock>(json.clone()).ok(),
+                Some(*lock),
+                "{}",
+                json
+            );
+        }
+        for bad in &[
+            serde_json::json!({"AH": 1748736000}),
+            serde_json::json!("2008-01-01T00:00:00Z"),
+        ] {
+            assert!(serde_json::from_value::<AnyAbsTimeLock>(bad.clone()).is_err());
+        }
+        // relative locks are still raw sequence bits
+        assert_eq!(
+            serde_json::from_value::<RelTime>(serde_json::json!(1 << 22 | 5)).unwrap(),
+            RelTime::from(5)
+        );
+
+        let schema = serde_json::to_string(&schemars::schema_for!(AnyAbsTimeLock)).unwrap();
+        assert!(schema.contains("date-time"));
+        assert!(schema.contains("^height:[0-9]+$"));
+    }
+    #[test]
+    fn compatibility_table() {
+        let ah = AnyTimeLock::A(AbsHeight::try_from(100).unwrap().into());
+        let ah2 = AnyTimeLock::A(AbsHeight::try_from(200).unwrap().into());
+        let at = AnyTimeLock::A(AbsTime::try_from(1_600_000_000).unwrap().into());
+        let rh = AnyTimeLock::R(RelHeight::from(10).into());
+        let rt = AnyTimeLock::R(RelTime::from(10).into());
+        let classes = [ah, at, rh, rt];
+        // whether each pair of classes may share a path
+        let compatible = [
+            [true, false, true, true],
+            [false, true, true, true],
+            [true, true, true, false],
+            [true, true, false, true],
+        ];
+        for (i, a) in classes.iter().enumerate() {
+            for (j, b) in classes.iter().enumerate() {
+                let result = check_compatible(&[*a, *b]);
+                assert_eq!(result.is_ok(), compatible[i][j], "{:?} {:?}", a, b);
+                match result {
+                    Ok(combined) => {
+                        let mut expected: Vec<_> = vec![*a, *b];
+                        expected.dedup();
+                        assert_eq!(combined.iter().collect::<Vec<_>>().len(), expected.len());
+                        assert!(expected.iter().all(|l| combined.iter().any(|c| c == *l)));
+                    }
+                    Err(LockMixError::Absolute(..)) => assert!(i < 2 && j < 2),
+                    Err(LockMixError::Relative(..)) => assert!(i >= 2 && j >= 2),
+                }
+            }
+        }
+        assert_eq!(check_compatible(&[]), Ok(CombinedLocks::default()));
+        assert_eq!(
+            check_compatible(&[ah2, rh, ah]).map(|c| c.abs_height),
+            Ok(Some(AbsHeight::try_from(200).unwrap()))
+        );
+        // absolute mixes are reported first, with the largest of each kind
+        assert_eq!(
+            check_compatible(&[rh, rt, at, ah, ah2]),
+            Err(LockMixError::Absolute(
+                AbsHeight::try_from(200).unwrap(),
+                AbsTime::try_from(1_600_000_000).unwrap()
+            ))
+        );
+        assert_eq!(
+            check_compatible(&classes[1..]),
+            Err(LockMixError::Relative(
+                RelHeight::from(10),
+                RelTime::from(10)
+            ))
+        );
+    }
+}
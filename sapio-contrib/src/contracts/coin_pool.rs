@@ -7,7 +7,7 @@
 //! coin_pool has a contract `CoinPool` for sharing a UTXO
 use bitcoin::Amount;
 use sapio::contract::*;
-use sapio::util::amountrange::AmountF64;
+use sapio::util::amountrange::{AmountF64, AmountU64};
 use sapio::*;
 use sapio_base::timelocks::AnyRelTimeLock;
 use sapio_base::Clause;
@@ -33,6 +33,15 @@ fn default_coerce(
 }
 
 impl CoinPool {
+    /// the sum of the refunds, as a `CoinPool` must be funded with at least that
+    fn total_refunds(&self) -> Result<Amount, CompilationError> {
+        self.refunds
+            .iter()
+            .map(|x| AmountU64::from(Amount::from(x.1)))
+            .sum::<Option<AmountU64>>()
+            .map(Amount::from)
+            .ok_or(CompilationError::AmountOverflow)
+    }
     /// cuts the pool in half in order to remove an offline or malicious participant
     #[then]
     fn bisect_offline(self, ctx: sapio::Context) {
@@ -49,16 +58,8 @@ impl CoinPool {
             };
 
             ctx.template()
-                .add_output(
-                    Amount::from_sat(a.refunds.iter().map(|x| Amount::from(x.1).as_sat()).sum()),
-                    &a,
-                    None,
-                )?
-                .add_output(
-                    Amount::from_sat(b.refunds.iter().map(|x| Amount::from(x.1).as_sat()).sum()),
-                    &b,
-                    None,
-                )?
+                .add_output(a.total_refunds()?, &a, None)?
+                .add_output(b.total_refunds()?, &b, None)?
                 .into()
         } else {
             let mut builder = ctx.template();
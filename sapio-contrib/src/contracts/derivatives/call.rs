@@ -33,12 +33,17 @@ impl<'a> TryFrom<Call<'a>> for GenericBetArguments<'a> {
         let user = v.user_api.get_key();
         let mut outcomes = vec![];
         let strike = v.strike_x_one_unit;
-        let max_amount_bitcoin = v.amount * v.max_price_x_one_unit;
+        let max_amount_bitcoin = v
+            .amount
+            .checked_mul(v.max_price_x_one_unit)
+            .ok_or(CompilationError::AmountOverflow)?;
         // Increment 1 dollar per step
         let mut strike_ctx = v.ctx.derive_str(Arc::new("strike".into()))?;
         for price in (strike..=v.max_price_x_one_unit).step_by(ONE_UNIT as usize) {
             let mut profit = Amount::from_sat(price) - Amount::from_sat(strike);
-            let mut refund = max_amount_bitcoin - profit;
+            let mut refund = max_amount_bitcoin
+                .checked_sub(profit)
+                .ok_or(CompilationError::AmountOverflow)?;
             if v.buying {
                 std::mem::swap(&mut profit, &mut refund);
             }
@@ -113,6 +113,9 @@ impl DLCContract {
                     None,
                 )?;
             }
+            // the sats lost rounding each payout down go to fees
+            let rounding = tmpl.ctx().funds();
+            tmpl = tmpl.add_fees(rounding)?;
             tmpls.push(Ok(tmpl.into()));
         }
         Ok(Box::new(tmpls.into_iter()))
@@ -29,12 +29,17 @@ impl<'a> TryFrom<Put<'a>> for GenericBetArguments<'a> {
         let user = v.user_api.get_key();
         let mut outcomes = vec![];
         let strike = v.strike_x_one_unit;
-        let max_amount_bitcoin = v.amount * strike;
+        let max_amount_bitcoin = v
+            .amount
+            .checked_mul(strike)
+            .ok_or(CompilationError::AmountOverflow)?;
         // Increment 1 dollar per step
         let mut strike_ctx = v.ctx.derive_str(Arc::new("strike".into()))?;
         for price in (0..=strike).step_by(ONE_UNIT as usize) {
             let mut profit = Amount::from_sat(strike) - Amount::from_sat(price);
-            let mut refund = max_amount_bitcoin - profit;
+            let mut refund = max_amount_bitcoin
+                .checked_sub(profit)
+                .ok_or(CompilationError::AmountOverflow)?;
             if v.buying {
                 std::mem::swap(&mut profit, &mut refund);
             }
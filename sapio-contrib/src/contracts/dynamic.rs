@@ -36,7 +36,7 @@ impl AnyContract for D<'static> {
     ) -> &'a [fn() -> Option<Box<dyn actions::CallableAsFoF<Self, Self::StatefulArguments>>>] {
         &[]
     }
-    fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self>>] {
+    fn finish_fns<'a>(&'a self) -> &'a [actions::GuardGen<Self>] {
         &[]
     }
     fn get_inner_ref<'a>(&'a self) -> &Self {
@@ -66,8 +66,11 @@ impl DynamicExample {
                     sapio::contract::actions::ThenFunc {
                         conditional_compile_if: &[],
                         guard: &[],
+                        guard_combinator: Default::default(),
                         func: |_s, _ctx, _t| Err(CompilationError::TerminateCompilation),
                         name: Arc::new("Empty".into()),
+                        fee_policy: Default::default(),
+                        weight: None,
                     }
                     .into(),
                 )
@@ -59,7 +59,9 @@ impl Hanukkiah {
             )?;
         }
         let size = txn.estimate_tx_size();
-        txn = txn.add_amount(self.feerate_per_byte * size);
+        let fees = self.feerate_per_byte * size;
+        txn = txn.add_amount(fees);
+        txn = txn.add_fees(fees)?;
         let candle_time =
             AbsTime::try_from(self.night_time.get() + 24 * 60 * 60 * (night as u32 - 1_u32))?
                 .into();
@@ -44,7 +44,7 @@ impl ChainReturn {
     fn next_chain(self, ctx: sapio::Context, o: UpdateTypes) {
         let mut tmpl = ctx.template();
         if let UpdateTypes::AddData { data, fees } = o {
-            tmpl = tmpl.spend_amount(fees.into())?;
+            tmpl = tmpl.add_fees(fees.into())?;
             tmpl = tmpl.add_output(
                 Amount::from_sat(0),
                 &Compiled::from_op_return(data.as_str().as_bytes())?,
@@ -90,12 +90,12 @@ impl TapBet {
             )?;
         }
         // if we have funds remaining, make a recursive TapBet with the same
-        // parameters.
-        if builder.ctx().funds() >= s.fees_per_time {
-            let amt = builder.ctx().funds() - s.fees_per_time;
-            if amt > Amount::from_sat(0) {
-                builder = builder.add_output(amt, s, None)?;
-            }
+        // parameters, leaving the rest to fees.
+        let fees = std::cmp::min(builder.ctx().funds(), s.fees_per_time);
+        builder = builder.add_fees(fees)?;
+        let amt = builder.ctx().funds();
+        if amt > Amount::from_sat(0) {
+            builder = builder.add_output(amt, s, None)?;
         }
         builder.into()
     }
@@ -110,15 +110,15 @@ impl TapBet {
         let mut builder = ctx.template().set_label("stop_expansion".into());
         builder = builder.set_sequence(0, s.cancel_timeout.into())?;
         // Pay out to the orginal owner
-        if builder.ctx().funds() >= s.fees_per_time {
-            let amt = builder.ctx().funds() - s.fees_per_time;
-            if amt > Amount::from_sat(0) {
-                builder = builder.add_output(
-                    amt,
-                    &Compiled::from_address(s.cancel_to.clone(), None),
-                    None,
-                )?;
-            }
+        let fees = std::cmp::min(builder.ctx().funds(), s.fees_per_time);
+        builder = builder.add_fees(fees)?;
+        let amt = builder.ctx().funds();
+        if amt > Amount::from_sat(0) {
+            builder = builder.add_output(
+                amt,
+                &Compiled::from_address(s.cancel_to.clone(), None),
+                None,
+            )?;
         }
         builder.into()
     }
@@ -6,6 +6,8 @@
 
 //! contracts for paying a large set of recipients fee efficiently
 use sapio::contract::*;
+use sapio::util::amountrange::AmountU64;
+use sapio::util::batching::Radix;
 use sapio::*;
 
 use schemars::*;
@@ -27,24 +29,27 @@ pub struct TreePay {
     /// the list of payments to create
     pub participants: Vec<Payment>,
     /// the radix to use (4 or 5 near optimal, depending on if CTV emulation is used this may be inaccurate)
-    pub radix: usize,
+    pub radix: Radix,
 }
 
 impl TreePay {
     #[then]
     fn expand(self, ctx: sapio::Context) {
         let mut builder = ctx.template();
-        if self.participants.len() > self.radix {
+        if self.participants.len() > self.radix.get() {
             for c in self
                 .participants
-                .chunks(self.participants.len() / self.radix)
+                .chunks(self.participants.len() / self.radix.get())
             {
-                let mut amt = bitcoin::util::amount::Amount::from_sat(0);
+                let mut amt = AmountU64::from(0u64);
                 for Payment { amount, .. } in c {
-                    amt += (*amount).try_into()?;
+                    let amount: bitcoin::util::amount::Amount = (*amount).try_into()?;
+                    amt = amt
+                        .checked_add(amount.into())
+                        .ok_or(CompilationError::AmountOverflow)?;
                 }
                 builder = builder.add_output(
-                    amt,
+                    amt.into(),
                     &TreePay {
                         participants: c.to_vec(),
                         radix: self.radix,
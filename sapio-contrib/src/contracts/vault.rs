@@ -8,6 +8,7 @@
 use super::undo_send::UndoSendInternal;
 use bitcoin::util::amount::CoinAmount;
 use sapio::contract::*;
+use sapio::util::batching::Radix;
 use sapio::*;
 use sapio_base::timelocks::AnyRelTimeLock;
 
@@ -133,7 +134,7 @@ pub struct VaultTree {
     /// # Max Funds per Cold Storage Addreess
     max_per_address: CoinAmount,
     /// # Radix for the split tree
-    radix: usize,
+    radix: Radix,
     /// # A Hot Storage Address
     hot_storage: bitcoin::Address,
     /// # How many iterations of the contract to run
@@ -0,0 +1,128 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The descriptors of compiled contrib contracts match their addresses
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::util::amount::CoinAmount;
+use bitcoin::{Address, Network, XOnlyPublicKey};
+use sapio::contract::{Compilable, Compiled};
+use sapio::util::batching::Radix;
+use sapio::Context;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::serialization_helpers::SArc;
+use sapio_base::timelocks::{AnyRelTimeLock, RelHeight};
+use sapio_contrib::contracts::readme_contracts::{BasicEscrow, TrustlessEscrow};
+use sapio_contrib::contracts::treepay::{Payment, TreePay};
+use sapio_contrib::contracts::vault::{Vault, VaultAddress};
+use sapio_ctv_emulator_trait::CTVAvailable;
+use serde_json::json;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+fn key(i: u8) -> XOnlyPublicKey {
+    let secret = SecretKey::from_slice(&[i; 32]).unwrap();
+    XOnlyPublicKey::from_keypair(&secret.keypair(&Secp256k1::new())).0
+}
+
+fn address(i: u8) -> Address {
+    Address::p2tr(&Secp256k1::new(), key(i), None, Network::Regtest)
+}
+
+fn ctx(sats: u64) -> Context {
+    Context::new(
+        Network::Regtest,
+        bitcoin::Amount::from_sat(sats),
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("contrib").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    )
+}
+
+/// the address of every compiled object in `o`'s tree, by path
+fn walk(o: &Compiled, found: &mut Vec<(SArc<EffectPath>, bitcoin::Script)>) {
+    // objects not compiled from a contract, e.g. addresses, have no path
+    if !String::from(o.root_path.0.as_ref().clone()).is_empty() {
+        found.push((o.root_path.clone(), o.address.clone().into()));
+    }
+    for t in o.ctv_to_tx.values().chain(o.suggested_txs.values()) {
+        for out in t.outputs.iter() {
+            walk(&out.contract, found);
+        }
+    }
+}
+
+fn check(compiled: Compiled) -> usize {
+    let descriptors = compiled.descriptors();
+    let mut objects = vec![];
+    walk(&compiled, &mut objects);
+    assert_eq!(objects.len(), descriptors.len());
+    for (path, script) in objects {
+        assert_eq!(descriptors[&path].script_pubkey(), script);
+    }
+    let request = compiled.import_descriptors_request();
+    let request = request.as_array().unwrap();
+    assert_eq!(request.len(), descriptors.len());
+    for (entry, d) in request.iter().zip(descriptors.values()) {
+        let desc = entry["desc"].as_str().unwrap();
+        assert_eq!(desc, d.to_string());
+        assert!(desc.starts_with("tr(") && desc.contains('#'));
+    }
+    descriptors.len()
+}
+
+#[test]
+fn basic_escrow() {
+    let escrow: BasicEscrow = serde_json::from_value(json!({
+        "alice": key(1),
+        "bob": key(2),
+        "escrow": key(3),
+    }))
+    .unwrap();
+    assert_eq!(check(escrow.compile(ctx(100_000)).unwrap()), 1);
+}
+
+#[test]
+fn trustless_escrow() {
+    let escrow: TrustlessEscrow = serde_json::from_value(json!({
+        "alice": key(1),
+        "bob": key(2),
+        "alice_escrow": (CoinAmount::Sats(40_000), address(1)),
+        "bob_escrow": (CoinAmount::Sats(50_000), address(2)),
+    }))
+    .unwrap();
+    assert_eq!(check(escrow.compile(ctx(90_000)).unwrap()), 1);
+}
+
+#[test]
+fn tree_pay() {
+    let tree = TreePay {
+        participants: (0..10)
+            .map(|i| Payment {
+                amount: CoinAmount::Sats(10_000),
+                address: address(i + 1),
+            })
+            .collect(),
+        radix: Radix::try_from(4).unwrap(),
+    };
+    // the root, and a node for each chunk of participants
+    assert!(check(tree.compile(ctx(100_000)).unwrap()) > 1);
+}
+
+#[test]
+fn vault() {
+    let timeout: AnyRelTimeLock = RelHeight::from(144).into();
+    let vault: VaultAddress = serde_json::from_value(json!({
+        "cold_storage": address(1),
+        "hot_storage": address(2),
+        "n_steps": 3,
+        "amount_step": CoinAmount::Sats(30_000),
+        "timeout": timeout,
+        "mature": timeout,
+    }))
+    .unwrap();
+    // each step's sub vault and undo send
+    assert!(check(Vault::from(vault).compile(ctx(90_000)).unwrap()) > 3);
+}
@@ -0,0 +1,180 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Authenticating a session's client, with a bearer token or a key signing
+//! the session's challenge, and what each may do
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::schnorr::Signature;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::XOnlyPublicKey;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Something a client may be allowed to do
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// create contracts and patch the effects they are compiled with
+    Compile,
+    /// list the modules loaded and their schemas
+    LoadModules,
+    /// save, bind and broadcast contracts
+    Bind,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Compile => write!(f, "compile"),
+            Capability::LoadModules => write!(f, "load_modules"),
+            Capability::Bind => write!(f, "bind"),
+        }
+    }
+}
+
+/// What a credential allows, and until when
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+pub struct Grant {
+    /// what the client may do
+    pub capabilities: BTreeSet<Capability>,
+    /// the unix time, in seconds, after which the credential is refused, if
+    /// it expires
+    #[serde(default)]
+    pub expires: Option<u64>,
+}
+
+/// The credentials a server accepts, see `Session::set_auth`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct AuthConfig {
+    /// grants for bearer tokens, by the sha256 of the token, so that the
+    /// config doesn't hold the tokens themselves
+    #[serde(default)]
+    pub tokens: BTreeMap<sha256::Hash, Grant>,
+    /// grants for keys signing a session's challenge
+    #[serde(default)]
+    pub keys: BTreeMap<XOnlyPublicKey, Grant>,
+}
+
+/// Credentials sent with a handshake
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Credentials {
+    /// a bearer token
+    Bearer(String),
+    /// a signature by `key` of the session's challenge, see
+    /// `challenge_message`
+    Signed {
+        /// the key
+        key: XOnlyPublicKey,
+        /// the signature
+        signature: Signature,
+    },
+}
+
+/// Why a client may not do what it asked
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "reason", content = "content", rename_all = "snake_case")]
+pub enum AuthError {
+    /// the client hasn't authenticated
+    Unauthenticated,
+    /// the credentials are not accepted, or are no longer
+    InvalidCredentials,
+    /// the credentials expired at this unix time
+    Expired(u64),
+    /// the credentials don't allow this
+    MissingCapability(Capability),
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Unauthenticated => write!(f, "not authenticated"),
+            AuthError::InvalidCredentials => write!(f, "credentials not accepted"),
+            AuthError::Expired(t) => write!(f, "credentials expired at {}", t),
+            AuthError::MissingCapability(c) => write!(f, "credentials don't allow {}", c),
+        }
+    }
+}
+impl std::error::Error for AuthError {}
+
+/// Whose credentials a session was authenticated with, looked up again for
+/// each action so that changes to the config apply to open sessions
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Identity {
+    /// a bearer token, by its sha256
+    Token(sha256::Hash),
+    /// a key which signed the session's challenge
+    Key(XOnlyPublicKey),
+}
+
+/// What a key signs to authenticate a session with `challenge`
+pub fn challenge_message(challenge: &sha256::Hash) -> Message {
+    let tagged = [&b"sapio-front/authenticate"[..], &challenge[..]].concat();
+    Message::from_digest_slice(&sha256::Hash::hash(&tagged)[..]).expect("a hash is a valid message")
+}
+
+/// the current unix time, in seconds
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+impl AuthConfig {
+    /// Check `credentials`, signing `challenge` if signed, at unix time `now`
+    pub(crate) fn authenticate(
+        &self,
+        credentials: &Credentials,
+        challenge: &sha256::Hash,
+        now: u64,
+    ) -> Result<Identity, AuthError> {
+        let identity = match credentials {
+            Credentials::Bearer(token) => Identity::Token(sha256::Hash::hash(token.as_bytes())),
+            Credentials::Signed { key, signature } => {
+                Secp256k1::verification_only()
+                    .verify_schnorr(signature, &challenge_message(challenge), key)
+                    .map_err(|_| AuthError::InvalidCredentials)?;
+                Identity::Key(*key)
+            }
+        };
+        self.grant(&identity, now)?;
+        Ok(identity)
+    }
+    /// The grant for `identity`, unless it has been revoked or has expired
+    /// by unix time `now`
+    pub(crate) fn grant(&self, identity: &Identity, now: u64) -> Result<&Grant, AuthError> {
+        let grant = match identity {
+            Identity::Token(h) => self.tokens.get(h),
+            Identity::Key(k) => self.keys.get(k),
+        }
+        .ok_or(AuthError::InvalidCredentials)?;
+        match grant.expires {
+            Some(expires) if now > expires => Err(AuthError::Expired(expires)),
+            _ => Ok(grant),
+        }
+    }
+    /// May the client authenticated as `identity` do what needs
+    /// `capability`, at unix time `now`?
+    pub(crate) fn authorize(
+        &self,
+        identity: Option<&Identity>,
+        capability: Capability,
+        now: u64,
+    ) -> Result<(), AuthError> {
+        let grant = self.grant(identity.ok_or(AuthError::Unauthenticated)?, now)?;
+        if grant.capabilities.contains(&capability) {
+            Ok(())
+        } else {
+            Err(AuthError::MissingCapability(capability))
+        }
+    }
+}
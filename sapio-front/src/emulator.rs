@@ -0,0 +1,88 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Choosing the emulator a session's contracts compile against
+
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::Network;
+use emulator_connect::connections::local::LocalEmulator;
+use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Which emulator a session's contracts compile against, e.g. from a
+/// server's config file, see `Session::set_emulator`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum EmulatorConfig {
+    /// CheckTemplateVerify is available, so templates need no emulator
+    #[default]
+    CtvAvailable,
+    /// DEV ONLY: an in-process `LocalEmulator` with the hex seed's master key,
+    /// e.g. for regtest
+    DevSeed(String),
+    /// DEV ONLY: an in-process `LocalEmulator` with the extended private key
+    /// of a descriptor, e.g. one of a bitcoind wallet's
+    DevDescriptor(String),
+}
+
+impl EmulatorConfig {
+    /// The emulator for sessions on `network`. The DEV ONLY emulators are
+    /// refused for mainnet.
+    pub fn emulator(&self, network: Network) -> Result<Arc<dyn CTVEmulator>, EmulatorError> {
+        let dev_only = || {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "The Local Emulator is for Development Only, not Mainnet",
+            )
+        };
+        Ok(match self {
+            EmulatorConfig::CtvAvailable => Arc::new(CTVAvailable),
+            _ if network == Network::Bitcoin => return Err(dev_only().into()),
+            EmulatorConfig::DevSeed(seed) => {
+                let seed = Vec::<u8>::from_hex(seed).map_err(|e| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string())
+                })?;
+                Arc::new(LocalEmulator::from_seed(network, &seed)?)
+            }
+            EmulatorConfig::DevDescriptor(descriptor) => {
+                Arc::new(LocalEmulator::from_descriptor(descriptor)?)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::hex::ToHex;
+    use bitcoin::hashes::{sha256, Hash};
+
+    #[test]
+    fn configured_emulators() {
+        let h = sha256::Hash::hash(b"template");
+        let config: EmulatorConfig = serde_json::from_value(serde_json::json!({
+            "dev_seed": LocalEmulator::TEST_SEED.to_hex()
+        }))
+        .unwrap();
+        let local = config.emulator(Network::Regtest).unwrap();
+        assert_eq!(
+            local.get_signer_for(h).unwrap(),
+            LocalEmulator::for_tests().get_signer_for(h).unwrap()
+        );
+        assert!(config.emulator(Network::Bitcoin).is_err());
+
+        let xpriv =
+            bitcoin::util::bip32::ExtendedPrivKey::new_master(Network::Testnet, &[1; 32]).unwrap();
+        let config = EmulatorConfig::DevDescriptor(format!("tr({}/0/*)", xpriv));
+        assert!(config.emulator(Network::Regtest).is_ok());
+        assert!(config.emulator(Network::Bitcoin).is_err());
+
+        let config: EmulatorConfig = serde_json::from_str("\"ctv_available\"").unwrap();
+        assert_eq!(config, EmulatorConfig::default());
+        assert!(config.emulator(Network::Bitcoin).is_ok());
+    }
+}
@@ -0,0 +1,431 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The form every error a session reacts with takes, for clients to handle
+//! by its code rather than by its message
+use crate::auth::AuthError;
+use crate::session::SessionError;
+use sapio::contract::ErrorReport;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// What went wrong, stable across releases.
+///
+/// Codes for compilation errors are named as the kinds of `ErrorReport`,
+/// and are those of the innermost error, inside any failed branches or
+/// modules, whose details are kept in the envelope's data.
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// the contract stopped compilation
+    TerminateCompilation,
+    /// the contract stopped compilation with a message
+    TerminateWith,
+    /// a template spends more than the contract is funded with
+    OutOfFunds,
+    /// arguments are not valid for their schema
+    SchemaError,
+    /// amounts added up to more than there are coins
+    AmountOverflow,
+    /// a fee rate is below the minimum
+    MinFeerateError,
+    /// the fees reserved are more than the contract is funded with
+    FeeReservationExceedsFunds,
+    /// a template pays less fee than its fee rate needs
+    FeeRateShortfall,
+    /// change is below the minimum to create
+    ChangeBelowMinimum,
+    /// an output is below the dust limit
+    OutputBelowDust,
+    /// a template neither spends nor returns all its funds
+    UnaccountedFunds,
+    /// a contract is funded with amounts it doesn't accept
+    IncompatibleAmountRange,
+    /// an address or key is for another network
+    WrongNetwork,
+    /// the chain tip is needed but was not given
+    MissingChainTip,
+    /// a lock time is not valid
+    TimeLockError,
+    /// a template has absolute or relative lock times by both height and
+    /// time
+    IncompatibleTimeLocks,
+    /// a clause could not be compiled
+    ClauseCompilationFailed,
+    /// a clause can only be compiled for taproot
+    TaprootOnlyClause,
+    /// a clause for segwit v0 has an x-only key without its full key
+    SegwitV0KeyParityUnknown,
+    /// two functions of a contract have the same name
+    DuplicateFunctionName,
+    /// conditional compilation failed
+    ConditionalCompilationFailed,
+    /// warnings which were denied were raised
+    DeniedWarnings,
+    /// every branch of the contract is unreachable
+    AllBranchesUnreachable,
+    /// compilation went over a resource limit
+    ResourceLimitExceeded,
+    /// compilation was cancelled
+    Cancelled,
+    /// a module could not be found to compile with
+    UnknownModule,
+    /// a contract's own error
+    Custom,
+    /// a message could not be read, or a reaction written
+    InvalidMessage,
+    /// no contract is saved for the key requested
+    ContractNotRegistered,
+    /// the module directory could not be read
+    ModuleDirectoryUnreadable,
+    /// a contract was compiled but could not be bound
+    BindFailed,
+    /// a request in a batch was not compiled, as an earlier one failed
+    Skipped,
+    /// compilation panicked
+    Panicked,
+    /// a request to cancel is for no request compiling
+    UnknownRequest,
+    /// a request has the id of one already compiling
+    DuplicateRequest,
+    /// no session is saved with the id to resume
+    UnknownSession,
+    /// the session to resume has expired
+    SessionExpired,
+    /// the session store could not be read or written
+    StoreFailed,
+    /// a quota refused the request, which may be made again after the
+    /// `retry_after_ms` in the data
+    Throttled,
+    /// the client hasn't authenticated
+    Unauthenticated,
+    /// the credentials are not accepted
+    InvalidCredentials,
+    /// the credentials have expired
+    CredentialsExpired,
+    /// the credentials don't allow the request
+    MissingCapability,
+}
+
+/// An error reacted with
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct ErrorEnvelope {
+    /// what went wrong
+    pub code: ErrorCode,
+    /// the path of the contract or effect it went wrong at, if known
+    pub path: Option<String>,
+    /// what went wrong, for people
+    pub message: String,
+    /// the details, which for compilation errors are the full `ErrorReport`
+    pub data: Value,
+}
+
+impl ErrorEnvelope {
+    /// an error with `code`, not at a path, with no details
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorEnvelope {
+            code,
+            path: None,
+            message: message.into(),
+            data: Value::Null,
+        }
+    }
+    /// this error with details `data`
+    pub fn with_data(self, data: Value) -> Self {
+        ErrorEnvelope { data, ..self }
+    }
+}
+
+impl From<&ErrorReport> for ErrorEnvelope {
+    fn from(report: &ErrorReport) -> Self {
+        // the innermost error, and the path of the innermost `At` around it
+        let mut at = None;
+        let mut e = report;
+        loop {
+            match e {
+                ErrorReport::At { path, inner } => {
+                    at = Some(path);
+                    e = inner;
+                }
+                ErrorReport::BranchFailed { inner, .. }
+                | ErrorReport::ModuleError { inner, .. } => e = inner,
+                _ => break,
+            }
+        }
+        let path = match e {
+            ErrorReport::OutOfFunds { path, .. }
+            | ErrorReport::SchemaError { path, .. }
+            | ErrorReport::OutputBelowDust { path, .. }
+            | ErrorReport::UnaccountedFunds { path, .. }
+            | ErrorReport::IncompatibleAmountRange { path, .. }
+            | ErrorReport::IncompatibleTimeLocks { path, .. }
+            | ErrorReport::IncompatibleRelativeTimeLocks { path, .. }
+            | ErrorReport::ResourceLimitExceeded { path, .. }
+            | ErrorReport::Cancelled { path } => Some(path),
+            _ => at,
+        };
+        let (code, path) = (code(e), path.map(|p| String::from(p.clone())));
+        ErrorEnvelope {
+            code,
+            path,
+            message: report.to_string(),
+            data: serde_json::to_value(report).unwrap_or_default(),
+        }
+    }
+}
+
+/// the code for `e`, or for the error it wraps
+fn code(e: &ErrorReport) -> ErrorCode {
+    match e {
+        ErrorReport::TerminateCompilation => ErrorCode::TerminateCompilation,
+        ErrorReport::TerminateWith(_) => ErrorCode::TerminateWith,
+        ErrorReport::OutOfFunds { .. } => ErrorCode::OutOfFunds,
+        ErrorReport::SchemaError { .. } => ErrorCode::SchemaError,
+        ErrorReport::AmountOverflow => ErrorCode::AmountOverflow,
+        ErrorReport::MinFeerateError => ErrorCode::MinFeerateError,
+        ErrorReport::FeeReservationExceedsFunds { .. } => ErrorCode::FeeReservationExceedsFunds,
+        ErrorReport::FeeRateShortfall { .. } => ErrorCode::FeeRateShortfall,
+        ErrorReport::ChangeBelowMinimum { .. } => ErrorCode::ChangeBelowMinimum,
+        ErrorReport::OutputBelowDust { .. } => ErrorCode::OutputBelowDust,
+        ErrorReport::UnaccountedFunds { .. } => ErrorCode::UnaccountedFunds,
+        ErrorReport::IncompatibleAmountRange { .. } => ErrorCode::IncompatibleAmountRange,
+        ErrorReport::WrongNetwork { .. } => ErrorCode::WrongNetwork,
+        ErrorReport::MissingChainTip(_) => ErrorCode::MissingChainTip,
+        ErrorReport::TimeLockError(_) => ErrorCode::TimeLockError,
+        ErrorReport::IncompatibleTimeLocks { .. }
+        | ErrorReport::IncompatibleRelativeTimeLocks { .. } => ErrorCode::IncompatibleTimeLocks,
+        ErrorReport::ClauseCompilationFailed { .. } => ErrorCode::ClauseCompilationFailed,
+        ErrorReport::TaprootOnlyClause { .. } => ErrorCode::TaprootOnlyClause,
+        ErrorReport::SegwitV0KeyParityUnknown { .. } => ErrorCode::SegwitV0KeyParityUnknown,
+        ErrorReport::BranchFailed { inner, .. }
+        | ErrorReport::At { inner, .. }
+        | ErrorReport::ModuleError { inner, .. } => code(inner),
+        ErrorReport::DuplicateFunctionName(_) => ErrorCode::DuplicateFunctionName,
+        ErrorReport::ConditionalCompilationFailed(_) => ErrorCode::ConditionalCompilationFailed,
+        ErrorReport::DeniedWarnings(_) => ErrorCode::DeniedWarnings,
+        ErrorReport::AllBranchesUnreachable => ErrorCode::AllBranchesUnreachable,
+        ErrorReport::ResourceLimitExceeded { .. } => ErrorCode::ResourceLimitExceeded,
+        ErrorReport::Cancelled { .. } => ErrorCode::Cancelled,
+        ErrorReport::UnknownModule => ErrorCode::UnknownModule,
+        ErrorReport::Custom(_) => ErrorCode::Custom,
+    }
+}
+
+impl From<&AuthError> for ErrorEnvelope {
+    fn from(e: &AuthError) -> Self {
+        let code = match e {
+            AuthError::Unauthenticated => ErrorCode::Unauthenticated,
+            AuthError::InvalidCredentials => ErrorCode::InvalidCredentials,
+            AuthError::Expired(_) => ErrorCode::CredentialsExpired,
+            AuthError::MissingCapability(_) => ErrorCode::MissingCapability,
+        };
+        ErrorEnvelope::new(code, e.to_string())
+            .with_data(serde_json::to_value(e).unwrap_or_default())
+    }
+}
+
+impl From<&SessionError> for ErrorEnvelope {
+    fn from(e: &SessionError) -> Self {
+        match e {
+            SessionError::Compiler(e) => (&ErrorReport::from(e)).into(),
+            SessionError::Json(e) => ErrorEnvelope::new(ErrorCode::InvalidMessage, e.to_string()),
+            #[cfg(feature = "cbor")]
+            SessionError::Cbor(e) => ErrorEnvelope::new(ErrorCode::InvalidMessage, e.to_string()),
+            SessionError::ContractNotRegistered => ErrorEnvelope::new(
+                ErrorCode::ContractNotRegistered,
+                "no contract is saved for the key",
+            ),
+            SessionError::ModuleDirectory(e) => ErrorEnvelope::new(
+                ErrorCode::ModuleDirectoryUnreadable,
+                format!("couldn't read the module directory: {}", e),
+            ),
+            SessionError::UnknownModule(module) => ErrorEnvelope::new(
+                ErrorCode::UnknownModule,
+                format!("no module `{}` is in the module directory", module),
+            )
+            .with_data(json!({ "module": module })),
+            SessionError::UnknownSession(id) => ErrorEnvelope::new(
+                ErrorCode::UnknownSession,
+                format!("no session `{}` is saved", id),
+            )
+            .with_data(json!({ "session_id": id })),
+            SessionError::SessionExpired(id) => ErrorEnvelope::new(
+                ErrorCode::SessionExpired,
+                format!("session `{}` has expired", id),
+            )
+            .with_data(json!({ "session_id": id })),
+            SessionError::Store(e) => ErrorEnvelope::new(
+                ErrorCode::StoreFailed,
+                format!("couldn't use the session store: {}", e),
+            ),
+            SessionError::Throttled { quota, retry_after } => ErrorEnvelope::new(
+                ErrorCode::Throttled,
+                format!("{}, retry after {:?}", quota, retry_after),
+            )
+            .with_data(json!({
+                "quota": quota,
+                "retry_after_ms": retry_after.as_millis() as u64,
+            })),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::Capability;
+    use sapio::contract::CompilationError;
+    use sapio::sapio_base::effects::EffectPath;
+    use std::convert::TryFrom;
+
+    /// `envelope` serializes with `code`, and reads back the same
+    fn check(envelope: ErrorEnvelope, code: &str) -> Value {
+        let value = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(value["code"], code, "{}", value);
+        let read: ErrorEnvelope = serde_json::from_value(value.clone()).unwrap();
+        assert_eq!(read, envelope);
+        value
+    }
+    #[test]
+    fn compilation_codes() {
+        let path = || json!("a/b");
+        let sample = json!({
+            "terminate_compilation": null,
+            "terminate_with": "stop",
+            "out_of_funds": {"path": path(), "needed": 2, "available": 1},
+            "schema_error": {"path": path(), "message": "missing field"},
+            "amount_overflow": null,
+            "min_feerate_error": null,
+            "fee_reservation_exceeds_funds": {"reserved": 2, "available": 1},
+            "fee_rate_shortfall": {"template": "t", "short": 1},
+            "change_below_minimum": {"remaining": 1, "minimum": 2},
+            "output_below_dust": {"path": path(), "index": 0, "amount": 1, "limit": 546},
+            "unaccounted_funds": {"path": path(), "missing": -1},
+            "incompatible_amount_range": {"path": path(), "accepted": {"max_btc": 1.0}, "funded": {"min_btc": 2.0}},
+            "wrong_network": {"network": "bitcoin", "what": "address"},
+            "missing_chain_tip": "height",
+            "time_lock_error": "too long",
+            "incompatible_time_locks": {"path": path(), "height": 1, "time": 500_000_000},
+            "clause_compilation_failed": {"clause": "c", "message": "m"},
+            "taproot_only_clause": {"clause": "c", "message": "m"},
+            "duplicate_function_name": "f",
+            "conditional_compilation_failed": ["no"],
+            "denied_warnings": [],
+            "all_branches_unreachable": null,
+            "resource_limit_exceeded": {"limit": {"depth": 1}, "path": path()},
+            "cancelled": {"path": path()},
+            "unknown_module": null,
+            "custom": "mine",
+        });
+        for (kind, content) in sample.as_object().unwrap() {
+            let report = match content {
+                Value::Null => json!({ "kind": kind }),
+                content => json!({"kind": kind, "content": content}),
+            };
+            let report: ErrorReport = serde_json::from_value(report).unwrap();
+            let value = check((&report).into(), kind);
+            // the path is the error's own, if it has one
+            let path = content.get("path").cloned().unwrap_or(Value::Null);
+            assert_eq!(value["path"], path);
+            assert_eq!(value["message"], report.to_string());
+            assert_eq!(value["data"], serde_json::to_value(&report).unwrap());
+        }
+    }
+    #[test]
+    fn wrapped_errors_are_coded_by_their_cause() {
+        let at = |p: &str, inner| ErrorReport::At {
+            path: EffectPath::try_from(p).unwrap(),
+            inner: Box::new(inner),
+        };
+        let report = ErrorReport::ModuleError {
+            module: "m".into(),
+            inner: Box::new(at(
+                "a/b",
+                ErrorReport::BranchFailed {
+                    branch: "pay".into(),
+                    inner: Box::new(at("a/b/c", ErrorReport::AmountOverflow)),
+                },
+            )),
+        };
+        let value = check((&report).into(), "amount_overflow");
+        assert_eq!(value["path"], "a/b/c");
+        assert_eq!(value["data"]["kind"], "module_error");
+        let schema = SessionError::Compiler(CompilationError::SchemaError {
+            path: EffectPath::try_from("a").unwrap(),
+            message: "m".into(),
+        });
+        assert_eq!(check(schema.envelope(), "schema_error")["path"], "a");
+    }
+    #[test]
+    fn session_codes() {
+        let json = serde_json::from_str::<Value>("{").unwrap_err();
+        check(SessionError::Json(json).envelope(), "invalid_message");
+        #[cfg(feature = "cbor")]
+        {
+            let cbor = sapio::sapio_base::cbor::from_slice::<Value>(&[0xff]).unwrap_err();
+            check(SessionError::Cbor(cbor).envelope(), "invalid_message");
+        }
+        check(
+            SessionError::ContractNotRegistered.envelope(),
+            "contract_not_registered",
+        );
+        let io = std::io::Error::from(std::io::ErrorKind::NotFound);
+        check(
+            SessionError::ModuleDirectory(io).envelope(),
+            "module_directory_unreadable",
+        );
+        let unknown = check(
+            SessionError::UnknownModule("m".into()).envelope(),
+            "unknown_module",
+        );
+        assert_eq!(unknown["data"], json!({"module": "m"}));
+        check(
+            SessionError::UnknownSession("ab".into()).envelope(),
+            "unknown_session",
+        );
+        check(
+            SessionError::SessionExpired("ab".into()).envelope(),
+            "session_expired",
+        );
+        let io = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+        check(SessionError::Store(io).envelope(), "store_failed");
+        let throttled = SessionError::Throttled {
+            quota: crate::quota::Quota::RequestsPerMinute,
+            retry_after: std::time::Duration::from_millis(1500),
+        };
+        assert_eq!(
+            check(throttled.envelope(), "throttled")["data"],
+            json!({"quota": "requests_per_minute", "retry_after_ms": 1500})
+        );
+        for (code, name) in [
+            (ErrorCode::BindFailed, "bind_failed"),
+            (ErrorCode::Skipped, "skipped"),
+            (ErrorCode::Panicked, "panicked"),
+            (ErrorCode::UnknownRequest, "unknown_request"),
+            (ErrorCode::DuplicateRequest, "duplicate_request"),
+        ] {
+            check(ErrorEnvelope::new(code, "m"), name);
+        }
+    }
+    #[test]
+    fn auth_codes() {
+        for (e, code) in [
+            (AuthError::Unauthenticated, "unauthenticated"),
+            (AuthError::InvalidCredentials, "invalid_credentials"),
+            (AuthError::Expired(1), "credentials_expired"),
+            (
+                AuthError::MissingCapability(Capability::Bind),
+                "missing_capability",
+            ),
+        ] {
+            let value = check((&e).into(), code);
+            assert_eq!(value["data"], serde_json::to_value(&e).unwrap());
+        }
+    }
+}
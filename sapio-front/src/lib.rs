@@ -7,6 +7,12 @@
 //! architecture for a server which can compile sapio contracts
 
 #![deny(missing_docs)]
+pub mod auth;
+pub mod emulator;
+pub mod error;
+pub mod modules;
+pub mod persist;
+pub mod quota;
 pub mod session;
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,372 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Listing the modules in a directory, e.g. a cache of compiled WASM plugins,
+//! for a session's client to discover what it can compile
+use crate::session::SessionError;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Schemas which serialize to more bytes than this are left out of a
+/// listing, for the client to fetch with a `get_schema` if it needs them
+pub const INLINE_SCHEMA_BYTES: usize = 16 * 1024;
+
+/// What a `ModuleLoader` reads of a module
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct LoadedModule {
+    /// the module's name, from its `get_name`
+    pub name: String,
+    /// the module's hash, to call it by
+    pub hash: String,
+    /// the schema of the `CreateArgs` it is called with, from its `get_api`
+    pub schema: RootSchema,
+    /// the module's version, as it declares it
+    #[serde(default)]
+    pub version: Option<String>,
+    /// the traits the module declares it implements
+    #[serde(default)]
+    pub traits: Vec<String>,
+}
+
+/// Reads a module from a file in a `ModuleDirectory`
+pub trait ModuleLoader: Send + Sync {
+    /// load the module in `file`
+    fn load(&self, file: &Path) -> Result<LoadedModule, Box<dyn Error>>;
+}
+
+/// A trait a module declares it implements
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ImplementedTrait {
+    /// the trait, e.g. `Mint_NFT_Trait_Version_0_1_0`
+    pub name: String,
+    /// the trait's version from its name, e.g. `0.1.0`
+    pub version: Option<String>,
+}
+
+/// A module in a listing
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct ModuleListing {
+    /// the module's name
+    pub name: String,
+    /// the module's hash
+    pub hash: String,
+    /// the schema of the `CreateArgs` the module is called with, unless it
+    /// is larger than `INLINE_SCHEMA_BYTES`
+    pub schema: Option<RootSchema>,
+    /// the module's version, if it declares one
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub version: Option<String>,
+    /// the traits the module implements
+    pub traits: Vec<ImplementedTrait>,
+}
+
+/// The modules of a directory
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default)]
+pub struct ModuleList {
+    /// the modules which loaded, in the order of their files' names
+    pub modules: Vec<ModuleListing>,
+    /// why each file which didn't load didn't, by file name
+    pub failed: BTreeMap<String, String>,
+}
+
+/// the version in a trait's name, e.g. `0.1.0` in
+/// `Mint_NFT_Trait_Version_0_1_0` or `0.1.1` in `BatchingTraitVersion0_1_1`
+fn trait_version(name: &str) -> Option<String> {
+    let (_, version) = name.rsplit_once("Version")?;
+    let parts: Vec<_> = version.trim_start_matches('_').split('_').collect();
+    parts
+        .iter()
+        .all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit()))
+        .then(|| parts.join("."))
+}
+
+/// when each file of a directory was last changed, to tell if a listing of
+/// it is out of date
+type Stamp = BTreeMap<String, (u64, Option<SystemTime>)>;
+
+/// a listing of a directory, with the schemas of its modules by hash
+type Listing = (Stamp, ModuleList, BTreeMap<String, RootSchema>);
+
+/// A directory of modules, listed with a `ModuleLoader` and cached until
+/// the directory changes
+pub struct ModuleDirectory {
+    path: PathBuf,
+    loader: Arc<dyn ModuleLoader>,
+    cache: Option<Listing>,
+}
+
+impl ModuleDirectory {
+    /// the modules in the files directly in `path`, read by `loader`
+    pub fn new(path: impl Into<PathBuf>, loader: Arc<dyn ModuleLoader>) -> Self {
+        ModuleDirectory {
+            path: path.into(),
+            loader,
+            cache: None,
+        }
+    }
+    fn stamp(&self) -> Result<Stamp, SessionError> {
+        let mut stamp = Stamp::new();
+        for entry in std::fs::read_dir(&self.path).map_err(SessionError::ModuleDirectory)? {
+            let entry = entry.map_err(SessionError::ModuleDirectory)?;
+            let metadata = entry.metadata().map_err(SessionError::ModuleDirectory)?;
+            if metadata.is_file() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                stamp.insert(name, (metadata.len(), metadata.modified().ok()));
+            }
+        }
+        Ok(stamp)
+    }
+    /// load every module, unless the directory is unchanged since they were
+    /// last loaded
+    fn refresh(&mut self) -> Result<&Listing, SessionError> {
+        let stamp = self.stamp()?;
+        if self.cache.as_ref().is_none_or(|(s, ..)| *s != stamp) {
+            let mut list = ModuleList::default();
+            let mut schemas = BTreeMap::new();
+            for file in stamp.keys() {
+                match self.loader.load(&self.path.join(file)) {
+                    Ok(module) => {
+                        let size = serde_json::to_vec(&module.schema).map_or(0, |s| s.len());
+                        let traits = module
+                            .traits
+                            .iter()
+                            .map(|name| ImplementedTrait {
+                                name: name.clone(),
+                                version: trait_version(name),
+                            })
+                            .collect();
+                        list.modules.push(ModuleListing {
+                            traits,
+                            schema: (size <= INLINE_SCHEMA_BYTES).then(|| module.schema.clone()),
+                            version: module.version,
+                            name: module.name,
+                            hash: module.hash.clone(),
+                        });
+                        schemas.insert(module.hash, module.schema);
+                    }
+                    Err(e) => {
+                        list.failed.insert(file.clone(), e.to_string());
+                    }
+                }
+            }
+            self.cache = Some((stamp, list, schemas));
+        }
+        Ok(self.cache.as_ref().unwrap())
+    }
+    /// the modules in the directory
+    pub fn list(&mut self) -> Result<ModuleList, SessionError> {
+        Ok(self.refresh()?.1.clone())
+    }
+    /// the hash of the module with hash, or else name, `module`
+    pub fn hash(&mut self, module: &str) -> Result<String, SessionError> {
+        let (_, list, _) = self.refresh()?;
+        list.modules
+            .iter()
+            .find(|m| m.hash == module)
+            .or_else(|| list.modules.iter().find(|m| m.name == module))
+            .map(|m| m.hash.clone())
+            .ok_or_else(|| SessionError::UnknownModule(module.into()))
+    }
+    /// the schema of the `CreateArgs` for the module with hash, or else name,
+    /// `module`
+    pub fn schema(&mut self, module: &str) -> Result<RootSchema, SessionError> {
+        let hash = self.hash(module)?;
+        let (_, _, schemas) = self.cache.as_ref().expect("refreshed to find the hash");
+        schemas
+            .get(&hash)
+            .cloned()
+            .ok_or_else(|| SessionError::UnknownModule(module.into()))
+    }
+}
+
+/// Loads compiled WASM plugins from a plugin cache directory, as its file
+/// names are the plugins' hashes
+#[cfg(feature = "wasm")]
+pub struct WasmModuleLoader {
+    /// the network the plugins are instantiated for
+    pub network: bitcoin::Network,
+}
+
+#[cfg(feature = "wasm")]
+impl ModuleLoader for WasmModuleLoader {
+    fn load(&self, file: &Path) -> Result<LoadedModule, Box<dyn Error>> {
+        use sapio::contract::Compiled;
+        use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator};
+        use sapio_wasm_plugin::host::plugin_handle::{SyncModuleLocator, WASMCacheID};
+        use sapio_wasm_plugin::host::{PluginHandle, WasmPluginHandle};
+        use std::str::FromStr;
+        let key = file
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or("not the name of a cached plugin")?;
+        let emulator: Arc<dyn CTVEmulator> = Arc::new(CTVAvailable);
+        let handle = WasmPluginHandle::<Compiled>::new(
+            file.parent().unwrap_or(file).to_path_buf(),
+            &emulator,
+            SyncModuleLocator::Key(WASMCacheID::from_str(key)?),
+            self.network,
+            None,
+        )?;
+        let info = handle.get_info()?;
+        Ok(LoadedModule {
+            name: handle.get_name()?,
+            hash: handle.id().to_string(),
+            schema: handle.get_api()?.input().clone(),
+            version: info.version,
+            traits: info.traits,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn trait_versions() {
+        assert_eq!(
+            trait_version("Mint_NFT_Trait_Version_0_1_0").as_deref(),
+            Some("0.1.0")
+        );
+        assert_eq!(
+            trait_version("BatchingTraitVersion0_1_1").as_deref(),
+            Some("0.1.1")
+        );
+        assert_eq!(trait_version("SomeTrait"), None);
+        assert_eq!(trait_version("Version_beta"), None);
+    }
+
+    /// a plugin exporting only what listing it needs, whose `get_name`,
+    /// `get_create_arguments` and, if given, `get_info` return `name`, `api`
+    /// and `info`
+    #[cfg(feature = "wasm")]
+    fn fixture_plugin(name: &str, api: &str, info: Option<&str>) -> Vec<u8> {
+        let data = |at: usize, s: &str| {
+            format!(
+                "(data (i32.const {}) \"{}\\00\")",
+                at,
+                s.replace('"', "\\\"")
+            )
+        };
+        let get_info = info.map_or(String::new(), |info| {
+            format!(
+                "{} (func (export \"sapio_v1_wasm_plugin_client_get_info\") (result i32) i32.const 8192)",
+                data(8192, info)
+            )
+        });
+        format!(
+            r#"(module
+            (memory (export "memory") 1)
+            {} {} {}
+            (func (export "sapio_v1_wasm_plugin_client_allocate_bytes") (param i32) (result i32)
+                i32.const 16384)
+            (func (export "sapio_v1_wasm_plugin_client_get_create_arguments") (result i32)
+                i32.const 4096)
+            (func (export "sapio_v1_wasm_plugin_client_get_name") (result i32) i32.const 1024)
+            (func (export "sapio_v1_wasm_plugin_client_get_logo") (result i32) i32.const 1024)
+            (func (export "sapio_v1_wasm_plugin_client_drop_allocation") (param i32))
+            (func (export "sapio_v1_wasm_plugin_client_create") (param i32 i32) (result i32)
+                unreachable)
+            (func (export "sapio_v1_wasm_plugin_entry_point")))"#,
+            data(1024, name),
+            data(4096, api),
+            get_info
+        )
+        .into_bytes()
+    }
+
+    #[cfg(feature = "wasm")]
+    #[test]
+    fn lists_wasm_plugins() {
+        use sapio::contract::Compiled;
+        use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator};
+        use sapio_wasm_plugin::host::plugin_handle::SyncModuleLocator;
+        use sapio_wasm_plugin::host::WasmPluginHandle;
+        let dir = std::env::temp_dir().join(format!("sapio-front-wasm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let emulator: Arc<dyn CTVEmulator> = Arc::new(CTVAvailable);
+        let network = bitcoin::Network::Regtest;
+        let api = |title: &str| {
+            format!(
+                r#"{{"arguments":{{"title":"{}","type":"object"}},"returns":{{}}}}"#,
+                title
+            )
+        };
+        let info = r#"{"version":"0.3.1","traits":["Mint_NFT_Trait_Version_0_1_0"]}"#;
+        // a plugin declaring its version and traits, and one built before
+        // they could be declared
+        let plugins = [
+            fixture_plugin("minter", &api("Minter"), Some(info)),
+            fixture_plugin("legacy", &api("Legacy"), None),
+        ];
+        let hashes: Vec<_> = plugins
+            .iter()
+            .map(|wasm| {
+                WasmPluginHandle::<Compiled>::new(
+                    dir.clone(),
+                    &emulator,
+                    SyncModuleLocator::Bytes(wasm.clone()),
+                    network,
+                    None,
+                )
+                .unwrap()
+                .id()
+                .to_string()
+            })
+            .collect();
+        let mut modules = ModuleDirectory::new(&dir, Arc::new(WasmModuleLoader { network }));
+        let list = modules.list().unwrap();
+        assert_eq!(list.failed, BTreeMap::new());
+        let by_name = |name: &str| {
+            list.modules
+                .iter()
+                .find(|m| m.name == name)
+                .unwrap()
+                .clone()
+        };
+        let (minter, legacy) = (by_name("minter"), by_name("legacy"));
+        assert_eq!(minter.hash, hashes[0]);
+        assert_eq!(minter.version.as_deref(), Some("0.3.1"));
+        assert_eq!(
+            minter.traits,
+            vec![ImplementedTrait {
+                name: "Mint_NFT_Trait_Version_0_1_0".into(),
+                version: Some("0.1.0".into()),
+            }]
+        );
+        assert_eq!(
+            minter
+                .schema
+                .unwrap()
+                .schema
+                .metadata
+                .unwrap()
+                .title
+                .as_deref(),
+            Some("Minter")
+        );
+        assert_eq!(legacy.hash, hashes[1]);
+        assert_eq!(legacy.version, None);
+        assert!(legacy.traits.is_empty());
+        assert_eq!(
+            modules
+                .schema("legacy")
+                .unwrap()
+                .schema
+                .metadata
+                .unwrap()
+                .title
+                .as_deref(),
+            Some("Legacy")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
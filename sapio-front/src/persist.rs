@@ -0,0 +1,160 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Saving a session's workspace, so that a client can resume it after the
+//! server restarts, see `Session::set_store`
+use crate::auth::Identity;
+use crate::session::CompileRequest;
+use bitcoin::util::amount::Amount;
+use sapio::contract::object::Diagnostic;
+use sapio::sapio_base::effects::EffectPath;
+use sapio::sapio_base::serialization_helpers::SArc;
+use sapio::util::extended_address::ExtendedAddress;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// What a session last created, without the program, which the client can
+/// create again from the same request
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CreatedSummary {
+    /// the most the contract can be funded with
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: Amount,
+    /// the contract's address
+    pub address: ExtendedAddress,
+    /// the diagnostics it was compiled with
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// The workspace of a session, as saved in a `SessionStore`
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct SavedSession {
+    /// the unix time, in seconds, the session was last saved at
+    pub saved_at: u64,
+    /// who the session's client authenticated as, the only client which
+    /// may resume it
+    #[serde(default)]
+    pub owner: Option<Identity>,
+    /// the effects patched in, by continuation point and name
+    pub effects: BTreeMap<SArc<EffectPath>, BTreeMap<SArc<String>, Value>>,
+    /// the hashes of the modules the client fetched schemas for
+    #[serde(default)]
+    pub modules: BTreeSet<String>,
+    /// the last contract the client asked to create
+    #[serde(default)]
+    pub last_create: Option<CompileRequest>,
+    /// what that created, if it compiled
+    #[serde(default)]
+    pub last_created: Option<CreatedSummary>,
+}
+
+/// Where sessions are saved, by session id, e.g. a directory or a database
+pub trait SessionStore: Send + Sync {
+    /// save `session` as `id`, in place of any saved before
+    fn save(&self, id: &str, session: &SavedSession) -> Result<(), io::Error>;
+    /// the session saved as `id`, if there is one
+    fn load(&self, id: &str) -> Result<Option<SavedSession>, io::Error>;
+    /// forget the session saved as `id`, if there is one
+    fn remove(&self, id: &str) -> Result<(), io::Error>;
+    /// forget every session last saved before unix time `before`, returning
+    /// how many were
+    fn expire(&self, before: u64) -> Result<usize, io::Error>;
+}
+
+/// Sessions kept only as long as the process, e.g. for tests
+#[derive(Default)]
+pub struct MemoryStore(Mutex<BTreeMap<String, SavedSession>>);
+
+impl SessionStore for MemoryStore {
+    fn save(&self, id: &str, session: &SavedSession) -> Result<(), io::Error> {
+        self.0.lock().unwrap().insert(id.into(), session.clone());
+        Ok(())
+    }
+    fn load(&self, id: &str) -> Result<Option<SavedSession>, io::Error> {
+        Ok(self.0.lock().unwrap().get(id).cloned())
+    }
+    fn remove(&self, id: &str) -> Result<(), io::Error> {
+        self.0.lock().unwrap().remove(id);
+        Ok(())
+    }
+    fn expire(&self, before: u64) -> Result<usize, io::Error> {
+        let mut sessions = self.0.lock().unwrap();
+        let count = sessions.len();
+        sessions.retain(|_, s| s.saved_at >= before);
+        Ok(count - sessions.len())
+    }
+}
+
+/// Sessions saved as a JSON file each, named by session id, in a directory
+pub struct DirectoryStore {
+    path: PathBuf,
+}
+
+impl DirectoryStore {
+    /// save sessions in the directory at `path`, creating it if needed
+    pub fn new(path: impl Into<PathBuf>) -> Result<Self, io::Error> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+        Ok(DirectoryStore { path })
+    }
+    /// the file for session `id`, which clients choose when resuming, so
+    /// must not name a file elsewhere
+    fn file(&self, id: &str) -> Result<PathBuf, io::Error> {
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "session ids are hex",
+            ));
+        }
+        Ok(self.path.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for DirectoryStore {
+    fn save(&self, id: &str, session: &SavedSession) -> Result<(), io::Error> {
+        // written aside and renamed, so a crash doesn't leave half a session
+        let file = self.file(id)?;
+        let partial = file.with_extension("partial");
+        std::fs::write(&partial, serde_json::to_vec(session)?)?;
+        std::fs::rename(partial, file)
+    }
+    fn load(&self, id: &str) -> Result<Option<SavedSession>, io::Error> {
+        match std::fs::read(self.file(id)?) {
+            Ok(v) => Ok(Some(serde_json::from_slice(&v)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+    fn remove(&self, id: &str) -> Result<(), io::Error> {
+        match std::fs::remove_file(self.file(id)?) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+    fn expire(&self, before: u64) -> Result<usize, io::Error> {
+        let mut count = 0;
+        for entry in std::fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "json") {
+                continue;
+            }
+            // a file which can't be read as a session is left alone
+            let saved_at = std::fs::read(&path)
+                .ok()
+                .and_then(|v| serde_json::from_slice::<SavedSession>(&v).ok())
+                .map(|s| s.saved_at);
+            if saved_at.is_some_and(|t| t < before) {
+                std::fs::remove_file(path)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Limiting what each client may use of a server, and how many compiles
+//! the server runs at once, so that one client can't keep the others waiting
+use crate::auth::Identity;
+use sapio::contract::ResourceLimits;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a client throttled by compiles which are busy is told to wait,
+/// as when they will finish isn't known
+pub const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// What a session may use, see `Session::set_quotas`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quotas {
+    /// the most requests a session may compile at once, in the background
+    /// or not, counting those cancelled or timed out until they stop
+    pub max_concurrent: usize,
+    /// the most messages a client may send in a minute, see
+    /// `Session::set_rate_limiter`
+    pub requests_per_minute: usize,
+    /// the most each compile may use
+    pub limits: ResourceLimits,
+}
+
+impl Default for Quotas {
+    /// any number of requests, each compiled within
+    /// `ResourceLimits::sandboxed()`
+    fn default() -> Self {
+        Quotas {
+            max_concurrent: usize::MAX,
+            requests_per_minute: usize::MAX,
+            limits: ResourceLimits::sandboxed(),
+        }
+    }
+}
+
+/// Which quota a request was refused by
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Quota {
+    /// `Quotas::max_concurrent`
+    ConcurrentCompiles,
+    /// `Quotas::requests_per_minute`
+    RequestsPerMinute,
+    /// the server's `CompileQueue` is full
+    ServerBusy,
+}
+
+impl Display for Quota {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Quota::ConcurrentCompiles => write!(f, "too many requests compiling"),
+            Quota::RequestsPerMinute => write!(f, "too many requests this minute"),
+            Quota::ServerBusy => write!(f, "the server is busy"),
+        }
+    }
+}
+
+/// Who a request is from, for the quotas sessions share: the identity its
+/// session authenticated as, or the session itself before it has
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Client {
+    /// an authenticated client, whichever session it uses
+    Identity(Identity),
+    /// an unauthenticated session, by its id
+    Session(String),
+}
+
+/// The times of a client's recent messages, to limit how many it sends a
+/// minute
+#[derive(Default)]
+pub(crate) struct RateWindow(VecDeque<Instant>);
+
+impl RateWindow {
+    /// count a message at `now`, unless `limit` have been counted in the
+    /// minute before, returning how long until one of those is a minute old
+    pub(crate) fn admit(&mut self, limit: usize, now: Instant) -> Result<(), Duration> {
+        let minute = Duration::from_secs(60);
+        while self
+            .0
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= minute)
+        {
+            self.0.pop_front();
+        }
+        match self.0.front() {
+            Some(oldest) if self.0.len() >= limit => {
+                Err((*oldest + minute).saturating_duration_since(now))
+            }
+            _ if limit == 0 => Err(minute),
+            _ => {
+                self.0.push_back(now);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The rate windows of a server's clients, shared by their sessions so that
+/// a client can't send more by opening more sessions, see
+/// `Session::set_rate_limiter`
+#[derive(Default)]
+pub struct RateLimiter {
+    windows: Mutex<BTreeMap<Client, RateWindow>>,
+}
+
+impl RateLimiter {
+    /// count a message from `client` at `now`, see `RateWindow::admit`
+    pub(crate) fn admit(
+        &self,
+        client: &Client,
+        limit: usize,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let mut windows = self.windows.lock().unwrap();
+        let admitted = windows.entry(client.clone()).or_default().admit(limit, now);
+        // forget clients with nothing in the last minute
+        windows.retain(|_, w| {
+            w.0.back()
+                .is_some_and(|t| now.saturating_duration_since(*t) < Duration::from_secs(60))
+        });
+        admitted
+    }
+}
+
+struct QueueState {
+    running: usize,
+    next: u64,
+    /// the turns waiting, by client, in the order each asked
+    waiting: BTreeMap<Client, VecDeque<u64>>,
+    /// the clients with turns waiting, in the order they are next served
+    rotation: VecDeque<Client>,
+}
+
+impl QueueState {
+    fn n_waiting(&self) -> usize {
+        self.waiting.values().map(VecDeque::len).sum()
+    }
+    /// the turn served next
+    fn next_up(&self) -> Option<u64> {
+        let client = self.rotation.front()?;
+        self.waiting.get(client)?.front().copied()
+    }
+    /// stop waiting for `number`, a turn of `client`
+    fn remove(&mut self, client: &Client, number: u64) {
+        if let Some(turns) = self.waiting.get_mut(client) {
+            turns.retain(|n| *n != number);
+            if turns.is_empty() {
+                self.waiting.remove(client);
+                self.rotation.retain(|c| c != client);
+            }
+        }
+    }
+}
+
+/// The compiles a server runs at once, shared by its sessions, with those
+/// waiting for a turn taken from each client in turn, and from each client
+/// in the order it asked, see `Session::set_compile_queue`
+pub struct CompileQueue {
+    max_running: usize,
+    max_waiting: usize,
+    state: Mutex<QueueState>,
+    changed: Condvar,
+}
+
+impl CompileQueue {
+    /// run at most `max_running` compiles at once, with at most
+    /// `max_waiting` more waiting for a turn, refusing those after
+    pub fn new(max_running: usize, max_waiting: usize) -> Self {
+        CompileQueue {
+            max_running,
+            max_waiting,
+            state: Mutex::new(QueueState {
+                running: 0,
+                next: 0,
+                waiting: BTreeMap::new(),
+                rotation: VecDeque::new(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+    /// the number of compiles running, and waiting for a turn
+    pub fn load(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.running, state.n_waiting())
+    }
+    /// take a place in the queue for `client`, unless it is full
+    pub(crate) fn enqueue(self: &Arc<Self>, client: Client) -> Option<Turn> {
+        let mut state = self.state.lock().unwrap();
+        if state.running + state.n_waiting() >= self.max_running.saturating_add(self.max_waiting) {
+            return None;
+        }
+        let number = state.next;
+        state.next += 1;
+        if !state.waiting.contains_key(&client) {
+            state.rotation.push_back(client.clone());
+        }
+        state
+            .waiting
+            .entry(client.clone())
+            .or_default()
+            .push_back(number);
+        Some(Turn {
+            queue: self.clone(),
+            client,
+            number,
+            running: false,
+        })
+    }
+}
+
+/// A place in a `CompileQueue`, given up when dropped
+pub(crate) struct Turn {
+    queue: Arc<CompileQueue>,
+    client: Client,
+    number: u64,
+    running: bool,
+}
+
+impl Turn {
+    /// wait until it is this turn, returning false if `cancelled` is set
+    /// first
+    pub(crate) fn wait(&mut self, cancelled: &AtomicBool) -> bool {
+        let queue = &self.queue;
+        let mut state = queue.state.lock().unwrap();
+        loop {
+            if state.next_up() == Some(self.number) && state.running < queue.max_running {
+                state.remove(&self.client, self.number);
+                // the client's next turn waits for every other client's
+                if state.waiting.contains_key(&self.client) {
+                    state.rotation.retain(|c| *c != self.client);
+                    state.rotation.push_back(self.client.clone());
+                }
+                state.running += 1;
+                self.running = true;
+                // the next in line may be able to run too
+                queue.changed.notify_all();
+                return true;
+            }
+            if cancelled.load(Ordering::Relaxed) {
+                return false;
+            }
+            // woken when a turn ends, and every so often to notice a cancel
+            state = queue
+                .changed
+                .wait_timeout(state, Duration::from_millis(10))
+                .unwrap()
+                .0;
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        let mut state = self.queue.state.lock().unwrap();
+        if self.running {
+            state.running -= 1;
+        } else {
+            state.remove(&self.client, self.number);
+        }
+        self.queue.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn rate_window() {
+        let start = Instant::now();
+        let mut window = RateWindow::default();
+        assert!(window.admit(2, start).is_ok());
+        assert!(window.admit(2, start + Duration::from_secs(10)).is_ok());
+        assert_eq!(
+            window.admit(2, start + Duration::from_secs(30)),
+            Err(Duration::from_secs(30))
+        );
+        // the first is a minute old
+        assert!(window.admit(2, start + Duration::from_secs(60)).is_ok());
+        assert_eq!(
+            window.admit(2, start + Duration::from_secs(61)),
+            Err(Duration::from_secs(9))
+        );
+        assert!(RateWindow::default().admit(0, start).is_err());
+    }
+}
@@ -6,24 +6,42 @@
 
 //! An interactive compilation session designed to be compatible with sapio-lang/TUX
 
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::{sha256, Hash};
 use bitcoin::util::amount::Amount;
 use sapio::contract::context::MapEffectDB;
+#[cfg(feature = "cbor")]
+use sapio::sapio_base::cbor::{self, CborError};
+use sapio::sapio_base::effects::{EditableMapEffectDB, EffectPath};
+use sapio::sapio_base::serialization_helpers::SArc;
+use sapio::sapio_base::timelocks::{AbsHeight, AbsTime};
+use sapio::util::merge_patch::merge_patch;
 
-use sapio::contract::object::Program;
-use sapio::contract::{Compilable, CompilationError, Compiled, Context};
+use crate::auth::{now, AuthConfig, Capability, Credentials, Identity};
+use crate::emulator::EmulatorConfig;
+use crate::error::{ErrorCode, ErrorEnvelope};
+use crate::modules::{ModuleDirectory, ModuleList};
+use crate::persist::{CreatedSummary, SavedSession, SessionStore};
+use crate::quota::{Client, CompileQueue, Quota, Quotas, RateLimiter, BUSY_RETRY_AFTER};
+use sapio::contract::error::ResourceLimit;
+use sapio::contract::object::{Diagnostic, Program};
+use sapio::contract::{
+    Compilable, CompilationError, Compiled, Context, ErrorReport, ResourceLimits,
+};
 use sapio::util::extended_address::ExtendedAddress;
-use sapio_ctv_emulator_trait::CTVAvailable;
+use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError};
 use schemars::schema::RootSchema;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::convert::TryFrom;
 use std::convert::TryInto;
 use std::fmt::Display;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 type Key = bitcoin::hashes::sha256::Hash;
 /// Errors that can arise during a Session
@@ -31,10 +49,31 @@ type Key = bitcoin::hashes::sha256::Hash;
 pub enum SessionError {
     /// Issue was with Serde
     Json(serde_json::Error),
+    /// A message or reaction in CBOR could not be read or written
+    #[cfg(feature = "cbor")]
+    Cbor(CborError),
     /// Issue came from Compilation
     Compiler(CompilationError),
     /// The session does not have an object saved for the key requested
     ContractNotRegistered,
+    /// The module directory could not be read
+    ModuleDirectory(std::io::Error),
+    /// The module directory has no module with the hash or name requested
+    UnknownModule(String),
+    /// No session is saved with the id requested
+    UnknownSession(String),
+    /// The session saved with the id requested has expired
+    SessionExpired(String),
+    /// The session store could not be read or written
+    Store(std::io::Error),
+    /// A request was refused by one of the session's quotas, and may be
+    /// made again after `retry_after`
+    Throttled {
+        /// the quota
+        quota: Quota,
+        /// how long until the request may succeed
+        retry_after: Duration,
+    },
 }
 
 impl std::error::Error for SessionError {}
@@ -50,18 +89,62 @@ impl From<std::convert::Infallible> for SessionError {
     }
 }
 
+impl From<serde_json::Error> for SessionError {
+    fn from(v: serde_json::Error) -> Self {
+        SessionError::Json(v)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<CborError> for SessionError {
+    fn from(v: CborError) -> Self {
+        SessionError::Cbor(v)
+    }
+}
+
 impl From<CompilationError> for SessionError {
     fn from(v: CompilationError) -> Self {
         SessionError::Compiler(v)
     }
 }
 
+impl SessionError {
+    /// The structured form of this error, to send to a client
+    pub fn report(&self) -> ErrorReport {
+        match self {
+            SessionError::Compiler(e) => e.into(),
+            SessionError::Json(e) => ErrorReport::Custom(e.to_string()),
+            #[cfg(feature = "cbor")]
+            SessionError::Cbor(e) => ErrorReport::Custom(e.to_string()),
+            SessionError::ContractNotRegistered
+            | SessionError::ModuleDirectory(_)
+            | SessionError::UnknownModule(_)
+            | SessionError::UnknownSession(_)
+            | SessionError::SessionExpired(_)
+            | SessionError::Store(_)
+            | SessionError::Throttled { .. } => ErrorReport::Custom(self.to_string()),
+        }
+    }
+    /// The envelope this error is sent to a client in
+    pub fn envelope(&self) -> ErrorEnvelope {
+        self.into()
+    }
+}
+
+/// the arguments for the contract to compile in `ctx` don't match its schema
+fn schema_error(ctx: &Context, e: serde_json::Error) -> SessionError {
+    SessionError::Compiler(CompilationError::SchemaError {
+        path: ctx.path().as_ref().clone(),
+        message: e.to_string(),
+    })
+}
+
 /// Create a compiled object of type `T` from a JSON
 pub fn from_json<T>(s: serde_json::Value, ctx: Context) -> Result<Compiled, SessionError>
 where
     T: for<'a> Deserialize<'a> + Compilable,
 {
-    let t: T = serde_json::from_value(s).map_err(SessionError::Json)?;
+    let t: T = serde_json::from_value(s).map_err(|e| schema_error(&ctx, e))?;
 
     ctx.compile(t).map_err(SessionError::Compiler)
 }
@@ -77,18 +160,49 @@ where
     T: TryFrom<C, Error = E> + Compilable,
     SessionError: From<E>,
 {
-    let t: C = serde_json::from_value(s).map_err(SessionError::Json)?;
+    let t: C = serde_json::from_value(s).map_err(|e| schema_error(&ctx, e))?;
 
     ctx.compile(T::try_from(t).map_err(SessionError::from)?)
         .map_err(SessionError::Compiler)
 }
 
+/// How a session's messages and reactions are encoded
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ContentType {
+    /// JSON, the default
+    #[default]
+    #[serde(rename = "application/json")]
+    Json,
+    /// CBOR, with the same structure as the JSON, see `sapio_base::cbor`
+    #[cfg(feature = "cbor")]
+    #[serde(rename = "application/cbor")]
+    Cbor,
+}
+
 /// An action requested by the client
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "action", content = "content")]
 enum Action {
     #[serde(rename = "close")]
     Close,
+    /// encode the messages and reactions after this one as `content_type`,
+    /// and authenticate with `credentials` if any
+    #[serde(rename = "handshake")]
+    Handshake {
+        content_type: ContentType,
+        #[serde(default)]
+        credentials: Option<Credentials>,
+    },
+    /// get the session's challenge, for a key to sign to authenticate with
+    #[serde(rename = "challenge")]
+    Challenge,
+    /// get the session's id, to resume it with after reconnecting
+    #[serde(rename = "session_id")]
+    SessionId,
+    /// restore the workspace of the session saved as `session_id`, see
+    /// `Session::set_store`
+    #[serde(rename = "resume")]
+    Resume { session_id: String },
     #[serde(rename = "create")]
     Create {
         #[serde(rename = "type")]
@@ -99,6 +213,56 @@ enum Action {
     Save(bitcoin::Address),
     #[serde(rename = "bind")]
     Bind(bitcoin::OutPoint, bitcoin::Address),
+    /// merge a JSON Merge Patch into the arguments for the continuation
+    /// `name` at `path`, used by later compilations
+    #[serde(rename = "patch")]
+    Patch {
+        path: SArc<EffectPath>,
+        name: String,
+        patch: Value,
+    },
+    /// list the modules in the session's module directory, see
+    /// `Session::set_module_directory`
+    #[serde(rename = "list_modules")]
+    ListModules,
+    /// get the schema of the `CreateArgs` for the module with a hash, or
+    /// else name, left out of a listing as too large
+    #[serde(rename = "get_schema")]
+    GetSchema { module: String },
+    /// stop compiling the request with id `request_id`, reacting with its
+    /// id
+    #[serde(rename = "cancel")]
+    Cancel { request_id: String },
+    /// compile each request as a create would, within the session's batch
+    /// budget, see `Session::set_batch_limits`
+    #[serde(rename = "compile_batch")]
+    CompileBatch {
+        requests: Vec<CompileRequest>,
+        /// skip the requests after the first which fails
+        #[serde(default)]
+        fail_fast: bool,
+    },
+}
+
+/// A request from the client. One with an id is compiled in the background
+/// if it compiles anything, and reacted to with the id, see
+/// `Session::completed`.
+#[derive(Serialize, Deserialize)]
+struct Request {
+    #[serde(default)]
+    request_id: Option<String>,
+    #[serde(flatten)]
+    action: Action,
+}
+
+/// A contract to compile, as in a create or a `CompileBatch`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CompileRequest {
+    /// the name of the contract in the menu
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// the contract's arguments
+    pub args: Value,
 }
 
 /// A response to a client request
@@ -108,7 +272,25 @@ pub enum Reaction {
     /// Send over a menu of available contracts / their arguments
     #[serde(rename = "menu")]
     Menu(Value),
-    ///  sendthe Session ID
+    /// respond to a handshake with the content type agreed to, sent as JSON
+    #[serde(rename = "handshake")]
+    Handshake(ContentType),
+    /// respond to a Challenge request with the challenge, see
+    /// `auth::challenge_message`
+    #[serde(rename = "challenge")]
+    Challenge(sha256::Hash),
+    /// respond to a request with an id which is compiling in the background,
+    /// to be reacted to again once done, see `Session::completed`
+    #[serde(rename = "started")]
+    Started,
+    /// react to a request with an id which the client cancelled
+    #[serde(rename = "cancelled")]
+    Cancelled,
+    /// react to a request which was stopped after the session's timeout, see
+    /// `Session::set_timeout`
+    #[serde(rename = "timed_out")]
+    TimedOut,
+    /// send the Session ID, and whether the session was just resumed
     #[serde(rename = "session_id")]
     Session(bool, String),
     /// Send the program created
@@ -117,6 +299,7 @@ pub enum Reaction {
         #[serde(with = "bitcoin::util::amount::serde::as_sat")] Amount,
         ExtendedAddress,
         Program,
+        Vec<Diagnostic>,
     ),
     /// if the save request completed successfully
     #[serde(rename = "saved")]
@@ -124,7 +307,36 @@ pub enum Reaction {
     /// respond to Bind request with the transactions created
     #[serde(rename = "bound")]
     Bound(Vec<bitcoin::Transaction>),
+    /// respond to a Patch request with the arguments after patching
+    #[serde(rename = "patched")]
+    Patched(Value),
+    /// respond to a ListModules request
+    #[serde(rename = "modules")]
+    Modules(ModuleList),
+    /// respond to a GetSchema request
+    #[serde(rename = "schema")]
+    Schema(RootSchema),
+    /// respond to a CompileBatch request with a created or error reaction
+    /// for each request, in the order requested
+    #[serde(rename = "batch")]
+    Batch(Vec<Reaction>),
+    /// respond to a request which failed, or which the client isn't
+    /// allowed to make
+    #[serde(rename = "error")]
+    Error(ErrorEnvelope),
+}
+
+/// A reaction, with the id of the request it is to if it had one
+#[derive(Serialize, Deserialize)]
+pub struct Response {
+    /// the id of the request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// the reaction
+    #[serde(flatten)]
+    pub reaction: Reaction,
 }
+
 fn create_mock_output() -> bitcoin::OutPoint {
     bitcoin::OutPoint {
         txid: bitcoin::hashes::sha256d::Hash::from_inner(
@@ -136,30 +348,354 @@ fn create_mock_output() -> bitcoin::OutPoint {
 }
 
 use sapio::sapio_base::txindex::TxIndexLogger;
+
+/// What compiling a contract used of a batch's budget
+#[derive(Clone, Copy, Default)]
+struct Usage {
+    templates: usize,
+    bytes: usize,
+}
+
+impl Usage {
+    /// the templates in `c`'s tree and their size, as counted against the
+    /// `ResourceLimits` when they were compiled
+    fn of(c: &Compiled) -> Usage {
+        c.ctv_to_tx
+            .values()
+            .chain(c.suggested_txs.values())
+            .fold(Usage::default(), |u, t| {
+                let u = u.add(Usage {
+                    templates: 1,
+                    bytes: t.tx.size(),
+                });
+                t.outputs
+                    .iter()
+                    .fold(u, |u, o| u.add(Usage::of(&o.contract)))
+            })
+    }
+    fn add(self, other: Usage) -> Usage {
+        Usage {
+            templates: self.templates + other.templates,
+            bytes: self.bytes + other.bytes,
+        }
+    }
+    /// the limit of `limits` this exceeds, if any
+    fn exceeds(&self, limits: &ResourceLimits) -> Option<ResourceLimit> {
+        if self.templates > limits.max_templates {
+            Some(ResourceLimit::Templates(limits.max_templates))
+        } else if self.bytes > limits.max_bytes {
+            Some(ResourceLimit::Bytes(limits.max_bytes))
+        } else {
+            None
+        }
+    }
+    /// the limit of `limits` this has used all of, if any
+    fn spends(&self, limits: &ResourceLimits) -> Option<ResourceLimit> {
+        if self.templates >= limits.max_templates {
+            Some(ResourceLimit::Templates(limits.max_templates))
+        } else if self.bytes >= limits.max_bytes {
+            Some(ResourceLimit::Bytes(limits.max_bytes))
+        } else {
+            None
+        }
+    }
+    /// what is left of `limits` after this
+    fn left_of(&self, limits: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            max_depth: limits.max_depth,
+            max_templates: limits.max_templates.saturating_sub(self.templates),
+            max_bytes: limits.max_bytes.saturating_sub(self.bytes),
+        }
+    }
+}
+
+/// makes contexts to compile a session's requests in, see `Session::contexts`
+type Contexts = dyn Fn() -> Context + Send + Sync;
+
+/// the reaction to a request whose compilation panicked
+fn panicked() -> Reaction {
+    Reaction::Error(ErrorEnvelope::new(
+        ErrorCode::Panicked,
+        "compilation panicked",
+    ))
+}
+
+/// compile contract `type_` of `menu` and bind it to a mock output, reacting
+/// with the program created or the error, and what it used
+fn create(menu: &Menu, type_: String, args: Value, ctx: Context) -> (Reaction, Usage) {
+    react_to_compiled(menu.compile(type_, args, ctx))
+}
+
+/// bind a compiled contract to a mock output, see `create`
+fn react_to_compiled(compiled: Result<Compiled, SessionError>) -> (Reaction, Usage) {
+    let c = match compiled {
+        Ok(c) => c,
+        Err(e) => return (Reaction::Error(e.envelope()), Usage::default()),
+    };
+    let a = c.address.clone();
+    // todo amount
+    let program = match c.bind_psbt(
+        create_mock_output(),
+        BTreeMap::new(),
+        Rc::new(TxIndexLogger::new()),
+        &CTVAvailable,
+    ) {
+        Ok(program) => program,
+        Err(e) => {
+            return (
+                Reaction::Error(ErrorEnvelope::new(ErrorCode::BindFailed, e.to_string())),
+                Usage::default(),
+            )
+        }
+    };
+    let created = Reaction::Created(c.amount_range.max(), a, program, c.all_diagnostics());
+    (created, Usage::of(&c))
+}
+
+/// `e` with any limit it exceeds of what was left of a batch's `budget` as
+/// the limit of the budget, see `compile_batch`
+fn of_budget(e: CompilationError, budget: &ResourceLimits) -> CompilationError {
+    match e {
+        CompilationError::At { path, inner } => CompilationError::At {
+            path,
+            inner: Box::new(of_budget(*inner, budget)),
+        },
+        CompilationError::BranchFailed(name, inner) => {
+            CompilationError::BranchFailed(name, Box::new(of_budget(*inner, budget)))
+        }
+        CompilationError::ResourceLimitExceeded { limit, path } => {
+            let limit = match limit {
+                ResourceLimit::Templates(_) => ResourceLimit::Templates(budget.max_templates),
+                ResourceLimit::Bytes(_) => ResourceLimit::Bytes(budget.max_bytes),
+                limit => limit,
+            };
+            CompilationError::ResourceLimitExceeded { limit, path }
+        }
+        e => e,
+    }
+}
+
+/// compile each of `requests` as a create would, together within the
+/// session's batch limits, reacting to each in order.
+///
+/// Each request is compiled within what is left of the batch's limits after
+/// those before it, and requests after the limits are used up aren't
+/// compiled. With parallel compilation requests are compiled as many at a
+/// time as there are threads, each within what was left before any of them,
+/// and then counted against the limits in order, so the same requests fail
+/// either way.
+fn compile_batch(
+    menu: &Menu,
+    contexts: &Contexts,
+    budget: ResourceLimits,
+    parallel: bool,
+    requests: Vec<CompileRequest>,
+    fail_fast: bool,
+) -> Reaction {
+    let width = if parallel {
+        std::thread::available_parallelism().map_or(1, |n| n.get())
+    } else {
+        1
+    };
+    let skipped = || {
+        Reaction::Error(ErrorEnvelope::new(
+            ErrorCode::Skipped,
+            "not compiled, as an earlier request in the batch failed",
+        ))
+    };
+    let exceeded = |limit| {
+        SessionError::Compiler(CompilationError::ResourceLimitExceeded {
+            limit,
+            path: contexts().path().as_ref().clone(),
+        })
+    };
+    let compile = |r: CompileRequest, left: ResourceLimits| {
+        let ctx = contexts();
+        let limits = ctx.resource_limits();
+        let ctx = ctx.with_resource_limits(ResourceLimits {
+            max_depth: limits.max_depth.min(left.max_depth),
+            max_templates: limits.max_templates.min(left.max_templates),
+            max_bytes: limits.max_bytes.min(left.max_bytes),
+        });
+        // a request which exceeds what was left of the budget exceeds the budget
+        react_to_compiled(menu.compile(r.type_, r.args, ctx).map_err(|e| match e {
+            SessionError::Compiler(e) => SessionError::Compiler(of_budget(e, &budget)),
+            e => e,
+        }))
+    };
+    let compile = &compile;
+    let mut used = Usage::default();
+    let mut failed = false;
+    let mut reactions = Vec::with_capacity(requests.len());
+    let mut requests = requests.into_iter().peekable();
+    while requests.peek().is_some() {
+        let chunk: Vec<_> = requests.by_ref().take(width).collect();
+        if failed && fail_fast {
+            reactions.extend(chunk.iter().map(|_| skipped()));
+            continue;
+        }
+        if let Some(limit) = used.spends(&budget) {
+            // nothing is left for the rest, which fail without compiling
+            for (i, _) in chunk.into_iter().chain(requests).enumerate() {
+                reactions.push(match fail_fast && i > 0 {
+                    true => skipped(),
+                    false => Reaction::Error(exceeded(limit).envelope()),
+                });
+            }
+            break;
+        }
+        let left = used.left_of(&budget);
+        let done: Vec<_> = if chunk.len() == 1 {
+            chunk.into_iter().map(|r| compile(r, left)).collect()
+        } else {
+            std::thread::scope(|s| {
+                let handles: Vec<_> = chunk
+                    .into_iter()
+                    .map(|r| s.spawn(move || compile(r, left)))
+                    .collect();
+                handles
+                    .into_iter()
+                    .map(|h| h.join().unwrap_or_else(|_| (panicked(), Usage::default())))
+                    .collect()
+            })
+        };
+        for (reaction, usage) in done {
+            if failed && fail_fast {
+                reactions.push(skipped());
+                continue;
+            }
+            let reaction = match (reaction, used.add(usage).exceeds(&budget)) {
+                (Reaction::Created(..), Some(limit)) => Reaction::Error(exceeded(limit).envelope()),
+                (reaction, _) => reaction,
+            };
+            match reaction {
+                Reaction::Created(..) => used = used.add(usage),
+                _ => failed = true,
+            }
+            reactions.push(reaction);
+        }
+    }
+    Reaction::Batch(reactions)
+}
+
 impl Action {
-    fn react(self, session: &mut Session) -> Option<Reaction> {
+    /// what the client must be allowed to make this request, if anything
+    fn capability(&self) -> Option<Capability> {
+        match self {
+            Action::Close
+            | Action::Handshake { .. }
+            | Action::Challenge
+            | Action::SessionId
+            | Action::Cancel { .. } => None,
+            Action::Create { .. }
+            | Action::Patch { .. }
+            | Action::CompileBatch { .. }
+            | Action::Resume { .. } => Some(Capability::Compile),
+            Action::ListModules | Action::GetSchema { .. } => Some(Capability::LoadModules),
+            Action::Save(_) | Action::Bind(..) => Some(Capability::Bind),
+        }
+    }
+    fn react(self, session: &mut Session, request_id: Option<String>) -> Option<Reaction> {
+        if let (Some(auth), Some(capability)) = (session.auth.as_ref(), self.capability()) {
+            if let Err(e) = auth.authorize(session.identity.as_ref(), capability, now()) {
+                return Some(Reaction::Error((&e).into()));
+            }
+        }
         match self {
             Action::Close => None,
+            Action::Handshake {
+                content_type,
+                credentials,
+            } => {
+                if let (Some(auth), Some(credentials)) = (session.auth.as_ref(), credentials) {
+                    match auth.authenticate(&credentials, &session.challenge, now()) {
+                        Ok(identity) => session.identity = Some(identity),
+                        Err(e) => {
+                            session.identity = None;
+                            return Some(Reaction::Error((&e).into()));
+                        }
+                    }
+                }
+                session.content_type = content_type;
+                Some(Reaction::Handshake(content_type))
+            }
+            Action::Challenge => Some(Reaction::Challenge(session.challenge)),
+            Action::SessionId => Some(Reaction::Session(false, session.id.clone())),
+            Action::Resume { session_id } => Some(match session.resume(&session_id) {
+                Ok(()) => Reaction::Session(true, session_id),
+                Err(e) => Reaction::Error(e.envelope()),
+            }),
             Action::Create { type_, args } => {
-                let c = session
-                    .menu
-                    .compile(type_, args, session.get_context())
-                    .ok()?;
-                let a = c.address.clone();
-                // todo amount
-                let program = c
-                    .bind_psbt(
-                        create_mock_output(),
-                        BTreeMap::new(),
-                        Rc::new(TxIndexLogger::new()),
-                        &CTVAvailable,
-                    )
-                    .ok()?;
-                println!("{:?}", program);
-                Some(Reaction::Created(c.amount_range.max(), a, program))
+                session.last_create = Some(CompileRequest {
+                    type_: type_.clone(),
+                    args: args.clone(),
+                });
+                session.last_created = None;
+                session.dirty = true;
+                let menu = session.menu;
+                Some(session.compile(request_id, move |contexts| {
+                    create(menu, type_, args, contexts()).0
+                }))
             }
             Action::Save(_address) => Some(Reaction::Saved(true)),
             Action::Bind(_out, _address) => Some(Reaction::Bound(vec![])),
+            Action::Patch { path, name, patch } => {
+                let args = session
+                    .effects
+                    .entry(path)
+                    .or_default()
+                    .entry(SArc(Arc::new(name)))
+                    .or_insert(Value::Null);
+                merge_patch(args, patch);
+                session.dirty = true;
+                Some(Reaction::Patched(args.clone()))
+            }
+            Action::ListModules => Some(match session.modules.as_mut() {
+                Some(modules) => match modules.list() {
+                    Ok(list) => Reaction::Modules(list),
+                    Err(e) => Reaction::Error(e.envelope()),
+                },
+                None => Reaction::Modules(ModuleList::default()),
+            }),
+            Action::GetSchema { module } => {
+                let schema = match session.modules.as_mut() {
+                    Some(modules) => modules
+                        .hash(&module)
+                        .and_then(|hash| Ok((modules.schema(&hash)?, hash))),
+                    None => Err(SessionError::UnknownModule(module)),
+                };
+                let schema = schema.map(|(schema, hash)| {
+                    session.dirty |= session.fetched_modules.insert(hash);
+                    schema
+                });
+                Some(match schema {
+                    Ok(schema) => Reaction::Schema(schema),
+                    Err(e) => Reaction::Error(e.envelope()),
+                })
+            }
+            Action::CompileBatch {
+                requests,
+                fail_fast,
+            } => {
+                let (menu, budget) = (session.menu, session.batch_limits);
+                let parallel = session.parallel_compilation;
+                Some(session.compile(request_id, move |contexts| {
+                    compile_batch(menu, contexts, budget, parallel, requests, fail_fast)
+                }))
+            }
+            Action::Cancel { request_id } => Some(match session.pending.remove(&request_id) {
+                Some(pending) => {
+                    pending.cancelled.store(true, Ordering::Relaxed);
+                    Reaction::Cancelled
+                }
+                None => Reaction::Error(
+                    ErrorEnvelope::new(
+                        ErrorCode::UnknownRequest,
+                        format!("no request `{}` is compiling", request_id),
+                    )
+                    .with_data(json!({ "request_id": request_id })),
+                ),
+            }),
         }
     }
 }
@@ -282,6 +818,69 @@ pub struct Session {
     example_msg: Option<String>,
     menu: &'static Menu,
     network: bitcoin::Network,
+    feerate: Option<Amount>,
+    tip_height: Option<AbsHeight>,
+    median_time: Option<AbsTime>,
+    effects: BTreeMap<SArc<EffectPath>, BTreeMap<SArc<String>, Value>>,
+    emulator: Arc<dyn CTVEmulator>,
+    content_type: ContentType,
+    parallel_compilation: bool,
+    batch_limits: ResourceLimits,
+    modules: Option<ModuleDirectory>,
+    auth: Option<AuthConfig>,
+    identity: Option<Identity>,
+    challenge: sha256::Hash,
+    timeout: Option<Duration>,
+    pending: BTreeMap<String, Pending>,
+    /// the threads compiling the session's requests, counted until they
+    /// exit, even once cancelled or timed out
+    running: Arc<AtomicUsize>,
+    id: String,
+    store: Option<(Arc<dyn SessionStore>, Duration)>,
+    fetched_modules: BTreeSet<String>,
+    last_create: Option<CompileRequest>,
+    last_created: Option<CreatedSummary>,
+    /// has the workspace changed since it was last saved?
+    dirty: bool,
+    /// why the workspace could last not be saved, to react with separately
+    store_failure: Option<SessionError>,
+    quotas: Quotas,
+    queue: Option<Arc<CompileQueue>>,
+    rates: Arc<RateLimiter>,
+}
+
+/// A request compiling in the background
+struct Pending {
+    /// set to stop compiling, see `Context::with_cancellation`
+    cancelled: Arc<AtomicBool>,
+    reaction: mpsc::Receiver<Reaction>,
+    /// when the request times out
+    deadline: Option<Instant>,
+}
+
+/// Counts a thread compiling a request in `Session::running` until it exits
+struct Running(Arc<AtomicUsize>);
+
+impl Running {
+    fn start(running: &Arc<AtomicUsize>) -> Running {
+        running.fetch_add(1, Ordering::Relaxed);
+        Running(running.clone())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// requests still compiling are no longer needed
+impl Drop for Session {
+    fn drop(&mut self) {
+        for pending in self.pending.values() {
+            pending.cancelled.store(true, Ordering::Relaxed);
+        }
+    }
 }
 
 /// Internal msg type to permit either strings or bytes
@@ -300,30 +899,456 @@ impl Session {
             example_msg: None,
             menu,
             network,
+            feerate: None,
+            tip_height: None,
+            median_time: None,
+            effects: BTreeMap::new(),
+            emulator: Arc::new(CTVAvailable),
+            content_type: ContentType::default(),
+            parallel_compilation: false,
+            batch_limits: ResourceLimits::sandboxed(),
+            modules: None,
+            auth: None,
+            identity: None,
+            challenge: sha256::Hash::from_inner(bitcoin::secp256k1::rand::random()),
+            timeout: None,
+            pending: BTreeMap::new(),
+            running: Arc::new(AtomicUsize::new(0)),
+            id: sha256::Hash::from_inner(bitcoin::secp256k1::rand::random()).to_string(),
+            store: None,
+            fetched_modules: BTreeSet::new(),
+            last_create: None,
+            last_created: None,
+            dirty: false,
+            store_failure: None,
+            quotas: Quotas::default(),
+            queue: None,
+            rates: Arc::new(RateLimiter::default()),
+        }
+    }
+    /// set the emulator contracts compile against, e.g. a `LocalEmulator`
+    /// for regtest development, instead of assuming CTV is available
+    pub fn set_emulator(&mut self, emulator: Arc<dyn CTVEmulator>) {
+        self.emulator = emulator;
+    }
+    /// set the emulator contracts compile against from a server's config,
+    /// for the session's network
+    pub fn set_emulator_config(&mut self, config: &EmulatorConfig) -> Result<(), EmulatorError> {
+        self.emulator = config.emulator(self.network)?;
+        Ok(())
+    }
+    /// set the feerate, in sats per vbyte, contracts should pay at
+    pub fn set_feerate(&mut self, feerate: Option<Amount>) {
+        self.feerate = feerate;
+    }
+    /// set the chain tip, e.g. as reported by bitcoind, that contracts
+    /// compile against
+    pub fn set_chain_tip(&mut self, tip_height: Option<AbsHeight>, median_time: Option<AbsTime>) {
+        self.tip_height = tip_height;
+        self.median_time = median_time;
+    }
+    /// compile contracts' scripts in parallel, see
+    /// `Context::with_parallel_script_compilation`, and the requests of a batch
+    /// concurrently
+    pub fn set_parallel_compilation(&mut self, enabled: bool) {
+        self.parallel_compilation = enabled;
+    }
+    /// set the limits on what the contracts of a batch may use in total,
+    /// `ResourceLimits::sandboxed()` by default. Each is also within the
+    /// limits of a single contract.
+    pub fn set_batch_limits(&mut self, limits: ResourceLimits) {
+        self.batch_limits = limits;
+    }
+    /// set the directory of modules the client can list, e.g. a
+    /// `WasmModuleLoader` over a plugin cache
+    pub fn set_module_directory(&mut self, modules: ModuleDirectory) {
+        self.modules = Some(modules);
+    }
+    /// require clients to authenticate in their handshake with credentials
+    /// accepted by `auth`, and allow each action only with a credential
+    /// granting it. The config is checked again for each action, so setting
+    /// it again applies to a client already authenticated. Without one, as
+    /// by default, every client may do anything.
+    pub fn set_auth(&mut self, auth: Option<AuthConfig>) {
+        self.auth = auth;
+    }
+    /// stop compiling a request after `timeout`, reacting with
+    /// `Reaction::TimedOut`. Without one, as by default, a request without
+    /// an id is compiled as it is handled.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    /// save the session's workspace to `store` as it changes, for a client
+    /// to resume after reconnecting, e.g. to a restarted server, unless it
+    /// goes unchanged for longer than `ttl`. The credentials a client
+    /// authenticated with are not saved, so it must authenticate again
+    /// before resuming, as the same identity it saved the session as.
+    ///
+    /// A save which fails is tried again after the next message, and
+    /// reacted to with `ErrorCode::StoreFailed` among the `completed`
+    /// reactions, leaving the reaction to the request which changed the
+    /// workspace as it was.
+    pub fn set_store(&mut self, store: Arc<dyn SessionStore>, ttl: Duration) {
+        self.store = Some((store, ttl));
+        self.dirty = true;
+    }
+    /// limit what the session's client may use, refusing requests beyond
+    /// its quotas with `ErrorCode::Throttled`. By default any number of
+    /// requests are compiled, each within `ResourceLimits::sandboxed()`.
+    pub fn set_quotas(&mut self, quotas: Quotas) {
+        self.quotas = quotas;
+    }
+    /// compile the session's requests in turn with those of the other
+    /// sessions sharing `queue`, refusing them while it is full
+    pub fn set_compile_queue(&mut self, queue: Arc<CompileQueue>) {
+        self.queue = Some(queue);
+    }
+    /// count the session's messages against `Quotas::requests_per_minute`
+    /// with those of the other sessions sharing `rates` which authenticated
+    /// as the same identity. Otherwise, and before it authenticates, a
+    /// session's messages are counted on their own.
+    pub fn set_rate_limiter(&mut self, rates: Arc<RateLimiter>) {
+        self.rates = rates;
+    }
+    /// who the session's requests are from, for the quotas it shares
+    fn client(&self) -> Client {
+        match &self.identity {
+            Some(identity) => Client::Identity(identity.clone()),
+            None => Client::Session(self.id.clone()),
+        }
+    }
+    /// the id the session is saved as, see `set_store`
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+    /// save the workspace to the session's store, if it has one and the
+    /// workspace changed since it was last saved. Done after each message
+    /// handled, and to be done after reacting to `completed` requests.
+    pub fn persist(&mut self) -> Result<(), SessionError> {
+        let store = match &self.store {
+            Some((store, _)) if self.dirty => store,
+            _ => return Ok(()),
+        };
+        let saved = SavedSession {
+            saved_at: now(),
+            owner: self.identity.clone(),
+            effects: self.effects.clone(),
+            modules: self.fetched_modules.clone(),
+            last_create: self.last_create.clone(),
+            last_created: self.last_created.clone(),
+        };
+        store.save(&self.id, &saved).map_err(SessionError::Store)?;
+        self.dirty = false;
+        Ok(())
+    }
+    /// restore the workspace saved as `id`, and save this session as it
+    /// from now on. Only the identity the session was saved by may resume
+    /// it, and every module the saved session used must still be in the
+    /// module directory, or else nothing is restored.
+    fn resume(&mut self, id: &str) -> Result<(), SessionError> {
+        let (store, ttl) = self
+            .store
+            .clone()
+            .ok_or_else(|| SessionError::UnknownSession(id.into()))?;
+        let now = now();
+        let before = now.saturating_sub(ttl.as_secs());
+        let saved = store
+            .load(id)
+            .map_err(SessionError::Store)?
+            .filter(|saved| saved.owner == self.identity)
+            .ok_or_else(|| SessionError::UnknownSession(id.into()))?;
+        if saved.saved_at < before {
+            store.remove(id).map_err(SessionError::Store)?;
+            return Err(SessionError::SessionExpired(id.into()));
+        }
+        store.expire(before).map_err(SessionError::Store)?;
+        for module in &saved.modules {
+            match self.modules.as_mut() {
+                Some(modules) => modules.hash(module)?,
+                None => return Err(SessionError::UnknownModule(module.clone())),
+            };
+        }
+        if let Some(create) = &saved.last_create {
+            if !self.menu.internal_menu.contains_key(&create.type_) {
+                return Err(SessionError::ContractNotRegistered);
+            }
+        }
+        if self.id != id {
+            store.remove(&self.id).map_err(SessionError::Store)?;
+            self.id = id.into();
+        }
+        self.effects = saved.effects;
+        self.fetched_modules = saved.modules;
+        self.last_create = saved.last_create;
+        self.last_created = saved.last_created;
+        self.dirty = true;
+        Ok(())
+    }
+    /// note what `reaction` created, to save with the workspace
+    fn record(&mut self, reaction: &Reaction) {
+        if let Reaction::Created(amount, address, _, diagnostics) = reaction {
+            self.last_created = Some(CreatedSummary {
+                amount: *amount,
+                address: address.clone(),
+                diagnostics: diagnostics.clone(),
+            });
+            self.dirty = true;
         }
     }
     /// get a context for this session
     /// TODO: link to a bitcoin node or something to determine available funds
-    /// TODO: use an emulator if desired?
     pub fn get_context(&self) -> Context {
-        // Todo: Make Create specify the amount to send.
-        Context::new(
-            self.network,
-            Amount::from_sat(100_000_000_000),
-            Arc::new(CTVAvailable),
-            "frontend_session".try_into().unwrap(),
-            Arc::new(MapEffectDB::default()),
-        )
+        self.contexts(None)()
+    }
+    /// makes contexts like `get_context`, to compile in on another thread,
+    /// which stop compiling once `cancelled` is set
+    fn contexts(&self, cancelled: Option<Arc<AtomicBool>>) -> impl Fn() -> Context + Send + Sync {
+        let network = self.network;
+        let emulator = self.emulator.clone();
+        let effects = Arc::new(MapEffectDB::from(EditableMapEffectDB {
+            effects: self.effects.clone(),
+            empty: Default::default(),
+        }));
+        let (feerate, tip_height, median_time) = (self.feerate, self.tip_height, self.median_time);
+        let parallel = self.parallel_compilation;
+        let limits = self.quotas.limits;
+        move || {
+            // Todo: Make Create specify the amount to send.
+            let ctx = Context::new(
+                network,
+                Amount::from_sat(100_000_000_000),
+                emulator.clone(),
+                "frontend_session".try_into().unwrap(),
+                effects.clone(),
+            )
+            .with_feerate(feerate)
+            .with_chain_tip(tip_height, median_time)
+            .with_resource_limits(limits);
+            let ctx = match cancelled.as_ref() {
+                Some(cancelled) => ctx.with_cancellation(cancelled.clone()),
+                None => ctx,
+            };
+            if parallel {
+                ctx.with_parallel_script_compilation()
+            } else {
+                ctx
+            }
+        }
+    }
+    /// react to a request by compiling with `job`: in the background if the
+    /// request has an id, to react to with `completed`, and otherwise here,
+    /// on another thread to stop at the timeout if there is one. With a
+    /// compile queue the job first waits for its turn.
+    fn compile<F>(&mut self, request_id: Option<String>, job: F) -> Reaction
+    where
+        F: FnOnce(&Contexts) -> Reaction + Send + 'static,
+    {
+        let throttled = |quota| {
+            Reaction::Error(
+                SessionError::Throttled {
+                    quota,
+                    retry_after: BUSY_RETRY_AFTER,
+                }
+                .envelope(),
+            )
+        };
+        // a request cancelled or timed out still counts until its thread
+        // stops compiling
+        if self.running.load(Ordering::Relaxed) >= self.quotas.max_concurrent {
+            return throttled(Quota::ConcurrentCompiles);
+        }
+        let turn = match self.queue.as_ref().map(|q| q.enqueue(self.client())) {
+            Some(None) => return throttled(Quota::ServerBusy),
+            Some(Some(turn)) => Some(turn),
+            None => None,
+        };
+        if request_id.is_none() && self.timeout.is_none() {
+            // held until the job is done, as is the slot it waited for
+            let _turn = turn.map(|mut turn| {
+                turn.wait(&AtomicBool::new(false));
+                turn
+            });
+            return job(&self.contexts(None));
+        }
+        if let Some(id) = request_id
+            .as_ref()
+            .filter(|id| self.pending.contains_key(*id))
+        {
+            return Reaction::Error(
+                ErrorEnvelope::new(
+                    ErrorCode::DuplicateRequest,
+                    format!("request `{}` is already compiling", id),
+                )
+                .with_data(json!({ "request_id": id })),
+            );
+        }
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let contexts = self.contexts(Some(cancelled.clone()));
+        let (sender, reaction) = mpsc::channel();
+        let stop = cancelled.clone();
+        let running = Running::start(&self.running);
+        std::thread::spawn(move || {
+            // held until the job is done, as is the slot it waited for
+            let mut turn = turn;
+            if turn.as_mut().is_some_and(|t| !t.wait(&stop)) {
+                // cancelled before its turn, and already reacted to
+                return;
+            }
+            let reaction = job(&contexts);
+            // freed before the reaction is seen, so the client may go again
+            drop((turn, running));
+            // the request may have been cancelled, and its reaction unwanted
+            let _ = sender.send(reaction);
+        });
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let pending = Pending {
+            cancelled,
+            reaction,
+            deadline,
+        };
+        match request_id {
+            Some(id) => {
+                self.pending.insert(id, pending);
+                Reaction::Started
+            }
+            None => {
+                let reaction = match deadline {
+                    Some(deadline) => pending
+                        .reaction
+                        .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                    None => pending.reaction.recv().map_err(RecvTimeoutError::from),
+                };
+                match reaction {
+                    Ok(reaction) => reaction,
+                    Err(RecvTimeoutError::Timeout) => {
+                        pending.cancelled.store(true, Ordering::Relaxed);
+                        Reaction::TimedOut
+                    }
+                    Err(RecvTimeoutError::Disconnected) => panicked(),
+                }
+            }
+        }
+    }
+    /// The reactions to the requests compiling in the background which are
+    /// done or have timed out, for the networking stack to send after each
+    /// message it handles and periodically while any are compiling
+    pub fn completed(&mut self) -> Vec<Response> {
+        let now = Instant::now();
+        let mut done = vec![];
+        self.pending.retain(|id, pending| {
+            let reaction = match pending.reaction.try_recv() {
+                Ok(reaction) => reaction,
+                Err(TryRecvError::Disconnected) => panicked(),
+                Err(TryRecvError::Empty) if pending.deadline.is_some_and(|d| now >= d) => {
+                    pending.cancelled.store(true, Ordering::Relaxed);
+                    Reaction::TimedOut
+                }
+                Err(TryRecvError::Empty) => return true,
+            };
+            done.push(Response {
+                request_id: Some(id.clone()),
+                reaction,
+            });
+            false
+        });
+        for response in &done {
+            self.record(&response.reaction);
+        }
+        if let Some(e) = self.store_failure.take() {
+            done.push(Response {
+                request_id: None,
+                reaction: Reaction::Error(e.envelope()),
+            });
+        }
+        done
+    }
+    /// are any requests compiling in the background?
+    pub fn is_compiling(&self) -> bool {
+        !self.pending.is_empty()
     }
 
     /// process a message from the Session manager (e.g., networking stack)
     /// and react to it.
-    pub fn handle(&mut self, m: Msg<'_>) -> Result<Option<Reaction>, serde_json::Error> {
-        let action: Action = match m {
-            Msg::Text(m) => serde_json::from_str(m),
-            Msg::Bytes(m) => serde_json::from_slice(m),
-        }?;
-        Ok(action.react(self))
+    ///
+    /// Text messages are always JSON, and bytes are in the content type
+    /// agreed to by the last handshake, JSON if none.
+    pub fn handle(&mut self, m: Msg<'_>) -> Result<Option<Reaction>, SessionError> {
+        Ok(self.handle_request(m)?.map(|r| r.reaction))
+    }
+
+    /// process a message as `handle` does, with the id of the request the
+    /// reaction is to, if it had one. The reaction to cancelling a request
+    /// has the id of the request cancelled, and to a message which can't be
+    /// read an `ErrorCode::InvalidMessage` error.
+    pub fn handle_request(&mut self, m: Msg<'_>) -> Result<Option<Response>, SessionError> {
+        let request: Result<Request, SessionError> = match (m, self.content_type) {
+            (Msg::Text(m), _) => serde_json::from_str(m).map_err(SessionError::from),
+            (Msg::Bytes(m), ContentType::Json) => serde_json::from_slice(m).map_err(Into::into),
+            #[cfg(feature = "cbor")]
+            (Msg::Bytes(m), ContentType::Cbor) => cbor::from_slice(m).map_err(Into::into),
+        };
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                return Ok(Some(Response {
+                    request_id: None,
+                    reaction: Reaction::Error(e.envelope()),
+                }))
+            }
+        };
+        if !matches!(request.action, Action::Close) {
+            let now = Instant::now();
+            let admitted = self
+                .rates
+                .admit(&self.client(), self.quotas.requests_per_minute, now);
+            if let Err(retry_after) = admitted {
+                let error = SessionError::Throttled {
+                    quota: Quota::RequestsPerMinute,
+                    retry_after,
+                };
+                return Ok(Some(Response {
+                    request_id: request.request_id,
+                    reaction: Reaction::Error(error.envelope()),
+                }));
+            }
+        }
+        let request_id = match &request.action {
+            Action::Cancel { request_id } if self.pending.contains_key(request_id) => {
+                Some(request_id.clone())
+            }
+            _ => request.request_id.clone(),
+        };
+        let reaction = request.action.react(self, request.request_id);
+        if let Some(reaction) = &reaction {
+            self.record(reaction);
+        }
+        if let Err(e) = self.persist() {
+            self.store_failure = Some(e);
+        }
+        Ok(reaction.map(|reaction| Response {
+            request_id,
+            reaction,
+        }))
+    }
+
+    /// encode `reaction` to send to the client, in the content type agreed
+    /// to by the last handshake. The reaction to a handshake is JSON.
+    pub fn encode(&self, reaction: &Reaction) -> Result<Vec<u8>, SessionError> {
+        self.encode_as(matches!(reaction, Reaction::Handshake(_)), reaction)
+    }
+
+    /// encode `response` as `encode` does its reaction
+    pub fn encode_response(&self, response: &Response) -> Result<Vec<u8>, SessionError> {
+        let handshake = matches!(response.reaction, Reaction::Handshake(_));
+        self.encode_as(handshake, response)
+    }
+
+    fn encode_as<T: Serialize>(&self, handshake: bool, value: &T) -> Result<Vec<u8>, SessionError> {
+        match (handshake, self.content_type) {
+            (true, _) | (_, ContentType::Json) => Ok(serde_json::to_vec(value)?),
+            #[cfg(feature = "cbor")]
+            (_, ContentType::Cbor) => Ok(cbor::to_vec(value)?),
+        }
     }
 
     /// returns the precompiled menu
@@ -331,3 +1356,955 @@ impl Session {
         &self.menu.menu
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{KeyPair, XOnlyPublicKey};
+    use sapio::contract::Contract;
+    use sapio::*;
+
+    /// pays a key through a chain of `depth` templates
+    #[derive(JsonSchema, Deserialize)]
+    struct Chain {
+        depth: u8,
+    }
+    impl Chain {
+        #[then]
+        fn next(self, ctx: Context) {
+            let amount = ctx.funds();
+            if self.depth > 1 {
+                let next = Chain {
+                    depth: self.depth - 1,
+                };
+                ctx.template().add_output(amount, &next, None)?.into()
+            } else {
+                let kp = KeyPair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+                let key = XOnlyPublicKey::from_keypair(&kp).0;
+                ctx.template().add_output(amount, &key, None)?.into()
+            }
+        }
+    }
+    impl Contract for Chain {
+        declare! {then, Self::next}
+        declare! {non updatable}
+    }
+
+    fn chain_session() -> Session {
+        let mut menu = MenuBuilder::new();
+        menu.register_as::<Chain>(Some("chain".into()));
+        let menu: &'static Menu = Box::leak(Box::new(menu.into()));
+        Session::new(menu, bitcoin::Network::Regtest)
+    }
+    fn batch(session: &mut Session, requests: Value, fail_fast: bool) -> Vec<Value> {
+        let msg = json!({"action": "compile_batch", "content": {"requests": requests, "fail_fast": fail_fast}})
+            .to_string();
+        let reaction = serde_json::to_value(session.handle(Msg::Text(&msg)).unwrap()).unwrap();
+        assert_eq!(reaction["action"], "batch");
+        reaction["content"].as_array().unwrap().clone()
+    }
+    /// the action of each reaction, and the code of errors
+    fn outcomes(reactions: &[Value]) -> Vec<String> {
+        reactions
+            .iter()
+            .map(|r| match r["content"]["code"].as_str() {
+                Some(code) => code.to_string(),
+                None => r["action"].as_str().unwrap().to_string(),
+            })
+            .collect()
+    }
+    #[test]
+    fn configured_emulator() {
+        use emulator_connect::connections::local::LocalEmulator;
+        let script = |session: Session| {
+            let compiled = session.get_context().compile(Chain { depth: 1 }).unwrap();
+            bitcoin::Script::from(compiled.address)
+        };
+        let with = |emulator: Arc<dyn CTVEmulator>| {
+            let mut session = chain_session();
+            session.set_emulator(emulator);
+            script(session)
+        };
+        let mut session = chain_session();
+        let seed = bitcoin::hashes::hex::ToHex::to_hex(&LocalEmulator::TEST_SEED[..]);
+        session
+            .set_emulator_config(&EmulatorConfig::DevSeed(seed))
+            .unwrap();
+        let configured = script(session);
+        assert_eq!(configured, with(Arc::new(LocalEmulator::for_tests())));
+        assert_ne!(configured, with(Arc::new(CTVAvailable)));
+    }
+    #[test]
+    fn batches_react_in_order() {
+        let requests = json!([
+            {"type": "chain", "args": {"depth": 3}},
+            {"type": "chain", "args": {"bogus": 1}},
+            {"type": "missing", "args": {}},
+            {"type": "chain", "args": {"depth": 1}},
+        ]);
+        let mut session = chain_session();
+        let reactions = batch(&mut session, requests.clone(), false);
+        assert_eq!(
+            outcomes(&reactions),
+            [
+                "created",
+                "schema_error",
+                "contract_not_registered",
+                "created"
+            ]
+        );
+        // each is what it would be if created alone
+        let create = json!({"action": "create", "content": requests[0]}).to_string();
+        let created = serde_json::to_value(session.handle(Msg::Text(&create)).unwrap()).unwrap();
+        assert_eq!(reactions[0], created);
+        assert_ne!(reactions[0], reactions[3]);
+
+        let skipped = batch(&mut session, requests.clone(), true);
+        assert_eq!(
+            outcomes(&skipped),
+            ["created", "schema_error", "skipped", "skipped"]
+        );
+        assert_eq!(skipped[..2], reactions[..2]);
+        assert!(skipped[3]["content"]["message"]
+            .as_str()
+            .unwrap()
+            .contains("earlier request"));
+
+        session.set_parallel_compilation(true);
+        assert_eq!(batch(&mut session, requests.clone(), false), reactions);
+        assert_eq!(batch(&mut session, requests, true), skipped);
+    }
+    /// a fixture plugin: its `LoadedModule` as JSON in a file
+    struct FixtureLoader(std::sync::atomic::AtomicUsize);
+    impl crate::modules::ModuleLoader for FixtureLoader {
+        fn load(
+            &self,
+            file: &std::path::Path,
+        ) -> Result<crate::modules::LoadedModule, Box<dyn std::error::Error>> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+            Ok(serde_json::from_slice(&std::fs::read(file)?)?)
+        }
+    }
+    #[test]
+    fn lists_modules() {
+        use crate::modules::*;
+        use sapio::sapio_base::plugin_args::CreateArgs;
+        let dir = std::env::temp_dir().join(format!("sapio-front-modules-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let minter = LoadedModule {
+            name: "minter".into(),
+            hash: "00".repeat(32),
+            schema: schemars::schema_for!(CreateArgs<Chain>),
+            version: Some("1.2.0".into()),
+            traits: vec!["Mint_NFT_Trait_Version_0_1_0".into()],
+        };
+        let mut large = schemars::schema_for!(CreateArgs<Chain>);
+        large.schema.metadata().description = Some("x".repeat(INLINE_SCHEMA_BYTES));
+        let chain = LoadedModule {
+            name: "chain".into(),
+            hash: "11".repeat(32),
+            schema: large.clone(),
+            version: None,
+            traits: vec![],
+        };
+        for (file, module) in [("a", &minter), ("b", &chain)] {
+            std::fs::write(dir.join(file), serde_json::to_vec(module).unwrap()).unwrap();
+        }
+        std::fs::write(dir.join("c"), b"not a module").unwrap();
+        let loader = Arc::new(FixtureLoader(Default::default()));
+        let mut session = chain_session();
+        session.set_module_directory(ModuleDirectory::new(&dir, loader.clone()));
+        let mut handle = |msg: Value| {
+            let reaction = session.handle(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(reaction).unwrap()
+        };
+
+        let list = handle(json!({"action": "list_modules"}));
+        assert_eq!(list["action"], "modules");
+        let modules = &list["content"]["modules"];
+        assert_eq!(modules[0]["name"], "minter");
+        assert_eq!(
+            modules[0]["traits"],
+            json!([{"name": "Mint_NFT_Trait_Version_0_1_0", "version": "0.1.0"}])
+        );
+        assert_eq!(modules[0]["version"], "1.2.0");
+        assert_eq!(
+            modules[0]["schema"],
+            serde_json::to_value(&minter.schema).unwrap()
+        );
+        assert_eq!(modules[1]["hash"], chain.hash);
+        assert_eq!(modules[1]["traits"], json!([]));
+        assert_eq!(modules[1]["version"], Value::Null);
+        // too large to list, but can be fetched
+        assert_eq!(modules[1]["schema"], Value::Null);
+        assert!(list["content"]["failed"]["c"].is_string());
+        let schema = handle(json!({"action": "get_schema", "content": {"module": "chain"}}));
+        assert_eq!(schema["action"], "schema");
+        assert_eq!(schema["content"], serde_json::to_value(&large).unwrap());
+        let missing = handle(json!({"action": "get_schema", "content": {"module": "nope"}}));
+        assert_eq!(missing["action"], "error");
+
+        // cached until the directory changes
+        let loads = loader.0.load(std::sync::atomic::Ordering::Relaxed);
+        assert_eq!(handle(json!({"action": "list_modules"})), list);
+        assert_eq!(loader.0.load(std::sync::atomic::Ordering::Relaxed), loads);
+        std::fs::remove_file(dir.join("c")).unwrap();
+        let list = handle(json!({"action": "list_modules"}));
+        assert!(loader.0.load(std::sync::atomic::Ordering::Relaxed) > loads);
+        assert_eq!(list["content"]["failed"], json!({}));
+        assert_eq!(list["content"]["modules"].as_array().unwrap().len(), 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn resumes_saved_sessions() {
+        use crate::modules::*;
+        use crate::persist::*;
+        let dir = std::env::temp_dir().join(format!("sapio-front-resume-{}", std::process::id()));
+        let modules = dir.join("modules");
+        std::fs::create_dir_all(&modules).unwrap();
+        let chain = LoadedModule {
+            name: "chain".into(),
+            hash: "11".repeat(32),
+            schema: schemars::schema_for!(Chain),
+            version: None,
+            traits: vec![],
+        };
+        std::fs::write(modules.join("a"), serde_json::to_vec(&chain).unwrap()).unwrap();
+        let store: Arc<dyn SessionStore> =
+            Arc::new(DirectoryStore::new(dir.join("sessions")).unwrap());
+        let ttl = Duration::from_secs(3600);
+        // a server's session, with its module directory and store
+        let start = || {
+            let mut session = chain_session();
+            let loader = Arc::new(FixtureLoader(Default::default()));
+            session.set_module_directory(ModuleDirectory::new(&modules, loader));
+            session.set_store(store.clone(), ttl);
+            session
+        };
+        let handle = |session: &mut Session, msg: Value| {
+            let reaction = session.handle(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(reaction).unwrap()
+        };
+        let resume = |id: &str| json!({"action": "resume", "content": {"session_id": id}});
+
+        let mut session = start();
+        let id = handle(&mut session, json!({"action": "session_id"}));
+        assert_eq!(id["content"][0], false);
+        let id = id["content"][1].as_str().unwrap().to_string();
+        let patch = json!({"action": "patch", "content": {"path": "frontend_session", "name": "sell", "patch": 1}});
+        handle(&mut session, patch);
+        let schema = json!({"action": "get_schema", "content": {"module": "chain"}});
+        assert_eq!(handle(&mut session, schema)["action"], "schema");
+        let create =
+            json!({"action": "create", "content": {"type": "chain", "args": {"depth": 2}}});
+        let created = handle(&mut session, create);
+        assert_eq!(created["action"], "created");
+        let effects = session.effects.clone();
+        drop(session);
+
+        // after a restart
+        let mut session = start();
+        let fresh = session.id().to_string();
+        handle(&mut session, json!({"action": "session_id"}));
+        assert!(store.load(&fresh).unwrap().is_some());
+        assert_eq!(
+            handle(&mut session, resume(&id)),
+            json!({"action": "session_id", "content": [true, id]})
+        );
+        assert_eq!(session.id(), id);
+        assert_eq!(session.effects, effects);
+        assert!(store.load(&fresh).unwrap().is_none());
+        let saved = store.load(&id).unwrap().unwrap();
+        assert_eq!(saved.modules, BTreeSet::from([chain.hash.clone()]));
+        assert_eq!(saved.last_create.unwrap().args, json!({"depth": 2}));
+        assert_eq!(
+            serde_json::to_value(saved.last_created.unwrap().address).unwrap(),
+            created["content"][1]
+        );
+
+        // the module it used is gone
+        std::fs::remove_file(modules.join("a")).unwrap();
+        let mut session = start();
+        let missing = handle(&mut session, resume(&id));
+        assert_eq!(missing["content"]["code"], "unknown_module");
+        assert_eq!(missing["content"]["data"]["module"], chain.hash);
+        assert!(session.effects.is_empty());
+        assert_ne!(session.id(), id);
+
+        let unknown = handle(&mut session, resume("00"));
+        assert_eq!(unknown["content"]["code"], "unknown_session");
+        let mut stale = store.load(&id).unwrap().unwrap();
+        stale.saved_at = 1;
+        store.save(&id, &stale).unwrap();
+        let expired = handle(&mut session, resume(&id));
+        assert_eq!(expired["content"]["code"], "session_expired");
+        assert!(store.load(&id).unwrap().is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+    #[test]
+    fn only_the_owner_resumes() {
+        use crate::auth::Grant;
+        use crate::persist::*;
+        let grant = Grant {
+            capabilities: BTreeSet::from([Capability::Compile]),
+            expires: None,
+        };
+        let mut auth = AuthConfig::default();
+        for token in ["alice", "mallory"] {
+            auth.tokens
+                .insert(sha256::Hash::hash(token.as_bytes()), grant.clone());
+        }
+        let store: Arc<dyn SessionStore> = Arc::new(MemoryStore::default());
+        let start = |token: &str| {
+            let mut session = chain_session();
+            session.set_auth(Some(auth.clone()));
+            session.set_store(store.clone(), Duration::from_secs(3600));
+            let handshake = json!({"action": "handshake", "content": {"content_type": "application/json", "credentials": {"bearer": token}}});
+            session.handle(Msg::Text(&handshake.to_string())).unwrap();
+            session
+        };
+        let handle = |session: &mut Session, msg: Value| {
+            let reaction = session.handle(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(reaction).unwrap()
+        };
+        let resume = |id: &str| json!({"action": "resume", "content": {"session_id": id}});
+        let patch = json!({"action": "patch", "content": {"path": "frontend_session", "name": "sell", "patch": 1}});
+
+        let mut alice = start("alice");
+        handle(&mut alice, patch);
+        let id = alice.id().to_string();
+        drop(alice);
+        let mut mallory = start("mallory");
+        let refused = handle(&mut mallory, resume(&id));
+        assert_eq!(refused["content"]["code"], "unknown_session");
+        assert!(mallory.effects.is_empty());
+        let mut alice = start("alice");
+        assert_eq!(handle(&mut alice, resume(&id))["content"][0], true);
+        assert!(!alice.effects.is_empty());
+    }
+    /// a store which can't be written to
+    struct FullStore;
+    impl crate::persist::SessionStore for FullStore {
+        fn save(&self, _: &str, _: &crate::persist::SavedSession) -> std::io::Result<()> {
+            Err(std::io::Error::other("disk full"))
+        }
+        fn load(&self, _: &str) -> std::io::Result<Option<crate::persist::SavedSession>> {
+            Ok(None)
+        }
+        fn remove(&self, _: &str) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn expire(&self, _: u64) -> std::io::Result<usize> {
+            Ok(0)
+        }
+    }
+    #[test]
+    fn store_failures_are_reported_separately() {
+        let mut session = chain_session();
+        session.set_store(Arc::new(FullStore), Duration::from_secs(3600));
+        let create =
+            json!({"action": "create", "content": {"type": "chain", "args": {"depth": 1}}});
+        let reaction = session.handle(Msg::Text(&create.to_string())).unwrap();
+        assert!(matches!(reaction, Some(Reaction::Created(..))));
+        let failures = session.completed();
+        assert_eq!(failures.len(), 1);
+        assert!(
+            matches!(&failures[0].reaction, Reaction::Error(e) if e.code == ErrorCode::StoreFailed)
+        );
+        assert!(session.completed().is_empty());
+    }
+    #[test]
+    fn authenticated_capabilities() {
+        use crate::auth::{challenge_message, Grant};
+        let grant = |capabilities: &[Capability], expires| Grant {
+            capabilities: capabilities.iter().copied().collect(),
+            expires,
+        };
+        let token = |t: &str| sha256::Hash::hash(t.as_bytes());
+        let secp = Secp256k1::new();
+        let kp = KeyPair::from_seckey_slice(&secp, &[7; 32]).unwrap();
+        let key = XOnlyPublicKey::from_keypair(&kp).0;
+        let all = [
+            Capability::Compile,
+            Capability::LoadModules,
+            Capability::Bind,
+        ];
+        let mut auth = AuthConfig::default();
+        auth.tokens.insert(token("admin"), grant(&all, None));
+        auth.tokens
+            .insert(token("compiler"), grant(&[Capability::Compile], None));
+        auth.tokens.insert(token("old"), grant(&all, Some(1)));
+        auth.keys
+            .insert(key, grant(&[Capability::LoadModules], Some(now() + 3600)));
+        let mut session = chain_session();
+        session.set_auth(Some(auth.clone()));
+        let handle = |session: &mut Session, msg: Value| {
+            let reaction = session.handle(Msg::Text(&msg.to_string())).unwrap();
+            let mut reaction = serde_json::to_value(reaction).unwrap();
+            // errors are told apart by their codes, not their messages
+            if reaction["action"] == "error" {
+                reaction["content"]
+                    .as_object_mut()
+                    .unwrap()
+                    .remove("message");
+            }
+            reaction
+        };
+        let handshake = |credentials: Value| json!({"action": "handshake", "content": {"content_type": "application/json", "credentials": credentials}});
+        let address = bitcoin::Address::p2tr(&secp, key, None, bitcoin::Network::Regtest);
+        let save = json!({"action": "save", "content": address.to_string()});
+        let patch = json!({"action": "patch", "content": {"path": "frontend_session", "name": "sell", "patch": 1}});
+        let unauthorized = |code: &str, data: Value| json!({"action": "error", "content": {"code": code, "path": null, "data": data}});
+
+        // refused, but the session carries on
+        assert_eq!(
+            handle(&mut session, patch.clone()),
+            unauthorized("unauthenticated", json!({"reason": "unauthenticated"}))
+        );
+        assert_eq!(
+            handle(&mut session, handshake(json!({"bearer": "old"}))),
+            unauthorized(
+                "credentials_expired",
+                json!({"reason": "expired", "content": 1})
+            )
+        );
+        assert_eq!(
+            handle(&mut session, handshake(json!({"bearer": "unknown"}))),
+            unauthorized(
+                "invalid_credentials",
+                json!({"reason": "invalid_credentials"})
+            )
+        );
+        assert_eq!(
+            handle(&mut session, patch.clone()),
+            unauthorized("unauthenticated", json!({"reason": "unauthenticated"}))
+        );
+
+        assert_eq!(
+            handle(&mut session, handshake(json!({"bearer": "admin"})))["action"],
+            "handshake"
+        );
+        assert_eq!(handle(&mut session, save.clone())["action"], "saved");
+        // downgraded by a handshake with a weaker token
+        handle(&mut session, handshake(json!({"bearer": "compiler"})));
+        assert_eq!(handle(&mut session, patch.clone())["action"], "patched");
+        assert_eq!(
+            handle(&mut session, save.clone()),
+            unauthorized(
+                "missing_capability",
+                json!({"reason": "missing_capability", "content": "bind"})
+            )
+        );
+        // and by the server changing the grant of a token in use
+        handle(&mut session, handshake(json!({"bearer": "admin"})));
+        auth.tokens
+            .insert(token("admin"), grant(&[Capability::Bind], None));
+        session.set_auth(Some(auth.clone()));
+        assert_eq!(handle(&mut session, save.clone())["action"], "saved");
+        assert_eq!(
+            handle(&mut session, patch.clone())["content"]["code"],
+            "missing_capability"
+        );
+        auth.tokens.remove(&token("admin"));
+        session.set_auth(Some(auth));
+        assert_eq!(
+            handle(&mut session, save),
+            unauthorized(
+                "invalid_credentials",
+                json!({"reason": "invalid_credentials"})
+            )
+        );
+
+        // a key signing the session's challenge
+        let challenge = handle(&mut session, json!({"action": "challenge"}));
+        let challenge: sha256::Hash = serde_json::from_value(challenge["content"].clone()).unwrap();
+        let signed = |challenge| {
+            let signature = secp.sign_schnorr_no_aux_rand(&challenge_message(&challenge), &kp);
+            handshake(json!({"signed": {"key": key, "signature": signature}}))
+        };
+        assert_eq!(
+            handle(&mut session, signed(sha256::Hash::hash(b"another session"))),
+            unauthorized(
+                "invalid_credentials",
+                json!({"reason": "invalid_credentials"})
+            )
+        );
+        assert_eq!(
+            handle(&mut session, signed(challenge))["action"],
+            "handshake"
+        );
+        let list = json!({"action": "list_modules"});
+        assert_eq!(handle(&mut session, list)["action"], "modules");
+        assert_eq!(
+            handle(&mut session, patch)["content"]["code"],
+            "missing_capability"
+        );
+    }
+    /// how many `Slow` contracts are compiling
+    static SLOW: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    struct Compiling;
+    impl Drop for Compiling {
+        fn drop(&mut self) {
+            SLOW.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+    /// makes templates, slowly, until stopped
+    #[derive(JsonSchema, Deserialize)]
+    struct Slow {}
+    impl Slow {
+        #[then]
+        fn forever(self, mut ctx: Context) {
+            SLOW.fetch_add(1, Ordering::Relaxed);
+            let compiling = Compiling;
+            let kp = KeyPair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+            let key = XOnlyPublicKey::from_keypair(&kp).0;
+            let funds = ctx.funds();
+            let mut i = 0u64;
+            Ok(Box::new(std::iter::repeat_with(move || {
+                let _ = &compiling;
+                std::thread::sleep(Duration::from_millis(5));
+                i += 1;
+                let fee = Amount::from_sat(i);
+                Ok(ctx
+                    .derive_num(i)?
+                    .template()
+                    .add_fees(fee)?
+                    .add_output(funds - fee, &key, None)?
+                    .into())
+            })))
+        }
+    }
+    impl Contract for Slow {
+        declare! {then, Self::forever}
+        declare! {non updatable}
+    }
+    #[test]
+    fn slow_requests_stop() {
+        let mut menu = MenuBuilder::new();
+        menu.register_as::<Chain>(Some("chain".into()));
+        menu.register_as::<Slow>(Some("slow".into()));
+        let menu: &'static Menu = Box::leak(Box::new(menu.into()));
+        let mut session = Session::new(menu, bitcoin::Network::Regtest);
+        let handle = |session: &mut Session, msg: Value| {
+            let response = session.handle_request(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(response).unwrap()
+        };
+        let create = |id: Option<&str>, type_: &str| json!({"request_id": id, "action": "create", "content": {"type": type_, "args": {"depth": 1}}});
+        let wait = |session: &mut Session| {
+            for _ in 0..1000 {
+                if let Some(r) = session.completed().pop() {
+                    return serde_json::to_value(r).unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            panic!("nothing completed")
+        };
+        let stopped = || {
+            (0..1000).any(|_| {
+                std::thread::sleep(Duration::from_millis(5));
+                SLOW.load(Ordering::Relaxed) == 0
+            })
+        };
+
+        session.set_timeout(Some(Duration::from_millis(50)));
+        assert_eq!(
+            handle(&mut session, create(None, "slow")),
+            json!({"action": "timed_out"})
+        );
+        assert!(stopped());
+        assert_eq!(
+            handle(&mut session, create(None, "chain"))["action"],
+            "created"
+        );
+
+        // in the background, reacted to with their ids
+        session.set_timeout(None);
+        assert_eq!(
+            handle(&mut session, create(Some("a"), "slow")),
+            json!({"request_id": "a", "action": "started"})
+        );
+        assert_eq!(
+            handle(&mut session, create(Some("a"), "chain"))["action"],
+            "error"
+        );
+        assert_eq!(
+            handle(&mut session, create(Some("b"), "chain"))["action"],
+            "started"
+        );
+        let created = wait(&mut session);
+        assert_eq!(created["request_id"], "b");
+        assert_eq!(created["action"], "created");
+        assert!(session.is_compiling());
+        let cancel = |id: &str| json!({"action": "cancel", "content": {"request_id": id}});
+        assert_eq!(
+            handle(&mut session, cancel("a")),
+            json!({"request_id": "a", "action": "cancelled"})
+        );
+        assert!(stopped());
+        assert!(!session.is_compiling());
+        assert!(session.completed().is_empty());
+        assert_eq!(handle(&mut session, cancel("a"))["action"], "error");
+
+        session.set_timeout(Some(Duration::from_millis(50)));
+        handle(&mut session, create(Some("c"), "slow"));
+        assert_eq!(
+            wait(&mut session),
+            json!({"request_id": "c", "action": "timed_out"})
+        );
+        assert!(stopped());
+    }
+    #[test]
+    fn batches_share_a_budget() {
+        let requests = json!([
+            {"type": "chain", "args": {"depth": 2}},
+            {"type": "chain", "args": {"depth": 2}},
+            {"type": "chain", "args": {"depth": 2}},
+            {"type": "chain", "args": {"depth": 1}},
+            {"type": "chain", "args": {"depth": 1}},
+        ]);
+        for parallel in [false, true] {
+            let mut session = chain_session();
+            session.set_parallel_compilation(parallel);
+            session.set_batch_limits(ResourceLimits {
+                max_templates: 5,
+                ..ResourceLimits::sandboxed()
+            });
+            let reactions = batch(&mut session, requests.clone(), false);
+            // the last isn't compiled, as the first and second and fourth
+            // use up the budget
+            assert_eq!(
+                outcomes(&reactions),
+                [
+                    "created",
+                    "created",
+                    "resource_limit_exceeded",
+                    "created",
+                    "resource_limit_exceeded"
+                ]
+            );
+            for exceeded in [&reactions[2], &reactions[4]] {
+                assert!(exceeded["content"]["message"]
+                    .as_str()
+                    .unwrap()
+                    .ends_with("exceeds the limit of 5 templates"));
+            }
+        }
+    }
+    #[test]
+    fn patches_accumulate() {
+        let menu: &'static Menu = Box::leak(Box::new(MenuBuilder::new().into()));
+        let mut session = Session::new(menu, bitcoin::Network::Regtest);
+        let mut patch = |p: Value| {
+            let msg = json!({"action": "patch", "content": {"path": "frontend_session", "name": "sell", "patch": p}}).to_string();
+            match session.handle(Msg::Text(&msg)).unwrap() {
+                Some(Reaction::Patched(v)) => v,
+                _ => panic!("expected a patched reaction"),
+            }
+        };
+        assert_eq!(patch(json!("Hold")), json!("Hold"));
+        assert_eq!(
+            patch(json!({"MakeSale": {"price": 10, "extra": "x"}})),
+            json!({"MakeSale": {"price": 10, "extra": "x"}})
+        );
+        assert_eq!(
+            patch(json!({"MakeSale": {"extra": null}})),
+            json!({"MakeSale": {"price": 10}})
+        );
+        let path = SArc(Arc::new("frontend_session".try_into().unwrap()));
+        assert_eq!(
+            session.effects[&path][&SArc(Arc::new("sell".into()))],
+            json!({"MakeSale": {"price": 10}})
+        );
+    }
+    #[test]
+    fn create_reports_errors() {
+        let mut menu = MenuBuilder::new();
+        menu.register_as::<Compiled>(Some("object".into()));
+        let menu: &'static Menu = Box::leak(Box::new(menu.into()));
+        let mut session = Session::new(menu, bitcoin::Network::Regtest);
+        let msg = json!({"action": "create", "content": {"type": "object", "args": {"bogus": 1}}})
+            .to_string();
+        let reaction = serde_json::to_value(session.handle(Msg::Text(&msg)).unwrap()).unwrap();
+        assert_eq!(reaction["action"], "error");
+        assert_eq!(reaction["content"]["code"], "schema_error");
+        assert_eq!(reaction["content"]["path"], "frontend_session");
+        assert_eq!(reaction["content"]["data"]["kind"], "schema_error");
+    }
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_after_handshake() {
+        let menu: &'static Menu = Box::leak(Box::new(MenuBuilder::new().into()));
+        let mut session = Session::new(menu, bitcoin::Network::Regtest);
+        let msg = json!({"action": "handshake", "content": {"content_type": "application/cbor"}})
+            .to_string();
+        let reaction = session.handle(Msg::Text(&msg)).unwrap().unwrap();
+        assert_eq!(
+            session.encode(&reaction).unwrap(),
+            br#"{"action":"handshake","content":"application/cbor"}"#
+        );
+        let patch = json!({"action": "patch", "content": {"path": "frontend_session", "name": "sell", "patch": {"price": 10}}});
+        let msg = cbor::to_vec(&patch).unwrap();
+        // JSON bytes are no longer understood
+        match session.handle(Msg::Bytes(patch.to_string().as_bytes())) {
+            Ok(Some(Reaction::Error(e))) => assert_eq!(e.code, ErrorCode::InvalidMessage),
+            _ => panic!("expected an invalid message error"),
+        }
+        let garbled = session.handle(Msg::Text(&"{".to_string())).unwrap();
+        assert!(matches!(garbled, Some(Reaction::Error(e)) if e.code == ErrorCode::InvalidMessage));
+        let reaction = session.handle(Msg::Bytes(&msg)).unwrap().unwrap();
+        let encoded = session.encode(&reaction).unwrap();
+        let decoded: Value = cbor::from_slice(&encoded).unwrap();
+        assert_eq!(
+            decoded,
+            json!({"action": "patched", "content": {"price": 10}})
+        );
+        // re-encoding what was decoded gives the same bytes
+        assert_eq!(cbor::to_vec(&decoded).unwrap(), encoded);
+    }
+    /// the `Held` contracts which have started compiling, in order
+    static STARTED: std::sync::Mutex<Vec<String>> = std::sync::Mutex::new(Vec::new());
+    /// the `Held` contracts which may finish compiling
+    static RELEASED: std::sync::Mutex<BTreeSet<String>> = std::sync::Mutex::new(BTreeSet::new());
+    /// pays a key, once released
+    #[derive(JsonSchema, Deserialize)]
+    struct Held {
+        name: String,
+    }
+    impl Held {
+        #[then]
+        fn pay(self, ctx: Context) {
+            STARTED.lock().unwrap().push(self.name.clone());
+            while !RELEASED.lock().unwrap().contains(&self.name) {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            let kp = KeyPair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+            let key = XOnlyPublicKey::from_keypair(&kp).0;
+            let amount = ctx.funds();
+            ctx.template().add_output(amount, &key, None)?.into()
+        }
+    }
+    impl Contract for Held {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+    #[test]
+    fn cancelled_requests_count_until_they_stop() {
+        use crate::quota::*;
+        let mut menu = MenuBuilder::new();
+        menu.register_as::<Held>(Some("held".into()));
+        let menu: &'static Menu = Box::leak(Box::new(menu.into()));
+        let mut session = Session::new(menu, bitcoin::Network::Regtest);
+        session.set_quotas(Quotas {
+            max_concurrent: 1,
+            ..Quotas::default()
+        });
+        let handle = |session: &mut Session, msg: Value| {
+            let response = session.handle_request(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(response).unwrap()
+        };
+        let hold = |id: &str| json!({"request_id": id, "action": "create", "content": {"type": "held", "args": {"name": id}}});
+        let cancel = |id: &str| json!({"action": "cancel", "content": {"request_id": id}});
+
+        assert_eq!(handle(&mut session, hold("h1"))["action"], "started");
+        assert_eq!(handle(&mut session, cancel("h1"))["action"], "cancelled");
+        assert!(!session.is_compiling());
+        // h1 doesn't check for cancellation until released, so is still
+        // compiling
+        let refused = handle(&mut session, hold("h2"));
+        assert_eq!(refused["content"]["data"]["quota"], "concurrent_compiles");
+        RELEASED.lock().unwrap().insert("h1".into());
+        let admitted = (0..1000).any(|_| {
+            std::thread::sleep(Duration::from_millis(5));
+            handle(&mut session, hold("h2"))["action"] == "started"
+        });
+        assert!(admitted);
+        RELEASED.lock().unwrap().insert("h2".into());
+    }
+    #[test]
+    fn quotas_throttle_clients() {
+        use crate::quota::*;
+        let mut menu = MenuBuilder::new();
+        menu.register_as::<Chain>(Some("chain".into()));
+        menu.register_as::<Held>(Some("held".into()));
+        let menu: &'static Menu = Box::leak(Box::new(menu.into()));
+        // one compile at a time, with one more waiting
+        let queue = Arc::new(CompileQueue::new(1, 1));
+        let client = || {
+            let mut session = Session::new(menu, bitcoin::Network::Regtest);
+            session.set_compile_queue(queue.clone());
+            session
+        };
+        let (mut a, mut b) = (client(), client());
+        a.set_quotas(Quotas {
+            max_concurrent: 1,
+            requests_per_minute: 4,
+            ..Quotas::default()
+        });
+        let handle = |session: &mut Session, msg: Value| {
+            let response = session.handle_request(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(response).unwrap()
+        };
+        let hold = |id: &str| json!({"request_id": id, "action": "create", "content": {"type": "held", "args": {"name": id}}});
+        let started = |id: &str| {
+            (0..1000).any(|_| {
+                std::thread::sleep(Duration::from_millis(5));
+                STARTED.lock().unwrap().iter().any(|s| s == id)
+            })
+        };
+        let release = |id: &str| RELEASED.lock().unwrap().insert(id.to_string());
+        let wait = |session: &mut Session| {
+            for _ in 0..1000 {
+                if let Some(r) = session.completed().pop() {
+                    return serde_json::to_value(r).unwrap();
+                }
+                std::thread::sleep(Duration::from_millis(5));
+            }
+            panic!("nothing completed")
+        };
+        let throttled = |quota: &str, retry_after_ms: u64| json!({"code": "throttled", "quota": quota, "retry_after_ms": retry_after_ms});
+        let error = |r: &Value| {
+            json!({
+                "code": r["content"]["code"],
+                "quota": r["content"]["data"]["quota"],
+                "retry_after_ms": r["content"]["data"]["retry_after_ms"],
+            })
+        };
+
+        assert_eq!(handle(&mut a, hold("a1"))["action"], "started");
+        assert!(started("a1"));
+        // a may only compile one at a time, but b gets in line
+        let refused = handle(&mut a, hold("a2"));
+        assert_eq!(refused["request_id"], "a2");
+        assert_eq!(error(&refused), throttled("concurrent_compiles", 1000));
+        assert_eq!(handle(&mut b, hold("b1"))["action"], "started");
+        assert_eq!(queue.load(), (1, 1));
+        // and then the line is full
+        let busy = handle(&mut b, hold("b2"));
+        assert_eq!(error(&busy), throttled("server_busy", 1000));
+
+        release("a1");
+        assert_eq!(wait(&mut a)["action"], "created");
+        // a's next compile waits for b's, which asked first
+        assert_eq!(handle(&mut a, hold("a3"))["action"], "started");
+        assert!(started("b1"));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(!STARTED.lock().unwrap().iter().any(|s| s == "a3"));
+        release("b1");
+        assert_eq!(wait(&mut b)["request_id"], "b1");
+        assert!(started("a3"));
+        release("a3");
+        assert_eq!(wait(&mut a)["request_id"], "a3");
+        assert_eq!(queue.load(), (0, 0));
+
+        // a has sent three requests this minute, and may send one more
+        assert_eq!(
+            handle(&mut a, json!({"action": "session_id"}))["action"],
+            "session_id"
+        );
+        let limited = handle(&mut a, json!({"action": "session_id"}));
+        assert_eq!(limited["content"]["code"], "throttled");
+        assert_eq!(limited["content"]["data"]["quota"], "requests_per_minute");
+        let retry_after = limited["content"]["data"]["retry_after_ms"]
+            .as_u64()
+            .unwrap();
+        assert!(retry_after > 0 && retry_after <= 60_000);
+        // while b's quotas are its own
+        assert_eq!(
+            handle(&mut b, json!({"action": "session_id"}))["action"],
+            "session_id"
+        );
+
+        // and each compile is limited too
+        b.set_quotas(Quotas {
+            limits: ResourceLimits {
+                max_templates: 2,
+                ..ResourceLimits::sandboxed()
+            },
+            ..Quotas::default()
+        });
+        let chain = |depth: u8| json!({"action": "create", "content": {"type": "chain", "args": {"depth": depth}}});
+        assert_eq!(handle(&mut b, chain(2))["action"], "created");
+        assert_eq!(
+            handle(&mut b, chain(3))["content"]["code"],
+            "resource_limit_exceeded"
+        );
+    }
+    #[test]
+    fn clients_share_quotas() {
+        use crate::quota::*;
+        let mut menu = MenuBuilder::new();
+        menu.register_as::<Held>(Some("held".into()));
+        let menu: &'static Menu = Box::leak(Box::new(menu.into()));
+        let queue = Arc::new(CompileQueue::new(1, 3));
+        let rates = Arc::new(RateLimiter::default());
+        let grant = crate::auth::Grant {
+            capabilities: BTreeSet::from([Capability::Compile]),
+            expires: None,
+        };
+        let mut auth = AuthConfig::default();
+        for token in ["alice", "bob"] {
+            auth.tokens
+                .insert(sha256::Hash::hash(token.as_bytes()), grant.clone());
+        }
+        let client = || {
+            let mut session = Session::new(menu, bitcoin::Network::Regtest);
+            session.set_compile_queue(queue.clone());
+            session.set_rate_limiter(rates.clone());
+            session.set_auth(Some(auth.clone()));
+            session.set_quotas(Quotas {
+                requests_per_minute: 4,
+                ..Quotas::default()
+            });
+            session
+        };
+        let handle = |session: &mut Session, msg: Value| {
+            let response = session.handle_request(Msg::Text(&msg.to_string())).unwrap();
+            serde_json::to_value(response).unwrap()
+        };
+        let handshake = |token: &str| json!({"action": "handshake", "content": {"content_type": "application/json", "credentials": {"bearer": token}}});
+        let hold = |id: &str| json!({"request_id": id, "action": "create", "content": {"type": "held", "args": {"name": id}}});
+        let started = |id: &str| {
+            (0..1000).any(|_| {
+                std::thread::sleep(Duration::from_millis(5));
+                STARTED.lock().unwrap().iter().any(|s| s == id)
+            })
+        };
+        let release = |id: &str| RELEASED.lock().unwrap().insert(id.to_string());
+        let (mut a, mut b, mut a_again) = (client(), client(), client());
+        handle(&mut a, handshake("alice"));
+        handle(&mut b, handshake("bob"));
+        handle(&mut a_again, handshake("alice"));
+
+        // each client's turns wait for the other's
+        assert_eq!(handle(&mut a, hold("q-a1"))["action"], "started");
+        assert!(started("q-a1"));
+        assert_eq!(handle(&mut a, hold("q-a2"))["action"], "started");
+        assert_eq!(handle(&mut a, hold("q-a3"))["action"], "started");
+        assert_eq!(handle(&mut b, hold("q-b1"))["action"], "started");
+        for id in ["q-a1", "q-a2", "q-b1", "q-a3"] {
+            assert!(started(id));
+            release(id);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        let order: Vec<_> = STARTED
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|s| s.starts_with("q-"))
+            .cloned()
+            .collect();
+        assert_eq!(order, ["q-a1", "q-a2", "q-b1", "q-a3"]);
+
+        // a has sent three requests as alice, who may send one more from
+        // any session
+        let session_id = json!({"action": "session_id"});
+        assert_eq!(
+            handle(&mut a_again, session_id.clone())["action"],
+            "session_id"
+        );
+        let limited = handle(&mut a, session_id.clone());
+        assert_eq!(limited["content"]["data"]["quota"], "requests_per_minute");
+        assert_eq!(handle(&mut b, session_id)["action"], "session_id");
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Signing PSBTs with keys kept outside of sapio, e.g. on hardware wallets
+//! through [HWI](https://github.com/bitcoin-core/HWI)
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::util::bip32::{DerivationPath, ExtendedPubKey, Fingerprint, KeySource};
+use bitcoin::util::sighash::SchnorrSighashType;
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::{Network, XOnlyPublicKey};
+use miniscript::{Miniscript, Tap};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process::Command;
+use std::str::FromStr;
+
+/// Something holding keys which can sign PSBTs, e.g. a hardware wallet
+pub trait ExternalSigner {
+    /// the fingerprint of the signer's master key
+    fn fingerprint(&self) -> Fingerprint;
+    /// the keys the signer may be asked to sign with, and where they are
+    /// derived from its master key
+    fn keys(&self) -> Result<Vec<(XOnlyPublicKey, DerivationPath)>, ExternalSignerError>;
+    /// sign every input of `psbt` with any of the keys whose origins in it
+    /// are this signer's, returning the signed PSBT
+    fn sign_psbt(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, ExternalSignerError>;
+}
+
+/// Why an external signer couldn't sign
+#[derive(Debug)]
+pub enum ExternalSignerError {
+    /// the signer couldn't be run
+    Io(std::io::Error),
+    /// the signer returned something other than what was asked for
+    BadResponse(String),
+    /// HWI returned an error, with its code
+    Hwi {
+        /// the code, e.g. -14 if the user cancelled on the device
+        code: i64,
+        /// HWI's message
+        message: String,
+    },
+    /// an input to sign doesn't have its coin, which taproot signatures
+    /// commit to for every input
+    MissingUtxo(usize),
+    /// an input to sign asks for a sighash type hardware signers refuse,
+    /// only `SIGHASH_DEFAULT` and `SIGHASH_ALL` being supported
+    UnsupportedSighash {
+        /// the input's index
+        input: usize,
+        /// the sighash type it asks for
+        sighash: u32,
+    },
+    /// an input has more than one leaf with keys of the signers, and none
+    /// was chosen to spend it by, see `SpendPath`
+    NoLeafChosen {
+        /// the input's index
+        input: usize,
+        /// the leaves it may be spent by
+        leaves: Vec<TapLeafHash>,
+    },
+    /// the leaf chosen to spend an input by isn't one of its leaves
+    UnknownLeaf {
+        /// the input's index
+        input: usize,
+        /// the leaf chosen
+        leaf: TapLeafHash,
+    },
+    /// the signer returned without signing for one of its keys, e.g. as
+    /// some devices don't support taproot script path spends
+    NotSigned {
+        /// the signer's fingerprint
+        fingerprint: Fingerprint,
+        /// the input's index
+        input: usize,
+        /// the key it didn't sign with
+        key: XOnlyPublicKey,
+    },
+}
+
+impl Display for ExternalSignerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExternalSignerError::Io(e) => write!(f, "couldn't run the signer: {}", e),
+            ExternalSignerError::BadResponse(s) => {
+                write!(f, "unexpected response from the signer: {}", s)
+            }
+            ExternalSignerError::Hwi { code: -14, message } => {
+                write!(f, "cancelled on the device: {}", message)
+            }
+            ExternalSignerError::Hwi { code, message } => {
+                write!(f, "HWI error {}: {}", code, message)
+            }
+            ExternalSignerError::MissingUtxo(i) => {
+                write!(f, "input {} is missing the coin it spends", i)
+            }
+            ExternalSignerError::UnsupportedSighash { input, sighash } => write!(
+                f,
+                "input {} asks for sighash type {:#x}, which hardware signers only sign with SIGHASH_DEFAULT or SIGHASH_ALL",
+                input, sighash
+            ),
+            ExternalSignerError::NoLeafChosen { input, leaves } => {
+                write!(f, "input {} may be spent by any of the leaves", input)?;
+                for leaf in leaves {
+                    write!(f, " {}", leaf)?;
+                }
+                write!(f, ", choose one")
+            }
+            ExternalSignerError::UnknownLeaf { input, leaf } => {
+                write!(f, "input {} has no leaf {}", input, leaf)
+            }
+            ExternalSignerError::NotSigned {
+                fingerprint,
+                input,
+                key,
+            } => write!(
+                f,
+                "device {} didn't sign input {} with key {}, it may not support taproot script path spends",
+                fingerprint, input, key
+            ),
+        }
+    }
+}
+impl Error for ExternalSignerError {}
+
+impl From<std::io::Error> for ExternalSignerError {
+    fn from(e: std::io::Error) -> Self {
+        ExternalSignerError::Io(e)
+    }
+}
+
+/// How an input is to be spent, and so which keys it is signed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpendPath {
+    /// by its internal key
+    Key,
+    /// by the script of one of its leaves, e.g. the branch of a contract
+    /// taken
+    Leaf(TapLeafHash),
+}
+
+impl FromStr for SpendPath {
+    type Err = bitcoin::hashes::hex::Error;
+    /// `key`, or the hash of a leaf
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "key" => Ok(SpendPath::Key),
+            leaf => Ok(SpendPath::Leaf(leaf.parse()?)),
+        }
+    }
+}
+
+/// A device known to HWI, and the paths of the keys to use from it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRegistration {
+    /// the fingerprint of the device's master key
+    pub fingerprint: Fingerprint,
+    /// the derivation paths of its keys which contracts use
+    pub paths: Vec<DerivationPath>,
+}
+
+/// The hardware signers to request signatures from
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HwiConfig {
+    /// the `hwi` executable
+    #[serde(default = "HwiConfig::default_command")]
+    pub command: PathBuf,
+    /// the devices, in the order to ask them
+    pub devices: Vec<DeviceRegistration>,
+}
+
+impl HwiConfig {
+    fn default_command() -> PathBuf {
+        "hwi".into()
+    }
+    /// a signer for each device, on `network`
+    pub fn signers(&self, network: Network) -> Vec<HwiSigner> {
+        self.devices
+            .iter()
+            .map(|device| HwiSigner {
+                command: self.command.clone(),
+                network,
+                device: device.clone(),
+            })
+            .collect()
+    }
+}
+
+/// A hardware wallet, signing through HWI's command line JSON interface
+#[derive(Debug, Clone)]
+pub struct HwiSigner {
+    /// the `hwi` executable
+    pub command: PathBuf,
+    /// the network the device is to sign for
+    pub network: Network,
+    /// the device
+    pub device: DeviceRegistration,
+}
+
+impl HwiSigner {
+    /// run an HWI command on the device, returning its result
+    fn call(&self, args: &[String]) -> Result<serde_json::Value, ExternalSignerError> {
+        let chain = match self.network {
+            Network::Bitcoin => "main",
+            Network::Testnet => "test",
+            Network::Signet => "signet",
+            Network::Regtest => "regtest",
+        };
+        let output = Command::new(&self.command)
+            .arg("--fingerprint")
+            .arg(self.device.fingerprint.to_string())
+            .arg("--chain")
+            .arg(chain)
+            .args(args)
+            .output()?;
+        let value: serde_json::Value = serde_json::from_slice(&output.stdout).map_err(|_| {
+            ExternalSignerError::BadResponse(
+                String::from_utf8_lossy(if output.stdout.is_empty() {
+                    &output.stderr
+                } else {
+                    &output.stdout
+                })
+                .into_owned(),
+            )
+        })?;
+        if let Some(error) = value.get("error") {
+            return Err(ExternalSignerError::Hwi {
+                code: value.get("code").and_then(|c| c.as_i64()).unwrap_or(0),
+                message: error.as_str().unwrap_or_default().into(),
+            });
+        }
+        Ok(value)
+    }
+    /// the field `name` of an HWI result, as a string
+    fn field(value: &serde_json::Value, name: &str) -> Result<String, ExternalSignerError> {
+        value
+            .get(name)
+            .and_then(|v| v.as_str())
+            .map(String::from)
+            .ok_or_else(|| ExternalSignerError::BadResponse(value.to_string()))
+    }
+}
+
+impl ExternalSigner for HwiSigner {
+    fn fingerprint(&self) -> Fingerprint {
+        self.device.fingerprint
+    }
+    fn keys(&self) -> Result<Vec<(XOnlyPublicKey, DerivationPath)>, ExternalSignerError> {
+        self.device
+            .paths
+            .iter()
+            .map(|path| {
+                let value = self.call(&["getxpub".into(), path.to_string()])?;
+                let xpub = Self::field(&value, "xpub")?;
+                let xpub: ExtendedPubKey = xpub
+                    .parse()
+                    .map_err(|_| ExternalSignerError::BadResponse(xpub))?;
+                Ok((xpub.public_key.x_only_public_key().0, path.clone()))
+            })
+            .collect()
+    }
+    fn sign_psbt(
+        &self,
+        psbt: PartiallySignedTransaction,
+    ) -> Result<PartiallySignedTransaction, ExternalSignerError> {
+        let value = self.call(&["signtx".into(), base64::encode(serialize(&psbt))])?;
+        let signed = Self::field(&value, "psbt")?;
+        base64::decode(&signed)
+            .ok()
+            .and_then(|bytes| deserialize(&bytes).ok())
+            .ok_or(ExternalSignerError::BadResponse(signed))
+    }
+}
+
+/// Request the signatures `psbt` needs from `signers`, adding them to it.
+///
+/// Each input is signed for the way `paths` chooses to spend it. One not
+/// in `paths` is spent by its key path if its internal key is a signer's,
+/// and otherwise by its leaf with keys of the signers if it has only one.
+/// Each key of a signer used by the way an input is spent and not yet
+/// signed for gets its origin added to the input, as devices only sign for
+/// keys they find their fingerprint on, with the leaf that needs it. The
+/// signers are then asked in the order their keys first appear in the
+/// inputs and leaves, each once. A signer which returns without signing for
+/// one of its keys is an error rather than leaving the input unsatisfiable.
+pub fn request_signatures(
+    mut psbt: PartiallySignedTransaction,
+    signers: &[&dyn ExternalSigner],
+    paths: &BTreeMap<usize, SpendPath>,
+) -> Result<PartiallySignedTransaction, ExternalSignerError> {
+    let mut origins: BTreeMap<XOnlyPublicKey, (usize, KeySource)> = BTreeMap::new();
+    for (i, signer) in signers.iter().enumerate() {
+        for (key, path) in signer.keys()? {
+            origins
+                .entry(key)
+                .or_insert((i, (signer.fingerprint(), path)));
+        }
+    }
+    // what each signer is asked for, in the order to ask them
+    let mut requests: Vec<(usize, Vec<(usize, XOnlyPublicKey)>)> = vec![];
+    let has_utxos = psbt.inputs.iter().all(|i| i.witness_utxo.is_some());
+    for (idx, input) in psbt.inputs.iter_mut().enumerate() {
+        // the keys of the signers in each leaf
+        let mut leaves: BTreeMap<TapLeafHash, Vec<XOnlyPublicKey>> = BTreeMap::new();
+        for (script, ver) in input.tap_scripts.values() {
+            let leaf = TapLeafHash::from_script(script, *ver);
+            let keys = match Miniscript::<XOnlyPublicKey, Tap>::parse_insane(script) {
+                Ok(ms) => ms.iter_pk().filter(|k| origins.contains_key(k)).collect(),
+                Err(_) => vec![],
+            };
+            leaves.insert(leaf, keys);
+        }
+        let signs_key = input
+            .tap_internal_key
+            .is_some_and(|k| origins.contains_key(&k));
+        let path = match paths.get(&idx) {
+            Some(SpendPath::Leaf(leaf)) if !leaves.contains_key(leaf) => {
+                return Err(ExternalSignerError::UnknownLeaf {
+                    input: idx,
+                    leaf: *leaf,
+                })
+            }
+            Some(path) => Some(*path),
+            None if signs_key => Some(SpendPath::Key),
+            None => {
+                let ours: Vec<_> = leaves
+                    .iter()
+                    .filter(|(_, keys)| !keys.is_empty())
+                    .map(|(leaf, _)| *leaf)
+                    .collect();
+                match ours[..] {
+                    [] => None,
+                    [leaf] => Some(SpendPath::Leaf(leaf)),
+                    _ => {
+                        return Err(ExternalSignerError::NoLeafChosen {
+                            input: idx,
+                            leaves: ours,
+                        })
+                    }
+                }
+            }
+        };
+        let needed: Vec<(XOnlyPublicKey, Option<TapLeafHash>)> = match path {
+            None => vec![],
+            Some(SpendPath::Key) => input
+                .tap_internal_key
+                .filter(|k| origins.contains_key(k) && input.tap_key_sig.is_none())
+                .map(|k| (k, None))
+                .into_iter()
+                .collect(),
+            Some(SpendPath::Leaf(leaf)) => leaves[&leaf]
+                .iter()
+                .filter(|k| !input.tap_script_sigs.contains_key(&(**k, leaf)))
+                .map(|k| (*k, Some(leaf)))
+                .collect(),
+        };
+        if needed.is_empty() {
+            continue;
+        }
+        if !has_utxos {
+            return Err(ExternalSignerError::MissingUtxo(idx));
+        }
+        match input.schnorr_hash_ty() {
+            Ok(SchnorrSighashType::Default) | Ok(SchnorrSighashType::All) => {}
+            _ => {
+                return Err(ExternalSignerError::UnsupportedSighash {
+                    input: idx,
+                    sighash: input.sighash_type.map_or(0, |s| s.to_u32()),
+                })
+            }
+        }
+        for (key, leaf) in needed {
+            let (signer, source) = &origins[&key];
+            let origin = input
+                .tap_key_origins
+                .entry(key)
+                .or_insert((vec![], source.clone()));
+            origin.1 = source.clone();
+            if let Some(leaf) = leaf {
+                if !origin.0.contains(&leaf) {
+                    origin.0.push(leaf);
+                }
+            }
+            match requests.iter_mut().find(|(s, _)| s == signer) {
+                Some((_, keys)) => keys.push((idx, key)),
+                None => requests.push((*signer, vec![(idx, key)])),
+            }
+        }
+    }
+    for (i, keys) in requests {
+        let signed = signers[i].sign_psbt(psbt.clone())?;
+        if signed.unsigned_tx != psbt.unsigned_tx || signed.inputs.len() != psbt.inputs.len() {
+            return Err(ExternalSignerError::BadResponse(
+                "the signed PSBT is for another transaction".into(),
+            ));
+        }
+        let ours = |key: &XOnlyPublicKey| origins.get(key).map(|(s, _)| *s) == Some(i);
+        for (input, signed) in psbt.inputs.iter_mut().zip(signed.inputs) {
+            input.tap_script_sigs.extend(
+                signed
+                    .tap_script_sigs
+                    .into_iter()
+                    .filter(|((key, _), _)| ours(key)),
+            );
+            if input.tap_key_sig.is_none() && input.tap_internal_key.as_ref().is_some_and(ours) {
+                input.tap_key_sig = signed.tap_key_sig;
+            }
+        }
+        for (idx, key) in keys {
+            let input = &psbt.inputs[idx];
+            let signed = input.tap_script_sigs.keys().any(|(k, _)| *k == key)
+                || (input.tap_internal_key == Some(key) && input.tap_key_sig.is_some());
+            if !signed {
+                return Err(ExternalSignerError::NotSigned {
+                    fingerprint: signers[i].fingerprint(),
+                    input: idx,
+                    key,
+                });
+            }
+        }
+    }
+    Ok(psbt)
+}
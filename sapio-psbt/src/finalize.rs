@@ -0,0 +1,909 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finalizing the PSBTs of a contract's templates into transactions, with
+//! the emulator's signatures and whatever else their scripts need
+use crate::external_signer::{request_signatures, ExternalSigner, ExternalSignerError, SpendPath};
+use bitcoin::hashes::{hash160, ripemd160, sha256, sha256d, Hash};
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::sighash::Prevouts;
+use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
+use bitcoin::{Amount, SchnorrSig, Transaction, TxOut, XOnlyPublicKey};
+use miniscript::miniscript::decode::Terminal;
+use miniscript::psbt::{PsbtExt, PsbtInputSatisfier};
+use miniscript::{Interpreter, Miniscript, Satisfier, Tap};
+use sapio_base::util::CTVHash;
+use sapio_ctv_emulator_trait::{CTVEmulator, EmulatorError};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Display;
+
+/// What is needed to satisfy a PSBT's scripts beyond what is in it already
+#[derive(Default, Clone, Debug)]
+pub struct SatisfierInputs {
+    /// preimages of any hashes the scripts check, of any kind
+    pub preimages: Vec<Vec<u8>>,
+    /// signatures collected for each input by its index, by key and the
+    /// leaf they sign for
+    pub signatures: BTreeMap<usize, BTreeMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig>>,
+}
+
+/// Something a leaf of an input's script needs which isn't available
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Missing {
+    /// a signature by the key for the leaf
+    Signature(XOnlyPublicKey),
+    /// this many more signatures for the leaf, by any of the keys
+    Signatures {
+        /// how many more are needed
+        needed: usize,
+        /// the keys which haven't signed
+        keys: Vec<XOnlyPublicKey>,
+    },
+    /// a signature for the leaf by the key with this hash160
+    KeyHashSignature(hash160::Hash),
+    /// a signature for the key path, the keys in the PSBT having no leaves
+    KeySpendSignature(Option<XOnlyPublicKey>),
+    /// the preimage of a sha256 hash
+    Sha256Preimage(sha256::Hash),
+    /// the preimage of a double sha256 hash
+    Hash256Preimage(sha256d::Hash),
+    /// the preimage of a ripemd160 hash
+    Ripemd160Preimage(ripemd160::Hash),
+    /// the preimage of a hash160 hash
+    Hash160Preimage(hash160::Hash),
+    /// the transaction's lock_time to meet an absolute timelock
+    AbsoluteTimelock(u32),
+    /// the input's sequence to meet a relative timelock
+    RelativeTimelock(u32),
+    /// the transaction to match a CTV template hash
+    TxTemplate(sha256::Hash),
+}
+
+impl Display for Missing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Missing::Signature(k) => write!(f, "a signature by {}", k),
+            Missing::Signatures { needed, keys } => {
+                let keys: Vec<_> = keys.iter().map(XOnlyPublicKey::to_string).collect();
+                write!(
+                    f,
+                    "{} more signatures by any of {}",
+                    needed,
+                    keys.join(", ")
+                )
+            }
+            Missing::KeyHashSignature(h) => write!(f, "a signature by the key of hash160 {}", h),
+            Missing::KeySpendSignature(Some(k)) => {
+                write!(f, "a key path signature by internal key {}", k)
+            }
+            Missing::KeySpendSignature(None) => write!(f, "a key path signature"),
+            Missing::Sha256Preimage(h) => write!(f, "the preimage of sha256 {}", h),
+            Missing::Hash256Preimage(h) => write!(f, "the preimage of hash256 {}", h),
+            Missing::Ripemd160Preimage(h) => write!(f, "the preimage of ripemd160 {}", h),
+            Missing::Hash160Preimage(h) => write!(f, "the preimage of hash160 {}", h),
+            Missing::AbsoluteTimelock(n) => {
+                write!(f, "a lock_time meeting absolute timelock {}", n)
+            }
+            Missing::RelativeTimelock(n) => write!(f, "a sequence meeting relative timelock {}", n),
+            Missing::TxTemplate(h) => write!(f, "a transaction matching template {}", h),
+        }
+    }
+}
+
+/// Why a PSBT couldn't be finalized
+#[derive(Debug)]
+pub enum FinalizeError {
+    /// the coin an input spends isn't in the PSBT, so nothing can sign it
+    MissingUtxo(usize),
+    /// the emulator couldn't add its signatures
+    Emulator(EmulatorError),
+    /// an external signer couldn't add its signatures
+    Signer(ExternalSignerError),
+    /// no leaf of an input's script can be satisfied, with what each one is
+    /// missing
+    Unsatisfied {
+        /// the input's index
+        input: usize,
+        /// what each way of satisfying a leaf is missing, in the order of the
+        /// PSBT's leaves, leaving out ways which need more than another
+        missing: Vec<Vec<Missing>>,
+    },
+    /// the input couldn't be finalized for another reason, e.g. a bad
+    /// signature
+    Miniscript(miniscript::psbt::Error),
+    /// the finalized transaction's input fails script verification
+    Invalid {
+        /// the input's index
+        input: usize,
+        /// why the interpreter rejected it
+        error: miniscript::interpreter::Error,
+    },
+    /// the finalized transaction pays out more than the coins it spends
+    Overspends {
+        /// the total of the coins spent
+        spent: Amount,
+        /// the total of the outputs
+        paid: Amount,
+    },
+}
+
+impl Display for FinalizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FinalizeError::MissingUtxo(i) => write!(f, "input {} is missing the coin it spends", i),
+            FinalizeError::Emulator(e) => write!(f, "the emulator couldn't sign: {}", e),
+            FinalizeError::Signer(e) => write!(f, "{}", e),
+            FinalizeError::Unsatisfied { input, missing } => {
+                write!(f, "input {} can't be satisfied", input)?;
+                for (i, leaf) in missing.iter().enumerate() {
+                    let items: Vec<_> = leaf.iter().map(Missing::to_string).collect();
+                    let sep = if i == 0 { ":" } else { "; or" };
+                    write!(f, "{} missing {}", sep, items.join(" and "))?;
+                }
+                Ok(())
+            }
+            FinalizeError::Miniscript(e) => write!(f, "{}", e),
+            FinalizeError::Invalid { input, error } => {
+                write!(f, "input {} fails verification: {}", input, error)
+            }
+            FinalizeError::Overspends { spent, paid } => {
+                write!(f, "the transaction pays {} but spends only {}", paid, spent)
+            }
+        }
+    }
+}
+impl Error for FinalizeError {}
+
+impl From<EmulatorError> for FinalizeError {
+    fn from(e: EmulatorError) -> Self {
+        FinalizeError::Emulator(e)
+    }
+}
+
+impl From<ExternalSignerError> for FinalizeError {
+    fn from(e: ExternalSignerError) -> Self {
+        FinalizeError::Signer(e)
+    }
+}
+
+/// Finalize `psbt` into a transaction ready to broadcast.
+///
+/// The `satisfier_inputs` are added to its inputs, and then the signatures
+/// of `emulator`, e.g. for the PSBTs from `Object::export_psbts`. Each input
+/// is satisfied by its key path if signed for, or else by the leaf with the
+/// smallest witness which can be, and the transaction is checked with
+/// `verify` before it is returned.
+pub fn finalize(
+    mut psbt: PartiallySignedTransaction,
+    emulator: &dyn CTVEmulator,
+    satisfier_inputs: &SatisfierInputs,
+) -> Result<Transaction, FinalizeError> {
+    if let Some(i) = psbt.inputs.iter().position(|i| i.witness_utxo.is_none()) {
+        return Err(FinalizeError::MissingUtxo(i));
+    }
+    for (i, input) in psbt.inputs.iter_mut().enumerate() {
+        for p in satisfier_inputs.preimages.iter() {
+            input
+                .sha256_preimages
+                .insert(sha256::Hash::hash(p), p.clone());
+            input
+                .hash256_preimages
+                .insert(sha256d::Hash::hash(p), p.clone());
+            input
+                .ripemd160_preimages
+                .insert(ripemd160::Hash::hash(p), p.clone());
+            input
+                .hash160_preimages
+                .insert(hash160::Hash::hash(p), p.clone());
+        }
+        if let Some(signatures) = satisfier_inputs.signatures.get(&i) {
+            input.tap_script_sigs.extend(signatures);
+        }
+    }
+    let mut psbt = emulator.sign(psbt)?;
+    let secp = Secp256k1::verification_only();
+    if let Err(errors) = psbt.finalize_mut(&secp) {
+        let error = errors.into_iter().next().expect("finalizing failed");
+        return Err(match error {
+            miniscript::psbt::Error::InputError(
+                miniscript::psbt::InputError::CouldNotSatisfyTr,
+                input,
+            ) => FinalizeError::Unsatisfied {
+                input,
+                missing: missing(&psbt, input),
+            },
+            e => FinalizeError::Miniscript(e),
+        });
+    }
+    let spent: Vec<_> = psbt
+        .inputs
+        .iter()
+        .filter_map(|i| i.witness_utxo.clone())
+        .collect();
+    let tx = psbt.extract_tx();
+    verify(&tx, &spent)?;
+    Ok(tx)
+}
+
+/// Check that `tx`, spending the coins `spent` by its inputs in order, pays
+/// out no more than they hold, and that the script of each one accepts the
+/// input's witness, running it in the miniscript interpreter with the
+/// transaction's signature hashes, timelocks and template.
+pub fn verify(tx: &Transaction, spent: &[TxOut]) -> Result<(), FinalizeError> {
+    if spent.len() < tx.input.len() {
+        return Err(FinalizeError::MissingUtxo(spent.len()));
+    }
+    let spent = &spent[..tx.input.len()];
+    let total = |outs: &[TxOut]| {
+        outs.iter()
+            .try_fold(0u64, |t, o| t.checked_add(o.value))
+            .map(Amount::from_sat)
+            .unwrap_or_else(Amount::max_value)
+    };
+    let (paid, spent_total) = (total(&tx.output), total(spent));
+    if paid > spent_total {
+        return Err(FinalizeError::Overspends {
+            spent: spent_total,
+            paid,
+        });
+    }
+    let secp = Secp256k1::verification_only();
+    let prevouts = Prevouts::All(spent);
+    for (i, (input, coin)) in tx.input.iter().zip(spent).enumerate() {
+        let invalid = |error| FinalizeError::Invalid { input: i, error };
+        let interpreter = Interpreter::from_txdata(
+            &coin.script_pubkey,
+            &input.script_sig,
+            &input.witness,
+            tx.lock_time,
+            input.sequence,
+            tx.get_ctv_hash(i as u32),
+        )
+        .map_err(invalid)?;
+        let error = interpreter
+            .iter(&secp, tx, i, &prevouts)
+            .find_map(Result::err);
+        if let Some(error) = error {
+            return Err(invalid(error));
+        }
+    }
+    Ok(())
+}
+
+/// Finalize `psbt` as `finalize` does, after requesting the signatures of
+/// `signers` for the keys it needs to be spent the way `paths` chooses, see
+/// `external_signer::request_signatures`.
+///
+/// The `satisfier_inputs` are added first, so keys already signed for aren't
+/// requested again.
+pub fn finalize_with_signers(
+    mut psbt: PartiallySignedTransaction,
+    emulator: &dyn CTVEmulator,
+    satisfier_inputs: &SatisfierInputs,
+    signers: &[&dyn ExternalSigner],
+    paths: &BTreeMap<usize, SpendPath>,
+) -> Result<Transaction, FinalizeError> {
+    for (i, signatures) in satisfier_inputs.signatures.iter() {
+        if let Some(input) = psbt.inputs.get_mut(*i) {
+            input.tap_script_sigs.extend(signatures);
+        }
+    }
+    let psbt = request_signatures(psbt, signers, paths)?;
+    finalize(psbt, emulator, satisfier_inputs)
+}
+
+/// what each way of satisfying the leaves of input `index` of `psbt` is
+/// missing, as its satisfier looks them up
+fn missing(psbt: &PartiallySignedTransaction, index: usize) -> Vec<Vec<Missing>> {
+    let input = &psbt.inputs[index];
+    let satisfier = PsbtInputSatisfier::new(psbt, index);
+    let leaves: Vec<_> = input
+        .tap_scripts
+        .values()
+        .filter(|(_, ver)| *ver == LeafVersion::TapScript)
+        .filter_map(|(script, ver)| {
+            let ms = Miniscript::<XOnlyPublicKey, Tap>::parse_insane(script).ok()?;
+            let leaf = TapLeafHash::from_script(script, *ver);
+            Some(ways(&ms, &satisfier, &leaf))
+        })
+        .collect();
+    if leaves.is_empty() {
+        vec![vec![Missing::KeySpendSignature(input.tap_internal_key)]]
+    } else {
+        leaves.into_iter().flatten().collect()
+    }
+}
+
+/// what each way of satisfying `ms` in `leaf` is missing, leaving out ways
+/// which need more than another, so a met way is `vec![]` and none at all
+/// means it can't be satisfied
+fn ways(
+    ms: &Miniscript<XOnlyPublicKey, Tap>,
+    satisfier: &PsbtInputSatisfier,
+    leaf: &TapLeafHash,
+) -> Vec<Vec<Missing>> {
+    let check = |met: bool, missing| {
+        if met {
+            vec![vec![]]
+        } else {
+            vec![vec![missing]]
+        }
+    };
+    let signed = |pk: &XOnlyPublicKey| satisfier.lookup_tap_leaf_script_sig(pk, leaf).is_some();
+    let sub = |ms| ways(ms, satisfier, leaf);
+    match &ms.node {
+        Terminal::True => vec![vec![]],
+        Terminal::False => vec![],
+        Terminal::PkK(pk) => check(signed(pk), Missing::Signature(*pk)),
+        Terminal::PkH(h) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::lookup_pkh_tap_leaf_script_sig(
+                satisfier,
+                &(*h, *leaf),
+            )
+            .is_some(),
+            Missing::KeyHashSignature(*h),
+        ),
+        Terminal::After(n) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::check_after(satisfier, *n),
+            Missing::AbsoluteTimelock(*n),
+        ),
+        Terminal::Older(n) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::check_older(satisfier, *n),
+            Missing::RelativeTimelock(*n),
+        ),
+        Terminal::Sha256(h) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::lookup_sha256(satisfier, *h).is_some(),
+            Missing::Sha256Preimage(*h),
+        ),
+        Terminal::Hash256(h) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::lookup_hash256(satisfier, *h).is_some(),
+            Missing::Hash256Preimage(*h),
+        ),
+        Terminal::Ripemd160(h) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::lookup_ripemd160(satisfier, *h).is_some(),
+            Missing::Ripemd160Preimage(*h),
+        ),
+        Terminal::Hash160(h) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::lookup_hash160(satisfier, *h).is_some(),
+            Missing::Hash160Preimage(*h),
+        ),
+        Terminal::TxTemplate(h) => check(
+            <dyn Satisfier<XOnlyPublicKey>>::check_tx_template(satisfier, *h),
+            Missing::TxTemplate(*h),
+        ),
+        Terminal::Alt(a)
+        | Terminal::Swap(a)
+        | Terminal::Check(a)
+        | Terminal::DupIf(a)
+        | Terminal::Verify(a)
+        | Terminal::NonZero(a)
+        | Terminal::ZeroNotEqual(a) => sub(a),
+        Terminal::AndV(a, b) | Terminal::AndB(a, b) => both(sub(a), sub(b)),
+        Terminal::AndOr(a, b, c) => either(both(sub(a), sub(b)), sub(c)),
+        Terminal::OrB(a, b) | Terminal::OrD(a, b) | Terminal::OrC(a, b) | Terminal::OrI(a, b) => {
+            either(sub(a), sub(b))
+        }
+        Terminal::Thresh(k, subs) => {
+            threshold(*k, &subs.iter().map(|s| sub(s)).collect::<Vec<_>>())
+        }
+        Terminal::Multi(k, keys) | Terminal::MultiA(k, keys) => {
+            let unsigned: Vec<_> = keys.iter().filter(|pk| !signed(pk)).cloned().collect();
+            match k.saturating_sub(keys.len() - unsigned.len()) {
+                0 => vec![vec![]],
+                n if n == unsigned.len() => {
+                    vec![unsigned.into_iter().map(Missing::Signature).collect()]
+                }
+                needed => vec![vec![Missing::Signatures {
+                    needed,
+                    keys: unsigned,
+                }]],
+            }
+        }
+    }
+}
+
+/// the ways of meeting both of two conditions, given the ways of each
+fn both(a: Vec<Vec<Missing>>, b: Vec<Vec<Missing>>) -> Vec<Vec<Missing>> {
+    minimal(
+        a.iter()
+            .flat_map(|x| b.iter().map(move |y| x.iter().chain(y).cloned().collect()))
+            .collect(),
+    )
+}
+
+/// the ways of meeting either of two conditions, given the ways of each
+fn either(mut a: Vec<Vec<Missing>>, b: Vec<Vec<Missing>>) -> Vec<Vec<Missing>> {
+    a.extend(b);
+    minimal(a)
+}
+
+/// the ways of meeting `k` of `subs`, given the ways of each
+fn threshold(k: usize, subs: &[Vec<Vec<Missing>>]) -> Vec<Vec<Missing>> {
+    match subs.split_first() {
+        _ if k == 0 => vec![vec![]],
+        None => vec![],
+        Some((first, rest)) => either(
+            both(first.clone(), threshold(k - 1, rest)),
+            threshold(k, rest),
+        ),
+    }
+}
+
+/// `ways` without duplicates, or any way which needs all of another and more
+fn minimal(mut ways: Vec<Vec<Missing>>) -> Vec<Vec<Missing>> {
+    for way in ways.iter_mut() {
+        way.sort();
+        way.dedup();
+    }
+    ways.sort_by_key(Vec::len);
+    let mut kept: Vec<Vec<Missing>> = vec![];
+    for way in ways {
+        if !kept.iter().any(|k| k.iter().all(|m| way.contains(m))) {
+            kept.push(way);
+        }
+    }
+    kept
+}
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::secp256k1::Message;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::util::bip32::{DerivationPath, Fingerprint};
+    use bitcoin::util::sighash::{Prevouts, SchnorrSighashType, SighashCache};
+    use bitcoin::KeyPair;
+    use bitcoin::{Network, OutPoint, Script, TxOut};
+    use emulator_connect::connections::local::LocalEmulator;
+    use sapio::contract::*;
+    use sapio::*;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::cell::RefCell;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn keypair(seed: u8) -> KeyPair {
+        KeyPair::from_seckey_slice(&Secp256k1::new(), &[seed; 32]).unwrap()
+    }
+    fn key(seed: u8) -> XOnlyPublicKey {
+        XOnlyPublicKey::from_keypair(&keypair(seed)).0
+    }
+    const PREIMAGE: [u8; 32] = [7; 32];
+
+    /// pays to `key(3)`, when signed by `key(1)`, or by `key(2)` with the
+    /// preimage of `PREIMAGE`
+    struct Escrow;
+    fn pay(ctx: Context) -> TxTmplIt {
+        let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amount, &key(3), None)?
+            .into()
+    }
+    impl Escrow {
+        #[guard]
+        fn first(self, _ctx: Context) {
+            Clause::Key(key(1))
+        }
+        #[guard]
+        fn second(self, _ctx: Context) {
+            Clause::And(vec![
+                Clause::Key(key(2)),
+                Clause::Sha256(sha256::Hash::hash(&PREIMAGE)),
+            ])
+        }
+        #[then(guarded_by = "[Self::first]")]
+        fn by_first(self, ctx: Context) {
+            pay(ctx)
+        }
+        #[then(guarded_by = "[Self::second]")]
+        fn by_second(self, ctx: Context) {
+            pay(ctx)
+        }
+    }
+    impl Contract for Escrow {
+        declare! {then, Self::by_first, Self::by_second}
+        declare! {non updatable}
+    }
+
+    /// sign every leaf of the only input of `psbt` which has `seed`'s key
+    fn sign(psbt: &PartiallySignedTransaction, seed: u8) -> SatisfierInputs {
+        let secp = Secp256k1::new();
+        let prevouts = [psbt.inputs[0].witness_utxo.clone().unwrap()];
+        let k = key(seed).serialize();
+        let signatures = psbt.inputs[0]
+            .tap_scripts
+            .values()
+            .filter(|(s, _)| s.as_bytes().windows(32).any(|w| w == k))
+            .map(|(s, v)| {
+                let leaf = TapLeafHash::from_script(s, *v);
+                let sighash = SighashCache::new(&psbt.unsigned_tx)
+                    .taproot_script_spend_signature_hash(
+                        0,
+                        &Prevouts::All(&prevouts),
+                        leaf,
+                        SchnorrSighashType::All,
+                    )
+                    .unwrap();
+                let msg = Message::from_digest_slice(&sighash[..]).unwrap();
+                let sig = SchnorrSig {
+                    sig: secp.sign_schnorr_no_aux_rand(&msg, &keypair(seed)),
+                    hash_ty: SchnorrSighashType::All,
+                };
+                ((key(seed), leaf), sig)
+            })
+            .collect();
+        SatisfierInputs {
+            preimages: vec![],
+            signatures: [(0, signatures)].into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn finalize_escrow_leaves() {
+        let emulator = LocalEmulator::for_tests();
+        let compiled = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(LocalEmulator::for_tests()),
+            EffectPath::try_from("escrow").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .compile(Escrow)
+        .unwrap();
+        let txout = TxOut {
+            value: 100_000,
+            script_pubkey: Script::from(compiled.address.clone()),
+        };
+        // without the emulator's signatures, which `finalize` adds
+        let psbts = compiled
+            .export_psbts(OutPoint::default(), txout, &CTVAvailable)
+            .unwrap();
+        assert_eq!(psbts.len(), 1);
+        let psbt = &psbts[0].1;
+        let h = sha256::Hash::hash(&PREIMAGE);
+
+        let error = finalize(psbt.clone(), &emulator, &sign(psbt, 2)).unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains(&format!("a signature by {}", key(1))));
+        assert!(message.contains(&format!("the preimage of sha256 {}", h)));
+        match error {
+            FinalizeError::Unsatisfied { input, missing } => {
+                assert_eq!(input, 0);
+                assert_eq!(missing.len(), 2);
+                assert!(missing.contains(&vec![Missing::Sha256Preimage(h)]));
+            }
+            e => panic!("unexpected error {}", e),
+        }
+
+        let mut inputs = sign(psbt, 2);
+        inputs.preimages.push(PREIMAGE.to_vec());
+        let second = finalize(psbt.clone(), &emulator, &inputs).unwrap();
+        // both leaves can be satisfied, and the first has the smaller witness
+        inputs
+            .signatures
+            .get_mut(&0)
+            .unwrap()
+            .extend(sign(psbt, 1).signatures.remove(&0).unwrap());
+        let first = finalize(psbt.clone(), &emulator, &inputs).unwrap();
+        assert!(first.input[0].witness.len() < second.input[0].witness.len());
+        assert_eq!(first.txid(), second.txid());
+        let leaf =
+            |tx: &Transaction| Script::from(tx.input[0].witness.second_to_last().unwrap().to_vec());
+        assert!(leaf(&first)
+            .as_bytes()
+            .windows(32)
+            .any(|w| w == key(1).serialize()));
+        assert!(leaf(&second)
+            .as_bytes()
+            .windows(32)
+            .any(|w| w == key(2).serialize()));
+    }
+
+    /// pays to `key(3)`, when signed by `key(1)` or `key(2)`, with the
+    /// preimage of `PREIMAGE`, after a relative timelock its template doesn't
+    /// meet
+    struct Locked;
+    impl Locked {
+        #[guard]
+        fn timelocked(self, _ctx: Context) {
+            Clause::And(vec![
+                Clause::Or(vec![(1, Clause::Key(key(1))), (1, Clause::Key(key(2)))]),
+                Clause::And(vec![
+                    Clause::Sha256(sha256::Hash::hash(&PREIMAGE)),
+                    Clause::Older(10),
+                ]),
+            ])
+        }
+        #[then(guarded_by = "[Self::timelocked]")]
+        fn spend(self, ctx: Context) {
+            pay(ctx)
+        }
+    }
+    impl Contract for Locked {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+    }
+
+    #[test]
+    fn finalize_reports_what_satisfier_lacks() {
+        let emulator = LocalEmulator::for_tests();
+        let compiled = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(LocalEmulator::for_tests()),
+            EffectPath::try_from("locked").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .compile(Locked)
+        .unwrap();
+        let txout = TxOut {
+            value: 100_000,
+            script_pubkey: Script::from(compiled.address.clone()),
+        };
+        let psbt = compiled
+            .export_psbts(OutPoint::default(), txout, &CTVAvailable)
+            .unwrap()
+            .remove(0)
+            .1;
+        let h = sha256::Hash::hash(&PREIMAGE);
+        // `key(1)` has signed, so `key(2)` isn't needed
+        match finalize(psbt.clone(), &emulator, &sign(&psbt, 1)).unwrap_err() {
+            FinalizeError::Unsatisfied { input, missing } => {
+                assert_eq!(input, 0);
+                assert_eq!(
+                    missing,
+                    vec![vec![
+                        Missing::Sha256Preimage(h),
+                        Missing::RelativeTimelock(10)
+                    ]]
+                );
+            }
+            e => panic!("unexpected error {}", e),
+        }
+        // either signature will do
+        let preimage = SatisfierInputs {
+            preimages: vec![PREIMAGE.to_vec()],
+            ..Default::default()
+        };
+        match finalize(psbt, &emulator, &preimage).unwrap_err() {
+            FinalizeError::Unsatisfied { missing, .. } => assert_eq!(
+                missing,
+                vec![
+                    vec![Missing::Signature(key(1)), Missing::RelativeTimelock(10)],
+                    vec![Missing::Signature(key(2)), Missing::RelativeTimelock(10)]
+                ]
+            ),
+            e => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn verify_finalized() {
+        let emulator = LocalEmulator::for_tests();
+        let compiled = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(LocalEmulator::for_tests()),
+            EffectPath::try_from("escrow").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .compile(Escrow)
+        .unwrap();
+        let txout = TxOut {
+            value: 100_000,
+            script_pubkey: Script::from(compiled.address.clone()),
+        };
+        let psbt = compiled
+            .export_psbts(OutPoint::default(), txout.clone(), &CTVAvailable)
+            .unwrap()
+            .remove(0)
+            .1;
+        let tx = finalize(psbt.clone(), &emulator, &sign(&psbt, 1)).unwrap();
+        let spent = [txout.clone()];
+        verify(&tx, &spent).unwrap();
+        assert!(matches!(
+            verify(&tx, &[]),
+            Err(FinalizeError::MissingUtxo(0))
+        ));
+        // the signatures no longer cover the outputs
+        let mut changed = tx.clone();
+        changed.output[0].value -= 1;
+        assert!(matches!(
+            verify(&changed, &spent),
+            Err(FinalizeError::Invalid { input: 0, .. })
+        ));
+        // nor the coin it spends
+        let other = [TxOut {
+            value: 100_001,
+            ..txout
+        }];
+        assert!(matches!(
+            verify(&tx, &other),
+            Err(FinalizeError::Invalid { input: 0, .. })
+        ));
+        let poor = [TxOut {
+            value: 1,
+            ..other[0].clone()
+        }];
+        assert!(matches!(
+            verify(&tx, &poor),
+            Err(FinalizeError::Overspends { .. })
+        ));
+    }
+
+    /// a hardware signer holding `key(seed)`, signing the leaves its origins
+    /// in a PSBT ask for, unless it can't sign script paths
+    struct Device {
+        seed: u8,
+        script_path: bool,
+        requests: RefCell<Vec<u8>>,
+    }
+    impl Device {
+        fn new(seed: u8) -> Self {
+            Device {
+                seed,
+                script_path: true,
+                requests: RefCell::new(vec![]),
+            }
+        }
+    }
+    impl ExternalSigner for Device {
+        fn fingerprint(&self) -> Fingerprint {
+            Fingerprint::from(&[self.seed; 4][..])
+        }
+        fn keys(&self) -> Result<Vec<(XOnlyPublicKey, DerivationPath)>, ExternalSignerError> {
+            Ok(vec![(key(self.seed), "m/86'/1'/0'/0/0".parse().unwrap())])
+        }
+        fn sign_psbt(
+            &self,
+            mut psbt: PartiallySignedTransaction,
+        ) -> Result<PartiallySignedTransaction, ExternalSignerError> {
+            self.requests.borrow_mut().push(self.seed);
+            if !self.script_path {
+                return Ok(psbt);
+            }
+            let secp = Secp256k1::new();
+            let tx = psbt.unsigned_tx.clone();
+            let prevouts: Vec<_> = psbt
+                .inputs
+                .iter()
+                .map(|i| i.witness_utxo.clone().unwrap())
+                .collect();
+            for (i, input) in psbt.inputs.iter_mut().enumerate() {
+                for (k, (leaves, (fingerprint, _))) in input.tap_key_origins.clone() {
+                    if k != key(self.seed) || fingerprint != self.fingerprint() {
+                        continue;
+                    }
+                    for leaf in leaves {
+                        let sighash = SighashCache::new(&tx)
+                            .taproot_script_spend_signature_hash(
+                                i,
+                                &Prevouts::All(&prevouts),
+                                leaf,
+                                SchnorrSighashType::Default,
+                            )
+                            .unwrap();
+                        let msg = Message::from_digest_slice(&sighash[..]).unwrap();
+                        let sig = SchnorrSig {
+                            sig: secp.sign_schnorr_no_aux_rand(&msg, &keypair(self.seed)),
+                            hash_ty: SchnorrSighashType::Default,
+                        };
+                        input.tap_script_sigs.insert((k, leaf), sig);
+                    }
+                }
+            }
+            Ok(psbt)
+        }
+    }
+
+    #[test]
+    fn finalize_with_mock_devices() {
+        let emulator = LocalEmulator::for_tests();
+        let compiled = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(LocalEmulator::for_tests()),
+            EffectPath::try_from("escrow").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .compile(Escrow)
+        .unwrap();
+        let txout = TxOut {
+            value: 100_000,
+            script_pubkey: Script::from(compiled.address.clone()),
+        };
+        let psbt = compiled
+            .export_psbts(OutPoint::default(), txout, &CTVAvailable)
+            .unwrap()
+            .remove(0)
+            .1;
+        let (first, second, stranger) = (Device::new(1), Device::new(2), Device::new(4));
+        let signers: [&dyn ExternalSigner; 3] = [&stranger, &second, &first];
+        // the leaf of the first branch
+        let leaf = psbt.inputs[0]
+            .tap_scripts
+            .values()
+            .find(|(s, _)| s.as_bytes().windows(32).any(|w| w == key(1).serialize()))
+            .map(|(s, v)| TapLeafHash::from_script(s, *v))
+            .unwrap();
+        let by_first: BTreeMap<_, _> = [(0, SpendPath::Leaf(leaf))].into();
+
+        // either device could sign, for its own branch
+        match request_signatures(psbt.clone(), &signers, &BTreeMap::new()) {
+            Err(ExternalSignerError::NoLeafChosen { input: 0, leaves }) => {
+                assert_eq!(leaves.len(), 2);
+                assert!(leaves.contains(&leaf));
+            }
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+        let tx = finalize_with_signers(
+            psbt.clone(),
+            &emulator,
+            &SatisfierInputs::default(),
+            &signers,
+            &by_first,
+        )
+        .unwrap();
+        // only the device with keys in the leaf chosen is asked, once
+        assert!(stranger.requests.borrow().is_empty());
+        assert!(second.requests.borrow().is_empty());
+        assert_eq!(*first.requests.borrow(), vec![1]);
+        assert_eq!(tx.txid(), psbt.unsigned_tx.txid());
+        // and it is asked for the leaf without choosing, if its key is in no
+        // other
+        let first = Device::new(1);
+        request_signatures(psbt.clone(), &[&first], &BTreeMap::new()).unwrap();
+        assert_eq!(*first.requests.borrow(), vec![1]);
+
+        // keys already signed for aren't requested again
+        let first = Device::new(1);
+        finalize_with_signers(
+            psbt.clone(),
+            &emulator,
+            &sign(&psbt, 1),
+            &[&first],
+            &by_first,
+        )
+        .unwrap();
+        assert!(first.requests.borrow().is_empty());
+
+        let unknown = [(0, SpendPath::Leaf(TapLeafHash::hash(&[])))].into();
+        let error = request_signatures(psbt.clone(), &[&first], &unknown).unwrap_err();
+        assert!(matches!(
+            error,
+            ExternalSignerError::UnknownLeaf { input: 0, .. }
+        ));
+
+        let mut blind = Device::new(1);
+        blind.script_path = false;
+        match request_signatures(psbt.clone(), &[&blind], &by_first) {
+            Err(ExternalSignerError::NotSigned {
+                fingerprint,
+                input,
+                key: k,
+            }) => {
+                assert_eq!(fingerprint, blind.fingerprint());
+                assert_eq!((input, k), (0, key(1)));
+            }
+            r => panic!("unexpected result {:?}", r.map(|_| ())),
+        }
+
+        let mut anyone = psbt;
+        anyone.inputs[0].sighash_type = Some(SchnorrSighashType::SinglePlusAnyoneCanPay.into());
+        let error = request_signatures(anyone, &[&Device::new(1)], &by_first).unwrap_err();
+        assert!(matches!(
+            error,
+            ExternalSignerError::UnsupportedSighash {
+                input: 0,
+                sighash: 0x83
+            }
+        ));
+    }
+}
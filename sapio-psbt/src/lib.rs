@@ -22,6 +22,8 @@ use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt::Display;
 pub mod external_api;
+pub mod external_signer;
+pub mod finalize;
 
 pub struct SigningKey(pub Vec<ExtendedPrivKey>);
 
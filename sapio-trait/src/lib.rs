@@ -23,6 +23,11 @@ pub trait SapioJSONTrait: JsonSchema + Serialize + for<'a> Deserialize<'a> {
         //             context: ContextualArguments {
         //                 amount: Amount::from_sat(0),
         //                 network: Network::Bitcoin,
+        //                 feerate: None,
+        //                 entropy_seed: None,
+        //                 tip_height: None,
+        //                 median_time: None,
+        //                 profile: false,
         //                 effects: Default::default(),
         //             },
         //         })
@@ -0,0 +1,60 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Derives a 10k deep chain of Contexts, counting the allocations each
+//! `derive` makes and checking that paths and effect lookups are unaffected.
+use bitcoin::util::amount::Amount;
+use criterion::{criterion_group, criterion_main, Criterion};
+use sapio::contract::Context;
+use sapio_base::effects::{EffectDB, EffectPath, MapEffectDB};
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+use common::allocations;
+
+const DEPTH: u64 = 10_000;
+
+fn root() -> Context {
+    Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("bench").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    )
+    .with_feerate(Some(Amount::from_sat(1)))
+}
+
+fn chain(mut ctx: Context) -> Context {
+    for i in 0..DEPTH {
+        ctx = ctx.derive_num(i).unwrap();
+    }
+    ctx
+}
+
+fn derivation_chain(c: &mut Criterion) {
+    let before = allocations();
+    let leaf = chain(root());
+    let made = allocations() - before;
+    println!(
+        "{} allocations deriving {} contexts ({:.2} per derive)",
+        made,
+        DEPTH,
+        made as f64 / DEPTH as f64
+    );
+    assert_eq!(leaf.path().iter().count() as u64, DEPTH + 1);
+    assert_eq!(leaf.feerate(), Some(Amount::from_sat(1)));
+    let effects = unsafe { leaf.get_effects_internal() };
+    assert_eq!(effects.get_value(leaf.path()).count(), 0);
+
+    c.bench_function("derive_10k_chain", |b| b.iter(|| chain(root())));
+}
+
+criterion_group!(benches, derivation_chain);
+criterion_main!(benches);
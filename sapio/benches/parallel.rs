@@ -0,0 +1,123 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compiles a tree of 31 contracts which each have a wide guard of 2-of-3
+//! multisigs, with and without `Context::with_parallel_script_compilation`,
+//! checking that both compile to the same `Object`.
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::util::amount::Amount;
+use bitcoin::XOnlyPublicKey;
+use criterion::{criterion_group, criterion_main, Criterion};
+use sapio::contract::{Compilable, Compiled, Contract};
+use sapio::*;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// the number of multisigs in each guard, each compiled as its own leaf
+const WIDTH: u32 = 16;
+
+fn key(seed: u32) -> XOnlyPublicKey {
+    let mut sk = [1u8; 32];
+    sk[28..].copy_from_slice(&seed.to_be_bytes());
+    let sk = SecretKey::from_slice(&sk).unwrap();
+    XOnlyPublicKey::from_keypair(&bitcoin::KeyPair::from_secret_key(&Secp256k1::new(), &sk)).0
+}
+
+/// `WIDTH` 2-of-3 multisigs, with keys from `first`
+fn wide_guard(first: u32) -> Clause {
+    Clause::Or(
+        (0..WIDTH)
+            .map(|i| {
+                let seed = (first * WIDTH + i) * 3;
+                let keys = (seed..seed + 3).map(|s| Clause::Key(key(s))).collect();
+                (1, Clause::Threshold(2, keys))
+            })
+            .collect(),
+    )
+}
+
+struct Leaf {
+    first: u32,
+}
+
+impl Leaf {
+    #[guard]
+    fn signed(self, _ctx: Context) {
+        wide_guard(self.first)
+    }
+}
+
+impl Contract for Leaf {
+    declare! {finish, Self::signed}
+    declare! {non updatable}
+}
+
+struct Tree {
+    depth: u32,
+    first: u32,
+}
+
+impl Tree {
+    #[guard]
+    fn signed(self, _ctx: Context) {
+        wide_guard(self.first)
+    }
+    #[then]
+    fn split(self, ctx: Context) {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mut builder = ctx.template();
+        for i in 0..2 {
+            let first = self.first * 2 + i + 1;
+            builder = if self.depth == 1 {
+                builder.add_output(half, &Leaf { first }, None)?
+            } else {
+                let depth = self.depth - 1;
+                builder.add_output(half, &Tree { depth, first }, None)?
+            };
+        }
+        builder.into()
+    }
+}
+
+impl Contract for Tree {
+    declare! {finish, Self::signed}
+    declare! {then, Self::split}
+    declare! {non updatable}
+}
+
+fn compile_wide(parallel: bool) -> Compiled {
+    let ctx = Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("bench").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    );
+    let ctx = if parallel {
+        ctx.with_parallel_script_compilation()
+    } else {
+        ctx
+    };
+    Tree { depth: 4, first: 0 }.compile(ctx).unwrap()
+}
+
+fn parallel_script_compilation(c: &mut Criterion) {
+    assert_eq!(
+        serde_json::to_string(&compile_wide(false)).unwrap(),
+        serde_json::to_string(&compile_wide(true)).unwrap()
+    );
+    let mut group = c.benchmark_group("31_wide_contracts");
+    group.sample_size(10);
+    group.bench_function("sequential", |b| b.iter(|| compile_wide(false)));
+    group.bench_function("parallel", |b| b.iter(|| compile_wide(true)));
+    group.finish();
+}
+
+criterion_group!(benches, parallel_script_compilation);
+criterion_main!(benches);
@@ -0,0 +1,117 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Compiles a 1024 leaf tree whose leaves each derive a key in their guard,
+//! comparing a fresh secp256k1 context per guard against `Context::secp`.
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::amount::Amount;
+use bitcoin::{KeyPair, XOnlyPublicKey};
+use criterion::{criterion_group, criterion_main, Criterion};
+use sapio::contract::{Compilable, Contract};
+use sapio::*;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+struct Leaf {
+    seed: u32,
+    shared: bool,
+}
+
+impl Leaf {
+    #[guard]
+    fn signed(self, ctx: Context) {
+        let fresh: Arc<Secp256k1<All>>;
+        let secp = if self.shared {
+            ctx.secp()
+        } else {
+            fresh = Arc::new(Secp256k1::new());
+            &fresh
+        };
+        let mut sk = [1u8; 32];
+        sk[28..].copy_from_slice(&self.seed.to_be_bytes());
+        let kp = KeyPair::from_seckey_slice(secp, &sk).unwrap();
+        Clause::Key(XOnlyPublicKey::from_keypair(&kp).0)
+    }
+}
+
+impl Contract for Leaf {
+    declare! {finish, Self::signed}
+    declare! {non updatable}
+}
+
+struct Tree {
+    depth: u32,
+    first: u32,
+    shared: bool,
+}
+
+impl Tree {
+    #[then]
+    fn split(self, ctx: Context) {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mut builder = ctx.template();
+        for i in 0..2 {
+            let first = self.first + i * (1 << (self.depth - 1));
+            builder = if self.depth == 1 {
+                builder.add_output(
+                    half,
+                    &Leaf {
+                        seed: first,
+                        shared: self.shared,
+                    },
+                    None,
+                )?
+            } else {
+                builder.add_output(
+                    half,
+                    &Tree {
+                        depth: self.depth - 1,
+                        first,
+                        shared: self.shared,
+                    },
+                    None,
+                )?
+            };
+        }
+        builder.into()
+    }
+}
+
+impl Contract for Tree {
+    declare! {then, Self::split}
+    declare! {non updatable}
+}
+
+fn compile_tree(shared: bool) {
+    let ctx = Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("bench").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    );
+    Tree {
+        depth: 10,
+        first: 0,
+        shared,
+    }
+    .compile(ctx)
+    .unwrap();
+}
+
+fn secp_contexts(c: &mut Criterion) {
+    let mut group = c.benchmark_group("1024_leaf_tree");
+    group.sample_size(10);
+    group.bench_function("fresh_secp_per_guard", |b| b.iter(|| compile_tree(false)));
+    group.bench_function("shared_context_secp", |b| b.iter(|| compile_tree(true)));
+    group.finish();
+}
+
+criterion_group!(benches, secp_contexts);
+criterion_main!(benches);
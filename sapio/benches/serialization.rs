@@ -0,0 +1,121 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Serializes a compiled tree of 4^6 leaves as JSON and as CBOR, printing
+//! their sizes and checking both parse back to the same `Object`.
+use bitcoin::secp256k1::{Secp256k1, SecretKey};
+use bitcoin::util::amount::Amount;
+use bitcoin::XOnlyPublicKey;
+use criterion::{criterion_group, criterion_main, Criterion};
+use sapio::contract::{Compilable, Compiled, Contract};
+use sapio::*;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+const FANOUT: u32 = 4;
+const DEPTH: u32 = 6;
+
+fn key(seed: u32) -> XOnlyPublicKey {
+    let mut sk = [1u8; 32];
+    sk[28..].copy_from_slice(&seed.to_be_bytes());
+    let sk = SecretKey::from_slice(&sk).unwrap();
+    XOnlyPublicKey::from_keypair(&bitcoin::KeyPair::from_secret_key(&Secp256k1::new(), &sk)).0
+}
+
+struct Leaf {
+    seed: u32,
+}
+
+impl Leaf {
+    #[guard]
+    fn signed(self, _ctx: Context) {
+        Clause::Key(key(self.seed))
+    }
+}
+
+impl Contract for Leaf {
+    declare! {finish, Self::signed}
+    declare! {non updatable}
+}
+
+struct Tree {
+    depth: u32,
+    first: u32,
+}
+
+impl Tree {
+    #[then]
+    fn split(self, ctx: Context) {
+        let part = Amount::from_sat(ctx.funds().as_sat() / FANOUT as u64);
+        let mut builder = ctx.template();
+        for i in 0..FANOUT {
+            let first = self.first * FANOUT + i + 1;
+            builder = if self.depth == 1 {
+                builder.add_output(part, &Leaf { seed: first }, None)?
+            } else {
+                let depth = self.depth - 1;
+                builder.add_output(part, &Tree { depth, first }, None)?
+            };
+        }
+        builder.into()
+    }
+}
+
+impl Contract for Tree {
+    declare! {then, Self::split}
+    declare! {non updatable}
+}
+
+fn compile_tree() -> Compiled {
+    let ctx = Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("bench").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    );
+    Tree {
+        depth: DEPTH,
+        first: 0,
+    }
+    .compile(ctx)
+    .unwrap()
+}
+
+fn serialization(c: &mut Criterion) {
+    let compiled = compile_tree();
+    let json = serde_json::to_vec(&compiled).unwrap();
+    let cbor = compiled.to_cbor().unwrap();
+    println!(
+        "{} bytes of JSON, {} bytes of CBOR ({:.0}%)",
+        json.len(),
+        cbor.len(),
+        100.0 * cbor.len() as f64 / json.len() as f64
+    );
+    let from_cbor = Compiled::from_cbor(&cbor).unwrap();
+    assert_eq!(serde_json::to_vec(&from_cbor).unwrap(), json);
+    assert_eq!(from_cbor.to_cbor().unwrap(), cbor);
+
+    let mut group = c.benchmark_group("4096_leaf_tree");
+    group.sample_size(10);
+    group.bench_function("to_json", |b| {
+        b.iter(|| serde_json::to_vec(&compiled).unwrap())
+    });
+    group.bench_function("to_cbor", |b| b.iter(|| compiled.to_cbor().unwrap()));
+    group.bench_function("from_json", |b| {
+        b.iter(|| serde_json::from_slice::<Compiled>(&json).unwrap())
+    });
+    group.bench_function("from_cbor", |b| {
+        b.iter(|| Compiled::from_cbor(&cbor).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, serialization);
+criterion_main!(benches);
@@ -0,0 +1,436 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! What changed between two compilations of a contract, e.g. before signing
+//! off on a recompilation after upgrading a plugin or changing an argument
+use super::Nodes;
+use crate::contract::object::Object;
+use crate::template::Template;
+use crate::util::extended_address::ExtendedAddress;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// What kind of node of a contract's tree a change is to
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    /// a contract, or the address an output pays
+    Object,
+    /// a template of a contract
+    Template,
+}
+
+/// A field of a node which changed
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Field {
+    /// the object's address
+    Address,
+    /// the amounts the object accepts
+    AmountRange,
+    /// the object's continuation points
+    Continuations,
+    /// the template's CTV hash
+    CtvHash,
+    /// the amount the template sends
+    Amount,
+    /// the amount of each of the template's outputs
+    Outputs,
+    /// the template's nLockTime
+    LockTime,
+    /// the nSequence of each of the template's inputs
+    Sequences,
+    /// the clauses guarding the template
+    Guards,
+    /// the template's label
+    Label,
+}
+
+impl Field {
+    fn describe(&self) -> &'static str {
+        match self {
+            Field::Address => "address",
+            Field::AmountRange => "amount range",
+            Field::Continuations => "continuations",
+            Field::CtvHash => "CTV hash",
+            Field::Amount => "amount",
+            Field::Outputs => "output amounts",
+            Field::LockTime => "lock time",
+            Field::Sequences => "sequences",
+            Field::Guards => "guards",
+            Field::Label => "label",
+        }
+    }
+}
+
+/// A change to one node, identified by its path.
+///
+/// Objects are identified by their path, or if they have none, e.g. an
+/// address, by their template's and their output index. Templates are
+/// identified by their object's and the branch which created them, or their
+/// hash if none did.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "change", rename_all = "snake_case")]
+pub enum Change {
+    /// a node only in the new contract
+    Added {
+        /// the node's kind
+        kind: NodeKind,
+        /// the node's path
+        path: String,
+    },
+    /// a node only in the old contract
+    Removed {
+        /// the node's kind
+        kind: NodeKind,
+        /// the node's path
+        path: String,
+    },
+    /// a field of a node in both contracts which differs
+    Changed {
+        /// the node's kind
+        kind: NodeKind,
+        /// the node's path
+        path: String,
+        /// what changed
+        field: Field,
+        /// the field in the old contract
+        old: String,
+        /// the field in the new contract
+        new: String,
+    },
+}
+
+/// The changes between two compiled contracts, see `diff`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Default)]
+pub struct ContractDiff {
+    /// each change, ordered by path
+    pub changes: Vec<Change>,
+}
+
+fn plural(n: usize, kind: NodeKind) -> String {
+    let kind = match kind {
+        NodeKind::Object => "object",
+        NodeKind::Template => "template",
+    };
+    format!("{} {}{}", n, kind, if n == 1 { "" } else { "s" })
+}
+
+impl ContractDiff {
+    /// if the contracts are the same
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+    /// How many nodes of each kind were added, removed or changed each field,
+    /// e.g. "3 templates changed CTV hash"
+    pub fn summary(&self) -> Vec<String> {
+        let mut added = BTreeMap::<NodeKind, usize>::new();
+        let mut removed = BTreeMap::<NodeKind, usize>::new();
+        let mut changed = BTreeMap::<(NodeKind, Field), usize>::new();
+        for change in self.changes.iter() {
+            match change {
+                Change::Added { kind, .. } => *added.entry(*kind).or_default() += 1,
+                Change::Removed { kind, .. } => *removed.entry(*kind).or_default() += 1,
+                Change::Changed { kind, field, .. } => {
+                    *changed.entry((*kind, *field)).or_default() += 1
+                }
+            }
+        }
+        added
+            .into_iter()
+            .map(|(kind, n)| format!("{} added", plural(n, kind)))
+            .chain(
+                removed
+                    .into_iter()
+                    .map(|(kind, n)| format!("{} removed", plural(n, kind))),
+            )
+            .chain(changed.into_iter().map(|((kind, field), n)| {
+                format!("{} changed {}", plural(n, kind), field.describe())
+            }))
+            .collect()
+    }
+    /// Each change on a line, then the summary, with removals in red,
+    /// additions in green and changes in yellow if `color`
+    pub fn render(&self, color: bool) -> String {
+        let paint = |code: &str, s: String| {
+            if color {
+                format!("\x1b[{}m{}\x1b[0m", code, s)
+            } else {
+                s
+            }
+        };
+        let mut s = String::new();
+        for change in self.changes.iter() {
+            let line = match change {
+                Change::Added { kind, path } => paint("32", format!("+ {:?} {}", kind, path)),
+                Change::Removed { kind, path } => paint("31", format!("- {:?} {}", kind, path)),
+                Change::Changed {
+                    kind,
+                    path,
+                    field,
+                    old,
+                    new,
+                } => format!(
+                    "{} {}: {} -> {}",
+                    paint("33", format!("~ {:?} {}", kind, path)),
+                    field.describe(),
+                    paint("31", old.clone()),
+                    paint("32", new.clone())
+                ),
+            };
+            let _ = writeln!(s, "{}", line);
+        }
+        if self.is_empty() {
+            s.push_str("no changes\n");
+        } else {
+            s.push('\n');
+            for line in self.summary() {
+                let _ = writeln!(s, "{}", line);
+            }
+        }
+        s
+    }
+}
+
+/// `a` as text: its address, descriptor or script
+pub(crate) fn address(a: &ExtendedAddress) -> String {
+    match a {
+        ExtendedAddress::Address(a) => a.to_string(),
+        ExtendedAddress::Descriptor(d) => d.to_string(),
+        _ => bitcoin::Script::from(a.clone()).asm(),
+    }
+}
+
+fn object_fields(o: &Object) -> Vec<(Field, String)> {
+    let range = o.amount_range;
+    vec![
+        (Field::Address, address(&o.address)),
+        (
+            Field::AmountRange,
+            format!(
+                "{}..{} sats",
+                range.min_bound().map_or(0, |a| a.as_sat()),
+                range.max_bound().map_or(u64::MAX, |a| a.as_sat())
+            ),
+        ),
+        (
+            Field::Continuations,
+            o.continue_apis
+                .keys()
+                .map(|p| String::from(p.0.as_ref().clone()))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+    ]
+}
+
+fn template_fields(t: &Template) -> Vec<(Field, String)> {
+    vec![
+        (Field::CtvHash, t.ctv.to_string()),
+        (Field::Amount, format!("{} sats", t.total_amount().as_sat())),
+        (
+            Field::Outputs,
+            t.outputs
+                .iter()
+                .map(|o| o.amount.as_sat().to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        (Field::LockTime, t.tx.lock_time.to_string()),
+        (
+            Field::Sequences,
+            t.tx.input
+                .iter()
+                .map(|i| format!("{:#x}", i.sequence))
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        (
+            Field::Guards,
+            t.guards
+                .iter()
+                .map(|g| g.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+        ),
+        (
+            Field::Label,
+            t.metadata_map_s2s.label.clone().unwrap_or_default(),
+        ),
+    ]
+}
+
+/// the changes between the nodes `old` and `new` of one kind
+fn diff_nodes<T>(
+    kind: NodeKind,
+    old: &BTreeMap<String, &T>,
+    new: &BTreeMap<String, &T>,
+    fields: fn(&T) -> Vec<(Field, String)>,
+    changes: &mut Vec<Change>,
+) {
+    for (path, o) in old.iter() {
+        match new.get(path) {
+            None => changes.push(Change::Removed {
+                kind,
+                path: path.clone(),
+            }),
+            Some(n) => {
+                for ((field, old), (_, new)) in fields(o).into_iter().zip(fields(n)) {
+                    if old != new {
+                        changes.push(Change::Changed {
+                            kind,
+                            path: path.clone(),
+                            field,
+                            old,
+                            new,
+                        })
+                    }
+                }
+            }
+        }
+    }
+    for path in new.keys().filter(|p| !old.contains_key(*p)) {
+        changes.push(Change::Added {
+            kind,
+            path: path.clone(),
+        })
+    }
+}
+
+/// The changes from `old` to `new`, matching their nodes by path, so a node
+/// whose path changed is removed and added.
+pub fn diff(old: &Object, new: &Object) -> ContractDiff {
+    let [old_nodes, new_nodes] = [Nodes::of(old), Nodes::of(new)];
+    let mut changes = vec![];
+    diff_nodes(
+        NodeKind::Object,
+        &old_nodes.objects,
+        &new_nodes.objects,
+        object_fields,
+        &mut changes,
+    );
+    diff_nodes(
+        NodeKind::Template,
+        &old_nodes.templates,
+        &new_nodes.templates,
+        template_fields,
+        &mut changes,
+    );
+    changes.sort_by(|a, b| path(a).cmp(path(b)));
+    ContractDiff { changes }
+}
+
+fn path(c: &Change) -> &String {
+    match c {
+        Change::Added { path, .. }
+        | Change::Removed { path, .. }
+        | Change::Changed { path, .. } => path,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::abi::object::graph::test::{nft_sale, SimpleNftSale};
+    use crate::contract::Context;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("diff").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    #[test]
+    fn nft_sale_royalty_diff() {
+        let old = ctx().compile(nft_sale()).unwrap();
+        assert!(diff(&old, &old).is_empty());
+        let new = ctx()
+            .compile(SimpleNftSale {
+                royalty_percent: 20,
+                ..nft_sale()
+            })
+            .unwrap();
+        let d = diff(&old, &new);
+        let changed: Vec<_> = d
+            .changes
+            .iter()
+            .filter_map(|c| match c {
+                Change::Changed {
+                    kind, path, field, ..
+                } => Some((*kind, path.as_str(), *field)),
+                _ => None,
+            })
+            .collect();
+        // the payouts change, and with them the template and the object
+        // committing to it, but the resold NFT doesn't
+        assert_eq!(
+            changed,
+            [
+                (NodeKind::Object, "diff", Field::Address),
+                (NodeKind::Template, "diff/transfer", Field::CtvHash),
+                (NodeKind::Template, "diff/transfer", Field::Outputs),
+                (NodeKind::Object, "diff/transfer/#1", Field::AmountRange),
+                (NodeKind::Object, "diff/transfer/#2", Field::AmountRange),
+            ]
+        );
+        assert!(d.changes.contains(&Change::Changed {
+            kind: NodeKind::Template,
+            path: "diff/transfer".into(),
+            field: Field::Outputs,
+            old: "100000, 45000, 5000".into(),
+            new: "100000, 40000, 10000".into(),
+        }));
+        // the artist's and seller's addresses are unchanged
+        assert!(d
+            .changes
+            .iter()
+            .all(|c| matches!(c, Change::Changed { .. })));
+        assert_eq!(
+            d.summary(),
+            [
+                "1 object changed address",
+                "2 objects changed amount range",
+                "1 template changed CTV hash",
+                "1 template changed output amounts"
+            ]
+        );
+        assert!(!d.render(false).contains('\x1b'));
+        assert!(d
+            .render(true)
+            .contains("\x1b[31m100000, 45000, 5000\x1b[0m"));
+        let json = serde_json::to_value(&d).unwrap();
+        assert_eq!(json["changes"][0]["change"], "changed");
+        assert_eq!(json["changes"][0]["field"], "address");
+        // renaming the branch removes its template and adds another
+        let mut renamed = new.clone();
+        let hashes = renamed.branches.remove("transfer").unwrap();
+        renamed.branches.insert("sell".into(), hashes);
+        let d = diff(&new, &renamed);
+        assert!(d.changes.contains(&Change::Removed {
+            kind: NodeKind::Template,
+            path: "diff/transfer".into()
+        }));
+        assert!(d.changes.contains(&Change::Added {
+            kind: NodeKind::Template,
+            path: "diff/sell".into()
+        }));
+    }
+}
@@ -0,0 +1,527 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Every key which can influence the funds at each node of a compiled
+//! contract, for auditing which parties a contract trusts
+use super::{absolute_lock, describe_lock, relative_lock, Nodes};
+use crate::contract::object::{InternalKeySource, Object, SupportedDescriptors};
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::XOnlyPublicKey;
+use miniscript::descriptor::WshInner;
+use miniscript::{Descriptor, Miniscript, MiniscriptKey, ScriptContext, Terminal};
+use sapio_base::timelocks::AnyTimeLock;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+
+/// How a key is used in a spending condition
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(tag = "role", rename_all = "snake_case")]
+pub enum KeyRole {
+    /// the key's signature alone satisfies the condition, with any timelocks
+    Single,
+    /// the key is one of `n` members of a threshold needing `k` of them
+    Threshold {
+        /// the members needed
+        k: usize,
+        /// the members
+        n: usize,
+    },
+    /// the key is an emulator's oracle, signing for templates in place of
+    /// `OP_CHECKTEMPLATEVERIFY`, which the contract trusts not to sign
+    /// anything else
+    Emulator,
+}
+
+impl std::fmt::Display for KeyRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyRole::Single => write!(f, "single sig"),
+            KeyRole::Threshold { k, n } => write!(f, "{}-of-{}", k, n),
+            KeyRole::Emulator => write!(f, "emulator"),
+        }
+    }
+}
+
+/// A threshold of conditions, of which a key's use is one member
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Threshold {
+    /// the members needed
+    pub k: usize,
+    /// the members
+    pub n: usize,
+}
+
+/// One way a key can spend a node
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct KeyUse {
+    /// how the key is used
+    #[serde(flatten)]
+    pub role: KeyRole,
+    /// the timelocks which must pass before the key can spend this way
+    pub timelocks: Vec<AnyTimeLock>,
+    /// the thresholds this use is nested in as one member, outermost first,
+    /// e.g. a 2-of-3 which is one of the members of another 2-of-3
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub within: Vec<Threshold>,
+}
+
+impl KeyUse {
+    /// the role, and the thresholds it's nested in
+    fn describe(&self) -> String {
+        let mut s = self.role.to_string();
+        for t in self.within.iter().rev() {
+            let _ = write!(s, " within {}-of-{}", t.k, t.n);
+        }
+        s
+    }
+}
+
+/// The keys of every node of a contract, see `Object::key_report`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Default)]
+pub struct KeyReport {
+    /// the ways each key can spend each node with keys, by the node's path
+    #[schemars(with = "BTreeMap<String, BTreeMap<String, Vec<KeyUse>>>")]
+    pub paths: BTreeMap<String, BTreeMap<XOnlyPublicKey, Vec<KeyUse>>>,
+}
+
+impl KeyReport {
+    /// every key in the contract
+    pub fn keys(&self) -> BTreeSet<XOnlyPublicKey> {
+        self.paths
+            .values()
+            .flat_map(|k| k.keys().copied())
+            .collect()
+    }
+    /// A table of each way each key can spend each node
+    pub fn render(&self) -> String {
+        let rows: Vec<[String; 4]> = self
+            .paths
+            .iter()
+            .flat_map(|(path, keys)| {
+                keys.iter().flat_map(move |(key, uses)| {
+                    uses.iter().map(move |u| {
+                        [
+                            path.clone(),
+                            key.to_string(),
+                            u.describe(),
+                            u.timelocks
+                                .iter()
+                                .map(describe_lock)
+                                .collect::<Vec<_>>()
+                                .join(", "),
+                        ]
+                    })
+                })
+            })
+            .collect();
+        let header = ["PATH", "KEY", "ROLE", "TIMELOCKS"].map(String::from);
+        let mut widths = [0; 4];
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            for (w, cell) in widths.iter_mut().zip(row.iter()) {
+                *w = (*w).max(cell.chars().count());
+            }
+        }
+        let mut s = String::new();
+        for row in std::iter::once(&header).chain(rows.iter()) {
+            let line = row
+                .iter()
+                .zip(widths.iter())
+                .map(|(cell, w)| format!("{:w$}", cell, w = w))
+                .collect::<Vec<_>>()
+                .join("  ");
+            let _ = writeln!(s, "{}", line.trim_end());
+        }
+        s
+    }
+}
+
+/// the keys of a descriptor, as x-only keys
+trait XOnly: MiniscriptKey {
+    fn x_only(&self) -> XOnlyPublicKey;
+}
+impl XOnly for XOnlyPublicKey {
+    fn x_only(&self) -> XOnlyPublicKey {
+        *self
+    }
+}
+impl XOnly for bitcoin::PublicKey {
+    fn x_only(&self) -> XOnlyPublicKey {
+        self.inner.into()
+    }
+}
+
+/// A spending condition, keeping only its keys and timelocks
+pub(crate) enum Condition {
+    Key(XOnlyPublicKey),
+    Lock(AnyTimeLock),
+    /// a hash lock, a template, or a constant
+    Other,
+    /// `k` of the conditions, with nested thresholds of the same kind, all
+    /// or any, merged into this one
+    Threshold(usize, Vec<Condition>),
+}
+
+impl Condition {
+    fn threshold(k: usize, subs: Vec<Condition>) -> Condition {
+        let all = k == subs.len();
+        let subs = subs
+            .into_iter()
+            .flat_map(|c| match c {
+                Condition::Threshold(j, inner)
+                    if (all && j == inner.len()) || (k == 1 && j == 1) =>
+                {
+                    inner
+                }
+                c => vec![c],
+            })
+            .collect::<Vec<_>>();
+        let k = if all { subs.len() } else { k };
+        Condition::Threshold(k, subs)
+    }
+
+    fn of<Pk: XOnly, Ctx: ScriptContext>(ms: &Miniscript<Pk, Ctx>) -> Condition {
+        match &ms.node {
+            Terminal::PkK(k) => Condition::Key(k.x_only()),
+            Terminal::After(n) => absolute_lock(*n).map_or(Condition::Other, Condition::Lock),
+            Terminal::Older(n) => relative_lock(*n).map_or(Condition::Other, Condition::Lock),
+            Terminal::Alt(a)
+            | Terminal::Swap(a)
+            | Terminal::Check(a)
+            | Terminal::DupIf(a)
+            | Terminal::Verify(a)
+            | Terminal::NonZero(a)
+            | Terminal::ZeroNotEqual(a) => Condition::of(a),
+            Terminal::AndV(a, b) | Terminal::AndB(a, b) => {
+                Condition::threshold(2, vec![Condition::of(a), Condition::of(b)])
+            }
+            Terminal::AndOr(a, b, c) => Condition::threshold(
+                1,
+                vec![
+                    Condition::threshold(2, vec![Condition::of(a), Condition::of(b)]),
+                    Condition::of(c),
+                ],
+            ),
+            Terminal::OrB(a, b)
+            | Terminal::OrD(a, b)
+            | Terminal::OrC(a, b)
+            | Terminal::OrI(a, b) => {
+                Condition::threshold(1, vec![Condition::of(a), Condition::of(b)])
+            }
+            Terminal::Thresh(k, subs) => {
+                Condition::threshold(*k, subs.iter().map(|s| Condition::of(s)).collect())
+            }
+            Terminal::Multi(k, keys) | Terminal::MultiA(k, keys) => Condition::threshold(
+                *k,
+                keys.iter().map(|k| Condition::Key(k.x_only())).collect(),
+            ),
+            _ => Condition::Other,
+        }
+    }
+
+    /// add the uses of each key, needing `role` of its threshold, `within`
+    /// the thresholds above and after `timelocks` of the conditions above, to
+    /// `uses`. The `emulator` keys stand in for templates, so they don't
+    /// count as other members.
+    fn uses(
+        &self,
+        role: KeyRole,
+        timelocks: &[AnyTimeLock],
+        within: &[Threshold],
+        emulator: &BTreeSet<XOnlyPublicKey>,
+        uses: &mut BTreeMap<XOnlyPublicKey, Vec<KeyUse>>,
+    ) {
+        match self {
+            Condition::Key(key) => {
+                let u = KeyUse {
+                    role: if emulator.contains(key) {
+                        KeyRole::Emulator
+                    } else {
+                        role
+                    },
+                    timelocks: timelocks.to_vec(),
+                    within: within.to_vec(),
+                };
+                let all = uses.entry(*key).or_default();
+                if !all.contains(&u) {
+                    all.push(u)
+                }
+            }
+            Condition::Threshold(k, subs) => {
+                let all = *k == subs.len();
+                let mut timelocks = timelocks.to_vec();
+                if all {
+                    timelocks.extend(subs.iter().filter_map(|c| match c {
+                        Condition::Lock(l) => Some(*l),
+                        _ => None,
+                    }));
+                }
+                // the signers, and the nested thresholds of them
+                let members = subs
+                    .iter()
+                    .filter(|c| match c {
+                        Condition::Key(k) => !emulator.contains(k),
+                        Condition::Threshold(..) => true,
+                        _ => false,
+                    })
+                    .count();
+                let threshold = if *k == 1 || (all && members <= 1) {
+                    None
+                } else if all {
+                    // every member, whatever else is needed
+                    Some(Threshold {
+                        k: members,
+                        n: members,
+                    })
+                } else {
+                    Some(Threshold {
+                        k: *k,
+                        n: subs.len(),
+                    })
+                };
+                let role = threshold.map_or(KeyRole::Single, |Threshold { k, n }| {
+                    KeyRole::Threshold { k, n }
+                });
+                let nested: Vec<_> = within.iter().copied().chain(threshold).collect();
+                for c in subs {
+                    match c {
+                        Condition::Threshold(..) => {
+                            c.uses(role, &timelocks, &nested, emulator, uses)
+                        }
+                        _ => c.uses(role, &timelocks, within, emulator, uses),
+                    }
+                }
+            }
+            Condition::Lock(_) | Condition::Other => {}
+        }
+    }
+}
+
+/// the conditions `object`'s descriptor can be spent with
+pub(crate) fn conditions(object: &Object) -> Vec<Condition> {
+    fn of_script<Pk: XOnly>(d: &Descriptor<Pk>, object: &Object) -> Vec<Condition> {
+        match d {
+            Descriptor::Tr(tr) => {
+                let unspendable = matches!(
+                    object.internal_key,
+                    Some(k) if k.source == InternalKeySource::Unspendable
+                );
+                (!unspendable)
+                    .then(|| Condition::Key(tr.internal_key().x_only()))
+                    .into_iter()
+                    .chain(tr.iter_scripts().map(|(_, ms)| Condition::of(ms)))
+                    .collect()
+            }
+            Descriptor::Wsh(wsh) => match wsh.as_inner() {
+                WshInner::Ms(ms) => vec![Condition::of(ms)],
+                WshInner::SortedMulti(m) => vec![Condition::threshold(
+                    m.k,
+                    m.pks.iter().map(|k| Condition::Key(k.x_only())).collect(),
+                )],
+            },
+            Descriptor::Wpkh(w) => vec![Condition::Key(w.as_inner().x_only())],
+            Descriptor::Pkh(p) => vec![Condition::Key(p.as_inner().x_only())],
+            _ => vec![],
+        }
+    }
+    match (&object.descriptor, &object.address) {
+        (Some(SupportedDescriptors::Pk(d)), _) => of_script(d, object),
+        (Some(SupportedDescriptors::XOnly(d)), _) => of_script(d, object),
+        // e.g. a key's address, with its output key
+        (None, ExtendedAddress::Address(a)) => {
+            let script = a.script_pubkey();
+            script
+                .is_v1_p2tr()
+                .then(|| XOnlyPublicKey::from_slice(&script[2..]).ok())
+                .flatten()
+                .map(Condition::Key)
+                .into_iter()
+                .collect()
+        }
+        (None, _) => vec![],
+    }
+}
+
+impl Object {
+    /// Every key which can spend each node of this object's tree, how it is
+    /// used and the timelocks gating it, for auditing, by the nodes' paths as
+    /// in `analysis::diff`.
+    ///
+    /// Keys are found in the compiled descriptors, or for a node known only
+    /// by a taproot address, its output key. The keys an emulator signs templates with are
+    /// reported as `KeyRole::Emulator` wherever they appear.
+    pub fn key_report(&self) -> KeyReport {
+        let nodes = Nodes::of(self);
+        let emulator: BTreeSet<_> = nodes
+            .objects
+            .values()
+            .flat_map(|o| o.emulator_keys.iter().copied())
+            .collect();
+        let mut paths = BTreeMap::new();
+        for (path, object) in nodes.objects {
+            let mut uses = BTreeMap::new();
+            for c in conditions(object) {
+                c.uses(KeyRole::Single, &[], &[], &emulator, &mut uses);
+            }
+            if !uses.is_empty() {
+                paths.insert(path, uses);
+            }
+        }
+        KeyReport { paths }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{Compilable, Context, Contract, TxTmplIt};
+    use bitcoin::hashes::sha256;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::{CTVAvailable, CTVEmulator, EmulatorError};
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("keys").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    /// an emulator co-signing with `member(8)`
+    struct Oracle;
+    impl CTVEmulator for Oracle {
+        fn get_signer_for(&self, _h: sha256::Hash) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Key(member(8)))
+        }
+        fn sign(
+            &self,
+            b: bitcoin::util::psbt::PartiallySignedTransaction,
+        ) -> Result<bitcoin::util::psbt::PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+    }
+    fn locked<'a, T>(
+        name: &str,
+        guard: GuardList<'a, T>,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard,
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new(name.into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn pay_to(ctx: Context, key: XOnlyPublicKey) -> TxTmplIt {
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        let builder = ctx.template().add_output(amt, &key, None)?;
+        let rest = builder.ctx().funds();
+        builder.add_fees(rest)?.into()
+    }
+    fn member(i: u8) -> XOnlyPublicKey {
+        let key =
+            bitcoin::KeyPair::from_seckey_slice(&bitcoin::secp256k1::Secp256k1::new(), &[i; 32])
+                .unwrap();
+        XOnlyPublicKey::from_keypair(&key).0
+    }
+    fn committee<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| {
+                let k = |i| Clause::Key(member(i));
+                Clause::Threshold(
+                    2,
+                    vec![k(1), k(2), Clause::Threshold(2, vec![k(3), k(4), k(5)])],
+                )
+            }),
+            None,
+        ))
+    }
+    fn recovery<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| Clause::And(vec![Clause::Key(member(6)), Clause::Older(144)])),
+            None,
+        ))
+    }
+    fn pay_committee<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        pay_to(ctx, member(7))
+    }
+    struct Committee;
+    impl Committee {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("spend", &[GuardGen::Fn(committee)], pay_committee)
+        }
+        fn recover<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("recover", &[GuardGen::Fn(recovery)], pay_committee)
+        }
+    }
+    impl Contract for Committee {
+        declare! {then, Self::spend, Self::recover}
+        declare! {non updatable}
+    }
+    #[test]
+    fn committee_key_report() {
+        use sapio_base::timelocks::RelHeight;
+        let oracle = Arc::new(Oracle);
+        let compiled = Committee.compile(ctx().with_emulator(oracle)).unwrap();
+        let report = compiled.key_report();
+        let root = &report.paths["keys"];
+        let members = KeyUse {
+            role: KeyRole::Threshold { k: 2, n: 3 },
+            timelocks: vec![],
+            within: vec![],
+        };
+        for i in 1..=2 {
+            assert_eq!(root[&member(i)], std::slice::from_ref(&members));
+        }
+        // the inner committee is one member of the outer one
+        let inner = KeyUse {
+            within: vec![Threshold { k: 2, n: 3 }],
+            ..members
+        };
+        for i in 3..=5 {
+            assert_eq!(root[&member(i)], std::slice::from_ref(&inner));
+        }
+        assert_eq!(
+            root[&member(6)],
+            [KeyUse {
+                role: KeyRole::Single,
+                timelocks: vec![AnyTimeLock::R(RelHeight::from(144).into())],
+                within: vec![],
+            }]
+        );
+        // the oracle co-signs both branches, so it is tagged in each
+        assert_eq!(root[&member(8)].len(), 2);
+        assert!(root[&member(8)].iter().all(|u| u.role == KeyRole::Emulator));
+        assert_eq!(root.len(), 7);
+        let payee = &report.paths["keys/recover/#0"];
+        assert_eq!(payee[&member(7)][0].role, KeyRole::Single);
+        assert_eq!(report.keys().len(), 8);
+        let table = report.render();
+        assert!(table.starts_with("PATH"));
+        let recovery = table
+            .lines()
+            .find(|l| l.contains(&member(6).to_string()))
+            .unwrap();
+        assert!(recovery.starts_with("keys "));
+        let role = format!("{:20}", "single sig");
+        assert!(recovery.ends_with(&format!("{}  144 blocks after confirmation", role)));
+        assert!(table.contains(&format!("{}  2-of-3 within 2-of-3", member(3))));
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The least and most each node of a compiled contract can end up holding,
+//! across every choice of branches
+use super::diff::address;
+use super::Nodes;
+use crate::contract::object::Object;
+use bitcoin::util::amount::Amount;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The amounts a node can hold
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Liquidity {
+    /// the least
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub min: Amount,
+    /// the most
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    #[schemars(with = "u64")]
+    pub max: Amount,
+    /// if the least is below the dust limit for the node's script, so some
+    /// branches leave it unspendable
+    pub dust: bool,
+}
+
+impl Liquidity {
+    fn include(&mut self, amount: Amount) {
+        self.min = self.min.min(amount);
+        self.max = self.max.max(amount);
+    }
+}
+
+/// The amounts each node of a contract can hold, see
+/// `Object::liquidity_report`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Default)]
+pub struct LiquidityReport {
+    /// each node's amounts, by its path
+    pub paths: BTreeMap<String, Liquidity>,
+    /// the amounts paid to each address the tree ends at, across every node
+    /// paying it
+    pub leaves: BTreeMap<String, Liquidity>,
+}
+
+impl LiquidityReport {
+    /// A table of the amounts of each node and then each leaf address, marking
+    /// those which may be dust
+    pub fn render(&self) -> String {
+        let row = |name: &String, l: &Liquidity| {
+            [
+                name.clone(),
+                l.min.as_sat().to_string(),
+                l.max.as_sat().to_string(),
+                if l.dust { "dust" } else { "" }.to_string(),
+            ]
+        };
+        let mut s = String::new();
+        for (title, table) in [("PATH", &self.paths), ("LEAF", &self.leaves)] {
+            let header = [title, "MIN", "MAX", ""].map(String::from);
+            let rows: Vec<_> = table.iter().map(|(n, l)| row(n, l)).collect();
+            let mut widths = [0; 4];
+            for r in std::iter::once(&header).chain(rows.iter()) {
+                for (w, cell) in widths.iter_mut().zip(r.iter()) {
+                    *w = (*w).max(cell.chars().count());
+                }
+            }
+            if !s.is_empty() {
+                s.push('\n');
+            }
+            for r in std::iter::once(&header).chain(rows.iter()) {
+                let line = format!(
+                    "{:w0$}  {:>w1$}  {:>w2$}  {}",
+                    r[0],
+                    r[1],
+                    r[2],
+                    r[3],
+                    w0 = widths[0],
+                    w1 = widths[1],
+                    w2 = widths[2]
+                );
+                let _ = writeln!(s, "{}", line.trim_end());
+            }
+        }
+        s
+    }
+}
+
+impl Object {
+    /// The least and most each node of this object's tree can hold, across
+    /// every choice of branches, by the nodes' paths as in `analysis::diff`,
+    /// and for each address the tree ends at, across every node paying it.
+    ///
+    /// The root holds what its `AmountRange` accepts, and every other node
+    /// what the outputs paying it do. Leaves whose least is below the dust
+    /// limit for their script are flagged, except OP_RETURNs.
+    pub fn liquidity_report(&self) -> LiquidityReport {
+        let nodes = Nodes::of(self);
+        let mut paths = BTreeMap::<String, Liquidity>::new();
+        let mut include = |path: &String, amount: Amount| {
+            paths
+                .entry(path.clone())
+                .and_modify(|l| l.include(amount))
+                .or_insert(Liquidity {
+                    min: amount,
+                    max: amount,
+                    dust: false,
+                });
+        };
+        let root = String::from(self.root_path.0.as_ref().clone());
+        let range = self.amount_range;
+        include(&root, range.min_bound().unwrap_or(Amount::ZERO));
+        include(&root, range.max_bound().unwrap_or(Amount::MAX_MONEY));
+        for (tpath, template) in nodes.templates.iter() {
+            for (path, output) in nodes.pays[tpath].iter().zip(template.outputs.iter()) {
+                include(path, output.amount);
+            }
+        }
+        let mut leaves = BTreeMap::<String, Liquidity>::new();
+        for (path, l) in paths.iter_mut() {
+            let object = nodes.objects[path];
+            if !(object.ctv_to_tx.is_empty() && object.suggested_txs.is_empty()) {
+                continue;
+            }
+            let script = bitcoin::Script::from(object.address.clone());
+            l.dust = !script.is_op_return() && l.min < script.dust_value();
+            leaves
+                .entry(address(&object.address))
+                .and_modify(|leaf| {
+                    leaf.include(l.min);
+                    leaf.include(l.max);
+                    leaf.dust |= l.dust;
+                })
+                .or_insert(*l);
+        }
+        LiquidityReport { paths, leaves }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{Context, Contract, TxTmplIt};
+    use crate::template::builder::Builder;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("liquidity").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    fn member(i: u8) -> XOnlyPublicKey {
+        let key =
+            bitcoin::KeyPair::from_seckey_slice(&bitcoin::secp256k1::Secp256k1::new(), &[i; 32])
+                .unwrap();
+        XOnlyPublicKey::from_keypair(&key).0
+    }
+    fn locked<'a, T>(
+        name: &str,
+        guard: GuardList<'a, T>,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard,
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new(name.into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn leaving_fees(builder: Builder) -> TxTmplIt {
+        let rest = builder.ctx().funds();
+        builder.add_fees(rest)?.into()
+    }
+    fn pay_to(ctx: Context, key: XOnlyPublicKey) -> TxTmplIt {
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        leaving_fees(ctx.template().add_output(amt, &key, None)?)
+    }
+    /// pays a key, or bumps the fee by paying it almost nothing
+    struct Payout;
+    impl Payout {
+        fn pay<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("pay", &[], |_, ctx, _| pay_to(ctx, member(7)))
+        }
+        fn bump<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("bump", &[], |_, ctx, _| {
+                leaving_fees(
+                    ctx.template()
+                        .add_output(Amount::from_sat(300), &member(7), None)?,
+                )
+            })
+        }
+    }
+    impl Contract for Payout {
+        declare! {then, Self::pay, Self::bump}
+        declare! {non updatable}
+    }
+    struct Funding;
+    impl Funding {
+        fn fund<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("fund", &[], |_, ctx, _| {
+                let amt = ctx.funds() - Amount::from_sat(1000);
+                leaving_fees(ctx.template().add_output(amt, &Payout, None)?)
+            })
+        }
+    }
+    impl Contract for Funding {
+        declare! {then, Self::fund}
+        declare! {non updatable}
+    }
+    #[test]
+    fn fee_bump_liquidity_report() {
+        let report = ctx()
+            .with_dust_as_warning(true)
+            .compile(Funding)
+            .unwrap()
+            .liquidity_report();
+        let payout = "liquidity/@action/fund/@next/@default_effect/#0";
+        let amounts = |path: &str| {
+            let l = &report.paths[path];
+            (l.min.as_sat(), l.max.as_sat(), l.dust)
+        };
+        assert_eq!(amounts(payout), (99_000, 99_000, false));
+        assert_eq!(
+            amounts(&format!("{}/pay/#0", payout)),
+            (98_000, 98_000, false)
+        );
+        assert_eq!(amounts(&format!("{}/bump/#0", payout)), (300, 300, true));
+        // both branches end at the same address
+        assert_eq!(report.leaves.len(), 1);
+        let leaf = report.leaves.values().next().unwrap();
+        assert_eq!(
+            (leaf.min.as_sat(), leaf.max.as_sat(), leaf.dust),
+            (300, 98_000, true)
+        );
+        assert!(report.render().contains("300  98000  dust"));
+    }
+}
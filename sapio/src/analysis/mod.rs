@@ -0,0 +1,114 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Analyses of compiled contracts
+pub mod diff;
+pub use diff::*;
+pub mod keys;
+pub use keys::*;
+pub mod liquidity;
+pub use liquidity::*;
+pub mod timelocks;
+pub use timelocks::*;
+
+use crate::contract::object::Object;
+use crate::template::Template;
+use bitcoin::hashes::sha256;
+use sapio_base::timelocks::{
+    AbsHeight, AbsTime, AnyAbsTimeLock, AnyRelTimeLock, AnyTimeLock, Sequence,
+};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// The nodes of a contract's tree, by path, identified as in `Change`
+#[derive(Default)]
+pub(crate) struct Nodes<'a> {
+    pub(crate) objects: BTreeMap<String, &'a Object>,
+    pub(crate) templates: BTreeMap<String, &'a Template>,
+    /// the path of the object each template spends
+    pub(crate) spends: BTreeMap<String, String>,
+    /// the paths of the objects each template's outputs pay, in order
+    pub(crate) pays: BTreeMap<String, Vec<String>>,
+    /// the path of the template creating each object, unless it is the root
+    pub(crate) created_by: BTreeMap<String, String>,
+}
+
+impl<'a> Nodes<'a> {
+    pub(crate) fn add(&mut self, path: String, object: &'a Object) {
+        // a template is named by the branch which created it
+        let mut named = BTreeMap::<sha256::Hash, String>::new();
+        for (branch, hashes) in object.branches.iter() {
+            for (i, h) in hashes.iter().enumerate() {
+                let name = if hashes.len() == 1 {
+                    branch.clone()
+                } else {
+                    format!("{}[{}]", branch, i)
+                };
+                named.entry(*h).or_insert(name);
+            }
+        }
+        for (h, template) in object.ctv_to_tx.iter().chain(object.suggested_txs.iter()) {
+            let name = named.get(h).cloned().unwrap_or_else(|| h.to_string());
+            let tpath = format!("{}/{}", path, name);
+            let mut pays = vec![];
+            for (vout, output) in template.outputs.iter().enumerate() {
+                let opath = String::from(output.contract.root_path.0.as_ref().clone());
+                let opath = if opath.is_empty() {
+                    format!("{}/#{}", tpath, vout)
+                } else {
+                    opath
+                };
+                self.created_by.insert(opath.clone(), tpath.clone());
+                self.add(opath.clone(), &output.contract);
+                pays.push(opath);
+            }
+            self.spends.insert(tpath.clone(), path.clone());
+            self.pays.insert(tpath.clone(), pays);
+            self.templates.insert(tpath, template);
+        }
+        self.objects.insert(path, object);
+    }
+    /// the templates spending the object at `path`
+    pub(crate) fn spending(&self, path: &str) -> impl Iterator<Item = (&String, &&'a Template)> {
+        let path = path.to_string();
+        self.templates
+            .iter()
+            .filter(move |(t, _)| self.spends.get(*t) == Some(&path))
+    }
+    /// the nodes of `object`'s tree
+    pub(crate) fn of(object: &'a Object) -> Self {
+        let mut nodes = Nodes::default();
+        nodes.add(String::from(object.root_path.0.as_ref().clone()), object);
+        nodes
+    }
+}
+
+/// The lock of `Clause::After(n)`, unless `n` is neither a height nor a time
+pub(crate) fn absolute_lock(n: u32) -> Option<AnyTimeLock> {
+    match AbsHeight::try_from(n) {
+        Ok(h) => Some(AnyTimeLock::A(h.into())),
+        Err(_) => AbsTime::try_from(n).ok().map(|t| AnyTimeLock::A(t.into())),
+    }
+}
+
+/// The lock of `Clause::Older(n)`, unless the relative lock is disabled
+pub(crate) fn relative_lock(n: u32) -> Option<AnyTimeLock> {
+    Sequence(n).relative_lock().map(AnyTimeLock::R)
+}
+
+/// `lock` in words, e.g. "144 blocks after confirmation"
+pub(crate) fn describe_lock(lock: &AnyTimeLock) -> String {
+    match lock {
+        AnyTimeLock::A(AnyAbsTimeLock::AH(h)) => format!("at height {}", h.get()),
+        AnyTimeLock::A(AnyAbsTimeLock::AT(t)) => format!("at time {}", t.get()),
+        AnyTimeLock::R(AnyRelTimeLock::RH(h)) => {
+            format!("{} blocks after confirmation", h.get())
+        }
+        AnyTimeLock::R(AnyRelTimeLock::RT(t)) => {
+            format!("{} seconds after confirmation", t.units() as u32 * 512)
+        }
+    }
+}
@@ -0,0 +1,413 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Every timelock deadline in a compiled contract, as a calendar to check
+//! before funding it
+use super::keys::{conditions, Condition};
+use super::{absolute_lock, relative_lock, Nodes};
+use crate::contract::object::Object;
+use bitcoin::XOnlyPublicKey;
+use sapio_base::timelocks::{AnyAbsTimeLock, AnyRelTimeLock, AnyTimeLock};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// What passing a deadline lets happen
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "unlocks", rename_all = "snake_case")]
+pub enum Unlocks {
+    /// a spending condition of the node, signed by these keys, not counting
+    /// any emulator's
+    Keys {
+        /// the keys
+        #[schemars(with = "Vec<String>")]
+        keys: Vec<XOnlyPublicKey>,
+    },
+    /// a template spending the node
+    Template {
+        /// the template's path
+        path: String,
+        /// the paths of the nodes its outputs pay
+        pays: Vec<String>,
+    },
+}
+
+/// One deadline gating a node
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Deadline {
+    /// the lock
+    pub lock: AnyTimeLock,
+    /// the lock in words, relative locks counting from when the node is
+    /// created, e.g. "+144 blocks after funding confirms"
+    pub description: String,
+    /// the estimated unix time the lock passes, see `ChainTip`
+    pub estimated_time: i64,
+    /// what can happen once the lock passes
+    #[serde(flatten)]
+    pub unlocks: Unlocks,
+}
+
+/// The deadlines of every node of a contract, see `Object::timelock_report`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq, Default)]
+pub struct TimelockReport {
+    /// the deadlines gating each node with any, by the node's path
+    pub paths: BTreeMap<String, Vec<Deadline>>,
+}
+
+/// The block a contract would be funded after, to estimate when its
+/// deadlines pass with ten minute blocks
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChainTip {
+    /// the block's height
+    pub height: u32,
+    /// the block's time
+    pub time: u32,
+}
+
+impl ChainTip {
+    /// the estimated unix time `lock` passes, with relative locks counting
+    /// from `from`
+    fn passes(&self, lock: &AnyTimeLock, from: i64) -> i64 {
+        match lock {
+            AnyTimeLock::A(AnyAbsTimeLock::AH(h)) => {
+                self.time as i64 + (h.get() as i64 - self.height as i64) * 600
+            }
+            AnyTimeLock::A(AnyAbsTimeLock::AT(t)) => t.get() as i64,
+            AnyTimeLock::R(AnyRelTimeLock::RH(h)) => from + h.units() as i64 * 600,
+            AnyTimeLock::R(AnyRelTimeLock::RT(t)) => from + t.units() as i64 * 512,
+        }
+    }
+
+    /// the estimated earliest unix time the node at `path` can confirm
+    fn confirms(&self, nodes: &Nodes, path: &str) -> i64 {
+        let tpath = match nodes.created_by.get(path) {
+            Some(tpath) => tpath,
+            None => return self.time as i64,
+        };
+        let spent = nodes
+            .spends
+            .get(tpath)
+            .map_or(self.time as i64, |p| self.confirms(nodes, p));
+        nodes.templates.get(tpath).map_or(spent, |t| {
+            template_locks(&t.tx)
+                .iter()
+                .map(|l| self.passes(l, spent))
+                .fold(spent, i64::max)
+        })
+    }
+}
+
+/// the locks `tx` sets on spending the node its first input spends
+fn template_locks(tx: &bitcoin::Transaction) -> Vec<AnyTimeLock> {
+    let mut locks = vec![];
+    if tx.lock_time != 0 && tx.input.iter().any(|i| i.sequence != 0xFFFF_FFFF) {
+        locks.extend(absolute_lock(tx.lock_time));
+    }
+    // only the first input spends this node
+    locks.extend(
+        tx.input
+            .first()
+            .filter(|_| tx.version >= 2)
+            .and_then(|i| relative_lock(i.sequence))
+            .filter(|l| l.get() & 0xFFFF != 0),
+    );
+    locks
+}
+
+/// `lock` in words, with relative locks counting from `created`
+fn describe(lock: &AnyTimeLock, created: &str) -> String {
+    match lock {
+        AnyTimeLock::A(AnyAbsTimeLock::AH(h)) => format!("at height {}", h.get()),
+        AnyTimeLock::A(AnyAbsTimeLock::AT(t)) => format!("at time {}", t.get()),
+        AnyTimeLock::R(AnyRelTimeLock::RH(h)) => {
+            format!("+{} blocks after {} confirms", h.units(), created)
+        }
+        AnyTimeLock::R(AnyRelTimeLock::RT(t)) => format!(
+            "+{} seconds after {} confirms",
+            t.units() as u32 * 512,
+            created
+        ),
+    }
+}
+
+/// the keys under `c`
+fn keys(c: &Condition, keys: &mut BTreeSet<XOnlyPublicKey>) {
+    match c {
+        Condition::Key(k) => {
+            keys.insert(*k);
+        }
+        Condition::Threshold(_, subs) => subs.iter().for_each(|s| self::keys(s, keys)),
+        Condition::Lock(_) | Condition::Other => {}
+    }
+}
+
+/// each lock in `c` which some satisfaction needs, with the keys signing
+/// along with it
+fn gated(c: &Condition, out: &mut Vec<(AnyTimeLock, BTreeSet<XOnlyPublicKey>)>) {
+    match c {
+        Condition::Lock(l) => out.push((*l, BTreeSet::new())),
+        Condition::Threshold(k, subs) => {
+            let mut signers = BTreeSet::new();
+            if *k == subs.len() {
+                for s in subs {
+                    match s {
+                        Condition::Lock(_) => {}
+                        s => keys(s, &mut signers),
+                    }
+                }
+            }
+            for s in subs {
+                match s {
+                    Condition::Lock(l) if *k == subs.len() => out.push((*l, signers.clone())),
+                    s => gated(s, out),
+                }
+            }
+        }
+        Condition::Key(_) | Condition::Other => {}
+    }
+}
+
+impl Object {
+    /// Every deadline gating each node of this object's tree, from the
+    /// timelocks of its spending conditions and of the templates spending
+    /// it, with what each one unlocks, by the nodes' paths as in
+    /// `analysis::diff`.
+    ///
+    /// A relative lock counts from when the node's coin confirms, so it is
+    /// described from the funding transaction at the root, and elsewhere
+    /// from the template creating the node. The deadlines of each node are
+    /// sorted by when they are estimated to pass, if the contract is funded
+    /// after `tip`.
+    pub fn timelock_report(&self, tip: ChainTip) -> TimelockReport {
+        let nodes = Nodes::of(self);
+        let emulator: BTreeSet<_> = nodes
+            .objects
+            .values()
+            .flat_map(|o| o.emulator_keys.iter().copied())
+            .collect();
+        let mut paths = BTreeMap::new();
+        for (path, object) in nodes.objects.iter() {
+            let created = nodes.created_by.get(path).map_or("funding", |t| t.as_str());
+            let confirms = tip.confirms(&nodes, path);
+            let mut deadlines = vec![];
+            let mut add = |lock: AnyTimeLock, unlocks: Unlocks| {
+                let d = Deadline {
+                    lock,
+                    description: describe(&lock, created),
+                    estimated_time: tip.passes(&lock, confirms),
+                    unlocks,
+                };
+                if !deadlines.contains(&d) {
+                    deadlines.push(d)
+                }
+            };
+            for c in conditions(object) {
+                let mut locks = vec![];
+                gated(&c, &mut locks);
+                for (lock, signers) in locks {
+                    let keys = signers.difference(&emulator).copied().collect();
+                    add(lock, Unlocks::Keys { keys });
+                }
+            }
+            for (tpath, template) in nodes.spending(path) {
+                for lock in template_locks(&template.tx) {
+                    let unlocks = Unlocks::Template {
+                        path: tpath.clone(),
+                        pays: nodes.pays.get(tpath).cloned().unwrap_or_default(),
+                    };
+                    add(lock, unlocks);
+                }
+            }
+            deadlines.sort_by_key(|d| d.estimated_time);
+            if !deadlines.is_empty() {
+                paths.insert(path.clone(), deadlines);
+            }
+        }
+        TimelockReport { paths }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{Context, Contract, TxTmplIt};
+    use crate::template::builder::Builder;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::timelocks::AbsHeight;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("timelocks").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    fn member(i: u8) -> XOnlyPublicKey {
+        let key =
+            bitcoin::KeyPair::from_seckey_slice(&bitcoin::secp256k1::Secp256k1::new(), &[i; 32])
+                .unwrap();
+        XOnlyPublicKey::from_keypair(&key).0
+    }
+    fn locked<'a, T>(
+        name: &str,
+        guard: GuardList<'a, T>,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard,
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new(name.into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn leaving_fees(builder: Builder) -> TxTmplIt {
+        let rest = builder.ctx().funds();
+        builder.add_fees(rest)?.into()
+    }
+    fn pay_to(ctx: Context, key: XOnlyPublicKey) -> TxTmplIt {
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        leaving_fees(ctx.template().add_output(amt, &key, None)?)
+    }
+    fn hot_after_delay<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| Clause::And(vec![Clause::Key(member(9)), Clause::Older(144)])),
+            None,
+        ))
+    }
+    fn backup_after_expiry<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| {
+                Clause::And(vec![Clause::Key(member(8)), Clause::After(1_600_000_000)])
+            }),
+            None,
+        ))
+    }
+    /// pays the hot key after a delay, or the cold key at any time
+    struct Unvaulting;
+    impl Unvaulting {
+        fn claim<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("claim", &[GuardGen::Fn(hot_after_delay)], |_, ctx, _| {
+                pay_to(ctx, member(9))
+            })
+        }
+        fn sweep<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("sweep", &[], |_, ctx, _| pay_to(ctx, member(10)))
+        }
+    }
+    impl Contract for Unvaulting {
+        declare! {then, Self::claim, Self::sweep}
+        declare! {non updatable}
+    }
+    /// unvaults from a height, or pays a backup key once it expires
+    struct ExpiringVault;
+    impl ExpiringVault {
+        fn unvault<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("unvault", &[], |_, ctx, _| {
+                let amt = ctx.funds() - Amount::from_sat(1000);
+                leaving_fees(
+                    ctx.template()
+                        .set_lock_time(AbsHeight::try_from(700_000).unwrap().into())?
+                        .add_output(amt, &Unvaulting, None)?,
+                )
+            })
+        }
+        fn backup<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "backup",
+                &[GuardGen::Fn(backup_after_expiry)],
+                |_, ctx, _| pay_to(ctx, member(8)),
+            )
+        }
+    }
+    impl Contract for ExpiringVault {
+        declare! {then, Self::unvault, Self::backup}
+        declare! {non updatable}
+    }
+    #[test]
+    fn vault_timelock_report() {
+        let vault = ctx().compile(ExpiringVault).unwrap();
+        let unvaulting = "timelocks/@action/unvault/@next/@default_effect/#0";
+        let deadlines = |tip| -> Vec<_> {
+            vault
+                .timelock_report(tip)
+                .paths
+                .into_iter()
+                .flat_map(|(path, ds)| ds.into_iter().map(move |d| (path.clone(), d)))
+                .map(|(path, d)| (path, d.description, d.estimated_time, d.unlocks))
+                .collect()
+        };
+        let height = |estimated| {
+            (
+                "timelocks".to_string(),
+                "at height 700000".to_string(),
+                estimated,
+                Unlocks::Template {
+                    path: "timelocks/unvault".into(),
+                    pays: vec![unvaulting.into()],
+                },
+            )
+        };
+        let time = (
+            "timelocks".to_string(),
+            "at time 1600000000".to_string(),
+            1_600_000_000,
+            Unlocks::Keys {
+                keys: vec![member(8)],
+            },
+        );
+        let claim = |estimated| {
+            (
+                unvaulting.to_string(),
+                "+144 blocks after timelocks/unvault confirms".to_string(),
+                estimated,
+                Unlocks::Keys {
+                    keys: vec![member(9)],
+                },
+            )
+        };
+        // from height 600000, height 700000 is estimated to pass after the time
+        let early = ChainTip {
+            height: 600_000,
+            time: 1_570_000_000,
+        };
+        assert_eq!(
+            deadlines(early),
+            [
+                time.clone(),
+                height(1_630_000_000),
+                claim(1_630_000_000 + 144 * 600)
+            ]
+        );
+        // and, from height 699000, before it
+        let late = ChainTip {
+            height: 699_000,
+            time: 1_500_000_000,
+        };
+        assert_eq!(
+            deadlines(late),
+            [
+                height(1_500_600_000),
+                time,
+                claim(1_500_600_000 + 144 * 600)
+            ]
+        );
+    }
+}
@@ -6,9 +6,10 @@
 
 //! ABI for contract resumption
 
+use sapio_base::effects::EffectPath;
 use sapio_base::serialization_helpers::SArc;
+use sapio_base::simp::ContinuationPointLT;
 use sapio_base::simp::{SIMPAttachableAt, SIMPError, SIMP};
-use sapio_base::{effects::EffectPath, simp::ContinuationPointLT};
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
@@ -26,6 +27,24 @@ pub struct ContinuationPoint {
     pub path: Arc<EffectPath>,
     /// Metadata for this particular Continuation Point
     pub simp: BTreeMap<i64, Value>,
+    /// The schema of the templates which may be returned at this point
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub returned_template_schema: Option<SArc<Value>>,
+    /// where a UI should place this continuation relative to others, lowest first
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub display_order: Option<i64>,
+    /// if a UI should hide this continuation by default
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub hidden: bool,
+    /// a human readable description of this continuation
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub description: Option<String>,
+    /// the guards which must be satisfied to use this continuation
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub guards: Option<String>,
+    /// if the default arguments yielded any templates when compiled
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub default_yields_templates: Option<bool>,
 }
 impl ContinuationPoint {
     /// Creates a new continuation
@@ -34,9 +53,42 @@ impl ContinuationPoint {
             schema: schema.map(SArc),
             path,
             simp: Default::default(),
+            returned_template_schema: None,
+            display_order: None,
+            hidden: false,
+            description: None,
+            guards: None,
+            default_yields_templates: None,
         }
     }
 
+    /// Records what compiling this continuation found: a summary of its
+    /// guards and if its default arguments yield templates
+    pub fn with_summary(mut self, guards: String, default_yields_templates: bool) -> Self {
+        self.guards = Some(guards);
+        self.default_yields_templates = Some(default_yields_templates);
+        self
+    }
+
+    /// Sets the schema of the templates which may be returned at this point
+    pub fn with_returned_template_schema(mut self, schema: Option<Arc<Value>>) -> Self {
+        self.returned_template_schema = schema.map(SArc);
+        self
+    }
+
+    /// Sets the display metadata a UI may use to present this continuation
+    pub fn with_display(
+        mut self,
+        display_order: Option<i64>,
+        hidden: bool,
+        description: Option<String>,
+    ) -> Self {
+        self.display_order = display_order;
+        self.hidden = hidden;
+        self.description = description;
+        self
+    }
+
     /// attempts to add a SIMP to the output meta.
     ///
     /// Returns [`SIMPError::AlreadyDefined`] if one was previously set.
@@ -53,6 +105,26 @@ impl ContinuationPoint {
     }
 }
 
+/// A [`ContinuationPoint`] found somewhere in a tree of compiled contracts,
+/// see [`crate::contract::Compiled::continuation_points`]
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct QualifiedContinuation {
+    /// the named fragments of the continuation's path, e.g. `root/sell`
+    pub name: String,
+    /// the continuation itself
+    #[serde(flatten)]
+    pub point: ContinuationPoint,
+}
+
+impl From<&ContinuationPoint> for QualifiedContinuation {
+    fn from(point: &ContinuationPoint) -> Self {
+        QualifiedContinuation {
+            name: point.path.label(),
+            point: point.clone(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -72,4 +144,22 @@ mod test {
         assert_eq!(a, b);
         Ok(())
     }
+    #[test]
+    fn test_continuation_point_display_ser() -> Result<(), Box<dyn std::error::Error>> {
+        let path = EffectPath::push(None, PathFragment::Named(SArc(Arc::new("one".into()))));
+        let plain = ContinuationPoint::at(None, path.clone());
+        assert_eq!(
+            serde_json::to_string(&plain)?,
+            "{\"schema\":null,\"path\":\"one\",\"simp\":{}}"
+        );
+        let shown = plain.with_display(Some(2), true, Some("Advanced".into()));
+        assert_eq!(
+            serde_json::to_value(&shown)?,
+            serde_json::json!({"schema": null, "path": "one", "simp": {},
+                "display_order": 2, "hidden": true, "description": "Advanced"})
+        );
+        let back: ContinuationPoint = serde_json::from_value(serde_json::to_value(&shown)?)?;
+        assert_eq!(back, shown);
+        Ok(())
+    }
 }
@@ -16,10 +16,13 @@ use ::miniscript::*;
 
 use bitcoin::hashes::sha256::Hash as Sha256;
 
+use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::psbt;
 use bitcoin::util::psbt::PartiallySignedTransaction;
+use bitcoin::util::sighash::SchnorrSighashType;
 use bitcoin::util::taproot::TaprootBuilder;
 use bitcoin::util::taproot::TaprootSpendInfo;
-use bitcoin::OutPoint;
+use bitcoin::{EcdsaSighashType, OutPoint, TxOut};
 
 use sapio_base::effects::EffectPath;
 
@@ -31,6 +34,15 @@ use std::collections::BTreeMap;
 use std::rc::Rc;
 use std::sync::Arc;
 impl Object {
+    /// Fail with `ObjectError::Skeleton` if this was compiled as a dry run,
+    /// as any coin sent to it would be lost
+    pub fn check_bindable(&self) -> Result<(), ObjectError> {
+        if self.skeleton {
+            Err(ObjectError::Skeleton)
+        } else {
+            Ok(())
+        }
+    }
     /// bind_psbt attaches and `Object` to a specific UTXO, returning a
     /// Vector of PSBTs and transaction metadata.
     ///
@@ -43,13 +55,14 @@ impl Object {
         blockdata: Rc<dyn TxIndex>,
         emulator: &dyn CTVEmulator,
     ) -> Result<Program, ObjectError> {
+        self.check_bindable()?;
         let mut result = BTreeMap::<SArc<EffectPath>, SapioStudioObject>::new();
         // Could use a queue instead to do BFS linking, but order doesn't matter and stack is
         // faster.
         let mut stack = vec![(out_in, self)];
         let mut mock_out = OutPoint::default();
         mock_out.vout = 0;
-        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let secp = crate::contract::context::SECP.clone();
         while let Some((
             out,
             Object {
@@ -84,7 +97,12 @@ impl Object {
                             )| {
                                 let mut tx = tx.clone();
                                 tx.input[0].previous_output = out;
-                                for inp in tx.input[1..].iter_mut() {
+                                // inputs contributed to a specific outpoint are
+                                // already filled in
+                                for inp in tx.input[1..]
+                                    .iter_mut()
+                                    .filter(|inp| inp.previous_output.is_null())
+                                {
                                     inp.previous_output = mock_out;
                                     mock_out.vout += 1;
                                 }
@@ -103,44 +121,17 @@ impl Object {
                                     psbt_in.witness_utxo =
                                         blockdata.lookup_output(&tx_in.previous_output).ok();
                                 }
-                                // Missing other Witness Info.
-                                match descriptor {
-                                    Some(SupportedDescriptors::Pk(d)) => {
-                                        psbtx.inputs[0].witness_script = Some(d.explicit_script()?);
-                                    }
-                                    Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
-                                        let mut builder = TaprootBuilder::new();
-                                        let mut added = false;
-                                        for (depth, ms) in t.iter_scripts() {
-                                            added = true;
-                                            let script = ms.encode();
-                                            builder = builder.add_leaf(depth, script)?;
-                                        }
-                                        let info = if added {
-                                            builder.finalize(&secp, *t.internal_key())?
-                                        } else {
-                                            TaprootSpendInfo::new_key_spend(
-                                                &secp,
-                                                *t.internal_key(),
-                                                None,
-                                            )
-                                        };
-                                        let inp = &mut psbtx.inputs[0];
-                                        for item in info.as_script_map().keys() {
-                                            let cb =
-                                                info.control_block(item).expect("Must be present");
-                                            inp.tap_scripts.insert(cb.clone(), item.clone());
-                                        }
-                                        inp.tap_merkle_root = info.merkle_root();
-                                        inp.tap_internal_key = Some(info.internal_key());
-                                    }
-                                    _ => (),
-                                }
+                                add_spend_info(descriptor.as_ref(), &mut psbtx.inputs[0], &secp)?;
                                 psbtx = emulator.sign(psbtx)?;
                                 let final_tx = psbtx.clone().extract_tx();
                                 let txid = blockdata.add_tx(Arc::new(final_tx))?;
                                 stack.reserve(outputs.len());
-                                for (vout, v) in outputs.iter().enumerate() {
+                                // external outputs aren't contracts to follow
+                                for (vout, v) in outputs
+                                    .iter()
+                                    .enumerate()
+                                    .filter(|(_, v)| !v.added_metadata.is_external())
+                                {
                                     let vout = vout as u32;
                                     stack.push((bitcoin::OutPoint { txid, vout }, &v.contract));
                                 }
@@ -167,4 +158,292 @@ impl Object {
         }
         Ok(Program { program: result })
     }
+
+    /// Every template in this object's tree as a PSBT spending from the
+    /// contract it is in, with this object funded by `txout` at `outpoint`.
+    /// They are keyed by the path of that contract, and in order of it and
+    /// then of the template's hash.
+    ///
+    /// The first input of each PSBT has the coin it spends, the scripts and
+    /// internal key to spend it with, the sighash type, and any signatures
+    /// `emulator` adds, leaving those of users to be added. A template with
+    /// more inputs needs them filled in, and then the emulator's signatures,
+    /// before it or any template spending its outputs can be broadcast.
+    pub fn export_psbts(
+        &self,
+        outpoint: OutPoint,
+        txout: TxOut,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Vec<(EffectPath, PartiallySignedTransaction)>, ObjectError> {
+        self.check_bindable()?;
+        let secp = crate::contract::context::SECP.clone();
+        let mut psbts = vec![];
+        let mut stack = vec![(outpoint, txout, self)];
+        while let Some((out, txout, object)) = stack.pop() {
+            for (h, template) in object.ctv_to_tx.iter().chain(object.suggested_txs.iter()) {
+                let mut tx = template.tx.clone();
+                tx.input[0].previous_output = out;
+                let mut psbtx = PartiallySignedTransaction::from_unsigned_tx(tx)
+                    .map_err(|e| ObjectError::Custom(Box::new(e)))?;
+                let input = &mut psbtx.inputs[0];
+                input.witness_utxo = Some(txout.clone());
+                add_spend_info(object.descriptor.as_ref(), input, &secp)?;
+                input.sighash_type = match object.descriptor {
+                    Some(SupportedDescriptors::XOnly(_)) => Some(SchnorrSighashType::All.into()),
+                    Some(SupportedDescriptors::Pk(_)) => Some(EcdsaSighashType::All.into()),
+                    None => None,
+                };
+                // the emulator signs over every coin spent
+                let psbtx = if psbtx.inputs.iter().all(|i| i.witness_utxo.is_some()) {
+                    emulator.sign(psbtx)?
+                } else {
+                    psbtx
+                };
+                let txid = psbtx.unsigned_tx.txid();
+                // external outputs aren't contracts to follow
+                for (vout, (output, txout)) in template
+                    .outputs
+                    .iter()
+                    .zip(psbtx.unsigned_tx.output.iter())
+                    .enumerate()
+                    .filter(|(_, (o, _))| !o.added_metadata.is_external())
+                {
+                    let out = OutPoint::new(txid, vout as u32);
+                    stack.push((out, txout.clone(), &output.contract));
+                }
+                let path = object.root_path.0.as_ref().clone();
+                psbts.push(((String::from(path.clone()), *h), path, psbtx));
+            }
+        }
+        psbts.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(psbts
+            .into_iter()
+            .map(|(_, path, psbtx)| (path, psbtx))
+            .collect())
+    }
+}
+
+/// Add the scripts, and internal key if taproot, to spend an output of
+/// `descriptor` to `input`
+fn add_spend_info(
+    descriptor: Option<&SupportedDescriptors>,
+    input: &mut psbt::Input,
+    secp: &Secp256k1<All>,
+) -> Result<(), ObjectError> {
+    match descriptor {
+        Some(SupportedDescriptors::Pk(d)) => {
+            input.witness_script = Some(d.explicit_script()?);
+        }
+        Some(SupportedDescriptors::XOnly(Descriptor::Tr(t))) => {
+            let mut builder = TaprootBuilder::new();
+            let mut added = false;
+            for (depth, ms) in t.iter_scripts() {
+                added = true;
+                let script = ms.encode();
+                builder = builder.add_leaf(depth, script)?;
+            }
+            let info = if added {
+                builder.finalize(secp, *t.internal_key())?
+            } else {
+                TaprootSpendInfo::new_key_spend(secp, *t.internal_key(), None)
+            };
+            for item in info.as_script_map().keys() {
+                let cb = info.control_block(item).expect("Must be present");
+                input.tap_scripts.insert(cb.clone(), item.clone());
+            }
+            input.tap_merkle_root = info.merkle_root();
+            input.tap_internal_key = Some(info.internal_key());
+        }
+        // Missing other Witness Info.
+        _ => (),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::contract::actions::*;
+    use crate::contract::{Contract, TxTmplIt};
+    use crate::Context;
+    use bitcoin::secp256k1::{Keypair, Message, SecretKey};
+    use bitcoin::util::amount::Amount;
+    use bitcoin::util::sighash::{Prevouts, SchnorrSighashType, SighashCache};
+    use bitcoin::util::taproot::TapLeafHash;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use emulator_connect::connections::local::LocalEmulator;
+    use miniscript::psbt::PsbtExt;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::Clause;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    /// `(n + 1) * G`
+    fn nth_key(n: usize) -> XOnlyPublicKey {
+        [
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        ][n]
+            .parse()
+            .unwrap()
+    }
+    fn payout<'a, T>(
+        fee_policy: FeePolicy,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard: &[],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new("payout".into()),
+                fee_policy,
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn pay_all_but_fee<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amt, &nth_key(0), None)?
+            .into()
+    }
+    /// pays its funds to `nth_key(0)` in two halves
+    struct Halves;
+    fn split_in_half(_: &Halves, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        ctx.template()
+            .add_output(half, &nth_key(0), None)?
+            .add_output(half, &nth_key(0), None)?
+            .into()
+    }
+    impl Halves {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, split_in_half)
+        }
+    }
+    impl Contract for Halves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    /// a branch `cosigned` by `nth_key(2)` paying to `nth_key(0)`, and one
+    /// splitting the funds into `Halves`
+    struct Exported;
+    fn pay_to_halves(_: &Exported, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        ctx.template()
+            .add_output(half, &Halves, None)?
+            .add_output(half, &nth_key(0), None)?
+            .into()
+    }
+    impl Exported {
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|_, _| Clause::Key(nth_key(2))),
+                None,
+            ))
+        }
+        fn cosigned<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[GuardGen::Fn(Self::signed)],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: pay_all_but_fee,
+                    name: Arc::new("cosigned".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, pay_to_halves)
+        }
+    }
+    impl Contract for Exported {
+        declare! {then, Self::cosigned, Self::split}
+        declare! {non updatable}
+    }
+    #[test]
+    fn exported_psbts_finalize() {
+        let compiled = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(LocalEmulator::for_tests()),
+            EffectPath::try_from("bind").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .compile(Exported)
+        .unwrap();
+        let funding = bitcoin::OutPoint::new(bitcoin::Txid::default(), 3);
+        let txout = bitcoin::TxOut {
+            value: 100_000,
+            script_pubkey: bitcoin::Script::from(compiled.address.clone()),
+        };
+        let export = || {
+            compiled
+                .export_psbts(funding, txout.clone(), &LocalEmulator::for_tests())
+                .unwrap()
+        };
+        let psbts = export();
+        assert_eq!(
+            serde_json::to_string(&psbts).unwrap(),
+            serde_json::to_string(&export()).unwrap()
+        );
+        let paths: Vec<_> = psbts.iter().map(|(p, _)| String::from(p.clone())).collect();
+        let mut sorted = paths.clone();
+        sorted.sort();
+        assert_eq!(paths, sorted);
+        // both branches at the root, and the one template of the `Halves`
+        assert_eq!(paths.len(), 3);
+        assert_eq!(paths[0], "bind");
+        assert_eq!(paths[1], "bind");
+        // the `Halves` spends the split's first output
+        let split = psbts
+            .iter()
+            .find(|(_, p)| p.unsigned_tx.output.len() == 2)
+            .unwrap();
+        assert_eq!(
+            psbts[2].1.unsigned_tx.input[0].previous_output,
+            bitcoin::OutPoint::new(split.1.unsigned_tx.txid(), 0)
+        );
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        // `nth_key(2)` is 3 * G
+        let mut three = [0u8; 32];
+        three[31] = 3;
+        let user = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&three).unwrap());
+        for (_, mut psbt) in psbts {
+            assert!(psbt.inputs[0].witness_utxo.is_some());
+            assert!(psbt.inputs[0].sighash_type.is_some());
+            let prevouts = [psbt.inputs[0].witness_utxo.clone().unwrap()];
+            let leaves: Vec<_> = psbt.inputs[0]
+                .tap_scripts
+                .values()
+                .filter(|(s, _)| s.to_string().contains(&nth_key(2).to_string()))
+                .map(|(s, v)| TapLeafHash::from_script(s, *v))
+                .collect();
+            for leaf in leaves {
+                let sighash = SighashCache::new(&psbt.unsigned_tx)
+                    .taproot_script_spend_signature_hash(
+                        0,
+                        &Prevouts::All(&prevouts),
+                        leaf,
+                        SchnorrSighashType::All,
+                    )
+                    .unwrap();
+                let msg = Message::from_digest_slice(&sighash[..]).unwrap();
+                let sig = bitcoin::SchnorrSig {
+                    sig: secp.sign_schnorr_no_aux_rand(&msg, &user),
+                    hash_ty: SchnorrSighashType::All,
+                };
+                psbt.inputs[0]
+                    .tap_script_sigs
+                    .insert((nth_key(2), leaf), sig);
+            }
+            psbt.finalize_mut(&secp).unwrap();
+        }
+    }
 }
@@ -0,0 +1,285 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A compact serialized form of an `Object`, storing each distinct nested
+//! object once. Trees of identical sub-contracts, e.g. from batching, differ
+//! only in their paths, so they are compared with their paths made relative.
+use super::Object;
+use crate::contract::CompilationError;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
+use sapio_base::effects::EffectPath;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// The fields of a serialized `Object` and `Template` which nested objects
+/// are found under
+const TEMPLATE_MAPS: [&str; 2] = [
+    "template_hash_to_template_map",
+    "suggested_template_hash_to_template_map",
+];
+const OUTPUTS: &str = "outputs_info";
+const CONTRACT: &str = "receiving_contract";
+
+/// Stands in for the `root_path` of the object a string is in, see
+/// `relativize`
+const ROOT: char = '~';
+
+/// An `Object` with each distinct object in its tree stored once, see
+/// `Object::dedup`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct DedupedObject {
+    /// the root object
+    pub root: ObjectRef,
+    /// each distinct object, as a serialized `Object` with its nested objects
+    /// replaced by an `ObjectRef` and its paths made relative to its
+    /// `root_path`
+    pub objects: BTreeMap<sha256::Hash, Value>,
+}
+
+/// A reference to one of `DedupedObject::objects`, in place of a nested
+/// object
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct ObjectRef {
+    /// the hash of the object's serialized form
+    #[serde(rename = "$ref")]
+    pub hash: sha256::Hash,
+    /// the `root_path` of this instance of the object
+    pub root_path: EffectPath,
+}
+
+impl Object {
+    /// Store each distinct object in this object's tree once, see
+    /// `DedupedObject::expand`
+    pub fn dedup(&self) -> Result<DedupedObject, CompilationError> {
+        let mut objects = BTreeMap::new();
+        let v = serde_json::to_value(self).map_err(CompilationError::SerializationError)?;
+        let root = dedup_value(v, &mut objects)?;
+        Ok(DedupedObject { root, objects })
+    }
+}
+
+impl Object {
+    /// This object as compiled at `root` instead, for an object which
+    /// depends on its path only through the paths in it, see
+    /// `Context::not_memoizable`
+    pub(crate) fn rerooted(&self, root: &EffectPath) -> Result<Object, CompilationError> {
+        let mut v = serde_json::to_value(self).map_err(CompilationError::SerializationError)?;
+        relativize(&mut v, &String::from(self.root_path.0.as_ref().clone()));
+        absolutize(&mut v, &String::from(root.clone()));
+        serde_json::from_value(v).map_err(CompilationError::DeserializationError)
+    }
+}
+
+impl DedupedObject {
+    /// The `Object` this was created from
+    pub fn expand(&self) -> Result<Object, CompilationError> {
+        serde_json::from_value(self.expand_value(&self.root)?)
+            .map_err(CompilationError::DeserializationError)
+    }
+    /// The serialized form of the object `r` refers to
+    pub fn expand_value(&self, r: &ObjectRef) -> Result<Value, CompilationError> {
+        let mut v = self.objects.get(&r.hash).cloned().ok_or_else(|| {
+            CompilationError::DeserializationError(serde::de::Error::custom(format!(
+                "no object with hash {}",
+                r.hash
+            )))
+        })?;
+        absolutize(&mut v, &String::from(r.root_path.clone()));
+        for contract in nested(&mut v) {
+            let r: ObjectRef = serde_json::from_value(contract.take())
+                .map_err(CompilationError::DeserializationError)?;
+            *contract = self.expand_value(&r)?;
+        }
+        Ok(v)
+    }
+}
+
+fn dedup_value(
+    mut v: Value,
+    objects: &mut BTreeMap<sha256::Hash, Value>,
+) -> Result<ObjectRef, CompilationError> {
+    let root_path: EffectPath = serde_json::from_value(v["root_path"].clone())
+        .map_err(CompilationError::DeserializationError)?;
+    for contract in nested(&mut v) {
+        let r = dedup_value(contract.take(), objects)?;
+        *contract = serde_json::to_value(r).map_err(CompilationError::SerializationError)?;
+    }
+    relativize(&mut v, &String::from(root_path.clone()));
+    let hash = sha256::Hash::hash(v.to_string().as_bytes());
+    objects.entry(hash).or_insert(v);
+    Ok(ObjectRef { hash, root_path })
+}
+
+/// the serialized objects nested in the outputs of a serialized object's
+/// templates
+fn nested(v: &mut Value) -> Vec<&mut Value> {
+    let mut found = vec![];
+    for (name, templates) in v.as_object_mut().into_iter().flatten() {
+        if !TEMPLATE_MAPS.contains(&name.as_str()) {
+            continue;
+        }
+        for template in templates
+            .as_object_mut()
+            .into_iter()
+            .flat_map(|m| m.values_mut())
+        {
+            for output in template
+                .get_mut(OUTPUTS)
+                .and_then(Value::as_array_mut)
+                .into_iter()
+                .flatten()
+            {
+                found.extend(output.get_mut(CONTRACT));
+            }
+        }
+    }
+    found
+}
+
+/// Replace `root` at the start of every string and key in `v` with `ROOT`,
+/// escaping any which already start with it by doubling it. Nested objects
+/// must already be `ObjectRef`s, so that their paths are relative too.
+fn relativize(v: &mut Value, root: &str) {
+    map_strings(v, &|s| {
+        if s == root {
+            ROOT.to_string()
+        } else if let Some(rest) = s.strip_prefix(root).and_then(|rest| rest.strip_prefix('/')) {
+            format!("{}/{}", ROOT, rest)
+        } else if s.starts_with(ROOT) {
+            format!("{}{}", ROOT, s)
+        } else {
+            s.to_string()
+        }
+    })
+}
+
+/// The inverse of `relativize`
+fn absolutize(v: &mut Value, root: &str) {
+    map_strings(v, &|s| match s.strip_prefix(ROOT) {
+        Some(rest) if rest.starts_with(ROOT) => rest.to_string(),
+        Some("") => root.to_string(),
+        Some(rest) if rest.starts_with('/') => format!("{}{}", root, rest),
+        _ => s.to_string(),
+    })
+}
+
+fn map_strings(v: &mut Value, f: &dyn Fn(&str) -> String) {
+    match v {
+        Value::String(s) => *s = f(s),
+        Value::Array(a) => a.iter_mut().for_each(|v| map_strings(v, f)),
+        Value::Object(m) => {
+            *m = std::mem::take(m)
+                .into_iter()
+                .map(|(k, mut v)| {
+                    map_strings(&mut v, f);
+                    (f(&k), v)
+                })
+                .collect()
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{Compilable, Context, Contract, TxTmplIt};
+    use bitcoin::util::amount::Amount;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::MapEffectDB;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(1024 * 1000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("dedup").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    /// a tree of identical halves, with `G` at each of its `2^depth` leaves
+    struct Halves(u32);
+    fn split_in_half(s: &Halves, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mut builder = ctx.template();
+        for _ in 0..2 {
+            builder = match s.0 {
+                1 => builder.add_output(half, &key, None)?,
+                depth => builder.add_output(half, &Halves(depth - 1), None)?,
+            };
+        }
+        builder.into()
+    }
+    impl Halves {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: split_in_half,
+                    name: Arc::new("payout".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Halves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    #[test]
+    fn relative_strings_round_trip() {
+        let root = "a/b";
+        let original = serde_json::json!({
+            "a/b": ["a/b", "a/b/c", "a/bc", "~", "~/x", "~~", "c"],
+            "a/b/d": {"~e": null}
+        });
+        let mut v = original.clone();
+        relativize(&mut v, root);
+        assert_eq!(
+            v,
+            serde_json::json!({
+                "~": ["~", "~/c", "a/bc", "~~", "~~/x", "~~~", "c"],
+                "~/d": {"~~e": null}
+            })
+        );
+        absolutize(&mut v, root);
+        assert_eq!(v, original);
+    }
+    #[test]
+    fn dedup_identical_subtrees() {
+        let compiled = Halves(10).compile(ctx()).unwrap();
+        let deduped = compiled.dedup().unwrap();
+        // one object per level, and the key at the leaves
+        assert_eq!(deduped.objects.len(), 11);
+        let expanded = deduped.expand().unwrap();
+        assert_eq!(
+            serde_json::to_value(&expanded).unwrap(),
+            serde_json::to_value(&compiled).unwrap()
+        );
+        assert_eq!(
+            serde_json::to_value(&expanded.address).unwrap(),
+            serde_json::to_value(&compiled.address).unwrap()
+        );
+        let full = serde_json::to_string(&compiled).unwrap().len();
+        let small = serde_json::to_string(&deduped).unwrap().len();
+        assert!(small * 50 < full, "{} bytes deduped to {}", full, small);
+    }
+}
@@ -16,6 +16,7 @@ use bitcoin::XOnlyPublicKey;
 
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::fmt;
 
 /// Multiple Types of Allowed Descriptor
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
@@ -45,3 +46,13 @@ impl SupportedDescriptors {
         }
     }
 }
+
+/// Renders the descriptor with its checksum, e.g. for `importdescriptors`
+impl fmt::Display for SupportedDescriptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SupportedDescriptors::Pk(p) => write!(f, "{}", p),
+            SupportedDescriptors::XOnly(x) => write!(f, "{}", x),
+        }
+    }
+}
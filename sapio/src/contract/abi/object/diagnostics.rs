@@ -0,0 +1,70 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Non-fatal findings about a contract, collected while compiling it.
+use sapio_base::effects::EffectPath;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// How serious a `Diagnostic` is
+#[derive(
+    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum DiagnosticLevel {
+    /// informational only
+    Note,
+    /// likely a mistake, see `Object::deny_warnings`
+    Warning,
+}
+
+/// A non-fatal finding from compiling the contract at `path`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// how serious the finding is
+    pub level: DiagnosticLevel,
+    /// a stable identifier for the kind of finding, e.g. `unreachable_branch`
+    pub code: String,
+    /// the path of the `Context` the finding was made in
+    pub path: EffectPath,
+    /// a human readable description
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            DiagnosticLevel::Note => "note",
+            DiagnosticLevel::Warning => "warning",
+        };
+        write!(
+            f,
+            "{}[{}] at `{}`: {}",
+            level,
+            self.code,
+            String::from(self.path.clone()),
+            self.message
+        )
+    }
+}
+
+/// The diagnostics collected while compiling one contract, shared by every
+/// `Context` derived while compiling it
+#[derive(Clone, Default)]
+pub(crate) struct Diagnostics(Arc<Mutex<Vec<Diagnostic>>>);
+
+impl Diagnostics {
+    /// add a diagnostic
+    pub(crate) fn push(&self, d: Diagnostic) {
+        self.0.lock().unwrap().push(d)
+    }
+    /// remove all of the diagnostics collected
+    pub(crate) fn take(&self) -> Vec<Diagnostic> {
+        std::mem::take(&mut *self.0.lock().unwrap())
+    }
+}
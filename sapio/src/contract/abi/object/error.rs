@@ -10,7 +10,11 @@ pub use crate::contract::abi::studio::*;
 
 use ::miniscript::{self, *};
 
+use crate::util::amountrange::AmountRange;
+use bitcoin::hashes::sha256;
+use bitcoin::util::amount::Amount;
 use bitcoin::util::taproot::TaprootBuilderError;
+use bitcoin::{OutPoint, Txid};
 
 use sapio_base::txindex::TxIndexError;
 use sapio_ctv_emulator_trait::EmulatorError;
@@ -28,6 +32,44 @@ pub enum ObjectError {
     UnknownScriptType(bitcoin::Script),
     /// OpReturn Too Long
     OpReturnTooLong,
+    /// The coins funding an object are not an amount it accepts
+    FundingOutOfRange {
+        /// what the coins are worth, less any fee
+        amount: Amount,
+        /// the amounts the object accepts
+        range: AmountRange,
+    },
+    /// The coins aggregated to fund an object don't cover the fee
+    FeeExceedsFunding {
+        /// what the coins are worth
+        total: Amount,
+        /// the fee of the funding transaction
+        fee: Amount,
+    },
+    /// A coin was given to fund an object more than once
+    DuplicateFunding(OutPoint),
+    /// The funding transaction doesn't pay the object
+    NoFundingOutput(Txid),
+    /// No coin was given for an input a template declared
+    UnfundedInput {
+        /// the template's hash
+        template: sha256::Hash,
+        /// the input's index
+        input: usize,
+    },
+    /// The coins spent by a template's declared inputs are worth less than
+    /// the external funds it added
+    InsufficientInputs {
+        /// the template's hash
+        template: sha256::Hash,
+        /// what the coins are worth
+        amount: Amount,
+        /// the external funds the template added
+        required: Amount,
+    },
+    /// The object was compiled as a dry run, and pays to unspendable keys,
+    /// see `Object::skeleton`
+    Skeleton,
     /// The Error was for an unknown/unhandled reason
     Custom(Box<dyn std::error::Error>),
 }
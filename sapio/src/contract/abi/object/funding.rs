@@ -0,0 +1,484 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Binding an `Object` funded by several coins, either aggregated into its
+//! output by a funding transaction or spent by the inputs its templates
+//! declared with `Builder::add_sequenced_input`
+use super::Object;
+use super::ObjectError;
+use super::Program;
+use crate::template::input::PrevoutSpec;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use bitcoin::util::amount::Amount;
+use bitcoin::{OutPoint, Script, Transaction, TxIn, TxOut};
+use sapio_base::txindex::TxIndex;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::sync::Arc;
+
+/// A coin contributed towards funding an object
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FundingCoin {
+    /// where the coin is
+    pub outpoint: OutPoint,
+    /// the coin
+    pub txout: TxOut,
+}
+
+/// reject any outpoint given more than once
+fn distinct<'a>(outpoints: impl IntoIterator<Item = &'a OutPoint>) -> Result<(), ObjectError> {
+    let mut seen = BTreeSet::new();
+    for o in outpoints {
+        if !seen.insert(*o) {
+            return Err(ObjectError::DuplicateFunding(*o));
+        }
+    }
+    Ok(())
+}
+
+/// A backtracking search for coins for a template's inputs for any coin
+struct InputSearch<'a> {
+    /// the coins which may be spent, tried in order
+    coins: &'a [FundingCoin],
+    /// each input's index and amount hint
+    hints: &'a [(usize, Amount)],
+    /// what the coins spent by the template must be worth
+    required: Amount,
+    /// the coins chosen for the inputs so far
+    chosen: Vec<OutPoint>,
+    /// the most the coins spent were worth, of the assignments tried
+    best: Option<Amount>,
+    /// the most inputs which were given coins, in any assignment tried
+    reached: usize,
+}
+
+impl InputSearch<'_> {
+    /// give the rest of the inputs coins not in `spent`, already worth
+    /// `amount`, adding them to `spent` if they are worth enough
+    fn run(&mut self, spent: &mut BTreeSet<OutPoint>, amount: Amount) -> bool {
+        self.reached = self.reached.max(self.chosen.len());
+        let hint = match self.hints.get(self.chosen.len()) {
+            Some((_, hint)) => *hint,
+            None => {
+                self.best = self.best.max(Some(amount));
+                return amount >= self.required;
+            }
+        };
+        // coins of the same value are interchangeable
+        let mut tried = BTreeSet::new();
+        for coin in self.coins.iter() {
+            let value = Amount::from_sat(coin.txout.value);
+            if value < hint || spent.contains(&coin.outpoint) || !tried.insert(value) {
+                continue;
+            }
+            spent.insert(coin.outpoint);
+            self.chosen.push(coin.outpoint);
+            if self.run(spent, amount + value) {
+                return true;
+            }
+            self.chosen.pop();
+            spent.remove(&coin.outpoint);
+        }
+        false
+    }
+}
+
+impl Object {
+    /// check that `amount` is one this object accepts
+    fn check_funding_amount(&self, amount: Amount) -> Result<(), ObjectError> {
+        if self.amount_range.contains(amount) {
+            Ok(())
+        } else {
+            Err(ObjectError::FundingOutOfRange {
+                amount,
+                range: self.amount_range,
+            })
+        }
+    }
+
+    /// An unsigned transaction spending all of `coins` to this object's
+    /// address, less `fee`, for `Object::bind_aggregated`. The coins must be
+    /// worth more than `fee`.
+    pub fn aggregate_funding(
+        &self,
+        coins: &[FundingCoin],
+        fee: Amount,
+    ) -> Result<Transaction, ObjectError> {
+        distinct(coins.iter().map(|c| &c.outpoint))?;
+        let total = Amount::from_sat(coins.iter().map(|c| c.txout.value).sum());
+        let amount = total
+            .checked_sub(fee)
+            .ok_or(ObjectError::FeeExceedsFunding { total, fee })?;
+        self.check_funding_amount(amount)?;
+        Ok(Transaction {
+            version: 2,
+            lock_time: 0,
+            input: coins
+                .iter()
+                .map(|c| TxIn {
+                    previous_output: c.outpoint,
+                    ..Default::default()
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: amount.as_sat(),
+                script_pubkey: Script::from(self.address.clone()),
+            }],
+        })
+    }
+
+    /// Bind this object to the output of `funding` paying it, e.g. from
+    /// `Object::aggregate_funding`, after checking that output is an amount
+    /// it accepts and that `funding` spends no coin twice.
+    ///
+    /// `funding` is added to `blockdata`.
+    pub fn bind_aggregated(
+        &self,
+        funding: &Transaction,
+        blockdata: Rc<dyn TxIndex>,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Program, ObjectError> {
+        distinct(funding.input.iter().map(|i| &i.previous_output))?;
+        let script = Script::from(self.address.clone());
+        let (vout, output) = funding
+            .output
+            .iter()
+            .enumerate()
+            .find(|(_, o)| o.script_pubkey == script)
+            .ok_or_else(|| ObjectError::NoFundingOutput(funding.txid()))?;
+        self.check_funding_amount(Amount::from_sat(output.value))?;
+        let txid = blockdata.add_tx(Arc::new(funding.clone()))?;
+        self.bind_psbt(
+            OutPoint::new(txid, vout as u32),
+            BTreeMap::new(),
+            blockdata,
+            emulator,
+        )
+    }
+
+    /// Which of `coins` each input declared with
+    /// `Builder::add_sequenced_input` spends, for the templates of this
+    /// object's tree declaring any, as the `output_map` of
+    /// `Object::bind_psbt`.
+    ///
+    /// An input for a specific outpoint spends that coin, which must be one
+    /// of `coins`, and an input for any coin spends one of `coins` worth at
+    /// least its amount hint not spent by the template or any template before
+    /// it in the tree. The coins each template spends must be worth at least
+    /// the external funds it added, and the inputs for any coin are assigned
+    /// by a backtracking search trying `coins` in order until they are.
+    pub fn assign_declared_inputs(
+        &self,
+        coins: &[FundingCoin],
+    ) -> Result<BTreeMap<Sha256, Vec<Option<OutPoint>>>, ObjectError> {
+        distinct(coins.iter().map(|c| &c.outpoint))?;
+        let mut assigned = BTreeMap::new();
+        // each object, with the coins spent by the templates before it
+        let mut stack = vec![(self, BTreeSet::new())];
+        while let Some((object, spent_before)) = stack.pop() {
+            for (h, template) in object.ctv_to_tx.iter().chain(object.suggested_txs.iter()) {
+                let mut spent = spent_before.clone();
+                let mut outpoints = vec![None; template.inputs.len()];
+                let declared: Vec<_> = template
+                    .inputs
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, m)| Some((i, m.external.as_ref()?)))
+                    .collect();
+                // the specific outpoints first, so no input for any coin
+                // takes one of them
+                for (i, external) in declared.iter() {
+                    if let PrevoutSpec::Outpoint(o) = external.prevout {
+                        if !coins.iter().any(|c| c.outpoint == o) {
+                            return Err(ObjectError::UnfundedInput {
+                                template: *h,
+                                input: *i,
+                            });
+                        }
+                        if !spent.insert(o) {
+                            return Err(ObjectError::DuplicateFunding(o));
+                        }
+                        outpoints[*i] = Some(o);
+                    }
+                }
+                let any: Vec<_> = declared
+                    .iter()
+                    .filter(|(_, e)| matches!(e.prevout, PrevoutSpec::Any))
+                    .map(|(i, e)| (*i, e.amount_hint.unwrap_or(Amount::from_sat(0))))
+                    .collect();
+                let base = Amount::from_sat(
+                    coins
+                        .iter()
+                        .filter(|c| outpoints.contains(&Some(c.outpoint)))
+                        .map(|c| c.txout.value)
+                        .sum(),
+                );
+                let required = template.external_funds.unwrap_or(Amount::from_sat(0));
+                let mut search = InputSearch {
+                    coins,
+                    hints: &any,
+                    required,
+                    chosen: vec![],
+                    best: None,
+                    reached: 0,
+                };
+                if !search.run(&mut spent, base) {
+                    return Err(match search.best {
+                        Some(amount) => ObjectError::InsufficientInputs {
+                            template: *h,
+                            amount,
+                            required,
+                        },
+                        None => ObjectError::UnfundedInput {
+                            template: *h,
+                            input: any[search.reached].0,
+                        },
+                    });
+                }
+                for ((i, _), o) in any.iter().zip(search.chosen) {
+                    outpoints[*i] = Some(o);
+                }
+                if !declared.is_empty() {
+                    assigned.insert(*h, outpoints);
+                }
+                // external outputs aren't contracts to follow
+                stack.extend(
+                    template
+                        .outputs
+                        .iter()
+                        .filter(|o| !o.added_metadata.is_external())
+                        .map(|o| (&o.contract, spent.clone())),
+                );
+            }
+        }
+        Ok(assigned)
+    }
+
+    /// Bind this object to `own`, the coin paying it, with the inputs its
+    /// templates declared spending `coins`, see
+    /// `Object::assign_declared_inputs`. `own` must be an amount this object
+    /// accepts, and not also one of `coins`.
+    pub fn bind_declared_inputs(
+        &self,
+        own: &FundingCoin,
+        coins: &[FundingCoin],
+        blockdata: Rc<dyn TxIndex>,
+        emulator: &dyn CTVEmulator,
+    ) -> Result<Program, ObjectError> {
+        distinct(std::iter::once(&own.outpoint).chain(coins.iter().map(|c| &c.outpoint)))?;
+        self.check_funding_amount(Amount::from_sat(own.txout.value))?;
+        let output_map = self.assign_declared_inputs(coins)?;
+        self.bind_psbt(own.outpoint, output_map, blockdata, emulator)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{Context, Contract, TxTmplIt};
+    use crate::template::builder::Builder;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("funding").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    /// `G`
+    fn nth_key(n: usize) -> XOnlyPublicKey {
+        ["79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"][n]
+            .parse()
+            .unwrap()
+    }
+    fn payout<'a, T>(
+        fee_policy: FeePolicy,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard: &[],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new("payout".into()),
+                fee_policy,
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn leaving_fees(builder: Builder) -> TxTmplIt {
+        let rest = builder.ctx().funds();
+        builder.add_fees(rest)?.into()
+    }
+    /// pays its funds to `nth_key(0)` in two halves
+    struct Halves;
+    fn split_in_half(_: &Halves, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        ctx.template()
+            .add_output(half, &nth_key(0), None)?
+            .add_output(half, &nth_key(0), None)?
+            .into()
+    }
+    impl Halves {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, split_in_half)
+        }
+    }
+    impl Contract for Halves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    /// pays `nth_key(0)` its funds and two coins worth 50_000 contributed by
+    /// wallets, of at least the amounts hinted, less a fee
+    struct Pooled([u64; 2]);
+    fn pay_with_inputs(s: &Pooled, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let amount = ctx.funds() + Amount::from_sat(49_000);
+        leaving_fees(
+            ctx.template()
+                .add_sequenced_input(PrevoutSpec::Any, None, Some(Amount::from_sat(s.0[0])))
+                .add_sequenced_input(PrevoutSpec::Any, None, Some(Amount::from_sat(s.0[1])))
+                .add_amount(Amount::from_sat(50_000))
+                .add_output(amount, &nth_key(0), None)?,
+        )
+    }
+    impl Pooled {
+        fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, pay_with_inputs)
+        }
+    }
+    impl Contract for Pooled {
+        declare! {then, Self::payout}
+        declare! {non updatable}
+    }
+    fn coin(n: u8, value: u64) -> FundingCoin {
+        use bitcoin::hashes::Hash;
+        FundingCoin {
+            outpoint: bitcoin::OutPoint::new(bitcoin::Txid::from_inner([n; 32]), 0),
+            txout: bitcoin::TxOut {
+                value,
+                script_pubkey: Default::default(),
+            },
+        }
+    }
+    #[test]
+    fn aggregated_funding() {
+        use sapio_base::txindex::TxIndexLogger;
+        let compiled = ctx().compile(Halves).unwrap();
+        let max = compiled.amount_range.max().as_sat();
+        let coins = [coin(1, max / 2), coin(2, max - max / 2 + 500)];
+        let fee = Amount::from_sat(500);
+        let funding = compiled.aggregate_funding(&coins, fee).unwrap();
+        assert_eq!(funding.input.len(), 2);
+        assert_eq!(funding.output[0].value, max);
+        let program = compiled
+            .bind_aggregated(&funding, Rc::new(TxIndexLogger::new()), &CTVAvailable)
+            .unwrap();
+        assert_eq!(
+            program.program[&compiled.root_path].out,
+            bitcoin::OutPoint::new(funding.txid(), 0)
+        );
+
+        assert!(matches!(
+            compiled.aggregate_funding(&[coins[0].clone(), coins[0].clone()], fee),
+            Err(ObjectError::DuplicateFunding(o)) if o == coins[0].outpoint
+        ));
+        assert!(matches!(
+            compiled.aggregate_funding(&coins, Amount::from_sat(0)),
+            Err(ObjectError::FundingOutOfRange { amount, .. })
+                if amount == Amount::from_sat(max + 500)
+        ));
+        assert!(matches!(
+            compiled.aggregate_funding(&coins, Amount::from_sat(max + 501)),
+            Err(ObjectError::FeeExceedsFunding { total, .. })
+                if total == Amount::from_sat(max + 500)
+        ));
+        let mut twice = funding.clone();
+        twice.input.push(twice.input[0].clone());
+        assert!(matches!(
+            compiled.bind_aggregated(&twice, Rc::new(TxIndexLogger::new()), &CTVAvailable),
+            Err(ObjectError::DuplicateFunding(_))
+        ));
+    }
+    #[test]
+    fn declared_input_funding() {
+        use crate::contract::abi::studio::SapioStudioFormat;
+        use bitcoin::util::psbt::PartiallySignedTransaction;
+        use sapio_base::txindex::TxIndexLogger;
+        let compiled = ctx().compile(Pooled([30_000, 20_000])).unwrap();
+        let (h, _) = compiled.ctv_to_tx.iter().next().unwrap();
+        let own = coin(0, 100_000);
+        // each input takes the first coin worth its hint
+        let coins = [coin(1, 5_000), coin(2, 35_000), coin(3, 20_000)];
+        let assigned = compiled.assign_declared_inputs(&coins).unwrap();
+        assert_eq!(
+            assigned[h],
+            vec![None, Some(coins[1].outpoint), Some(coins[2].outpoint)]
+        );
+        // unless that leaves no coin for a later input
+        let ascending = ctx().compile(Pooled([20_000, 30_000])).unwrap();
+        let (h_ascending, _) = ascending.ctv_to_tx.iter().next().unwrap();
+        let tight = [coin(1, 35_000), coin(2, 25_000)];
+        assert_eq!(
+            ascending.assign_declared_inputs(&tight).unwrap()[h_ascending],
+            vec![None, Some(tight[1].outpoint), Some(tight[0].outpoint)]
+        );
+        // or the coins taken aren't worth enough together
+        let unhinted = ctx().compile(Pooled([0, 0])).unwrap();
+        let spread = [coin(1, 10_000), coin(2, 40_000), coin(3, 15_000)];
+        let (h_unhinted, _) = unhinted.ctv_to_tx.iter().next().unwrap();
+        assert_eq!(
+            unhinted.assign_declared_inputs(&spread).unwrap()[h_unhinted],
+            vec![None, Some(spread[0].outpoint), Some(spread[1].outpoint)]
+        );
+        assert!(matches!(
+            unhinted.assign_declared_inputs(&[coin(1, 10_000), coin(2, 20_000), coin(3, 15_000)]),
+            Err(ObjectError::InsufficientInputs { amount, .. }) if amount == Amount::from_sat(35_000)
+        ));
+        let program = compiled
+            .bind_declared_inputs(&own, &coins, Rc::new(TxIndexLogger::new()), &CTVAvailable)
+            .unwrap();
+        let SapioStudioFormat::LinkedPSBT { psbt, .. } =
+            &program.program[&compiled.root_path].txs[0];
+        let psbt: PartiallySignedTransaction =
+            bitcoin::consensus::deserialize(&base64::decode(psbt).unwrap()).unwrap();
+        let spent: Vec<_> = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|i| i.previous_output)
+            .collect();
+        assert_eq!(
+            spent,
+            vec![own.outpoint, coins[1].outpoint, coins[2].outpoint]
+        );
+
+        let bind = |own: &_, coins: &[_]| {
+            compiled.bind_declared_inputs(own, coins, Rc::new(TxIndexLogger::new()), &CTVAvailable)
+        };
+        assert!(matches!(
+            bind(&coins[1], &coins),
+            Err(ObjectError::DuplicateFunding(o)) if o == coins[1].outpoint
+        ));
+        assert!(matches!(
+            bind(&own, &coins[..2]),
+            Err(ObjectError::UnfundedInput { input: 2, .. })
+        ));
+        assert!(matches!(
+            bind(&coin(0, 200_000), &coins),
+            Err(ObjectError::FundingOutOfRange { .. })
+        ));
+    }
+}
@@ -0,0 +1,425 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Drawing an `Object`'s transaction tree as a Graphviz DOT or Mermaid graph
+use super::Object;
+use crate::template::Template;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::util::amount::Amount;
+use std::fmt::Write;
+
+/// The longest a descriptor is drawn before it is cut short
+const MAX_DESCRIPTOR: usize = 24;
+
+/// What a node of the graph is
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NodeKind {
+    /// a contract, or the address an output pays
+    Object,
+    /// a template the contract commits to
+    Committed,
+    /// a template suggested by a continuation
+    Suggested,
+    /// a continuation point
+    Continuation,
+}
+
+struct Node {
+    id: String,
+    kind: NodeKind,
+    lines: Vec<String>,
+}
+
+struct Edge {
+    from: String,
+    to: String,
+    label: Option<String>,
+    /// drawn dashed, for suggested templates and continuation points
+    dashed: bool,
+}
+
+/// The nodes and edges of an `Object`'s tree, in the order they are found
+#[derive(Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+impl Graph {
+    fn node(&mut self, prefix: char, kind: NodeKind, lines: Vec<String>) -> String {
+        let id = format!("{}{}", prefix, self.nodes.len());
+        self.nodes.push(Node {
+            id: id.clone(),
+            kind,
+            lines,
+        });
+        id
+    }
+    fn edge(&mut self, from: &str, to: &str, label: Option<String>, dashed: bool) {
+        self.edges.push(Edge {
+            from: from.into(),
+            to: to.into(),
+            label,
+            dashed,
+        })
+    }
+    /// add `object`, paid `amount` if it is an output, and everything under
+    /// it, returning its node
+    fn add_object(&mut self, object: &Object, amount: Option<Amount>) -> String {
+        let mut lines: Vec<String> = Some(String::from(object.root_path.0.as_ref().clone()))
+            .filter(|p| !p.is_empty())
+            .into_iter()
+            .collect();
+        let range = object.amount_range;
+        lines.push(match (amount, range.min_bound(), range.max_bound()) {
+            (Some(amount), _, _) => format!("{} sats", amount.as_sat()),
+            (None, Some(min), Some(max)) if min == max => format!("{} sats", max.as_sat()),
+            (None, min, Some(max)) => {
+                format!("{}..{} sats", min.map_or(0, |m| m.as_sat()), max.as_sat())
+            }
+            (None, Some(min), None) => format!("{}.. sats", min.as_sat()),
+            (None, None, None) => "any amount".into(),
+        });
+        if object.ctv_to_tx.is_empty() && object.suggested_txs.is_empty() {
+            lines.push(address(&object.address));
+        }
+        let id = self.node('o', NodeKind::Object, lines);
+        for (kind, templates) in [
+            (NodeKind::Committed, &object.ctv_to_tx),
+            (NodeKind::Suggested, &object.suggested_txs),
+        ] {
+            for (h, template) in templates {
+                let mut lines: Vec<String> = object
+                    .branches
+                    .iter()
+                    .filter(|(_, hashes)| hashes.contains(h))
+                    .map(|(name, _)| name.clone())
+                    .collect();
+                lines.extend(template.metadata_map_s2s.label.clone());
+                lines.push(format!("{:.8}", h.to_string()));
+                lines.push(format!("{} sats", template.total_amount().as_sat()));
+                lines.extend(timelocks(template));
+                let t = self.node('t', kind, lines);
+                self.edge(&id, &t, None, kind == NodeKind::Suggested);
+                for (vout, output) in template.outputs.iter().enumerate() {
+                    let child = self.add_object(&output.contract, Some(output.amount));
+                    let mut label = format!("#{}", vout);
+                    if let Some(l) = &output.added_metadata.label {
+                        label = format!("{} {}", label, l);
+                    }
+                    self.edge(&t, &child, Some(label), false);
+                }
+            }
+        }
+        for path in object.continue_apis.keys() {
+            // the path ends with the continuation's name, and then any
+            // fragments sapio adds, which start with `@`
+            let name = path
+                .0
+                .iter()
+                .map(|f| String::from(f.clone()))
+                .find(|f| !f.starts_with('@'));
+            let c = self.node('c', NodeKind::Continuation, name.into_iter().collect());
+            self.edge(&id, &c, None, true);
+        }
+        id
+    }
+}
+
+/// what an output pays, for drawing
+fn address(a: &ExtendedAddress) -> String {
+    match a {
+        ExtendedAddress::Address(a) => a.to_string(),
+        ExtendedAddress::Descriptor(d) => {
+            let d = d.to_string();
+            if d.chars().count() > MAX_DESCRIPTOR {
+                format!("{}…", d.chars().take(MAX_DESCRIPTOR).collect::<String>())
+            } else {
+                d
+            }
+        }
+        ExtendedAddress::OpReturn(_) => "OP_RETURN".into(),
+        ExtendedAddress::Unknown(s) => format!("script {}", s.asm()),
+    }
+}
+
+/// the time locks `template`'s transaction enforces
+fn timelocks(template: &Template) -> Vec<String> {
+    let tx = &template.tx;
+    let mut locks = vec![];
+    if tx.lock_time != 0 && tx.input.iter().any(|i| i.sequence != 0xFFFF_FFFF) {
+        locks.push(if tx.lock_time < 500_000_000 {
+            format!("at height {}", tx.lock_time)
+        } else {
+            format!("at time {}", tx.lock_time)
+        });
+    }
+    if tx.version >= 2 {
+        for (i, input) in tx.input.iter().enumerate() {
+            let s = input.sequence;
+            if s & (1 << 31) != 0 {
+                continue;
+            }
+            let value = s & 0xFFFF;
+            if value == 0 {
+                continue;
+            }
+            locks.push(if s & (1 << 22) != 0 {
+                format!("input {} after {} seconds", i, value * 512)
+            } else {
+                format!("input {} after {} blocks", i, value)
+            });
+        }
+    }
+    locks
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn mermaid_escape(s: &str) -> String {
+    s.replace('"', "#quot;")
+}
+
+impl Object {
+    /// This object's transaction tree as a Graphviz DOT graph.
+    ///
+    /// Contracts and the addresses outputs pay are boxes with their path and
+    /// amount, or the range of amounts this object accepts. Templates are
+    /// ellipses with their branch, hash, amount and time locks, and each
+    /// output is an edge from its template. Suggested templates are dashed,
+    /// and continuation points are diamonds with their name.
+    pub fn to_dot(&self) -> String {
+        let mut graph = Graph::default();
+        graph.add_object(self, None);
+        let mut s = String::from("digraph sapio {\n    node [fontname=\"monospace\"];\n");
+        for node in graph.nodes.iter() {
+            let label = dot_escape(&node.lines.join("\n")).replace('\n', "\\n");
+            let style = match node.kind {
+                NodeKind::Object => "shape=box",
+                NodeKind::Committed => "shape=ellipse",
+                NodeKind::Suggested => "shape=ellipse, style=dashed",
+                NodeKind::Continuation => "shape=diamond",
+            };
+            let _ = writeln!(s, "    {} [{}, label=\"{}\"];", node.id, style, label);
+        }
+        for edge in graph.edges.iter() {
+            let mut attrs = vec![];
+            if let Some(label) = &edge.label {
+                attrs.push(format!("label=\"{}\"", dot_escape(label)));
+            }
+            if edge.dashed {
+                attrs.push("style=dashed".into());
+            }
+            let attrs = if attrs.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", attrs.join(", "))
+            };
+            let _ = writeln!(s, "    {} -> {}{};", edge.from, edge.to, attrs);
+        }
+        s.push_str("}\n");
+        s
+    }
+
+    /// This object's transaction tree as a Mermaid flowchart, drawn as in
+    /// `Object::to_dot`
+    pub fn to_mermaid(&self) -> String {
+        let mut graph = Graph::default();
+        graph.add_object(self, None);
+        let mut s = String::from("flowchart TD\n");
+        for node in graph.nodes.iter() {
+            let label = mermaid_escape(&node.lines.join("\n")).replace('\n', "<br/>");
+            let (open, close) = match node.kind {
+                NodeKind::Object => ("[", "]"),
+                NodeKind::Committed | NodeKind::Suggested => ("(", ")"),
+                NodeKind::Continuation => ("{", "}"),
+            };
+            let _ = writeln!(s, "    {}{}\"{}\"{}", node.id, open, label, close);
+        }
+        for edge in graph.edges.iter() {
+            let arrow = if edge.dashed { "-.->" } else { "-->" };
+            match &edge.label {
+                Some(label) => {
+                    let _ = writeln!(
+                        s,
+                        "    {} {}|\"{}\"| {}",
+                        edge.from,
+                        arrow,
+                        mermaid_escape(label),
+                        edge.to
+                    );
+                }
+                None => {
+                    let _ = writeln!(s, "    {} {} {}", edge.from, arrow, edge.to);
+                }
+            }
+        }
+        for node in graph.nodes.iter().filter(|n| n.kind == NodeKind::Suggested) {
+            let _ = writeln!(s, "    style {} stroke-dasharray: 5 5", node.id);
+        }
+        s
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use crate::contract::actions::*;
+    use crate::contract::{empty, Contract, TxTmplIt};
+    use crate::Context;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("graph").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    /// `(n + 1) * G`
+    fn nth_key(n: usize) -> XOnlyPublicKey {
+        [
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+        ][n]
+            .parse()
+            .unwrap()
+    }
+    /// an NFT owned by `nth_key(owner)`, who may sell it, after the NFT sale
+    /// example
+    pub(crate) struct SellableNft {
+        pub(crate) owner: usize,
+    }
+    impl SellableNft {
+        fn owned() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|s, _| Clause::Key(nth_key(s.owner))),
+                None,
+            ))
+        }
+        fn sell() -> Option<Box<dyn CallableAsFoF<Self, ()>>> {
+            Some(Box::new(FinishOrFunc::<_, _, _, WebAPIDisabled> {
+                simp_gen: None,
+                coerce_args: Ok,
+                guard: &[GuardGen::Fn(Self::owned)],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                conditional_compile_if_args: &[],
+                func: |_, _, _| empty(),
+                schema: None,
+                returned_template_schema: None,
+                name: Arc::new("sell".into()),
+                f: Default::default(),
+                returned_txtmpls_modify_guards: false,
+                extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+                fee_policy: FeePolicy::None,
+                weight: None,
+                display_order: None,
+                hidden: false,
+                description: None,
+            }))
+        }
+    }
+    impl Contract for SellableNft {
+        declare! {updatable<()>, Self::sell}
+    }
+    /// the NFT sale example's `SimpleNFTSale`, selling `nft` to `buyer` for
+    /// `price` with `royalty_percent` of it paid to `artist`
+    pub(crate) struct SimpleNftSale {
+        pub(crate) nft: SellableNft,
+        pub(crate) buyer: usize,
+        pub(crate) artist: usize,
+        pub(crate) price: u64,
+        pub(crate) royalty_percent: u64,
+    }
+    fn sell_nft(s: &SimpleNftSale, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        use crate::template::OutputMeta;
+        let amt = ctx.funds();
+        let artist_gets = Amount::from_sat(s.price * s.royalty_percent / 100);
+        let seller_gets = Amount::from_sat(s.price) - artist_gets;
+        ctx.template()
+            .add_amount(Amount::from_sat(s.price))
+            .add_output(amt, &SellableNft { owner: s.buyer }, None)?
+            .add_sequence()
+            .add_output(seller_gets, &nth_key(s.nft.owner), None)?
+            .add_output_with_meta(
+                artist_gets,
+                &nth_key(s.artist),
+                OutputMeta::default().with_label("artist royalty"),
+            )?
+            .into()
+    }
+    impl SimpleNftSale {
+        fn transfer<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: sell_nft,
+                    name: Arc::new("transfer".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for SimpleNftSale {
+        declare! {then, Self::transfer}
+        declare! {non updatable}
+    }
+    pub(crate) fn nft_sale() -> SimpleNftSale {
+        SimpleNftSale {
+            nft: SellableNft { owner: 0 },
+            buyer: 1,
+            artist: 2,
+            price: 50_000,
+            royalty_percent: 10,
+        }
+    }
+    /// compare `got` to the snapshot `name`, or rewrite the snapshot if
+    /// SAPIO_UPDATE_SNAPSHOTS is set
+    pub(crate) fn check_snapshot(name: &str, got: &str, snapshot: &str) {
+        if std::env::var_os("SAPIO_UPDATE_SNAPSHOTS").is_some() {
+            let path = format!(
+                "{}/src/contract/abi/object/snapshots/{}",
+                env!("CARGO_MANIFEST_DIR"),
+                name
+            );
+            std::fs::write(path, got).unwrap();
+        } else {
+            assert_eq!(
+                got, snapshot,
+                "{} changed, see SAPIO_UPDATE_SNAPSHOTS",
+                name
+            );
+        }
+    }
+    #[test]
+    fn nft_sale_graph() {
+        let compiled = ctx().compile(nft_sale()).unwrap();
+        check_snapshot(
+            "nft_sale.dot",
+            &compiled.to_dot(),
+            include_str!("snapshots/nft_sale.dot"),
+        );
+        check_snapshot(
+            "nft_sale.mmd",
+            &compiled.to_mermaid(),
+            include_str!("snapshots/nft_sale.mmd"),
+        );
+    }
+}
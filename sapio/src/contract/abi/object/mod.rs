@@ -9,21 +9,37 @@
 pub mod error;
 pub use error::*;
 pub mod bind;
+pub mod dedup;
+pub use dedup::*;
 pub mod descriptors;
 pub use descriptors::*;
+pub mod diagnostics;
+pub use diagnostics::*;
+pub mod funding;
+pub use funding::*;
+pub mod graph;
+pub mod profile;
+pub use profile::*;
+pub mod skeleton;
+pub use skeleton::*;
+pub mod trace;
+use sapio_base::clause::SatisfactionWeight;
 use sapio_base::simp::CompiledObjectLT;
 use sapio_base::simp::SIMPAttachableAt;
 use sapio_base::Clause;
 use serde_json::Value;
+pub use trace::*;
 
-use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::continuation::{ContinuationPoint, QualifiedContinuation};
 pub use crate::contract::abi::studio::*;
 use crate::contract::CompilationError;
 use crate::template::Template;
 use crate::util::amountrange::AmountRange;
+use crate::util::canonical_json::{to_canonical_vec, CanonicalJsonError};
 use crate::util::extended_address::ExtendedAddress;
 use ::miniscript::*;
 use bitcoin::hashes::sha256;
+use bitcoin::hashes::Hash;
 
 use bitcoin::util::amount::Amount;
 
@@ -34,7 +50,7 @@ use sapio_base::serialization_helpers::SArc;
 use sapio_base::simp::SIMPError;
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 
 use std::sync::Arc;
 /// Metadata for Object, arbitrary KV set.
@@ -47,6 +63,10 @@ pub struct ObjectMetadata {
     pub simp: BTreeMap<i64, serde_json::Value>,
     /// SIMPs for guards
     pub simps_for_guards: BTreeMap<Clause, BTreeMap<i64, Vec<serde_json::Value>>>,
+    /// The label of the path the contract was compiled at, see
+    /// [`EffectPath::label`]
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub path: Option<String>,
 }
 impl ObjectMetadata {
     /// Is there any metadata in this field?
@@ -149,9 +169,154 @@ pub struct Object {
     pub amount_range: AmountRange,
     /// metadata generated for this contract
     pub metadata: ObjectMetadata,
+    /// non-fatal diagnostics generated during compilation, e.g. from a
+    /// `ConditionalCompileType::Warn`
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub warnings: Vec<String>,
+    /// findings about this contract which don't stop it from compiling,
+    /// see `Context::warn`. Those of nested contracts stay with them, see
+    /// `Object::all_diagnostics`.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub diagnostics: Vec<Diagnostic>,
+    /// the names of the `ThenFunc` branches compiled into this object, with the
+    /// hashes of the templates each one returned
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub branches: BTreeMap<String, Vec<sha256::Hash>>,
+    /// the witness weight needed to satisfy the guards of each `ThenFunc` and
+    /// `FinishOrFunc` branch by name, unless they are unsatisfiable
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub satisfaction_weights: BTreeMap<String, SatisfactionWeights>,
+    /// the conditional compilation decisions made, if tracing was enabled
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub compile_trace: Option<CompileTrace>,
+    /// how each committed template is enforced, if a covenant backend was
+    /// chosen with `Context::with_covenant_backend`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub covenants: BTreeMap<sha256::Hash, TemplateCovenant>,
+    /// where the time compiling this contract and those nested in it went,
+    /// if profiling was enabled, see `Context::with_profiling`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub profile: Option<CompileProfile>,
+    /// the taproot internal key, if this is a taproot output
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub internal_key: Option<InternalKey>,
+    /// who signs for this contract's templates, by `CTVEmulator::describe`,
+    /// if an emulator was chosen for the subtree it is in with
+    /// `Context::with_emulator`
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub emulator: Option<String>,
+    /// the keys the emulator signs this contract's templates with, if it
+    /// signs any, see `Object::key_report`
+    #[serde(skip_serializing_if = "BTreeSet::is_empty", default)]
+    #[schemars(with = "BTreeSet<String>")]
+    pub emulator_keys: BTreeSet<bitcoin::XOnlyPublicKey>,
+    /// if this was compiled as a dry run, see `Context::dry_run`, paying to
+    /// unspendable keys, so that it is refused by anything binding or
+    /// funding it
+    #[serde(skip_serializing_if = "std::ops::Not::not", default)]
+    pub skeleton: bool,
+}
+
+/// The internal key of a taproot output, and why it was chosen
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InternalKey {
+    /// the key
+    #[schemars(with = "String")]
+    pub key: bitcoin::XOnlyPublicKey,
+    /// where the key came from
+    pub source: InternalKeySource,
+}
+
+/// Where a taproot internal key came from, see
+/// `Context::with_internal_key_promotion`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InternalKeySource {
+    /// a branch which was just this key, taken out of the script tree
+    Promoted,
+    /// a branch which was just this key, also left in the script tree
+    Kept,
+    /// a key nobody knows the secret key of, as no branch was just a key
+    Unspendable,
+}
+
+/// How a committed template is enforced, see `Context::with_covenant_backend`
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateCovenant {
+    /// by `OP_CHECKTEMPLATEVERIFY`
+    Native,
+    /// by an emulator's oracle
+    Emulated,
+    /// by either of them
+    Both,
+}
+
+/// Bounds on the witness weight to satisfy a `Clause`, see
+/// `sapio_base::clause::SatisfactionWeight`. This doesn't count the script
+/// or control block of the taproot leaf the clause is compiled to.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SatisfactionWeights {
+    /// a lower bound on any satisfaction
+    pub min: usize,
+    /// an upper bound on any satisfaction
+    pub max: usize,
+}
+
+impl SatisfactionWeights {
+    /// the bounds for `clause`, if it is satisfiable
+    pub fn of(clause: &Clause) -> Option<Self> {
+        Some(SatisfactionWeights {
+            min: clause.min_satisfaction_weight()?,
+            max: clause.max_satisfaction_weight()?,
+        })
+    }
+}
+
+/// The fee to broadcast the most expensive path of committed templates
+/// through a contract tree, see `Object::tree_fee_estimate`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TreeFeeEstimate {
+    /// the total fee of the templates along the path
+    pub fee: Amount,
+    /// the hashes of the templates along the path, starting from the root
+    pub path: Vec<sha256::Hash>,
 }
 
 impl Object {
+    /// Estimate the fee, at `feerate` sats per vbyte, to broadcast every
+    /// committed template along the most expensive path from this contract
+    /// down to a leaf.
+    ///
+    /// Each template is sized with `Template::estimated_vsize`, so this is an
+    /// upper bound, but it does not count any inputs other than the ones
+    /// spending the contracts in the tree.
+    pub fn tree_fee_estimate(&self, feerate: Amount) -> TreeFeeEstimate {
+        self.ctv_to_tx
+            .iter()
+            .map(|(h, t)| {
+                let worst_child = t
+                    .outputs
+                    .iter()
+                    .map(|o| o.contract.tree_fee_estimate(feerate))
+                    .max_by_key(|e| e.fee);
+                let mut estimate = TreeFeeEstimate {
+                    fee: feerate * t.estimated_vsize(),
+                    path: vec![*h],
+                };
+                if let Some(child) = worst_child {
+                    estimate.fee += child.fee;
+                    estimate.path.extend(child.path);
+                }
+                estimate
+            })
+            .max_by_key(|e| e.fee)
+            .unwrap_or(TreeFeeEstimate {
+                fee: Amount::from_sat(0),
+                path: vec![],
+            })
+    }
+
     /// Creates an object from a given address. The optional AmountRange argument determines the
     /// safe bounds the contract can receive, otherwise it is set to any.
     pub fn from_address(address: bitcoin::Address, a: Option<AmountRange>) -> Object {
@@ -172,6 +337,17 @@ impl Object {
                 a
             }),
             metadata: Default::default(),
+            warnings: vec![],
+            diagnostics: vec![],
+            branches: BTreeMap::new(),
+            satisfaction_weights: BTreeMap::new(),
+            compile_trace: None,
+            covenants: BTreeMap::new(),
+            profile: None,
+            internal_key: None,
+            skeleton: false,
+            emulator: None,
+            emulator_keys: BTreeSet::new(),
         }
     }
 
@@ -186,6 +362,40 @@ impl Object {
             .ok_or_else(|| ObjectError::UnknownScriptType(script.clone()))
             .map(|m| Object::from_address(m, a))
     }
+    /// Creates an object from a script which may not have an address, e.g. an
+    /// anchor output. The optional AmountRange argument determines the safe
+    /// bounds the contract can receive, otherwise it is set to any.
+    pub fn from_unknown_script(script: bitcoin::Script, a: Option<AmountRange>) -> Object {
+        Object {
+            ctv_to_tx: BTreeMap::new(),
+            suggested_txs: BTreeMap::new(),
+            continue_apis: Default::default(),
+            root_path: SArc(EffectPath::push(
+                None,
+                PathFragment::Named(SArc(Arc::new("".into()))),
+            )),
+            address: ExtendedAddress::Unknown(script),
+            descriptor: None,
+            amount_range: a.unwrap_or_else(|| {
+                let mut a = AmountRange::new();
+                a.update_range(Amount::min_value());
+                a.update_range(Amount::from_sat(21_000_000 * 100_000_000));
+                a
+            }),
+            metadata: Default::default(),
+            warnings: vec![],
+            diagnostics: vec![],
+            branches: BTreeMap::new(),
+            satisfaction_weights: BTreeMap::new(),
+            compile_trace: None,
+            covenants: BTreeMap::new(),
+            profile: None,
+            internal_key: None,
+            skeleton: false,
+            emulator: None,
+            emulator_keys: BTreeSet::new(),
+        }
+    }
     /// create an op_return of no more than 40 bytes
     pub fn from_op_return<'a, I: ?Sized>(data: &'a I) -> Result<Object, ObjectError>
     where
@@ -203,12 +413,59 @@ impl Object {
             descriptor: None,
             amount_range: AmountRange::new(),
             metadata: Default::default(),
+            warnings: vec![],
+            diagnostics: vec![],
+            branches: BTreeMap::new(),
+            satisfaction_weights: BTreeMap::new(),
+            compile_trace: None,
+            covenants: BTreeMap::new(),
+            profile: None,
+            internal_key: None,
+            skeleton: false,
+            emulator: None,
+            emulator_keys: BTreeSet::new(),
         })
     }
 
-    /// converts a descriptor and an optional AmountRange to a Object object.
+    /// create a zero value OP_RETURN carrying `data`, of any length, see
+    /// `Builder::add_data_output`
+    pub fn from_data_carrier(data: &[u8]) -> Object {
+        let mut amount_range = AmountRange::new();
+        amount_range.update_range(Amount::from_sat(0));
+        Object {
+            ctv_to_tx: BTreeMap::new(),
+            suggested_txs: BTreeMap::new(),
+            continue_apis: Default::default(),
+            root_path: SArc(EffectPath::push(
+                None,
+                PathFragment::Named(SArc(Arc::new("".into()))),
+            )),
+            address: ExtendedAddress::make_data_carrier(data),
+            descriptor: None,
+            amount_range,
+            metadata: Default::default(),
+            warnings: vec![],
+            diagnostics: vec![],
+            branches: BTreeMap::new(),
+            satisfaction_weights: BTreeMap::new(),
+            compile_trace: None,
+            covenants: BTreeMap::new(),
+            profile: None,
+            internal_key: None,
+            skeleton: false,
+            emulator: None,
+            emulator_keys: BTreeSet::new(),
+        }
+    }
+
+    /// converts a descriptor, the network to render its address for, and an
+    /// optional AmountRange to a Object object.
     /// This can be used for e.g. creating raw SegWit Scripts.
-    pub fn from_descriptor<T>(d: Descriptor<T>, a: Option<AmountRange>) -> Self
+    pub fn from_descriptor<T>(
+        d: Descriptor<T>,
+        network: bitcoin::Network,
+        a: Option<AmountRange>,
+    ) -> Self
     where
         Descriptor<T>: Into<SupportedDescriptors>,
         T: MiniscriptKey + ToPublicKey,
@@ -221,7 +478,7 @@ impl Object {
                 None,
                 PathFragment::Named(SArc(Arc::new("".into()))),
             )),
-            address: d.address(bitcoin::Network::Bitcoin).unwrap().into(),
+            address: d.address(network).unwrap().into(),
             descriptor: Some(d.into()),
             amount_range: a.unwrap_or_else(|| {
                 let mut a = AmountRange::new();
@@ -230,6 +487,279 @@ impl Object {
                 a
             }),
             metadata: Default::default(),
+            warnings: vec![],
+            diagnostics: vec![],
+            branches: BTreeMap::new(),
+            satisfaction_weights: BTreeMap::new(),
+            compile_trace: None,
+            covenants: BTreeMap::new(),
+            profile: None,
+            internal_key: None,
+            skeleton: false,
+            emulator: None,
+            emulator_keys: BTreeSet::new(),
+        }
+    }
+    /// The descriptor of every object in the tree with a known one, keyed by
+    /// its `root_path`, including the contracts created by its templates.
+    ///
+    /// Objects not made by compiling a contract, e.g. by
+    /// `Object::from_descriptor`, all have the empty path, so only the first
+    /// of those is kept.
+    pub fn descriptors(&self) -> BTreeMap<SArc<EffectPath>, SupportedDescriptors> {
+        let mut found = BTreeMap::new();
+        self.collect_descriptors(&mut found);
+        found
+    }
+    fn collect_descriptors(&self, found: &mut BTreeMap<SArc<EffectPath>, SupportedDescriptors>) {
+        if let Some(d) = &self.descriptor {
+            found
+                .entry(self.root_path.clone())
+                .or_insert_with(|| d.clone());
+        }
+        for tmpl in self.ctv_to_tx.values().chain(self.suggested_txs.values()) {
+            for output in tmpl.outputs.iter() {
+                output.contract.collect_descriptors(found);
+            }
+        }
+    }
+    /// The request for bitcoind's `importdescriptors` RPC to watch every
+    /// object in `Object::descriptors`, labeled with its path.
+    pub fn import_descriptors_request(&self) -> Value {
+        self.descriptors()
+            .into_iter()
+            .map(|(path, d)| {
+                serde_json::json!({
+                    "desc": d.to_string(),
+                    "timestamp": "now",
+                    "label": String::from(path.0.as_ref().clone()),
+                })
+            })
+            .collect()
+    }
+    /// Every continuation reachable from this object, including those of the
+    /// contracts created by its templates, in depth first order.
+    pub fn continuation_points(&self) -> Vec<QualifiedContinuation> {
+        let mut found: Vec<QualifiedContinuation> =
+            self.continue_apis.values().map(Into::into).collect();
+        for tmpl in self.ctv_to_tx.values().chain(self.suggested_txs.values()) {
+            for output in tmpl.outputs.iter() {
+                found.extend(output.contract.continuation_points());
+            }
         }
+        found
+    }
+
+    /// The diagnostics of this object and of every contract nested in it
+    pub fn all_diagnostics(&self) -> Vec<Diagnostic> {
+        let mut found = self.diagnostics.clone();
+        for tmpl in self.ctv_to_tx.values().chain(self.suggested_txs.values()) {
+            for output in tmpl.outputs.iter() {
+                found.extend(output.contract.all_diagnostics());
+            }
+        }
+        found
+    }
+
+    /// Fail if this object, or any contract nested in it, has a diagnostic
+    /// at `DiagnosticLevel::Warning`
+    pub fn deny_warnings(&self) -> Result<(), CompilationError> {
+        let warnings: Vec<_> = self
+            .all_diagnostics()
+            .into_iter()
+            .filter(|d| d.level >= DiagnosticLevel::Warning)
+            .collect();
+        if warnings.is_empty() {
+            Ok(())
+        } else {
+            Err(CompilationError::DeniedWarnings(warnings))
+        }
+    }
+
+    /// This object as canonical JSON, the stable form to hash or sign, see
+    /// `crate::util::canonical_json`. It deserializes like any other JSON
+    /// of the object.
+    pub fn canonical_bytes(&self) -> Result<Vec<u8>, CanonicalJsonError> {
+        to_canonical_vec(self)
+    }
+
+    /// The sha256 of `Object::canonical_bytes`, e.g. to pin a compiled
+    /// contract's version. The warnings and diagnostics of the object and
+    /// of its nested contracts are left out, so rewording one doesn't
+    /// change the hash.
+    pub fn canonical_hash(&self) -> Result<sha256::Hash, CanonicalJsonError> {
+        Ok(sha256::Hash::hash(
+            &self.without_diagnostics().canonical_bytes()?,
+        ))
+    }
+
+    /// A copy of this object with no warnings or diagnostics, in it or in
+    /// any contract nested in it
+    fn without_diagnostics(&self) -> Object {
+        let mut clean = self.clone();
+        clean.warnings.clear();
+        clean.diagnostics.clear();
+        for tmpl in clean
+            .ctv_to_tx
+            .values_mut()
+            .chain(clean.suggested_txs.values_mut())
+        {
+            for output in tmpl.outputs.iter_mut() {
+                output.contract = output.contract.without_diagnostics();
+            }
+        }
+        clean
+    }
+
+    /// This object as CBOR, a smaller and faster to parse form of the same
+    /// data as its JSON, see `sapio_base::cbor`
+    #[cfg(feature = "cbor")]
+    pub fn to_cbor(&self) -> Result<Vec<u8>, sapio_base::cbor::CborError> {
+        sapio_base::cbor::to_vec(self)
+    }
+
+    /// An object from `Object::to_cbor`
+    #[cfg(feature = "cbor")]
+    pub fn from_cbor(bytes: &[u8]) -> Result<Object, sapio_base::cbor::CborError> {
+        sapio_base::cbor::from_slice(bytes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[cfg(feature = "cbor")]
+    use crate::contract::abi::object::graph::test::nft_sale;
+    use crate::contract::actions::*;
+    use crate::contract::{Compilable, Context, Contract, TxTmplIt};
+    use crate::template::builder::{AnchorTo, DEFAULT_ANCHOR_SATS};
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::MapEffectDB;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    fn only_template(compiled: &Object) -> &Template {
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        compiled.ctv_to_tx.values().next().unwrap()
+    }
+    fn payout<'a, T>(
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard: &[],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new("payout".into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    /// pays a key, with an anchor to bump its fee
+    struct AnchoredLeaf;
+    fn pay_anchored(_: &AnchoredLeaf, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let amt = ctx.funds() - Amount::from_sat(DEFAULT_ANCHOR_SATS);
+        ctx.template()
+            .add_output(amt, &key, None)?
+            .add_anchor(AnchorTo::Key(key), None)?
+            .into()
+    }
+    impl AnchoredLeaf {
+        fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(pay_anchored)
+        }
+    }
+    impl Contract for AnchoredLeaf {
+        declare! {then, Self::payout}
+        declare! {non updatable}
+    }
+    /// splits its funds between two `AnchoredLeaf`s
+    struct AnchoredTree;
+    fn fan_out(_: &AnchoredTree, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat((ctx.funds().as_sat() - DEFAULT_ANCHOR_SATS) / 2);
+        ctx.template()
+            .add_output(half, &AnchoredLeaf, None)?
+            .add_output(half, &AnchoredLeaf, None)?
+            .add_anchor(AnchorTo::PayToAnchor, None)?
+            .into()
+    }
+    impl AnchoredTree {
+        fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(fan_out)
+        }
+    }
+    impl Contract for AnchoredTree {
+        declare! {then, Self::payout}
+        declare! {non updatable}
+    }
+    #[test]
+    fn tree_fee_estimate_takes_worst_path() {
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("object").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let rate = Amount::from_sat(2);
+        let tree = AnchoredTree.compile(ctx).unwrap();
+        let root = only_template(&tree);
+        let leaf = only_template(&root.outputs[0].contract);
+        let estimate = tree.tree_fee_estimate(rate);
+        assert_eq!(estimate.path, vec![root.hash(), leaf.hash()]);
+        assert_eq!(
+            estimate.fee,
+            rate * (root.estimated_vsize() + leaf.estimated_vsize())
+        );
+        let unspendable = Object::from_data_carrier(b"");
+        assert_eq!(unspendable.tree_fee_estimate(rate).fee, Amount::from_sat(0));
+    }
+    #[test]
+    fn canonical_hash_ignores_diagnostics() {
+        let plain = Object::from_data_carrier(b"sapio");
+        let mut noisy = plain.clone();
+        noisy.warnings.push("a warning".into());
+        noisy.diagnostics.push(Diagnostic {
+            level: DiagnosticLevel::Warning,
+            code: "unreachable_branch".into(),
+            path: EffectPath::try_from("noisy").unwrap(),
+            message: "reworded at will".into(),
+        });
+        assert_ne!(
+            plain.canonical_bytes().unwrap(),
+            noisy.canonical_bytes().unwrap()
+        );
+        assert_eq!(
+            plain.canonical_hash().unwrap(),
+            noisy.canonical_hash().unwrap()
+        );
+    }
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_nft_sale() {
+        let json = serde_json::to_string(
+            &Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("object").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .compile(nft_sale())
+            .unwrap(),
+        )
+        .unwrap();
+        let parsed: Object = serde_json::from_str(&json).unwrap();
+        let cbor = parsed.to_cbor().unwrap();
+        assert!(cbor.len() < json.len());
+        let back = Object::from_cbor(&cbor).unwrap();
+        assert_eq!(serde_json::to_string(&back).unwrap(), json);
+        assert_eq!(back.to_cbor().unwrap(), cbor);
     }
 }
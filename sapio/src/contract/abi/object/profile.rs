@@ -0,0 +1,338 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Where the time compiling a contract went, for finding compilation
+//! hotspots, see `Context::with_profiling`
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// The time spent compiling one contract, in nanoseconds, and the templates
+/// it compiled.
+///
+/// WASM plugins have no clock of their own, so without a `Clock` from the
+/// host the times they record are all zero.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct NodeProfile {
+    /// compiling the whole contract, including `nested_ns`
+    pub total_ns: u64,
+    /// evaluating its guards
+    pub guards_ns: u64,
+    /// calling its `ThenFunc` and `FinishOrFunc` branches, including any
+    /// contracts they compile
+    pub func_ns: u64,
+    /// compiling the contracts nested in its templates
+    pub nested_ns: u64,
+    /// compiling its leaves with miniscript
+    pub miniscript_ns: u64,
+    /// the templates compiled for it
+    pub templates: usize,
+}
+
+impl NodeProfile {
+    /// The time spent compiling the contract other than its nested contracts
+    pub fn self_ns(&self) -> u64 {
+        self.total_ns.saturating_sub(self.nested_ns)
+    }
+}
+
+/// The profile of every contract compiled for a top level contract, keyed by
+/// their paths
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq, Eq)]
+pub struct CompileProfile {
+    /// the profile of each contract compiled
+    pub nodes: BTreeMap<String, NodeProfile>,
+}
+
+impl CompileProfile {
+    /// The `n` contracts which took the longest to compile, not counting their
+    /// nested contracts, slowest first
+    pub fn slowest(&self, n: usize) -> Vec<(&String, &NodeProfile)> {
+        let mut nodes: Vec<_> = self.nodes.iter().collect();
+        nodes.sort_by_key(|(_, p)| std::cmp::Reverse(p.self_ns()));
+        nodes.truncate(n);
+        nodes
+    }
+    /// A table of the `n` slowest contracts, in microseconds
+    pub fn render(&self, n: usize) -> String {
+        let mut s = format!(
+            "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>9}  path\n",
+            "self", "total", "guards", "func", "nested", "miniscript", "templates"
+        );
+        for (path, p) in self.slowest(n) {
+            let _ = writeln!(
+                s,
+                "{:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>9}  {}",
+                p.self_ns() / 1000,
+                p.total_ns / 1000,
+                p.guards_ns / 1000,
+                p.func_ns / 1000,
+                p.nested_ns / 1000,
+                p.miniscript_ns / 1000,
+                p.templates,
+                path
+            );
+        }
+        s
+    }
+}
+
+/// A monotonic clock in nanoseconds, for where `Instant` has none, e.g. one
+/// imported by a WASM plugin from its host, see `Context::with_clock`
+pub type Clock = fn() -> u64;
+
+/// What a `Span` measures
+#[derive(Clone, Copy)]
+pub(crate) enum SpanKind {
+    /// compiling a contract, marking its path as one of the profile's nodes
+    Node,
+    /// see `NodeProfile::guards_ns`
+    Guards,
+    /// see `NodeProfile::func_ns`
+    Func,
+    /// see `NodeProfile::miniscript_ns`
+    Miniscript,
+}
+
+/// What was recorded at one path, before it is attributed to the contract
+/// compiled there or above it
+#[derive(Default)]
+struct Recorded {
+    node: bool,
+    profile: NodeProfile,
+}
+
+/// The spans recorded while compiling, shared by every `Context` derived from
+/// the one which enabled profiling
+#[derive(Clone, Default)]
+pub(crate) struct Profiler(Arc<Mutex<BTreeMap<String, Recorded>>>);
+
+impl Profiler {
+    /// start measuring `kind` at `path` with `clock`, if any, recorded once
+    /// the span is dropped
+    pub(crate) fn span(&self, kind: SpanKind, path: String, clock: Option<Clock>) -> Span {
+        Span {
+            profiler: self.clone(),
+            kind,
+            path,
+            clock,
+            start: now(clock),
+        }
+    }
+    /// count a template compiled at `path`
+    pub(crate) fn count_template(&self, path: String) {
+        self.0
+            .lock()
+            .unwrap()
+            .entry(path)
+            .or_default()
+            .profile
+            .templates += 1;
+    }
+    /// The profile of what has been recorded, each span attributed to the
+    /// contract at or nearest above its path. The recorded spans are removed.
+    pub(crate) fn take(&self) -> CompileProfile {
+        let recorded = std::mem::take(&mut *self.0.lock().unwrap());
+        let mut nodes: BTreeMap<String, NodeProfile> = recorded
+            .iter()
+            .filter(|(_, r)| r.node)
+            .map(|(path, _)| (path.clone(), NodeProfile::default()))
+            .collect();
+        for (path, r) in recorded {
+            let node = match nearest(&nodes, &path, true) {
+                Some(node) => node,
+                None => continue,
+            };
+            let p = nodes.get_mut(&node).expect("found above");
+            p.total_ns += r.profile.total_ns;
+            p.guards_ns += r.profile.guards_ns;
+            p.func_ns += r.profile.func_ns;
+            p.miniscript_ns += r.profile.miniscript_ns;
+            p.templates += r.profile.templates;
+        }
+        let nested: Vec<_> = nodes
+            .iter()
+            .filter_map(|(path, p)| Some((nearest(&nodes, path, false)?, p.total_ns)))
+            .collect();
+        for (parent, total) in nested {
+            nodes.get_mut(&parent).expect("found above").nested_ns += total;
+        }
+        CompileProfile { nodes }
+    }
+}
+
+/// the longest of `nodes` which is `path`, if `inclusive`, or above it
+fn nearest<T>(nodes: &BTreeMap<String, T>, path: &str, inclusive: bool) -> Option<String> {
+    let mut path = if inclusive {
+        path
+    } else {
+        &path[..path.rfind('/')?]
+    };
+    loop {
+        if nodes.contains_key(path) {
+            return Some(path.to_string());
+        }
+        path = &path[..path.rfind('/')?];
+    }
+}
+
+/// A measurement in progress, see `Context::span`
+pub(crate) struct Span {
+    profiler: Profiler,
+    kind: SpanKind,
+    path: String,
+    clock: Option<Clock>,
+    start: Option<u64>,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let ns = match (self.start, now(self.clock)) {
+            (Some(start), Some(end)) => end.saturating_sub(start),
+            _ => 0,
+        };
+        let mut recorded = self.profiler.0.lock().unwrap();
+        let r = recorded.entry(std::mem::take(&mut self.path)).or_default();
+        match self.kind {
+            SpanKind::Node => {
+                r.node = true;
+                r.profile.total_ns += ns
+            }
+            SpanKind::Guards => r.profile.guards_ns += ns,
+            SpanKind::Func => r.profile.func_ns += ns,
+            SpanKind::Miniscript => r.profile.miniscript_ns += ns,
+        }
+    }
+}
+
+/// the time from `clock`, or else since the first time asked
+#[cfg(not(target_arch = "wasm32"))]
+fn now(clock: Option<Clock>) -> Option<u64> {
+    lazy_static::lazy_static! {
+        static ref EPOCH: Instant = Instant::now();
+    }
+    Some(clock.map_or_else(|| EPOCH.elapsed().as_nanos() as u64, |c| c()))
+}
+
+/// the time from `clock`, as `Instant::now` panics without a clock
+#[cfg(target_arch = "wasm32")]
+fn now(clock: Option<Clock>) -> Option<u64> {
+    clock.map(|c| c())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::contract::actions::*;
+    use crate::contract::compiler::InternalCompilerTag;
+    use crate::contract::{Compilable, Context, Contract, TxTmplIt};
+    use bitcoin::util::amount::Amount;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::EffectPath;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("profile").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    /// a tree of identical halves, with `G` at each of its `2^depth` leaves
+    struct Halves(u32);
+    fn split_in_half(s: &Halves, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mut builder = ctx.template();
+        for _ in 0..2 {
+            builder = match s.0 {
+                1 => builder.add_output(half, &key, None)?,
+                depth => builder.add_output(half, &Halves(depth - 1), None)?,
+            };
+        }
+        builder.into()
+    }
+    impl Halves {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: split_in_half,
+                    name: Arc::new("payout".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Halves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    #[test]
+    fn profiling() {
+        assert!(Halves(2).compile(ctx()).unwrap().profile.is_none());
+        let ctx = ctx().with_profiling(true);
+        let profile = Halves(2)
+            .compile(ctx.internal_clone(InternalCompilerTag::for_tests()))
+            .unwrap()
+            .profile
+            .unwrap();
+        // the root and its two halves, with one template each
+        assert_eq!(profile.nodes.len(), 3);
+        assert!(profile.nodes.values().all(|p| p.templates == 1));
+        let root = &profile.nodes["profile"];
+        let halves: u64 = profile
+            .nodes
+            .iter()
+            .filter(|(path, _)| path.as_str() != "profile")
+            .map(|(_, p)| {
+                assert_eq!(p.nested_ns, 0);
+                p.total_ns
+            })
+            .sum();
+        // the halves are compiled by the root's branch
+        assert_eq!(root.nested_ns, halves);
+        assert!(root.total_ns >= root.func_ns && root.func_ns >= root.nested_ns);
+        assert_eq!(profile.slowest(2).len(), 2);
+        assert_eq!(profile.render(2).lines().count(), 3);
+        // each top level compilation reports only its own contracts
+        let again = Halves(2).compile(ctx).unwrap().profile.unwrap();
+        assert_eq!(
+            again.nodes.keys().collect::<Vec<_>>(),
+            profile.nodes.keys().collect::<Vec<_>>()
+        );
+        assert!(again.nodes.values().all(|p| p.templates == 1));
+        // a clock from elsewhere, e.g. a WASM plugin's host, is used instead
+        static TICKS: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        fn tick() -> u64 {
+            TICKS.fetch_add(1000, std::sync::atomic::Ordering::SeqCst) + 1000
+        }
+        let ticked = Halves(2)
+            .compile(self::ctx().with_profiling(true).with_clock(tick))
+            .unwrap()
+            .profile
+            .unwrap();
+        assert!(ticked.nodes["profile"].total_ns >= 1000);
+        assert!(ticked
+            .nodes
+            .values()
+            .all(|p| p.total_ns % 1000 == 0 && p.miniscript_ns % 1000 == 0));
+    }
+}
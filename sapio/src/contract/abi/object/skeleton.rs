@@ -0,0 +1,33 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! The result of a dry run compilation, see `Context::dry_run`
+use super::Object;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// An `Object` compiled as a dry run, with the shape of the real
+/// compilation but without its scripts, so that it can't be bound to a coin.
+///
+/// It serializes as the `Object` does, which has `"skeleton": true` for a
+/// dry run. The `Object` is marked as a skeleton even if it was compiled as a
+/// dry run with `Context::compile`, and binding it fails with
+/// `ObjectError::Skeleton`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
+pub struct SkeletonObject {
+    #[serde(flatten)]
+    object: Object,
+}
+
+impl SkeletonObject {
+    pub(crate) fn new(object: Object) -> Self {
+        SkeletonObject { object }
+    }
+    /// The dry run's `Object`. Every address in it is unspendable.
+    pub fn object(&self) -> &Object {
+        &self.object
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Traces of the decisions made during conditional compilation, for debugging
+//! why a branch was (or was not) included.
+use crate::contract::actions::ConditionalCompileType;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// What happened to a branch after conditional compilation was decided
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub enum BranchOutcome {
+    /// The branch is present in the contract, having emitted `templates`
+    Compiled {
+        /// the number of templates the branch emitted
+        templates: usize,
+    },
+    /// The branch was dropped because no templates were emitted, either
+    /// since it was not called (`Skippable` or `Never`) or since it was
+    /// `Nullable` and returned none.
+    NoTemplates,
+    /// Conditional compilation failed for this branch
+    Failed,
+}
+
+/// The decisions made for a single branch
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+pub struct BranchTrace {
+    /// The result of each `ConditionallyCompileIf`, in order
+    pub conditions: Vec<ConditionalCompileType>,
+    /// The merged result of `conditions`
+    pub merged: ConditionalCompileType,
+    /// What happened to the branch
+    pub outcome: BranchOutcome,
+    /// How many of the branch's leaves were removed since they can never be
+    /// spent, see `Context::with_unreachable_pruning`
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub pruned_leaves: usize,
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// A trace of conditional compilation, keyed by function name.
+///
+/// Only recorded if enabled with `Context::enable_compile_trace`.
+pub type CompileTrace = BTreeMap<String, BranchTrace>;
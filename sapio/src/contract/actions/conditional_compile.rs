@@ -6,11 +6,16 @@
 
 //! A decorator which can be used to skip clausesd based on a computation.
 use super::Context;
+use bitcoin::Network;
 use sapio_base::effects::PathFragment;
+use sapio_base::timelocks::AbsHeight;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use std::collections::LinkedList;
 /// Conditional Compilation function has specified that compilation of this
 /// function should be required or not.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub enum ConditionalCompileType {
     /// May proceed without calling this function at all
     Skippable,
@@ -26,12 +31,18 @@ pub enum ConditionalCompileType {
     NoConstraint,
     /// The branch should always trigger an error, with some reasons
     Fail(LinkedList<String>),
+    /// No Constraint, but compilation should record some non-fatal diagnostics.
+    /// When folded over a `ConditionallyCompileIfList`, the warnings are
+    /// collected separately and do not affect the outcome of the other rules.
+    Warn(LinkedList<String>),
 }
 
 impl ConditionalCompileType {
     /// Merge two `ConditionalCompileTypes` into one conditions.
     /// Precedence:
     ///     Fail > non-Fail ==> Fail
+    ///     Warn + Warn ==> Warn (concatenated)
+    ///     forall X != Warn. X > Warn ==> X
     ///     forall X. X > NoConstraint ==> X
     ///     Required > {Skippable, Nullable} ==> Required
     ///     Skippable > Nullable ==> Skippable
@@ -41,6 +52,16 @@ impl ConditionalCompileType {
         match (self, other) {
             (ConditionalCompileType::NoConstraint, x) => x,
             (x, ConditionalCompileType::NoConstraint) => x,
+            // Merge warnings, otherwise warnings are subordinate to any other
+            // condition.
+            (ConditionalCompileType::Warn(mut v), ConditionalCompileType::Warn(mut v2)) => {
+                ConditionalCompileType::Warn({
+                    v.append(&mut v2);
+                    v
+                })
+            }
+            (ConditionalCompileType::Warn(_), x) => x,
+            (x, ConditionalCompileType::Warn(_)) => x,
             // Merge error messages
             (ConditionalCompileType::Fail(mut v), ConditionalCompileType::Fail(mut v2)) => {
                 ConditionalCompileType::Fail({
@@ -87,6 +108,53 @@ impl ConditionalCompileType {
     }
 }
 
+impl ConditionalCompileType {
+    /// `NoConstraint` if the `Context` is compiling for one of `networks`,
+    /// otherwise `Never`.
+    ///
+    /// Useful for e.g. escape hatches which should only be present off of
+    /// mainnet.
+    pub fn on_networks(ctx: &Context, networks: &[Network]) -> Self {
+        if networks.contains(&ctx.network()) {
+            ConditionalCompileType::NoConstraint
+        } else {
+            ConditionalCompileType::Never
+        }
+    }
+
+    /// `Skippable` once the `Context`'s chain tip has reached `height`,
+    /// otherwise `NoConstraint`.
+    ///
+    /// Useful for pruning branches which can only be used before `height`,
+    /// e.g. a refund path which has already expired. If the tip was not
+    /// supplied the branch is kept, with a warning.
+    pub fn skip_if_expired(ctx: &Context, height: AbsHeight) -> Self {
+        match ctx.tip_height() {
+            Ok(tip) if tip >= height => ConditionalCompileType::Skippable,
+            Ok(_) => ConditionalCompileType::NoConstraint,
+            Err(e) => ConditionalCompileType::Warn(
+                std::iter::once(format!("could not check expiry at {}: {}", height.get(), e))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Attribute any failure reasons or warnings to the branch `name`, e.g.
+    /// "branch `redeem`: reason".
+    pub fn for_branch(self, name: &str) -> Self {
+        let attribute = |v: LinkedList<String>| {
+            v.into_iter()
+                .map(|reason| format!("branch `{}`: {}", name, reason))
+                .collect()
+        };
+        match self {
+            ConditionalCompileType::Fail(v) => ConditionalCompileType::Fail(attribute(v)),
+            ConditionalCompileType::Warn(v) => ConditionalCompileType::Warn(attribute(v)),
+            x => x,
+        }
+    }
+}
+
 /// A `ConditionallyCompileIf` is a function wrapper which generates some
 /// condition that must be met to disable a branch.
 ///
@@ -95,23 +163,67 @@ impl ConditionalCompileType {
 pub enum ConditionallyCompileIf<ContractSelf> {
     /// Fresh Variant may be called repeatedly
     Fresh(fn(&ContractSelf, Context) -> ConditionalCompileType),
+    /// Boxed Variant may be called repeatedly, and may capture its environment
+    Boxed(BoxedCompileIfFn<ContractSelf>),
+}
+
+/// A boxed closure which generates a ConditionalCompileType
+pub type BoxedCompileIfFn<ContractSelf> =
+    Box<dyn Fn(&ContractSelf, Context) -> ConditionalCompileType + Send + Sync>;
+
+impl<ContractSelf> ConditionallyCompileIf<ContractSelf> {
+    /// Evaluate the condition
+    pub fn call(&self, cself: &ContractSelf, ctx: Context) -> ConditionalCompileType {
+        match self {
+            ConditionallyCompileIf::Fresh(f) => f(cself, ctx),
+            ConditionallyCompileIf::Boxed(f) => f(cself, ctx),
+        }
+    }
 }
 
 /// A List of ConditionallyCompileIfs, for convenience
 pub type ConditionallyCompileIfList<'a, T> = &'a [fn() -> Option<ConditionallyCompileIf<T>>];
 
+/// A List of functions which decide if a `FinishOrFunc` should be invoked
+/// based on the arguments it was called with, evaluated after `coerce_args`.
+pub type ConditionallyCompileIfArgsList<'a, ContractSelf, SpecificArgs> =
+    &'a [fn(&ContractSelf, &Context, &SpecificArgs) -> ConditionalCompileType];
+
+impl ConditionalCompileType {
+    fn traced(self, trace: &mut Option<&mut Vec<ConditionalCompileType>>) -> Self {
+        if let Some(t) = trace {
+            t.push(self.clone());
+        }
+        self
+    }
+}
+
 pub(crate) struct CCILWrapper<'a, T>(pub ConditionallyCompileIfList<'a, T>);
 
 impl<'a, T> CCILWrapper<'a, T> {
-    /// Assembles the list by folding merge over it
-    pub fn assemble(&self, self_ref: &T, context: &mut Context) -> ConditionalCompileType {
+    /// Assembles the list by folding merge over it, returning the merged
+    /// result and any warnings separately.
+    ///
+    /// If `trace` is provided, each individual result is recorded into it.
+    pub fn assemble(
+        &self,
+        self_ref: &T,
+        context: &mut Context,
+        mut trace: Option<&mut Vec<ConditionalCompileType>>,
+    ) -> (ConditionalCompileType, ConditionalCompileType) {
         self.0
             .iter()
             .filter_map(|compf| compf())
             .zip((0..).flat_map(|i| context.derive(PathFragment::Branch(i)).ok()))
-            .fold(ConditionalCompileType::NoConstraint, |acc, (cond, c)| {
-                let ConditionallyCompileIf::Fresh(f) = cond;
-                acc.merge(f(self_ref, c))
-            })
+            .fold(
+                (
+                    ConditionalCompileType::NoConstraint,
+                    ConditionalCompileType::NoConstraint,
+                ),
+                |(acc, warnings), (cond, c)| match cond.call(self_ref, c).traced(&mut trace) {
+                    w @ ConditionalCompileType::Warn(_) => (acc, warnings.merge(w)),
+                    x => (acc.merge(x), warnings),
+                },
+            )
     }
 }
@@ -8,8 +8,13 @@
 use super::CompilationError;
 use super::Context;
 use super::TxTmplIt;
+use crate::contract::actions::ConditionalCompileType;
+use crate::contract::actions::ConditionallyCompileIfArgsList;
 use crate::contract::actions::ConditionallyCompileIfList;
+use crate::contract::actions::FeePolicy;
+use crate::contract::actions::GuardCombinator;
 use crate::contract::actions::GuardList;
+use crate::contract::empty;
 use crate::template::Template;
 use sapio_base::effects::EffectDBError;
 use sapio_base::simp::ContinuationPointLT;
@@ -19,6 +24,7 @@ use serde_json::Value;
 
 use core::marker::PhantomData;
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use std::sync::Arc;
 
@@ -40,9 +46,15 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
     pub coerce_args: fn(StatefulArguments) -> Result<SpecificArgs, CompilationError>,
     /// Guards returns Clauses -- if any -- before the coins should be unlocked
     pub guard: GuardList<'a, ContractSelf>,
+    /// how the Clauses returned by `guard` are combined
+    pub guard_combinator: GuardCombinator,
     /// conditional_compile_if returns ConditionallyCompileType to determine if a function
     /// should be included.
     pub conditional_compile_if: ConditionallyCompileIfList<'a, ContractSelf>,
+    /// conditional_compile_if_args is evaluated on the arguments after `coerce_args`, and merged
+    /// with the result of `conditional_compile_if`. If the merged result is `Skippable` or
+    /// `Never`, or these arguments are `Nullable`, `func` is not invoked for those arguments.
+    pub conditional_compile_if_args: ConditionallyCompileIfArgsList<'a, ContractSelf, SpecificArgs>,
     /// func returns an iterator of possible transactions
     /// Implementors should aim to return as few `TxTmpl`s as possible for enhanced
     /// semantics, preferring to split across multiple `FinishOrFunc`'s.
@@ -52,6 +64,10 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
     /// because negative trait bounds do not exists, that is up to the
     /// implementation to decide if the trait exists.
     pub schema: Option<Arc<Value>>,
+    /// an optional schema describing the templates `func` may return (e.g.,
+    /// number of outputs, if zero templates may be returned, typical amounts),
+    /// so that a caller may preview the consequences of a continuation.
+    pub returned_template_schema: Option<Arc<Value>>,
     /// name derived from Function Name.
     /// N.B. must be renamable by changing this field!
     pub name: Arc<String>,
@@ -63,6 +79,18 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
     /// extract a clause from the txtmpl
     pub extract_clause_from_txtmpl:
         fn(&Template, &Context) -> Result<Option<Clause>, CompilationError>,
+    /// how much should be reserved from the funds for fees
+    pub fee_policy: FeePolicy,
+    /// how likely this branch is to be used relative to the others, which
+    /// shapes the taproot tree so that likelier branches are cheaper to spend.
+    /// `None` is the same as `Some(1.0)`.
+    pub weight: Option<f64>,
+    /// where a UI should place this continuation relative to others, lowest first
+    pub display_order: Option<i64>,
+    /// if a UI should hide this continuation by default (e.g., advanced use)
+    pub hidden: bool,
+    /// a human readable description of this continuation for a UI
+    pub description: Option<String>,
 }
 
 /// This trait hides the generic parameter `SpecificArgs` in FinishOrFunc
@@ -72,7 +100,15 @@ pub struct FinishOrFunc<'a, ContractSelf, StatefulArguments, SpecificArgs, WebAP
 /// presently done through `std::convert::TryInto::try_into`.
 pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
     /// Calls the internal function, should convert `StatefulArguments` to `SpecificArgs`.
-    fn call(&self, cself: &ContractSelf, ctx: Context, o: StatefulArguments) -> TxTmplIt;
+    /// `cc` is the result of evaluating `conditional_compile_if`, to be merged with any
+    /// `conditional_compile_if_args`.
+    fn call(
+        &self,
+        cself: &ContractSelf,
+        ctx: Context,
+        o: StatefulArguments,
+        cc: ConditionalCompileType,
+    ) -> TxTmplIt;
 
     /// generate any SIMPs to attach here
     fn gen_simps(
@@ -81,7 +117,13 @@ pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
         ctx: Context,
     ) -> Result<Vec<Box<dyn SIMPAttachableAt<ContinuationPointLT>>>, CompilationError>;
     /// Calls the internal function, should convert `StatefulArguments` to `SpecificArgs`.
-    fn call_json(&self, _cself: &ContractSelf, _ctx: Context, _o: serde_json::Value) -> TxTmplIt {
+    fn call_json(
+        &self,
+        _cself: &ContractSelf,
+        _ctx: Context,
+        _o: serde_json::Value,
+        _cc: ConditionalCompileType,
+    ) -> TxTmplIt {
         Err(CompilationError::WebAPIDisabled)
     }
     /// to be set to true if call_json may return a non-error type.
@@ -92,10 +134,14 @@ pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
     fn get_conditional_compile_if(&self) -> ConditionallyCompileIfList<'_, ContractSelf>;
     /// Getter Method for internal field
     fn get_guard(&self) -> GuardList<'_, ContractSelf>;
+    /// Getter Method for internal field
+    fn get_guard_combinator(&self) -> GuardCombinator;
     /// Get the name for this function
     fn get_name(&self) -> &Arc<String>;
     /// Get the RootSchema for calling this with an update
     fn get_schema(&self) -> &Option<Arc<Value>>;
+    /// Get the RootSchema describing the templates returned by calling this
+    fn get_returned_template_schema(&self) -> &Option<Arc<Value>>;
     /// get if txtmpls returned by the func should modify guards.
     fn get_returned_txtmpls_modify_guards(&self) -> bool;
     /// extract a clause from the txtmpl
@@ -104,6 +150,49 @@ pub trait CallableAsFoF<ContractSelf, StatefulArguments> {
     ) -> fn(&Template, &Context) -> Result<Option<Clause>, CompilationError>;
     /// rename this object
     fn rename(&mut self, a: Arc<String>);
+    /// Getter Method for internal field
+    fn get_fee_policy(&self) -> FeePolicy;
+    /// Getter Method for internal field
+    fn get_weight(&self) -> Option<f64>;
+    /// Getter Method for internal field
+    fn get_display_order(&self) -> Option<i64>;
+    /// Getter Method for internal field
+    fn get_hidden(&self) -> bool;
+    /// Getter Method for internal field
+    fn get_description(&self) -> &Option<String>;
+}
+
+impl<ContractSelf, StatefulArguments, SpecificArgs, WebAPIStatus>
+    FinishOrFunc<'_, ContractSelf, StatefulArguments, SpecificArgs, WebAPIStatus>
+{
+    /// Invoke `func` unless `conditional_compile_if_args` (merged with `cc`) prunes it.
+    fn call_if_compiled(
+        &self,
+        cself: &ContractSelf,
+        ctx: Context,
+        args: SpecificArgs,
+        cc: ConditionalCompileType,
+    ) -> TxTmplIt {
+        let args_cc = self
+            .conditional_compile_if_args
+            .iter()
+            .fold(ConditionalCompileType::NoConstraint, |acc, f| {
+                acc.merge(f(cself, &ctx, &args))
+            });
+        let args_nullable = args_cc == ConditionalCompileType::Nullable;
+        match cc.merge(args_cc).for_branch(&self.name) {
+            ConditionalCompileType::Fail(errors) => {
+                Err(CompilationError::ConditionalCompilationFailed(errors))
+            }
+            ConditionalCompileType::Skippable | ConditionalCompileType::Never => empty(),
+            // the arguments indicated there is nothing to be done
+            ConditionalCompileType::Nullable if args_nullable => empty(),
+            ConditionalCompileType::Required
+            | ConditionalCompileType::Nullable
+            | ConditionalCompileType::NoConstraint
+            | ConditionalCompileType::Warn(_) => (self.func)(cself, ctx, args),
+        }
+    }
 }
 
 /// Type Tag for FinishOrFunc Variant
@@ -114,9 +203,15 @@ pub struct WebAPIDisabled;
 impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf, StatefulArguments>
     for FinishOrFunc<'_, ContractSelf, StatefulArguments, SpecificArgs, WebAPIDisabled>
 {
-    fn call(&self, cself: &ContractSelf, ctx: Context, o: StatefulArguments) -> TxTmplIt {
+    fn call(
+        &self,
+        cself: &ContractSelf,
+        ctx: Context,
+        o: StatefulArguments,
+        cc: ConditionalCompileType,
+    ) -> TxTmplIt {
         let args = (self.coerce_args)(o)?;
-        (self.func)(cself, ctx, args)
+        self.call_if_compiled(cself, ctx, args, cc)
     }
     fn get_conditional_compile_if(&self) -> ConditionallyCompileIfList<'_, ContractSelf> {
         self.conditional_compile_if
@@ -124,12 +219,18 @@ impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf,
     fn get_guard(&self) -> GuardList<'_, ContractSelf> {
         self.guard
     }
+    fn get_guard_combinator(&self) -> GuardCombinator {
+        self.guard_combinator
+    }
     fn get_name(&self) -> &Arc<String> {
         &self.name
     }
     fn get_schema(&self) -> &Option<Arc<Value>> {
         &self.schema
     }
+    fn get_returned_template_schema(&self) -> &Option<Arc<Value>> {
+        &self.returned_template_schema
+    }
     fn get_returned_txtmpls_modify_guards(&self) -> bool {
         self.returned_txtmpls_modify_guards
     }
@@ -143,6 +244,26 @@ impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf,
         self.name = a;
     }
 
+    fn get_fee_policy(&self) -> FeePolicy {
+        self.fee_policy
+    }
+
+    fn get_weight(&self) -> Option<f64> {
+        self.weight
+    }
+
+    fn get_display_order(&self) -> Option<i64> {
+        self.display_order
+    }
+
+    fn get_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    fn get_description(&self) -> &Option<String> {
+        &self.description
+    }
+
     fn gen_simps(
         &self,
         cself: &ContractSelf,
@@ -157,15 +278,27 @@ impl<ContractSelf, StatefulArguments, SpecificArgs> CallableAsFoF<ContractSelf,
 where
     SpecificArgs: for<'de> Deserialize<'de>,
 {
-    fn call(&self, cself: &ContractSelf, ctx: Context, o: StatefulArguments) -> TxTmplIt {
+    fn call(
+        &self,
+        cself: &ContractSelf,
+        ctx: Context,
+        o: StatefulArguments,
+        cc: ConditionalCompileType,
+    ) -> TxTmplIt {
         let args = (self.coerce_args)(o)?;
-        (self.func)(cself, ctx, args)
+        self.call_if_compiled(cself, ctx, args, cc)
     }
-    fn call_json(&self, cself: &ContractSelf, ctx: Context, o: serde_json::Value) -> TxTmplIt {
+    fn call_json(
+        &self,
+        cself: &ContractSelf,
+        ctx: Context,
+        o: serde_json::Value,
+        cc: ConditionalCompileType,
+    ) -> TxTmplIt {
         serde_json::from_value(o)
             .map_err(EffectDBError::SerializationError)
             .map_err(CompilationError::EffectDBError)
-            .and_then(|args| (self.func)(cself, ctx, args))
+            .and_then(|args| self.call_if_compiled(cself, ctx, args, cc))
     }
     fn web_api(&self) -> bool {
         true
@@ -176,12 +309,18 @@ where
     fn get_guard(&self) -> GuardList<'_, ContractSelf> {
         self.guard
     }
+    fn get_guard_combinator(&self) -> GuardCombinator {
+        self.guard_combinator
+    }
     fn get_name(&self) -> &Arc<String> {
         &self.name
     }
     fn get_schema(&self) -> &Option<Arc<Value>> {
         &self.schema
     }
+    fn get_returned_template_schema(&self) -> &Option<Arc<Value>> {
+        &self.returned_template_schema
+    }
     fn get_returned_txtmpls_modify_guards(&self) -> bool {
         self.returned_txtmpls_modify_guards
     }
@@ -196,6 +335,26 @@ where
         self.name = a;
     }
 
+    fn get_fee_policy(&self) -> FeePolicy {
+        self.fee_policy
+    }
+
+    fn get_weight(&self) -> Option<f64> {
+        self.weight
+    }
+
+    fn get_display_order(&self) -> Option<i64> {
+        self.display_order
+    }
+
+    fn get_hidden(&self) -> bool {
+        self.hidden
+    }
+
+    fn get_description(&self) -> &Option<String> {
+        &self.description
+    }
+
     fn gen_simps(
         &self,
         cself: &ContractSelf,
@@ -205,6 +364,23 @@ where
     }
 }
 
+/// A `coerce_args` for contracts with `StatefulArguments = serde_json::Value`,
+/// which deserializes the `SpecificArgs` for each `FinishOrFunc` so that no
+/// conversion from a shared type is needed.
+///
+/// `Value::Null` (the default `StatefulArguments`) coerces to the default
+/// `SpecificArgs`.
+pub fn coerce_json<SpecificArgs>(v: Value) -> Result<SpecificArgs, CompilationError>
+where
+    SpecificArgs: DeserializeOwned + Default,
+{
+    if v.is_null() {
+        Ok(SpecificArgs::default())
+    } else {
+        serde_json::from_value(v).map_err(CompilationError::DeserializationError)
+    }
+}
+
 /// default clause extractor should not attempt to do anything, but should fail if the txtmpl has attached guards
 pub fn default_extract_clause_from_txtmpl(
     t: &Template,
@@ -225,3 +401,101 @@ pub fn default_extract_clause_from_txtmpl(
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    struct Nft;
+    enum Sell {
+        Hold,
+        MakeSale,
+        Burn,
+    }
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("nft").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    fn sell<'a>() -> FinishOrFunc<'a, Nft, Sell, Sell, WebAPIDisabled> {
+        FinishOrFunc {
+            simp_gen: None,
+            coerce_args: Ok,
+            guard: &[],
+            guard_combinator: Default::default(),
+            conditional_compile_if: &[],
+            conditional_compile_if_args: &[|_, _, s| match s {
+                Sell::Hold => ConditionalCompileType::Nullable,
+                Sell::MakeSale => ConditionalCompileType::NoConstraint,
+                Sell::Burn => ConditionalCompileType::Never,
+            }],
+            func: |_, ctx, s| match s {
+                Sell::Hold => panic!("Hold should have been pruned"),
+                _ => ctx.template().into(),
+            },
+            schema: None,
+            returned_template_schema: None,
+            name: Arc::new("sell".into()),
+            f: Default::default(),
+            returned_txtmpls_modify_guards: false,
+            extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+            fee_policy: FeePolicy::None,
+            weight: None,
+            display_order: None,
+            hidden: false,
+            description: None,
+        }
+    }
+    #[test]
+    fn compile_if_args_prunes() {
+        let f = sell();
+        let held = f.call(
+            &Nft,
+            ctx(),
+            Sell::Hold,
+            ConditionalCompileType::NoConstraint,
+        );
+        assert_eq!(held.unwrap().count(), 0);
+        let sold = f.call(
+            &Nft,
+            ctx(),
+            Sell::MakeSale,
+            ConditionalCompileType::NoConstraint,
+        );
+        assert_eq!(sold.unwrap().count(), 1);
+        let burned = f.call(&Nft, ctx(), Sell::Burn, ConditionalCompileType::Required);
+        match burned {
+            Err(CompilationError::ConditionalCompilationFailed(reasons)) => assert_eq!(
+                reasons.front().map(String::as_str),
+                Some("branch `sell`: Never and Required incompatible")
+            ),
+            _ => panic!("Never should conflict with Required"),
+        }
+    }
+    #[derive(serde::Deserialize, Default, Debug, PartialEq)]
+    struct Price {
+        price: u64,
+    }
+    #[test]
+    fn coerce_json_reports_fields() {
+        assert_eq!(coerce_json::<Price>(Value::Null).unwrap(), Price::default());
+        assert_eq!(
+            coerce_json::<Price>(serde_json::json!({"price": 7})).unwrap(),
+            Price { price: 7 }
+        );
+        match coerce_json::<Price>(serde_json::json!({})) {
+            Err(e @ CompilationError::DeserializationError(_)) => {
+                assert!(e.to_string().contains("price"))
+            }
+            _ => panic!("missing field should fail to deserialize"),
+        }
+    }
+}
@@ -5,8 +5,11 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! a decorator type which is used to generate a spending condition
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 
+use crate::contract::object::SpanKind;
 use crate::contract::CompilationError;
 
 use super::Context;
@@ -16,20 +19,70 @@ use sapio_base::{
     Clause,
 };
 /// A Guard is a function which generates some condition that must be met to unlock a script.
-/// If bool = true, the computation of the guard is cached, which is useful if e.g. Guard
-/// must contact a remote server or it should be the same across calls *for a given contract
+/// If the Cache variant is used, the computation of the guard is cached, which is useful if e.g.
+/// Guard must contact a remote server or it should be the same across calls *for a given contract
 /// instance*.
 pub enum Guard<ContractSelf> {
-    /// Cache Variant should only be called one time per contract and the result saved
-    Cache(
-        fn(&ContractSelf, Context) -> Clause,
-        Option<SimpGen<ContractSelf>>,
-    ),
+    /// Cache Variant is only called one time per contract compilation and the result saved, no
+    /// matter how many branches it guards.
+    Cache(GuardFn<ContractSelf>, Option<SimpGen<ContractSelf>>),
     /// Fresh Variant may be called repeatedly
-    Fresh(
-        fn(&ContractSelf, Context) -> Clause,
-        Option<SimpGen<ContractSelf>>,
-    ),
+    Fresh(GuardFn<ContractSelf>, Option<SimpGen<ContractSelf>>),
+    /// Async Variant may be called repeatedly, and returns a future which is
+    /// driven by the `GuardExecutor` set on the `Context`. All Async guards
+    /// in a `GuardList` are resolved concurrently.
+    Async(AsyncGuardFn<ContractSelf>, Option<SimpGen<ContractSelf>>),
+}
+
+/// A future which resolves to a `Clause`, e.g. after fetching a key from an oracle.
+pub type AsyncClause = Pin<Box<dyn Future<Output = Clause> + Send>>;
+
+/// The function backing a `Guard::Async`. The returned future must not
+/// borrow from the contract, so clone anything needed into it.
+pub type AsyncGuardFn<ContractSelf> = fn(&ContractSelf, Context) -> AsyncClause;
+
+/// A `GuardExecutor` drives `Guard::Async` futures to completion during
+/// compilation. Compilation is synchronous, so implementations should block
+/// until every future has resolved.
+pub trait GuardExecutor: Send + Sync {
+    /// Resolve all of `futures` (concurrently, if possible), returning the
+    /// Clauses in the same order.
+    fn block_on_all(&self, futures: Vec<AsyncClause>) -> Vec<Clause>;
+}
+
+/// The function backing a `Guard`. `GuardFn::Fn` is a plain function pointer
+/// (what the `guard` macro generates), `GuardFn::Fallible` one which may fail
+/// compilation, and `GuardFn::Boxed` may be a closure capturing data that was
+/// only available at runtime.
+pub enum GuardFn<ContractSelf> {
+    /// A plain function pointer
+    Fn(fn(&ContractSelf, Context) -> Clause),
+    /// A plain function pointer which may fail, e.g. from
+    /// `#[guard(weighted_threshold = k)]`
+    Fallible(fn(&ContractSelf, Context) -> Result<Clause, CompilationError>),
+    /// A closure which may capture its environment
+    Boxed(BoxedGuardFn<ContractSelf>),
+}
+
+/// A boxed closure which generates a guard's Clause
+pub type BoxedGuardFn<ContractSelf> = Box<dyn Fn(&ContractSelf, Context) -> Clause + Send + Sync>;
+
+impl<ContractSelf> GuardFn<ContractSelf> {
+    /// Evaluate the guard function
+    pub fn call(&self, cself: &ContractSelf, ctx: Context) -> Result<Clause, CompilationError> {
+        let _span = ctx.span(SpanKind::Guards);
+        match self {
+            GuardFn::Fn(f) => Ok(f(cself, ctx)),
+            GuardFn::Fallible(f) => f(cself, ctx),
+            GuardFn::Boxed(f) => Ok(f(cself, ctx)),
+        }
+    }
+}
+
+impl<ContractSelf> From<fn(&ContractSelf, Context) -> Clause> for GuardFn<ContractSelf> {
+    fn from(f: fn(&ContractSelf, Context) -> Clause) -> Self {
+        GuardFn::Fn(f)
+    }
 }
 
 /// A Function that can be used to generate metadata for a Guard
@@ -39,5 +92,53 @@ pub type SimpGen<ContractSelf> =
         ctx: Context,
     ) -> Result<Vec<Arc<dyn SIMPAttachableAt<GuardLT>>>, CompilationError>;
 
+/// Generates a `Guard` on demand. `GuardGen::Fn` is used for statically declared guards
+/// (e.g., from `guarded_by` or `declare!{finish, ...}`) and `GuardGen::Boxed` for guards which
+/// are built at runtime, e.g. for a `DynamicContract` from a key set loaded from a config.
+pub enum GuardGen<ContractSelf> {
+    /// A plain function pointer
+    Fn(fn() -> Option<Guard<ContractSelf>>),
+    /// A closure which may capture its environment
+    Boxed(Box<dyn Fn() -> Option<Guard<ContractSelf>> + Send + Sync>),
+}
+
+impl<ContractSelf> GuardGen<ContractSelf> {
+    /// Generate the Guard, if any
+    pub fn generate(&self) -> Option<Guard<ContractSelf>> {
+        match self {
+            GuardGen::Fn(f) => f(),
+            GuardGen::Boxed(f) => f(),
+        }
+    }
+    /// Identity of this generator, used for caching guard computations.
+    /// Boxed closures may be zero sized, so they are identified by where the
+    /// `GuardGen` itself lives rather than by the (possibly dangling) box.
+    pub(crate) fn cache_key(&self) -> usize {
+        match self {
+            GuardGen::Fn(f) => *f as usize,
+            GuardGen::Boxed(_) => self as *const Self as usize,
+        }
+    }
+}
+
+impl<ContractSelf> From<fn() -> Option<Guard<ContractSelf>>> for GuardGen<ContractSelf> {
+    fn from(f: fn() -> Option<Guard<ContractSelf>>) -> Self {
+        GuardGen::Fn(f)
+    }
+}
+
 /// A List of Guards, for convenience
-pub type GuardList<'a, T> = &'a [fn() -> Option<Guard<T>>];
+pub type GuardList<'a, T> = &'a [GuardGen<T>];
+
+/// How the Clauses generated by a `GuardList` get combined into a single
+/// spending condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum GuardCombinator {
+    /// All of the guards must be satisfied (the default)
+    #[default]
+    All,
+    /// At least one of the guards must be satisfied
+    Any,
+    /// At least `k` of the guards must be satisfied
+    Threshold(usize),
+}
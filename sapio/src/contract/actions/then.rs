@@ -9,9 +9,11 @@ use super::CompilationError;
 use super::Context;
 use super::TxTmplIt;
 use crate::contract::actions::ConditionallyCompileIfList;
+use crate::contract::actions::GuardCombinator;
 use crate::contract::actions::GuardList;
 use crate::contract::actions::{FinishOrFunc, WebAPIDisabled};
 use crate::template::Template;
+use bitcoin::util::amount::Amount;
 use sapio_base::Clause;
 use std::marker::PhantomData;
 use std::sync::Arc;
@@ -30,12 +32,31 @@ impl ThenFuncTypeTag {
 pub type ThenFuncAsFinishOrFunc<'a, ContractSelf, StatefulArguments> =
     FinishOrFunc<'a, ContractSelf, StatefulArguments, ThenFuncTypeTag, WebAPIDisabled>;
 
+/// How much of the funds available to a `ThenFunc` should be reserved for fees
+/// rather than handed to its `func`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FeePolicy {
+    /// No reservation, `func` is responsible for leaving fees
+    #[default]
+    None,
+    /// Deduct a fixed amount from the `Context` passed to `func`, and count it
+    /// towards the fee of every template returned.
+    Reserve(Amount),
+    /// Reserve a fee rate, in sats per vbyte, for every template returned.
+    /// As the size of a template is not known until `func` returns, this is
+    /// checked against each template rather than deducted up front.
+    ReserveRate(Amount),
+}
+
 /// A ThenFunc takes a list of Guards and a TxTmplIt generator.  Each TxTmpl returned from the
-/// ThenFunc is Covenant Permitted only if the AND of all guards is satisfied.
+/// ThenFunc is Covenant Permitted only if the guards are satisfied, as combined by
+/// `guard_combinator` (by default, the AND of all guards).
 pub struct ThenFunc<'a, ContractSelf> {
     /// Guards returns Clauses -- if any -- before the internal func's returned
     /// TxTmpls should execute on-chain
     pub guard: GuardList<'a, ContractSelf>,
+    /// how the Clauses returned by `guard` are combined
+    pub guard_combinator: GuardCombinator,
     /// conditional_compile_if returns ConditionallyCompileType to determine if a function
     /// should be included.
     pub conditional_compile_if: ConditionallyCompileIfList<'a, ContractSelf>,
@@ -45,6 +66,11 @@ pub struct ThenFunc<'a, ContractSelf> {
     pub func: fn(&ContractSelf, Context, ThenFuncTypeTag) -> TxTmplIt,
     /// name derived from Function Name.
     pub name: Arc<String>,
+    /// how much should be reserved from the funds for fees
+    pub fee_policy: FeePolicy,
+    /// how likely this branch is to be used relative to the others, see
+    /// `FinishOrFunc::weight`
+    pub weight: Option<f64>,
 }
 
 impl<'a, ContractSelf, StatefulArgs> From<ThenFunc<'a, ContractSelf>>
@@ -53,16 +79,24 @@ impl<'a, ContractSelf, StatefulArgs> From<ThenFunc<'a, ContractSelf>>
     fn from(f: ThenFunc<'a, ContractSelf>) -> Self {
         FinishOrFunc {
             guard: f.guard,
+            guard_combinator: f.guard_combinator,
             conditional_compile_if: f.conditional_compile_if,
+            conditional_compile_if_args: &[],
             func: f.func,
             name: f.name,
             coerce_args: ThenFuncTypeTag::coerce_args,
             schema: None,
+            returned_template_schema: None,
+            fee_policy: f.fee_policy,
+            weight: f.weight,
             f: PhantomData::default(),
             returned_txtmpls_modify_guards: true,
             extract_clause_from_txtmpl: ctv_clause_extractor,
             // TODO: Maybe Then should be able to get simps?
             simp_gen: None,
+            display_order: None,
+            hidden: false,
+            description: None,
         }
     }
 }
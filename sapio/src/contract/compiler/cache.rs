@@ -7,7 +7,12 @@
 //! Caches for guards
 use super::Context;
 use super::InternalCompilerTag;
+use crate::contract::actions::AsyncClause;
+use crate::contract::actions::AsyncGuardFn;
 use crate::contract::actions::Guard;
+use crate::contract::actions::GuardCombinator;
+use crate::contract::actions::GuardFn;
+use crate::contract::actions::GuardGen;
 use crate::contract::actions::SimpGen;
 use crate::contract::CompilationError;
 use sapio_base::effects::PathFragment;
@@ -20,11 +25,21 @@ use std::sync::Arc;
 pub type GuardSimps = Vec<Arc<dyn SIMPAttachableAt<GuardLT>>>;
 pub(crate) enum CacheEntry<T> {
     Cached(Clause, GuardSimps),
-    Fresh(fn(&T, Context) -> Clause, Option<SimpGen<T>>),
+    Fresh(GuardFn<T>, Option<SimpGen<T>>),
+    Async(AsyncGuardFn<T>, Option<SimpGen<T>>),
+}
+
+/// The result of looking up a guard, which may still need to be driven by the
+/// `Context`'s `GuardExecutor`.
+pub(crate) enum GuardOutput {
+    Ready(Clause),
+    Pending(AsyncClause),
 }
 
 /// GuardCache assists with caching the computation of guard functions
-/// during compilation.
+/// during compilation. One GuardCache is used per contract compilation, so a
+/// `Guard::Cache` is evaluated at most once per contract instance, keyed by the
+/// identity of its `GuardGen`.
 pub(crate) struct GuardCache<T> {
     cache: BTreeMap<usize, Option<CacheEntry<T>>>,
 }
@@ -41,26 +56,34 @@ impl<T> GuardCache<T> {
         simp_ctx: Context,
     ) -> Result<Option<CacheEntry<T>>, CompilationError> {
         match g {
-            Some(Guard::Cache(f, Some(simp_gen))) => {
-                Ok(Some(CacheEntry::Cached(f(t, ctx), simp_gen(t, simp_ctx)?)))
-            }
-            Some(Guard::Cache(f, None)) => Ok(Some(CacheEntry::Cached(f(t, ctx), vec![]))),
+            Some(Guard::Cache(f, Some(simp_gen))) => Ok(Some(CacheEntry::Cached(
+                f.call(t, ctx)?,
+                simp_gen(t, simp_ctx)?,
+            ))),
+            Some(Guard::Cache(f, None)) => Ok(Some(CacheEntry::Cached(f.call(t, ctx)?, vec![]))),
             Some(Guard::Fresh(f, simp_gen)) => Ok(Some(CacheEntry::Fresh(f, simp_gen))),
+            Some(Guard::Async(f, simp_gen)) => Ok(Some(CacheEntry::Async(f, simp_gen))),
             None => Ok(None),
         }
     }
     pub(crate) fn get(
         &mut self,
         t: &T,
-        f: fn() -> Option<Guard<T>>,
+        f: &GuardGen<T>,
         ctx: Context,
         simp_ctx: Context,
-    ) -> Result<Option<(Clause, GuardSimps)>, CompilationError> {
-        let mut entry = self.cache.entry(f as usize);
+    ) -> Result<Option<(GuardOutput, GuardSimps)>, CompilationError> {
+        // a dry run keeps only whether there is a guard, see `Context::dry_run`
+        if ctx.is_dry_run() {
+            return Ok(f
+                .generate()
+                .map(|_| (GuardOutput::Ready(Clause::Unsatisfiable), vec![])));
+        }
+        let mut entry = self.cache.entry(f.cache_key());
         let r = match entry {
             std::collections::btree_map::Entry::Vacant(v) => {
                 let ent = Self::create_entry(
-                    f(),
+                    f.generate(),
                     t,
                     ctx.internal_clone(InternalCompilerTag { _secret: () }),
                     simp_ctx.internal_clone(InternalCompilerTag { _secret: () }),
@@ -71,9 +94,19 @@ impl<T> GuardCache<T> {
             std::collections::btree_map::Entry::Occupied(ref mut o) => o.get_mut(),
         };
         match r {
-            Some(CacheEntry::Cached(s, v)) => Ok(Some((s.clone(), v.to_vec()))),
+            Some(CacheEntry::Cached(s, v)) => Ok(Some((GuardOutput::Ready(s.clone()), v.to_vec()))),
             Some(CacheEntry::Fresh(f, s)) => Ok(Some((
-                f(t, ctx),
+                GuardOutput::Ready({
+                    ctx.not_memoizable();
+                    f.call(t, ctx)?
+                }),
+                match s {
+                    Some(f2) => f2(t, simp_ctx)?,
+                    None => vec![],
+                },
+            ))),
+            Some(CacheEntry::Async(f, s)) => Ok(Some((
+                GuardOutput::Pending(f(t, ctx)),
                 match s {
                     Some(f2) => f2(t, simp_ctx)?,
                     None => vec![],
@@ -84,32 +117,260 @@ impl<T> GuardCache<T> {
     }
 }
 
+/// Drive any pending guards to completion with the `Context`'s executor, all at once.
+pub(crate) fn resolve_guards(
+    ctx: &Context,
+    v: Vec<(GuardOutput, GuardSimps)>,
+) -> Result<Vec<(Clause, GuardSimps)>, CompilationError> {
+    let mut pending = vec![];
+    let v: Vec<_> = v
+        .into_iter()
+        .map(|(g, simps)| match g {
+            GuardOutput::Ready(c) => (Some(c), simps),
+            GuardOutput::Pending(fut) => {
+                pending.push(fut);
+                (None, simps)
+            }
+        })
+        .collect();
+    let mut resolved = if pending.is_empty() {
+        vec![]
+    } else {
+        let n = pending.len();
+        let resolved = ctx
+            .executor()
+            .ok_or(CompilationError::NoGuardExecutor)?
+            .block_on_all(pending);
+        if resolved.len() != n {
+            return Err(CompilationError::TerminateWith(
+                "GuardExecutor did not resolve every guard".into(),
+            ));
+        }
+        resolved
+    }
+    .into_iter();
+    Ok(v.into_iter()
+        .map(|(c, simps)| match c {
+            Some(c) => (c, simps),
+            None => (resolved.next().expect("length checked"), simps),
+        })
+        .collect())
+}
+
 pub(crate) fn create_guards<T>(
     self_ref: &T,
     mut ctx: Context,
-    guards: &[fn() -> Option<Guard<T>>],
+    guards: &[GuardGen<T>],
+    combinator: GuardCombinator,
     gc: &mut GuardCache<T>,
 ) -> Result<(Clause, Vec<(Clause, GuardSimps)>), CompilationError> {
     let v = guards
         .iter()
         .zip((0..).flat_map(|i| {
-            let new = ctx.derive(PathFragment::Branch(i)).ok()?;
-            let simp = ctx.derive(PathFragment::Metadata).ok()?;
+            let mut new = ctx.derive(PathFragment::Branch(i)).ok()?;
+            let simp = new.derive(PathFragment::Metadata).ok()?;
             Some((new, simp))
         }))
-        .filter_map(|(x, (c, simp_c))| gc.get(self_ref, *x, c, simp_c).transpose())
+        .filter_map(|(x, (c, simp_c))| gc.get(self_ref, x, c, simp_c).transpose())
         .collect::<Result<Vec<_>, _>>()?;
+    let v = resolve_guards(&ctx, v)?;
+    let n_guards = v.len();
+    let n_trivial = v.iter().filter(|x| x.0 == Clause::Trivial).count();
     let mut clauses: Vec<_> = v
         .iter()
         .map(|x| &x.0)
         .filter(|x| **x != Clause::Trivial)
         .cloned()
         .collect(); // no point in using any Trivials
-    if clauses.is_empty() {
-        Ok((Clause::Trivial, v))
-    } else if clauses.len() == 1 {
-        Ok((clauses.pop().unwrap(), v))
-    } else {
-        Ok((Clause::And(clauses), v))
+    let clause = match combinator {
+        GuardCombinator::All => match clauses.len() {
+            0 => Clause::Trivial,
+            1 => clauses.pop().unwrap(),
+            _ => Clause::And(clauses),
+        },
+        GuardCombinator::Any if n_guards == 0 => {
+            return Err(CompilationError::InvalidGuardCombinator(
+                combinator, n_guards,
+            ))
+        }
+        // any Trivial satisfies the whole thing
+        GuardCombinator::Any if n_trivial > 0 => Clause::Trivial,
+        GuardCombinator::Any => match clauses.len() {
+            1 => clauses.pop().unwrap(),
+            _ => Clause::Or(clauses.into_iter().map(|c| (1, c)).collect()),
+        },
+        GuardCombinator::Threshold(k) if k == 0 || k > n_guards => {
+            return Err(CompilationError::InvalidGuardCombinator(
+                combinator, n_guards,
+            ))
+        }
+        // each Trivial counts towards the threshold for free
+        GuardCombinator::Threshold(k) => match k.saturating_sub(n_trivial) {
+            0 => Clause::Trivial,
+            1 if clauses.len() == 1 => clauses.pop().unwrap(),
+            k if k == clauses.len() => Clause::And(clauses),
+            k => Clause::Threshold(k, clauses),
+        },
+    };
+    Ok((clause, v))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::sha256;
+    use bitcoin::hashes::Hash;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    struct Guarded;
+    fn preimage(i: u8) -> Clause {
+        Clause::Sha256(sha256::Hash::hash(&[i]))
+    }
+    fn a() -> Option<Guard<Guarded>> {
+        Some(Guard::Fresh(GuardFn::Fn(|_, _| preimage(0)), None))
+    }
+    fn b() -> Option<Guard<Guarded>> {
+        Some(Guard::Fresh(GuardFn::Fn(|_, _| preimage(1)), None))
+    }
+    fn c() -> Option<Guard<Guarded>> {
+        Some(Guard::Fresh(GuardFn::Fn(|_, _| preimage(2)), None))
+    }
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("guards").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    fn combine(
+        guards: &[fn() -> Option<Guard<Guarded>>],
+        combinator: GuardCombinator,
+    ) -> Result<Clause, CompilationError> {
+        let guards: Vec<GuardGen<Guarded>> = guards.iter().cloned().map(GuardGen::Fn).collect();
+        create_guards(&Guarded, ctx(), &guards, combinator, &mut GuardCache::new()).map(|r| r.0)
+    }
+    #[test]
+    fn threshold_of_guards() {
+        assert_eq!(
+            combine(&[a, b, c], GuardCombinator::Threshold(2)).unwrap(),
+            Clause::Threshold(2, vec![preimage(0), preimage(1), preimage(2)])
+        );
+        assert_eq!(
+            combine(&[a, b, c], GuardCombinator::Any).unwrap(),
+            Clause::Or(vec![(1, preimage(0)), (1, preimage(1)), (1, preimage(2))])
+        );
+        assert_eq!(
+            combine(&[a, b, c], GuardCombinator::All).unwrap(),
+            Clause::And(vec![preimage(0), preimage(1), preimage(2)])
+        );
+        assert!(matches!(
+            combine(&[a, b, c], GuardCombinator::Threshold(4)),
+            Err(CompilationError::InvalidGuardCombinator(_, 3))
+        ));
+    }
+    static CACHED_CALLS: AtomicUsize = AtomicUsize::new(0);
+    static FRESH_CALLS: AtomicUsize = AtomicUsize::new(0);
+    fn cached() -> Option<Guard<Guarded>> {
+        Some(Guard::Cache(
+            GuardFn::Fn(|_, _| {
+                CACHED_CALLS.fetch_add(1, Ordering::SeqCst);
+                preimage(3)
+            }),
+            None,
+        ))
+    }
+    fn fresh() -> Option<Guard<Guarded>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| {
+                FRESH_CALLS.fetch_add(1, Ordering::SeqCst);
+                preimage(4)
+            }),
+            None,
+        ))
+    }
+    #[test]
+    fn cached_guard_evaluated_once() {
+        let mut gc = GuardCache::new();
+        let guards = [GuardGen::Fn(cached), GuardGen::Fn(fresh)];
+        // the same guards attached to three branches
+        for _ in 0..3 {
+            let (clause, _) =
+                create_guards(&Guarded, ctx(), &guards, GuardCombinator::All, &mut gc).unwrap();
+            assert_eq!(clause, Clause::And(vec![preimage(3), preimage(4)]));
+        }
+        assert_eq!(CACHED_CALLS.load(Ordering::SeqCst), 1);
+        assert_eq!(FRESH_CALLS.load(Ordering::SeqCst), 3);
+    }
+    struct TokioExecutor(tokio::runtime::Runtime);
+    impl crate::contract::actions::GuardExecutor for TokioExecutor {
+        fn block_on_all(&self, futures: Vec<AsyncClause>) -> Vec<Clause> {
+            self.0.block_on(async {
+                let handles: Vec<_> = futures.into_iter().map(tokio::spawn).collect();
+                let mut clauses = vec![];
+                for h in handles {
+                    clauses.push(h.await.expect("Guard should not panic"));
+                }
+                clauses
+            })
+        }
+    }
+    static BARRIER: OnceLock<tokio::sync::Barrier> = OnceLock::new();
+    /// Neither guard can resolve until both are being polled at once
+    async fn both_polled(i: u8) -> Clause {
+        let barrier = BARRIER.get_or_init(|| tokio::sync::Barrier::new(2));
+        match tokio::time::timeout(Duration::from_secs(10), barrier.wait()).await {
+            Ok(_) => preimage(i),
+            Err(_) => Clause::Unsatisfiable,
+        }
+    }
+    fn oracle_a() -> Option<Guard<Guarded>> {
+        Some(Guard::Async(|_, _| Box::pin(both_polled(5)), None))
+    }
+    fn oracle_b() -> Option<Guard<Guarded>> {
+        Some(Guard::Async(|_, _| Box::pin(both_polled(6)), None))
+    }
+    #[test]
+    fn async_guards_resolve_concurrently() {
+        let guards = [
+            GuardGen::Fn(oracle_a),
+            GuardGen::Fn(a),
+            GuardGen::Fn(oracle_b),
+        ];
+        let executor = TokioExecutor(tokio::runtime::Runtime::new().unwrap());
+        let (clause, _) = create_guards(
+            &Guarded,
+            ctx().with_executor(Arc::new(executor)),
+            &guards,
+            GuardCombinator::All,
+            &mut GuardCache::new(),
+        )
+        .unwrap();
+        assert_eq!(
+            clause,
+            Clause::And(vec![preimage(5), preimage(0), preimage(6)])
+        );
+        assert!(matches!(
+            combine(&[oracle_a], GuardCombinator::All),
+            Err(CompilationError::NoGuardExecutor)
+        ));
+    }
+    #[test]
+    fn empty_any_is_an_error() {
+        assert!(matches!(
+            combine(&[], GuardCombinator::Any),
+            Err(CompilationError::InvalidGuardCombinator(
+                GuardCombinator::Any,
+                0
+            ))
+        ));
+        assert_eq!(combine(&[], GuardCombinator::All).unwrap(), Clause::Trivial);
     }
 }
@@ -0,0 +1,243 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Memoizing the compilation of nested contracts
+use super::Compilable;
+use crate::contract::{CompilationError, Compiled, Context};
+use serde::Serialize;
+
+/// A contract which is compiled at most once for the same arguments by the
+/// Contexts derived from one `Context::new` with the same settings, e.g. for
+/// the many identical members of a payment pool.
+///
+/// The contract is identified by its type and serialized arguments, and is
+/// compiled again only if the amount, network or effects under its path
+/// differ. Any other compilation of it is the first one, re-pathed.
+///
+/// Some contracts depend on more than that, e.g. on their path. Those using
+/// a `Guard::Fresh` or `Context::derive_entropy` are never memoized, and
+/// others may opt out with `Context::not_memoizable`.
+pub struct Memoized<'a, T>(&'a T);
+
+impl<'a, T> Memoized<'a, T>
+where
+    T: Compilable + Serialize,
+{
+    /// Memoize compiling `contract`
+    pub fn new(contract: &'a T) -> Self {
+        Memoized(contract)
+    }
+}
+
+impl<'a, T> Compilable for Memoized<'a, T>
+where
+    T: Compilable + Serialize,
+{
+    fn compile(&self, ctx: Context) -> Result<Compiled, CompilationError> {
+        let arguments =
+            serde_json::to_string(self.0).map_err(CompilationError::SerializationError)?;
+        ctx.memoized(&[std::any::type_name::<T>(), &arguments], |ctx| {
+            self.0.compile(ctx)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{empty, Contract, StatefulArgumentsTrait, TxTmplIt};
+    use crate::template::Template;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::{EditableMapEffectDB, EffectPath, MapEffectDB};
+    use sapio_base::serialization_helpers::SArc;
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("memo").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    fn key() -> XOnlyPublicKey {
+        "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+            .parse()
+            .unwrap()
+    }
+    fn only_template(compiled: &Compiled) -> &Template {
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        compiled.ctv_to_tx.values().next().unwrap()
+    }
+    #[derive(serde::Deserialize, Default)]
+    enum Sale {
+        #[default]
+        Hold,
+        MakeSale,
+    }
+    impl StatefulArgumentsTrait for Sale {}
+    static MEMBER_COMPILES: AtomicU32 = AtomicU32::new(0);
+    #[derive(serde::Serialize)]
+    struct Member {
+        entropy: bool,
+    }
+    fn pay_member(s: &Member, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        MEMBER_COMPILES.fetch_add(1, Ordering::Relaxed);
+        if s.entropy {
+            ctx.derive_entropy("member");
+        }
+        let amt = ctx.funds();
+        ctx.template().add_output(amt, &key(), None)?.into()
+    }
+    impl Member {
+        fn pay<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, Sale>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: pay_member,
+                    name: Arc::new("pay".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+        fn sell<'a>() -> Option<Box<dyn CallableAsFoF<Self, Sale> + 'a>> {
+            Some(Box::new(FinishOrFunc::<_, _, Sale, WebAPIEnabled> {
+                simp_gen: None,
+                coerce_args: Ok,
+                // a `Guard::Fresh` is never memoized
+                guard: &[GuardGen::Fn(|| {
+                    Some(Guard::Cache(GuardFn::Fn(|_, _| Clause::Key(key())), None))
+                })],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                conditional_compile_if_args: &[],
+                func: |_, ctx, s| match s {
+                    Sale::Hold => empty(),
+                    Sale::MakeSale => {
+                        let amt = ctx.funds();
+                        ctx.template().add_output(amt, &key(), None)?.into()
+                    }
+                },
+                schema: None,
+                returned_template_schema: None,
+                name: Arc::new("sell".into()),
+                f: Default::default(),
+                returned_txtmpls_modify_guards: false,
+                extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+                fee_policy: FeePolicy::None,
+                weight: None,
+                display_order: None,
+                hidden: false,
+                description: None,
+            }))
+        }
+    }
+    impl Contract for Member {
+        declare! {then, Self::pay}
+        declare! {updatable<Sale>, Self::sell}
+    }
+    /// four identical `Member`s, memoized if `.0`
+    struct Pool(bool, bool);
+    fn pay_pool(s: &Pool, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let amt = Amount::from_sat(ctx.funds().as_sat() / 4);
+        let member = Member { entropy: s.1 };
+        let mut builder = ctx.template();
+        for _ in 0..4 {
+            builder = if s.0 {
+                builder.add_output(amt, &Memoized::new(&member), None)?
+            } else {
+                builder.add_output(amt, &member, None)?
+            };
+        }
+        builder.into()
+    }
+    impl Pool {
+        fn pay<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: pay_pool,
+                    name: Arc::new("pay".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Pool {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+    #[test]
+    fn memoized_contracts() {
+        let compiles = |pool: Pool, ctx: Context| {
+            let before = MEMBER_COMPILES.load(Ordering::Relaxed);
+            let compiled = pool.compile(ctx).unwrap();
+            let json = serde_json::to_value(&compiled).unwrap();
+            (MEMBER_COMPILES.load(Ordering::Relaxed) - before, json)
+        };
+        let (n, plain) = compiles(Pool(false, false), ctx());
+        assert_eq!(n, 4);
+        let (n, memoized) = compiles(Pool(true, false), ctx());
+        assert_eq!(n, 1);
+        assert_eq!(memoized, plain);
+        // the entropy differs by path
+        let (n, memoized) = compiles(Pool(true, true), ctx());
+        assert_eq!(n, 4);
+        assert_eq!(memoized, compiles(Pool(false, true), ctx()).1);
+        // a sale at one member's path makes it differ from its siblings
+        let compiled = Pool(false, false).compile(ctx()).unwrap();
+        let sells: Vec<_> = compiled
+            .continuation_points()
+            .into_iter()
+            .filter(|c| c.name.ends_with("/sell"))
+            .map(|c| SArc(c.point.path))
+            .collect();
+        assert_eq!(sells.len(), 4);
+        let effect: BTreeMap<_, _> =
+            std::iter::once((SArc(Arc::new("buy".into())), serde_json::json!("MakeSale")))
+                .collect();
+        let with_sale = || {
+            let effects = EditableMapEffectDB {
+                effects: std::iter::once((sells[2].clone(), effect.clone())).collect(),
+                empty: Default::default(),
+            };
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("memo").unwrap(),
+                Arc::new(effects.into()),
+            )
+        };
+        let (n, plain) = compiles(Pool(false, false), with_sale());
+        assert_eq!(n, 4);
+        let (n, memoized) = compiles(Pool(true, false), with_sale());
+        assert_eq!(n, 2);
+        assert_eq!(memoized, plain);
+        let compiled = Pool(true, false).compile(with_sale()).unwrap();
+        let sold: Vec<_> = only_template(&compiled)
+            .outputs
+            .iter()
+            .map(|o| o.contract.suggested_txs.len())
+            .collect();
+        assert_eq!(sold, vec![0, 0, 1, 0]);
+    }
+}
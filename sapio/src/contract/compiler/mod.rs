@@ -10,15 +10,30 @@ use super::AnyContract;
 use super::CompilationError;
 use super::Compiled;
 use super::Context;
+use super::CovenantBackend;
+use super::ScriptTarget;
 use crate::contract::abi::continuation::ContinuationPoint;
+use crate::contract::abi::object::{
+    BranchOutcome, BranchTrace, CompileTrace, Diagnostic, DiagnosticLevel, ObjectMetadata,
+    SatisfactionWeights, SpanKind,
+};
 use crate::contract::actions::conditional_compile::CCILWrapper;
 use crate::contract::actions::CallableAsFoF;
+use crate::contract::actions::FeePolicy;
+use crate::contract::context::MAX_SIGNER_BATCH;
+use crate::contract::error::AtPath;
 use crate::contract::TxTmplIt;
+use crate::template::Template;
 use crate::util::amountrange::AmountRange;
+use crate::util::extended_address::ExtendedAddress;
 
 use ::miniscript::*;
+use bitcoin::hashes::sha256;
 use bitcoin::schnorr::TweakedPublicKey;
+use bitcoin::secp256k1::Parity;
+use bitcoin::util::amount::Amount;
 use bitcoin::XOnlyPublicKey;
+use sapio_base::clause::{MixedTimeLocks, Simplify, ToPolicyString};
 use sapio_base::effects::EffectDB;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
@@ -26,16 +41,25 @@ use sapio_base::effects::PathFragment;
 use sapio_base::serialization_helpers::SArc;
 use sapio_base::Clause;
 use std::collections::{BTreeMap, BTreeSet};
-
 use std::sync::Arc;
+
 mod cache;
+mod memo;
 mod util;
 use cache::*;
+pub use memo::Memoized;
 use util::*;
 /// Used to prevent unintended callers to internal_clone.
 pub struct InternalCompilerTag {
     _secret: (),
 }
+#[cfg(test)]
+impl InternalCompilerTag {
+    /// lets tests elsewhere in the crate compile twice with one Context
+    pub(crate) fn for_tests() -> Self {
+        InternalCompilerTag { _secret: () }
+    }
+}
 
 /// private::ImplSeal prevents anyone from implementing Compilable except by
 /// implementing Contract.
@@ -44,6 +68,7 @@ mod private {
     /// Allow Contract to implement Compile
     impl ImplSeal for super::Compiled {}
     impl ImplSeal for bitcoin::XOnlyPublicKey {}
+    impl<'a, T> ImplSeal for super::Memoized<'a, T> {}
     impl<'a, C> ImplSeal for C where C: super::AnyContract {}
 }
 /// Compilable is a trait for anything which can be compiled
@@ -54,7 +79,10 @@ pub trait Compilable: private::ImplSeal {
 
 /// Implements a basic identity
 impl Compilable for Compiled {
-    fn compile(&self, _ctx: Context) -> Result<Compiled, CompilationError> {
+    fn compile(&self, ctx: Context) -> Result<Compiled, CompilationError> {
+        if let ExtendedAddress::Address(address) = &self.address {
+            ctx.check_address(address)?;
+        }
         Ok(self.clone())
     }
 }
@@ -64,7 +92,7 @@ impl Compilable for bitcoin::XOnlyPublicKey {
     fn compile(&self, ctx: Context) -> Result<Compiled, CompilationError> {
         let addr = bitcoin::Address::p2tr_tweaked(
             TweakedPublicKey::dangerous_assume_tweaked(*self),
-            ctx.network,
+            ctx.network(),
         );
         let mut amt = AmountRange::new();
         amt.update_range(ctx.funds());
@@ -72,6 +100,22 @@ impl Compilable for bitcoin::XOnlyPublicKey {
     }
 }
 
+/// see `Context::stub_call`
+pub(crate) fn stub_object(ctx: &Context, what: &str) -> Compiled {
+    let addr = bitcoin::Address::p2tr_tweaked(
+        TweakedPublicKey::dangerous_assume_tweaked(nums_key()),
+        ctx.network(),
+    );
+    let mut stub = Compiled::from_address(addr, Some(AmountRange::exactly(ctx.funds())));
+    stub.diagnostics.push(Diagnostic {
+        level: DiagnosticLevel::Note,
+        code: "stubbed_call".into(),
+        path: ctx.path().as_ref().clone(),
+        message: format!("{} was not called in a dry run", what),
+    });
+    stub
+}
+
 #[derive(PartialEq, Eq)]
 enum Nullable {
     Yes,
@@ -79,13 +123,32 @@ enum Nullable {
 }
 
 const UNIQUE_DERIVE_PANIC_MSG: &str = "Must be a valid derivation or internal invariant not held";
+/// `default_yields_templates` is set if the default arguments yield any
+/// templates, except for a `ThenFunc`.
 fn compute_all_effects<C, A: Default>(
     mut top_effect_ctx: Context,
     self_ref: &C,
     func: &dyn CallableAsFoF<C, A>,
+    cc: ConditionalCompileType,
+    default_yields_templates: &mut bool,
 ) -> TxTmplIt {
     let default_applied_effect_ctx = top_effect_ctx.derive(PathFragment::DefaultEffect)?;
-    let def = func.call(self_ref, default_applied_effect_ctx, Default::default())?;
+    let span = top_effect_ctx.span(SpanKind::Func);
+    let def = func.call(
+        self_ref,
+        default_applied_effect_ctx,
+        Default::default(),
+        cc.clone(),
+    )?;
+    drop(span);
+    // a ThenFunc has no continuation point to summarize, so its templates
+    // are consumed as they are generated
+    if func.get_returned_txtmpls_modify_guards() && !func.web_api() {
+        return Ok(def);
+    }
+    let def: Vec<_> = def.collect();
+    *default_yields_templates = def.iter().any(Result::is_ok);
+    let def: Box<dyn Iterator<Item = _>> = Box::new(def.into_iter());
     if !func.web_api() {
         return Ok(def);
     }
@@ -100,32 +163,123 @@ fn compute_all_effects<C, A: Default>(
             let c = applied_effects_ctx
                 .derive(PathFragment::Named(SArc(k.clone())))
                 .expect(UNIQUE_DERIVE_PANIC_MSG);
-            let w = func.call_json(self_ref, c, arg.clone())?;
+            let _span = top_effect_ctx.span(SpanKind::Func);
+            let w = func.call_json(self_ref, c, arg.clone(), cc.clone())?;
             Ok(Box::new(v.chain(w)))
         })
 }
 
-struct Renamer {
-    used_names: BTreeSet<String>,
+/// A branch whose templates are built, with the clauses of those to be
+/// extracted once the emulator has been asked for all of the contract's, see
+/// `Context::prefetch_signers`
+struct BuiltBranch<F> {
+    f_ctx: Context,
+    func: F,
+    nullability: Nullable,
+    trace: Option<(String, BranchTrace)>,
+    guards: Clause,
+    guard_metadata: Vec<(Clause, GuardSimps)>,
+    branch_path: Arc<EffectPath>,
+    effect_path: Arc<EffectPath>,
+    simp_ctx: Context,
+    default_yields_templates: bool,
+    templates: usize,
+    hashes: Vec<sha256::Hash>,
+    /// the hashes of the templates to extract clauses from
+    to_extract: Vec<sha256::Hash>,
+    /// the clauses already extracted from streamed templates, which aren't
+    /// kept
+    streamed_clauses: BTreeMap<sha256::Hash, Option<Clause>>,
+}
+
+/// What the checks of committed templates made once all of a contract's
+/// branches are compiled need from streamed templates, which aren't kept
+#[derive(Default)]
+struct StreamedChecks {
+    anchor_warnings: Vec<String>,
+    dust_warnings: Vec<String>,
+    dust_adjacent: Vec<String>,
+    /// the weight, total amount, and minimum feerate of committed templates
+    /// with one
+    feerates: Vec<(usize, Amount, Amount)>,
 }
 
-impl Renamer {
-    fn new() -> Self {
-        Renamer {
-            used_names: Default::default(),
+impl StreamedChecks {
+    fn record(&mut self, h: &sha256::Hash, txtmpl: &Template, committed: bool) {
+        if committed {
+            self.anchor_warnings.extend(anchor_warning(h, txtmpl));
+            self.feerates.extend(
+                txtmpl
+                    .min_feerate_sats_vbyte
+                    .map(|m| (txtmpl.tx.weight(), txtmpl.total_amount(), m)),
+            );
         }
+        self.dust_warnings.extend(dust_warnings(h, txtmpl));
+        self.dust_adjacent.extend(dust_adjacent(h, txtmpl));
     }
-    fn get_name(&mut self, a: &String) -> String {
-        let count = 0u64;
-        let mut name: String = a.clone();
-        loop {
-            if self.used_names.insert(name.clone()) {
-                return name;
-            } else {
-                name = format!("{}_renamed_{}", a, count);
-            }
+}
+
+/// A template returned from a branch with `available` funds, with the
+/// branch's `fee_policy` applied, checked to spend all of its funds if
+/// `conservation_checks`, and counted against the `ResourceLimits`
+fn check_template(
+    txtmpl: Result<Template, CompilationError>,
+    fee_policy: FeePolicy,
+    available: Amount,
+    conservation_checks: bool,
+    ctx: &Context,
+    effect_path: &EffectPath,
+    in_branch: impl Fn(CompilationError) -> CompilationError,
+) -> Result<Template, CompilationError> {
+    let txtmpl = txtmpl
+        .and_then(|t| apply_fee_policy(fee_policy, available, t))
+        .and_then(|t| match conservation_checks {
+            true => check_conservation(effect_path, available, t),
+            false => Ok(t),
+        })
+        .map_err(in_branch)?;
+    ctx.count_template(&txtmpl, effect_path)?;
+    Ok(txtmpl)
+}
+
+/// The clause `extractor` takes from `txtmpl`, with which the branch's
+/// `guards` must also be satisfiable given the template's nLockTime
+fn extract_clause(
+    extractor: fn(&Template, &Context) -> Result<Option<Clause>, CompilationError>,
+    txtmpl: &Template,
+    ctx: &Context,
+    guards: &Clause,
+    effect_path: &EffectPath,
+    in_branch: impl Fn(CompilationError) -> CompilationError,
+) -> Result<Option<Clause>, CompilationError> {
+    let clause = (extractor)(txtmpl, ctx)?;
+    if let Some(c) = clause.as_ref() {
+        let mut needed = vec![guards.clone(), c.clone()];
+        if txtmpl.tx.lock_time != 0 {
+            needed.push(Clause::After(txtmpl.tx.lock_time));
+        }
+        if let Some(mix) = Clause::And(needed).mixed_time_locks() {
+            return Err(in_branch(CompilationError::mixed_time_locks(
+                effect_path.clone(),
+                mix,
+            )));
         }
     }
+    Ok(clause)
+}
+
+/// errors from a ThenFunc are attributed to the branch
+fn branch_error<C, A>(
+    func: &dyn CallableAsFoF<C, A>,
+    branch_path: &EffectPath,
+    e: CompilationError,
+) -> CompilationError {
+    let e = if func.get_returned_txtmpls_modify_guards() {
+        CompilationError::in_branch(func.get_name().as_ref().clone(), e)
+    } else {
+        e
+    };
+    CompilationError::at(branch_path, e)
 }
 
 #[derive(Default)]
@@ -152,7 +306,11 @@ where
     /// The main Compilation Logic for a Contract.
     /// TODO: Better Document Semantics
     fn compile(&self, mut ctx: Context) -> Result<Compiled, CompilationError> {
+        ctx.check_depth()?;
+        ctx.check_cancelled()?;
+        let node = ctx.span(SpanKind::Node);
         let self_ref = self.get_inner_ref();
+        let diagnostics = ctx.fresh_diagnostics();
         let mut guard_clauses = GuardCache::new();
 
         // The below maps track metadata that is useful for consumers / verification.
@@ -160,6 +318,20 @@ where
         let mut comitted_txns = BTreeMap::new();
         // All other transactions
         let mut other_txns = BTreeMap::new();
+        // if templates are streamed, only what's needed to check them once
+        // all branches are compiled is kept
+        let sink = ctx.template_sink().cloned();
+        let simplify = ctx.clause_simplification();
+        // a dry run compiles no scripts, see `Context::dry_run`
+        let dry_run = ctx.is_dry_run();
+        let parallel = ctx.parallel_script_compilation();
+        let target = self.script_target(&ctx);
+        let mut streamed = BTreeSet::new();
+        // how committed templates are enforced, if a backend was chosen
+        let covenant = ctx.covenant_backend().map(CovenantBackend::covenant);
+        let mut covenants = BTreeMap::new();
+        let mut emulator_keys = BTreeSet::new();
+        let mut streamed_checks = StreamedChecks::default();
 
         // the min and max amount of funds spendable in the transactions
         let mut amount_range = AmountRange::new();
@@ -178,8 +350,18 @@ where
         //
         // we need a unique context for each.
         let mut action_ctx = ctx.derive(PathFragment::Action)?;
-        let mut renamer = Renamer::new();
-        let all_values = self
+        // then_fns and finish_or_fns share a namespace for effect routing
+        let mut used_names = BTreeSet::new();
+        // non-fatal diagnostics from conditional compilation
+        let mut warnings = vec![];
+        // conditional compilation decisions, if requested
+        let tracing = ctx.compile_trace_enabled();
+        let mut compile_trace = CompileTrace::new();
+        // whether any unreachable leaves were pruned
+        let mut pruned = false;
+        // the templates of ThenFuncs whose every leaf was pruned
+        let mut orphaned = vec![];
+        let built = self
             .then_fns()
             .iter()
             .filter_map(|func| func())
@@ -188,114 +370,433 @@ where
             // TODO: Without allocations?
             .map(|x| -> Box<dyn CallableAsFoF<_, _>> { Box::new(x) })
             .chain(self.finish_or_fns().iter().filter_map(|func| func()))
-            .map(|mut x| {
-                let new_name = Arc::new(renamer.get_name(x.get_name().as_ref()));
-                x.rename(new_name.clone());
-                let name = PathFragment::Named(SArc(new_name));
+            .map(|x| {
+                if !used_names.insert(x.get_name().clone()) {
+                    return Err(CompilationError::DuplicateFunctionName(
+                        x.get_name().as_ref().clone(),
+                    ));
+                }
+                let name = PathFragment::Named(SArc(x.get_name().clone()));
                 let f_ctx = action_ctx.derive(name).expect(UNIQUE_DERIVE_PANIC_MSG);
-                (f_ctx, x)
+                Ok((f_ctx, x))
             })
             // flat_map will discard any
             // skippable / never branches here
-            .flat_map(|(mut f_ctx, func)| {
+            .flat_map(|r| {
+                let (mut f_ctx, func) = match r {
+                    Ok(v) => v,
+                    Err(e) => return Some(Err(e)),
+                };
                 let mut this_ctx = f_ctx
                     // this should always be Ok(_)
                     .derive(PathFragment::CondCompIf)
                     .expect(UNIQUE_DERIVE_PANIC_MSG);
-                match CCILWrapper(func.get_conditional_compile_if())
-                    .assemble(self_ref, &mut this_ctx)
+                let mut conditions = vec![];
+                let (cc, branch_warnings) = CCILWrapper(func.get_conditional_compile_if())
+                    .assemble(
+                        self_ref,
+                        &mut this_ctx,
+                        Some(&mut conditions).filter(|_| tracing),
+                    );
+                if let ConditionalCompileType::Warn(w) = branch_warnings.for_branch(func.get_name())
                 {
+                    for w in w.iter() {
+                        f_ctx.diagnose(DiagnosticLevel::Warning, "conditional_compile", w.clone());
+                    }
+                    warnings.extend(w);
+                }
+                let trace = tracing.then(|| {
+                    (
+                        func.get_name().as_ref().clone(),
+                        BranchTrace {
+                            conditions,
+                            merged: cc.clone(),
+                            outcome: BranchOutcome::NoTemplates,
+                            pruned_leaves: 0,
+                        },
+                    )
+                });
+                let mut record = |outcome| {
+                    if let Some((name, mut trace)) = trace.clone() {
+                        trace.outcome = outcome;
+                        compile_trace.insert(name, trace);
+                    }
+                };
+                match cc.for_branch(func.get_name()) {
                     // Throw errors
                     ConditionalCompileType::Fail(errors) => {
-                        Some(Err(CompilationError::ConditionalCompilationFailed(errors)))
+                        record(BranchOutcome::Failed);
+                        Some(Err(CompilationError::at(
+                            f_ctx.path(),
+                            CompilationError::ConditionalCompilationFailed(errors),
+                        )))
                     }
                     // Non nullable
-                    ConditionalCompileType::Required | ConditionalCompileType::NoConstraint => {
-                        Some(Ok((f_ctx, func, Nullable::No)))
+                    cc @ ConditionalCompileType::Required
+                    | cc @ ConditionalCompileType::NoConstraint => {
+                        Some(Ok((f_ctx, func, Nullable::No, cc, trace)))
                     }
                     // Nullable
-                    ConditionalCompileType::Nullable => Some(Ok((f_ctx, func, Nullable::Yes))),
+                    cc @ ConditionalCompileType::Nullable => {
+                        Some(Ok((f_ctx, func, Nullable::Yes, cc, trace)))
+                    }
                     // Drop these
-                    ConditionalCompileType::Skippable | ConditionalCompileType::Never => None,
+                    ConditionalCompileType::Never => {
+                        f_ctx.diagnose(
+                            DiagnosticLevel::Note,
+                            "unreachable_branch",
+                            format!("branch `{}` is never compiled", func.get_name()),
+                        );
+                        record(BranchOutcome::NoTemplates);
+                        None
+                    }
+                    ConditionalCompileType::Skippable => {
+                        record(BranchOutcome::NoTemplates);
+                        None
+                    }
+                    // Warnings are split out by assemble
+                    ConditionalCompileType::Warn(_) => {
+                        unreachable!("Warnings are returned separately")
+                    }
                 }
             })
             .map(|r| {
-                let (mut f_ctx, func, nullability) = r?;
+                let (mut f_ctx, func, nullability, cc, trace) = r?;
+                let branch_path = f_ctx.path().clone();
                 let gctx = f_ctx.derive(PathFragment::Guard)?;
+                let guard_path = gctx.path().clone();
                 let simp_ctx = f_ctx.derive(PathFragment::Metadata)?;
                 // TODO: Suggested path frag?
-                let (guards, guard_metadata) =
-                    create_guards(self_ref, gctx, func.get_guard(), &mut guard_clauses)?;
+                let (guards, guard_metadata) = create_guards(
+                    self_ref,
+                    gctx,
+                    func.get_guard(),
+                    func.get_guard_combinator(),
+                    &mut guard_clauses,
+                )
+                .at(&guard_path)?;
+                if !func.get_guard().is_empty() && guards == Clause::Trivial {
+                    f_ctx.warn(
+                        "trivial_guard",
+                        format!(
+                            "the guards of branch `{}` are always satisfied",
+                            func.get_name()
+                        ),
+                    );
+                }
                 let effect_ctx = f_ctx.derive(if func.get_returned_txtmpls_modify_guards() {
                     PathFragment::Next
                 } else {
                     PathFragment::Suggested
                 })?;
                 let effect_path = effect_ctx.path().clone();
-                let transactions = compute_all_effects(effect_ctx, self_ref, func.as_ref());
+                let in_branch = |e| branch_error(func.as_ref(), &branch_path, e);
+                let fee_policy = func.get_fee_policy();
+                let mut default_yields_templates = false;
+                let available = effect_ctx.funds();
+                let conservation_checks = effect_ctx.conservation_checks();
+                let transactions = match fee_policy {
+                    FeePolicy::Reserve(fee) if fee > available => {
+                        Err(CompilationError::FeeReservationExceedsFunds(fee, available))
+                    }
+                    FeePolicy::Reserve(fee) => effect_ctx.spend_amount(fee),
+                    FeePolicy::None | FeePolicy::ReserveRate(_) => Ok(effect_ctx),
+                }
+                .and_then(|effect_ctx| {
+                    compute_all_effects(
+                        effect_ctx,
+                        self_ref,
+                        func.as_ref(),
+                        cc,
+                        &mut default_yields_templates,
+                    )
+                })
+                .map_err(in_branch);
                 // If no guards and not CTV, then nothing gets added (not
                 // interpreted as Trivial True)
                 //   - If CTV and no guards, just CTV added.
                 //   - If CTV and guards, CTV & guards added.
                 // it would be an error if any of r_txtmpls is an error
                 // instead of just an empty iterator.
-                let txtmpl_clauses = transactions?
+                let mut templates = 0;
+                let mut hashes = vec![];
+                let extractor = func.get_extract_clause_from_txtmpl();
+                // streamed templates are dropped once their clauses are
+                // extracted, asking the emulator for those of a ThenFunc's
+                // in batches
+                let mut streamed_clauses = BTreeMap::new();
+                let mut pending: Vec<Template> = vec![];
+                let extract_pending =
+                    |pending: &mut Vec<Template>, clauses: &mut BTreeMap<_, _>| {
+                        let hashes: Vec<_> = pending.iter().map(Template::hash).collect();
+                        ctx.prefetch_signers(&hashes)?;
+                        for txtmpl in pending.drain(..) {
+                            let clause = extract_clause(
+                                extractor,
+                                &txtmpl,
+                                &ctx,
+                                &guards,
+                                &effect_path,
+                                in_branch,
+                            )?;
+                            clauses.insert(txtmpl.hash(), clause);
+                        }
+                        Ok::<_, CompilationError>(())
+                    };
+                let to_extract = transactions?
                     .map(|r_txtmpl| {
-                        let txtmpl = r_txtmpl?;
+                        let txtmpl = check_template(
+                            r_txtmpl,
+                            fee_policy,
+                            available,
+                            conservation_checks,
+                            &ctx,
+                            &effect_path,
+                            in_branch,
+                        )?;
+                        templates += 1;
                         let h = txtmpl.hash();
+                        hashes.push(h);
                         amount_range.update_range(txtmpl.max);
-                        // Add the addition guards to these clauses
-                        let txtmpl = if func.get_returned_txtmpls_modify_guards() {
-                            &mut comitted_txns
-                        } else {
-                            &mut other_txns
+                        // Suggested templates from a ThenFunc are not
+                        // committed to, so add no clauses
+                        let committed = func.get_returned_txtmpls_modify_guards()
+                            && txtmpl.commitment.is_committed();
+                        if let Some(covenant) = covenant.filter(|_| committed) {
+                            covenants.insert(h, covenant);
+                        }
+                        let stored = match sink.as_ref() {
+                            Some(sink) => {
+                                if streamed.insert(h) {
+                                    sink(&txtmpl).map_err(in_branch)?;
+                                    streamed_checks.record(&h, &txtmpl, committed);
+                                }
+                                Some(txtmpl)
+                            }
+                            None => {
+                                if committed {
+                                    &mut comitted_txns
+                                } else {
+                                    &mut other_txns
+                                }
+                                .entry(h)
+                                .or_insert(txtmpl);
+                                None
+                            }
+                        };
+                        if func.get_returned_txtmpls_modify_guards() && !committed {
+                            return Ok(None);
+                        }
+                        if let Some(txtmpl) = stored {
+                            if func.get_returned_txtmpls_modify_guards() {
+                                pending.push(txtmpl);
+                                if pending.len() == MAX_SIGNER_BATCH {
+                                    extract_pending(&mut pending, &mut streamed_clauses)?;
+                                }
+                            } else {
+                                let clause = extract_clause(
+                                    extractor,
+                                    &txtmpl,
+                                    &ctx,
+                                    &guards,
+                                    &effect_path,
+                                    in_branch,
+                                )?;
+                                streamed_clauses.insert(h, clause);
+                            }
+                        }
+                        Ok(Some(h))
+                    })
+                    // Drop None values
+                    .filter_map(|s| s.transpose())
+                    // Forces any error to abort the whole thing
+                    .collect::<Result<Vec<_>, CompilationError>>()?;
+                extract_pending(&mut pending, &mut streamed_clauses)?;
+                Ok(BuiltBranch {
+                    f_ctx,
+                    func,
+                    nullability,
+                    trace,
+                    guards,
+                    guard_metadata,
+                    branch_path,
+                    effect_path,
+                    simp_ctx,
+                    default_yields_templates,
+                    templates,
+                    hashes,
+                    to_extract,
+                    streamed_clauses,
+                })
+            })
+            .collect::<Result<Vec<_>, CompilationError>>()?;
+        // the clauses of ThenFuncs' templates come from the emulator, so ask
+        // for all of this contract's at once
+        let then_hashes: Vec<_> = built
+            .iter()
+            .filter(|b| b.func.get_returned_txtmpls_modify_guards())
+            .flat_map(|b| {
+                b.to_extract
+                    .iter()
+                    .filter(move |h| !b.streamed_clauses.contains_key(*h))
+                    .copied()
+            })
+            .collect();
+        ctx.prefetch_signers(&then_hashes)?;
+        let all_values = built
+            .into_iter()
+            .map(|b| {
+                let BuiltBranch {
+                    f_ctx,
+                    func,
+                    nullability,
+                    mut trace,
+                    guards,
+                    guard_metadata,
+                    branch_path,
+                    effect_path,
+                    simp_ctx,
+                    default_yields_templates,
+                    templates,
+                    hashes,
+                    to_extract,
+                    streamed_clauses,
+                } = b;
+                let in_branch = |e| branch_error(func.as_ref(), &branch_path, e);
+                if func.get_returned_txtmpls_modify_guards() {
+                    for h in to_extract.iter() {
+                        let signer = ctx.ctv_emulator(*h).map_err(in_branch)?;
+                        emulator_keys.extend(signer.keys().into_iter().copied());
+                    }
+                }
+                let extractor = func.get_extract_clause_from_txtmpl();
+                let txtmpl_clauses = to_extract
+                    .iter()
+                    .map(|h| {
+                        if let Some(clause) = streamed_clauses.get(h) {
+                            return Ok(clause.clone());
                         }
-                        .entry(h)
-                        .or_insert(txtmpl);
-                        let extractor = func.get_extract_clause_from_txtmpl();
-                        (extractor)(txtmpl, &ctx)
+                        let txtmpl = comitted_txns
+                            .get(h)
+                            .or_else(|| other_txns.get(h))
+                            .expect("stored above");
+                        extract_clause(extractor, txtmpl, &ctx, &guards, &effect_path, in_branch)
                     })
                     // Drop None values
                     .filter_map(|s| s.transpose())
                     // Forces any error to abort the whole thing
                     .collect::<Result<Vec<Clause>, CompilationError>>()?;
+                if let Some((_, trace)) = trace.as_mut() {
+                    trace.outcome = if templates == 0 && nullability == Nullable::Yes {
+                        BranchOutcome::NoTemplates
+                    } else {
+                        BranchOutcome::Compiled { templates }
+                    };
+                }
 
+                let weights =
+                    SatisfactionWeights::of(&guards).map(|w| (func.get_name().as_ref().clone(), w));
                 // N.B. the order of the matches below is significant
-                Ok(if func.get_returned_txtmpls_modify_guards() {
-                    (
-                        None,
-                        combine_txtmpls(nullability, txtmpl_clauses, guards)?,
-                        guard_metadata,
-                    )
-                } else {
-                    let mut cp =
-                        ContinuationPoint::at(func.get_schema().clone(), effect_path.clone());
-                    for simp in func.gen_simps(self_ref, simp_ctx)? {
-                        cp = cp.add_simp(simp.as_ref())?;
+                let (v, mut leaves, m, mut trace, n, w) =
+                    if func.get_returned_txtmpls_modify_guards() {
+                        (
+                            None,
+                            weigh_leaves(
+                                func.get_name(),
+                                func.get_weight(),
+                                if dry_run {
+                                    if nullability == Nullable::No && txtmpl_clauses.is_empty() {
+                                        return Err(CompilationError::MissingTemplates);
+                                    }
+                                    vec![]
+                                } else {
+                                    combine_txtmpls(nullability, txtmpl_clauses, guards, simplify)?
+                                },
+                            )?,
+                            guard_metadata,
+                            trace,
+                            Some((func.get_name().as_ref().clone(), hashes)),
+                            weights,
+                        )
+                    } else {
+                        let mut cp =
+                            ContinuationPoint::at(func.get_schema().clone(), effect_path.clone())
+                                .with_returned_template_schema(
+                                    func.get_returned_template_schema().clone(),
+                                )
+                                .with_display(
+                                    func.get_display_order(),
+                                    func.get_hidden(),
+                                    func.get_description().clone(),
+                                )
+                                .with_summary(guards.to_policy_string(), default_yields_templates);
+                        for simp in func.gen_simps(self_ref, simp_ctx)? {
+                            cp = cp.add_simp(simp.as_ref())?;
+                        }
+                        let v = weigh_leaves(
+                            func.get_name(),
+                            func.get_weight(),
+                            if dry_run {
+                                vec![]
+                            } else {
+                                optimizer_flatten_and_simplify(guards, simplify)
+                            },
+                        )?;
+                        (
+                            Some((SArc(effect_path), cp)),
+                            v,
+                            guard_metadata,
+                            trace,
+                            None,
+                            weights,
+                        )
+                    };
+                let unreachable = prune_unreachable(&f_ctx, func.get_name(), &mut leaves);
+                if let Some((_, trace)) = trace.as_mut() {
+                    trace.pruned_leaves = unreachable;
+                }
+                pruned |= unreachable > 0;
+                // a ThenFunc without any leaves left can't be spent, so
+                // neither can its templates
+                let n = match n {
+                    Some((_, hashes)) if unreachable > 0 && leaves.is_empty() => {
+                        orphaned.extend(hashes);
+                        None
                     }
-                    let v = optimizer_flatten_and_compile(guards)?;
-                    (Some((SArc(effect_path), cp)), v, guard_metadata)
-                })
+                    n => n,
+                };
+                Ok((v, leaves, m, trace, n, w))
             })
-            .collect::<Result<Vec<(_, Vec<Miniscript<XOnlyPublicKey, Tap>>, _)>, CompilationError>>(
-            )?;
+            .collect::<Result<Vec<(_, Vec<(f64, Clause)>, _, _, _, _)>, CompilationError>>()?;
 
         let mut continue_apis = ContinueAPIs::default();
         let mut clause_accumulator = vec![];
         let mut all_guard_simps: BTreeMap<Clause, GuardSimps> = Default::default();
-        for (v, b, c) in all_values {
+        let mut then_branches = BTreeMap::new();
+        let mut satisfaction_weights = BTreeMap::new();
+        for (v, b, c, t, n, w) in all_values {
             continue_apis.extend(std::iter::once(v));
+            compile_trace.extend(t);
+            then_branches.extend(n);
+            satisfaction_weights.extend(w);
             clause_accumulator.push(b);
             for (pol, mut simps) in c {
                 all_guard_simps.entry(pol).or_default().append(&mut simps)
             }
         }
+        // unless another branch has the same template
+        let kept: BTreeSet<_> = then_branches.values().flatten().copied().collect();
+        for h in orphaned.iter().filter(|h| !kept.contains(*h)) {
+            comitted_txns.remove(h);
+            other_txns.remove(h);
+            covenants.remove(h);
+        }
         for guard_simps in all_guard_simps.values_mut() {
             guard_simps.sort_by_key(|k| k as *const _ as usize);
             guard_simps.dedup_by(|a, b| std::ptr::eq(a, b))
         }
 
-        let branches: Vec<Miniscript<XOnlyPublicKey, Tap>> = {
+        let branches: Vec<(f64, Clause)> = {
             let mut finish_fns_ctx = ctx.derive(PathFragment::FinishFn)?;
             // Compute all finish_functions at this level, caching if requested.
             let guards = self
@@ -308,13 +809,23 @@ where
                     Some((new, simp))
                 }))
                 .filter_map(|(func, (c, simp_c))| {
-                    guard_clauses.get(self_ref, *func, c, simp_c).transpose()
+                    guard_clauses.get(self_ref, func, c, simp_c).transpose()
                 })
                 .collect::<Result<Vec<_>, _>>()?;
+            let guards = resolve_guards(&finish_fns_ctx, guards)?;
             let all_g = guards
                 .into_iter()
-                .map(|(policy, _m)| optimizer_flatten_and_compile(policy))
-                .collect::<Result<Vec<_>, _>>()?;
+                .filter(|_| !dry_run)
+                .map(|(policy, _m)| {
+                    let mut leaves = weigh_leaves(
+                        "finish",
+                        None,
+                        optimizer_flatten_and_simplify(policy, simplify),
+                    )?;
+                    pruned |= prune_unreachable(&finish_fns_ctx, "finish", &mut leaves) > 0;
+                    Ok(leaves)
+                })
+                .collect::<Result<Vec<_>, CompilationError>>()?;
 
             all_g
                 .into_iter()
@@ -322,31 +833,140 @@ where
                 .flatten()
                 .collect()
         };
-        // TODO: Pick a better branch that is guaranteed to work!
-        let some_key = pick_key_from_miniscripts(branches.iter());
-        // Don't remove the key from the scripts in case it was bogus
-        let tree = branches_to_tree(branches);
-        let descriptor = Descriptor::Tr(descriptor::Tr::new(some_key, tree)?);
-        let estimated_max_size = descriptor.max_satisfaction_weight()?;
-        // TODO: Convert into an address instead of keeping descriptor,
-        // hot-fix workaround
-        let address = descriptor.clone().into();
-        let descriptor = Some(descriptor.into());
+        if pruned && branches.is_empty() {
+            return Err(CompilationError::AllBranchesUnreachable);
+        }
+        // compiling the leaves is independent of the rest of the contract, so
+        // they are compiled together, see
+        // `Context::with_parallel_script_compilation`
+        let span = ctx.span(SpanKind::Miniscript);
+        let branches = compile_leaves(branches, target, ctx.segwit_v0_keys(), parallel)?;
+        drop(span);
+        let mut internal_key = None;
+        let (address, descriptor, estimated_max_size) = match target {
+            ScriptTarget::TaprootPreferred => {
+                let branches: Vec<_> = branches
+                    .into_iter()
+                    .filter_map(|(w, b)| match b {
+                        CompiledLeaf::Tap(ms) => Some((w, ms)),
+                        CompiledLeaf::SegwitV0(_) => None,
+                    })
+                    .collect();
+                let (key, branches) = if ctx.internal_key_promotion() {
+                    promote_internal_key(branches)
+                } else {
+                    (
+                        pick_key_from_miniscripts(branches.iter().map(|(_, ms)| ms)),
+                        branches,
+                    )
+                };
+                internal_key = Some(key);
+                let tree = branches_to_tree(branches);
+                let descriptor = Descriptor::Tr(descriptor::Tr::new(key.key, tree)?);
+                let estimated_max_size = descriptor.max_satisfaction_weight()?;
+                // TODO: Convert into an address instead of keeping descriptor,
+                // hot-fix workaround
+                (
+                    descriptor.clone().into(),
+                    descriptor.into(),
+                    estimated_max_size,
+                )
+            }
+            ScriptTarget::SegwitV0Only => {
+                let mut clauses: Vec<_> = branches
+                    .into_iter()
+                    .filter_map(|(_, b)| match b {
+                        CompiledLeaf::SegwitV0(clause) => Some((1, clause)),
+                        CompiledLeaf::Tap(_) => None,
+                    })
+                    .collect();
+                let clause = match clauses.len() {
+                    0 => Clause::Key(unspendable_key()),
+                    1 => clauses.remove(0).1,
+                    _ => Clause::Or(clauses),
+                };
+                let descriptor = Descriptor::Wsh(descriptor::Wsh::new(compile_segwit_v0(
+                    &clause,
+                    ctx.segwit_v0_keys(),
+                )?)?);
+                let estimated_max_size = descriptor.max_satisfaction_weight()?;
+                let address = descriptor.address(ctx.network)?.into();
+                (address, descriptor.into(), estimated_max_size)
+            }
+        };
+        for t in comitted_txns.values_mut().chain(other_txns.values_mut()) {
+            t.input_witness_weight = Some(estimated_max_size as u64);
+        }
+        let descriptor = Some(descriptor);
         let root_path = SArc(ctx.path().clone());
 
-        let failed_estimate = comitted_txns.values().any(|a| {
-            // witness space not scaled
-            let tx_size = a.tx.get_weight() + estimated_max_size;
-            let fees = amount_range.max() - a.total_amount();
-            a.min_feerate_sats_vbyte
-                .map(|m| fees.as_sat() < (m.as_sat() * tx_size as u64))
-                == Some(false)
-        });
+        // a committed transaction's fee can't be adjusted later, so it needs
+        // some slack or a way to CPFP. A template with no outputs already pays
+        // everything to fees.
+        let unbumpable: Vec<_> = comitted_txns
+            .iter()
+            .filter_map(|(h, t)| anchor_warning(h, t))
+            .chain(streamed_checks.anchor_warnings)
+            .collect();
+        // dust let through by `Context::with_dust_as_warning`
+        let dust: Vec<_> = comitted_txns
+            .iter()
+            .chain(other_txns.iter())
+            .flat_map(|(h, t)| dust_warnings(h, t))
+            .chain(streamed_checks.dust_warnings)
+            .collect();
+        for w in unbumpable.iter() {
+            ctx.warn("unbumpable_template", w.clone());
+        }
+        for w in dust.iter() {
+            ctx.warn("dust_output", w.clone());
+        }
+        warnings.extend(unbumpable);
+        warnings.extend(dust);
+        // outputs which would become dust if their fee were bumped a little
+        for w in comitted_txns
+            .iter()
+            .chain(other_txns.iter())
+            .flat_map(|(h, t)| dust_adjacent(h, t))
+            .chain(streamed_checks.dust_adjacent)
+        {
+            ctx.warn("dust_adjacent_output", w);
+        }
+        if ctx.is_top_level() && ctx.feerate().is_none() {
+            ctx.diagnose(
+                DiagnosticLevel::Note,
+                "no_feerate",
+                "no feerate was set, so templates reserve only the fees their branches ask for",
+            );
+        }
+        let failed_estimate = comitted_txns
+            .values()
+            .filter_map(|a| {
+                a.min_feerate_sats_vbyte
+                    .map(|m| (a.tx.weight(), a.total_amount(), m))
+            })
+            .chain(streamed_checks.feerates)
+            .any(|(weight, total, m)| {
+                // witness space not scaled
+                let tx_size = weight + estimated_max_size;
+                let fees = amount_range.max() - total;
+                fees.as_sat() >= (m.as_sat() * tx_size as u64)
+            });
+        // a nested contract is funded with at most `ctx.funds()`, any surplus
+        // going to fees, which must still cover the amount it ensures
+        let accepted = AmountRange::between(ensured_amount, amount_range.max());
+        let funded = AmountRange::between(Amount::from_sat(0), ctx.funds());
         if failed_estimate {
             Err(CompilationError::MinFeerateError)
+        } else if !ctx.is_top_level() && accepted.intersect(&funded).is_empty() {
+            Err(CompilationError::IncompatibleAmountRange {
+                path: ctx.path().as_ref().clone(),
+                accepted: Box::new(accepted),
+                funded: Box::new(funded),
+            })
         } else {
             let metadata_ctx = ctx.derive(PathFragment::Metadata)?;
-            Ok(Compiled {
+            let mut compiled = Compiled {
                 ctv_to_tx: comitted_txns,
                 suggested_txs: other_txns,
                 continue_apis: continue_apis.inner,
@@ -354,29 +974,297 @@ where
                 address,
                 descriptor,
                 amount_range,
-                metadata: self
-                    .metadata(metadata_ctx)?
-                    .add_guard_simps(all_guard_simps)?,
-            })
+                metadata: ObjectMetadata {
+                    path: Some(ctx.path().label()),
+                    ..self
+                        .metadata(metadata_ctx)?
+                        .add_guard_simps(all_guard_simps)?
+                },
+                warnings,
+                branches: then_branches,
+                satisfaction_weights,
+                compile_trace: tracing.then_some(compile_trace),
+                covenants,
+                profile: None,
+                internal_key,
+                emulator: ctx.emulator_override(),
+                emulator_keys,
+                diagnostics: diagnostics.take(),
+                skeleton: dry_run,
+            };
+            // Effects are looked up by the full path of each continuation, so
+            // reach nested contracts too. Any left over went nowhere.
+            if ctx.is_top_level() {
+                let reachable: BTreeSet<_> = compiled
+                    .continuation_points()
+                    .into_iter()
+                    .map(|c| c.point.path)
+                    .collect();
+                for path in ctx.get_effects(InternalCompilerTag { _secret: () }).paths() {
+                    if path.has_prefix(ctx.path()) && !reachable.contains(path) {
+                        let message = format!(
+                            "effect at `{}` matches no continuation",
+                            String::from(path.as_ref().clone())
+                        );
+                        compiled.diagnostics.push(Diagnostic {
+                            level: DiagnosticLevel::Warning,
+                            code: "unused_effect".into(),
+                            path: path.as_ref().clone(),
+                            message: message.clone(),
+                        });
+                        compiled.warnings.push(message);
+                    }
+                }
+                // the contract's own span must end before the profile is taken
+                drop(node);
+                compiled.profile = ctx.take_profile();
+            }
+            Ok(compiled)
         }
     }
 }
 
-fn optimizer_flatten_and_compile(
-    guards: policy::Concrete<XOnlyPublicKey>,
-) -> Result<Vec<Miniscript<XOnlyPublicKey, Tap>>, CompilationError> {
-    let v = optimizer_flatten_policy(guards)
+/// Warns about a committed template if its fee can't be bumped
+fn anchor_warning(h: &sha256::Hash, t: &Template) -> Option<String> {
+    (!t.outputs.is_empty() && t.anchor().is_none() && t.max <= t.total_amount()).then(|| {
+        format!(
+            "committed template {} reserves no fees and has no anchor output",
+            h
+        )
+    })
+}
+
+/// Warns about each output of a template below the dust limit
+fn dust_warnings(h: &sha256::Hash, t: &Template) -> Vec<String> {
+    t.dust_outputs()
         .into_iter()
-        .map(|g| g.compile())
-        .collect::<Result<Vec<_>, _>>()?;
-    Ok(v)
+        .map(|(index, amount, limit)| {
+            format!(
+                "output {} of template {} sends {}, below the dust limit of {}",
+                index, h, amount, limit
+            )
+        })
+        .collect()
+}
+
+/// Warns about each output of a template worth less than twice its dust
+/// limit, which a small change in fees could push below it
+fn dust_adjacent(h: &sha256::Hash, t: &Template) -> Vec<String> {
+    t.tx.output
+        .iter()
+        .enumerate()
+        .map(|(i, o)| (i, Amount::from_sat(o.value), o.script_pubkey.dust_value()))
+        .filter(|(_, amount, limit)| *amount >= *limit && amount.as_sat() < 2 * limit.as_sat())
+        .map(|(index, amount, limit)| {
+            format!(
+                "output {} of template {} sends {}, close to the dust limit of {}",
+                index, h, amount, limit
+            )
+        })
+        .collect()
+}
+
+/// `simplify` the guards first, see `Context::with_clause_simplification`
+fn optimizer_flatten_and_simplify(
+    guards: policy::Concrete<XOnlyPublicKey>,
+    simplify: bool,
+) -> Vec<Clause> {
+    let guards = if simplify { guards.simplify() } else { guards };
+    optimizer_flatten_policy(guards)
+}
+
+/// Warns about the leaves of branch `name` which can never be spent, e.g.
+/// from a threshold of no keys, removing them if
+/// `Context::with_unreachable_pruning`. Returns how many were removed.
+fn prune_unreachable(ctx: &Context, name: &str, leaves: &mut Vec<(f64, Clause)>) -> usize {
+    let unreachable = |leaf: &Clause| leaf.simplify() == Clause::Unsatisfiable;
+    let count = leaves.iter().filter(|(_, l)| unreachable(l)).count();
+    if count == 0 {
+        return 0;
+    }
+    ctx.warn(
+        "unreachable_branch",
+        format!(
+            "{} of the {} leaves of branch `{}` can never be spent",
+            count,
+            leaves.len(),
+            name
+        ),
+    );
+    if !ctx.unreachable_pruning() {
+        return 0;
+    }
+    leaves.retain(|(_, l)| !unreachable(l));
+    count
+}
+
+/// pairs each leaf of a branch with the branch's `weight`, see
+/// `FinishOrFunc::weight`. Without weights, every leaf weighs the same.
+fn weigh_leaves<L>(
+    name: &str,
+    weight: Option<f64>,
+    leaves: Vec<L>,
+) -> Result<Vec<(f64, L)>, CompilationError> {
+    let weight = weight.unwrap_or(1.0);
+    if !(weight.is_finite() && weight > 0.0) {
+        return Err(CompilationError::InvalidBranchWeight(name.into(), weight));
+    }
+    Ok(leaves.into_iter().map(|l| (weight, l)).collect())
+}
+
+/// An error from compiling a `Clause`, which unlike a `CompilationError` can
+/// be sent between threads, see `compile_leaves`
+#[derive(Debug)]
+enum LeafError {
+    /// see `CompilationError::ClauseCompilationFailed`
+    Failed(String, policy::compiler::CompilerError),
+    /// see `CompilationError::TaprootOnlyClause`
+    TaprootOnly(String, policy::compiler::CompilerError),
+    /// see `CompilationError::SegwitV0KeyParityUnknown`
+    ParityUnknown(String, XOnlyPublicKey),
+}
+
+impl From<LeafError> for CompilationError {
+    fn from(e: LeafError) -> Self {
+        match e {
+            LeafError::Failed(clause, error) => {
+                CompilationError::ClauseCompilationFailed { clause, error }
+            }
+            LeafError::TaprootOnly(clause, error) => {
+                CompilationError::TaprootOnlyClause { clause, error }
+            }
+            LeafError::ParityUnknown(clause, key) => {
+                CompilationError::SegwitV0KeyParityUnknown { clause, key }
+            }
+        }
+    }
+}
+
+/// compile each weighted leaf for `target`, keeping their order. With the
+/// `parallel` feature they are compiled on the rayon thread pool if
+/// `parallel`, and the error of the first leaf which fails is returned, as
+/// when compiling them in order.
+#[cfg_attr(
+    not(all(feature = "parallel", not(target_arch = "wasm32"))),
+    allow(unused_variables)
+)]
+fn compile_leaves(
+    leaves: Vec<(f64, Clause)>,
+    target: ScriptTarget,
+    keys: &BTreeMap<XOnlyPublicKey, bitcoin::PublicKey>,
+    parallel: bool,
+) -> Result<Vec<(f64, CompiledLeaf)>, CompilationError> {
+    let compile = |(weight, clause)| compile_leaf(clause, target, keys).map(|l| (weight, l));
+    #[cfg(all(feature = "parallel", not(target_arch = "wasm32")))]
+    if parallel {
+        use rayon::prelude::*;
+        let compiled: Vec<_> = leaves.into_par_iter().map(compile).collect();
+        return Ok(compiled.into_iter().collect::<Result<_, _>>()?);
+    }
+    Ok(leaves.into_iter().map(compile).collect::<Result<_, _>>()?)
+}
+
+/// compile a single `Clause` for `target`, see `compile_segwit_v0` for `keys`
+fn compile_leaf(
+    clause: Clause,
+    target: ScriptTarget,
+    keys: &BTreeMap<XOnlyPublicKey, bitcoin::PublicKey>,
+) -> Result<CompiledLeaf, LeafError> {
+    match target {
+        ScriptTarget::TaprootPreferred => compile_clause(clause).map(CompiledLeaf::Tap),
+        ScriptTarget::SegwitV0Only => {
+            compile_segwit_v0(&clause, keys)?;
+            Ok(CompiledLeaf::SegwitV0(clause))
+        }
+    }
+}
+
+/// compile a `Clause` to a segwit v0 script, using the full key in `keys` of
+/// each x-only key, and naming the clause in any error. Only the unspendable
+/// key, which nobody can sign for, may be missing from `keys`.
+fn compile_segwit_v0(
+    clause: &Clause,
+    keys: &BTreeMap<XOnlyPublicKey, bitcoin::PublicKey>,
+) -> Result<Miniscript<bitcoin::PublicKey, Segwitv0>, LeafError> {
+    let policy: policy::Concrete<bitcoin::PublicKey> =
+        clause.translate_pk(|k| match keys.get(k) {
+            Some(full) => Ok(*full),
+            None if *k == unspendable_key() => Ok(bitcoin::PublicKey::new(
+                bitcoin::secp256k1::PublicKey::from_x_only_public_key(*k, Parity::Even),
+            )),
+            None => Err(LeafError::ParityUnknown(clause.to_policy_string(), *k)),
+        })?;
+    policy.compile::<Segwitv0>().map_err(|error| {
+        // only an error for segwit v0 if taproot would have been fine
+        match compile_clause(clause.clone()) {
+            Ok(_) => LeafError::TaprootOnly(clause.to_policy_string(), error),
+            Err(e) => e,
+        }
+    })
+}
+
+/// compile a single `Clause`, naming it in any error
+fn compile_clause(clause: Clause) -> Result<Miniscript<XOnlyPublicKey, Tap>, LeafError> {
+    clause
+        .compile()
+        .map_err(|error| LeafError::Failed(clause.to_policy_string(), error))
+}
+
+/// Counts any fee reserved by `fee_policy` towards the fee of a template
+/// returned from a branch with `available` funds.
+fn apply_fee_policy(
+    fee_policy: FeePolicy,
+    available: Amount,
+    mut txtmpl: Template,
+) -> Result<Template, CompilationError> {
+    match fee_policy {
+        FeePolicy::None => {}
+        // already deducted from the funds passed to the branch
+        FeePolicy::Reserve(fee) => txtmpl.max += fee,
+        FeePolicy::ReserveRate(rate) => {
+            let fee = rate * txtmpl.tx.vsize() as u64;
+            let remaining = available
+                .checked_sub(txtmpl.max)
+                .unwrap_or_else(|| Amount::from_sat(0));
+            if fee > remaining {
+                return Err(CompilationError::FeeReservationExceedsFunds(fee, remaining));
+            }
+            txtmpl.max += fee;
+        }
+    }
+    Ok(txtmpl)
+}
+
+/// Checks that a template returned from a branch with `available` funds,
+/// plus any external funds it adds, spends all of them on its outputs and
+/// fees.
+fn check_conservation(
+    path: &EffectPath,
+    available: Amount,
+    txtmpl: Template,
+) -> Result<Template, CompilationError> {
+    let funds = available + txtmpl.external_funds.unwrap_or(Amount::from_sat(0));
+    if funds == txtmpl.max {
+        Ok(txtmpl)
+    } else {
+        Err(CompilationError::UnaccountedFunds {
+            path: path.clone(),
+            missing: funds.to_signed()? - txtmpl.max.to_signed()?,
+        })
+    }
 }
 
+/// The leaves of a branch which commits to `txtmpl_clauses`, each to be
+/// compiled with `compile_leaves`. `simplify` the clauses first, see
+/// `Context::with_clause_simplification`
 fn combine_txtmpls(
     nullability: Nullable,
     txtmpl_clauses: Vec<Clause>,
     guards: Clause,
-) -> Result<Vec<Miniscript<XOnlyPublicKey, Tap>>, CompilationError> {
+    simplify: bool,
+) -> Result<Vec<Clause>, CompilationError> {
+    let maybe_simplify = |c: Clause| if simplify { c.simplify() } else { c };
+    let guards = maybe_simplify(guards);
     match (nullability, txtmpl_clauses.len(), guards) {
         // This is a nullable branch without any proposed
         // transactions.
@@ -394,22 +1282,15 @@ fn combine_txtmpls(
         // Error if 0 templates return and we don't want to be nullable
         (Nullable::No, 0, _) => Err(CompilationError::MissingTemplates),
         // If the guard is trivial, return the hashes standalone
-        (_, _, Clause::Trivial) => Ok(txtmpl_clauses
-            .into_iter()
-            .map(|policy| policy.compile().map_err(Into::<CompilationError>::into))
-            .collect::<Result<Vec<_>, _>>()?),
+        (_, _, Clause::Trivial) => Ok(txtmpl_clauses.into_iter().map(maybe_simplify).collect()),
         // If the guard is non-trivial, zip it to each hash
         // TODO: Arc in miniscript to dedup memory?
         //       This could be Clause::Shared(x) or something...
         (_, _, guards) => Ok(txtmpl_clauses
             .into_iter()
             // extra_guards will contain any CTV
-            .map(|extra_guards| {
-                Clause::And(vec![guards.clone(), extra_guards])
-                    .compile()
-                    .map_err(Into::<CompilationError>::into)
-            })
-            .collect::<Result<Vec<_>, _>>()?),
+            .map(|extra_guards| maybe_simplify(Clause::And(vec![guards.clone(), extra_guards])))
+            .collect()),
     }
 }
 
@@ -425,3 +1306,2692 @@ fn optimizer_flatten_policy(p: Clause) -> Vec<Clause> {
         p => vec![p],
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::context::MAX_SIGNER_BATCH;
+    use crate::contract::object::{
+        InternalKey, InternalKeySource, ObjectError, SupportedDescriptors, TemplateCovenant,
+    };
+    use crate::contract::{empty, Contract};
+    use crate::template::builder::{AnchorTo, Builder, DEFAULT_ANCHOR_SATS};
+    use crate::template::Commitment;
+    use bitcoin::util::amount::{Amount, SignedAmount};
+    use bitcoin::Network;
+    use sapio_base::clause::MuSig;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_base::musig::MuSigKeySet;
+    use sapio_base::simp::SIMP;
+    use sapio_base::timelocks::{AbsHeight, AbsTime, AnyAbsTimeLock};
+    use sapio_ctv_emulator_trait::{
+        CTVAvailable, CTVEmulator, EmulatorError, EmulatorRequest, EmulatorResponse,
+    };
+    use std::convert::TryFrom;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("compiler").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    struct Redeemable;
+    impl Redeemable {
+        fn never() -> Option<ConditionallyCompileIf<Self>> {
+            Some(ConditionallyCompileIf::Fresh(|_, _| {
+                ConditionalCompileType::Never
+            }))
+        }
+        fn required() -> Option<ConditionallyCompileIf<Self>> {
+            Some(ConditionallyCompileIf::Fresh(|_, _| {
+                ConditionalCompileType::Required
+            }))
+        }
+        fn redeem<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[Self::never, Self::required],
+                    func: |_, _, _| empty(),
+                    name: Arc::new("redeem".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Redeemable {
+        declare! {then, Self::redeem}
+        declare! {non updatable}
+    }
+    #[test]
+    fn failure_names_branch() {
+        let err = Redeemable.compile(ctx()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("branch `redeem`: Never and Required incompatible"));
+    }
+    fn warn<T>() -> Option<ConditionallyCompileIf<T>> {
+        Some(ConditionallyCompileIf::Fresh(|_, _| {
+            ConditionalCompileType::Warn(std::iter::once("deprecated".into()).collect())
+        }))
+    }
+    fn fail<T>() -> Option<ConditionallyCompileIf<T>> {
+        Some(ConditionallyCompileIf::Fresh(|_, _| {
+            ConditionalCompileType::Fail(std::iter::once("broken".into()).collect())
+        }))
+    }
+    /// `builder`, leaving the funds it doesn't spend as its fee
+    fn leaving_fees(builder: Builder) -> TxTmplIt {
+        let rest = builder.ctx().funds();
+        builder.add_fees(rest)?.into()
+    }
+    fn branch<'a, T>(
+        name: &str,
+        conditional_compile_if: ConditionallyCompileIfList<'a, T>,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard: &[],
+                guard_combinator: Default::default(),
+                conditional_compile_if,
+                func: |_, ctx, _| leaving_fees(ctx.template()),
+                name: Arc::new(name.into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    struct Warned;
+    impl Warned {
+        fn a<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("a", &[warn])
+        }
+        fn b<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("b", &[warn, warn])
+        }
+    }
+    impl Contract for Warned {
+        declare! {then, Self::a, Self::b}
+        declare! {non updatable}
+    }
+    #[test]
+    fn warnings_from_branches_are_collected() {
+        let compiled = Warned.compile(ctx()).unwrap();
+        assert_eq!(
+            compiled.warnings,
+            vec![
+                "branch `a`: deprecated".to_string(),
+                "branch `b`: deprecated".into(),
+                "branch `b`: deprecated".into(),
+            ]
+        );
+    }
+    struct WarnedAndFailed;
+    impl WarnedAndFailed {
+        fn c<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("c", &[warn, fail])
+        }
+    }
+    impl Contract for WarnedAndFailed {
+        declare! {then, Self::c}
+        declare! {non updatable}
+    }
+    #[test]
+    fn warn_does_not_mask_failure() {
+        let w = || ConditionalCompileType::Warn(std::iter::once("w".into()).collect());
+        let f = || ConditionalCompileType::Fail(std::iter::once("f".into()).collect());
+        assert_eq!(w().merge(f()), f());
+        assert_eq!(f().merge(w()), f());
+        let err = WarnedAndFailed.compile(ctx()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "at `compiler/@action/c`: Conditional Compilation Failed: branch `c`: broken"
+        );
+    }
+    fn skippable<T>() -> Option<ConditionallyCompileIf<T>> {
+        Some(ConditionallyCompileIf::Fresh(|_, _| {
+            ConditionalCompileType::Skippable
+        }))
+    }
+    fn nullable<T>() -> Option<ConditionallyCompileIf<T>> {
+        Some(ConditionallyCompileIf::Fresh(|_, _| {
+            ConditionalCompileType::Nullable
+        }))
+    }
+    struct Traced;
+    impl Traced {
+        fn maybe<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("maybe", &[skippable, nullable])
+        }
+    }
+    impl Contract for Traced {
+        declare! {then, Self::maybe}
+        declare! {non updatable}
+    }
+    #[test]
+    fn trace_records_dropped_branch() {
+        assert!(Traced.compile(ctx()).unwrap().compile_trace.is_none());
+        let compiled = Traced.compile(ctx().enable_compile_trace()).unwrap();
+        let trace = &compiled.compile_trace.as_ref().unwrap()["maybe"];
+        assert_eq!(
+            trace.conditions,
+            vec![
+                ConditionalCompileType::Skippable,
+                ConditionalCompileType::Nullable
+            ]
+        );
+        assert_eq!(trace.merged, ConditionalCompileType::Skippable);
+        assert_eq!(trace.outcome, BranchOutcome::NoTemplates);
+        let json = serde_json::to_value(&compiled).unwrap();
+        assert_eq!(
+            json["compile_trace"]["maybe"]["merged"],
+            serde_json::json!("Skippable")
+        );
+    }
+    struct Previewed;
+    impl Previewed {
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|_, _| {
+                    Clause::Key(
+                        "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                            .parse()
+                            .unwrap(),
+                    )
+                }),
+                None,
+            ))
+        }
+        fn preview() -> Option<Box<dyn CallableAsFoF<Self, ()>>> {
+            Some(Box::new(FinishOrFunc::<_, _, _, WebAPIDisabled> {
+                simp_gen: None,
+                coerce_args: Ok,
+                guard: &[GuardGen::Fn(Self::signed)],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                conditional_compile_if_args: &[],
+                func: |_, _, _| empty(),
+                schema: None,
+                returned_template_schema: Some(Arc::new(serde_json::json!({"maxItems": 0}))),
+                name: Arc::new("preview".into()),
+                f: Default::default(),
+                returned_txtmpls_modify_guards: false,
+                extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+                fee_policy: FeePolicy::None,
+                weight: None,
+                display_order: None,
+                hidden: false,
+                description: None,
+            }))
+        }
+    }
+    impl Contract for Previewed {
+        declare! {updatable<()>, Self::preview}
+    }
+    #[test]
+    fn returned_template_schema_on_continuation() {
+        let compiled = Previewed.compile(ctx()).unwrap();
+        let cp: Vec<_> = compiled.continue_apis.values().collect();
+        assert_eq!(cp.len(), 1);
+        assert_eq!(
+            cp[0]
+                .returned_template_schema
+                .as_ref()
+                .map(|s| s.0.as_ref()),
+            Some(&serde_json::json!({"maxItems": 0}))
+        );
+    }
+    struct Escrow;
+    impl Escrow {
+        fn complete<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("complete", &[])
+        }
+        fn refund<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("refund", &[])
+        }
+    }
+    impl Contract for Escrow {
+        declare! {then, Self::complete, Self::refund}
+        declare! {non updatable}
+    }
+    #[test]
+    fn then_branches_are_named() {
+        let compiled = Escrow.compile(ctx()).unwrap();
+        assert_eq!(
+            compiled.branches.keys().collect::<Vec<_>>(),
+            vec!["complete", "refund"]
+        );
+        for hashes in compiled.branches.values() {
+            assert_eq!(hashes.len(), 1);
+            assert!(compiled.ctv_to_tx.contains_key(&hashes[0]));
+        }
+    }
+    struct Broken;
+    impl Broken {
+        fn refund<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, _, _| Err(CompilationError::TerminateWith("no refunds".into())),
+                    name: Arc::new("refund".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Broken {
+        declare! {then, Self::refund}
+        declare! {non updatable}
+    }
+    #[test]
+    fn branch_errors_are_named() {
+        let err = Broken.compile(ctx()).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("at `compiler/@action/refund`: branch `refund` failed: "));
+        assert!(
+            matches!(err.without_path(), CompilationError::BranchFailed(name, _) if name == "refund")
+        );
+    }
+    struct Vault;
+    impl Vault {
+        fn key(ctx: &Context, k: u8) -> Clause {
+            let kp = bitcoin::KeyPair::from_seckey_slice(ctx.secp(), &[k; 32]).unwrap();
+            Clause::Key(XOnlyPublicKey::from_keypair(&kp).0)
+        }
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|_, ctx| {
+                    if ctx.funds() > Amount::ONE_BTC {
+                        Clause::Threshold(
+                            2,
+                            vec![Self::key(&ctx, 1), Self::key(&ctx, 2), Self::key(&ctx, 3)],
+                        )
+                    } else {
+                        Clause::Threshold(1, vec![Self::key(&ctx, 1), Self::key(&ctx, 2)])
+                    }
+                }),
+                None,
+            ))
+        }
+    }
+    impl Contract for Vault {
+        declare! {finish, Self::signed}
+        declare! {non updatable}
+    }
+    #[test]
+    fn guards_see_funds() {
+        let vault_ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(2 * Amount::ONE_BTC.as_sat()),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("vault").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let small = vault_ctx()
+            .with_amount(Amount::from_sat(50_000_000))
+            .unwrap();
+        assert_eq!(small.funds(), Amount::from_sat(50_000_000));
+        let big = Vault.compile(vault_ctx()).unwrap();
+        let small = Vault.compile(small).unwrap();
+        let desc = |c: &Compiled| serde_json::to_string(&c.descriptor).unwrap();
+        assert_eq!(desc(&big), desc(&Vault.compile(vault_ctx()).unwrap()));
+        assert_ne!(desc(&big), desc(&small));
+    }
+    fn payout<'a, T>(
+        fee_policy: FeePolicy,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard: &[],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new("payout".into()),
+                fee_policy,
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn pay_to_key(ctx: Context, amt: Amount) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        leaving_fees(ctx.template().add_output(amt, &key, None)?)
+    }
+    fn pay_all<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let amt = ctx.funds();
+        pay_to_key(ctx, amt)
+    }
+    /// pays all but 1000 sats, without declaring them as a fee
+    fn pay_all_but_1000<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        ctx.template().add_output(amt, &key, None)?.into()
+    }
+    macro_rules! fee_contract {
+        ($name:ident, $policy:expr, $func:ident) => {
+            struct $name;
+            impl $name {
+                fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+                    payout($policy, $func)
+                }
+            }
+            impl Contract for $name {
+                declare! {then, Self::payout}
+                declare! {non updatable}
+            }
+        };
+    }
+    fee_contract!(
+        Reserved,
+        FeePolicy::Reserve(Amount::from_sat(10_000)),
+        pay_all
+    );
+    fee_contract!(
+        OverReserved,
+        FeePolicy::Reserve(Amount::from_sat(200_000)),
+        pay_all
+    );
+    fee_contract!(
+        RateReserved,
+        FeePolicy::ReserveRate(Amount::from_sat(5)),
+        pay_all_but_1000
+    );
+    fee_contract!(
+        OverRateReserved,
+        FeePolicy::ReserveRate(Amount::from_sat(5)),
+        pay_all
+    );
+    fn only_template(compiled: &Compiled) -> &Template {
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        compiled.ctv_to_tx.values().next().unwrap()
+    }
+    #[test]
+    fn fee_reservation() {
+        let compiled = Reserved.compile(ctx()).unwrap();
+        let t = only_template(&compiled);
+        assert_eq!(t.total_amount(), Amount::from_sat(90_000));
+        assert_eq!(t.max, Amount::from_sat(100_000));
+        let err = OverReserved.compile(ctx()).unwrap_err();
+        assert!(matches!(
+            err.root_cause(),
+            CompilationError::FeeReservationExceedsFunds(r, a)
+                if *r == Amount::from_sat(200_000) && *a == Amount::from_sat(100_000)
+        ));
+    }
+    #[test]
+    fn fee_rate_reservation() {
+        // the rate only reserves part of the 1000 sats left over
+        let compiled = RateReserved
+            .compile(ctx().with_conservation_checks(false))
+            .unwrap();
+        let t = only_template(&compiled);
+        assert_eq!(t.total_amount(), Amount::from_sat(99_000));
+        let fee = Amount::from_sat(5 * t.tx.vsize() as u64);
+        assert_eq!(t.max, Amount::from_sat(99_000) + fee);
+        let err = OverRateReserved.compile(ctx()).unwrap_err();
+        assert!(matches!(
+            err.root_cause(),
+            CompilationError::FeeReservationExceedsFunds(r, a)
+                if *r == fee && *a == Amount::from_sat(0)
+        ));
+    }
+    fn pay_anchored<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let amt = ctx.funds() - Amount::from_sat(DEFAULT_ANCHOR_SATS);
+        ctx.template()
+            .add_output(amt, &key, None)?
+            .add_anchor(AnchorTo::Key(key), None)?
+            .into()
+    }
+    fn fan_out<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat((ctx.funds().as_sat() - DEFAULT_ANCHOR_SATS) / 2);
+        ctx.template()
+            .add_output(half, &AnchoredLeaf, None)?
+            .add_output(half, &AnchoredLeaf, None)?
+            .add_anchor(AnchorTo::PayToAnchor, None)?
+            .into()
+    }
+    fee_contract!(AnchoredLeaf, FeePolicy::None, pay_anchored);
+    fee_contract!(AnchoredTree, FeePolicy::None, fan_out);
+    fee_contract!(Unbumpable, FeePolicy::None, pay_all);
+    #[test]
+    fn anchored_tree() {
+        // counts the committed templates, checking each has one anchor
+        fn check(compiled: &Compiled) -> usize {
+            assert!(compiled.warnings.is_empty());
+            compiled
+                .ctv_to_tx
+                .values()
+                .map(|t| {
+                    let anchors = t.outputs.iter().filter(|o| o.added_metadata.is_anchor());
+                    assert_eq!(anchors.count(), 1);
+                    1 + t.outputs.iter().map(|o| check(&o.contract)).sum::<usize>()
+                })
+                .sum()
+        }
+        assert_eq!(check(&AnchoredTree.compile(ctx()).unwrap()), 3);
+
+        let unbumpable = Unbumpable.compile(ctx()).unwrap();
+        assert_eq!(
+            unbumpable.warnings,
+            vec![format!(
+                "committed template {} reserves no fees and has no anchor output",
+                only_template(&unbumpable).hash()
+            )]
+        );
+        assert!(Reserved.compile(ctx()).unwrap().warnings.is_empty());
+
+        let twice = ctx()
+            .template()
+            .add_anchor(AnchorTo::PayToAnchor, None)
+            .unwrap()
+            .add_anchor(AnchorTo::PayToAnchor, None);
+        assert!(matches!(twice, Err(CompilationError::OverwriteMetadata(_))));
+    }
+    #[test]
+    fn change_output() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let rate = Amount::from_sat(2);
+        let paying = |ctx: Context, sats: u64| {
+            ctx.template()
+                .add_output(Amount::from_sat(sats), &key, None)
+                .unwrap()
+        };
+        let rated = || ctx().with_feerate(Some(rate));
+        // the fee covers the change output whether or not the rest of
+        // the template's fee was added first
+        let auto: Template = paying(rated(), 50_000)
+            .add_change(&Unbumpable, Amount::from_sat(1_000))
+            .unwrap()
+            .into();
+        let first: Template = paying(rated(), 50_000)
+            .add_fee_from_rate()
+            .unwrap()
+            .add_change(&Unbumpable, Amount::from_sat(1_000))
+            .unwrap()
+            .into();
+        for t in [&auto, &first] {
+            assert_eq!(t.outputs.len(), 2);
+            assert_eq!(t.max, Amount::from_sat(100_000));
+            assert_eq!(t.max - t.total_amount(), rate * t.tx.vsize() as u64);
+        }
+        assert_eq!(auto.hash(), first.hash());
+
+        let exact: Template = paying(ctx(), 100_000)
+            .add_change(&Unbumpable, Amount::from_sat(0))
+            .unwrap()
+            .into();
+        assert_eq!(exact.outputs.len(), 1);
+        // change below the dust limit is left to fees
+        let dust = paying(ctx(), 99_800)
+            .add_change(&Unbumpable, Amount::from_sat(0))
+            .unwrap()
+            .finalize()
+            .unwrap();
+        assert_eq!(dust.outputs.len(), 1);
+        assert_eq!(dust.max - dust.total_amount(), Amount::from_sat(200));
+
+        match paying(ctx(), 99_500).add_change(&Unbumpable, Amount::from_sat(1_000)) {
+            Err(CompilationError::ChangeBelowMinimum(remaining, min)) => {
+                assert_eq!(remaining, Amount::from_sat(500));
+                assert_eq!(min, Amount::from_sat(1_000));
+            }
+            _ => panic!("change should be below the minimum"),
+        }
+    }
+    fn burn_1000<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        ctx.template()
+            .add_output(amt, &key, None)?
+            .explicitly_burn(Amount::from_sat(1000))?
+            .into()
+    }
+    fee_contract!(Leaky, FeePolicy::None, pay_all_but_1000);
+    fee_contract!(Burning, FeePolicy::None, burn_1000);
+    /// a continuation which, on a sale, leaks 1000 sats like `Leaky`
+    struct LeakySale;
+    impl LeakySale {
+        fn sell<'a>() -> Option<Box<dyn CallableAsFoF<Self, Sale> + 'a>> {
+            Some(Box::new(FinishOrFunc::<_, _, _, WebAPIEnabled> {
+                simp_gen: None,
+                coerce_args: Ok,
+                guard: &[GuardGen::Fn(signed)],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                conditional_compile_if_args: &[],
+                func: |s, ctx, sale| match sale {
+                    Sale::Hold => empty(),
+                    Sale::MakeSale => pay_all_but_1000(s, ctx, ThenFuncTypeTag(())),
+                },
+                schema: None,
+                returned_template_schema: None,
+                name: Arc::new("sell".into()),
+                f: Default::default(),
+                returned_txtmpls_modify_guards: false,
+                extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+                fee_policy: FeePolicy::None,
+                weight: None,
+                display_order: None,
+                hidden: false,
+                description: None,
+            }))
+        }
+    }
+    impl Contract for LeakySale {
+        declare! {updatable<Sale>, Self::sell}
+    }
+    #[test]
+    fn conservation() {
+        let unchecked = || ctx().with_conservation_checks(false);
+        assert!(Leaky.compile(unchecked()).is_ok());
+        let err = Leaky.compile(ctx()).unwrap_err();
+        assert!(matches!(
+            err.root_cause(),
+            CompilationError::UnaccountedFunds { missing, .. }
+                if *missing == SignedAmount::from_sat(1000)
+        ));
+        let burning = Burning.compile(ctx()).unwrap();
+        assert_eq!(only_template(&burning).max, Amount::from_sat(100_000));
+        assert!(Reserved.compile(ctx()).is_ok());
+
+        // continuations are checked too, once an effect makes a template
+        let points = LeakySale.compile(ctx()).unwrap().continuation_points();
+        let effect: BTreeMap<_, _> =
+            std::iter::once((SArc(Arc::new("buy".into())), serde_json::json!("MakeSale")))
+                .collect();
+        let path = SArc(points[0].point.path.clone());
+        let selling = || {
+            let effects = sapio_base::effects::EditableMapEffectDB {
+                effects: std::iter::once((path.clone(), effect.clone())).collect(),
+                empty: Default::default(),
+            };
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(effects.into()),
+            )
+        };
+        let sold = LeakySale
+            .compile(selling().with_conservation_checks(false))
+            .unwrap();
+        assert_eq!(sold.suggested_txs.len(), 1);
+        let err = LeakySale.compile(selling()).unwrap_err();
+        assert!(matches!(
+            err.root_cause(),
+            CompilationError::UnaccountedFunds { missing, .. }
+                if *missing == SignedAmount::from_sat(1000)
+        ));
+    }
+    fn after_height<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| Clause::After(700_000)),
+            None,
+        ))
+    }
+    fn after_time<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| Clause::After(1_600_000_000)),
+            None,
+        ))
+    }
+    fn relative<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(GuardFn::Fn(|_, _| Clause::Older(144)), None))
+    }
+    fn locked<'a, T>(
+        name: &str,
+        guard: GuardList<'a, T>,
+        func: fn(&T, Context, ThenFuncTypeTag) -> TxTmplIt,
+    ) -> Option<ThenFuncAsFinishOrFunc<'a, T, ()>> {
+        Some(
+            ThenFunc {
+                guard,
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func,
+                name: Arc::new(name.into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+    fn pay_locked(ctx: Context, lock: AnyAbsTimeLock) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .set_lock_time(lock)?
+            .add_output(amt, &key, None)?
+            .into()
+    }
+    fn by_height<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        pay_locked(ctx, AbsHeight::try_from(700_000).unwrap().into())
+    }
+    fn by_time<T>(_: &T, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        pay_locked(ctx, AbsTime::try_from(1_600_000_000).unwrap().into())
+    }
+    struct HeightGuardTimeLock;
+    impl HeightGuardTimeLock {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("spend", &[GuardGen::Fn(after_height)], by_time)
+        }
+    }
+    impl Contract for HeightGuardTimeLock {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+    }
+    struct EitherLock;
+    impl EitherLock {
+        fn height<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("height", &[GuardGen::Fn(after_height)], by_height)
+        }
+        fn time<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("time", &[GuardGen::Fn(after_time)], by_time)
+        }
+    }
+    impl Contract for EitherLock {
+        declare! {then, Self::height, Self::time}
+        declare! {non updatable}
+    }
+    struct RelativeAndAbsolute;
+    impl RelativeAndAbsolute {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "spend",
+                &[GuardGen::Fn(after_height), GuardGen::Fn(relative)],
+                by_height,
+            )
+        }
+    }
+    impl Contract for RelativeAndAbsolute {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+    }
+    #[test]
+    fn time_lock_mixing() {
+        let err = HeightGuardTimeLock.compile(ctx()).unwrap_err();
+        assert!(err.to_string().contains("branch `spend` failed"));
+        match err.root_cause() {
+            CompilationError::IncompatibleTimeLocks { path, height, time } => {
+                assert!(String::from(path.clone()).contains("spend"));
+                assert_eq!(height.get(), 700_000);
+                assert_eq!(time.get(), 1_600_000_000);
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        // also caught for a template's own guards
+        let own = ctx()
+            .template()
+            .add_guard(Clause::After(700_000))
+            .set_lock_time(AbsTime::try_from(1_600_000_000).unwrap().into())
+            .unwrap()
+            .finalize();
+        assert!(matches!(
+            own,
+            Err(CompilationError::IncompatibleTimeLocks { .. })
+        ));
+        // and for relative locks in blocks and seconds on the same input
+        let relative = ctx()
+            .template()
+            .add_guard(Clause::And(vec![
+                Clause::Older(144),
+                Clause::Older(4_194_305),
+            ]))
+            .finalize();
+        match relative {
+            Err(CompilationError::IncompatibleRelativeTimeLocks { height, time, .. }) => {
+                assert_eq!(height.units(), 144);
+                assert_eq!(time.units(), 1);
+            }
+            e => panic!("unexpected {:?}", e.map(|_| ())),
+        }
+        // different branches may use different kinds of lock
+        assert_eq!(EitherLock.compile(ctx()).unwrap().ctv_to_tx.len(), 2);
+        RelativeAndAbsolute.compile(ctx()).unwrap();
+    }
+    struct Escapable;
+    impl Escapable {
+        fn testnets() -> Option<ConditionallyCompileIf<Self>> {
+            Some(ConditionallyCompileIf::Fresh(|_, ctx| {
+                ConditionalCompileType::on_networks(&ctx, &[Network::Regtest, Network::Signet])
+            }))
+        }
+        fn escape<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("escape", &[Self::testnets])
+        }
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("spend", &[])
+        }
+    }
+    impl Contract for Escapable {
+        declare! {then, Self::escape, Self::spend}
+        declare! {non updatable}
+    }
+    #[test]
+    fn branch_only_on_networks() {
+        let mainnet = Context::new(
+            Network::Bitcoin,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("compiler").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        assert_eq!(mainnet.network(), Network::Bitcoin);
+        let on_mainnet = Escapable.compile(mainnet).unwrap();
+        assert_eq!(
+            on_mainnet.branches.keys().collect::<Vec<_>>(),
+            vec!["spend"]
+        );
+        let on_regtest = Escapable.compile(ctx()).unwrap();
+        assert_eq!(
+            on_regtest.branches.keys().collect::<Vec<_>>(),
+            vec!["escape", "spend"]
+        );
+    }
+    struct Refundable;
+    impl Refundable {
+        fn unexpired() -> Option<ConditionallyCompileIf<Self>> {
+            Some(ConditionallyCompileIf::Fresh(|_, ctx| {
+                ConditionalCompileType::skip_if_expired(&ctx, AbsHeight::try_from(700_000).unwrap())
+            }))
+        }
+        fn refund<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("refund", &[Self::unexpired])
+        }
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("spend", &[])
+        }
+    }
+    impl Contract for Refundable {
+        declare! {then, Self::refund, Self::spend}
+        declare! {non updatable}
+    }
+    #[test]
+    fn expired_branches_are_skipped() {
+        let at =
+            |height: u32| ctx().with_chain_tip(Some(AbsHeight::try_from(height).unwrap()), None);
+        let before = Refundable.compile(at(699_999)).unwrap();
+        assert_eq!(
+            before.branches.keys().collect::<Vec<_>>(),
+            vec!["refund", "spend"]
+        );
+        let after = Refundable.compile(at(700_000)).unwrap();
+        assert_eq!(after.branches.keys().collect::<Vec<_>>(), vec!["spend"]);
+        let unknown = Refundable.compile(ctx()).unwrap();
+        assert_eq!(
+            unknown.branches.keys().collect::<Vec<_>>(),
+            vec!["refund", "spend"]
+        );
+        assert_eq!(
+            unknown.warnings,
+            vec![
+                "branch `refund`: could not check expiry at 700000: `tip_height` was not supplied to the Context"
+            ]
+        );
+        assert!(matches!(
+            ctx().median_time(),
+            Err(CompilationError::MissingChainTip("median_time"))
+        ));
+    }
+    fn pay_with(
+        ctx: &mut Context,
+        amt: u64,
+        commitment: Commitment,
+    ) -> Result<Template, CompilationError> {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let builder = ctx
+            .derive_num(amt)?
+            .template()
+            .add_output(Amount::from_sat(amt), &key, None)?
+            .set_commitment(commitment);
+        let rest = builder.ctx().funds();
+        builder.add_fees(rest)?.finalize()
+    }
+    struct Advised;
+    impl Advised {
+        fn pay<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, |_, mut ctx, _| {
+                let txtmpls = vec![
+                    pay_with(&mut ctx, 1000, Commitment::Committed),
+                    pay_with(&mut ctx, 2000, Commitment::Suggested),
+                    pay_with(&mut ctx, 3000, Commitment::Suggested),
+                ];
+                Ok(Box::new(txtmpls.into_iter()))
+            })
+        }
+    }
+    impl Contract for Advised {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+    #[test]
+    fn suggested_templates_are_not_committed() {
+        let compiled = Advised.compile(ctx()).unwrap();
+        let committed = only_template(&compiled);
+        assert_eq!(committed.total_amount(), Amount::from_sat(1000));
+        assert_eq!(compiled.suggested_txs.len(), 2);
+        assert!(compiled
+            .suggested_txs
+            .values()
+            .all(|t| t.commitment == Commitment::Suggested));
+        let json = serde_json::to_value(&compiled).unwrap();
+        assert_eq!(
+            json["template_hash_to_template_map"]
+                .as_object()
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(
+            json["suggested_template_hash_to_template_map"]
+                .as_object()
+                .unwrap()
+                .len(),
+            2
+        );
+        // only the committed template is a CTV branch
+        let branch_hashes = &compiled.branches["payout"];
+        assert_eq!(branch_hashes.len(), 3);
+        let desc = serde_json::to_string(&compiled.descriptor).unwrap();
+        assert!(desc.contains(&committed.hash().to_string()));
+        for h in compiled.suggested_txs.keys() {
+            assert!(!desc.contains(&h.to_string()));
+        }
+    }
+    fn signed_after_height<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| {
+                Clause::And(vec![
+                    Clause::After(700_000),
+                    Clause::Key(
+                        "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                            .parse()
+                            .unwrap(),
+                    ),
+                ])
+            }),
+            None,
+        ))
+    }
+    struct Redundant;
+    impl Redundant {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "spend",
+                &[
+                    GuardGen::Fn(after_height),
+                    GuardGen::Fn(signed_after_height),
+                ],
+                pay_all,
+            )
+        }
+    }
+    impl Contract for Redundant {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+    }
+    #[test]
+    fn clause_simplification() {
+        let script_bytes = |compiled: &Compiled| match compiled.descriptor.as_ref() {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr
+                .iter_scripts()
+                .map(|(_, ms)| ms.encode().len())
+                .sum::<usize>(),
+            d => panic!("unexpected descriptor {:?}", d),
+        };
+        let plain = Redundant.compile(ctx()).unwrap();
+        let simplified = Redundant
+            .compile(ctx().with_clause_simplification(true))
+            .unwrap();
+        assert_eq!(
+            only_template(&plain).hash(),
+            only_template(&simplified).hash()
+        );
+        // the duplicate `<700000> OP_CLTV OP_VERIFY` is dropped
+        assert_eq!(script_bytes(&plain) - script_bytes(&simplified), 6);
+    }
+    struct Holder;
+    impl Holder {
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|_, _| {
+                    Clause::Key(
+                        "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                            .parse()
+                            .unwrap(),
+                    )
+                }),
+                None,
+            ))
+        }
+        fn lock<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, ctx, _| {
+                        let amt = ctx.funds();
+                        ctx.template().add_output(amt, &Previewed, None)?.into()
+                    },
+                    name: Arc::new("lock".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+        fn sweep() -> Option<Box<dyn CallableAsFoF<Self, ()>>> {
+            Some(Box::new(FinishOrFunc::<_, _, _, WebAPIDisabled> {
+                simp_gen: None,
+                coerce_args: Ok,
+                guard: &[GuardGen::Fn(Self::signed)],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                conditional_compile_if_args: &[],
+                func: |_, ctx, _| {
+                    let amt = ctx.funds();
+                    pay_to_key(ctx, amt)
+                },
+                schema: None,
+                returned_template_schema: None,
+                name: Arc::new("sweep".into()),
+                f: Default::default(),
+                returned_txtmpls_modify_guards: false,
+                extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+                fee_policy: FeePolicy::None,
+                weight: None,
+                display_order: None,
+                hidden: false,
+                description: None,
+            }))
+        }
+    }
+    impl Contract for Holder {
+        declare! {then, Self::lock}
+        declare! {updatable<()>, Self::sweep}
+    }
+    #[test]
+    fn satisfaction_weights_per_branch() {
+        let compiled = Holder.compile(ctx()).unwrap();
+        assert_eq!(
+            compiled.satisfaction_weights["sweep"],
+            SatisfactionWeights { min: 65, max: 66 }
+        );
+        // the template hash is checked by the script, not the witness
+        assert_eq!(
+            compiled.satisfaction_weights["lock"],
+            SatisfactionWeights { min: 0, max: 0 }
+        );
+    }
+    #[test]
+    fn continuation_points_are_enumerated() {
+        let compiled = Holder.compile(ctx()).unwrap();
+        let points = compiled.continuation_points();
+        let names: Vec<_> = points.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["compiler/sweep", "compiler/lock/preview"]);
+        let key = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+        for point in points.iter() {
+            assert!(point.point.guards.as_ref().unwrap().contains(key));
+        }
+        assert_eq!(points[0].point.default_yields_templates, Some(true));
+        assert_eq!(points[1].point.default_yields_templates, Some(false));
+        let json = serde_json::to_value(&points).unwrap();
+        assert_eq!(json[1]["name"], serde_json::json!("compiler/lock/preview"));
+        assert_eq!(
+            json[1]["default_yields_templates"],
+            serde_json::json!(false)
+        );
+        let nested = serde_json::to_value(&compiled).unwrap();
+        assert!(nested
+            .to_string()
+            .contains("\"default_yields_templates\":false"));
+    }
+    fn signed<T>() -> Option<Guard<T>> {
+        Some(Guard::Fresh(
+            GuardFn::Fn(|_, _| {
+                Clause::Key(
+                    "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                        .parse()
+                        .unwrap(),
+                )
+            }),
+            None,
+        ))
+    }
+    fn continuation<'a, T>(
+        name: &str,
+        guard: GuardList<'a, T>,
+    ) -> Option<Box<dyn CallableAsFoF<T, ()> + 'a>> {
+        Some(Box::new(FinishOrFunc::<_, _, _, WebAPIDisabled> {
+            simp_gen: None,
+            coerce_args: Ok,
+            guard,
+            guard_combinator: Default::default(),
+            conditional_compile_if: &[],
+            conditional_compile_if_args: &[],
+            func: |_, _, _| empty(),
+            schema: None,
+            returned_template_schema: None,
+            name: Arc::new(name.into()),
+            f: Default::default(),
+            returned_txtmpls_modify_guards: false,
+            extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+            fee_policy: FeePolicy::None,
+            weight: None,
+            display_order: None,
+            hidden: false,
+            description: None,
+        }))
+    }
+    struct Listed;
+    impl Listed {
+        fn sell<'a>() -> Option<Box<dyn CallableAsFoF<Self, ()> + 'a>> {
+            continuation("sell", &[GuardGen::Fn(signed)])
+        }
+        fn resell<'a>() -> Option<Box<dyn CallableAsFoF<Self, ()> + 'a>> {
+            continuation("sell", &[GuardGen::Fn(signed)])
+        }
+    }
+    impl Contract for Listed {
+        declare! {updatable<()>, Self::sell, Self::resell}
+    }
+    struct Relisted;
+    impl Relisted {
+        fn preview<'a>() -> Option<Box<dyn CallableAsFoF<Self, ()> + 'a>> {
+            continuation("preview", &[GuardGen::Fn(signed)])
+        }
+        fn lock<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, ctx, _| {
+                        let amt = ctx.funds();
+                        ctx.template().add_output(amt, &Previewed, None)?.into()
+                    },
+                    name: Arc::new("lock".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Relisted {
+        declare! {then, Self::lock}
+        declare! {updatable<()>, Self::preview}
+    }
+    struct Refunded;
+    impl Refunded {
+        fn refund<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("refund", &[])
+        }
+        fn refund_again<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            branch("refund", &[])
+        }
+    }
+    impl Contract for Refunded {
+        declare! {then, Self::refund, Self::refund_again}
+        declare! {non updatable}
+    }
+    #[test]
+    fn duplicate_function_names() {
+        match Listed
+            .compile(ctx())
+            .as_ref()
+            .map_err(CompilationError::root_cause)
+        {
+            Err(CompilationError::DuplicateFunctionName(name)) => assert_eq!(name, "sell"),
+            r => panic!("expected a duplicate name, got {:?}", r.map(|_| ())),
+        }
+        match Refunded
+            .compile(ctx())
+            .as_ref()
+            .map_err(CompilationError::root_cause)
+        {
+            Err(CompilationError::DuplicateFunctionName(name)) => assert_eq!(name, "refund"),
+            r => panic!("expected a duplicate name, got {:?}", r.map(|_| ())),
+        }
+        // the same local name is fine at different paths
+        let compiled = Relisted.compile(ctx()).unwrap();
+        let names: Vec<_> = compiled
+            .continuation_points()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(names, vec!["compiler/preview", "compiler/lock/preview"]);
+    }
+    #[test]
+    fn addresses_must_match_network() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let address_on = |network| {
+            let address = bitcoin::Address::p2tr_tweaked(
+                TweakedPublicKey::dangerous_assume_tweaked(key),
+                network,
+            );
+            // users supply addresses as strings
+            let parsed: bitcoin::Address = address.to_string().parse().unwrap();
+            (address.to_string(), Compiled::from_address(parsed, None))
+        };
+        for (network, other) in [
+            (Network::Bitcoin, Network::Regtest),
+            (Network::Signet, Network::Bitcoin),
+            (Network::Regtest, Network::Signet),
+        ] {
+            let mut ctx = Context::new(
+                network,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            );
+            let mut sub = ctx.derive_num(0u64).unwrap();
+            assert_eq!(sub.network(), network);
+            let ok = sub.derive_num(0u64).unwrap().template().add_output(
+                Amount::from_sat(1000),
+                &address_on(network).1,
+                None,
+            );
+            assert!(ok.is_ok());
+            let (other_address, wrong_address) = address_on(other);
+            let wrong = sub.derive_num(1u64).unwrap().template().add_output(
+                Amount::from_sat(1000),
+                &wrong_address,
+                None,
+            );
+            match wrong.as_ref().map_err(CompilationError::root_cause) {
+                Err(CompilationError::WrongNetwork(n, what)) => {
+                    assert_eq!(*n, network);
+                    assert_eq!(*what, format!("address `{}`", other_address));
+                }
+                _ => panic!("address for {} should be rejected on {}", other, network),
+            }
+        }
+    }
+    struct Leaf;
+    impl Leaf {
+        fn labeled<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, mut ctx, _| {
+                        if ctx.label() != "root/branch_a/leaf" {
+                            return Err(CompilationError::Custom(ctx.label().into()));
+                        }
+                        let cached = ctx.derive_labeled("cache")?;
+                        if cached.label() != "root/branch_a/leaf/cache" {
+                            return Err(CompilationError::Custom(cached.label().into()));
+                        }
+                        leaving_fees(ctx.template())
+                    },
+                    name: Arc::new("leaf".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Leaf {
+        declare! {then, Self::labeled}
+        declare! {non updatable}
+    }
+    struct Branching;
+    impl Branching {
+        fn branch_a<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, ctx, _| {
+                        let amt = ctx.funds();
+                        ctx.template().add_output(amt, &Leaf, None)?.into()
+                    },
+                    name: Arc::new("branch_a".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Branching {
+        declare! {then, Self::branch_a}
+        declare! {non updatable}
+    }
+    #[test]
+    fn leaf_knows_its_path() {
+        let root = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::from(PathFragment::Root),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let compiled = Branching.compile(root()).unwrap();
+        let leaf = &only_template(&compiled).outputs[0].contract;
+        assert_eq!(leaf.root_path.0.label(), "root/branch_a");
+        assert_eq!(compiled.metadata.path.as_deref(), Some("root"));
+        assert_eq!(leaf.metadata.path.as_deref(), Some("root/branch_a"));
+        // paths are stable across recompilation
+        let again = Branching.compile(root()).unwrap();
+        assert_eq!(
+            serde_json::to_value(&compiled).unwrap(),
+            serde_json::to_value(&again).unwrap()
+        );
+    }
+    struct Shuffled;
+    impl Shuffled {
+        fn pay<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, ctx, _| {
+                        let e = ctx.derive_entropy("amount");
+                        let amt = 1000 + u16::from_le_bytes([e[0], e[1]]) as u64;
+                        pay_to_key(ctx, Amount::from_sat(amt))
+                    },
+                    name: Arc::new("pay".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Shuffled {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+    #[test]
+    fn entropy_is_deterministic() {
+        let seeded = |seed: &[u8]| {
+            let seed = <bitcoin::hashes::sha256::Hash as bitcoin::hashes::Hash>::hash(seed);
+            Shuffled
+                .compile(ctx().with_entropy_seed(Some(seed)))
+                .unwrap()
+        };
+        let a = seeded(b"a");
+        let again = seeded(b"a");
+        assert_eq!(only_template(&a).tx.output, only_template(&again).tx.output);
+        let b = seeded(b"b");
+        assert_ne!(only_template(&a).tx.output, only_template(&b).tx.output);
+    }
+    #[derive(serde::Deserialize, Default)]
+    enum Sale {
+        #[default]
+        Hold,
+        MakeSale,
+    }
+    impl crate::contract::StatefulArgumentsTrait for Sale {}
+    fn sale<'a, T>(guard: GuardList<'a, T>) -> Option<Box<dyn CallableAsFoF<T, Sale> + 'a>> {
+        Some(Box::new(FinishOrFunc::<_, _, Sale, WebAPIEnabled> {
+            simp_gen: None,
+            coerce_args: Ok,
+            guard,
+            guard_combinator: Default::default(),
+            conditional_compile_if: &[],
+            conditional_compile_if_args: &[],
+            func: |_, ctx, s| match s {
+                Sale::Hold => empty(),
+                Sale::MakeSale => {
+                    let amt = ctx.funds();
+                    pay_to_key(ctx, amt)
+                }
+            },
+            schema: None,
+            returned_template_schema: None,
+            name: Arc::new("sell".into()),
+            f: Default::default(),
+            returned_txtmpls_modify_guards: false,
+            extract_clause_from_txtmpl: default_extract_clause_from_txtmpl,
+            fee_policy: FeePolicy::None,
+            weight: None,
+            display_order: None,
+            hidden: false,
+            description: None,
+        }))
+    }
+    struct Minted;
+    impl Minted {
+        fn sell<'a>() -> Option<Box<dyn CallableAsFoF<Self, Sale> + 'a>> {
+            sale(&[GuardGen::Fn(signed)])
+        }
+    }
+    impl Contract for Minted {
+        declare! {updatable<Sale>, Self::sell}
+    }
+    struct Minter;
+    impl Minter {
+        fn mint<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, Sale>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |_, ctx, _| {
+                        let amt = ctx.funds();
+                        ctx.template().add_output(amt, &Minted, None)?.into()
+                    },
+                    name: Arc::new("mint".into()),
+                    fee_policy: FeePolicy::Reserve(Amount::from_sat(1000)),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+        fn sell<'a>() -> Option<Box<dyn CallableAsFoF<Self, Sale> + 'a>> {
+            sale(&[GuardGen::Fn(signed)])
+        }
+    }
+    impl Contract for Minter {
+        declare! {then, Self::mint}
+        declare! {updatable<Sale>, Self::sell}
+    }
+    #[test]
+    fn effects_reach_nested_contracts() {
+        let points = Minter.compile(ctx()).unwrap().continuation_points();
+        assert_eq!(points[1].name, "compiler/mint/sell");
+        let child_sell = SArc(points[1].point.path.clone());
+        let nowhere = SArc(Arc::new(EffectPath::try_from("compiler/nowhere").unwrap()));
+        let effect: BTreeMap<_, _> =
+            std::iter::once((SArc(Arc::new("buy".into())), serde_json::json!("MakeSale")))
+                .collect();
+        let effects = sapio_base::effects::EditableMapEffectDB {
+            effects: vec![(child_sell, effect.clone()), (nowhere, effect)]
+                .into_iter()
+                .collect(),
+            empty: Default::default(),
+        };
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("compiler").unwrap(),
+            Arc::new(effects.into()),
+        );
+        let compiled = Minter.compile(ctx).unwrap();
+        assert!(compiled.suggested_txs.is_empty());
+        let child = &only_template(&compiled).outputs[0].contract;
+        assert_eq!(child.suggested_txs.len(), 1);
+        assert!(child.warnings.is_empty());
+        assert_eq!(
+            compiled.warnings,
+            vec!["effect at `compiler/nowhere` matches no continuation".to_string()]
+        );
+    }
+    fn warn_and_pay_all(_: &Cautious, ctx: Context, t: ThenFuncTypeTag) -> TxTmplIt {
+        ctx.warn("cautious", "from the child");
+        pay_all(&Cautious, ctx, t)
+    }
+    fee_contract!(
+        Cautious,
+        FeePolicy::Reserve(Amount::from_sat(1000)),
+        warn_and_pay_all
+    );
+    fn warn_and_nest(_: &Careless, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        ctx.warn("careless", "from the parent");
+        let amt = ctx.funds();
+        ctx.template().add_output(amt, &Cautious, None)?.into()
+    }
+    fee_contract!(
+        Careless,
+        FeePolicy::Reserve(Amount::from_sat(1000)),
+        warn_and_nest
+    );
+    #[test]
+    fn diagnostics_stay_with_their_contract() {
+        let compiled = Careless.compile(ctx()).unwrap();
+        let child = &only_template(&compiled).outputs[0].contract;
+        let codes = |ds: &[Diagnostic]| -> Vec<(DiagnosticLevel, String, String)> {
+            ds.iter()
+                .map(|d| (d.level, d.code.clone(), String::from(d.path.clone())))
+                .collect()
+        };
+        assert_eq!(
+            codes(&child.diagnostics),
+            vec![(
+                DiagnosticLevel::Warning,
+                "cautious".into(),
+                "compiler/@action/payout/@next/@default_effect/#0/@action/payout/@next/@default_effect"
+                    .into()
+            )]
+        );
+        assert_eq!(
+            codes(&compiled.diagnostics),
+            vec![
+                (
+                    DiagnosticLevel::Warning,
+                    "careless".into(),
+                    "compiler/@action/payout/@next/@default_effect".into()
+                ),
+                (
+                    DiagnosticLevel::Note,
+                    "no_feerate".into(),
+                    "compiler".into()
+                ),
+            ]
+        );
+        let all = compiled.all_diagnostics();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2], child.diagnostics[0]);
+        assert_eq!(
+            all[2].to_string(),
+            format!(
+                "warning[cautious] at `{}`: from the child",
+                String::from(all[2].path.clone())
+            )
+        );
+    }
+    #[test]
+    fn deny_warnings() {
+        // notes are allowed
+        Reserved.compile(ctx()).unwrap().deny_warnings().unwrap();
+        match Careless.compile(ctx()).unwrap().deny_warnings() {
+            Err(CompilationError::DeniedWarnings(denied)) => assert_eq!(
+                denied.iter().map(|d| d.code.as_str()).collect::<Vec<_>>(),
+                vec!["careless", "cautious"]
+            ),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+    #[test]
+    fn clause_compilation_errors_name_the_clause() {
+        let err =
+            compile_clause(Clause::And(vec![Clause::After(10), Clause::Older(5)])).unwrap_err();
+        assert_eq!(
+            CompilationError::from(err).to_string(),
+            format!(
+                "could not compile `and(after(10),older(5))`: {}",
+                policy::compiler::CompilerError::TopLevelNonSafe
+            )
+        );
+    }
+    /// pays to G with the `hash160` preimage, or to 2G after a timeout
+    struct Htlc {
+        hash: bitcoin::hashes::hash160::Hash,
+    }
+    impl Htlc {
+        fn redeem<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "redeem",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|s: &Htlc, _| {
+                            Clause::And(vec![
+                                Clause::Key(
+                                    "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                                        .parse()
+                                        .unwrap(),
+                                ),
+                                Clause::Hash160(s.hash),
+                            ])
+                        }),
+                        None,
+                    ))
+                })],
+                pay_all,
+            )
+        }
+        fn refund<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "refund",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| {
+                            Clause::And(vec![
+                                Clause::Key(
+                                    "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5"
+                                        .parse()
+                                        .unwrap(),
+                                ),
+                                Clause::After(700_000),
+                            ])
+                        }),
+                        None,
+                    ))
+                })],
+                pay_all,
+            )
+        }
+    }
+    impl Contract for Htlc {
+        declare! {then, Self::redeem, Self::refund}
+        declare! {non updatable}
+    }
+    #[test]
+    fn hash160_and_ripemd160_preimages() {
+        use bitcoin::blockdata::opcodes::all::{OP_HASH160, OP_RIPEMD160};
+        use bitcoin::blockdata::script::Instruction;
+        use bitcoin::hashes::{hash160, ripemd160, Hash};
+        let has_op = |script: &bitcoin::Script, op| {
+            script
+                .instructions()
+                .any(|i| matches!(i, Ok(Instruction::Op(o)) if o == op))
+        };
+        let hash = hash160::Hash::hash(&[1; 32]);
+        let compiled = Htlc { hash }.compile(ctx()).unwrap();
+        let scripts: Vec<_> = match compiled.descriptor.as_ref() {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => {
+                tr.iter_scripts().map(|(_, ms)| ms.encode()).collect()
+            }
+            d => panic!("unexpected descriptor {:?}", d),
+        };
+        assert_eq!(scripts.len(), 2);
+        assert_eq!(scripts.iter().filter(|s| has_op(s, OP_HASH160)).count(), 1);
+        let json = serde_json::to_string(&compiled.descriptor).unwrap();
+        assert!(json.contains(&format!("hash160({})", hash)));
+
+        let guard = Clause::And(vec![
+            Clause::Key(
+                "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                    .parse()
+                    .unwrap(),
+            ),
+            Clause::Ripemd160(ripemd160::Hash::hash(&[2; 32])),
+        ]);
+        assert!(has_op(
+            &compile_clause(guard).unwrap().encode(),
+            OP_RIPEMD160
+        ));
+    }
+    fn musig_keys() -> [XOnlyPublicKey; 2] {
+        [
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap(),
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5"
+                .parse()
+                .unwrap(),
+        ]
+    }
+    struct Joint;
+    impl Joint {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "spend",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| Clause::musig(&musig_keys()).unwrap()),
+                        Some(|_, _| Ok(vec![Arc::new(MuSigKeySet::new(&musig_keys()).unwrap())])),
+                    ))
+                })],
+                pay_all,
+            )
+        }
+    }
+    impl Contract for Joint {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+    }
+    #[test]
+    fn musig_key_sets_are_recorded() {
+        let compiled = Joint.compile(ctx()).unwrap();
+        let set = MuSigKeySet::new(&musig_keys()).unwrap();
+        let recorded = &compiled.metadata.simps_for_guards[&set.clause()]
+            [&MuSigKeySet::static_get_protocol_number()];
+        assert_eq!(recorded, &vec![set.to_json().unwrap()]);
+    }
+    /// needs at least 200_000 sats
+    struct Greedy;
+    impl Greedy {
+        fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, pay_all)
+        }
+    }
+    impl Contract for Greedy {
+        declare! {then, Self::payout}
+        declare! {non updatable}
+        fn ensure_amount(&self, _ctx: Context) -> Result<Amount, CompilationError> {
+            Ok(Amount::from_sat(200_000))
+        }
+    }
+    /// funds a `Greedy` with all of its funds
+    struct FundsGreedy;
+    impl FundsGreedy {
+        fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, |_, ctx, _| {
+                let amount = ctx.funds();
+                ctx.template().add_output(amount, &Greedy, None)?.into()
+            })
+        }
+    }
+    impl Contract for FundsGreedy {
+        declare! {then, Self::payout}
+        declare! {non updatable}
+    }
+    #[test]
+    fn nested_amount_ranges() {
+        let with_funds = |sats| {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(sats),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let greedy = Greedy.compile(with_funds(300_000)).unwrap();
+        assert_eq!(
+            greedy.amount_range,
+            AmountRange::between(Amount::from_sat(200_000), Amount::from_sat(300_000))
+        );
+        // the ensured amount doesn't replace a smaller template amount
+        let greedy = Greedy.compile(with_funds(150_000)).unwrap();
+        assert_eq!(
+            greedy.amount_range,
+            AmountRange::between(Amount::from_sat(150_000), Amount::from_sat(200_000))
+        );
+        // funding is enough if it covers what the child ensures
+        assert!(FundsGreedy.compile(with_funds(200_000)).is_ok());
+        let err = FundsGreedy.compile(ctx()).unwrap_err();
+        match err.root_cause() {
+            CompilationError::IncompatibleAmountRange {
+                accepted, funded, ..
+            } => {
+                assert_eq!(accepted.min_bound(), Some(Amount::from_sat(200_000)));
+                assert_eq!(funded.max_bound(), Some(Amount::from_sat(100_000)));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+    /// a `k` of `keys` multisig, which may insist on segwit v0
+    struct Multisig {
+        k: usize,
+        keys: Vec<XOnlyPublicKey>,
+        segwit_v0: bool,
+    }
+    impl Multisig {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "spend",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|s: &Multisig, _| {
+                            Clause::Threshold(
+                                s.k,
+                                s.keys.iter().cloned().map(Clause::Key).collect(),
+                            )
+                        }),
+                        None,
+                    ))
+                })],
+                pay_all,
+            )
+        }
+    }
+    impl Contract for Multisig {
+        declare! {then, Self::spend}
+        declare! {non updatable}
+        fn script_target(&self, ctx: &Context) -> ScriptTarget {
+            if self.segwit_v0 {
+                ScriptTarget::SegwitV0Only
+            } else {
+                ctx.script_target()
+            }
+        }
+    }
+    #[test]
+    fn script_targets() {
+        let secp = crate::contract::context::SECP.clone();
+        let full_keys = |n: u8| -> Vec<bitcoin::PublicKey> {
+            (1..=n)
+                .map(|i| {
+                    let mut secret = [0u8; 32];
+                    secret[31] = i;
+                    bitcoin::PublicKey::new(
+                        bitcoin::secp256k1::Keypair::from_seckey_slice(&secp, &secret)
+                            .unwrap()
+                            .public_key(),
+                    )
+                })
+                .collect()
+        };
+        let keys = |n: u8| -> Vec<XOnlyPublicKey> {
+            full_keys(n)
+                .into_iter()
+                .map(|k| k.inner.x_only_public_key().0)
+                .collect()
+        };
+        let without_full_keys = || {
+            Context::new(
+                Network::Bitcoin,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let on_mainnet = || {
+            full_keys(150)
+                .into_iter()
+                .fold(without_full_keys(), Context::with_segwit_v0_key)
+        };
+        let multisig = |segwit_v0| Multisig {
+            k: 2,
+            keys: keys(6),
+            segwit_v0,
+        };
+        let address = |c: &Compiled| match &c.address {
+            ExtendedAddress::Address(a) => a.to_string(),
+            ExtendedAddress::Descriptor(d) => d.address(Network::Bitcoin).unwrap().to_string(),
+            a => panic!("unexpected address {:?}", a),
+        };
+
+        let taproot = multisig(false).compile(on_mainnet()).unwrap();
+        assert!(address(&taproot).starts_with("bc1p"));
+        let segwit = multisig(false)
+            .compile(on_mainnet().with_script_target(ScriptTarget::SegwitV0Only))
+            .unwrap();
+        assert!(address(&segwit).starts_with("bc1q"));
+        match segwit.descriptor.as_ref() {
+            Some(SupportedDescriptors::Pk(d @ Descriptor::Wsh(_))) => {
+                assert_eq!(
+                    d.script_pubkey(),
+                    bitcoin::Script::from(segwit.address.clone())
+                );
+                assert!(d.to_string().contains("multi(2,"));
+                // the full keys are used, whichever their parity
+                let odd = full_keys(6)
+                    .into_iter()
+                    .find(|k| k.inner.x_only_public_key().1 == Parity::Odd)
+                    .unwrap();
+                assert!(d.to_string().contains(&odd.to_string()));
+            }
+            d => panic!("unexpected descriptor {:?}", d),
+        }
+        // the template is the same, only the output it spends differs
+        assert_eq!(
+            only_template(&taproot).hash(),
+            only_template(&segwit).hash()
+        );
+        assert!(only_template(&segwit).input_witness_weight.is_some());
+
+        // a contract may pick its own target
+        let overridden = multisig(true).compile(on_mainnet()).unwrap();
+        assert_eq!(address(&overridden), address(&segwit));
+
+        // without them, the parity of y is unknown
+        let err = multisig(true).compile(without_full_keys()).unwrap_err();
+        match err.root_cause() {
+            CompilationError::SegwitV0KeyParityUnknown { key, .. } => {
+                assert!(keys(6).contains(key))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+
+        // 150 signature checks are too many for a segwit v0 script
+        let large = Multisig {
+            k: 150,
+            keys: keys(150),
+            segwit_v0: true,
+        };
+        let err = large.compile(on_mainnet()).unwrap_err();
+        match err.root_cause() {
+            CompilationError::TaprootOnlyClause { clause, .. } => {
+                assert!(clause.contains("thresh(150,"))
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let large = Multisig {
+            segwit_v0: false,
+            ..large
+        };
+        assert!(address(&large.compile(on_mainnet()).unwrap()).starts_with("bc1p"));
+    }
+    /// one common branch and three rare ones, each signed by a different key
+    struct Skewed;
+    fn nth_key(n: usize) -> XOnlyPublicKey {
+        [
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee5",
+            "f9308a019258c31049344f85f89d5229b531c845836f99b08601f113bce036f9",
+            "e493dbf1c10d80f3581e4904930b1404cc6c13900ee0758474fa94abe8c4cd13",
+        ][n]
+            .parse()
+            .unwrap()
+    }
+    impl Skewed {
+        fn weighted<'a>(
+            name: &str,
+            guard: GuardList<'a, Self>,
+            weight: f64,
+        ) -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            let mut f = locked(name, guard, pay_all)?;
+            f.weight = Some(weight);
+            Some(f)
+        }
+        fn common<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Self::weighted(
+                "common",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| Clause::Key(nth_key(0))),
+                        None,
+                    ))
+                })],
+                0.85,
+            )
+        }
+        fn rare_1<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Self::weighted(
+                "rare_1",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| Clause::Key(nth_key(1))),
+                        None,
+                    ))
+                })],
+                0.05,
+            )
+        }
+        fn rare_2<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Self::weighted(
+                "rare_2",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| Clause::Key(nth_key(2))),
+                        None,
+                    ))
+                })],
+                0.05,
+            )
+        }
+        fn rare_3<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Self::weighted(
+                "rare_3",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| Clause::Key(nth_key(3))),
+                        None,
+                    ))
+                })],
+                0.05,
+            )
+        }
+    }
+    impl Contract for Skewed {
+        declare! {then, Self::common, Self::rare_1, Self::rare_2, Self::rare_3}
+        declare! {non updatable}
+    }
+    #[test]
+    fn weighted_tap_tree() {
+        use bitcoin::util::taproot::LeafVersion;
+        let compiled = Skewed.compile(ctx()).unwrap();
+        let tr = match compiled.descriptor.as_ref() {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr,
+            d => panic!("unexpected descriptor {:?}", d),
+        };
+        let control_block_len = |n| {
+            let (_, ms) = tr
+                .iter_scripts()
+                .find(|(_, ms)| ms.iter_pk().any(|k| k == nth_key(n)))
+                .unwrap();
+            tr.spend_info()
+                .control_block(&(ms.encode(), LeafVersion::TapScript))
+                .unwrap()
+                .serialize()
+                .len()
+        };
+        // with equal weights all four leaves would be at depth 2
+        assert_eq!(control_block_len(0), 33 + 32);
+        for n in 1..4 {
+            assert!(control_block_len(n) > control_block_len(0));
+        }
+        assert_eq!(control_block_len(3), 33 + 32 * 3);
+    }
+    struct HappyPath;
+    impl HappyPath {
+        fn happy() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|_, _| Clause::Key(nth_key(0))),
+                None,
+            ))
+        }
+        fn cold<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked(
+                "cold",
+                &[GuardGen::Fn(|| {
+                    Some(Guard::Fresh(
+                        GuardFn::Fn(|_, _| Clause::Key(nth_key(1))),
+                        None,
+                    ))
+                })],
+                pay_all,
+            )
+        }
+    }
+    impl Contract for HappyPath {
+        declare! {then, Self::cold}
+        declare! {finish, Self::happy}
+        declare! {non updatable}
+    }
+    #[test]
+    fn internal_key_selection() {
+        let tr = |c: &Compiled| match c.descriptor.clone() {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr,
+            d => panic!("unexpected descriptor {:?}", d),
+        };
+        let lone_key_leaves = |c: &Compiled| {
+            tr(c)
+                .iter_scripts()
+                .filter(|(_, ms)| ms.to_string() == format!("pk({})", nth_key(0)))
+                .count()
+        };
+        // the happy path moves from the script tree to the key path
+        let promoted = HappyPath
+            .compile(ctx().with_internal_key_promotion(true))
+            .unwrap();
+        assert_eq!(
+            promoted.internal_key,
+            Some(InternalKey {
+                key: nth_key(0),
+                source: InternalKeySource::Promoted
+            })
+        );
+        assert_eq!(*tr(&promoted).internal_key(), nth_key(0));
+        assert_eq!(tr(&promoted).iter_scripts().count(), 1);
+        assert_eq!(lone_key_leaves(&promoted), 0);
+        // every branch of Skewed has a CTV, so nobody can spend the key path
+        let nums = Skewed
+            .compile(ctx().with_internal_key_promotion(true))
+            .unwrap();
+        assert_eq!(*tr(&nums).internal_key(), nums_key());
+        assert_eq!(
+            nums.internal_key.map(|k| k.source),
+            Some(InternalKeySource::Unspendable)
+        );
+        // by default the happy path is kept in the tree too
+        let kept = HappyPath.compile(ctx()).unwrap();
+        assert_eq!(*tr(&kept).internal_key(), nth_key(0));
+        assert_eq!(
+            kept.internal_key.map(|k| k.source),
+            Some(InternalKeySource::Kept)
+        );
+        assert_eq!(lone_key_leaves(&kept), 1);
+        assert_ne!(tr(&kept), tr(&promoted));
+        let old_nums = Skewed.compile(ctx()).unwrap();
+        assert_eq!(*tr(&old_nums).internal_key(), unspendable_key());
+        // segwit v0 has no internal key
+        let wsh = HappyPath
+            .compile(
+                (0..4)
+                    .map(|n| format!("02{}", nth_key(n)).parse().unwrap())
+                    .fold(ctx(), Context::with_segwit_v0_key)
+                    .with_script_target(ScriptTarget::SegwitV0Only),
+            )
+            .unwrap();
+        assert_eq!(wsh.internal_key, None);
+    }
+    /// funds a `Broken` from a branch named `timeout`
+    struct TimesOut;
+    impl TimesOut {
+        fn timeout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("timeout", &[], |_, ctx, _| {
+                let amount = ctx.funds();
+                ctx.template().add_output(amount, &Broken, None)?.into()
+            })
+        }
+    }
+    impl Contract for TimesOut {
+        declare! {then, Self::timeout}
+        declare! {non updatable}
+    }
+    /// funds a `TimesOut` from a branch named `escrow`
+    struct Escrows;
+    impl Escrows {
+        fn escrow<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("escrow", &[], |_, ctx, _| {
+                let amount = ctx.funds();
+                ctx.template().add_output(amount, &TimesOut, None)?.into()
+            })
+        }
+    }
+    impl Contract for Escrows {
+        declare! {then, Self::escrow}
+        declare! {non updatable}
+    }
+    #[test]
+    fn errors_name_their_path() {
+        let err = Escrows.compile(ctx()).unwrap_err();
+        let refund = "compiler/@action/escrow/@next/@default_effect/#0/@action/timeout/@next/@default_effect/#0/@action/refund";
+        assert_eq!(
+            err.path().map(|p| String::from(p.clone())),
+            Some(refund.into())
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "at `{}`: branch `escrow` failed: branch `timeout` failed: \
+                 branch `refund` failed: TerminateWith(\"no refunds\")",
+                refund
+            )
+        );
+    }
+    static SALE_GUARD_CALLS: AtomicU32 = AtomicU32::new(0);
+    fn counted_key(n: usize) -> Clause {
+        SALE_GUARD_CALLS.fetch_add(1, Ordering::SeqCst);
+        Clause::Key(nth_key(n))
+    }
+    /// A stand-in for the NFT sale plugin, which re-mints the NFT to the
+    /// buyer and pays the seller
+    struct Nft;
+    impl Nft {
+        fn owner() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(GuardFn::Fn(|_, _| counted_key(1)), None))
+        }
+        fn sell<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("sell", &[GuardGen::Fn(Self::owner)], pay_all)
+        }
+    }
+    impl Contract for Nft {
+        declare! {then, Self::sell}
+        declare! {non updatable}
+    }
+    struct NftSale;
+    impl NftSale {
+        fn seller() -> Option<Guard<Self>> {
+            Some(Guard::Cache(GuardFn::Fn(|_, _| counted_key(0)), None))
+        }
+        fn transfer<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("transfer", &[GuardGen::Fn(Self::seller)], |_, ctx, _| {
+                let price = ctx.funds() - Amount::from_sat(20_000);
+                leaving_fees(
+                    ctx.template()
+                        .add_output(Amount::from_sat(10_000), &Nft, None)?
+                        .add_output(price, &nth_key(2), None)?,
+                )
+            })
+        }
+        fn cancel<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("cancel", &[GuardGen::Fn(Self::seller)], pay_all)
+        }
+    }
+    impl Contract for NftSale {
+        declare! {then, Self::transfer, Self::cancel}
+        declare! {non updatable}
+    }
+    /// the branch names, and the amounts of the outputs of each of their
+    /// templates, all the way down
+    fn tree_shape(c: &Compiled) -> serde_json::Value {
+        c.branches
+            .iter()
+            .map(|(name, hashes)| {
+                let templates: Vec<_> = hashes
+                    .iter()
+                    .map(|h| {
+                        let t = c.ctv_to_tx.get(h).or_else(|| c.suggested_txs.get(h));
+                        t.unwrap()
+                            .outputs
+                            .iter()
+                            .map(|o| {
+                                serde_json::json!([o.amount.as_sat(), tree_shape(&o.contract)])
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                (name.clone(), serde_json::json!(templates))
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into()
+    }
+    #[test]
+    fn dry_run_matches_tree_shape() {
+        let real = NftSale.compile(ctx()).unwrap();
+        assert!(SALE_GUARD_CALLS.load(Ordering::SeqCst) > 0);
+        let calls = SALE_GUARD_CALLS.load(Ordering::SeqCst);
+        let declared = AmountRange::between(Amount::from_sat(50_000), Amount::from_sat(150_000));
+        let skeleton = Context::new(
+            Network::Regtest,
+            Amount::ZERO,
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("compiler").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .dry_run(declared)
+        .compile_skeleton(NftSale)
+        .unwrap();
+        assert_eq!(SALE_GUARD_CALLS.load(Ordering::SeqCst), calls);
+        assert_eq!(tree_shape(&real)["transfer"][0][0][0], 10_000);
+        assert_eq!(tree_shape(skeleton.object()), tree_shape(&real));
+        // nothing in it can be spent
+        let nested = &skeleton.object().ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        for o in [skeleton.object(), nested] {
+            assert_eq!(
+                o.internal_key.map(|k| k.source),
+                Some(InternalKeySource::Unspendable)
+            );
+        }
+        let json = serde_json::to_value(&skeleton).unwrap();
+        assert_eq!(json["skeleton"], true);
+        assert!(!real.skeleton);
+        assert!(serde_json::to_value(&real)
+            .unwrap()
+            .get("skeleton")
+            .is_none());
+        // and it can't be bound, even if compiled as a plain `Compiled`
+        let plain = ctx().dry_run(declared).compile(NftSale).unwrap();
+        for o in [skeleton.object(), &plain] {
+            assert!(o.skeleton);
+            let bound = o.bind_psbt(
+                bitcoin::OutPoint::default(),
+                BTreeMap::new(),
+                std::rc::Rc::new(sapio_base::txindex::TxIndexLogger::new()),
+                &CTVAvailable,
+            );
+            assert!(matches!(bound, Err(ObjectError::Skeleton)));
+        }
+        assert_eq!(
+            json["address"],
+            serde_json::to_value(&skeleton.object().address).unwrap()
+        );
+    }
+    /// a tree of identical halves, with `nth_key(0)` at each of its
+    /// `2^depth` leaves
+    struct Halves(u32);
+    fn split_in_half(s: &Halves, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mut builder = ctx.template();
+        for _ in 0..2 {
+            builder = match s.0 {
+                1 => builder.add_output(half, &nth_key(0), None)?,
+                depth => builder.add_output(half, &Halves(depth - 1), None)?,
+            };
+        }
+        builder.into()
+    }
+    impl Halves {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, split_in_half)
+        }
+    }
+    impl Contract for Halves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    /// an emulator whose oracle is `nth_key(1)`
+    struct Oracle;
+    impl CTVEmulator for Oracle {
+        fn get_signer_for(&self, _h: sha256::Hash) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Key(nth_key(1)))
+        }
+        fn sign(
+            &self,
+            b: bitcoin::util::psbt::PartiallySignedTransaction,
+        ) -> Result<bitcoin::util::psbt::PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+    }
+    /// an emulator whose oracle is `nth_key(self.0)`, describing itself
+    struct NthOracle(usize);
+    impl CTVEmulator for NthOracle {
+        fn get_signer_for(&self, _h: sha256::Hash) -> Result<Clause, EmulatorError> {
+            Ok(Clause::Key(nth_key(self.0)))
+        }
+        fn sign(
+            &self,
+            b: bitcoin::util::psbt::PartiallySignedTransaction,
+        ) -> Result<bitcoin::util::psbt::PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+        fn describe(&self) -> Option<String> {
+            Some(format!("oracle {}", self.0))
+        }
+    }
+    /// `Halves(1)` twice, the first secured by `NthOracle(2)` and the second
+    /// by the Context's emulator
+    struct TwoOracles;
+    fn split_between_oracles(_: &TwoOracles, mut ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mine = ctx
+            .derive_labeled("mine")?
+            .with_amount(half)?
+            .with_emulator(Arc::new(NthOracle(2)));
+        let mine = Halves(1).compile(mine)?;
+        let theirs = Halves(1).compile(ctx.derive_labeled("theirs")?.with_amount(half)?)?;
+        ctx.template()
+            .add_output(half, &mine, None)?
+            .add_output(half, &theirs, None)?
+            .into()
+    }
+    impl TwoOracles {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, split_between_oracles)
+        }
+    }
+    impl Contract for TwoOracles {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    /// an emulator like `Oracle` which counts how often it is asked for its
+    /// oracle's key, failing the first request of each batch if `flaky`
+    #[derive(Default)]
+    struct Remote {
+        flaky: bool,
+        batches: AtomicU32,
+        single: AtomicU32,
+    }
+    impl CTVEmulator for Remote {
+        fn get_signer_for(&self, _h: sha256::Hash) -> Result<Clause, EmulatorError> {
+            self.single.fetch_add(1, Ordering::Relaxed);
+            Ok(Clause::Key(nth_key(1)))
+        }
+        fn sign(
+            &self,
+            b: bitcoin::util::psbt::PartiallySignedTransaction,
+        ) -> Result<bitcoin::util::psbt::PartiallySignedTransaction, EmulatorError> {
+            Ok(b)
+        }
+        fn sign_batch(
+            &self,
+            requests: &[EmulatorRequest],
+        ) -> Result<Vec<Result<EmulatorResponse, EmulatorError>>, EmulatorError> {
+            self.batches.fetch_add(1, Ordering::Relaxed);
+            Ok((0..requests.len())
+                .map(|i| match i {
+                    0 if self.flaky => Err(EmulatorError::NetworkIssue(
+                        std::io::ErrorKind::TimedOut.into(),
+                    )),
+                    _ => Ok(EmulatorResponse::SignerFor(Clause::Key(nth_key(1)))),
+                })
+                .collect())
+        }
+    }
+    /// a contract with two ThenFuncs of `0` templates each
+    struct Batched(u64);
+    impl Batched {
+        fn pay<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            payout(FeePolicy::None, |s, mut ctx, _| {
+                let txtmpls: Vec<_> = (1..=s.0)
+                    .map(|i| pay_with(&mut ctx, i * 1000, Commitment::Committed))
+                    .collect();
+                Ok(Box::new(txtmpls.into_iter()))
+            })
+        }
+        fn pay_more<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: |s: &Self, mut ctx, _| {
+                        let txtmpls: Vec<_> = (1..=s.0)
+                            .map(|i| pay_with(&mut ctx, i * 1000 + 500, Commitment::Committed))
+                            .collect();
+                        Ok(Box::new(txtmpls.into_iter()))
+                    },
+                    name: Arc::new("pay_more".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Batched {
+        declare! {then, Self::pay, Self::pay_more}
+        declare! {non updatable}
+    }
+    #[test]
+    fn batched_emulator_requests() {
+        let compile = |flaky, n| {
+            let remote = Arc::new(Remote {
+                flaky,
+                ..Default::default()
+            });
+            let compiled = Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000_000),
+                remote.clone(),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .compile(Batched(n))
+            .unwrap();
+            assert_eq!(compiled.ctv_to_tx.len() as u64, 2 * n);
+            (
+                remote.batches.load(Ordering::Relaxed),
+                remote.single.load(Ordering::Relaxed),
+            )
+        };
+        // one request for both branches' four templates
+        assert_eq!(compile(false, 2), (1, 0));
+        // and the one which failed in it asked for alone, once
+        assert_eq!(compile(true, 2), (1, 1));
+        // split once there are too many to ask for at once
+        let n = MAX_SIGNER_BATCH as u64 / 2 + 1;
+        assert_eq!(compile(false, n), (2, 0));
+        assert_eq!(compile(true, n), (2, 2));
+    }
+    /// the local emulator's signatures finalize the spend, without a node to
+    /// broadcast it to, which `tools/tests/regtest.rs` does against bitcoind
+    #[test]
+    fn local_emulator_spends() {
+        use crate::contract::abi::studio::SapioStudioFormat;
+        use bitcoin::util::psbt::PartiallySignedTransaction;
+        use emulator_connect::connections::local::LocalEmulator;
+        use sapio_base::txindex::{TxIndex, TxIndexLogger};
+        use std::rc::Rc;
+        let compile = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(LocalEmulator::for_tests()),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .compile(Halves(1))
+            .unwrap()
+        };
+        let compiled = compile();
+        // the test seed gives the same address every time
+        let script = |c: &Compiled| bitcoin::Script::from(c.address.clone());
+        assert_eq!(script(&compiled), script(&compile()));
+        let txindex: Rc<dyn TxIndex> = Rc::new(TxIndexLogger::new());
+        let funding = txindex
+            .add_tx(Arc::new(bitcoin::Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![],
+                output: vec![bitcoin::TxOut {
+                    value: 100_000,
+                    script_pubkey: script(&compiled),
+                }],
+            }))
+            .unwrap();
+        let program = compiled
+            .bind_psbt(
+                bitcoin::OutPoint::new(funding, 0),
+                Default::default(),
+                txindex,
+                &LocalEmulator::for_tests(),
+            )
+            .unwrap();
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let mut spent = 0;
+        for object in program.program.values() {
+            for tx in object.txs.iter() {
+                let SapioStudioFormat::LinkedPSBT { psbt, .. } = tx;
+                let mut psbt: PartiallySignedTransaction =
+                    bitcoin::consensus::deserialize(&base64::decode(psbt).unwrap()).unwrap();
+                ::miniscript::psbt::finalize(&mut psbt, &secp).unwrap();
+                spent += 1;
+            }
+        }
+        assert_eq!(spent, 1);
+    }
+    #[test]
+    fn covenant_backends() {
+        let ctx = |backend: Option<CovenantBackend>| {
+            let ctx = Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(Oracle),
+                EffectPath::try_from("compiler").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            );
+            match backend {
+                Some(backend) => ctx.with_covenant_backend(backend),
+                None => ctx,
+            }
+        };
+        let h = <sha256::Hash as bitcoin::hashes::Hash>::hash(&[]);
+        let oracle = Clause::Key(nth_key(1));
+        assert_eq!(ctx(None).ctv_emulator(h).unwrap(), oracle);
+        assert_eq!(
+            ctx(Some(CovenantBackend::NativeCtv))
+                .ctv_emulator(h)
+                .unwrap(),
+            Clause::TxTemplate(h)
+        );
+        assert_eq!(
+            ctx(Some(CovenantBackend::Emulator(Arc::new(CTVAvailable))))
+                .ctv_emulator(h)
+                .unwrap(),
+            Clause::TxTemplate(h)
+        );
+        assert_eq!(
+            ctx(Some(CovenantBackend::Both)).ctv_emulator(h).unwrap(),
+            Clause::Or(vec![(1, Clause::TxTemplate(h)), (1, oracle)])
+        );
+        // whether the oracle's key is in the scripts, and how the template
+        // is recorded as enforced
+        let compile = |backend| {
+            let compiled = Halves(1).compile(ctx(backend)).unwrap();
+            let scripts = match compiled.descriptor.as_ref() {
+                Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr
+                    .iter_scripts()
+                    .map(|(_, ms)| ms.to_string())
+                    .collect::<Vec<_>>(),
+                d => panic!("unexpected descriptor {:?}", d),
+            };
+            // a lone oracle key is promoted to the internal key
+            let oracle = scripts.iter().any(|s| s.contains(&nth_key(1).to_string()))
+                || compiled.internal_key.map(|k| k.key) == Some(nth_key(1));
+            let covenants: Vec<_> = compiled.covenants.values().copied().collect();
+            (oracle, covenants)
+        };
+        assert_eq!(compile(None), (true, vec![]));
+        assert_eq!(
+            compile(Some(CovenantBackend::NativeCtv)),
+            (false, vec![TemplateCovenant::Native])
+        );
+        assert_eq!(
+            compile(Some(CovenantBackend::Both)),
+            (true, vec![TemplateCovenant::Both])
+        );
+    }
+    #[test]
+    fn subtree_emulators() {
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(NthOracle(1)),
+            EffectPath::try_from("compiler").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let compiled = TwoOracles.compile(ctx).unwrap();
+        assert_eq!(compiled.emulator, None);
+        assert_eq!(compiled.internal_key.unwrap().key, nth_key(1));
+        let outputs = &compiled.ctv_to_tx.values().next().unwrap().outputs;
+        let subtrees: Vec<_> = outputs
+            .iter()
+            .map(|o| {
+                (
+                    o.contract.emulator.clone(),
+                    o.contract.internal_key.unwrap().key,
+                )
+            })
+            .collect();
+        assert_eq!(
+            subtrees,
+            vec![
+                (Some("oracle 2".to_string()), nth_key(2)),
+                (None, nth_key(1)),
+            ]
+        );
+    }
+    #[test]
+    fn parallel_script_compilation_is_deterministic() {
+        use bitcoin::hashes::Hash;
+        let hash = bitcoin::hashes::hash160::Hash::hash(&[1; 32]);
+        let sequential = (
+            serde_json::to_string(&Halves(5).compile(ctx()).unwrap()).unwrap(),
+            serde_json::to_string(&Htlc { hash }.compile(ctx()).unwrap()).unwrap(),
+        );
+        for _ in 0..4 {
+            let ctx = || ctx().with_parallel_script_compilation();
+            let parallel = (
+                serde_json::to_string(&Halves(5).compile(ctx()).unwrap()).unwrap(),
+                serde_json::to_string(&Htlc { hash }.compile(ctx()).unwrap()).unwrap(),
+            );
+            assert_eq!(parallel, sequential);
+        }
+    }
+    fn no_keys<T>() -> Option<Guard<T>> {
+        Some(Guard::Cache(
+            GuardFn::Fn(|_, _| {
+                Clause::And(vec![Clause::Key(nth_key(2)), Clause::Threshold(1, vec![])])
+            }),
+            None,
+        ))
+    }
+    fn first_key<T>() -> Option<Guard<T>> {
+        Some(Guard::Cache(
+            GuardFn::Fn(|_, _| Clause::Key(nth_key(0))),
+            None,
+        ))
+    }
+    fn second_key<T>() -> Option<Guard<T>> {
+        Some(Guard::Cache(
+            GuardFn::Fn(|_, _| Clause::Key(nth_key(1))),
+            None,
+        ))
+    }
+    struct Unreachable;
+    impl Contract for Unreachable {
+        declare! {finish, first_key, second_key, no_keys}
+        declare! {non updatable}
+    }
+    struct Orphaned;
+    impl Orphaned {
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            locked("spend", &[GuardGen::Fn(no_keys)], pay_all)
+        }
+    }
+    impl Contract for Orphaned {
+        declare! {then, Self::spend}
+        declare! {finish, first_key}
+        declare! {non updatable}
+    }
+    /// a key path and a ThenFunc which is never compiled
+    struct Disabled;
+    impl Disabled {
+        fn never() -> Option<ConditionallyCompileIf<Self>> {
+            Some(ConditionallyCompileIf::Fresh(|_, _| {
+                ConditionalCompileType::Never
+            }))
+        }
+        fn spend<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[Self::never],
+                    func: pay_all,
+                    name: Arc::new("spend".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Disabled {
+        declare! {then, Self::spend}
+        declare! {finish, first_key}
+        declare! {non updatable}
+    }
+    struct Dead;
+    impl Contract for Dead {
+        declare! {finish, no_keys}
+        declare! {non updatable}
+    }
+    #[test]
+    fn unreachable_branches() {
+        let leaves = |c: &Compiled| match c.descriptor.as_ref() {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr.iter_scripts().count(),
+            d => panic!("unexpected descriptor {:?}", d),
+        };
+        let unreachable = |c: &Compiled| {
+            c.diagnostics
+                .iter()
+                .filter(|d| d.code == "unreachable_branch")
+                .map(|d| String::from(d.path.clone()))
+                .collect::<Vec<_>>()
+        };
+        // without pruning, the empty threshold is compiled to a leaf which
+        // can't be spent
+        let reported = Unreachable
+            .compile(ctx().with_clause_simplification(true))
+            .unwrap();
+        assert_eq!(unreachable(&reported), vec!["compiler/@finish_fn"]);
+        let pruned = Unreachable
+            .compile(ctx().with_unreachable_pruning(true))
+            .unwrap();
+        assert_eq!(unreachable(&pruned), vec!["compiler/@finish_fn"]);
+        assert_eq!(leaves(&pruned), leaves(&reported) - 1);
+        // pruning is recorded in the trace
+        let compiled = Orphaned
+            .compile(ctx().with_unreachable_pruning(true).enable_compile_trace())
+            .unwrap();
+        assert_eq!(unreachable(&compiled), vec!["compiler/@action/spend"]);
+        let trace = compiled.compile_trace.as_ref().unwrap();
+        assert_eq!(trace["spend"].pruned_leaves, 1);
+        // along with the templates of a branch with no leaves left
+        assert!(compiled.ctv_to_tx.is_empty());
+        assert!(compiled.branches.is_empty());
+        // a branch which is never compiled is reported too
+        let disabled = Disabled.compile(ctx()).unwrap();
+        assert_eq!(unreachable(&disabled), vec!["compiler/@action/spend"]);
+        // but not of the only way to spend a contract
+        assert!(Dead.compile(ctx().with_clause_simplification(true)).is_ok());
+        assert!(matches!(
+            Dead.compile(ctx().with_unreachable_pruning(true)),
+            Err(CompilationError::AllBranchesUnreachable)
+        ));
+    }
+}
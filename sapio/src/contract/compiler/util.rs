@@ -6,46 +6,135 @@
 
 //! utility functions for compiler
 
+use crate::contract::object::{InternalKey, InternalKeySource};
 use ::miniscript::descriptor::TapTree;
 use ::miniscript::*;
 use bitcoin::hashes::sha256::Hash as Sha256;
 use bitcoin::hashes::Hash;
 use bitcoin::XOnlyPublicKey;
+use sapio_base::Clause;
 use std::cmp::Reverse;
 use std::collections::BinaryHeap;
+use std::str::FromStr;
 use std::sync::Arc;
+/// A branch's spending condition, compiled for the `ScriptTarget` in use
+pub enum CompiledLeaf {
+    /// A tapscript leaf
+    Tap(Miniscript<XOnlyPublicKey, Tap>),
+    /// A clause known to compile for segwit v0, to be combined with the
+    /// other branches into one witness script
+    SegwitV0(Clause),
+}
+
+/// a key nobody knows the secret key of
+pub fn unspendable_key() -> XOnlyPublicKey {
+    XOnlyPublicKey::from_slice(&Sha256::hash(&[1u8; 32]).into_inner()).expect("constant")
+}
+
+/// the key of a branch which is just a signature from that key, e.g. from a
+/// `Clause::Key` or a MuSig aggregate
+fn lone_key(ms: &Miniscript<XOnlyPublicKey, Tap>) -> Option<XOnlyPublicKey> {
+    if let Terminal::Check(check) = &ms.node {
+        if let Terminal::PkK(k) = &check.node {
+            return Some(*k);
+        }
+    }
+    None
+}
+
 /// picks a key from an iter of miniscripts, or returns a static default key
 pub fn pick_key_from_miniscripts<'a, I: Iterator<Item = &'a Miniscript<XOnlyPublicKey, Tap>>>(
     branches: I,
-) -> XOnlyPublicKey {
+) -> InternalKey {
     branches
-        .filter_map(|f| {
-            if let Terminal::Check(check) = &f.node {
-                if let Terminal::PkK(k) = &check.node {
-                    return Some(*k);
-                }
-            }
-            None
-        })
+        .filter_map(lone_key)
         .next()
-        .unwrap_or(
-            XOnlyPublicKey::from_slice(&Sha256::hash(&[1u8; 32]).into_inner()).expect("constant"),
-        )
+        .map(|key| InternalKey {
+            key,
+            source: InternalKeySource::Kept,
+        })
+        .unwrap_or_else(|| InternalKey {
+            key: unspendable_key(),
+            source: InternalKeySource::Unspendable,
+        })
+}
+
+/// the `H` point from BIP-341, which provably has no known discrete log
+pub fn nums_key() -> XOnlyPublicKey {
+    XOnlyPublicKey::from_str("50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0")
+        .expect("constant")
+}
+
+/// Take the likeliest branch which is just a key out of the tree to be the
+/// internal key, so it can be spent by the key path. Any other branch with
+/// the same key is redundant and removed too. Without one, the internal key
+/// is `nums_key`.
+pub fn promote_internal_key(
+    mut branches: Vec<(f64, Miniscript<XOnlyPublicKey, Tap>)>,
+) -> (InternalKey, Vec<(f64, Miniscript<XOnlyPublicKey, Tap>)>) {
+    let mut best: Option<(f64, XOnlyPublicKey)> = None;
+    for (w, k) in branches
+        .iter()
+        .filter_map(|(w, ms)| Some((*w, lone_key(ms)?)))
+    {
+        if best.is_none_or(|(best_w, _)| w > best_w) {
+            best = Some((w, k));
+        }
+    }
+    match best {
+        Some((_, key)) => {
+            branches.retain(|(_, ms)| lone_key(ms) != Some(key));
+            (
+                InternalKey {
+                    key,
+                    source: InternalKeySource::Promoted,
+                },
+                branches,
+            )
+        }
+        None => (
+            InternalKey {
+                key: nums_key(),
+                source: InternalKeySource::Unspendable,
+            },
+            branches,
+        ),
+    }
+}
+
+/// A branch weight, ordered with `f64::total_cmp` so it can go in a heap
+struct Weight(f64);
+impl PartialEq for Weight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+impl Eq for Weight {}
+impl PartialOrd for Weight {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Weight {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
 }
 
-/// Convert the branches into a heap for taproot tree consumption
+/// Convert the weighted branches into a taproot tree by Huffman coding, so
+/// that the likeliest branches have the shortest merkle proofs
 pub fn branches_to_tree(
-    branches: Vec<Miniscript<XOnlyPublicKey, Tap>>,
+    branches: Vec<(f64, Miniscript<XOnlyPublicKey, Tap>)>,
 ) -> Option<TapTree<XOnlyPublicKey>> {
-    let mut scripts: BinaryHeap<(Reverse<u64>, TapTree<XOnlyPublicKey>)> = branches
+    let mut scripts: BinaryHeap<(Reverse<Weight>, TapTree<XOnlyPublicKey>)> = branches
         .into_iter()
-        .map(|b| (Reverse(1), TapTree::Leaf(Arc::new(b))))
+        .map(|(w, b)| (Reverse(Weight(w)), TapTree::Leaf(Arc::new(b))))
         .collect();
     while scripts.len() > 1 {
         let (w1, v1) = scripts.pop().unwrap();
         let (w2, v2) = scripts.pop().unwrap();
         scripts.push((
-            Reverse(w1.0.saturating_add(w2.0)),
+            Reverse(Weight(w1.0 .0 + w2.0 .0)),
             TapTree::Tree(Arc::new(v1), Arc::new(v2)),
         ));
     }
@@ -6,32 +6,209 @@
 
 //! general non-parameter compilation state required by all contracts
 use super::{Amount, Compilable, CompilationError, Compiled};
+use crate::contract::actions::GuardExecutor;
 use crate::contract::compiler::InternalCompilerTag;
+use crate::contract::error::{AtPath, ResourceLimit};
+use crate::contract::object::{
+    Clock, CompileProfile, Diagnostic, DiagnosticLevel, Diagnostics, Profiler, SkeletonObject,
+    Span, SpanKind, TemplateCovenant,
+};
+use crate::template::Template;
+use crate::util::amountrange::AmountRange;
 
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::secp256k1::{All, Secp256k1};
 use bitcoin::Network;
+use bitcoin::XOnlyPublicKey;
 
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::PathFragment;
 pub use sapio_base::effects::{EffectDB, MapEffectDB};
+use sapio_base::timelocks::{AbsHeight, AbsTime};
 
-use sapio_ctv_emulator_trait::CTVEmulator;
+use sapio_ctv_emulator_trait::{CTVEmulator, EmulatorError, EmulatorRequest, EmulatorResponse};
 use std::convert::TryInto;
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Receives every template as it is compiled, see `Context::with_template_sink`
+pub type TemplateSink = Arc<dyn Fn(&Template) -> Result<(), CompilationError> + Send + Sync>;
+
+/// Which kind of output a contract compiles to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ScriptTarget {
+    /// A taproot output, with each branch in its own tapscript leaf
+    #[default]
+    TaprootPreferred,
+    /// A P2WSH output with all branches in one witness script, for signers
+    /// which can't spend taproot outputs. Each x-only key must have its full
+    /// key set with `Context::with_segwit_v0_key`, as its parity is unknown.
+    SegwitV0Only,
+}
+
+/// How the templates a contract commits to are enforced, see
+/// `Context::with_covenant_backend`
+#[derive(Clone)]
+pub enum CovenantBackend {
+    /// by `OP_CHECKTEMPLATEVERIFY`, without consulting any emulator
+    NativeCtv,
+    /// by the oracle of this emulator, instead of the Context's
+    Emulator(Arc<dyn CTVEmulator>),
+    /// by either `OP_CHECKTEMPLATEVERIFY` or the oracle of the Context's
+    /// emulator, so that the same address works before and after CTV
+    /// activates
+    Both,
+}
+
+impl CovenantBackend {
+    /// how the templates this backend commits to are enforced
+    pub fn covenant(&self) -> TemplateCovenant {
+        match self {
+            CovenantBackend::NativeCtv => TemplateCovenant::Native,
+            CovenantBackend::Emulator(_) => TemplateCovenant::Emulated,
+            CovenantBackend::Both => TemplateCovenant::Both,
+        }
+    }
+}
+
+/// Limits on what compiling a contract may use, so that a contract which
+/// e.g. recursively instantiates itself fails instead of exhausting memory or
+/// the stack, see `Context::with_resource_limits`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// the most fragments the path of a `Context` a contract is compiled in
+    /// may have. Each level of nesting adds a handful of fragments.
+    pub max_depth: usize,
+    /// the most templates which may be compiled in total
+    pub max_templates: usize,
+    /// the most bytes of transactions, as serialized, which may be compiled
+    /// in total
+    pub max_bytes: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        ResourceLimits {
+            max_depth: 512,
+            max_templates: 1 << 20,
+            max_bytes: 1 << 30,
+        }
+    }
+}
+
+impl ResourceLimits {
+    /// Stricter limits for compiling a contract on behalf of someone else,
+    /// e.g. in a plugin or a server session
+    pub fn sandboxed() -> Self {
+        ResourceLimits {
+            max_depth: 256,
+            max_templates: 1 << 16,
+            max_bytes: 1 << 26,
+        }
+    }
+}
+
+/// What has been compiled so far, counted against the `ResourceLimits`
+#[derive(Default)]
+struct ResourceUsage {
+    templates: AtomicUsize,
+    bytes: AtomicUsize,
+}
 
 /// Context is used to track statet during compilation such as remaining value.
 pub struct Context {
     /* TODO: Add Context Fields! */
     available_funds: Amount,
-    emulator: Arc<dyn CTVEmulator>,
     /// which network is the contract building for?
     pub network: Network,
     /// TODO: reversed linked list of ARCs to better de-duplicate memory.
     path: Arc<EffectPath>,
     already_derived: HashSet<PathFragment>,
+    shared: Arc<SharedContext>,
+    top_level: bool,
+    /// shared with every Context derived from this one, until the next
+    /// contract compiled starts its own
+    diagnostics: Diagnostics,
+    /// set if what is being compiled depends on more than a `Memoized`
+    /// contract's key, shared like `diagnostics` until the next `Memoized`
+    /// contract starts its own
+    not_memoizable: Arc<AtomicBool>,
+}
+
+/// Earlier compilations of `Memoized` contracts, by their key
+#[derive(Default)]
+struct MemoCache(Mutex<BTreeMap<sha256::Hash, Compiled>>);
+
+/// Compilations with other settings may differ, so a copy made by a `with_*`
+/// setter starts empty
+impl Clone for MemoCache {
+    fn clone(&self) -> Self {
+        Default::default()
+    }
+}
+
+/// The most clauses `Context::prefetch_signers` asks an emulator for at once
+pub(crate) const MAX_SIGNER_BATCH: usize = 256;
+
+/// Clauses fetched from an emulator ahead of `Context::ctv_emulator`, by
+/// template hash, see `Context::prefetch_signers`
+#[derive(Default)]
+struct SignerCache(Mutex<BTreeMap<sha256::Hash, sapio_base::Clause>>);
+
+/// The emulator may differ after a `with_*` setter, so a copy starts empty
+impl Clone for SignerCache {
+    fn clone(&self) -> Self {
+        Default::default()
+    }
+}
+
+/// The parts of a `Context` which every Context derived from it inherits
+/// unchanged, shared rather than copied so that deriving is cheap. A
+/// `with_*` setter copies them only if they are still shared.
+#[derive(Clone)]
+struct SharedContext {
+    emulator: Arc<dyn CTVEmulator>,
+    /// the `CTVEmulator::describe` of `emulator`, if it was chosen with
+    /// `Context::with_emulator`
+    emulator_override: Option<String>,
+    covenant_backend: Option<CovenantBackend>,
     effects: Arc<MapEffectDB>,
+    executor: Option<Arc<dyn GuardExecutor>>,
+    template_sink: Option<TemplateSink>,
+    compile_trace: bool,
+    feerate: Option<Amount>,
+    entropy_seed: Option<sha256::Hash>,
+    tip_height: Option<AbsHeight>,
+    median_time: Option<AbsTime>,
+    secp: Arc<Secp256k1<All>>,
+    standardness_checks: bool,
+    dust_as_warning: bool,
+    conservation_checks: bool,
+    clause_simplification: bool,
+    script_target: ScriptTarget,
+    segwit_v0_keys: BTreeMap<XOnlyPublicKey, bitcoin::PublicKey>,
+    internal_key_promotion: bool,
+    dry_run: bool,
+    parallel_script_compilation: bool,
+    unreachable_pruning: bool,
+    profiler: Option<Profiler>,
+    clock: Option<Clock>,
+    resource_limits: ResourceLimits,
+    /// shared by every Context derived from the same `Context::new`, even
+    /// after a `with_*` setter
+    resource_usage: Arc<ResourceUsage>,
+    /// set to stop compiling, see `Context::with_cancellation`
+    cancelled: Option<Arc<AtomicBool>>,
+    memo: MemoCache,
+    signers: SignerCache,
+}
+
+lazy_static::lazy_static! {
+    /// shared by every `Context`, created the first time one is
+    pub(crate) static ref SECP: Arc<Secp256k1<All>> = Arc::new(Secp256k1::new());
 }
 
 impl Context {
@@ -46,26 +223,564 @@ impl Context {
     ) -> Self {
         Context {
             available_funds,
-            emulator,
             network,
             // TODO: Should return Option Self if path is not length > 0
             path: Arc::new(path),
             already_derived: Default::default(),
-            effects,
+            shared: Arc::new(SharedContext {
+                emulator,
+                emulator_override: None,
+                covenant_backend: None,
+                effects,
+                executor: None,
+                template_sink: None,
+                compile_trace: false,
+                feerate: None,
+                entropy_seed: None,
+                tip_height: None,
+                median_time: None,
+                secp: SECP.clone(),
+                standardness_checks: true,
+                dust_as_warning: false,
+                conservation_checks: true,
+                clause_simplification: false,
+                script_target: ScriptTarget::TaprootPreferred,
+                segwit_v0_keys: Default::default(),
+                internal_key_promotion: false,
+                dry_run: false,
+                parallel_script_compilation: false,
+                unreachable_pruning: false,
+                profiler: None,
+                clock: None,
+                resource_limits: Default::default(),
+                resource_usage: Default::default(),
+                cancelled: None,
+                memo: Default::default(),
+                signers: Default::default(),
+            }),
+            top_level: true,
+            diagnostics: Default::default(),
+            not_memoizable: Default::default(),
+        }
+    }
+    /// The `SharedContext` to change, copied if it is still shared. Anything
+    /// memoized with the old settings is forgotten.
+    fn shared_mut(&mut self) -> &mut SharedContext {
+        let shared = Arc::make_mut(&mut self.shared);
+        shared.memo = Default::default();
+        shared.signers = Default::default();
+        shared
+    }
+    /// Set the executor used to resolve any `Guard::Async` during compilation.
+    pub fn with_executor(mut self, executor: Arc<dyn GuardExecutor>) -> Self {
+        self.shared_mut().executor = Some(executor);
+        self
+    }
+    /// Get the executor for `Guard::Async`, if one has been set.
+    pub fn executor(&self) -> Option<&Arc<dyn GuardExecutor>> {
+        self.shared.executor.as_ref()
+    }
+    /// Stream every template to `sink` as it is compiled, rather than keeping
+    /// them in the compiled object (and any objects compiled from derived
+    /// contexts), e.g. to write contracts with too many templates to hold in
+    /// memory to disk. Only the hashes of the templates of each branch are
+    /// kept, and streamed templates don't have `input_witness_weight` set, as
+    /// it isn't known until all of a contract's branches are compiled.
+    pub fn with_template_sink(mut self, sink: TemplateSink) -> Self {
+        self.shared_mut().template_sink = Some(sink);
+        self
+    }
+    /// Get the sink templates are streamed to, if one has been set.
+    pub fn template_sink(&self) -> Option<&TemplateSink> {
+        self.shared.template_sink.as_ref()
+    }
+    /// Record a trace of conditional compilation decisions into the compiled
+    /// object (and any objects compiled from derived contexts).
+    pub fn enable_compile_trace(mut self) -> Self {
+        self.shared_mut().compile_trace = true;
+        self
+    }
+    /// Is a conditional compilation trace being recorded?
+    pub fn compile_trace_enabled(&self) -> bool {
+        self.shared.compile_trace
+    }
+    /// Set the feerate, in sats per vbyte, templates should pay with
+    /// `Builder::add_fee_from_rate`
+    pub fn with_feerate(mut self, feerate: Option<Amount>) -> Self {
+        self.shared_mut().feerate = feerate;
+        self
+    }
+    /// Get the feerate, in sats per vbyte, set by the caller if any
+    pub fn feerate(&self) -> Option<Amount> {
+        self.shared.feerate
+    }
+    /// Set the seed `derive_entropy` derives from, chosen by whoever creates
+    /// the contract
+    pub fn with_entropy_seed(mut self, seed: Option<sha256::Hash>) -> Self {
+        self.shared_mut().entropy_seed = seed;
+        self
+    }
+    /// Get the seed `derive_entropy` derives from, if one was set
+    pub fn entropy_seed(&self) -> Option<sha256::Hash> {
+        self.shared.entropy_seed
+    }
+    /// Get the secp256k1 context shared by all contracts being compiled,
+    /// which guards and helpers should use rather than creating their own
+    pub fn secp(&self) -> &Arc<Secp256k1<All>> {
+        &self.shared.secp
+    }
+    /// Enable (the default) or disable policy checks, such as the size of
+    /// data outputs, which keep templates relayable on the default network,
+    /// e.g. to experiment on regtest
+    pub fn with_standardness_checks(mut self, enabled: bool) -> Self {
+        self.shared_mut().standardness_checks = enabled;
+        self
+    }
+    /// Are templates checked to be relayable by default policy?
+    pub fn standardness_checks(&self) -> bool {
+        self.shared.standardness_checks
+    }
+    /// Downgrade outputs below the dust limit from an error to a compiler
+    /// warning, e.g. to experiment on regtest
+    pub fn with_dust_as_warning(mut self, enabled: bool) -> Self {
+        self.shared_mut().dust_as_warning = enabled;
+        self
+    }
+    /// Are outputs below the dust limit only warned about?
+    pub fn dust_as_warning(&self) -> bool {
+        self.shared.dust_as_warning
+    }
+    /// Enable (the default) or disable failing compilation when a template
+    /// doesn't spend exactly its funds on outputs, fees and explicit burns
+    pub fn with_conservation_checks(mut self, enabled: bool) -> Self {
+        self.shared_mut().conservation_checks = enabled;
+        self
+    }
+    /// Must templates account for all of their funds?
+    pub fn conservation_checks(&self) -> bool {
+        self.shared.conservation_checks
+    }
+    /// Enable or disable (the default) simplifying clauses, e.g. dropping
+    /// duplicate guards, before they are compiled to scripts. This changes
+    /// the scripts, and so the address, of contracts with clauses which can
+    /// be simplified.
+    pub fn with_clause_simplification(mut self, enabled: bool) -> Self {
+        self.shared_mut().clause_simplification = enabled;
+        self
+    }
+    /// Are clauses simplified before they are compiled?
+    pub fn clause_simplification(&self) -> bool {
+        self.shared.clause_simplification
+    }
+    /// Set the kind of output contracts compile to, unless a contract picks
+    /// one itself with `Contract::script_target`
+    pub fn with_script_target(mut self, target: ScriptTarget) -> Self {
+        self.shared_mut().script_target = target;
+        self
+    }
+    /// The kind of output contracts compile to by default
+    pub fn script_target(&self) -> ScriptTarget {
+        self.shared.script_target
+    }
+    /// Use `key` wherever its x-only key is in a clause compiled for
+    /// `ScriptTarget::SegwitV0Only`. An x-only key drops the parity of y,
+    /// which a segwit v0 script needs, so keys without one are rejected.
+    pub fn with_segwit_v0_key(mut self, key: bitcoin::PublicKey) -> Self {
+        let x_only = key.inner.x_only_public_key().0;
+        self.shared_mut().segwit_v0_keys.insert(x_only, key);
+        self
+    }
+    /// The full keys set by `Context::with_segwit_v0_key`, by their x-only key
+    pub fn segwit_v0_keys(&self) -> &BTreeMap<XOnlyPublicKey, bitcoin::PublicKey> {
+        &self.shared.segwit_v0_keys
+    }
+    /// Set whether a branch which is a lone key (the happy path) is taken out
+    /// of the taproot tree to be the internal key, with BIP-341's NUMS point
+    /// as the internal key otherwise.
+    ///
+    /// Off by default, as enabling it changes the address of every contract
+    /// with a lone key branch or none. Without it the first lone key is the
+    /// internal key, with its branch kept in the tree too.
+    pub fn with_internal_key_promotion(mut self, enabled: bool) -> Self {
+        self.shared_mut().internal_key_promotion = enabled;
+        self
+    }
+    /// Is a lone key branch promoted to the taproot internal key?
+    pub fn internal_key_promotion(&self) -> bool {
+        self.shared.internal_key_promotion
+    }
+    /// Compile only the shape of the transaction tree, e.g. for a UI preview,
+    /// at the midpoint of the `declared` amounts if they are bounded.
+    ///
+    /// Guards are not evaluated, each standing in as `Clause::Unsatisfiable`,
+    /// so no scripts are compiled and every address is unspendable. CTV
+    /// clauses are not requested from the emulator, and modules are not
+    /// called, see `Context::stub_call`. Templates are generated as usual.
+    /// Every object compiled is marked as a skeleton, and refused by
+    /// `Object::bind_psbt` and anything else binding or funding it. See
+    /// `Context::compile_skeleton`.
+    pub fn dry_run(mut self, declared: AmountRange) -> Self {
+        self.shared_mut().dry_run = true;
+        if let Some(amount) = declared.midpoint() {
+            self.available_funds = amount;
+        }
+        self
+    }
+    /// Compile the scripts of each contract's branches in parallel, on the
+    /// rayon thread pool. The compiled contract is the same as without it.
+    ///
+    /// Only the scripts are compiled in parallel: branches, guards and nested
+    /// contracts are still evaluated in order, as contracts needn't be
+    /// `Send`. Requires the opt-in `parallel` feature, and has no effect
+    /// without it or on wasm32.
+    pub fn with_parallel_script_compilation(mut self) -> Self {
+        self.shared_mut().parallel_script_compilation = true;
+        self
+    }
+    /// Are scripts compiled in parallel, see
+    /// `Context::with_parallel_script_compilation`?
+    pub fn parallel_script_compilation(&self) -> bool {
+        self.shared.parallel_script_compilation
+    }
+    /// Leave out the leaves of each branch which can never be spent, e.g.
+    /// from a threshold of no keys, rather than only warning about them with
+    /// an `unreachable_branch` diagnostic. It is an error if that would leave
+    /// no way to spend a contract.
+    pub fn with_unreachable_pruning(mut self, enabled: bool) -> Self {
+        self.shared_mut().unreachable_pruning = enabled;
+        self
+    }
+    /// Are unreachable leaves pruned, see
+    /// `Context::with_unreachable_pruning`?
+    pub fn unreachable_pruning(&self) -> bool {
+        self.shared.unreachable_pruning
+    }
+    /// Record where the time compiling each contract goes, attaching a
+    /// `CompileProfile` to the top level contract compiled
+    pub fn with_profiling(mut self, enabled: bool) -> Self {
+        self.shared_mut().profiler = enabled.then(Profiler::default);
+        self
+    }
+    /// Is the time compiling each contract being recorded, see
+    /// `Context::with_profiling`?
+    pub fn profiling(&self) -> bool {
+        self.shared.profiler.is_some()
+    }
+    /// Measure time for `Context::with_profiling` with `clock` rather than
+    /// `Instant`, e.g. in a WASM plugin, which has no clock of its own
+    pub fn with_clock(mut self, clock: Clock) -> Self {
+        self.shared_mut().clock = Some(clock);
+        self
+    }
+    /// Start measuring `kind` at this Context's path, if profiling
+    pub(crate) fn span(&self, kind: SpanKind) -> Option<Span> {
+        let profiler = self.shared.profiler.as_ref()?;
+        let path = String::from(self.path.as_ref().clone());
+        Some(profiler.span(kind, path, self.shared.clock))
+    }
+    /// The profile of everything compiled since the last one was taken, if
+    /// profiling
+    pub(crate) fn take_profile(&self) -> Option<CompileProfile> {
+        self.shared.profiler.as_ref().map(Profiler::take)
+    }
+    /// Use `emulator` for the clauses of the templates of the contracts
+    /// compiled in this Context and those derived from it, e.g. for a subtree
+    /// which another oracle secures than the rest of a contract. Each of them
+    /// records which, see `Object::emulator`.
+    ///
+    /// A `CovenantBackend::Emulator` is replaced by `emulator` too, while a
+    /// `CovenantBackend::NativeCtv` still doesn't consult any emulator.
+    pub fn with_emulator(mut self, emulator: Arc<dyn CTVEmulator>) -> Self {
+        let shared = self.shared_mut();
+        if let Some(CovenantBackend::Emulator(_)) = shared.covenant_backend {
+            shared.covenant_backend = Some(CovenantBackend::Emulator(emulator.clone()));
+        }
+        shared.emulator_override = Some(
+            emulator
+                .describe()
+                .unwrap_or_else(|| "an undescribed emulator".into()),
+        );
+        shared.emulator = emulator;
+        self
+    }
+    /// The emulator the clauses of templates come from, unless a
+    /// `CovenantBackend` says otherwise, e.g. to compile a module with, see
+    /// `WasmPluginHandle::call_with_emulator`
+    pub fn emulator(&self) -> &Arc<dyn CTVEmulator> {
+        &self.shared.emulator
+    }
+    /// The emulator chosen with `Context::with_emulator`, if templates
+    /// compiled in this Context are secured by it
+    pub(crate) fn emulator_override(&self) -> Option<String> {
+        match self.shared.covenant_backend {
+            _ if self.is_dry_run() => None,
+            Some(CovenantBackend::NativeCtv) => None,
+            _ => self.shared.emulator_override.clone(),
+        }
+    }
+    /// Set how the templates contracts commit to are enforced, recording it
+    /// for each of them in the compiled object. Without a backend, the
+    /// Context's emulator alone decides, e.g. `CTVAvailable` for
+    /// `OP_CHECKTEMPLATEVERIFY`.
+    pub fn with_covenant_backend(mut self, backend: CovenantBackend) -> Self {
+        self.shared_mut().covenant_backend = Some(backend);
+        self
+    }
+    /// The backend set with `Context::with_covenant_backend`, if any
+    pub fn covenant_backend(&self) -> Option<&CovenantBackend> {
+        self.shared.covenant_backend.as_ref()
+    }
+    /// Set the `ResourceLimits` to compile with. The templates and bytes
+    /// compiled are counted across every Context derived from the same
+    /// `Context::new`.
+    pub fn with_resource_limits(mut self, limits: ResourceLimits) -> Self {
+        self.shared_mut().resource_limits = limits;
+        self
+    }
+    /// Get the `ResourceLimits` being compiled with
+    pub fn resource_limits(&self) -> ResourceLimits {
+        self.shared.resource_limits
+    }
+    /// Stop compiling, with a `CompilationError::Cancelled`, once `cancelled`
+    /// is set, e.g. by another thread once the contract is no longer needed
+    /// or is taking too long. It is checked before each contract and
+    /// template is compiled, and a contract which takes long otherwise may
+    /// check it with `Context::check_cancelled`.
+    pub fn with_cancellation(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.shared_mut().cancelled = Some(cancelled);
+        self
+    }
+    /// Fail if compiling has been cancelled, see `Context::with_cancellation`
+    pub fn check_cancelled(&self) -> Result<(), CompilationError> {
+        match self.shared.cancelled.as_ref() {
+            Some(c) if c.load(Ordering::Relaxed) => Err(CompilationError::Cancelled {
+                path: self.path.as_ref().clone(),
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Check that a contract compiled in this `Context` is within the
+    /// `ResourceLimits::max_depth`. A contract only derives a few fragments
+    /// deeper than the `Context` it is compiled in, so this is checked once
+    /// per contract rather than on every derivation.
+    pub(crate) fn check_depth(&self) -> Result<(), CompilationError> {
+        let max_depth = self.shared.resource_limits.max_depth;
+        if self.path.iter().nth(max_depth.saturating_sub(1)).is_some() {
+            return Err(CompilationError::ResourceLimitExceeded {
+                limit: ResourceLimit::Depth(max_depth),
+                path: self.path.as_ref().clone(),
+            });
         }
+        Ok(())
+    }
+    /// Count a compiled template against the `ResourceLimits`, attributing
+    /// any error to `path`
+    pub(crate) fn count_template(
+        &self,
+        template: &Template,
+        path: &EffectPath,
+    ) -> Result<(), CompilationError> {
+        self.check_cancelled()?;
+        let limits = self.shared.resource_limits;
+        let usage = &self.shared.resource_usage;
+        let exceeded = |limit| {
+            Err(CompilationError::ResourceLimitExceeded {
+                limit,
+                path: path.clone(),
+            })
+        };
+        if let Some(profiler) = self.shared.profiler.as_ref() {
+            profiler.count_template(String::from(path.clone()));
+        }
+        if usage.templates.fetch_add(1, Ordering::Relaxed) >= limits.max_templates {
+            return exceeded(ResourceLimit::Templates(limits.max_templates));
+        }
+        let size = template.tx.size();
+        if usage.bytes.fetch_add(size, Ordering::Relaxed) + size > limits.max_bytes {
+            return exceeded(ResourceLimit::Bytes(limits.max_bytes));
+        }
+        Ok(())
+    }
+    /// Is this a dry run, see `Context::dry_run`?
+    pub fn is_dry_run(&self) -> bool {
+        self.shared.dry_run
+    }
+    /// A placeholder for the contract `what` would create, e.g. a call to
+    /// another module, which a dry run skips. It pays to an unspendable key.
+    pub fn stub_call(&self, what: &str) -> Compiled {
+        crate::contract::compiler::stub_object(self, what)
+    }
+    /// Set the chain tip, as seen by whoever creates the contract, for guards
+    /// and `compile_if` functions which depend on the current block height or
+    /// median time past
+    pub fn with_chain_tip(
+        mut self,
+        tip_height: Option<AbsHeight>,
+        median_time: Option<AbsTime>,
+    ) -> Self {
+        let shared = self.shared_mut();
+        shared.tip_height = tip_height;
+        shared.median_time = median_time;
+        self
+    }
+    /// Get the height of the chain tip, erroring if it was not supplied
+    pub fn tip_height(&self) -> Result<AbsHeight, CompilationError> {
+        self.shared
+            .tip_height
+            .ok_or(CompilationError::MissingChainTip("tip_height"))
+    }
+    /// Get the median time past of the chain tip, erroring if it was not
+    /// supplied
+    pub fn median_time(&self) -> Result<AbsTime, CompilationError> {
+        self.shared
+            .median_time
+            .ok_or(CompilationError::MissingChainTip("median_time"))
+    }
+    /// Deterministically derive 32 bytes for `tag` from the entropy seed and
+    /// this Context's path, e.g. for tie-breaking or shuffling, so that the
+    /// same arguments always compile to the same contract.
+    ///
+    /// These bytes are *not* secret: anyone who knows the seed and the
+    /// contract can recompute them, so never use them as keys or nonces that
+    /// must stay private.
+    pub fn derive_entropy(&self, tag: &str) -> [u8; 32] {
+        // the bytes depend on the path
+        self.not_memoizable();
+        let mut engine = sha256::Hash::engine();
+        engine.input(b"sapio/derive_entropy");
+        match self.shared.entropy_seed {
+            Some(seed) => {
+                engine.input(&[1]);
+                engine.input(&seed[..]);
+            }
+            None => engine.input(&[0]),
+        }
+        let path: String = self.path.as_ref().clone().into();
+        engine.input(&(path.len() as u64).to_le_bytes());
+        engine.input(path.as_bytes());
+        engine.input(tag.as_bytes());
+        sha256::Hash::from_engine(engine).into_inner()
+    }
+    /// Get the network the contract is being compiled for
+    pub fn network(&self) -> Network {
+        self.network
+    }
+    /// Check that an address, e.g. a payout address from a contract's
+    /// arguments, is valid on the network being compiled for
+    pub fn check_address(&self, address: &bitcoin::Address) -> Result<(), CompilationError> {
+        if address.is_valid_for_network(self.network) {
+            Ok(())
+        } else {
+            Err(CompilationError::WrongNetwork(
+                self.network,
+                format!("address `{}`", address),
+            ))
+        }
+    }
+    /// Report a finding which doesn't stop the contract from compiling, e.g.
+    /// an argument which is allowed but likely a mistake. It is returned in
+    /// the `Object::diagnostics` of the contract being compiled, at this
+    /// Context's path.
+    pub fn warn(&self, code: &str, message: impl Into<String>) {
+        self.diagnose(DiagnosticLevel::Warning, code, message)
+    }
+    /// Report a finding at any `DiagnosticLevel`, see `Context::warn`
+    pub(crate) fn diagnose(&self, level: DiagnosticLevel, code: &str, message: impl Into<String>) {
+        self.diagnostics.push(Diagnostic {
+            level,
+            code: code.into(),
+            path: self.path.as_ref().clone(),
+            message: message.into(),
+        })
+    }
+    /// Start collecting diagnostics for a new contract, so that those of a
+    /// nested contract stay with it
+    pub(crate) fn fresh_diagnostics(&mut self) -> Diagnostics {
+        self.diagnostics = Default::default();
+        self.diagnostics.clone()
+    }
+    /// Mark the contract being compiled as depending on more than its
+    /// arguments, amount, network and effects, e.g. on its path, so that
+    /// neither it nor any contract it is nested in is `Memoized`.
+    ///
+    /// This is done for a contract using a `Guard::Fresh` or
+    /// `Context::derive_entropy` already.
+    pub fn not_memoizable(&self) {
+        self.not_memoizable.store(true, Ordering::Relaxed)
+    }
+    /// Compile the contract identified by `id` with `compile`, or re-path an
+    /// earlier compilation of it, see `Memoized`
+    pub(crate) fn memoized(
+        mut self,
+        id: &[&str],
+        compile: impl FnOnce(Context) -> Result<Compiled, CompilationError>,
+    ) -> Result<Compiled, CompilationError> {
+        let key = self.memo_key(id);
+        let hit = self.shared.memo.0.lock().unwrap().get(&key).cloned();
+        if let Some(compiled) = hit {
+            return compiled.rerooted(&self.path);
+        }
+        let outer = std::mem::take(&mut self.not_memoizable);
+        let inner = self.not_memoizable.clone();
+        let shared = self.shared.clone();
+        let compiled = compile(self)?;
+        if inner.load(Ordering::Relaxed) {
+            outer.store(true, Ordering::Relaxed);
+        } else {
+            shared.memo.0.lock().unwrap().insert(key, compiled.clone());
+        }
+        Ok(compiled)
+    }
+    /// Identifies compiling the contract identified by `id` in this
+    /// Context: its funds, network, and the effects under its path relative
+    /// to it
+    fn memo_key(&self, id: &[&str]) -> sha256::Hash {
+        let mut engine = sha256::Hash::engine();
+        let mut input = |bytes: &[u8]| {
+            engine.input(&(bytes.len() as u64).to_le_bytes());
+            engine.input(bytes);
+        };
+        for part in id {
+            input(part.as_bytes());
+        }
+        input(&self.available_funds.as_sat().to_le_bytes());
+        input(&self.network.magic().to_le_bytes());
+        let root = String::from(self.path.as_ref().clone());
+        for path in self.shared.effects.paths() {
+            if !path.has_prefix(&self.path) {
+                continue;
+            }
+            let path_string = String::from(path.as_ref().clone());
+            input(&path_string.as_bytes()[root.len()..]);
+            for (name, value) in self.shared.effects.get_value(path) {
+                input(name.as_bytes());
+                input(value.to_string().as_bytes());
+            }
+        }
+        sha256::Hash::from_engine(engine)
+    }
+    /// Was this Context created by `Context::new` rather than derived?
+    pub(crate) fn is_top_level(&self) -> bool {
+        self.top_level
     }
     /// Get this Context's effect database, for clients
     pub unsafe fn get_effects_internal(&self) -> &Arc<MapEffectDB> {
-        &self.effects
+        &self.shared.effects
     }
     /// Get this Context's effect database
     pub(crate) fn get_effects(&self, _: InternalCompilerTag) -> &Arc<MapEffectDB> {
-        &self.effects
+        &self.shared.effects
     }
     /// Gets this Context's Path, but does not clone (left to caller)
     pub fn path(&self) -> &Arc<EffectPath> {
         &self.path
     }
+    /// Gets a human readable label for this Context's Path, see
+    /// [`EffectPath::label`]. Like the path, it is stable across
+    /// recompilations of identical inputs.
+    pub fn label(&self) -> String {
+        self.path.label()
+    }
 
     /// Derive a new contextual path
     pub fn derive_str<'a>(&mut self, path: Arc<String>) -> Result<Self, CompilationError> {
@@ -76,6 +791,11 @@ impl Context {
             Err(CompilationError::InvalidPathName)
         }
     }
+    /// Derive a new contextual path with a human readable segment, which is
+    /// included in the `label` of this and any derived contexts
+    pub fn derive_labeled(&mut self, name: &str) -> Result<Self, CompilationError> {
+        self.derive_str(Arc::new(name.into()))
+    }
     /// Derive a new contextual path
     pub fn derive_num<T: Into<u64>>(&mut self, path: T) -> Result<Self, CompilationError> {
         self.derive(PathFragment::Branch(path.into()))
@@ -89,11 +809,13 @@ impl Context {
             let new_path = EffectPath::push(Some(self.path.clone()), path);
             Ok(Context {
                 available_funds: self.available_funds,
-                emulator: self.emulator.clone(),
                 path: new_path,
                 network: self.network,
                 already_derived: Default::default(),
-                effects: self.effects.clone(),
+                shared: self.shared.clone(),
+                top_level: false,
+                diagnostics: self.diagnostics.clone(),
+                not_memoizable: self.not_memoizable.clone(),
             })
         }
     }
@@ -102,15 +824,29 @@ impl Context {
     pub(crate) fn internal_clone(&self, _i: InternalCompilerTag) -> Self {
         Context {
             available_funds: self.available_funds,
-            emulator: self.emulator.clone(),
             path: self.path.clone(),
             network: self.network,
             already_derived: self.already_derived.clone(),
-            effects: self.effects.clone(),
+            shared: self.shared.clone(),
+            top_level: self.top_level,
+            diagnostics: self.diagnostics.clone(),
+            not_memoizable: self.not_memoizable.clone(),
         }
     }
 
-    /// return the available funds
+    /// return the available funds, i.e. the amount of the contract instance
+    /// being compiled.
+    ///
+    /// This is stable for use from guards and compile_ifs: it reflects any
+    /// `with_amount` (e.g., the amount passed to `Builder::add_output` for the
+    /// contract being compiled), is inherited by every derived context, and is
+    /// reduced by `spend_amount`.
+    ///
+    /// Note that this is the amount being compiled for, whereas the compiled
+    /// object's `AmountRange` tracks the range of amounts it is safe to send
+    /// (from `ensure_amount` and the templates it returns). A contract whose
+    /// guards branch on `funds` is only valid for the amount it was compiled
+    /// with, and should be recompiled if that changes.
     pub fn funds(&self) -> Amount {
         self.available_funds
     }
@@ -120,34 +856,108 @@ impl Context {
         &self,
         b: bitcoin::hashes::sha256::Hash,
     ) -> Result<sapio_base::Clause, CompilationError> {
-        Ok(self.emulator.get_signer_for(b)?)
+        if self.is_dry_run() {
+            return Ok(sapio_base::Clause::TxTemplate(b));
+        }
+        let emulated = |emulator: &Arc<dyn CTVEmulator>| {
+            let prefetched = self.shared.signers.0.lock().unwrap().get(&b).cloned();
+            match prefetched {
+                Some(c) => Ok(c),
+                None => {
+                    // remembered, so asking for the clause again doesn't
+                    // need another request
+                    let c = emulator.get_signer_for(b)?;
+                    self.shared.signers.0.lock().unwrap().insert(b, c.clone());
+                    Ok::<_, EmulatorError>(c)
+                }
+            }
+        };
+        Ok(match self.shared.covenant_backend.as_ref() {
+            None => emulated(&self.shared.emulator)?,
+            Some(CovenantBackend::NativeCtv) => sapio_base::Clause::TxTemplate(b),
+            Some(CovenantBackend::Emulator(emulator)) => emulated(emulator)?,
+            Some(CovenantBackend::Both) => sapio_base::Clause::Or(vec![
+                (1, sapio_base::Clause::TxTemplate(b)),
+                (1, emulated(&self.shared.emulator)?),
+            ]),
+        })
+    }
+
+    /// Ask the emulator `Context::ctv_emulator` would use for the clauses of
+    /// the templates `hashes` with `CTVEmulator::sign_batch`, in batches of
+    /// at most `MAX_SIGNER_BATCH`. Any which fail are asked for again one at
+    /// a time, to report their errors.
+    pub(crate) fn prefetch_signers(&self, hashes: &[sha256::Hash]) -> Result<(), CompilationError> {
+        let emulator = match self.shared.covenant_backend.as_ref() {
+            _ if self.is_dry_run() => return Ok(()),
+            Some(CovenantBackend::NativeCtv) => return Ok(()),
+            Some(CovenantBackend::Emulator(emulator)) => emulator,
+            None | Some(CovenantBackend::Both) => &self.shared.emulator,
+        };
+        let requests: Vec<_> = {
+            let cache = self.shared.signers.0.lock().unwrap();
+            hashes
+                .iter()
+                .filter(|h| !cache.contains_key(*h))
+                .map(|h| EmulatorRequest::SignerFor(*h))
+                .collect()
+        };
+        if requests.is_empty() {
+            return Ok(());
+        }
+        for requests in requests.chunks(MAX_SIGNER_BATCH) {
+            let responses = emulator.sign_batch(requests)?;
+            let mut cache = self.shared.signers.0.lock().unwrap();
+            for (request, response) in requests.iter().zip(responses) {
+                if let (EmulatorRequest::SignerFor(h), Ok(EmulatorResponse::SignerFor(c))) =
+                    (request, response)
+                {
+                    cache.insert(*h, c);
+                }
+            }
+        }
+        Ok(())
     }
 
     /// Compile the compilable item with this context.
     pub fn compile<A: Compilable>(self, a: A) -> Result<Compiled, CompilationError> {
-        a.compile(self)
+        let path = self.path().clone();
+        a.compile(self).at(&path)
+    }
+    /// Compile the compilable item with this context as a dry run, see
+    /// `Context::dry_run`, at the amount already set.
+    pub fn compile_skeleton<A: Compilable>(
+        mut self,
+        a: A,
+    ) -> Result<SkeletonObject, CompilationError> {
+        self.shared_mut().dry_run = true;
+        self.compile(a).map(SkeletonObject::new)
     }
 
     // TODO: Fix
     /// return a context with the new amount if amount is smaller or equal to available
     pub fn with_amount(self, amount: Amount) -> Result<Self, CompilationError> {
         if self.available_funds < amount {
-            Err(CompilationError::OutOfFunds)
+            Err(CompilationError::OutOfFunds {
+                path: self.path().as_ref().clone(),
+                needed: amount,
+                available: self.available_funds,
+            })
         } else {
             Ok(Context {
                 available_funds: amount,
-                emulator: self.emulator.clone(),
-                path: self.path.clone(),
-                network: self.network,
-                already_derived: self.already_derived.clone(),
-                effects: self.effects.clone(),
+                ..self
             })
         }
     }
     /// decrease the amount available in this context object.
     pub fn spend_amount(mut self, amount: Amount) -> Result<Self, CompilationError> {
         if self.available_funds < amount {
-            Err(CompilationError::OutOfFunds)
+            Err(CompilationError::OutOfFunds {
+                path: self.path().as_ref().clone(),
+                needed: amount,
+                available: self.available_funds,
+            })
         } else {
             self.available_funds -= amount;
             Ok(self)
@@ -165,3 +975,158 @@ impl Context {
         crate::template::Builder::new(self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::{Contract, TxTmplIt};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("context").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    /// a tree of identical halves, with `G` at each of its `2^depth` leaves
+    struct Halves(u32);
+    fn split_in_half(s: &Halves, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let half = Amount::from_sat(ctx.funds().as_sat() / 2);
+        let mut builder = ctx.template();
+        for _ in 0..2 {
+            builder = match s.0 {
+                1 => builder.add_output(half, &key, None)?,
+                depth => builder.add_output(half, &Halves(depth - 1), None)?,
+            };
+        }
+        builder.into()
+    }
+    impl Halves {
+        fn split<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: split_in_half,
+                    name: Arc::new("payout".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Halves {
+        declare! {then, Self::split}
+        declare! {non updatable}
+    }
+    #[test]
+    fn derived_contexts_copy_on_write() {
+        let mut root = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("root").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .with_feerate(Some(Amount::from_sat(2)));
+        let child = root.derive_num(0u64).unwrap();
+        assert!(Arc::ptr_eq(&root.shared, &child.shared));
+        let mut child = child.with_feerate(Some(Amount::from_sat(5)));
+        assert!(!Arc::ptr_eq(&root.shared, &child.shared));
+        assert_eq!(root.feerate(), Some(Amount::from_sat(2)));
+        let grandchild = child.derive_num(0u64).unwrap();
+        assert_eq!(grandchild.feerate(), Some(Amount::from_sat(5)));
+        let path: String = grandchild.path().as_ref().clone().into();
+        assert_eq!(path, "root/#0/#0");
+    }
+    /// a contract which recursively instantiates itself
+    struct Forever;
+    fn pay_forever(_: &Forever, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let amt = ctx.funds();
+        ctx.template().add_output(amt, &Forever, None)?.into()
+    }
+    impl Forever {
+        fn again<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: pay_forever,
+                    name: Arc::new("again".into()),
+                    fee_policy: FeePolicy::None,
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Forever {
+        declare! {then, Self::again}
+        declare! {non updatable}
+    }
+    #[test]
+    fn resource_limits() {
+        fn limit(e: CompilationError) -> (ResourceLimit, EffectPath) {
+            match e.root_cause() {
+                CompilationError::ResourceLimitExceeded { limit, path } => (*limit, path.clone()),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        // the sandboxed limits are hit before an 8MB stack runs out, even
+        // without optimizations
+        let sandboxed = std::thread::Builder::new()
+            .stack_size(8 << 20)
+            .spawn(|| {
+                Forever
+                    .compile(ctx().with_resource_limits(ResourceLimits::sandboxed()))
+                    .map(|_| ())
+                    .map_err(limit)
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        assert_eq!(sandboxed.unwrap_err().0, ResourceLimit::Depth(256));
+        let (exceeded, path) = Forever
+            .compile(ctx().with_resource_limits(ResourceLimits {
+                max_depth: 16,
+                ..Default::default()
+            }))
+            .map(|_| ())
+            .map_err(limit)
+            .unwrap_err();
+        assert_eq!(exceeded, ResourceLimit::Depth(16));
+        assert!(path.iter().nth(15).is_some());
+        // a tree of 15 templates
+        let exceeded = Halves(4)
+            .compile(ctx().with_resource_limits(ResourceLimits {
+                max_templates: 3,
+                ..Default::default()
+            }))
+            .map(|_| ())
+            .map_err(limit)
+            .unwrap_err()
+            .0;
+        assert_eq!(exceeded, ResourceLimit::Templates(3));
+        let exceeded = Halves(4)
+            .compile(ctx().with_resource_limits(ResourceLimits {
+                max_bytes: 1000,
+                ..Default::default()
+            }))
+            .map(|_| ())
+            .map_err(limit)
+            .unwrap_err()
+            .0;
+        assert_eq!(exceeded, ResourceLimit::Bytes(1000));
+    }
+}
@@ -7,13 +7,21 @@
 //! error types that can be returned from Sapio.
 //! Where possible, concrete error types are wrapped, but in order to handle
 //! errors created by the user we allow boxing an error trait.
+//! [`ErrorReport`] is the stable, serializable form of a [`CompilationError`]
+//! for passing errors to plugin hosts and clients.
+use crate::contract::actions::GuardCombinator;
+use crate::contract::object::Diagnostic;
 use crate::contract::object::ObjectError;
+use bitcoin::util::amount::Amount;
 use sapio_base::effects::EffectDBError;
 use sapio_base::effects::EffectPath;
 use sapio_base::effects::ValidFragmentError;
 use sapio_base::plugin_args::CreateArgs;
 use sapio_base::simp::SIMPError;
+use sapio_base::timelocks::LockMixError;
 use sapio_ctv_emulator_trait::EmulatorError;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 use std::collections::LinkedList;
 use std::error::Error;
 use std::fmt;
@@ -41,26 +49,178 @@ pub enum CompilationError {
     PathFragmentError(ValidFragmentError),
     /// Error when a `ThenFunc` returns no Templates.
     MissingTemplates,
+    /// Error if pruning unreachable branches would leave a contract with no
+    /// way to be spent, see `Context::with_unreachable_pruning`
+    AllBranchesUnreachable,
+    /// Error returned by the `func` of the named `ThenFunc` branch
+    BranchFailed(String, Box<CompilationError>),
+    /// Error from compiling at `path`, see `CompilationError::at`
+    At {
+        /// the path of the `Context` the error came from
+        path: EffectPath,
+        /// the error
+        inner: Box<CompilationError>,
+    },
+    /// Error if two `FinishOrFunc`s or `ThenFunc`s of a contract share a name
+    DuplicateFunctionName(String),
+    /// Error if warnings were denied, see `Object::deny_warnings`
+    DeniedWarnings(Vec<Diagnostic>),
+    /// Error if compiling at `path` would exceed one of the `Context`'s
+    /// `ResourceLimits`
+    ResourceLimitExceeded {
+        /// the limit exceeded
+        limit: ResourceLimit,
+        /// the path of the `Context` which would have exceeded it
+        path: EffectPath,
+    },
+    /// Error if compiling was stopped at `path`, see
+    /// `Context::with_cancellation`
+    Cancelled {
+        /// the path of the `Context` which was being compiled
+        path: EffectPath,
+    },
     /// Error if a Policy is empty
     EmptyPolicy,
-    /// Error if a contract does not have sufficient funds available
-    OutOfFunds,
+    /// Error if a `GuardCombinator` can never be met by the number of guards
+    /// it combines, e.g. `Any` of no guards.
+    InvalidGuardCombinator(GuardCombinator, usize),
+    /// Error if a `Guard::Async` is used but the `Context` has no
+    /// `GuardExecutor` (e.g., inside of a WASM plugin)
+    NoGuardExecutor,
+    /// Error if a contract at `path` does not have sufficient funds available
+    OutOfFunds {
+        /// the path of the `Context` the funds were requested from
+        path: EffectPath,
+        /// the amount requested
+        needed: Amount,
+        /// the amount available
+        available: Amount,
+    },
+    /// Error if the arguments for the contract at `path` don't match its
+    /// schema
+    SchemaError {
+        /// the path of the contract
+        path: EffectPath,
+        /// why the arguments don't match
+        message: String,
+    },
+    /// Error if the named branch's weight is not a positive number
+    InvalidBranchWeight(String, f64),
+    /// Error if arithmetic on amounts overflows or goes negative
+    AmountOverflow,
+    /// Error if a branch's `FeePolicy` reserves more than is available, with
+    /// the (reserved, available) amounts
+    FeeReservationExceedsFunds(bitcoin::util::amount::Amount, bitcoin::util::amount::Amount),
+    /// Error if the funds left in the named template can't pay its fee at
+    /// the `Context`'s feerate, with the amount short
+    FeeRateShortfall(String, bitcoin::util::amount::Amount),
+    /// Error if the funds left for `Builder::add_change` are below its
+    /// minimum, with the (remaining, minimum) amounts
+    ChangeBelowMinimum(bitcoin::util::amount::Amount, bitcoin::util::amount::Amount),
+    /// Error if something, e.g. a user supplied address, is not for the
+    /// network being compiled for
+    WrongNetwork(bitcoin::Network, String),
+    /// Error if a template's transaction version is not relayed by default
+    NonStandardVersion(i32),
+    /// Error if a data (OP_RETURN) output would make a template non-standard,
+    /// with the reason
+    NonStandardDataOutput(String),
+    /// Error if an output of the template at `path` is worth less than the
+    /// dust limit for its script
+    OutputBelowDust {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the index of the output
+        index: usize,
+        /// the amount sent to the output
+        amount: bitcoin::util::amount::Amount,
+        /// the dust limit for the output's script
+        limit: bitcoin::util::amount::Amount,
+    },
+    /// Error if the template at `path` doesn't spend exactly the funds it was
+    /// given on outputs, fees and explicit burns
+    UnaccountedFunds {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the funds left over, negative if the template spends more than it
+        /// was given
+        missing: bitcoin::util::amount::SignedAmount,
+    },
+    /// Error if a contract nested at `path` can't receive the amount it is
+    /// funded with
+    IncompatibleAmountRange {
+        /// the path of the nested contract
+        path: EffectPath,
+        /// the amounts the nested contract can receive
+        accepted: Box<crate::util::amountrange::AmountRange>,
+        /// the amounts it is funded with
+        funded: Box<crate::util::amountrange::AmountRange>,
+    },
+    /// Error if chain tip information (`tip_height` or `median_time`) was
+    /// required but not supplied to the Context
+    MissingChainTip(&'static str),
     /// Error if a CheckSequenceVerify clause is incompatible with the sequence already set.
     /// E.g., blocks and time
     IncompatibleSequence,
     /// Error if a CheckLockTime clause is incompatible with the locktime already set.
     /// E.g., blocks and time
     IncompatibleLockTime,
+    /// Error if a satisfaction of the template at `path`, with its nLockTime,
+    /// needs absolute lock times in both blocks and seconds
+    IncompatibleTimeLocks {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the height lock
+        height: sapio_base::timelocks::AbsHeight,
+        /// the time lock
+        time: sapio_base::timelocks::AbsTime,
+    },
+    /// Error if a satisfaction of the template at `path` needs relative lock
+    /// times in both blocks and seconds on the same input
+    IncompatibleRelativeTimeLocks {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the height lock
+        height: sapio_base::timelocks::RelHeight,
+        /// the time lock
+        time: sapio_base::timelocks::RelTime,
+    },
     /// Error if a sequence at index j >= inputs.len() is attempted to be set
     NoSuchSequence,
     /// Error if parsing an Amount failed
     ParseAmountError(bitcoin::util::amount::ParseAmountError),
     /// Error from the Policy Compiler
     Miniscript(miniscript::policy::compiler::CompilerError),
+    /// Error from the Policy Compiler for a particular `Clause`
+    ClauseCompilationFailed {
+        /// the `Clause`, see `sapio_base::clause::ToPolicyString`
+        clause: String,
+        /// why it could not be compiled
+        error: miniscript::policy::compiler::CompilerError,
+    },
+    /// Error if a `Clause` compiles for taproot but not for segwit v0, e.g.
+    /// as its script would exceed segwit v0's limits, while compiling for
+    /// `ScriptTarget::SegwitV0Only`
+    TaprootOnlyClause {
+        /// the `Clause`, see `sapio_base::clause::ToPolicyString`
+        clause: String,
+        /// why it could not be compiled for segwit v0
+        error: miniscript::policy::compiler::CompilerError,
+    },
+    /// Error if a `Clause` compiled for `ScriptTarget::SegwitV0Only` has an
+    /// x-only key without a full key from `Context::with_segwit_v0_key`
+    SegwitV0KeyParityUnknown {
+        /// the `Clause`, see `sapio_base::clause::ToPolicyString`
+        clause: String,
+        /// the key
+        key: bitcoin::XOnlyPublicKey,
+    },
     /// Error from the miniscript system
     MiniscriptE(miniscript::Error),
     /// Error with a Timelock
     TimeLockError(sapio_base::timelocks::LockTimeError),
+    /// Error building a `#[guard(weighted_threshold = k)]`
+    WeightedThreshold(sapio_base::clause::WeightedThresholdError),
     /// Error creating an object,
     CompiledObjectError(ObjectError),
     /// Failure in conditional compilation logic
@@ -77,6 +237,13 @@ pub enum CompilationError {
     InvalidModule,
     /// Module failed internally
     InternalModuleError(String),
+    /// Error returned by the named module, e.g. while creating a contract
+    ModuleError {
+        /// the module, as its hex key
+        module: String,
+        /// the module's error
+        inner: Box<ErrorReport>,
+    },
     /// Failed to get module memory
     ModuleFailedToGetMemory(ErrT),
     /// Module failed to allocate
@@ -86,13 +253,15 @@ pub enum CompilationError {
     /// Module Failed to Deallocate
     ModuleCouldNotDeallocate(i32, ErrT),
     /// Module failed to create
-    ModuleCouldNotCreateContract(EffectPath, CreateArgs<serde_json::Value>, ErrT),
+    ModuleCouldNotCreateContract(EffectPath, Box<CreateArgs<serde_json::Value>>, ErrT),
     /// Module failed to get_api
     ModuleCouldNotGetAPI(ErrT),
     /// Module failed to get_logo
     ModuleCouldNotGetLogo(ErrT),
     /// Module failed to get_name
     ModuleCouldNotGetName(ErrT),
+    /// Module failed to get_info
+    ModuleCouldNotGetInfo(ErrT),
     /// Module hit an error at runtime
     ModuleRuntimeError(ErrT),
     /// API Check Failed, module didn't satisfy examples.
@@ -139,6 +308,77 @@ impl CompilationError {
     pub fn custom<E: std::error::Error + 'static>(e: E) -> Self {
         CompilationError::Custom(Box::new(e))
     }
+    /// Attribute `inner` to compiling at `path`, unless it is already
+    /// attributed to a path, which is deeper as errors propagate up.
+    pub fn at(path: &EffectPath, inner: CompilationError) -> Self {
+        match inner {
+            e @ CompilationError::At { .. } => e,
+            e => CompilationError::At {
+                path: path.clone(),
+                inner: Box::new(e),
+            },
+        }
+    }
+    /// The error for a template at `path` whose satisfaction mixes the
+    /// kinds of lock in `mix`
+    pub fn mixed_time_locks(path: EffectPath, mix: LockMixError) -> Self {
+        match mix {
+            LockMixError::Absolute(height, time) => {
+                CompilationError::IncompatibleTimeLocks { path, height, time }
+            }
+            LockMixError::Relative(height, time) => {
+                CompilationError::IncompatibleRelativeTimeLocks { path, height, time }
+            }
+        }
+    }
+    /// `inner` from the named branch. Any path stays outermost, so that an
+    /// error from nested branches renders with one path and every branch
+    /// name.
+    pub fn in_branch(name: String, inner: CompilationError) -> Self {
+        match inner {
+            CompilationError::At { path, inner } => CompilationError::At {
+                path,
+                inner: Box::new(CompilationError::BranchFailed(name, inner)),
+            },
+            e => CompilationError::BranchFailed(name, Box::new(e)),
+        }
+    }
+    /// The path the error is attributed to, if any
+    pub fn path(&self) -> Option<&EffectPath> {
+        match self {
+            CompilationError::At { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+    /// The error which caused this one, under every path and branch it is
+    /// attributed to, e.g. to match on its kind
+    pub fn root_cause(&self) -> &CompilationError {
+        match self {
+            CompilationError::At { inner, .. } | CompilationError::BranchFailed(_, inner) => {
+                inner.root_cause()
+            }
+            e => e,
+        }
+    }
+    /// The error without the path it is attributed to
+    pub fn without_path(self) -> CompilationError {
+        match self {
+            CompilationError::At { inner, .. } => *inner,
+            e => e,
+        }
+    }
+}
+
+/// Attribute the error of a `Result` to a path, see `CompilationError::at`
+pub trait AtPath<T> {
+    /// Attribute any error to compiling at `path`
+    fn at(self, path: &EffectPath) -> Result<T, CompilationError>;
+}
+
+impl<T, E: Into<CompilationError>> AtPath<T> for Result<T, E> {
+    fn at(self, path: &EffectPath) -> Result<T, CompilationError> {
+        self.map_err(|e| CompilationError::at(path, e.into()))
+    }
 }
 
 impl From<bitcoin::util::amount::ParseAmountError> for CompilationError {
@@ -152,6 +392,11 @@ impl From<sapio_base::timelocks::LockTimeError> for CompilationError {
         CompilationError::TimeLockError(b)
     }
 }
+impl From<sapio_base::clause::WeightedThresholdError> for CompilationError {
+    fn from(b: sapio_base::clause::WeightedThresholdError) -> Self {
+        CompilationError::WeightedThreshold(b)
+    }
+}
 impl From<miniscript::policy::compiler::CompilerError> for CompilationError {
     fn from(v: miniscript::policy::compiler::CompilerError) -> Self {
         CompilationError::Miniscript(v)
@@ -170,14 +415,737 @@ impl From<ObjectError> for CompilationError {
 
 impl fmt::Display for CompilationError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            CompilationError::ConditionalCompilationFailed(reasons) => {
+                write!(f, "Conditional Compilation Failed: ")?;
+                for (i, reason) in reasons.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, "; ")?;
+                    }
+                    write!(f, "{}", reason)?;
+                }
+                Ok(())
+            }
+            CompilationError::BranchFailed(name, e) => write!(f, "branch `{}` failed: {}", name, e),
+            CompilationError::At { path, inner } => {
+                write!(f, "at `{}`: {}", String::from(path.clone()), inner)
+            }
+            CompilationError::FeeRateShortfall(name, short) => {
+                write!(f, "template `{}` is {} short of its fee", name, short)
+            }
+            CompilationError::AmountOverflow => write!(f, "amount arithmetic overflowed"),
+            CompilationError::InvalidBranchWeight(name, weight) => {
+                write!(
+                    f,
+                    "branch `{}` has weight {}, which is not positive",
+                    name, weight
+                )
+            }
+            CompilationError::ChangeBelowMinimum(remaining, min) => {
+                write!(f, "change of {} is below the minimum of {}", remaining, min)
+            }
+            CompilationError::WrongNetwork(network, what) => {
+                write!(f, "{} is not valid on network {}", what, network)
+            }
+            CompilationError::NonStandardVersion(v) => {
+                write!(f, "transaction version {} is non-standard", v)
+            }
+            CompilationError::NonStandardDataOutput(why) => {
+                write!(f, "data output is non-standard: {}", why)
+            }
+            CompilationError::OutputBelowDust {
+                path,
+                index,
+                amount,
+                limit,
+            } => write!(
+                f,
+                "output {} of template at `{}` sends {}, below the dust limit of {}",
+                index,
+                String::from(path.clone()),
+                amount,
+                limit
+            ),
+            CompilationError::UnaccountedFunds { path, missing } => write!(
+                f,
+                "template at `{}` leaves {} unaccounted for",
+                String::from(path.clone()),
+                missing
+            ),
+            CompilationError::IncompatibleTimeLocks { path, height, time } => write!(
+                f,
+                "template at `{}` needs both a lock until height {} and until time {}",
+                String::from(path.clone()),
+                height.get(),
+                time.get()
+            ),
+            CompilationError::IncompatibleRelativeTimeLocks { path, height, time } => write!(
+                f,
+                "template at `{}` needs both a relative lock of {} blocks and of {} seconds",
+                String::from(path.clone()),
+                height.units(),
+                time.units() as u32 * 512
+            ),
+            CompilationError::ClauseCompilationFailed { clause, error } => {
+                write!(f, "could not compile `{}`: {}", clause, error)
+            }
+            CompilationError::TaprootOnlyClause { clause, error } => {
+                write!(
+                    f,
+                    "`{}` can only be compiled for taproot: {}",
+                    clause, error
+                )
+            }
+            CompilationError::SegwitV0KeyParityUnknown { clause, key } => write!(
+                f,
+                "`{}` has no full key for {} to compile for segwit v0",
+                clause, key
+            ),
+            CompilationError::IncompatibleAmountRange {
+                path,
+                accepted,
+                funded,
+            } => write!(
+                f,
+                "contract at `{}` accepts {:?} but is funded with {:?}",
+                String::from(path.clone()),
+                accepted,
+                funded
+            ),
+            CompilationError::MissingChainTip(what) => {
+                write!(f, "`{}` was not supplied to the Context", what)
+            }
+            CompilationError::DuplicateFunctionName(name) => {
+                write!(f, "more than one function named `{}`", name)
+            }
+            CompilationError::OutOfFunds {
+                path,
+                needed,
+                available,
+            } => write!(
+                f,
+                "contract at `{}` needs {} but only {} is available",
+                String::from(path.clone()),
+                needed,
+                available
+            ),
+            CompilationError::SchemaError { path, message } => write!(
+                f,
+                "arguments for contract at `{}` don't match its schema: {}",
+                String::from(path.clone()),
+                message
+            ),
+            CompilationError::ModuleError { module, inner } => {
+                write!(f, "module `{}` failed: {}", module, inner)
+            }
+            CompilationError::DeniedWarnings(warnings) => write_denied(f, warnings),
+            CompilationError::ResourceLimitExceeded { limit, path } => write!(
+                f,
+                "contract at `{}` exceeds the limit of {}",
+                String::from(path.clone()),
+                limit
+            ),
+            CompilationError::Cancelled { path } => write!(
+                f,
+                "compilation was cancelled at `{}`",
+                String::from(path.clone())
+            ),
+            CompilationError::AllBranchesUnreachable => {
+                write!(f, "every branch of the contract can never be spent")
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl Error for CompilationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CompilationError::BranchFailed(_, e) | CompilationError::At { inner: e, .. } => {
+                Some(e.as_ref())
+            }
+            CompilationError::ModuleError { inner, .. } => Some(inner.as_ref()),
+            CompilationError::PathFragmentError(e) => Some(e),
+            CompilationError::ParseAmountError(e) => Some(e),
+            CompilationError::Miniscript(e)
+            | CompilationError::ClauseCompilationFailed { error: e, .. }
+            | CompilationError::TaprootOnlyClause { error: e, .. } => Some(e),
+            CompilationError::MiniscriptE(e) => Some(e),
+            CompilationError::TimeLockError(e) => Some(e),
+            CompilationError::CompiledObjectError(e) => Some(e),
+            CompilationError::EffectDBError(EffectDBError::SerializationError(e))
+            | CompilationError::SerializationError(e)
+            | CompilationError::DeserializationError(e) => Some(e),
+            CompilationError::SIMPError(e) => Some(e),
+            CompilationError::ModuleFailedToGetMemory(e)
+            | CompilationError::ModuleCouldNotAllocateError(_, e)
+            | CompilationError::ModuleCouldNotDeallocate(_, e)
+            | CompilationError::ModuleCouldNotCreateContract(_, _, e)
+            | CompilationError::ModuleCouldNotGetAPI(e)
+            | CompilationError::ModuleCouldNotGetLogo(e)
+            | CompilationError::ModuleCouldNotGetName(e)
+            | CompilationError::ModuleCouldNotGetInfo(e)
+            | CompilationError::ModuleRuntimeError(e)
+            | CompilationError::Custom(e) => Some(e.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Serializes as the [`ErrorReport`] for the error
+impl Serialize for CompilationError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ErrorReport::from(self).serialize(serializer)
+    }
+}
+
+/// The stable, serializable form of a [`CompilationError`], so that a client
+/// can tell which kind of error it got. Errors without a structured form
+/// here are reported as `Custom`, with their message.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "kind", content = "content", rename_all = "snake_case")]
+pub enum ErrorReport {
+    /// see `CompilationError::TerminateCompilation`
+    TerminateCompilation,
+    /// see `CompilationError::TerminateWith`
+    TerminateWith(String),
+    /// see `CompilationError::OutOfFunds`
+    OutOfFunds {
+        /// the path of the `Context` the funds were requested from
+        path: EffectPath,
+        /// the amount requested
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        needed: Amount,
+        /// the amount available
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        available: Amount,
+    },
+    /// see `CompilationError::SchemaError`
+    SchemaError {
+        /// the path of the contract
+        path: EffectPath,
+        /// why the arguments don't match
+        message: String,
+    },
+    /// see `CompilationError::AmountOverflow`
+    AmountOverflow,
+    /// see `CompilationError::MinFeerateError`
+    MinFeerateError,
+    /// see `CompilationError::FeeReservationExceedsFunds`
+    FeeReservationExceedsFunds {
+        /// the amount reserved
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        reserved: Amount,
+        /// the amount available
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        available: Amount,
+    },
+    /// see `CompilationError::FeeRateShortfall`
+    FeeRateShortfall {
+        /// the template's name
+        template: String,
+        /// the amount short
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        short: Amount,
+    },
+    /// see `CompilationError::ChangeBelowMinimum`
+    ChangeBelowMinimum {
+        /// the amount remaining
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        remaining: Amount,
+        /// the minimum change
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        minimum: Amount,
+    },
+    /// see `CompilationError::OutputBelowDust`
+    OutputBelowDust {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the index of the output
+        index: usize,
+        /// the amount sent to the output
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        amount: Amount,
+        /// the dust limit for the output's script
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "u64")]
+        limit: Amount,
+    },
+    /// see `CompilationError::UnaccountedFunds`
+    UnaccountedFunds {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the funds left over, negative if the template spends more than it
+        /// was given
+        #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+        #[schemars(with = "i64")]
+        missing: bitcoin::util::amount::SignedAmount,
+    },
+    /// see `CompilationError::IncompatibleAmountRange`
+    IncompatibleAmountRange {
+        /// the path of the nested contract
+        path: EffectPath,
+        /// the amounts the nested contract can receive
+        accepted: crate::util::amountrange::AmountRange,
+        /// the amounts it is funded with
+        funded: crate::util::amountrange::AmountRange,
+    },
+    /// see `CompilationError::WrongNetwork`
+    WrongNetwork {
+        /// the network being compiled for
+        #[schemars(with = "String")]
+        network: bitcoin::Network,
+        /// what was for another network
+        what: String,
+    },
+    /// see `CompilationError::MissingChainTip`
+    MissingChainTip(String),
+    /// an error with a lock time or sequence, see
+    /// `CompilationError::TimeLockError`
+    TimeLockError(String),
+    /// see `CompilationError::IncompatibleTimeLocks`
+    IncompatibleTimeLocks {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the height lock
+        height: u32,
+        /// the time lock
+        time: u32,
+    },
+    /// see `CompilationError::IncompatibleRelativeTimeLocks`
+    IncompatibleRelativeTimeLocks {
+        /// the path of the `Context` the template was built in
+        path: EffectPath,
+        /// the height lock, in blocks
+        height: u16,
+        /// the time lock, in units of 512 seconds
+        time: u16,
+    },
+    /// see `CompilationError::ClauseCompilationFailed`
+    ClauseCompilationFailed {
+        /// the `Clause`
+        clause: String,
+        /// why it could not be compiled
+        message: String,
+    },
+    /// see `CompilationError::TaprootOnlyClause`
+    TaprootOnlyClause {
+        /// the `Clause`
+        clause: String,
+        /// why it could not be compiled for segwit v0
+        message: String,
+    },
+    /// see `CompilationError::SegwitV0KeyParityUnknown`
+    SegwitV0KeyParityUnknown {
+        /// the `Clause`
+        clause: String,
+        /// the x-only key, in hex
+        key: String,
+    },
+    /// see `CompilationError::BranchFailed`
+    BranchFailed {
+        /// the branch's name
+        branch: String,
+        /// the branch's error
+        inner: Box<ErrorReport>,
+    },
+    /// see `CompilationError::At`
+    At {
+        /// the path of the `Context` the error came from
+        path: EffectPath,
+        /// the error
+        inner: Box<ErrorReport>,
+    },
+    /// see `CompilationError::DuplicateFunctionName`
+    DuplicateFunctionName(String),
+    /// see `CompilationError::ConditionalCompilationFailed`
+    ConditionalCompilationFailed(Vec<String>),
+    /// see `CompilationError::DeniedWarnings`
+    DeniedWarnings(Vec<Diagnostic>),
+    /// see `CompilationError::AllBranchesUnreachable`
+    AllBranchesUnreachable,
+    /// see `CompilationError::ResourceLimitExceeded`
+    ResourceLimitExceeded {
+        /// the limit exceeded
+        limit: ResourceLimit,
+        /// the path of the `Context` which would have exceeded it
+        path: EffectPath,
+    },
+    /// see `CompilationError::Cancelled`
+    Cancelled {
+        /// the path of the `Context` which was being compiled
+        path: EffectPath,
+    },
+    /// see `CompilationError::UnknownModule`
+    UnknownModule,
+    /// see `CompilationError::ModuleError`
+    ModuleError {
+        /// the module, as its hex key
+        module: String,
+        /// the module's error
+        inner: Box<ErrorReport>,
+    },
+    /// Any other error, by its message
+    Custom(String),
+}
+
+impl From<&CompilationError> for ErrorReport {
+    fn from(e: &CompilationError) -> Self {
+        match e {
+            CompilationError::TerminateCompilation => ErrorReport::TerminateCompilation,
+            CompilationError::TerminateWith(s) => ErrorReport::TerminateWith(s.clone()),
+            CompilationError::OutOfFunds {
+                path,
+                needed,
+                available,
+            } => ErrorReport::OutOfFunds {
+                path: path.clone(),
+                needed: *needed,
+                available: *available,
+            },
+            CompilationError::SchemaError { path, message } => ErrorReport::SchemaError {
+                path: path.clone(),
+                message: message.clone(),
+            },
+            CompilationError::AmountOverflow => ErrorReport::AmountOverflow,
+            CompilationError::MinFeerateError => ErrorReport::MinFeerateError,
+            CompilationError::FeeReservationExceedsFunds(reserved, available) => {
+                ErrorReport::FeeReservationExceedsFunds {
+                    reserved: *reserved,
+                    available: *available,
+                }
+            }
+            CompilationError::FeeRateShortfall(template, short) => ErrorReport::FeeRateShortfall {
+                template: template.clone(),
+                short: *short,
+            },
+            CompilationError::ChangeBelowMinimum(remaining, minimum) => {
+                ErrorReport::ChangeBelowMinimum {
+                    remaining: *remaining,
+                    minimum: *minimum,
+                }
+            }
+            CompilationError::OutputBelowDust {
+                path,
+                index,
+                amount,
+                limit,
+            } => ErrorReport::OutputBelowDust {
+                path: path.clone(),
+                index: *index,
+                amount: *amount,
+                limit: *limit,
+            },
+            CompilationError::UnaccountedFunds { path, missing } => ErrorReport::UnaccountedFunds {
+                path: path.clone(),
+                missing: *missing,
+            },
+            CompilationError::IncompatibleAmountRange {
+                path,
+                accepted,
+                funded,
+            } => ErrorReport::IncompatibleAmountRange {
+                path: path.clone(),
+                accepted: **accepted,
+                funded: **funded,
+            },
+            CompilationError::WrongNetwork(network, what) => ErrorReport::WrongNetwork {
+                network: *network,
+                what: what.clone(),
+            },
+            CompilationError::MissingChainTip(what) => {
+                ErrorReport::MissingChainTip(what.to_string())
+            }
+            CompilationError::TimeLockError(_)
+            | CompilationError::IncompatibleSequence
+            | CompilationError::IncompatibleLockTime
+            | CompilationError::NoSuchSequence => ErrorReport::TimeLockError(e.to_string()),
+            CompilationError::IncompatibleTimeLocks { path, height, time } => {
+                ErrorReport::IncompatibleTimeLocks {
+                    path: path.clone(),
+                    height: height.get(),
+                    time: time.get(),
+                }
+            }
+            CompilationError::IncompatibleRelativeTimeLocks { path, height, time } => {
+                ErrorReport::IncompatibleRelativeTimeLocks {
+                    path: path.clone(),
+                    height: height.units(),
+                    time: time.units(),
+                }
+            }
+            CompilationError::ClauseCompilationFailed { clause, error } => {
+                ErrorReport::ClauseCompilationFailed {
+                    clause: clause.clone(),
+                    message: error.to_string(),
+                }
+            }
+            CompilationError::TaprootOnlyClause { clause, error } => {
+                ErrorReport::TaprootOnlyClause {
+                    clause: clause.clone(),
+                    message: error.to_string(),
+                }
+            }
+            CompilationError::SegwitV0KeyParityUnknown { clause, key } => {
+                ErrorReport::SegwitV0KeyParityUnknown {
+                    clause: clause.clone(),
+                    key: key.to_string(),
+                }
+            }
+            CompilationError::BranchFailed(branch, inner) => ErrorReport::BranchFailed {
+                branch: branch.clone(),
+                inner: Box::new(inner.as_ref().into()),
+            },
+            CompilationError::At { path, inner } => ErrorReport::At {
+                path: path.clone(),
+                inner: Box::new(inner.as_ref().into()),
+            },
+            CompilationError::DuplicateFunctionName(name) => {
+                ErrorReport::DuplicateFunctionName(name.clone())
+            }
+            CompilationError::ConditionalCompilationFailed(reasons) => {
+                ErrorReport::ConditionalCompilationFailed(reasons.iter().cloned().collect())
+            }
+            CompilationError::DeniedWarnings(warnings) => {
+                ErrorReport::DeniedWarnings(warnings.clone())
+            }
+            CompilationError::ResourceLimitExceeded { limit, path } => {
+                ErrorReport::ResourceLimitExceeded {
+                    limit: *limit,
+                    path: path.clone(),
+                }
+            }
+            CompilationError::Cancelled { path } => ErrorReport::Cancelled { path: path.clone() },
+            CompilationError::AllBranchesUnreachable => ErrorReport::AllBranchesUnreachable,
+            CompilationError::UnknownModule => ErrorReport::UnknownModule,
+            CompilationError::ModuleError { module, inner } => ErrorReport::ModuleError {
+                module: module.clone(),
+                inner: inner.clone(),
+            },
+            _ => ErrorReport::Custom(e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ErrorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorReport::TerminateWith(s)
+            | ErrorReport::TimeLockError(s)
+            | ErrorReport::Custom(s) => write!(f, "{}", s),
+            ErrorReport::OutOfFunds {
+                path,
+                needed,
+                available,
+            } => write!(
+                f,
+                "contract at `{}` needs {} but only {} is available",
+                String::from(path.clone()),
+                needed,
+                available
+            ),
+            ErrorReport::SchemaError { path, message } => write!(
+                f,
+                "arguments for contract at `{}` don't match its schema: {}",
+                String::from(path.clone()),
+                message
+            ),
+            ErrorReport::ClauseCompilationFailed { clause, message } => {
+                write!(f, "could not compile `{}`: {}", clause, message)
+            }
+            ErrorReport::TaprootOnlyClause { clause, message } => write!(
+                f,
+                "`{}` can only be compiled for taproot: {}",
+                clause, message
+            ),
+            ErrorReport::SegwitV0KeyParityUnknown { clause, key } => write!(
+                f,
+                "`{}` has no full key for {} to compile for segwit v0",
+                clause, key
+            ),
+            ErrorReport::BranchFailed { branch, inner } => {
+                write!(f, "branch `{}` failed: {}", branch, inner)
+            }
+            ErrorReport::ModuleError { module, inner } => {
+                write!(f, "module `{}` failed: {}", module, inner)
+            }
+            ErrorReport::At { path, inner } => {
+                write!(f, "at `{}`: {}", String::from(path.clone()), inner)
+            }
+            ErrorReport::DeniedWarnings(warnings) => write_denied(f, warnings),
+            ErrorReport::ResourceLimitExceeded { limit, path } => write!(
+                f,
+                "contract at `{}` exceeds the limit of {}",
+                String::from(path.clone()),
+                limit
+            ),
+            ErrorReport::Cancelled { path } => write!(
+                f,
+                "compilation was cancelled at `{}`",
+                String::from(path.clone())
+            ),
+            ErrorReport::AllBranchesUnreachable => {
+                write!(f, "every branch of the contract can never be spent")
+            }
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+/// Which of the `Context`'s `ResourceLimits` was exceeded
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ResourceLimit {
+    /// `ResourceLimits::max_depth`
+    Depth(usize),
+    /// `ResourceLimits::max_templates`
+    Templates(usize),
+    /// `ResourceLimits::max_bytes`
+    Bytes(usize),
+}
+
+impl fmt::Display for ResourceLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResourceLimit::Depth(n) => write!(f, "{} path fragments", n),
+            ResourceLimit::Templates(n) => write!(f, "{} templates", n),
+            ResourceLimit::Bytes(n) => write!(f, "{} bytes of transactions", n),
+        }
+    }
+}
+
+fn write_denied(f: &mut fmt::Formatter<'_>, warnings: &[Diagnostic]) -> fmt::Result {
+    write!(f, "warnings are denied: ")?;
+    for (i, w) in warnings.iter().enumerate() {
+        if i > 0 {
+            write!(f, "; ")?;
+        }
+        write!(f, "{}", w)?;
     }
+    Ok(())
 }
 
-impl Error for CompilationError {}
+impl Error for ErrorReport {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ErrorReport::BranchFailed { inner, .. }
+            | ErrorReport::ModuleError { inner, .. }
+            | ErrorReport::At { inner, .. } => Some(inner.as_ref()),
+            _ => None,
+        }
+    }
+}
 
 impl From<EmulatorError> for CompilationError {
     fn from(e: EmulatorError) -> Self {
         CompilationError::Custom(Box::new(e))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Context;
+    use sapio_base::effects::MapEffectDB;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    fn round_trip(e: &CompilationError) -> ErrorReport {
+        let report: ErrorReport = serde_json::from_value(serde_json::to_value(e).unwrap()).unwrap();
+        assert_eq!(report, ErrorReport::from(e));
+        report
+    }
+    #[test]
+    fn reports_round_trip() {
+        let ctx = Context::new(
+            bitcoin::Network::Regtest,
+            Amount::from_sat(1000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("error").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let out_of_funds = match ctx.spend_amount(Amount::from_sat(1001)) {
+            Err(e) => e,
+            Ok(_) => panic!("spent more than available"),
+        };
+        assert_eq!(
+            serde_json::to_value(&out_of_funds).unwrap(),
+            serde_json::json!({
+                "kind": "out_of_funds",
+                "content": {"path": "error", "needed": 1001, "available": 1000}
+            })
+        );
+        round_trip(&out_of_funds);
+        round_trip(&CompilationError::TerminateCompilation);
+        round_trip(&CompilationError::SchemaError {
+            path: EffectPath::try_from("error").unwrap(),
+            message: "missing field `price`".into(),
+        });
+        round_trip(&CompilationError::TimeLockError(
+            sapio_base::timelocks::LockTimeError::HeightTooHigh(600_000_000),
+        ));
+        round_trip(&CompilationError::Cancelled {
+            path: EffectPath::try_from("error").unwrap(),
+        });
+        // anything unstructured keeps its message
+        assert_eq!(
+            round_trip(&CompilationError::NoGuardExecutor),
+            ErrorReport::Custom("NoGuardExecutor".into())
+        );
+    }
+    #[test]
+    fn nested_module_errors() {
+        let out_of_funds = ErrorReport::OutOfFunds {
+            path: EffectPath::try_from("inner").unwrap(),
+            needed: Amount::from_sat(2),
+            available: Amount::from_sat(1),
+        };
+        let e = CompilationError::ModuleError {
+            module: "outer".into(),
+            inner: Box::new(ErrorReport::BranchFailed {
+                branch: "spend".into(),
+                inner: Box::new(ErrorReport::ModuleError {
+                    module: "inner".into(),
+                    inner: Box::new(out_of_funds.clone()),
+                }),
+            }),
+        };
+        match round_trip(&e) {
+            ErrorReport::ModuleError { module, inner } => {
+                assert_eq!(module, "outer");
+                match *inner {
+                    ErrorReport::BranchFailed { inner, .. } => assert_eq!(
+                        *inner,
+                        ErrorReport::ModuleError {
+                            module: "inner".into(),
+                            inner: Box::new(out_of_funds.clone()),
+                        }
+                    ),
+                    r => panic!("unexpected {:?}", r),
+                }
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        let mut sources = vec![];
+        let mut source = e.source();
+        while let Some(s) = source {
+            sources.push(s.to_string());
+            source = s.source();
+        }
+        assert_eq!(sources.len(), 3);
+        assert_eq!(sources[2], out_of_funds.to_string());
+        assert_eq!(
+            e.to_string(),
+            "module `outer` failed: branch `spend` failed: module `inner` failed: \
+             contract at `inner` needs 0.00000002 BTC but only 0.00000001 BTC is available"
+        );
+    }
+}
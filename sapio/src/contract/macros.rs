@@ -6,6 +6,7 @@
 
 //! macros for making defining Sapio contracts less verbose.
 
+pub use bitcoin::Network;
 use core::any::TypeId;
 pub use paste::paste;
 
@@ -63,7 +64,7 @@ macro_rules! declare {
         /// sufficient to unlock funds, a `Guard` should not be bound if it is
         /// intended to be used with a `ThenFunc`.
         /// Any fn() which returns None is ignored (useful for type-level state machines)
-        const FINISH_FNS: &'static [fn() -> Option<$crate::contract::actions::Guard<Self>>] = &[$($a,)*];
+        const FINISH_FNS: &'static [$crate::contract::actions::GuardGen<Self>] = &[$($crate::contract::actions::GuardGen::Fn($a),)*];
     };
 
 
@@ -127,12 +128,12 @@ pub fn get_schema_for<T: schemars::JsonSchema + 'static + Sized>() -> Arc<Value>
 macro_rules! web_api {
     {$name:ident,$type:ty,{}} => {
         $crate::contract::macros::paste!{
-            const [<CONTINUE_SCHEMA_FOR_ $name:upper >] : Option<&'static dyn Fn() -> std::sync::Arc<$crate::schemars::schema::RootSchema>> = Some(&|| $crate::contract::macros::get_schema_for::<$type>());
+            const [<CONTINUE_SCHEMA_FOR_ $name:upper >] : Option<&'static dyn Fn() -> std::sync::Arc<serde_json::Value>> = Some(&|| $crate::contract::macros::get_schema_for::<$type>());
         }
     };
     {$name:ident,$type:ty} => {
         $crate::contract::macros::paste!{
-            const [<CONTINUE_SCHEMA_FOR_ $name:upper >] : Option<&'static dyn Fn() -> std::sync::Arc<$crate::schemars::schema::RootSchema>> = None;
+            const [<CONTINUE_SCHEMA_FOR_ $name:upper >] : Option<&'static dyn Fn() -> std::sync::Arc<serde_json::Value>> = None;
         }
     }
 }
@@ -15,11 +15,11 @@ pub use abi::object;
 pub mod actions;
 pub mod compiler;
 pub mod error;
-pub use error::CompilationError;
+pub use error::{CompilationError, ErrorReport};
 pub mod context;
 use bitcoin::util::amount::Amount;
-pub use compiler::Compilable;
-pub use context::Context;
+pub use compiler::{Compilable, Memoized};
+pub use context::{Context, CovenantBackend, ResourceLimits, ScriptTarget};
 pub use object::Object as Compiled;
 
 /// An Iterator which yields TransactionTemplates.
@@ -56,6 +56,12 @@ where
     fn ensure_amount(&self, _ctx: Context) -> Result<Amount, CompilationError> {
         Ok(Amount::from_sat(0))
     }
+
+    /// the kind of output to compile this contract to, by default the one
+    /// set on the `Context`
+    fn script_target(&self, ctx: &Context) -> ScriptTarget {
+        ctx.script_target()
+    }
 }
 
 /// DynamicContract wraps a struct S with a set of methods (that can be constructed dynamically)
@@ -66,7 +72,7 @@ pub struct DynamicContract<'a, T, S> {
     /// the list of `FinishOrFunc` for this contract.
     pub finish_or: Vec<fn() -> Option<Box<dyn actions::CallableAsFoF<S, T>>>>,
     /// the list of `Guard` for this contract to finish.
-    pub finish: Vec<fn() -> Option<actions::Guard<S>>>,
+    pub finish: Vec<actions::GuardGen<S>>,
     /// A metadata generator function
     pub metadata_f: Box<dyn (Fn(&S, Context) -> Result<ObjectMetadata, CompilationError>)>,
     /// A min amount generator function
@@ -95,7 +101,7 @@ where
     ) -> &'a [fn() -> Option<Box<dyn actions::CallableAsFoF<S, Self::StatefulArguments>>>] {
         &self.finish_or[..]
     }
-    fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<S>>] {
+    fn finish_fns<'a>(&'a self) -> &'a [actions::GuardGen<S>] {
         &self.finish[..]
     }
     fn get_inner_ref<'a>(&self) -> &Self::Ref {
@@ -112,9 +118,25 @@ where
 }
 
 /// Catch all trait for things `StatefulArguments` must be required to do.
-pub trait StatefulArgumentsTrait: Default {}
+pub trait StatefulArgumentsTrait: Default {
+    /// Applies a JSON Merge Patch (RFC 7396) onto the serialized arguments,
+    /// so that a caller may update only the fields which changed before
+    /// `coerce_args` runs. A `null` in the patch removes that field, and
+    /// fields unknown to `Self` are rejected if it uses `deny_unknown_fields`.
+    fn merge(self, patch: serde_json::Value) -> Result<Self, CompilationError>
+    where
+        Self: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let mut current =
+            serde_json::to_value(self).map_err(CompilationError::SerializationError)?;
+        crate::util::merge_patch::merge_patch(&mut current, patch);
+        serde_json::from_value(current).map_err(CompilationError::DeserializationError)
+    }
+}
 impl StatefulArgumentsTrait for () {}
 impl<T> StatefulArgumentsTrait for Option<T> {}
+/// Allows any `SpecificArgs` to be coerced via `actions::coerce_json`
+impl StatefulArgumentsTrait for serde_json::Value {}
 
 /// AnyContract is a generic API for types which can be compiled, encapsulating default static
 /// Contracts as well as DynamicContracts/DynamicContractRefs.
@@ -145,13 +167,17 @@ where
         &'a self,
     ) -> &'a [fn() -> Option<Box<dyn actions::CallableAsFoF<Self::Ref, Self::StatefulArguments>>>];
     /// obtain a reference to the `Guard` list.
-    fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self::Ref>>];
+    fn finish_fns<'a>(&'a self) -> &'a [actions::GuardGen<Self::Ref>];
     /// obtain a reference to `Self::Ref` type.
     fn get_inner_ref<'a>(&'a self) -> &'a Self::Ref;
     /// Generate the metadata
     fn metadata<'a>(&'a self, ctx: Context) -> Result<ObjectMetadata, CompilationError>;
     /// Minimum Amount
     fn ensure_amount<'a>(&'a self, ctx: Context) -> Result<Amount, CompilationError>;
+    /// The kind of output to compile to
+    fn script_target(&self, ctx: &Context) -> ScriptTarget {
+        ctx.script_target()
+    }
 }
 
 impl<C> AnyContract for C
@@ -177,7 +203,7 @@ where
     {
         Self::FINISH_OR_FUNCS
     }
-    fn finish_fns<'a>(&'a self) -> &'a [fn() -> Option<actions::Guard<Self::Ref>>] {
+    fn finish_fns<'a>(&'a self) -> &'a [actions::GuardGen<Self::Ref>] {
         Self::FINISH_FNS
     }
     fn get_inner_ref<'a>(&'a self) -> &Self::Ref {
@@ -190,4 +216,119 @@ where
     fn ensure_amount<'a>(&'a self, ctx: Context) -> Result<Amount, CompilationError> {
         Self::Ref::ensure_amount(self, ctx)
     }
+    fn script_target(&self, ctx: &Context) -> ScriptTarget {
+        Self::Ref::script_target(self, ctx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::actions::{Guard, GuardFn, GuardGen};
+    use super::*;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{KeyPair, Network, XOnlyPublicKey};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::Clause;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug)]
+    #[serde(deny_unknown_fields)]
+    struct SaleInfo {
+        price: u64,
+        extra: Option<String>,
+    }
+    /// mirrors the NFT plugin's `Sell`
+    #[derive(serde::Serialize, serde::Deserialize, PartialEq, Debug, Default)]
+    enum Sell {
+        #[default]
+        Hold,
+        MakeSale {
+            sale_info: SaleInfo,
+        },
+    }
+    impl StatefulArgumentsTrait for Sell {}
+    #[test]
+    fn merge_patches_arguments() {
+        let sale = Sell::default()
+            .merge(serde_json::json!({"MakeSale": {"sale_info": {"price": 10, "extra": "x"}}}))
+            .unwrap();
+        assert_eq!(
+            sale,
+            Sell::MakeSale {
+                sale_info: SaleInfo {
+                    price: 10,
+                    extra: Some("x".into())
+                }
+            }
+        );
+        // only the price changes, and null removes the optional field
+        let sale = sale
+            .merge(serde_json::json!({"MakeSale": {"sale_info": {"price": 20, "extra": null}}}))
+            .unwrap();
+        assert_eq!(
+            sale,
+            Sell::MakeSale {
+                sale_info: SaleInfo {
+                    price: 20,
+                    extra: None
+                }
+            }
+        );
+        let unknown = sale.merge(serde_json::json!({"MakeSale": {"sale_info": {"cost": 1}}}));
+        assert!(matches!(
+            unknown,
+            Err(CompilationError::DeserializationError(e)) if e.to_string().contains("cost")
+        ));
+    }
+    /// A 2-of-n multisig whose key set is only known at runtime
+    struct Multisig;
+    impl Multisig {
+        fn new(n: u8) -> (Vec<XOnlyPublicKey>, DynamicContract<'static, (), Multisig>) {
+            let secp = Secp256k1::new();
+            let keys: Vec<XOnlyPublicKey> = (1..=n)
+                .map(|i| KeyPair::from_seckey_slice(&secp, &[i; 32]).unwrap())
+                .map(|k| XOnlyPublicKey::from_keypair(&k).0)
+                .collect();
+            let captured = keys.clone();
+            let guard = GuardGen::Boxed(Box::new(move || {
+                let keys = captured.clone();
+                Some(Guard::Fresh(
+                    GuardFn::Boxed(Box::new(move |_, _| {
+                        Clause::Threshold(2, keys.iter().cloned().map(Clause::Key).collect())
+                    })),
+                    None,
+                ))
+            }));
+            let contract = DynamicContract {
+                then: vec![],
+                finish_or: vec![],
+                finish: vec![guard],
+                metadata_f: Box::new(|_, _| Ok(Default::default())),
+                ensure_amount_f: Box::new(|_, _| Ok(Amount::from_sat(0))),
+                data: Multisig,
+            };
+            (keys, contract)
+        }
+    }
+    #[test]
+    fn guard_captures_runtime_keys() {
+        let (keys, contract) = Multisig::new(3);
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("multisig").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let compiled = contract.compile(ctx).unwrap();
+        let descriptor = compiled.descriptor.unwrap();
+        let descriptor = match descriptor {
+            object::SupportedDescriptors::XOnly(d) => d.to_string(),
+            object::SupportedDescriptors::Pk(d) => d.to_string(),
+        };
+        for key in keys {
+            assert!(descriptor.contains(&key.to_string()));
+        }
+    }
 }
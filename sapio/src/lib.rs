@@ -10,6 +10,7 @@
 
 #[macro_use]
 pub mod contract;
+pub mod analysis;
 pub mod template;
 pub mod util;
 pub use contract::Context;
@@ -5,13 +5,19 @@
 //  file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 //! Interactive Transaction Template Builder
-use super::input::InputMetadata;
-pub use super::{Output, OutputMeta};
-use super::{Template, TemplateMetadata};
-use crate::contract::{CompilationError, Context};
+use super::input::{ExternalInput, InputMetadata, PrevoutSpec};
+use super::{Commitment, Template, TemplateMetadata};
+pub use super::{Output, OutputMeta, ANCHOR_METADATA_KEY, EXTERNAL_METADATA_KEY};
+use crate::contract::error::AtPath;
+use crate::contract::{CompilationError, Compiled, Context, Contract};
+use crate::util::amountrange::AmountRange;
+use crate::util::extended_address::ExtendedAddress;
+use bitcoin::hashes::sha256;
+use bitcoin::hashes::{Hash, HashEngine};
 use bitcoin::util::amount::Amount;
 use bitcoin::VarInt;
 use bitcoin::Witness;
+use sapio_base::clause::MixedTimeLocks;
 use sapio_base::effects::PathFragment;
 use sapio_base::simp::SIMPAttachableAt;
 use sapio_base::simp::TemplateInputLT;
@@ -22,12 +28,96 @@ use sapio_base::Clause;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 
+/// The most data a standard OP_RETURN output may carry
+pub const MAX_DATA_CARRIER_BYTES: usize = 80;
+
+/// The transaction versions relayed by default
+pub const STANDARD_VERSIONS: [i32; 3] = [1, 2, 3];
+
+/// The default value of a keyed anchor output, above the dust limit of any
+/// standard output type
+pub const DEFAULT_ANCHOR_SATS: u64 = 330;
+
+/// Who may spend an anchor output to bump a template's fee
+#[derive(Clone, Debug)]
+pub enum AnchorTo {
+    /// a taproot output spendable by this key
+    Key(bitcoin::XOnlyPublicKey),
+    /// an arbitrary script
+    Script(bitcoin::Script),
+    /// the keyless `pay_to_anchor` script, which anyone can spend
+    PayToAnchor,
+}
+
+/// the pay-to-anchor script, `OP_1 <0x4e73>`
+pub fn pay_to_anchor() -> bitcoin::Script {
+    bitcoin::Script::from(vec![0x51, 0x02, 0x4e, 0x73])
+}
+
+/// The order a Builder's outputs are put in when it is finalized.
+///
+/// The CTV hash commits to the order of the outputs, so sorting them changes
+/// the template's hash, and therefore the address of the contract returning
+/// it. Metadata and nested contracts move with their outputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum OutputOrdering {
+    /// the order the outputs were added in
+    #[default]
+    Insertion,
+    /// BIP-69: ascending by amount, then by scriptPubKey bytes
+    Bip69,
+    /// pseudo-random, keyed by a seed such as one from
+    /// `Context::derive_entropy`. The order depends only on the seed and the
+    /// outputs, not on the order they were added in.
+    Deterministic([u8; 32]),
+}
+
+impl OutputOrdering {
+    fn sort(&self, outputs: &mut [Output]) {
+        let script = |o: &Output| bitcoin::Script::from(o.contract.address.clone()).into_bytes();
+        match self {
+            OutputOrdering::Insertion => {}
+            OutputOrdering::Bip69 => outputs.sort_by_cached_key(|o| (o.amount, script(o))),
+            OutputOrdering::Deterministic(seed) => outputs.sort_by_cached_key(|o| {
+                let mut engine = sha256::Hash::engine();
+                engine.input(seed);
+                engine.input(&o.amount.as_sat().to_le_bytes());
+                engine.input(&script(o));
+                sha256::Hash::from_engine(engine)
+            }),
+        }
+    }
+}
+
+/// the vbytes a change output adds to a transaction: nested contracts compile
+/// to taproot, so an 8 byte amount and a 34 byte script with its length
+const CHANGE_OUTPUT_VBYTES: u64 = 8 + 1 + 34;
+
+/// How the nSequence of one of a Builder's inputs was set
+#[derive(Clone, Copy)]
+enum InputSequence {
+    /// a relative lock time, merged with any others set
+    Lock(AnyRelTimeLock),
+    /// an exact value from `set_raw_sequence`
+    Raw(Sequence),
+}
+
+/// does `seq` enforce at least the relative lock `lock`?
+fn enforces(seq: Sequence, lock: AnyRelTimeLock) -> bool {
+    match (seq.relative_lock(), lock) {
+        (Some(a @ AnyRelTimeLock::RH(_)), b @ AnyRelTimeLock::RH(_)) => a >= b,
+        (Some(a @ AnyRelTimeLock::RT(_)), b @ AnyRelTimeLock::RT(_)) => a >= b,
+        _ => false,
+    }
+}
+
 /// Builder can be used to interactively put together a transaction template before
 /// finalizing into a Template.
 pub struct Builder {
     guards: Vec<Clause>,
     // TODO: Should be Comitted/Uncomitted if not CTV
-    sequences: Vec<Option<AnyRelTimeLock>>,
+    sequences: Vec<Option<InputSequence>>,
+    rbf: bool,
     outputs: Vec<Output>,
     inputs: Vec<InputMetadata>,
     version: i32,
@@ -35,6 +125,10 @@ pub struct Builder {
     ctx: Context,
     fees: Amount,
     min_feerate: Option<Amount>,
+    commitment: Commitment,
+    ordering: OutputOrdering,
+    rate_fee_added: bool,
+    external_funds: Amount,
     // Metadata Fields:
     metadata: TemplateMetadata,
 }
@@ -45,6 +139,7 @@ impl Builder {
         Builder {
             guards: Vec::new(),
             sequences: vec![None],
+            rbf: false,
             inputs: vec![InputMetadata::default()],
             outputs: vec![],
             version: 2,
@@ -52,6 +147,10 @@ impl Builder {
             metadata: TemplateMetadata::new(),
             fees: Amount::from_sat(0),
             min_feerate: None,
+            commitment: Commitment::Committed,
+            ordering: OutputOrdering::Insertion,
+            rate_fee_added: false,
+            external_funds: Amount::from_sat(0),
             ctx,
         }
     }
@@ -74,6 +173,83 @@ impl Builder {
         Ok(c)
     }
 
+    /// Acknowledge that `amount` of the funds is deliberately left to miners,
+    /// e.g. because the contract burns it. Unless conservation checks are
+    /// disabled on the `Context`, compilation fails if a template doesn't spend
+    /// all of its funds on outputs, fees, or burns.
+    pub fn explicitly_burn(self, amount: Amount) -> Result<Self, CompilationError> {
+        self.add_fees(amount)
+    }
+
+    /// reduce the amount available in the builder's context by the fee for
+    /// the template at the `Context`'s feerate, and add to the fees. Call once
+    /// all the outputs with fixed amounts have been added, as the fee is
+    /// estimated from the template as built so far.
+    ///
+    /// Does nothing if no feerate was set.
+    pub fn add_fee_from_rate(self) -> Result<Self, CompilationError> {
+        let rate = match self.ctx.feerate() {
+            Some(rate) => rate,
+            None => return Ok(self),
+        };
+        let vbytes = self.estimate_weight().div_ceil(4);
+        let fee = Amount::from_sat(rate.as_sat() * vbytes);
+        let available = self.ctx.funds();
+        if fee > available {
+            let name = self
+                .metadata
+                .label
+                .clone()
+                .unwrap_or_else(|| self.ctx.path().as_ref().clone().into());
+            return Err(CompilationError::FeeRateShortfall(name, fee - available));
+        }
+        let mut ret = self.add_fees(fee)?;
+        ret.rate_fee_added = true;
+        Ok(ret)
+    }
+
+    /// Sends whatever is left of the funds, after the outputs and fees so far,
+    /// to `instance`, erroring if that is less than `min`. If nothing is left
+    /// (and `min` is zero) no output is added, and if what is left is below
+    /// the dust limit it all goes to fees instead.
+    ///
+    /// If the `Context` has a feerate, the fee for the template so far is
+    /// added first unless `add_fee_from_rate` already was, and the fee for
+    /// the change output itself is deducted from it. Add the change last, as
+    /// there are no funds left for anything after it.
+    pub fn add_change<T: Contract>(
+        self,
+        instance: &T,
+        min: Amount,
+    ) -> Result<Self, CompilationError> {
+        let mut ret = match self.ctx.feerate() {
+            Some(_) if !self.rate_fee_added => self.add_fee_from_rate()?,
+            _ => self,
+        };
+        let available = ret.ctx.funds();
+        let fee = ret
+            .ctx
+            .feerate()
+            .map_or(Amount::from_sat(0), |rate| rate * CHANGE_OUTPUT_VBYTES);
+        let remaining = available
+            .checked_sub(fee)
+            .unwrap_or_else(|| Amount::from_sat(0));
+        if remaining < min {
+            return Err(CompilationError::ChangeBelowMinimum(remaining, min));
+        }
+        if remaining == Amount::from_sat(0) {
+            return Ok(ret);
+        }
+        // a compiled contract always pays to a 32 byte witness program
+        let dust =
+            bitcoin::Script::new_v0_p2wsh(&bitcoin::WScriptHash::from_inner([0; 32])).dust_value();
+        if remaining < dust {
+            return ret.add_fees(available);
+        }
+        ret = ret.add_fees(fee)?;
+        ret.add_output(remaining, instance, None)
+    }
+
     /// Creates a new Output, forcing the compilation of the compilable object and defaulting
     /// metadata if not provided to blank.
     pub fn add_output(
@@ -86,19 +262,67 @@ impl Builder {
             .ctx
             .derive(PathFragment::Branch(self.outputs.len() as u64))?
             .with_amount(amount)?;
+        let path = subctx.path().as_ref().clone();
+        let contract = contract.compile(subctx).at(&path)?;
         let mut ret = self.spend_amount(amount)?;
         ret.outputs.push(Output {
             amount,
-            contract: contract.compile(subctx)?,
+            contract,
             added_metadata: metadata.unwrap_or_default(),
         });
         Ok(ret)
     }
 
+    /// Creates a new Output like `add_output`, annotated with `metadata`, e.g.
+    /// a label for GUIs to render. Metadata is not committed to by the
+    /// template's hash.
+    pub fn add_output_with_meta(
+        self,
+        amount: Amount,
+        contract: &dyn crate::contract::Compilable,
+        metadata: OutputMeta,
+    ) -> Result<Self, CompilationError> {
+        self.add_output(amount, contract, Some(metadata))
+    }
+
+    /// Creates a zero value OP_RETURN output carrying `data`, e.g. to commit
+    /// to some provenance information.
+    ///
+    /// Unless the `Context` has standardness checks disabled, `data` may be at
+    /// most `MAX_DATA_CARRIER_BYTES` long and only one data output may be
+    /// added per template.
+    pub fn add_data_output(mut self, data: &[u8]) -> Result<Self, CompilationError> {
+        if self.ctx.standardness_checks() {
+            if data.len() > MAX_DATA_CARRIER_BYTES {
+                return Err(CompilationError::NonStandardDataOutput(format!(
+                    "{} bytes exceeds the {} byte limit",
+                    data.len(),
+                    MAX_DATA_CARRIER_BYTES
+                )));
+            }
+            if self
+                .outputs
+                .iter()
+                .any(|o| matches!(o.contract.address, ExtendedAddress::OpReturn(_)))
+            {
+                return Err(CompilationError::NonStandardDataOutput(
+                    "only one is relayed per transaction".into(),
+                ));
+            }
+        }
+        self.outputs.push(Output {
+            amount: Amount::from_sat(0),
+            contract: Compiled::from_data_carrier(data),
+            added_metadata: Default::default(),
+        });
+        Ok(self)
+    }
+
     /// adds available funds to the builder's context object.
     /// TODO: Make guarantee there is some external input?
     pub fn add_amount(mut self, a: Amount) -> Self {
         self.ctx = self.ctx.add_amount(a);
+        self.external_funds += a;
         self
     }
 
@@ -109,6 +333,28 @@ impl Builder {
         self.inputs.push(Default::default());
         self
     }
+    /// Adds an input, other than the contract's own, which the wallet
+    /// spending the template must contribute, e.g. for a dual-funded sale or
+    /// to pay fees. The input's sequence is committed to like any other.
+    ///
+    /// `amount_hint` is only recorded for the wallet; call `add_amount` if the
+    /// template should spend the contributed funds.
+    pub fn add_sequenced_input(
+        mut self,
+        prevout: PrevoutSpec,
+        sequence: Option<AnyRelTimeLock>,
+        amount_hint: Option<Amount>,
+    ) -> Self {
+        self.sequences.push(sequence.map(InputSequence::Lock));
+        self.inputs.push(InputMetadata {
+            external: Some(ExternalInput {
+                prevout,
+                amount_hint,
+            }),
+            ..Default::default()
+        });
+        self
+    }
     /// set_sequence adds a height or time based relative lock time to the
     /// template. If a lock time is already set, it will check if it is of the
     /// same kind. Differing kinds will throw an error. Otherwise, it will merge
@@ -116,29 +362,92 @@ impl Builder {
     ///
     /// Negative indexing allows us to work from the back element easily
     pub fn set_sequence(mut self, ii: isize, s: AnyRelTimeLock) -> Result<Self, CompilationError> {
-        let i = if ii >= 0 {
-            ii
-        } else {
-            self.sequences.len() as isize + ii
-        } as usize;
+        let i = self.input_index(ii);
         match self.sequences.get_mut(i).as_mut() {
-            Some(Some(seq)) => match (*seq, s) {
-                (a @ AnyRelTimeLock::RH(_), b @ AnyRelTimeLock::RH(_)) => {
-                    *seq = std::cmp::max(a, b);
-                }
-                (a @ AnyRelTimeLock::RT(_), b @ AnyRelTimeLock::RT(_)) => {
-                    *seq = std::cmp::max(a, b);
+            Some(Some(InputSequence::Lock(seq))) => {
+                let combined = check_compatible(&[(*seq).into(), s.into()])
+                    .or(Err(CompilationError::IncompatibleSequence))?;
+                *seq = match (combined.rel_height, combined.rel_time) {
+                    (Some(h), _) => h.into(),
+                    (_, Some(t)) => t.into(),
+                    (None, None) => unreachable!("two relative locks were given"),
+                };
+            }
+            Some(Some(InputSequence::Raw(raw))) => {
+                if !enforces(*raw, s) {
+                    return Err(CompilationError::IncompatibleSequence);
                 }
-                _ => return Err(CompilationError::IncompatibleSequence),
-            },
+            }
             Some(x @ None) => {
-                x.replace(s);
+                x.replace(InputSequence::Lock(s));
             }
             None => return Err(CompilationError::NoSuchSequence),
         };
         Ok(self)
     }
 
+    /// set_relative_lock is `set_sequence` for a `RelHeight` or `RelTime`
+    pub fn set_relative_lock<L: Into<AnyRelTimeLock>>(
+        self,
+        ii: isize,
+        lock: L,
+    ) -> Result<Self, CompilationError> {
+        self.set_sequence(ii, lock.into())
+    }
+
+    /// set_raw_sequence sets the exact nSequence of an input, e.g.
+    /// `Sequence::MAX` to opt out of replaceability.
+    ///
+    /// Errors if the input already has a relative lock time that `s` does not
+    /// enforce, a different raw sequence, or if `s` does not signal
+    /// replaceability after a call to `set_rbf`.
+    pub fn set_raw_sequence(mut self, ii: isize, s: Sequence) -> Result<Self, CompilationError> {
+        if self.rbf && !s.signals_rbf() {
+            return Err(CompilationError::IncompatibleSequence);
+        }
+        let i = self.input_index(ii);
+        match self.sequences.get_mut(i) {
+            Some(Some(InputSequence::Raw(raw))) if *raw != s => {
+                Err(CompilationError::IncompatibleSequence)
+            }
+            Some(Some(InputSequence::Lock(lock))) if !enforces(s, *lock) => {
+                Err(CompilationError::IncompatibleSequence)
+            }
+            Some(x) => {
+                x.replace(InputSequence::Raw(s));
+                Ok(self)
+            }
+            None => Err(CompilationError::NoSuchSequence),
+        }
+    }
+
+    /// set_rbf ensures the template signals replaceability per BIP125, e.g.
+    /// for a suggested template a wallet may want to fee bump.
+    ///
+    /// Inputs with a relative lock time or the default sequence already
+    /// signal, so this only errors if a raw sequence which does not was set.
+    pub fn set_rbf(mut self) -> Result<Self, CompilationError> {
+        let opted_out = self
+            .sequences
+            .iter()
+            .any(|s| matches!(s, Some(InputSequence::Raw(raw)) if !raw.signals_rbf()));
+        if opted_out {
+            return Err(CompilationError::IncompatibleSequence);
+        }
+        self.rbf = true;
+        Ok(self)
+    }
+
+    /// resolve a possibly negative input index, which counts from the back
+    fn input_index(&self, ii: isize) -> usize {
+        let i = if ii >= 0 {
+            ii
+        } else {
+            self.sequences.len() as isize + ii
+        };
+        i as usize
+    }
+
     /// attempts to add a SIMP to the output meta.
     ///
     /// Returns [`SIMPError::AlreadyDefined`] if one was previously set.
@@ -147,11 +456,7 @@ impl Builder {
         ii: isize,
         s: S,
     ) -> Result<Self, CompilationError> {
-        let i = if ii >= 0 {
-            ii
-        } else {
-            self.sequences.len() as isize + ii
-        } as usize;
+        let i = self.input_index(ii);
         match self.inputs.get_mut(i) {
             Some(r) => {
                 r.add_simp_inplace(s)?;
@@ -166,21 +471,118 @@ impl Builder {
     /// by taking the max of the argument.
     pub fn set_lock_time(mut self, lt_in: AnyAbsTimeLock) -> Result<Self, CompilationError> {
         if let Some(lt) = self.lock_time.as_mut() {
-            match (*lt, lt_in) {
-                (a @ AnyAbsTimeLock::AH(_), b @ AnyAbsTimeLock::AH(_)) => {
-                    *lt = std::cmp::max(a, b);
-                }
-                (a @ AnyAbsTimeLock::AT(_), b @ AnyAbsTimeLock::AT(_)) => {
-                    *lt = std::cmp::max(a, b);
-                }
-                _ => return Err(CompilationError::IncompatibleSequence),
-            }
+            let combined = check_compatible(&[(*lt).into(), lt_in.into()])
+                .or(Err(CompilationError::IncompatibleLockTime))?;
+            *lt = match (combined.abs_height, combined.abs_time) {
+                (Some(h), _) => h.into(),
+                (_, Some(t)) => t.into(),
+                (None, None) => unreachable!("two absolute locks were given"),
+            };
         } else {
             self.lock_time = Some(lt_in);
         }
         Ok(self)
     }
 
+    /// set the transaction version, 2 by default.
+    ///
+    /// Unless the `Context` has standardness checks disabled, only the
+    /// versions relayed by default (`STANDARD_VERSIONS`) are allowed.
+    pub fn set_version(mut self, version: i32) -> Result<Self, CompilationError> {
+        if self.ctx.standardness_checks() && !STANDARD_VERSIONS.contains(&version) {
+            return Err(CompilationError::NonStandardVersion(version));
+        }
+        self.version = version;
+        Ok(self)
+    }
+
+    /// make the template a version 3 transaction with a zero value
+    /// pay-to-anchor output, which anyone can spend to bump its fee
+    pub fn v3_with_anchor(self) -> Result<Self, CompilationError> {
+        self.set_version(3)?.add_anchor(AnchorTo::PayToAnchor, None)
+    }
+
+    /// Adds an output for CPFP fee bumping, tagged with `ANCHOR_METADATA_KEY`
+    /// so that wallets can find it. Only one anchor may be added per template.
+    ///
+    /// If no amount is given, `DEFAULT_ANCHOR_SATS` are used, except for a
+    /// `AnchorTo::PayToAnchor` in a version 3 transaction, which may be
+    /// ephemeral and zero valued.
+    pub fn add_anchor(
+        self,
+        to: AnchorTo,
+        amount: Option<Amount>,
+    ) -> Result<Self, CompilationError> {
+        if self.outputs.iter().any(|o| o.added_metadata.is_anchor()) {
+            return Err(CompilationError::OverwriteMetadata(
+                ANCHOR_METADATA_KEY.into(),
+            ));
+        }
+        let amount = amount.unwrap_or_else(|| match to {
+            AnchorTo::PayToAnchor if self.version == 3 => Amount::from_sat(0),
+            _ => Amount::from_sat(DEFAULT_ANCHOR_SATS),
+        });
+        let metadata = OutputMeta::from([(ANCHOR_METADATA_KEY, true.into())]);
+        let script = match to {
+            AnchorTo::Key(key) => return self.add_output(amount, &key, Some(metadata)),
+            AnchorTo::Script(script) => script,
+            AnchorTo::PayToAnchor => pay_to_anchor(),
+        };
+        self.add_script_output(script, amount, metadata)
+    }
+
+    /// Pays `amount` to a script which is not a Sapio contract, e.g. one
+    /// specified by an exchange. The output is tagged with
+    /// `EXTERNAL_METADATA_KEY` so that watchers don't look for templates
+    /// spending it.
+    pub fn add_external_output(
+        self,
+        script_pubkey: bitcoin::Script,
+        amount: Amount,
+    ) -> Result<Self, CompilationError> {
+        let metadata = OutputMeta::from([(EXTERNAL_METADATA_KEY, true.into())]);
+        self.add_script_output(script_pubkey, amount, metadata)
+    }
+
+    /// Pays `amount` to an address with `add_external_output`, erroring if
+    /// the address is not for the `Context`'s network.
+    pub fn add_address_output(
+        self,
+        address: &bitcoin::Address,
+        amount: Amount,
+    ) -> Result<Self, CompilationError> {
+        self.ctx.check_address(address)?;
+        self.add_external_output(address.script_pubkey(), amount)
+    }
+
+    /// pays `amount` to `script`, which need not have an address
+    fn add_script_output(
+        self,
+        script: bitcoin::Script,
+        amount: Amount,
+        metadata: OutputMeta,
+    ) -> Result<Self, CompilationError> {
+        let mut range = AmountRange::new();
+        range.update_range(amount);
+        let contract = Compiled::from_script(script.clone(), Some(range), self.ctx.network)
+            .unwrap_or_else(|_| Compiled::from_unknown_script(script, Some(range)));
+        let mut ret = self.spend_amount(amount)?;
+        ret.outputs.push(Output {
+            amount,
+            contract,
+            added_metadata: metadata,
+        });
+        Ok(ret)
+    }
+
+    /// put the outputs in `ordering` when the template is finalized, rather
+    /// than the order they were added in. See `OutputOrdering` for how this
+    /// changes the template's hash.
+    pub fn sort_outputs(mut self, ordering: OutputOrdering) -> Self {
+        self.ordering = ordering;
+        self
+    }
+
     /// overwrite any existing label with the provided string,
     /// or set a label if none provided thus far.
     pub fn set_label(mut self, label: String) -> Self {
@@ -188,6 +590,13 @@ impl Builder {
         self
     }
 
+    /// Label the template, e.g. for GUIs to render its transaction, as
+    /// `add_output_with_meta` does for outputs. The label is not committed to
+    /// by the template's hash.
+    pub fn set_template_label<S: Into<String>>(self, label: S) -> Self {
+        self.set_label(label.into())
+    }
+
     /// overwrite any existing color with the provided string,
     /// or set a color if none provided thus far.
     pub fn set_color(mut self, color: String) -> Self {
@@ -215,6 +624,13 @@ impl Builder {
         Ok(self)
     }
 
+    /// set if a `ThenFunc` commits to this template via CTV (the default), or
+    /// only suggests it.
+    pub fn set_commitment(mut self, commitment: Commitment) -> Self {
+        self.commitment = commitment;
+        self
+    }
+
     /// adds an additional precondition on this template
     /// which ends up being computed as:
     /// And(And(Top Guard, And(add_guard(1),..., add_guard(n))), CTV)
@@ -224,10 +640,36 @@ impl Builder {
         self
     }
 
+    /// Finish building the Template, checking that its guards and lock time
+    /// don't mix height and time locks, and that no output is below the dust
+    /// limit for its script unless the `Context` downgrades that to a
+    /// warning. Converting the Builder into a `TxTmplIt` finalizes it.
+    pub fn finalize(self) -> Result<Template, CompilationError> {
+        let dust_as_warning = self.ctx.dust_as_warning();
+        let path = self.ctx.path().as_ref().clone();
+        let mut needed = self.guards.clone();
+        needed.extend(self.lock_time.map(|lt| Clause::After(lt.get())));
+        if let Some(mix) = Clause::And(needed).mixed_time_locks() {
+            return Err(CompilationError::mixed_time_locks(path, mix));
+        }
+        let tmpl: Template = self.into();
+        match tmpl.dust_outputs().first() {
+            Some(&(index, amount, limit)) if !dust_as_warning => {
+                Err(CompilationError::OutputBelowDust {
+                    path,
+                    index,
+                    amount,
+                    limit,
+                })
+            }
+            _ => Ok(tmpl),
+        }
+    }
+
     /// Creates a transaction from a Builder.
     /// Generally, should not be called directly.
     pub fn get_tx(&self) -> bitcoin::Transaction {
-        let default_seq = RelTime::try_from(0).unwrap().into();
+        let default_seq: AnyRelTimeLock = RelTime::from(0).into();
         let default_nlt = AbsHeight::try_from(0).unwrap().into();
         bitcoin::Transaction {
             version: self.version,
@@ -235,10 +677,21 @@ impl Builder {
             input: self
                 .sequences
                 .iter()
-                .map(|sequence| bitcoin::TxIn {
-                    previous_output: Default::default(),
+                .zip(self.inputs.iter())
+                .map(|(sequence, input)| bitcoin::TxIn {
+                    previous_output: match input.external {
+                        Some(ExternalInput {
+                            prevout: PrevoutSpec::Outpoint(o),
+                            ..
+                        }) => o,
+                        _ => Default::default(),
+                    },
                     script_sig: Default::default(),
-                    sequence: sequence.unwrap_or(default_seq).get(),
+                    sequence: match sequence {
+                        Some(InputSequence::Lock(lock)) => lock.get(),
+                        Some(InputSequence::Raw(raw)) => raw.0,
+                        None => default_seq.get(),
+                    },
                     witness: Witness::new(),
                 })
                 .collect(),
@@ -266,6 +719,12 @@ impl Builder {
         self
     }
 
+    /// estimate the weight of the template, not including the witness, which
+    /// is unknown until the contract being spent is compiled
+    pub fn estimate_weight(&self) -> u64 {
+        self.get_tx().weight() as u64
+    }
+
     /// more efficient that get_tx() to estimate a tx size, not including witness
     pub fn estimate_tx_size(&self) -> u64 {
         let mut input_weight: u64 = 0;
@@ -312,8 +771,10 @@ impl Builder {
         }
     }
 }
+/// Builds the Template without any of the checks done by `Builder::finalize`
 impl From<Builder> for Template {
-    fn from(t: Builder) -> Template {
+    fn from(mut t: Builder) -> Template {
+        t.ordering.sort(&mut t.outputs);
         let tx = t.get_tx();
         Template {
             guards: t.guards,
@@ -325,13 +786,515 @@ impl From<Builder> for Template {
             min_feerate_sats_vbyte: t.min_feerate,
             tx,
             metadata_map_s2s: t.metadata,
+            commitment: t.commitment,
+            input_witness_weight: None,
+            external_funds: (t.external_funds > Amount::from_sat(0)).then_some(t.external_funds),
         }
     }
 }
 
 impl From<Builder> for crate::contract::TxTmplIt {
     fn from(t: Builder) -> Self {
-        // t.into() // works too, but prefer the explicit form so we know what we get concretely
-        Ok(Box::new(std::iter::once(Ok(t.into()))))
+        Ok(Box::new(std::iter::once(t.finalize())))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{Network, XOnlyPublicKey};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::sync::Arc;
+    fn pay_change_at(feerate: u64) -> Result<Template, CompilationError> {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("builder").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+        .with_feerate(Some(Amount::from_sat(feerate)));
+        let b = ctx
+            .template()
+            .add_output(Amount::from_sat(50_000), &key, None)?
+            .add_fee_from_rate()?;
+        let change = b.ctx().funds();
+        Ok(b.add_output(change, &key, None)?.into())
+    }
+    #[test]
+    fn fee_from_rate() {
+        let low = pay_change_at(1).unwrap();
+        let high = pay_change_at(50).unwrap();
+        let low_fee = 50_000 - low.outputs[1].amount.as_sat();
+        let high_fee = 50_000 - high.outputs[1].amount.as_sat();
+        assert!(low_fee > 0);
+        assert_eq!(high_fee, 50 * low_fee);
+        assert_eq!(high.max, Amount::from_sat(100_000));
+        match pay_change_at(10_000) {
+            Err(CompilationError::FeeRateShortfall(name, short)) => {
+                assert_eq!(name, "builder");
+                assert_eq!(short, Amount::from_sat(10_000 * low_fee - 50_000));
+            }
+            _ => panic!("fee should exceed the funds left"),
+        }
+    }
+    #[test]
+    fn data_output() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let cid = b"bafybeigdyrzt5sfp7udm7hu76uh7y26nf3efuylqabf3oclgtqy55fbzdi";
+        let b = ctx()
+            .template()
+            .add_output(Amount::from_sat(60_000), &key, None)
+            .unwrap()
+            .add_data_output(cid)
+            .unwrap();
+        assert_eq!(b.ctx().funds(), Amount::from_sat(40_000));
+        let t: Template = b.into();
+        let op_return = bitcoin::Script::new_op_return(cid);
+        assert_eq!(t.tx.output[1].value, 0);
+        assert_eq!(t.tx.output[1].script_pubkey, op_return);
+        assert_eq!(t.total_amount(), Amount::from_sat(60_000));
+        assert_eq!(t.max, Amount::from_sat(60_000));
+        let json = serde_json::to_string(&t).unwrap();
+        assert!(json.contains(&bitcoin::hashes::hex::ToHex::to_hex(&op_return[..])));
+
+        let big = [0u8; MAX_DATA_CARRIER_BYTES + 1];
+        assert!(matches!(
+            ctx().template().add_data_output(&big),
+            Err(CompilationError::NonStandardDataOutput(_))
+        ));
+        assert!(matches!(
+            ctx()
+                .template()
+                .add_data_output(cid)
+                .unwrap()
+                .add_data_output(cid),
+            Err(CompilationError::NonStandardDataOutput(_))
+        ));
+        ctx()
+            .with_standardness_checks(false)
+            .template()
+            .add_data_output(&big)
+            .unwrap()
+            .add_data_output(cid)
+            .unwrap();
+    }
+    #[test]
+    fn sequenced_input() {
+        let paying = |extra: Option<PrevoutSpec>| -> Template {
+            let key: XOnlyPublicKey =
+                "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                    .parse()
+                    .unwrap();
+            let b = Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .template()
+            .add_output(Amount::from_sat(60_000), &key, None)
+            .unwrap();
+            match extra {
+                Some(prevout) => b
+                    .add_sequenced_input(prevout, None, Some(Amount::from_sat(10_000)))
+                    .into(),
+                None => b.into(),
+            }
+        };
+        let alone = paying(None);
+        let outpoint: bitcoin::OutPoint =
+            "0000000000000000000000000000000000000000000000000000000000000001:7"
+                .parse()
+                .unwrap();
+        let with_any = paying(Some(PrevoutSpec::Any));
+        let with_outpoint = paying(Some(PrevoutSpec::Outpoint(outpoint)));
+        assert_ne!(alone.ctv, with_any.ctv);
+        // CTV does not commit to which coins are spent
+        assert_eq!(with_any.ctv, with_outpoint.ctv);
+        assert_eq!(with_outpoint.tx.input[1].previous_output, outpoint);
+
+        let json = serde_json::to_value(&with_outpoint).unwrap();
+        assert_eq!(
+            json["transaction_literal"]["input"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        let inputs = json["inputs_info"].as_array().unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert!(inputs[0].get("external").is_none());
+        assert_eq!(
+            inputs[1]["external"],
+            serde_json::json!({"prevout": {"Outpoint": outpoint.to_string()}, "amount_hint": 10_000})
+        );
+    }
+    #[test]
+    fn sequence_setters() {
+        let builder = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+            .template()
+        };
+        let sequence = |b: Builder| b.get_tx().input[0].sequence;
+        let unlocked: Template = builder().into();
+        let locked = builder()
+            .set_relative_lock(0, RelHeight::from(10))
+            .unwrap()
+            .set_relative_lock(-1, RelHeight::from(144))
+            .unwrap();
+        assert_eq!(sequence(locked.set_rbf().unwrap()), 144);
+        let locked: Template = builder()
+            .set_relative_lock(0, RelHeight::from(144))
+            .unwrap()
+            .into();
+        assert_ne!(unlocked.ctv, locked.ctv);
+
+        let rbf = builder().set_rbf().unwrap();
+        assert!(Sequence(sequence(rbf)).signals_rbf());
+        let final_seq = builder().set_raw_sequence(0, Sequence::MAX).unwrap();
+        assert_eq!(sequence(final_seq), 0xFFFF_FFFF);
+        assert!(matches!(
+            builder()
+                .set_rbf()
+                .unwrap()
+                .set_raw_sequence(0, Sequence::MAX),
+            Err(CompilationError::IncompatibleSequence)
+        ));
+        assert!(matches!(
+            builder()
+                .set_raw_sequence(0, Sequence::MAX)
+                .unwrap()
+                .set_rbf(),
+            Err(CompilationError::IncompatibleSequence)
+        ));
+
+        // a raw sequence may stand in for a relative lock it enforces
+        let raw = builder()
+            .set_relative_lock(0, RelHeight::from(10))
+            .unwrap()
+            .set_raw_sequence(0, Sequence(20))
+            .unwrap()
+            .set_relative_lock(0, RelHeight::from(15))
+            .unwrap();
+        assert_eq!(sequence(raw), 20);
+        assert!(matches!(
+            builder()
+                .set_raw_sequence(0, Sequence::MAX)
+                .unwrap()
+                .set_relative_lock(0, RelHeight::from(10)),
+            Err(CompilationError::IncompatibleSequence)
+        ));
+        assert!(matches!(
+            builder()
+                .set_relative_lock(0, RelTime::from(10))
+                .unwrap()
+                .set_raw_sequence(0, Sequence(20)),
+            Err(CompilationError::IncompatibleSequence)
+        ));
+        assert!(matches!(
+            builder().set_raw_sequence(1, Sequence::MAX),
+            Err(CompilationError::NoSuchSequence)
+        ));
+
+        // locks of one kind merge to the largest, other kinds don't mix
+        assert!(matches!(
+            builder()
+                .set_relative_lock(0, RelHeight::from(10))
+                .unwrap()
+                .set_relative_lock(0, RelTime::from(10)),
+            Err(CompilationError::IncompatibleSequence)
+        ));
+        let lock_time = |b: Builder| b.get_tx().lock_time;
+        let height = AbsHeight::try_from(700_000).unwrap();
+        let later = builder()
+            .set_lock_time(height.into())
+            .unwrap()
+            .set_lock_time(AbsHeight::try_from(10).unwrap().into())
+            .unwrap();
+        assert_eq!(lock_time(later), 700_000);
+        assert!(matches!(
+            builder()
+                .set_lock_time(height.into())
+                .unwrap()
+                .set_lock_time(START_OF_TIME.into()),
+            Err(CompilationError::IncompatibleLockTime)
+        ));
+    }
+    #[test]
+    fn version() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let paying = |version: i32| -> Template {
+            ctx()
+                .template()
+                .add_output(Amount::from_sat(60_000), &key, None)
+                .unwrap()
+                .set_version(version)
+                .unwrap()
+                .into()
+        };
+        let v2 = paying(2);
+        let v3 = paying(3);
+        assert_eq!(v2.tx.version, 2);
+        assert_eq!(v3.tx.version, 3);
+        assert_ne!(v2.ctv, v3.ctv);
+        let json = serde_json::to_value(&v3).unwrap();
+        assert_eq!(json["transaction_literal"]["version"], 3);
+
+        assert!(matches!(
+            ctx().template().set_version(4),
+            Err(CompilationError::NonStandardVersion(4))
+        ));
+        ctx()
+            .with_standardness_checks(false)
+            .template()
+            .set_version(4)
+            .unwrap();
+
+        let anchored: Template = ctx()
+            .template()
+            .add_output(Amount::from_sat(60_000), &key, None)
+            .unwrap()
+            .v3_with_anchor()
+            .unwrap()
+            .into();
+        assert_eq!(anchored.tx.version, 3);
+        assert_eq!(anchored.tx.output[1].value, 0);
+        assert_eq!(anchored.tx.output[1].script_pubkey, pay_to_anchor());
+        assert_eq!(anchored.max, Amount::from_sat(60_000));
+    }
+    #[test]
+    fn dust() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let paying = |ctx: Context, sats: u64| {
+            ctx.template()
+                .add_output(Amount::from_sat(sats), &key, None)
+                .unwrap()
+                .finalize()
+        };
+        paying(ctx(), 330).unwrap();
+        match paying(ctx(), 329).unwrap_err() {
+            CompilationError::OutputBelowDust {
+                path,
+                index: 0,
+                amount,
+                limit,
+            } => {
+                assert_eq!(path, EffectPath::try_from("builder").unwrap());
+                assert_eq!(amount, Amount::from_sat(329));
+                assert_eq!(limit, Amount::from_sat(330));
+            }
+            e => panic!("unexpected error {:?}", e),
+        }
+        let allowed = paying(ctx().with_dust_as_warning(true), 329).unwrap();
+        assert_eq!(
+            allowed.dust_outputs(),
+            vec![(0, Amount::from_sat(329), Amount::from_sat(330))]
+        );
+
+        ctx()
+            .template()
+            .add_data_output(b"provenance")
+            .unwrap()
+            .v3_with_anchor()
+            .unwrap()
+            .finalize()
+            .unwrap();
+    }
+    #[test]
+    fn output_ordering() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let tagged = OutputMeta::from([("tagged", true.into())]);
+        let paying = |amounts: &[u64], ordering| -> Template {
+            let mut b = ctx().template();
+            for a in amounts {
+                let meta = (*a == 10_000).then(|| tagged.clone());
+                b = b.add_output(Amount::from_sat(*a), &key, meta).unwrap();
+            }
+            b.add_data_output(b"sorted")
+                .unwrap()
+                .sort_outputs(ordering)
+                .into()
+        };
+        let amounts = |t: &Template| t.tx.output.iter().map(|o| o.value).collect::<Vec<_>>();
+
+        let inserted = paying(&[30_000, 10_000, 20_000], OutputOrdering::Insertion);
+        assert_eq!(amounts(&inserted), vec![30_000, 10_000, 20_000, 0]);
+
+        let sorted = paying(&[30_000, 10_000, 20_000], OutputOrdering::Bip69);
+        assert_eq!(amounts(&sorted), vec![0, 10_000, 20_000, 30_000]);
+        assert_eq!(sorted.outputs[1].added_metadata, tagged);
+        let with_meta = sorted
+            .outputs
+            .iter()
+            .filter(|o| !o.added_metadata.is_empty());
+        assert_eq!(with_meta.count(), 1);
+        assert_eq!(
+            sorted.hash().to_string(),
+            "ba34bdedd15b1574e43b7f74000cc2e05136eb24864dfa144f1105d338cbec9b"
+        );
+
+        let seed = OutputOrdering::Deterministic([7; 32]);
+        let shuffled = paying(&[30_000, 10_000, 20_000], seed);
+        assert_eq!(
+            shuffled.hash(),
+            paying(&[20_000, 30_000, 10_000], seed).hash()
+        );
+        let i = shuffled
+            .tx
+            .output
+            .iter()
+            .position(|o| o.value == 10_000)
+            .unwrap();
+        assert_eq!(shuffled.outputs[i].added_metadata, tagged);
+    }
+    #[test]
+    fn output_metadata() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let refund = OutputMeta::default()
+            .with_label("refund to Alice")
+            .with_color("green")
+            .set_extra("link", "https://example.com/alice")
+            .unwrap();
+        assert!(OutputMeta::default().set_extra("label", "x").is_err());
+        let labeled: Template = ctx()
+            .template()
+            .add_output_with_meta(Amount::from_sat(60_000), &key, refund.clone())
+            .unwrap()
+            .set_template_label("auction escrow")
+            .into();
+        let plain: Template = ctx()
+            .template()
+            .add_output(Amount::from_sat(60_000), &key, None)
+            .unwrap()
+            .into();
+        assert_eq!(labeled.hash(), plain.hash());
+
+        let json = serde_json::to_value(&labeled).unwrap();
+        assert_eq!(json["metadata_map_s2s"]["label"], "auction escrow");
+        let meta = &json["outputs_info"][0]["metadata_map_s2s"];
+        assert_eq!(meta["label"], "refund to Alice");
+        assert_eq!(meta["color"], "green");
+        assert_eq!(meta["link"], "https://example.com/alice");
+        let back: Template = serde_json::from_value(json).unwrap();
+        assert_eq!(back.outputs[0].added_metadata, refund);
+        assert!(serde_json::to_value(&plain).unwrap()["outputs_info"][0]
+            .get("metadata_map_s2s")
+            .is_none());
+    }
+    #[test]
+    fn external_outputs() {
+        let key: XOnlyPublicKey =
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798"
+                .parse()
+                .unwrap();
+        let ctx = || {
+            Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("builder").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            )
+        };
+        let mainnet = bitcoin::Address::p2tr_tweaked(
+            bitcoin::util::schnorr::TweakedPublicKey::dangerous_assume_tweaked(key),
+            Network::Bitcoin,
+        );
+        assert!(matches!(
+            ctx()
+                .template()
+                .add_address_output(&mainnet, Amount::from_sat(10_000)),
+            Err(CompilationError::WrongNetwork(Network::Regtest, _))
+        ));
+        let legacy_p2sh = bitcoin::Script::new_p2sh(&bitcoin::ScriptHash::hash(b"legacy"));
+        let mixed = || -> Template {
+            ctx()
+                .template()
+                .add_output(Amount::from_sat(50_000), &key, None)
+                .unwrap()
+                .add_external_output(legacy_p2sh.clone(), Amount::from_sat(20_000))
+                .unwrap()
+                .into()
+        };
+        let t = mixed();
+        assert_eq!(t.hash(), mixed().hash());
+        assert_eq!(t.tx.output[1].script_pubkey, legacy_p2sh);
+        assert_eq!(t.total_amount(), Amount::from_sat(70_000));
+        assert!(!t.outputs[0].added_metadata.is_external());
+        assert!(t.outputs[1].added_metadata.is_external());
+        assert!(t.outputs[1].contract.ctv_to_tx.is_empty());
     }
 }
@@ -8,9 +8,38 @@
 use super::*;
 use sapio_base::simp::SIMPError;
 use serde::{Deserialize, Serialize};
+/// Which coin an input other than the contract's own should spend
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug, PartialEq, Eq)]
+pub enum PrevoutSpec {
+    /// A specific outpoint, e.g. one agreed with a counterparty
+    Outpoint(#[schemars(with = "String")] bitcoin::OutPoint),
+    /// Any coin the wallet chooses to contribute, e.g. for fees
+    Any,
+}
+
+/// An input a wallet must contribute for a template to be spendable, see
+/// `Builder::add_sequenced_input`
+#[derive(Serialize, Deserialize, Clone, JsonSchema, Debug, PartialEq, Eq)]
+pub struct ExternalInput {
+    /// # The coin to spend
+    pub prevout: PrevoutSpec,
+    /// # How much the coin is expected to be worth, if known
+    #[serde(
+        with = "bitcoin::util::amount::serde::as_sat::opt",
+        skip_serializing_if = "Option::is_none",
+        default
+    )]
+    #[schemars(with = "Option<u64>")]
+    pub amount_hint: Option<Amount>,
+}
+
 /// Metadata for outputs, arbitrary KV set.
 #[derive(Serialize, Deserialize, Clone, JsonSchema, Debug, PartialEq, Eq)]
 pub struct InputMetadata {
+    /// Set if this input is contributed by a wallet rather than being the
+    /// contract's own coin
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub external: Option<ExternalInput>,
     /// Additional non-standard fields for future upgrades
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
@@ -56,6 +85,7 @@ impl InputMetadata {
 impl Default for InputMetadata {
     fn default() -> Self {
         InputMetadata {
+            external: None,
             extra: Default::default(),
             simp: Default::default(),
         }
@@ -65,6 +95,7 @@ impl Default for InputMetadata {
 impl<const N: usize> From<[(&str, serde_json::Value); N]> for InputMetadata {
     fn from(v: [(&str, serde_json::Value); N]) -> InputMetadata {
         InputMetadata {
+            external: None,
             extra: IntoIterator::into_iter(v)
                 .map(|(a, b)| (a.into(), b))
                 .collect(),
@@ -18,7 +18,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 pub mod input;
 pub mod output;
-pub use output::{Output, OutputMeta};
+pub use output::{Output, OutputMeta, ANCHOR_METADATA_KEY, EXTERNAL_METADATA_KEY};
 pub mod builder;
 pub use builder::Builder;
 
@@ -109,6 +109,24 @@ impl TemplateMetadata {
     }
 }
 
+/// Whether a Template returned by a `ThenFunc` is bound by the covenant, or
+/// merely suggested to wallets
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Commitment {
+    /// The Template is committed to via CTV
+    #[default]
+    Committed,
+    /// The Template is advisory only, and is not committed to via CTV
+    Suggested,
+}
+
+impl Commitment {
+    /// is this the default, `Committed`?
+    pub fn is_committed(&self) -> bool {
+        *self == Commitment::Committed
+    }
+}
+
 /// Template holds the data needed to construct a Transaction for CTV Purposes, along with relevant
 /// metadata
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
@@ -152,6 +170,24 @@ pub struct Template {
     /// sapio specific information about all the inputs in the `tx`.
     #[serde(rename = "inputs_info")]
     pub inputs: Vec<InputMetadata>,
+    /// if this template is committed to when returned from a `ThenFunc`.
+    /// Templates returned from a `FinishOrFunc` are always suggestions.
+    #[serde(skip_serializing_if = "Commitment::is_committed", default)]
+    pub commitment: Commitment,
+    /// the largest witness (in weight units) that could spend the contract
+    /// this template spends from, set by the compiler.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub input_witness_weight: Option<u64>,
+    /// funds spent by this template beyond those of the contract it spends
+    /// from, e.g. from external inputs added with `Builder::add_amount`
+    #[serde(
+        rename = "external_funds_sats",
+        skip_serializing_if = "Option::is_none",
+        default,
+        with = "bitcoin::util::amount::serde::as_sat::opt"
+    )]
+    #[schemars(with = "Option<i64>")]
+    pub external_funds: Option<Amount>,
 }
 
 impl Template {
@@ -169,4 +205,156 @@ impl Template {
             .map(|o| o.amount)
             .fold(Amount::from_sat(0), |b, a| b + a)
     }
+
+    /// An upper bound on the weight of this template's transaction once signed.
+    ///
+    /// The witness spending the contract (input 0) is counted as the largest
+    /// satisfaction of any of the contract's compiled guards. Witnesses for
+    /// any other inputs, e.g. from `Builder::add_sequenced_input`, are not
+    /// known and so are counted as empty.
+    pub fn estimated_weight(&self) -> u64 {
+        // the segwit marker and flag, and a witness item count per input,
+        // which `weight` does not count while all the witnesses are empty
+        let segwit_overhead = 2 + self.tx.input.len() as u64;
+        self.tx.weight() as u64 + segwit_overhead + self.input_witness_weight.unwrap_or(0)
+    }
+
+    /// An upper bound on the virtual size of this template's transaction once
+    /// signed, see `Template::estimated_weight`
+    pub fn estimated_vsize(&self) -> u64 {
+        self.estimated_weight().div_ceil(4)
+    }
+
+    /// the (index, amount, dust limit) of each output worth less than the dust
+    /// limit for its script. OP_RETURN outputs are exempt, as is a zero value
+    /// pay-to-anchor in a version 3 transaction, which may be ephemeral.
+    pub fn dust_outputs(&self) -> Vec<(usize, Amount, Amount)> {
+        self.tx
+            .output
+            .iter()
+            .enumerate()
+            .filter(|(_, o)| {
+                !(self.tx.version == 3
+                    && o.value == 0
+                    && o.script_pubkey == builder::pay_to_anchor())
+            })
+            .map(|(i, o)| (i, Amount::from_sat(o.value), o.script_pubkey.dust_value()))
+            .filter(|(_, amount, limit)| amount < limit)
+            .collect()
+    }
+
+    /// the index of the output tagged as a fee bumping anchor, if any
+    pub fn anchor(&self) -> Option<usize> {
+        self.outputs
+            .iter()
+            .position(|o| o.added_metadata.is_anchor())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::actions::*;
+    use crate::contract::object::SupportedDescriptors;
+    use crate::contract::{Compilable, Context, Contract, TxTmplIt};
+    use bitcoin::secp256k1::{Keypair, Message, SecretKey};
+    use bitcoin::util::sighash::{Prevouts, SchnorrSighashType, SighashCache};
+    use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
+    use bitcoin::{Network, XOnlyPublicKey};
+    use miniscript::{Descriptor, DescriptorTrait};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    /// the key 1 * G
+    const KEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    /// pays a key all but a 1000 sat fee, once signed by `KEY`
+    struct Cosigned;
+    fn pay(_: &Cosigned, ctx: Context, _: ThenFuncTypeTag) -> TxTmplIt {
+        let key: XOnlyPublicKey = KEY.parse().unwrap();
+        let amt = ctx.funds() - Amount::from_sat(1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amt, &key, None)?
+            .into()
+    }
+    impl Cosigned {
+        fn signed() -> Option<Guard<Self>> {
+            Some(Guard::Fresh(
+                GuardFn::Fn(|_, _| Clause::Key(KEY.parse().unwrap())),
+                None,
+            ))
+        }
+        fn payout<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+            Some(
+                ThenFunc {
+                    guard: &[GuardGen::Fn(Self::signed)],
+                    guard_combinator: Default::default(),
+                    conditional_compile_if: &[],
+                    func: pay,
+                    name: Arc::new("payout".into()),
+                    fee_policy: Default::default(),
+                    weight: None,
+                }
+                .into(),
+            )
+        }
+    }
+    impl Contract for Cosigned {
+        declare! {then, Self::payout}
+        declare! {non updatable}
+    }
+    #[test]
+    fn weight_estimate_bounds_signed_spend() {
+        let ctx = Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("template").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        let secp = ctx.secp().clone();
+        let compiled = Cosigned.compile(ctx).unwrap();
+        assert_eq!(compiled.ctv_to_tx.len(), 1);
+        let t = compiled.ctv_to_tx.values().next().unwrap();
+        let tr = match compiled.descriptor.as_ref() {
+            Some(SupportedDescriptors::XOnly(Descriptor::Tr(tr))) => tr,
+            d => panic!("unexpected descriptor {:?}", d),
+        };
+        // the signature for the key, 1 * G, has the same size whatever the
+        // sighash, but sign the real one anyways
+        let script = tr.iter_scripts().next().unwrap().1.encode();
+        let control_block = tr
+            .spend_info()
+            .control_block(&(script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        let prevout = bitcoin::TxOut {
+            value: 100_000,
+            script_pubkey: tr.script_pubkey(),
+        };
+        let mut tx = t.tx.clone();
+        let sighash = SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&[prevout]),
+                TapLeafHash::from_script(&script, LeafVersion::TapScript),
+                SchnorrSighashType::Default,
+            )
+            .unwrap();
+        let mut one = [0u8; 32];
+        one[31] = 1;
+        let keypair = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&one).unwrap());
+        let sig = secp
+            .sign_schnorr_no_aux_rand(&Message::from_digest_slice(&sighash[..]).unwrap(), &keypair);
+        tx.input[0].witness = bitcoin::Witness::from_vec(vec![
+            sig.as_ref().to_vec(),
+            script.into_bytes(),
+            control_block.serialize(),
+        ]);
+        let actual = tx.weight() as u64;
+        assert!(t.estimated_weight() >= actual);
+        // allowing e.g. for a sighash type byte after the signature
+        assert!(t.estimated_weight() - actual <= 16);
+        assert_eq!(t.estimated_vsize(), t.estimated_weight().div_ceil(4));
+    }
 }
@@ -11,19 +11,74 @@ use serde::{Deserialize, Serialize};
 /// Metadata for outputs, arbitrary KV set.
 #[derive(Serialize, Deserialize, Clone, JsonSchema, Debug, PartialEq, Eq)]
 pub struct OutputMeta {
+    /// A Label for this output
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub label: Option<String>,
     /// Additional non-standard fields for future upgrades
     #[serde(flatten)]
     pub extra: BTreeMap<String, serde_json::Value>,
     /// SIMP: Sapio Interactive Metadata Protocol
     pub simp: BTreeMap<i64, serde_json::Value>,
+    /// A Color to render this output.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub color: Option<String>,
 }
 
+/// The `OutputMeta` field marking an output as a fee bumping anchor
+pub const ANCHOR_METADATA_KEY: &str = "anchor";
+
+/// The `OutputMeta` field marking an output as paying a script from outside
+/// of Sapio, which has no templates to follow
+pub const EXTERNAL_METADATA_KEY: &str = "external";
+
 impl OutputMeta {
     /// Is there any metadata in this field?
     pub fn is_empty(&self) -> bool {
         *self == Default::default()
     }
 
+    /// overwrite any existing label with the provided string
+    pub fn with_label<S: Into<String>>(mut self, label: S) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// overwrite any existing color with the provided string
+    pub fn with_color<S: Into<String>>(mut self, color: S) -> Self {
+        self.color = Some(color.into());
+        self
+    }
+
+    /// set an extra metadata value, e.g. a link to more information
+    pub fn set_extra<I, J>(mut self, i: I, j: J) -> Result<Self, CompilationError>
+    where
+        I: Into<String>,
+        J: Into<serde_json::Value>,
+    {
+        let s: String = i.into();
+        match s.as_str() {
+            "color" | "label" => Err(CompilationError::TerminateWith(
+                "Don't Set label or color through the extra API".into(),
+            )),
+            _ => {
+                if self.extra.insert(s.clone(), j.into()).is_some() {
+                    return Err(CompilationError::OverwriteMetadata(s));
+                }
+                Ok(self)
+            }
+        }
+    }
+
+    /// Is this output tagged as a fee bumping anchor?
+    pub fn is_anchor(&self) -> bool {
+        self.extra.get(ANCHOR_METADATA_KEY) == Some(&serde_json::Value::Bool(true))
+    }
+
+    /// Is this output tagged as paying an external script?
+    pub fn is_external(&self) -> bool {
+        self.extra.get(EXTERNAL_METADATA_KEY) == Some(&serde_json::Value::Bool(true))
+    }
+
     /// attempts to add a SIMP to the output meta.
     ///
     /// Returns [`SIMPError::AlreadyDefined`] if one was previously set.
@@ -42,8 +97,10 @@ impl OutputMeta {
 impl Default for OutputMeta {
     fn default() -> Self {
         OutputMeta {
+            label: None,
             extra: Default::default(),
             simp: Default::default(),
+            color: None,
         }
     }
 }
@@ -54,7 +111,7 @@ impl<const N: usize> From<[(&str, serde_json::Value); N]> for OutputMeta {
             extra: IntoIterator::into_iter(v)
                 .map(|(a, b)| (a.into(), b))
                 .collect(),
-            simp: Default::default(),
+            ..Default::default()
         }
     }
 }
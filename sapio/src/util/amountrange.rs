@@ -7,8 +7,14 @@
 //! Functionality for working with ranges of amounts
 use bitcoin::util::amount::Amount;
 
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, Schema, SchemaObject, StringValidation, SubschemaValidation};
 use schemars::JsonSchema;
-use serde::{Deserialize, Serialize};
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter::Sum;
 
 /// A wrapper around `bitcoin::Amount` to force it to serialize with f64.
 #[derive(
@@ -33,16 +39,98 @@ impl From<AmountF64> for Amount {
     }
 }
 /// A wrapper around `bitcoin::Amount` to force it to serialize with u64.
-#[derive(
-    Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, Ord, PartialOrd, PartialEq, Eq,
-)]
-#[serde(transparent)]
-pub struct AmountU64(
-    /// # Amount (Sats)
-    #[schemars(with = "u64")]
-    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
-    Amount,
-);
+///
+/// It deserializes from either an integer number of sats or a string with an
+/// explicit unit, so that a frontend can't send BTC where sats are expected.
+/// Amounts finer than a sat are rejected.
+///
+/// ```
+/// # use sapio::util::amountrange::AmountU64;
+/// # use serde::Deserialize;
+/// /// the `price` field of the NFT sale trait
+/// #[derive(Deserialize)]
+/// struct Sale {
+///     price: AmountU64,
+/// }
+/// for price in &[
+///     serde_json::json!(1_500_000),
+///     serde_json::json!("0.015 BTC"),
+///     serde_json::json!("1500000 sats"),
+/// ] {
+///     let sale: Sale = serde_json::from_value(serde_json::json!({ "price": price })).unwrap();
+///     assert_eq!(u64::from(sale.price), 1_500_000);
+/// }
+/// // a unit is required for strings, and sub-sat precision is an error
+/// assert!(serde_json::from_value::<Sale>(serde_json::json!({"price": "0.015"})).is_err());
+/// assert!(serde_json::from_value::<Sale>(serde_json::json!({"price": "0.000000001 BTC"})).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, Ord, PartialOrd, PartialEq, Eq)]
+pub struct AmountU64(Amount);
+
+impl Serialize for AmountU64 {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_u64(self.0.as_sat())
+    }
+}
+
+impl<'de> Deserialize<'de> for AmountU64 {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = AmountU64;
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an integer number of sats or a string with a unit, like \"0.015 BTC\"")
+            }
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<AmountU64, E> {
+                Ok(AmountU64::from(v))
+            }
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<AmountU64, E> {
+                u64::try_from(v)
+                    .map(AmountU64::from)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+            }
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<AmountU64, E> {
+                Amount::from_str_with_denomination(v)
+                    .map(AmountU64)
+                    .map_err(|e| E::custom(format!("invalid amount `{}`: {}", v, e)))
+            }
+        }
+        d.deserialize_any(Visitor)
+    }
+}
+
+impl JsonSchema for AmountU64 {
+    fn schema_name() -> String {
+        "AmountU64".into()
+    }
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let text = SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            string: Some(Box::new(StringValidation {
+                pattern: Some(r"^\s*[0-9]+(\.[0-9]+)?\s+[a-zA-Z]+\s*$".into()),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let mut schema = SchemaObject {
+            subschemas: Some(Box::new(SubschemaValidation {
+                any_of: Some(vec![gen.subschema_for::<u64>(), text.into()]),
+                ..Default::default()
+            })),
+            ..Default::default()
+        };
+        let metadata = schema.metadata();
+        metadata.title = Some("Amount (Sats)".into());
+        metadata.description =
+            Some("An integer number of sats, or a string with a unit such as BTC or sats".into());
+        metadata.examples = vec![
+            serde_json::json!(15000),
+            serde_json::json!("0.015 BTC"),
+            serde_json::json!("15000 sats"),
+        ];
+        schema.into()
+    }
+}
 
 impl From<Amount> for AmountU64 {
     fn from(a: Amount) -> AmountU64 {
@@ -64,9 +152,56 @@ impl From<AmountU64> for u64 {
         a.0.as_sat()
     }
 }
+/// Checked arithmetic, which returns `None` rather than overflowing
+impl AmountU64 {
+    /// `self + other`, or `None` on overflow
+    pub fn checked_add(self, other: AmountU64) -> Option<AmountU64> {
+        self.0.checked_add(other.0).map(AmountU64)
+    }
+    /// `self - other`, or `None` if `other` is larger
+    pub fn checked_sub(self, other: AmountU64) -> Option<AmountU64> {
+        self.0.checked_sub(other.0).map(AmountU64)
+    }
+    /// `self * num / denom`, rounded down, or `None` if `denom` is zero or
+    /// the result does not fit.
+    ///
+    /// The product is computed without overflow, so e.g. a royalty of
+    /// `num` parts per `denom` may be taken of any amount.
+    pub fn checked_mul_ratio(self, num: u64, denom: u64) -> Option<AmountU64> {
+        if denom == 0 {
+            return None;
+        }
+        let r = u128::from(self.0.as_sat()) * u128::from(num) / u128::from(denom);
+        u64::try_from(r).ok().map(AmountU64::from)
+    }
+    /// Splits `self` into `(share, rest)` where `share` is
+    /// `checked_mul_ratio(num, denom)` and `rest` gets the remainder, so no
+    /// sats are lost to rounding. `None` if the share exceeds `self`.
+    pub fn checked_split_ratio(self, num: u64, denom: u64) -> Option<(AmountU64, AmountU64)> {
+        let share = self.checked_mul_ratio(num, denom)?;
+        Some((share, self.checked_sub(share)?))
+    }
+}
+
+impl Sum<AmountU64> for Option<AmountU64> {
+    /// `None` if the total overflows
+    fn sum<I: Iterator<Item = AmountU64>>(mut iter: I) -> Self {
+        iter.try_fold(AmountU64::from(0u64), AmountU64::checked_add)
+    }
+}
+impl<'a> Sum<&'a AmountU64> for Option<AmountU64> {
+    fn sum<I: Iterator<Item = &'a AmountU64>>(iter: I) -> Self {
+        iter.copied().sum()
+    }
+}
+
 /// `AmountRange` makes it simple to track and update the range of allowed values
 /// for a contract to receive.
-#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+///
+/// A range is the closed interval from `min` to `max`, where either bound may
+/// be unset. An unset `min` is no lower bound, while an unset `max` is treated
+/// as no upper bound by the set operations (but see [`AmountRange::max`]).
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
 pub struct AmountRange {
     #[serde(rename = "min_btc", skip_serializing_if = "Option::is_none", default)]
     min: Option<AmountF64>,
@@ -81,13 +216,198 @@ impl AmountRange {
             max: None,
         }
     }
+    /// the range from `min` to `max`, which is empty if `min > max`
+    pub fn between(min: Amount, max: Amount) -> AmountRange {
+        AmountRange {
+            min: Some(min.into()),
+            max: Some(max.into()),
+        }
+    }
+    /// the range of just `amount`
+    pub fn exactly(amount: Amount) -> AmountRange {
+        AmountRange::between(amount, amount)
+    }
     /// Update the min and the max value.
     pub fn update_range(&mut self, amount: Amount) {
-        self.min = std::cmp::min(self.min, Some(amount.into()));
-        self.max = std::cmp::max(self.max, Some(amount.into()));
+        let amount = Some(amount.into());
+        self.min = self.min.min(amount).or(amount);
+        self.max = std::cmp::max(self.max, amount);
     }
     /// Retreive the max value, if set, or return `Amount::min_value`.
     pub fn max(&self) -> Amount {
         self.max.unwrap_or(Amount::min_value().into()).0
     }
+    /// the lower bound, if any
+    pub fn min_bound(&self) -> Option<Amount> {
+        self.min.map(Into::into)
+    }
+    /// the upper bound, if any
+    pub fn max_bound(&self) -> Option<Amount> {
+        self.max.map(Into::into)
+    }
+    /// the amount halfway between the bounds, if both are set and the range
+    /// is not empty
+    pub fn midpoint(&self) -> Option<Amount> {
+        match (self.min_bound(), self.max_bound()) {
+            (Some(min), Some(max)) if min <= max => {
+                Some(min + Amount::from_sat((max - min).as_sat() / 2))
+            }
+            _ => None,
+        }
+    }
+    /// true if no amount is in the range
+    pub fn is_empty(&self) -> bool {
+        matches!((self.min, self.max), (Some(min), Some(max)) if min > max)
+    }
+    /// true if `amount` is in the range
+    pub fn contains(&self, amount: Amount) -> bool {
+        self.min.is_none_or(|min| min.0 <= amount) && self.max.is_none_or(|max| amount <= max.0)
+    }
+    /// the amounts in both ranges, which may be empty
+    pub fn intersect(&self, other: &AmountRange) -> AmountRange {
+        AmountRange {
+            min: self.min.max(other.min),
+            max: match (self.max, other.max) {
+                (Some(a), Some(b)) => Some(a.min(b)),
+                (a, b) => a.or(b),
+            },
+        }
+    }
+    /// the smallest range containing both ranges, including any amounts
+    /// between them. Empty ranges are ignored.
+    pub fn union_hull(&self, other: &AmountRange) -> AmountRange {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        AmountRange {
+            min: self.min.and(other.min).and(self.min.min(other.min)),
+            max: self.max.and(other.max).and(self.max.max(other.max)),
+        }
+    }
+    /// Combine `ranges` with `f`, e.g. `AmountRange::intersect`, or `None` if
+    /// there are none
+    pub fn fold<I, F>(ranges: I, f: F) -> Option<AmountRange>
+    where
+        I: IntoIterator<Item = AmountRange>,
+        F: Fn(&AmountRange, &AmountRange) -> AmountRange,
+    {
+        let mut ranges = ranges.into_iter();
+        let first = ranges.next()?;
+        Some(ranges.fold(first, |acc, r| f(&acc, &r)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    fn range(min: u64, max: u64) -> AmountRange {
+        AmountRange::between(Amount::from_sat(min), Amount::from_sat(max))
+    }
+    #[test]
+    fn parse_amounts() {
+        let parse = |v: serde_json::Value| serde_json::from_value::<AmountU64>(v).map(u64::from);
+        assert_eq!(parse(serde_json::json!(15000)).unwrap(), 15000);
+        assert_eq!(parse(serde_json::json!("0.015 BTC")).unwrap(), 1_500_000);
+        assert_eq!(parse(serde_json::json!("15000 sats")).unwrap(), 15000);
+        assert_eq!(parse(serde_json::json!("1 sat")).unwrap(), 1);
+        assert_eq!(parse(serde_json::json!("0.00000001 BTC")).unwrap(), 1);
+        assert_eq!(parse(serde_json::json!("2000 msat")).unwrap(), 2);
+        // sub-sat precision
+        assert!(parse(serde_json::json!("0.000000015 BTC")).is_err());
+        assert!(parse(serde_json::json!("1500 msat")).is_err());
+        assert!(parse(serde_json::json!("1.5 sats")).is_err());
+        // strings need a unit, and BTC decimals must be strings
+        assert!(parse(serde_json::json!("15000")).is_err());
+        assert!(parse(serde_json::json!(0.015)).is_err());
+        assert!(parse(serde_json::json!(-1)).is_err());
+        assert!(parse(serde_json::json!("-1 BTC")).is_err());
+        // emitting is unchanged
+        assert_eq!(
+            serde_json::to_value(AmountU64::from(1_500_000u64)).unwrap(),
+            serde_json::json!(1_500_000)
+        );
+    }
+    #[test]
+    fn checked_arithmetic() {
+        let max = AmountU64::from(u64::MAX);
+        let one = AmountU64::from(1u64);
+        assert_eq!(max.checked_add(one), None);
+        assert_eq!(max.checked_sub(max), Some(AmountU64::from(0u64)));
+        assert_eq!(one.checked_sub(max), None);
+        assert_eq!(max.checked_mul_ratio(u64::MAX, u64::MAX), Some(max));
+        assert_eq!(max.checked_mul_ratio(2, 1), None);
+        assert_eq!(max.checked_mul_ratio(1, 0), None);
+        assert_eq!(vec![max, one].into_iter().sum::<Option<AmountU64>>(), None);
+        assert_eq!(
+            [one, one, one].iter().sum::<Option<AmountU64>>(),
+            Some(AmountU64::from(3u64))
+        );
+        assert_eq!(
+            Vec::<AmountU64>::new()
+                .into_iter()
+                .sum::<Option<AmountU64>>(),
+            Some(AmountU64::from(0u64))
+        );
+    }
+    #[test]
+    fn royalty_rounding() {
+        // a 2% royalty rounds down, and the seller gets the remainder
+        let price = AmountU64::from(1_049u64);
+        let (artist, seller) = price.checked_split_ratio(20_000, 1_000_000).unwrap();
+        assert_eq!(u64::from(artist), 20);
+        assert_eq!(u64::from(seller), 1_029);
+        let (artist, seller) = AmountU64::from(u64::MAX).checked_split_ratio(1, 3).unwrap();
+        assert_eq!(u64::from(artist), u64::MAX / 3);
+        assert_eq!(u64::from(artist) + u64::from(seller), u64::MAX);
+        // more than the whole can't be split off
+        assert_eq!(price.checked_split_ratio(3, 2), None);
+    }
+    #[test]
+    fn set_operations() {
+        let child = range(10, 100);
+        let parent = range(50, 200);
+        assert_eq!(child.intersect(&parent), range(50, 100));
+        assert_eq!(child.union_hull(&parent), range(10, 200));
+        assert!(child.contains(Amount::from_sat(10)));
+        assert!(!child.contains(Amount::from_sat(101)));
+
+        let disjoint = range(0, 5).intersect(&range(6, 10));
+        assert!(disjoint.is_empty());
+        assert!(!disjoint.contains(Amount::from_sat(5)));
+        assert_eq!(disjoint.union_hull(&child), child);
+        let touching = range(0, 5).intersect(&range(5, 10));
+        assert_eq!(touching, AmountRange::exactly(Amount::from_sat(5)));
+        assert!(!touching.is_empty());
+
+        // unset bounds are unbounded
+        let any = AmountRange::new();
+        assert!(any.contains(Amount::max_value()) && !any.is_empty());
+        assert_eq!(any.intersect(&child), child);
+        assert_eq!(any.union_hull(&child), any);
+
+        assert_eq!(
+            AmountRange::fold(vec![child, parent, range(0, 60)], AmountRange::intersect),
+            Some(range(50, 60))
+        );
+        assert_eq!(AmountRange::fold(vec![], AmountRange::union_hull), None);
+    }
+    #[test]
+    fn update_range() {
+        let mut r = AmountRange::new();
+        r.update_range(Amount::from_sat(20));
+        r.update_range(Amount::from_sat(10));
+        r.update_range(Amount::from_sat(30));
+        assert_eq!(r, range(10, 30));
+        assert_eq!(r.max(), Amount::from_sat(30));
+    }
+    #[test]
+    fn midpoint() {
+        assert_eq!(range(10, 31).midpoint(), Some(Amount::from_sat(20)));
+        assert_eq!(range(10, 10).midpoint(), Some(Amount::from_sat(10)));
+        assert_eq!(range(30, 10).midpoint(), None);
+        assert_eq!(AmountRange::new().midpoint(), None);
+    }
 }
@@ -0,0 +1,243 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Utilities for paying many recipients at once
+use crate::contract::actions::{ThenFunc, ThenFuncAsFinishOrFunc};
+use crate::contract::{Compilable, CompilationError, Context, Contract, TxTmplIt};
+use crate::template::Builder;
+use bitcoin::util::amount::Amount;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// The most outputs a template in a payment tree may have, which is at least
+/// 2 so that each level of the tree is smaller than the last
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(try_from = "usize", into = "usize")]
+pub struct Radix(#[schemars(range(min = 2))] usize);
+
+impl Radix {
+    /// the radix as a number
+    pub fn get(self) -> usize {
+        self.0
+    }
+}
+
+impl TryFrom<usize> for Radix {
+    type Error = CompilationError;
+    fn try_from(radix: usize) -> Result<Self, Self::Error> {
+        if radix < 2 {
+            Err(CompilationError::TerminateWith(format!(
+                "a tree's radix must be at least 2, not {}",
+                radix
+            )))
+        } else {
+            Ok(Radix(radix))
+        }
+    }
+}
+
+impl From<Radix> for usize {
+    fn from(r: Radix) -> usize {
+        r.0
+    }
+}
+
+/// A recipient of a `TreePay`
+#[derive(Clone)]
+pub enum Payee {
+    /// an address from outside of Sapio, which must be for the network being
+    /// compiled for
+    Address(bitcoin::Address),
+    /// a contract, compiled for the amount it is paid
+    Contract(Arc<dyn Compilable>),
+}
+
+/// Pays many recipients through a tree of committed templates, each with at
+/// most `radix` outputs, so that a single small transaction commits to all of
+/// the payments and the rest of the tree can be expanded later, e.g. when
+/// fees are lower.
+///
+/// Recipients are split as evenly as possible between the branches of each
+/// level. Every template in the tree reserves `fee_per_tx`, so the tree needs
+/// `TreePay::total_amount` in total.
+#[derive(Clone)]
+pub struct TreePay {
+    /// who to pay, and how much
+    pub payouts: Vec<(Payee, Amount)>,
+    /// the most outputs any template in the tree may have
+    pub radix: Radix,
+    /// the fee reserved by each template in the tree
+    pub fee_per_tx: Amount,
+}
+
+impl TreePay {
+    /// the number of templates in the tree
+    pub fn templates(&self) -> u64 {
+        if self.payouts.len() <= self.radix.get() {
+            1
+        } else {
+            1 + self
+                .branches()
+                .iter()
+                .filter(|b| b.payouts.len() > 1)
+                .map(TreePay::templates)
+                .sum::<u64>()
+        }
+    }
+
+    /// the funds needed to pay everyone and every template's fee
+    pub fn total_amount(&self) -> Amount {
+        self.payouts
+            .iter()
+            .fold(self.fee_per_tx * self.templates(), |acc, (_, a)| acc + *a)
+    }
+
+    /// split the payouts into `radix` branches, the first `len % radix` of
+    /// which get one extra payout
+    fn branches(&self) -> Vec<TreePay> {
+        let radix = self.radix.get();
+        let (base, extra) = (self.payouts.len() / radix, self.payouts.len() % radix);
+        let mut rest = &self.payouts[..];
+        (0..radix)
+            .map(|i| {
+                let (these, others) = rest.split_at(base + (i < extra) as usize);
+                rest = others;
+                TreePay {
+                    payouts: these.to_vec(),
+                    ..self.clone()
+                }
+            })
+            .collect()
+    }
+
+    fn pay(builder: Builder, payee: &Payee, amount: Amount) -> Result<Builder, CompilationError> {
+        match payee {
+            Payee::Address(address) => builder.add_address_output(address, amount),
+            Payee::Contract(contract) => builder.add_output(amount, contract.as_ref(), None),
+        }
+    }
+
+    fn expand(&self, ctx: Context) -> TxTmplIt {
+        let mut builder = ctx.template();
+        if self.payouts.len() <= self.radix.get() {
+            for (payee, amount) in self.payouts.iter() {
+                builder = Self::pay(builder, payee, *amount)?;
+            }
+        } else {
+            for branch in self.branches() {
+                builder = match &branch.payouts[..] {
+                    [(payee, amount)] => Self::pay(builder, payee, *amount)?,
+                    _ => builder.add_output(branch.total_amount(), &branch, None)?,
+                };
+            }
+        }
+        builder.add_fees(self.fee_per_tx)?.into()
+    }
+
+    fn expand_fn<'a>() -> Option<ThenFuncAsFinishOrFunc<'a, Self, ()>> {
+        Some(
+            ThenFunc {
+                guard: &[],
+                guard_combinator: Default::default(),
+                conditional_compile_if: &[],
+                func: |s: &TreePay, ctx, _| s.expand(ctx),
+                name: Arc::new("expand".into()),
+                fee_policy: Default::default(),
+                weight: None,
+            }
+            .into(),
+        )
+    }
+}
+
+impl Contract for TreePay {
+    declare! {then, Self::expand_fn}
+    declare! {non updatable}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::Compiled;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{KeyPair, Network, XOnlyPublicKey};
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use std::convert::TryFrom;
+
+    fn tree(n: u8, sats: u64) -> TreePay {
+        let secp = Secp256k1::new();
+        let payouts = (1..=n)
+            .map(|i| {
+                let kp = KeyPair::from_seckey_slice(&secp, &[i; 32]).unwrap();
+                let key = XOnlyPublicKey::from_keypair(&kp).0;
+                let address = bitcoin::Address::p2tr(&secp, key, None, Network::Regtest);
+                (Payee::Address(address), Amount::from_sat(sats))
+            })
+            .collect();
+        TreePay {
+            payouts,
+            radix: Radix::try_from(4).unwrap(),
+            fee_per_tx: Amount::from_sat(500),
+        }
+    }
+    fn compile(t: &TreePay) -> Result<Compiled, CompilationError> {
+        let ctx = Context::new(
+            Network::Regtest,
+            t.total_amount(),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("batching").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        );
+        t.compile(ctx)
+    }
+    /// the (depth, templates, sats paid out) of a compiled tree
+    fn walk(c: &Compiled) -> (usize, u64, u64) {
+        c.ctv_to_tx.values().flat_map(|t| t.outputs.iter()).fold(
+            (1, 1, 0),
+            |(depth, templates, paid), o| {
+                if o.added_metadata.is_external() {
+                    (depth, templates, paid + o.amount.as_sat())
+                } else {
+                    let (d, t, p) = walk(&o.contract);
+                    (depth.max(d + 1), templates + t, paid + p)
+                }
+            },
+        )
+    }
+    #[test]
+    fn tree_shapes() {
+        for (n, depth, templates) in [(1, 1, 1), (4, 1, 1), (17, 3, 6)] {
+            let t = tree(n, 10_000);
+            assert_eq!(t.templates(), templates);
+            let compiled = compile(&t).unwrap();
+            assert_eq!(walk(&compiled), (depth, templates, 10_000 * n as u64));
+            let root = compiled.ctv_to_tx.values().next().unwrap();
+            assert_eq!(root.max, t.total_amount());
+            assert!(root.outputs.len() <= 4);
+        }
+    }
+    #[test]
+    fn radix_below_two() {
+        assert!(Radix::try_from(0).is_err());
+        assert!(Radix::try_from(1).is_err());
+        assert!(serde_json::from_str::<Radix>("1").is_err());
+        let radix: Radix = serde_json::from_str("2").unwrap();
+        assert_eq!(radix.get(), 2);
+    }
+    #[test]
+    fn dust_leaves_error() {
+        // the dusty leaf is below the root, so the error is wrapped once
+        // per branch it failed in, with the dusty leaf's path
+        let e = compile(&tree(5, 100)).unwrap_err();
+        assert!(matches!(
+            e.root_cause(),
+            CompilationError::OutputBelowDust { .. }
+        ));
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Canonical JSON, following the JSON Canonicalization Scheme (RFC 8785),
+//! for hashing serialized values
+//!
+//! The canonical form of a value has no whitespace, object members sorted
+//! by the UTF-16 code units of their keys, strings escaped as little as
+//! JSON allows, and floats written as ECMAScript writes numbers, so it does
+//! not depend on the serde_json version or the order maps are iterated in.
+//! Unlike RFC 8785, integers are written exactly rather than as the nearest
+//! float, as amounts in sats may exceed 2^53.
+use serde::ser::{self, Serialize};
+use serde_json::Value;
+
+/// Errors serializing a value canonically
+#[derive(Debug)]
+pub enum CanonicalJsonError {
+    /// The value could not be serialized to JSON
+    Serialization(serde_json::Error),
+    /// The value has a NaN or infinite float, which JSON has no form for
+    NonFiniteNumber(f64),
+}
+
+impl std::error::Error for CanonicalJsonError {}
+impl std::fmt::Display for CanonicalJsonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+impl From<serde_json::Error> for CanonicalJsonError {
+    fn from(e: serde_json::Error) -> Self {
+        CanonicalJsonError::Serialization(e)
+    }
+}
+impl ser::Error for CanonicalJsonError {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        CanonicalJsonError::Serialization(serde_json::Error::custom(msg))
+    }
+}
+
+/// The canonical JSON of `value`.
+///
+/// serde_json writes NaN and infinite floats as `null`, so they are
+/// rejected before the value is serialized.
+pub fn to_canonical_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, CanonicalJsonError> {
+    value.serialize(&mut FiniteCheck)?;
+    let value = serde_json::to_value(value)?;
+    let mut out = String::new();
+    write_value(&mut out, &value)?;
+    Ok(out.into_bytes())
+}
+
+fn write_value(out: &mut String, value: &Value) -> Result<(), CanonicalJsonError> {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                out.push_str(&u.to_string())
+            } else if let Some(i) = n.as_i64() {
+                out.push_str(&i.to_string())
+            } else {
+                let f = n.as_f64().unwrap_or(f64::NAN);
+                if !f.is_finite() {
+                    return Err(CanonicalJsonError::NonFiniteNumber(f));
+                }
+                out.push_str(&format_f64(f))
+            }
+        }
+        Value::String(s) => out.push_str(&serde_json::to_string(s)?),
+        Value::Array(a) => {
+            out.push('[');
+            for (i, v) in a.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_value(out, v)?;
+            }
+            out.push(']');
+        }
+        Value::Object(m) => {
+            let mut members: Vec<_> = m.iter().collect();
+            members.sort_by_cached_key(|(k, _)| k.encode_utf16().collect::<Vec<u16>>());
+            out.push('{');
+            for (i, (k, v)) in members.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&serde_json::to_string(k)?);
+                out.push(':');
+                write_value(out, v)?;
+            }
+            out.push('}');
+        }
+    }
+    Ok(())
+}
+
+/// `f`, which must be finite, as ECMAScript's `Number.prototype.toString`
+/// writes it
+fn format_f64(f: f64) -> String {
+    if f == 0.0 {
+        // including -0
+        return "0".into();
+    }
+    let sign = if f < 0.0 { "-" } else { "" };
+    // the shortest digits which round trip, as d.ddde<exp>
+    let sci = format!("{:e}", f.abs());
+    let (mantissa, exp) = sci.split_once('e').expect("{:e} has an exponent");
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let k = digits.len() as i32;
+    // the decimal point comes after the first n digits
+    let n = exp.parse::<i32>().expect("{:e} exponent is an integer") + 1;
+    let body = if k <= n && n <= 21 {
+        format!("{}{}", digits, "0".repeat((n - k) as usize))
+    } else if 0 < n && n <= 21 {
+        format!("{}.{}", &digits[..n as usize], &digits[n as usize..])
+    } else if -6 < n && n <= 0 {
+        format!("0.{}{}", "0".repeat(-n as usize), digits)
+    } else {
+        let e = n - 1;
+        let e = if e < 0 {
+            format!("-{}", -e)
+        } else {
+            format!("+{}", e)
+        };
+        if k == 1 {
+            format!("{}e{}", digits, e)
+        } else {
+            format!("{}.{}e{}", &digits[..1], &digits[1..], e)
+        }
+    };
+    format!("{}{}", sign, body)
+}
+
+/// A serializer which only checks every float is finite
+struct FiniteCheck;
+
+type Check = Result<(), CanonicalJsonError>;
+
+fn finite(f: f64) -> Check {
+    if f.is_finite() {
+        Ok(())
+    } else {
+        Err(CanonicalJsonError::NonFiniteNumber(f))
+    }
+}
+
+impl ser::Serializer for &mut FiniteCheck {
+    type Ok = ();
+    type Error = CanonicalJsonError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+    fn serialize_bool(self, _: bool) -> Check {
+        Ok(())
+    }
+    fn serialize_i8(self, _: i8) -> Check {
+        Ok(())
+    }
+    fn serialize_i16(self, _: i16) -> Check {
+        Ok(())
+    }
+    fn serialize_i32(self, _: i32) -> Check {
+        Ok(())
+    }
+    fn serialize_i64(self, _: i64) -> Check {
+        Ok(())
+    }
+    fn serialize_u8(self, _: u8) -> Check {
+        Ok(())
+    }
+    fn serialize_u16(self, _: u16) -> Check {
+        Ok(())
+    }
+    fn serialize_u32(self, _: u32) -> Check {
+        Ok(())
+    }
+    fn serialize_u64(self, _: u64) -> Check {
+        Ok(())
+    }
+    fn serialize_f32(self, f: f32) -> Check {
+        finite(f.into())
+    }
+    fn serialize_f64(self, f: f64) -> Check {
+        finite(f)
+    }
+    fn serialize_char(self, _: char) -> Check {
+        Ok(())
+    }
+    fn serialize_str(self, _: &str) -> Check {
+        Ok(())
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Check {
+        Ok(())
+    }
+    fn serialize_none(self) -> Check {
+        Ok(())
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Check {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> Check {
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Check {
+        Ok(())
+    }
+    fn serialize_unit_variant(self, _: &'static str, _: u32, _: &'static str) -> Check {
+        Ok(())
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _: &'static str, value: &T) -> Check {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        value: &T,
+    ) -> Check {
+        value.serialize(self)
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Self, CanonicalJsonError> {
+        Ok(self)
+    }
+}
+
+/// the compound parts of `FiniteCheck`, which check each element
+macro_rules! check_elements {
+    ($($t:ident::$f:ident($($key:ident),*)),*) => {
+        $(
+            impl ser::$t for &mut FiniteCheck {
+                type Ok = ();
+                type Error = CanonicalJsonError;
+                fn $f<T: Serialize + ?Sized>(&mut self, $($key: &'static str,)* value: &T) -> Check {
+                    $(let _ = $key;)*
+                    value.serialize(&mut **self)
+                }
+                fn end(self) -> Check {
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+check_elements!(
+    SerializeSeq::serialize_element(),
+    SerializeTuple::serialize_element(),
+    SerializeTupleStruct::serialize_field(),
+    SerializeTupleVariant::serialize_field(),
+    SerializeStruct::serialize_field(key),
+    SerializeStructVariant::serialize_field(key)
+);
+
+impl ser::SerializeMap for &mut FiniteCheck {
+    type Ok = ();
+    type Error = CanonicalJsonError;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Check {
+        key.serialize(&mut **self)
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Check {
+        value.serialize(&mut **self)
+    }
+    fn end(self) -> Check {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::contract::abi::object::graph::test::{check_snapshot, nft_sale};
+    use crate::contract::Compiled;
+    use crate::Context;
+    use bitcoin::util::amount::Amount;
+    use bitcoin::Network;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("canonical").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+    fn canonical<T: Serialize>(t: &T) -> String {
+        String::from_utf8(to_canonical_vec(t).unwrap()).unwrap()
+    }
+    #[test]
+    fn rfc_8785_numbers() {
+        // from RFC 8785 appendix B
+        for (f, s) in [
+            (0.0, "0"),
+            (-0.0, "0"),
+            (5e-324, "5e-324"),
+            (1.7976931348623157e308, "1.7976931348623157e+308"),
+            (9007199254740992.0, "9007199254740992"),
+            (295147905179352830000.0, "295147905179352830000"),
+            (1e21, "1e+21"),
+            (1e-7, "1e-7"),
+            (0.000001, "0.000001"),
+            (-1.5e-7, "-1.5e-7"),
+            (333333333.3333333, "333333333.3333333"),
+            (0.1, "0.1"),
+            (2.0, "2"),
+        ] {
+            assert_eq!(canonical(&f), s);
+        }
+    }
+    #[test]
+    fn sorted_compact_members() {
+        // sorted by UTF-16 code units, so U+1F600 comes before U+FB33
+        let v = json!({"\u{fb33}": 1, "b": [true, null, "\u{1f}é"], "\u{1f600}": {}, "a": 1.5});
+        assert_eq!(
+            canonical(&v),
+            "{\"a\":1.5,\"b\":[true,null,\"\\u001fé\"],\"\u{1f600}\":{},\"\u{fb33}\":1}"
+        );
+    }
+    #[test]
+    fn rejects_non_finite() {
+        let mut royalties = BTreeMap::new();
+        royalties.insert("artist", f64::NAN);
+        assert!(matches!(
+            to_canonical_vec(&royalties),
+            Err(CanonicalJsonError::NonFiniteNumber(_))
+        ));
+        assert!(matches!(
+            to_canonical_vec(&Some(f32::INFINITY)),
+            Err(CanonicalJsonError::NonFiniteNumber(_))
+        ));
+    }
+    #[test]
+    fn canonical_nft_sale() {
+        let compiled = ctx().compile(nft_sale()).unwrap();
+        let canonical = String::from_utf8(compiled.canonical_bytes().unwrap()).unwrap();
+        check_snapshot(
+            "nft_sale.json",
+            &canonical,
+            include_str!("../contract/abi/object/snapshots/nft_sale.json"),
+        );
+        check_snapshot(
+            "nft_sale.sha256",
+            &compiled.canonical_hash().unwrap().to_string(),
+            include_str!("../contract/abi/object/snapshots/nft_sale.sha256"),
+        );
+        // the canonical form parses back to an object with the same form
+        let parsed: Compiled = serde_json::from_str(&canonical).unwrap();
+        assert_eq!(parsed.canonical_bytes().unwrap(), canonical.as_bytes());
+    }
+}
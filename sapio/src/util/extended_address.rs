@@ -43,6 +43,11 @@ impl ExtendedAddress {
             bitcoin::Script::new_op_return(slice),
         )))
     }
+    /// create an OP_RETURN address type carrying `data`, without the length
+    /// check of `make_op_return`. Callers are responsible for standardness.
+    pub fn make_data_carrier(data: &[u8]) -> Self {
+        ExtendedAddress::OpReturn(OpReturn(bitcoin::Script::new_op_return(data)))
+    }
 }
 
 /// Internal type for processing OpReturn through serde
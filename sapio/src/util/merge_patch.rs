@@ -0,0 +1,54 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! JSON Merge Patch (RFC 7396) for partially updating arguments
+use serde_json::Value;
+
+/// Applies `patch` onto `target` following RFC 7396: objects are merged
+/// recursively, a `null` member removes that member, and anything else
+/// replaces the target outright.
+pub fn merge_patch(target: &mut Value, patch: Value) {
+    match patch {
+        Value::Object(patch) => {
+            if !target.is_object() {
+                *target = Value::Object(Default::default());
+            }
+            if let Value::Object(map) = target {
+                for (k, v) in patch {
+                    if v.is_null() {
+                        map.remove(&k);
+                    } else {
+                        merge_patch(map.entry(k).or_insert(Value::Null), v);
+                    }
+                }
+            }
+        }
+        patch => *target = patch,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    #[test]
+    fn rfc_7396_examples() {
+        let mut target = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["example", "sample"], "content": "This will be unchanged"});
+        merge_patch(
+            &mut target,
+            json!({"title": "Hello!", "phoneNumber": "+01-123-456-7890", "author": {"familyName": null}, "tags": ["example"]}),
+        );
+        assert_eq!(
+            target,
+            json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["example"], "content": "This will be unchanged", "phoneNumber": "+01-123-456-7890"})
+        );
+        let mut target = json!(["a"]);
+        merge_patch(&mut target, json!({"a": {"b": null}}));
+        assert_eq!(target, json!({"a": {}}));
+        merge_patch(&mut target, json!("replaced"));
+        assert_eq!(target, json!("replaced"));
+    }
+}
@@ -6,4 +6,7 @@
 
 //! Basic functionality / structs for Sapio
 pub mod amountrange;
+pub mod batching;
+pub mod canonical_json;
 pub mod extended_address;
+pub mod merge_patch;
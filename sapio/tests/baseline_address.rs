@@ -0,0 +1,70 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Contracts compile to the same addresses as before, unless a `Context`
+//! opts in to something which changes them.
+use bitcoin::hashes::hex::ToHex;
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::amount::Amount;
+use bitcoin::{KeyPair, XOnlyPublicKey};
+use sapio::contract::{Compilable, Contract};
+use sapio::*;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+fn key(k: u8) -> XOnlyPublicKey {
+    let secp = Secp256k1::new();
+    let kp = KeyPair::from_seckey_slice(&secp, &[k; 32]).unwrap();
+    XOnlyPublicKey::from_keypair(&kp).0
+}
+
+/// spendable by key 1, or sent on to key 2 once key 3 signs
+struct HappyPath;
+
+impl HappyPath {
+    #[guard]
+    fn happy(self, _ctx: Context) {
+        Clause::Key(key(1))
+    }
+    #[guard]
+    fn cold(self, _ctx: Context) {
+        Clause::Key(key(3))
+    }
+    #[then(guarded_by = "[Self::cold]")]
+    fn sweep(self, ctx: Context) {
+        let amount = ctx.funds();
+        ctx.template().add_output(amount, &key(2), None)?.into()
+    }
+}
+
+impl Contract for HappyPath {
+    declare! {then, Self::sweep}
+    declare! {finish, Self::happy}
+    declare! {non updatable}
+}
+
+fn ctx() -> Context {
+    Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("test").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    )
+}
+
+/// the address `HappyPath` compiled to before internal key promotion
+const BASELINE: &str = "512096a0e43b09a1f60cbec4b4418fc39ec56c9c2865e96331bdf8c3c5af10a7b28b";
+
+#[test]
+fn baseline_address() {
+    let script = |ctx| bitcoin::Script::from(HappyPath.compile(ctx).unwrap().address).to_hex();
+    assert_eq!(script(ctx()), BASELINE);
+    assert_ne!(script(ctx().with_internal_key_promotion(true)), BASELINE);
+}
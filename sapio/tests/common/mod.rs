@@ -0,0 +1,58 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! A global allocator counting each thread's allocations and the bytes it
+//! has live, shared by the tests and benches measuring memory. Including
+//! this module installs it for the whole binary, so only binaries measuring
+//! memory should.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Counts the allocations and bytes of each thread, so work running
+/// concurrently doesn't disturb each other's measurements
+pub struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    static LIVE_BYTES: Cell<usize> = const { Cell::new(0) };
+    static PEAK_BYTES: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.with(|a| a.set(a.get() + 1));
+        let live = LIVE_BYTES.with(|l| {
+            l.set(l.get() + layout.size());
+            l.get()
+        });
+        PEAK_BYTES.with(|p| p.set(p.get().max(live)));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // memory may be freed by a different thread than allocated it
+        LIVE_BYTES.with(|l| l.set(l.get().saturating_sub(layout.size())));
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// the allocations made by this thread so far
+#[allow(dead_code)]
+pub fn allocations() -> usize {
+    ALLOCATIONS.with(|a| a.get())
+}
+
+/// the most bytes live at once on this thread while running `f`, beyond
+/// those live before it
+#[allow(dead_code)]
+pub fn peak_bytes<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let start = LIVE_BYTES.with(|l| l.get());
+    PEAK_BYTES.with(|p| p.set(start));
+    let t = f();
+    (t, PEAK_BYTES.with(|p| p.get()) - start)
+}
@@ -0,0 +1,128 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Streaming templates to a sink keeps them out of a compile's memory. A
+//! binary of its own, as it measures memory with the counting allocator.
+mod common;
+
+use bitcoin::util::amount::Amount;
+use common::peak_bytes;
+use sapio::contract::{Compilable, Contract};
+
+use sapio::*;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::timelocks::AbsHeight;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+const ENUMERATED: u32 = 100_000;
+
+/// a committed template for each of `ENUMERATED` lock times
+struct Enumerated;
+impl Enumerated {
+    #[then]
+    fn settle(self, ctx: Context) {
+        let mut ctx = ctx;
+        Ok(Box::new((1..=ENUMERATED).map(move |i| {
+            let ctx = ctx.derive_num(i as u64)?;
+            let funds = ctx.funds();
+            ctx.template()
+                .explicitly_burn(funds)?
+                .set_lock_time(AbsHeight::try_from(i)?.into())?
+                .finalize()
+        })))
+    }
+}
+impl Contract for Enumerated {
+    declare! {then, Self::settle}
+    declare! {non updatable}
+}
+
+fn ctx() -> Context {
+    Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("streamed").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    )
+}
+
+#[test]
+fn streamed_templates() {
+    let (unstreamed, unstreamed_peak) = peak_bytes(|| Enumerated.compile(ctx()).unwrap());
+    let count = Arc::new(AtomicU32::new(0));
+    let counter = count.clone();
+    let streaming = ctx().with_template_sink(Arc::new(move |_| {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }));
+    let (streamed, streamed_peak) = peak_bytes(|| Enumerated.compile(streaming).unwrap());
+    assert_eq!(count.load(Ordering::Relaxed), ENUMERATED);
+    assert!(streamed.ctv_to_tx.is_empty() && streamed.suggested_txs.is_empty());
+    assert_eq!(streamed.branches, unstreamed.branches);
+    // each template is committed, so its clause is in the output either
+    // way, but streaming never holds the templates themselves
+    let (templates, templates_size) =
+        peak_bytes(|| unstreamed.ctv_to_tx.values().cloned().collect::<Vec<_>>());
+    assert_eq!(templates.len(), ENUMERATED as usize);
+    assert!(unstreamed_peak - streamed_peak > templates_size);
+    assert_eq!(
+        bitcoin::Script::from(streamed.address),
+        bitcoin::Script::from(unstreamed.address)
+    );
+}
+
+/// a committed template for each of `ENUMERATED` lock times, splitting
+/// the funds between two keys
+struct Settled;
+impl Settled {
+    #[then]
+    fn settle(self, ctx: Context) {
+        let mut ctx = ctx;
+        let half = ctx.funds() / 2;
+        let secp = bitcoin::secp256k1::Secp256k1::new();
+        let key = |i: u8| {
+            let key = bitcoin::KeyPair::from_seckey_slice(&secp, &[i; 32]).unwrap();
+            bitcoin::XOnlyPublicKey::from_keypair(&key).0
+        };
+        let (alice, bob) = (key(1), key(2));
+        Ok(Box::new((1..=ENUMERATED).map(move |i| {
+            ctx.derive_num(i as u64)?
+                .template()
+                .add_output(half, &alice, None)?
+                .add_output(half, &bob, None)?
+                .set_lock_time(AbsHeight::try_from(i)?.into())?
+                .finalize()
+        })))
+    }
+}
+impl Contract for Settled {
+    declare! {then, Self::settle}
+    declare! {non updatable}
+}
+
+#[test]
+fn streamed_committed_templates() {
+    let unstreamed = Settled.compile(ctx()).unwrap();
+    let (templates, templates_size) =
+        peak_bytes(|| unstreamed.ctv_to_tx.values().cloned().collect::<Vec<_>>());
+    assert_eq!(templates.len(), ENUMERATED as usize);
+    drop(templates);
+    let streaming = ctx().with_template_sink(Arc::new(|_| Ok(())));
+    let (streamed, streamed_peak) = peak_bytes(|| Settled.compile(streaming).unwrap());
+    assert!(streamed.ctv_to_tx.is_empty());
+    assert_eq!(streamed.branches, unstreamed.branches);
+    assert_eq!(
+        bitcoin::Script::from(streamed.address),
+        bitcoin::Script::from(unstreamed.address.clone())
+    );
+    // each committed template's clause is extracted as it streams, so the
+    // templates are never all held at once
+    assert!(streamed_peak < templates_size);
+}
@@ -0,0 +1,62 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! `#[guard(weighted_threshold = k)]` fails compilation when its threshold
+//! can't be built, rather than locking the funds.
+use bitcoin::secp256k1::Secp256k1;
+use bitcoin::util::amount::Amount;
+use bitcoin::{KeyPair, XOnlyPublicKey};
+use sapio::contract::{Compilable, CompilationError, Compiled, Contract};
+use sapio::*;
+use sapio_base::clause::WeightedThresholdError;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+fn key(k: u8) -> Clause {
+    let secp = Secp256k1::new();
+    let kp = KeyPair::from_seckey_slice(&secp, &[k; 32]).unwrap();
+    Clause::Key(XOnlyPublicKey::from_keypair(&kp).0)
+}
+
+struct Weighted {
+    k: usize,
+}
+
+impl Weighted {
+    #[guard(weighted_threshold = 3)]
+    fn signed(self, _ctx: Context) {
+        (1..=self.k as u8).map(|k| (key(k), k as usize)).collect()
+    }
+}
+
+impl Contract for Weighted {
+    declare! {finish, Self::signed}
+    declare! {non updatable}
+}
+
+fn compile(k: usize) -> Result<Compiled, CompilationError> {
+    let ctx = Context::new(
+        bitcoin::Network::Regtest,
+        Amount::ONE_BTC,
+        Arc::new(CTVAvailable),
+        EffectPath::try_from("test").unwrap(),
+        Arc::new(MapEffectDB::default()),
+    );
+    Weighted { k }.compile(ctx)
+}
+
+#[test]
+fn weighted_guards() {
+    // key 2 and key 1 together, or key 3 alone
+    assert!(compile(3).is_ok());
+    match compile(1).as_ref().map_err(CompilationError::root_cause) {
+        Err(CompilationError::WeightedThreshold(WeightedThresholdError::Unreachable(3, 1))) => {}
+        r => panic!("expected an unreachable threshold, got {:?}", r.map(|_| ())),
+    }
+}
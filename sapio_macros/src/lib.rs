@@ -19,10 +19,16 @@ use syn::{parse_macro_input, AttributeArgs, ItemFn, Meta, NestedMeta};
 /// fn name(self, ctx: Context) {
 ///     /*ConditionallyCompileType*/
 /// }
+/// /// `Never` unless compiling for one of these networks, otherwise the body
+/// /// decides
+/// #[compile_if(networks = "[Regtest, Signet]")]
+/// fn name(self, ctx: Context) {
+///     ConditionalCompileType::NoConstraint
+/// }
 /// ```
 #[proc_macro_attribute]
 pub fn compile_if(args: TokenStream, input: TokenStream) -> TokenStream {
-    let _args = parse_macro_input!(args as AttributeArgs);
+    let args = parse_macro_input!(args as AttributeArgs);
     let input = parse_macro_input!(input as ItemFn);
     if input.sig.inputs.len() != 2 {
         panic!("Too may Arguments to function");
@@ -31,15 +37,41 @@ pub fn compile_if(args: TokenStream, input: TokenStream) -> TokenStream {
     let name = input.sig.ident;
     let compile_if_name = format_ident!("compile_if_{}", name);
     let block = input.block;
+    let compile_if_f = match networks(&args) {
+        Some(networks) => quote! {
+            |s, ctx| match sapio::contract::actions::ConditionalCompileType::on_networks(&ctx, &#networks) {
+                sapio::contract::actions::ConditionalCompileType::NoConstraint => Self::#compile_if_name(s, ctx),
+                never => never,
+            }
+        },
+        None => quote! {Self::#compile_if_name},
+    };
     proc_macro::TokenStream::from(quote! {
         fn #compile_if_name(&self, #context_arg) -> sapio::contract::actions::ConditionalCompileType
         #block
         fn #name() -> Option<sapio::contract::actions::ConditionallyCompileIf<Self>> {
-            Some(sapio::contract::actions::ConditionallyCompileIf::Fresh(Self::#compile_if_name))
+            Some(sapio::contract::actions::ConditionallyCompileIf::Fresh(#compile_if_f))
         }
     })
 }
 
+fn networks(args: &Vec<NestedMeta>) -> Option<proc_macro2::TokenStream> {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("networks") => match &v.lit {
+                Lit::Str(l) => {
+                    let networks: syn::ExprArray = l.parse().expect("Token Stream Parsing");
+                    let networks = networks.elems.iter();
+                    return Some(quote! {[#(sapio::contract::macros::Network::#networks),*]});
+                }
+                _ => panic!("Improperly Formatted {:?}", v),
+            },
+            _ => continue,
+        }
+    }
+    None
+}
+
 /// The guard macro is used to define a `Guard`. Guards may be cached or uncached.
 /// formats for calling are:
 /// ```ignore
@@ -50,6 +82,14 @@ pub fn compile_if(args: TokenStream, input: TokenStream) -> TokenStream {
 /// fn name(self, ctx) {
 ///     /*Clause*/
 /// }
+/// /// A threshold where each clause may count more than once, see
+/// /// `sapio_base::clause::WeightedThreshold`. If the threshold can't be
+/// /// built, e.g. it can't be reached, compilation fails with
+/// /// `CompilationError::WeightedThreshold`.
+/// #[guard(weighted_threshold = 3)]
+/// fn name(self, ctx) {
+///     /*Vec<(Clause, usize)>*/
+/// }
 /// ```
 #[proc_macro_attribute]
 pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
@@ -64,19 +104,50 @@ pub fn guard(args: TokenStream, input: TokenStream) -> TokenStream {
     let block = input.block;
     let mut ty = format_ident!("Fresh");
     let simp_gen_f = simp_at(&args).unwrap_or(TokenStream::from_str("None").unwrap().into());
+    let mut weighted_threshold = None;
     for arg in args {
         match arg {
-            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("cached") => {
-                ty = format_ident!("Cached");
+            NestedMeta::Meta(Meta::Path(v)) if v.is_ident("cached") => {
+                ty = format_ident!("Cache");
+            }
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("weighted_threshold") => {
+                match v.lit {
+                    Lit::Int(k) => weighted_threshold = Some(k),
+                    _ => panic!("Improperly Formatted {:?}", v),
+                }
             }
             _ => {}
         }
     }
+    let mut guard_fn = format_ident!("Fn");
+    let guard_f = match weighted_threshold {
+        Some(k) => {
+            let weighted_name = format_ident!("weighted_{}", name);
+            let ctx = match context_arg {
+                syn::FnArg::Typed(t) => &t.pat,
+                _ => panic!("Wrong type: {:?}", context_arg),
+            };
+            guard_fn = format_ident!("Fallible");
+            quote! {
+                fn #weighted_name(&self, #context_arg) -> Vec<(sapio::sapio_base::Clause, usize)>
+                #block
+                fn #guard_name(&self, #context_arg) -> Result<sapio::sapio_base::Clause, sapio::contract::CompilationError> {
+                    Ok(<sapio::sapio_base::Clause as sapio::sapio_base::clause::WeightedThreshold>::weighted_threshold(
+                        #k,
+                        &self.#weighted_name(#ctx),
+                    )?)
+                }
+            }
+        }
+        None => quote! {
+            fn #guard_name(&self, #context_arg) -> sapio::sapio_base::Clause
+            #block
+        },
+    };
     proc_macro::TokenStream::from(quote! {
-        fn #guard_name(&self, #context_arg) -> sapio::sapio_base::Clause
-        #block
+        #guard_f
         fn  #name() -> Option<sapio::contract::actions::Guard<Self>> {
-            Some(sapio::contract::actions::Guard::#ty(Self::#guard_name, #simp_gen_f))
+            Some(sapio::contract::actions::Guard::#ty(sapio::contract::actions::GuardFn::#guard_fn(Self::#guard_name), #simp_gen_f))
         }
     })
 }
@@ -89,7 +160,10 @@ fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2:
             (_, None, NestedMeta::Meta(Meta::NameValue(v))) if v.path.is_ident("guarded_by") => {
                 match &v.lit {
                     Lit::Str(l) => {
-                        guarded_by_array = Some(l.parse().expect("Token Stream Parsing"));
+                        let guards: syn::ExprArray = l.parse().expect("Token Stream Parsing");
+                        let guards = guards.elems.iter();
+                        guarded_by_array =
+                            Some(quote! {[#(sapio::contract::actions::GuardGen::Fn(#guards)),*]});
                     }
                     _ => panic!("Improperly Formatted {:?}", v),
                 }
@@ -117,6 +191,58 @@ fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2:
     )
 }
 
+fn guard_combinator(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("guard_combinator") => {
+                match &v.lit {
+                    Lit::Str(l) => {
+                        let combinator: proc_macro2::TokenStream =
+                            l.parse().expect("Token Stream Parsing");
+                        return quote! {sapio::contract::actions::GuardCombinator::#combinator};
+                    }
+                    _ => panic!("Improperly Formatted {:?}", v),
+                }
+            }
+            _ => continue,
+        }
+    }
+    quote! {sapio::contract::actions::GuardCombinator::All}
+}
+
+fn fee_policy(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("fee_policy") => match &v.lit {
+                Lit::Str(l) => {
+                    let policy: proc_macro2::TokenStream = l.parse().expect("Token Stream Parsing");
+                    return quote! {sapio::contract::actions::FeePolicy::#policy};
+                }
+                _ => panic!("Improperly Formatted {:?}", v),
+            },
+            _ => continue,
+        }
+    }
+    quote! {sapio::contract::actions::FeePolicy::None}
+}
+
+fn weight(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("weight") => {
+                let w: f64 = match &v.lit {
+                    Lit::Float(l) => l.base10_parse().expect("Float Parsing"),
+                    Lit::Int(l) => l.base10_parse().expect("Int Parsing"),
+                    _ => panic!("Improperly Formatted {:?}", v),
+                };
+                return quote! {Some(#w)};
+            }
+            _ => continue,
+        }
+    }
+    quote! {None}
+}
+
 /// The then macro is used to define a `ThenFunction`.
 /// formats for calling are:
 /// ```ignore
@@ -125,7 +251,15 @@ fn get_arrays(args: &Vec<NestedMeta>) -> (proc_macro2::TokenStream, proc_macro2:
 ///     /// optional: only compile these branches if these compile_if statements permit
 ///     compile_if= "[compile_if_1, ... compile_if_n]",
 ///     /// optional: protect these branches with the conjunction (and) of these clauses
-///     guarded_by= "[guard_1, ... guard_n]"
+///     guarded_by= "[guard_1, ... guard_n]",
+///     /// optional: combine the guards with "All" (default), "Any", or "Threshold(k)"
+///     guard_combinator= "Threshold(2)",
+///     /// optional: reserve fees from the funds, "None" (default),
+///     /// "Reserve(amount)", or "ReserveRate(sats_per_vbyte)"
+///     fee_policy= "Reserve(Amount::from_sat(1000))",
+///     /// optional: how likely this branch is to be used relative to the
+///     /// others (default 1.0), so likelier branches are cheaper to spend
+///     weight= 0.9
 /// )]
 /// fn name(self, ctx) {
 ///     /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -143,14 +277,20 @@ pub fn then(args: TokenStream, input: TokenStream) -> TokenStream {
     let then_fn_name = format_ident!("then_{}", name);
     let block = input.block;
     let (cia, gba) = get_arrays(&args);
+    let combinator = guard_combinator(&args);
+    let fees = fee_policy(&args);
+    let weight = weight(&args);
     proc_macro::TokenStream::from(quote! {
             /// (missing docs fix)
             fn #name<'a>() -> Option<sapio::contract::actions::ThenFuncAsFinishOrFunc<'a, Self, <Self as sapio::contract::Contract>::StatefulArguments>>{
                 Some(sapio::contract::actions::ThenFunc{
                     guard: &#gba,
+                    guard_combinator: #combinator,
                     conditional_compile_if: &#cia,
                     func: Self::#then_fn_name,
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
+                    fee_policy: #fees,
+                    weight: #weight,
                 }.into())
             }
             /// (missing docs fix)
@@ -184,7 +324,79 @@ fn coerce_args(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
             _ => continue,
         }
     }
-    panic!("No Coerce Arguments found");
+    quote! {sapio::contract::actions::coerce_json}
+}
+fn compile_if_args(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("compile_if_args") => {
+                match &v.lit {
+                    Lit::Str(l) => {
+                        return l.parse().expect("Token Stream Parsing");
+                    }
+                    _ => panic!("Improperly Formatted {:?}", v),
+                }
+            }
+            _ => continue,
+        }
+    }
+    quote! {[]}
+}
+fn returned_template_schema(args: &Vec<NestedMeta>) -> proc_macro2::TokenStream {
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("returned_template_schema") => {
+                match &v.lit {
+                    Lit::Str(l) => {
+                        let ty: syn::Type = l.parse().expect("Token Stream Parsing");
+                        return quote! {Some(sapio::contract::macros::get_schema_for::<#ty>())};
+                    }
+                    _ => panic!("Improperly Formatted {:?}", v),
+                }
+            }
+            _ => continue,
+        }
+    }
+    quote! {None}
+}
+fn web_display(
+    args: &Vec<NestedMeta>,
+) -> (
+    proc_macro2::TokenStream,
+    proc_macro2::TokenStream,
+    proc_macro2::TokenStream,
+) {
+    let mut order = quote! {None};
+    let mut hidden = quote! {false};
+    let mut description = quote! {None};
+    for arg in args {
+        match arg {
+            NestedMeta::Meta(Meta::List(l)) if l.path.is_ident("web") => {
+                for item in l.nested.iter() {
+                    match item {
+                        NestedMeta::Meta(Meta::Path(v)) if v.is_ident("hidden") => {
+                            hidden = quote! {true};
+                        }
+                        NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("order") => {
+                            match &v.lit {
+                                Lit::Int(i) => order = quote! {Some(#i)},
+                                _ => panic!("Improperly Formatted {:?}", v),
+                            }
+                        }
+                        NestedMeta::Meta(Meta::NameValue(v)) if v.path.is_ident("description") => {
+                            match &v.lit {
+                                Lit::Str(d) => description = quote! {Some(#d.into())},
+                                _ => panic!("Improperly Formatted {:?}", v),
+                            }
+                        }
+                        _ => panic!("Unknown web argument {:?}", item),
+                    }
+                }
+            }
+            _ => continue,
+        }
+    }
+    (order, hidden, description)
 }
 fn simp_at(args: &Vec<NestedMeta>) -> Option<proc_macro2::TokenStream> {
     for arg in args {
@@ -248,14 +460,28 @@ fn web_api_schema(
 ///     #[continuation(
 ///         /// required: guards for the miniscript clauses required
 ///         guarded_by = "[Self::guard_1,... Self::guard_n]",
+///         /// optional: combine the guards with "All" (default), "Any", or "Threshold(k)"
+///         guard_combinator = "Any",
 ///         /// optional: Conditional compilation
 ///         compile_if = "[Self::compile_if_1, ... Self::compile_if_n]",
+///         /// optional: Conditional compilation on the arguments, after coerce_args
+///         /// e.g., fn compile_if_args_1(&self, ctx: &Context, o: &UpdateType) -> ConditionalCompileType
+///         compile_if_args = "[Self::compile_if_args_1, ... Self::compile_if_args_n]",
 ///         ///  optional: Enables compiling this for a json callable continuation
 ///         web_api,
-///         /// helper for coercing args for json api, could be arbitrary
+///         /// helper for coercing args for json api, could be arbitrary.
+///         /// optional if StatefulArguments is serde_json::Value, in which
+///         /// case `coerce_json` is used.
 ///         coerce_args = "default_coerce",
 ///         /// simps
 ///         simps = "simp_gen",
+///         /// optional: a JsonSchema type describing the templates returned
+///         returned_template_schema = "ReturnedTemplates",
+///         /// optional: display metadata for a UI, all fields optional
+///         web(order = 2, hidden, description = "Does a thing"),
+///         /// optional: how likely this branch is to be used relative to
+///         /// the others (default 1.0), see `#[then]`
+///         weight = 0.1,
 ///     )]
 ///     fn name(self, ctx:Context, o:UpdateType) {
 ///         /*Result<Box<Iterator<TransactionTemplate>>>*/
@@ -287,6 +513,11 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
     let web_api_schema_s = web_api_schema(&args, &continue_schema_for_name, arg_type);
     let coerce_args_f = coerce_args(&args);
     let simp_gen_f = simp_at(&args).unwrap_or(TokenStream::from_str("None").unwrap().into());
+    let combinator = guard_combinator(&args);
+    let ciaa = compile_if_args(&args);
+    let (display_order, hidden, description) = web_display(&args);
+    let returned_schema = returned_template_schema(&args);
+    let weight = weight(&args);
     proc_macro::TokenStream::from(quote! {
             #web_api_schema_s
             /// (missing docs fix)
@@ -300,13 +531,21 @@ pub fn continuation(args: TokenStream, input: TokenStream) -> TokenStream {
                     simp_gen: #simp_gen_f,
                     coerce_args: #coerce_args_f,
                     guard: &#gba,
+                    guard_combinator: #combinator,
                     conditional_compile_if: &#cia,
+                    conditional_compile_if_args: &#ciaa,
                     func: Self::#continue_name,
                     schema: Self::#continue_schema_for_name.map(|f|f()),
+                    returned_template_schema: #returned_schema,
                     name: std::sync::Arc::new(std::stringify!(#name).into()),
                     f: std::default::Default::default(),
                     returned_txtmpls_modify_guards: false,
-                    extract_clause_from_txtmpl: sapio::contract::actions::default_extract_clause_from_txtmpl
+                    extract_clause_from_txtmpl: sapio::contract::actions::default_extract_clause_from_txtmpl,
+                    fee_policy: sapio::contract::actions::FeePolicy::None,
+                    weight: #weight,
+                    display_order: #display_order,
+                    hidden: #hidden,
+                    description: #description,
                 };
                 Some(Box::new(f))
             }
@@ -0,0 +1,497 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Binding a compiled contract to a coin found or funded by a bitcoind
+//! wallet, checking the coin pays the contract before anything is signed
+use bitcoin::{consensus::Decodable, psbt::PartiallySignedTransaction};
+use bitcoin::{Address, Network, OutPoint, Transaction, TxOut};
+use bitcoincore_rpc_async::RpcApi;
+use miniscript::descriptor::DescriptorTrait;
+use sapio::{
+    contract::{
+        object::{LinkedPSBT, ObjectMetadata, Program, SapioStudioObject},
+        Compiled,
+    },
+    template::{OutputMeta, TemplateMetadata},
+    util::{amountrange::AmountRange, extended_address::ExtendedAddress},
+};
+use sapio_base::{
+    serialization_helpers::SArc,
+    txindex::{TxIndex, TxIndexLogger},
+};
+use sapio_ctv_emulator_trait::CTVEmulator;
+use std::{collections::BTreeMap, convert::TryInto, error::Error, fmt::Display, rc::Rc, sync::Arc};
+
+/// Why a coin can't fund a compiled contract
+#[derive(Debug)]
+pub enum FundingError {
+    /// the contract has no address to pay, e.g. it's an opaque script
+    NoAddress,
+    /// the contract was compiled as a dry run, so its address is unspendable
+    Skeleton,
+    /// the contract's address is for another network than the one expected
+    WrongNetwork {
+        /// the contract's address
+        address: Address,
+        /// the network expected, of the request or the node
+        expected: Network,
+    },
+    /// the node is on a network other than the request's
+    WrongNodeNetwork {
+        /// the node's network
+        node: String,
+        /// the request's network
+        expected: Network,
+    },
+    /// the funding transaction doesn't pay the contract's address
+    NoOutput(bitcoin::Txid),
+    /// the output paying the contract is not an amount it accepts
+    AmountOutOfRange {
+        /// the output's amount
+        amount: bitcoin::Amount,
+        /// the amounts the contract accepts
+        range: AmountRange,
+    },
+}
+
+impl Display for FundingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FundingError::NoAddress => write!(f, "the contract has no address to fund"),
+            FundingError::Skeleton => write!(f, "the contract is a dry run, and can't be funded"),
+            FundingError::WrongNetwork { address, expected } => {
+                write!(f, "address {} is not for network {}", address, expected)
+            }
+            FundingError::WrongNodeNetwork { node, expected } => {
+                write!(f, "the node is on chain {}, not network {}", node, expected)
+            }
+            FundingError::NoOutput(txid) => {
+                write!(f, "transaction {} doesn't pay the contract", txid)
+            }
+            FundingError::AmountOutOfRange { amount, range } => write!(
+                f,
+                "{} is outside the amounts the contract accepts, from {:?} to {:?}",
+                amount,
+                range.min_bound(),
+                range.max_bound()
+            ),
+        }
+    }
+}
+impl Error for FundingError {}
+
+/// A compiled contract bound to a coin by `bind_with_rpc`
+pub struct RpcBound {
+    /// the bound contract, with the funding transaction under `funding` if it
+    /// was created
+    pub program: Program,
+    /// the transaction creating the coin, which still needs signing by the
+    /// wallet if it was created
+    pub funding: Transaction,
+    /// the output of `funding` paying the contract
+    pub vout: u32,
+    /// if `funding` was created by the wallet, rather than already paying the
+    /// contract
+    pub created: bool,
+}
+
+/// The contract's address on `net`, which it must be for if it was compiled
+/// from one
+pub(crate) fn address_for(compiled: &Compiled, net: Network) -> Result<Address, FundingError> {
+    if compiled.skeleton {
+        return Err(FundingError::Skeleton);
+    }
+    match &compiled.address {
+        ExtendedAddress::Address(a) if a.is_valid_for_network(net) => Ok(a.clone()),
+        ExtendedAddress::Address(a) => Err(FundingError::WrongNetwork {
+            address: a.clone(),
+            expected: net,
+        }),
+        ExtendedAddress::Descriptor(d) => d.address(net).map_err(|_| FundingError::NoAddress),
+        _ => Err(FundingError::NoAddress),
+    }
+}
+
+/// Check that output `vout` of `tx` pays `compiled`, on `net`, an amount it
+/// accepts
+pub fn check_funding(
+    compiled: &Compiled,
+    net: Network,
+    tx: &Transaction,
+    vout: u32,
+) -> Result<(), FundingError> {
+    let script = address_for(compiled, net)?.script_pubkey();
+    let output = tx
+        .output
+        .get(vout as usize)
+        .filter(|o| o.script_pubkey == script)
+        .ok_or_else(|| FundingError::NoOutput(tx.txid()))?;
+    let amount = bitcoin::Amount::from_sat(output.value);
+    if !compiled.amount_range.contains(amount) {
+        return Err(FundingError::AmountOutOfRange {
+            amount,
+            range: compiled.amount_range,
+        });
+    }
+    Ok(())
+}
+
+/// the network of bitcoind's `chain` name
+fn chain_network(chain: &str) -> Option<Network> {
+    match chain {
+        "main" => Some(Network::Bitcoin),
+        "test" => Some(Network::Testnet),
+        "signet" => Some(Network::Signet),
+        "regtest" => Some(Network::Regtest),
+        _ => None,
+    }
+}
+
+/// Bind `compiled` to a coin paying it from `client`'s wallet.
+///
+/// An unspent output already paying the contract an amount it accepts is
+/// used if there is one, and otherwise the wallet funds a transaction paying
+/// it the most it accepts, which is returned unsigned and not broadcast. The
+/// node, the contract and `net` must all be for the same network, and the
+/// funding is checked with `check_funding` before binding.
+pub async fn bind_with_rpc<R: RpcApi + Sync>(
+    client: &R,
+    compiled: &Compiled,
+    net: Network,
+    emulator: &dyn CTVEmulator,
+) -> Result<RpcBound, Box<dyn Error>> {
+    let address = address_for(compiled, net)?;
+    let info: serde_json::Value = client.call("getblockchaininfo", &[]).await?;
+    let chain = info["chain"].as_str().unwrap_or_default();
+    if chain_network(chain) != Some(net) {
+        return Err(FundingError::WrongNodeNetwork {
+            node: chain.into(),
+            expected: net,
+        })?;
+    }
+    let unspent = client
+        .list_unspent(None, None, Some(&[&address]), None, None)
+        .await?;
+    let found = unspent
+        .iter()
+        .find(|u| u.spendable && compiled.amount_range.contains(u.amount));
+    let (funding, vout, created) = if let Some(u) = found {
+        let tx = client.get_transaction(&u.txid, None).await?.transaction()?;
+        (tx, u.vout, false)
+    } else {
+        let unfunded = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: compiled.amount_range.max().as_sat(),
+                script_pubkey: address.script_pubkey(),
+            }],
+        };
+        let res = client
+            .fund_raw_transaction(&unfunded, None, Some(true))
+            .await?;
+        let tx = Transaction::consensus_decode(&res.hex[..])?;
+        let script = address.script_pubkey();
+        let vout = tx
+            .output
+            .iter()
+            .position(|o| o.script_pubkey == script)
+            .ok_or_else(|| FundingError::NoOutput(tx.txid()))?;
+        (tx, vout as u32, true)
+    };
+    bind_funding(compiled, net, funding, vout, created, emulator)
+}
+
+/// Bind `compiled` to output `vout` of `funding`, once `check_funding`
+/// passes, adding `funding` to the program if it was `created`
+pub(crate) fn bind_funding(
+    compiled: &Compiled,
+    net: Network,
+    funding: Transaction,
+    vout: u32,
+    created: bool,
+    emulator: &dyn CTVEmulator,
+) -> Result<RpcBound, Box<dyn Error>> {
+    check_funding(compiled, net, &funding, vout)?;
+    let logger = Rc::new(TxIndexLogger::new());
+    (*logger).add_tx(Arc::new(funding.clone()))?;
+    let mut program = compiled.bind_psbt(
+        OutPoint::new(funding.txid(), vout),
+        BTreeMap::new(),
+        logger,
+        emulator,
+    )?;
+    if created {
+        add_funding(&mut program, funding.clone())?;
+    }
+    Ok(RpcBound {
+        program,
+        funding,
+        vout,
+        created,
+    })
+}
+
+/// Add `tx`, which funds the contract, to `program` under `funding`
+pub fn add_funding(program: &mut Program, tx: Transaction) -> Result<(), Box<dyn Error>> {
+    let added_output_metadata = vec![OutputMeta::default(); tx.output.len()];
+    let output_metadata = vec![ObjectMetadata::default(); tx.output.len()];
+    let out = tx
+        .input
+        .first()
+        .map(|i| i.previous_output)
+        .unwrap_or_default();
+    let psbt = PartiallySignedTransaction::from_unsigned_tx(tx)?;
+    program.program.insert(
+        SArc(Arc::new("funding".try_into()?)),
+        SapioStudioObject {
+            metadata: Default::default(),
+            out,
+            continue_apis: Default::default(),
+            txs: vec![LinkedPSBT {
+                psbt,
+                metadata: TemplateMetadata {
+                    label: Some("funding".into()),
+                    color: Some("pink".into()),
+                    extra: BTreeMap::new(),
+                    simp: Default::default(),
+                },
+                output_metadata,
+                added_output_metadata,
+            }
+            .into()],
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::consensus::encode::serialize_hex;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{Amount, KeyPair, Script, TxIn, Txid, XOnlyPublicKey};
+    use bitcoincore_rpc_async as rpc;
+    use sapio::contract::{Compilable, Contract};
+    use sapio::*;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use serde_json::{json, Value};
+    use std::convert::TryFrom;
+    use std::sync::Mutex;
+
+    /// pays its funds less a fee to a key
+    struct Pay;
+    impl Pay {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let key = KeyPair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+            let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+            ctx.template()
+                .add_fees(Amount::from_sat(1000))?
+                .add_output(amount, &XOnlyPublicKey::from_keypair(&key).0, None)?
+                .into()
+        }
+    }
+    impl Contract for Pay {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    fn compiled(net: Network) -> Compiled {
+        Pay.compile(Context::new(
+            net,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("pay").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        ))
+        .unwrap()
+    }
+
+    /// A mock of a regtest node's wallet, holding the transactions it has
+    /// received. `tests/regtest.rs` runs against a real node.
+    struct Wallet {
+        chain: &'static str,
+        received: Vec<Transaction>,
+        calls: Mutex<Vec<String>>,
+    }
+
+    impl Wallet {
+        fn new(chain: &'static str, received: Vec<Transaction>) -> Self {
+            Wallet {
+                chain,
+                received,
+                calls: Mutex::new(vec![]),
+            }
+        }
+        fn handle(&self, cmd: &str, args: &[Value]) -> Option<Value> {
+            self.calls.lock().unwrap().push(cmd.into());
+            Some(match cmd {
+                "getblockchaininfo" => json!({ "chain": self.chain }),
+                "listunspent" => {
+                    let addresses: Vec<String> = serde_json::from_value(args[2].clone()).ok()?;
+                    let unspent: Vec<_> = self
+                        .received
+                        .iter()
+                        .flat_map(|tx| tx.output.iter().enumerate().map(move |o| (tx, o)))
+                        .filter_map(|(tx, (vout, o))| {
+                            let a = Address::from_script(&o.script_pubkey, Network::Regtest)?;
+                            addresses.contains(&a.to_string()).then(|| {
+                                json!({
+                                    "txid": tx.txid(), "vout": vout, "address": a,
+                                    "scriptPubKey": o.script_pubkey,
+                                    "amount": Amount::from_sat(o.value).as_btc(),
+                                    "confirmations": 1, "spendable": true,
+                                    "solvable": true, "safe": true,
+                                })
+                            })
+                        })
+                        .collect();
+                    json!(unspent)
+                }
+                "gettransaction" => {
+                    let txid: Txid = serde_json::from_value(args[0].clone()).ok()?;
+                    let tx = self.received.iter().find(|tx| tx.txid() == txid)?;
+                    json!({
+                        "confirmations": 1, "txid": txid, "time": 0,
+                        "timereceived": 0, "bip125-replaceable": "no",
+                        "amount": 0.0, "details": [], "hex": serialize_hex(tx),
+                    })
+                }
+                "fundrawtransaction" => {
+                    let hex: Vec<u8> =
+                        bitcoin::hashes::hex::FromHex::from_hex(args[0].as_str()?).ok()?;
+                    let mut tx: Transaction = bitcoin::consensus::deserialize(&hex).ok()?;
+                    tx.input.push(TxIn {
+                        previous_output: OutPoint::new(
+                            Txid::from_hash(Hash::from_inner([1; 32])),
+                            0,
+                        ),
+                        ..Default::default()
+                    });
+                    tx.output.insert(
+                        0,
+                        TxOut {
+                            value: 50_000,
+                            script_pubkey: Script::new_v0_p2wsh(&Default::default()),
+                        },
+                    );
+                    json!({ "hex": serialize_hex(&tx), "fee": 0.00001, "changepos": 0 })
+                }
+                _ => return None,
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl RpcApi for Wallet {
+        async fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            args: &[Value],
+        ) -> rpc::Result<T> {
+            let v = self
+                .handle(cmd, args)
+                .ok_or(rpc::Error::UnexpectedStructure)?;
+            serde_json::from_value(v).map_err(rpc::Error::Json)
+        }
+    }
+
+    /// a transaction paying `compiled` `amount`
+    fn paying(compiled: &Compiled, amount: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: amount,
+                script_pubkey: compiled.address.clone().into(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn bind_with_mock_wallet() {
+        let compiled = compiled(Network::Regtest);
+        // too much to be the contract's funds
+        let overpaid = paying(&compiled, 150_000);
+        assert!(matches!(
+            check_funding(&compiled, Network::Regtest, &overpaid, 0),
+            Err(FundingError::AmountOutOfRange { .. })
+        ));
+        let wallet = Wallet::new("regtest", vec![overpaid]);
+        let bound = bind_with_rpc(&wallet, &compiled, Network::Regtest, &CTVAvailable)
+            .await
+            .unwrap();
+        assert!(bound.created);
+        assert_eq!(bound.vout, 1);
+        assert_eq!(bound.funding.output[1].value, 100_000);
+        assert!(bound
+            .program
+            .program
+            .contains_key(&SArc(Arc::new("funding".try_into().unwrap()))));
+        assert_eq!(
+            bound.program.program[&compiled.root_path].out,
+            OutPoint::new(bound.funding.txid(), 1)
+        );
+
+        // a coin paying the contract already is used as is
+        let paid = paying(&compiled, 100_000);
+        let wallet = Wallet::new("regtest", vec![paid.clone()]);
+        let bound = bind_with_rpc(&wallet, &compiled, Network::Regtest, &CTVAvailable)
+            .await
+            .unwrap();
+        assert!(!bound.created);
+        assert_eq!((bound.funding, bound.vout), (paid, 0));
+        assert!(!wallet
+            .calls
+            .lock()
+            .unwrap()
+            .contains(&"fundrawtransaction".into()));
+    }
+
+    #[tokio::test]
+    async fn wrong_network_fails_before_funding() {
+        let wallet = Wallet::new("regtest", vec![]);
+        let address =
+            Address::from_script(&compiled(Network::Regtest).address.into(), Network::Testnet)
+                .unwrap();
+        let testnet = Compiled::from_address(address, None);
+        let e = bind_with_rpc(&wallet, &testnet, Network::Regtest, &CTVAvailable)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(FundingError::WrongNetwork { .. })
+        ));
+        let e = bind_with_rpc(&wallet, &testnet, Network::Testnet, &CTVAvailable)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(
+            e.downcast_ref(),
+            Some(FundingError::WrongNodeNetwork { .. })
+        ));
+        assert_eq!(*wallet.calls.lock().unwrap(), vec!["getblockchaininfo"]);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_are_not_funded() {
+        let wallet = Wallet::new("regtest", vec![]);
+        let mut skeleton = compiled(Network::Regtest);
+        skeleton.skeleton = true;
+        let e = bind_with_rpc(&wallet, &skeleton, Network::Regtest, &CTVAvailable)
+            .await
+            .err()
+            .unwrap();
+        assert!(matches!(e.downcast_ref(), Some(FundingError::Skeleton)));
+        assert!(wallet.calls.lock().unwrap().is_empty());
+    }
+}
@@ -12,6 +12,11 @@ use bitcoincore_rpc_async as rpc;
 use rpc::RpcApi;
 use sapio_base::txindex::{TxIndex, TxIndexError};
 use std::sync::Arc;
+
+pub mod fund;
+pub mod select;
+pub mod sequence;
+pub mod watch;
 /// A TxIndex based on a Bitcoin RPC Client
 pub struct BitcoinNodeIndex {
     /// RPC Client
@@ -0,0 +1,567 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Selecting a wallet's coins to fund a compiled contract, and binding the
+//! contract to the funding transaction built from them
+use crate::fund::{address_for, bind_funding, FundingError, RpcBound};
+use bitcoin::consensus::encode::VarInt;
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use bitcoin::{Amount, Network, OutPoint, Script, Transaction, TxIn, TxOut, Txid};
+use bitcoincore_rpc_async::RpcApi;
+use sapio::contract::Compiled;
+use sapio_ctv_emulator_trait::CTVEmulator;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Display;
+
+/// A coin a wallet can spend
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    /// the coin
+    pub outpoint: OutPoint,
+    /// its amount and script
+    pub txout: TxOut,
+    /// if the wallet has locked it against being spent
+    #[serde(default)]
+    pub locked: bool,
+}
+
+/// How to fund a contract with `select_coins`
+#[derive(Clone, Debug)]
+pub struct SelectionParams {
+    /// the feerate, in sats per vbyte
+    pub feerate: Amount,
+    /// the script any change is paid to
+    pub change: Script,
+    /// the seed of the random search used when no coins match the amount
+    /// exactly, so that a selection can be reproduced
+    pub seed: u64,
+    /// if change below the dust limit is added to the fee, rather than being
+    /// an error
+    pub dust_to_fee: bool,
+}
+
+/// The coins selected to fund a contract
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Selection {
+    /// the coins, largest first
+    pub coins: Vec<Utxo>,
+    /// the change, if there is any
+    pub change: Option<Amount>,
+    /// the fee paid
+    pub fee: Amount,
+}
+
+/// Why coins couldn't be selected to fund a contract
+#[derive(Debug)]
+pub enum SelectionError {
+    /// the contract can't be funded with the amount, e.g. it's outside of
+    /// the amounts it accepts
+    Funding(FundingError),
+    /// the coins aren't enough to pay the amount and fees
+    InsufficientFunds {
+        /// the amount and fees
+        needed: Amount,
+        /// what the spendable coins are worth, less the fees to spend them
+        available: Amount,
+    },
+    /// the coins would be enough, but only with some the wallet has locked
+    Locked {
+        /// the amount and fees
+        needed: Amount,
+        /// what the unlocked coins are worth, less the fees to spend them
+        available: Amount,
+        /// the locked coins
+        locked: Vec<OutPoint>,
+    },
+    /// every selection of the coins would leave change below the dust limit,
+    /// see `SelectionParams::dust_to_fee`
+    DustChange {
+        /// the change which would be left
+        change: Amount,
+        /// the dust limit of the change script
+        dust: Amount,
+    },
+}
+
+impl Display for SelectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SelectionError::Funding(e) => write!(f, "{}", e),
+            SelectionError::InsufficientFunds { needed, available } => write!(
+                f,
+                "insufficient funds, needed {} but only {} is available",
+                needed, available
+            ),
+            SelectionError::Locked {
+                needed,
+                available,
+                locked,
+            } => write!(
+                f,
+                "needed {} but only {} is available, unless {} locked coins are unlocked",
+                needed,
+                available,
+                locked.len()
+            ),
+            SelectionError::DustChange { change, dust } => write!(
+                f,
+                "the change of {} would be below the dust limit of {}",
+                change, dust
+            ),
+        }
+    }
+}
+impl Error for SelectionError {}
+
+impl From<FundingError> for SelectionError {
+    fn from(e: FundingError) -> Self {
+        SelectionError::Funding(e)
+    }
+}
+
+/// the vbytes of a transaction besides its inputs and outputs, rounded up
+const OVERHEAD_VBYTES: u64 = 11;
+/// how many tries the exact match search gets before giving up
+const BNB_TRIES: u32 = 100_000;
+/// how many random orders of the coins are tried for a selection with change
+const KNAPSACK_ROUNDS: usize = 1000;
+
+/// the vbytes spending a coin paying `script` adds, for the native segwit
+/// coins which are selected, as the contract is bound to the funding txid
+/// before it is signed
+fn input_vbytes(script: &Script) -> Option<u64> {
+    if script.is_v0_p2wpkh() {
+        Some(68)
+    } else if script.is_v1_p2tr() {
+        Some(58)
+    } else {
+        None
+    }
+}
+
+/// the vbytes of an output paying `script`
+fn output_vbytes(script: &Script) -> u64 {
+    let len = script.len() as u64;
+    8 + VarInt(len).len() as u64 + len
+}
+
+/// the sum of `values` at `picked`
+fn total(values: &[u64], picked: &[usize]) -> u64 {
+    picked.iter().map(|i| values[*i]).sum()
+}
+
+/// the coins, by their indexes in `values`, largest first, whose values sum
+/// to at least `target` and at most `tolerance` over it, wasting the least,
+/// searching depth first as Bitcoin Core's branch and bound does
+fn branch_and_bound(values: &[u64], target: u64, tolerance: u64) -> Option<Vec<usize>> {
+    struct Search<'a> {
+        values: &'a [u64],
+        // the sum of the values from each index on
+        rest: Vec<u64>,
+        target: u64,
+        tolerance: u64,
+        tries: u32,
+        picked: Vec<usize>,
+        best: Option<(u64, Vec<usize>)>,
+    }
+    impl Search<'_> {
+        fn from(&mut self, i: usize, sum: u64) {
+            if self.tries == 0 || sum > self.target + self.tolerance {
+                return;
+            }
+            self.tries -= 1;
+            if sum >= self.target {
+                let waste = sum - self.target;
+                if self.best.as_ref().is_none_or(|(w, _)| waste < *w) {
+                    self.best = Some((waste, self.picked.clone()));
+                }
+                return;
+            }
+            if i == self.values.len() || sum + self.rest[i] < self.target {
+                return;
+            }
+            self.picked.push(i);
+            self.from(i + 1, sum + self.values[i]);
+            self.picked.pop();
+            self.from(i + 1, sum);
+        }
+    }
+    let mut rest = vec![0; values.len() + 1];
+    for i in (0..values.len()).rev() {
+        rest[i] = rest[i + 1] + values[i];
+    }
+    let mut search = Search {
+        values,
+        rest,
+        target,
+        tolerance,
+        tries: BNB_TRIES,
+        picked: vec![],
+        best: None,
+    };
+    search.from(0, 0);
+    search.best.map(|(_, picked)| picked)
+}
+
+/// the coins, by their indexes in `values`, whose values sum to the least at
+/// or above `target` of those found by adding coins in random orders from
+/// `rng` until reaching it
+fn knapsack(values: &[u64], target: u64, rng: &mut StdRng) -> Option<Vec<usize>> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    let mut best: Option<(u64, Vec<usize>)> = None;
+    for _ in 0..KNAPSACK_ROUNDS {
+        order.shuffle(rng);
+        let mut sum = 0;
+        let mut picked = vec![];
+        for i in order.iter() {
+            if sum >= target {
+                break;
+            }
+            sum += values[*i];
+            picked.push(*i);
+        }
+        if sum >= target && best.as_ref().is_none_or(|(b, _)| sum < *b) {
+            picked.sort_unstable();
+            best = Some((sum, picked));
+        }
+    }
+    best.map(|(_, picked)| picked)
+}
+
+/// Select from `coins` to pay `amount` to `script`, with the fees and any
+/// change of `params`.
+///
+/// Coins are valued at their amount less the fee to spend them, and only
+/// unlocked native segwit coins are selected. A selection needing no change
+/// is searched for first, and failing that the random search seeded by
+/// `params` looks for the least needing change above the dust limit.
+pub fn select_coins(
+    coins: &[Utxo],
+    script: &Script,
+    amount: Amount,
+    params: &SelectionParams,
+) -> Result<Selection, SelectionError> {
+    let fee = |vbytes: u64| vbytes * params.feerate.as_sat();
+    let target = amount.as_sat() + fee(OVERHEAD_VBYTES + output_vbytes(script));
+    let change_fee = fee(output_vbytes(&params.change));
+    // what change costs, to create and to spend later
+    let cost_of_change = change_fee + fee(input_vbytes(&params.change).unwrap_or(68));
+    let dust = params.change.dust_value();
+    let effective = |c: &Utxo| {
+        let v = c
+            .txout
+            .value
+            .checked_sub(fee(input_vbytes(&c.txout.script_pubkey)?))?;
+        (v > 0).then_some(v)
+    };
+    let mut pool: Vec<(u64, &Utxo)> = coins
+        .iter()
+        .filter(|c| !c.locked)
+        .filter_map(|c| Some((effective(c)?, c)))
+        .collect();
+    pool.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.outpoint.cmp(&b.1.outpoint)));
+    let values: Vec<u64> = pool.iter().map(|(v, _)| *v).collect();
+    let available: u64 = values.iter().sum();
+    if available < target {
+        let locked: Vec<_> = coins
+            .iter()
+            .filter(|c| c.locked)
+            .filter_map(|c| Some((effective(c)?, c.outpoint)))
+            .collect();
+        let needed = Amount::from_sat(target);
+        let available_sat = Amount::from_sat(available);
+        return Err(
+            if !locked.is_empty()
+                && available + locked.iter().map(|(v, _)| v).sum::<u64>() >= target
+            {
+                SelectionError::Locked {
+                    needed,
+                    available: available_sat,
+                    locked: locked.into_iter().map(|(_, o)| o).collect(),
+                }
+            } else {
+                SelectionError::InsufficientFunds {
+                    needed,
+                    available: available_sat,
+                }
+            },
+        );
+    }
+    let (picked, change) = match branch_and_bound(&values, target, cost_of_change) {
+        Some(picked) => (picked, None),
+        None => {
+            let mut rng = StdRng::seed_from_u64(params.seed);
+            let with_change = target + change_fee + dust.as_sat();
+            match knapsack(&values, with_change, &mut rng) {
+                Some(picked) => {
+                    let change = total(&values, &picked) - target - change_fee;
+                    (picked, Some(Amount::from_sat(change)))
+                }
+                None => {
+                    let picked = knapsack(&values, target, &mut rng)
+                        .expect("the coins available are enough without change");
+                    if !params.dust_to_fee {
+                        let excess = total(&values, &picked) - target;
+                        return Err(SelectionError::DustChange {
+                            change: Amount::from_sat(excess.saturating_sub(change_fee)),
+                            dust,
+                        });
+                    }
+                    (picked, None)
+                }
+            }
+        }
+    };
+    let coins: Vec<Utxo> = picked.iter().map(|i| pool[*i].1.clone()).collect();
+    let spent: u64 = coins.iter().map(|c| c.txout.value).sum();
+    let fee = spent - amount.as_sat() - change.map_or(0, |c| c.as_sat());
+    Ok(Selection {
+        coins,
+        change,
+        fee: Amount::from_sat(fee),
+    })
+}
+
+/// The transaction funding `compiled` on `net` with `amount` from `coins`,
+/// selected by `select_coins`, as a PSBT for the wallet to sign, paying the
+/// contract with its first output and any change with its second.
+pub fn funding_psbt(
+    compiled: &Compiled,
+    net: Network,
+    coins: &[Utxo],
+    amount: Amount,
+    params: &SelectionParams,
+) -> Result<(PartiallySignedTransaction, Selection), SelectionError> {
+    if !compiled.amount_range.contains(amount) {
+        return Err(FundingError::AmountOutOfRange {
+            amount,
+            range: compiled.amount_range,
+        }
+        .into());
+    }
+    let script = address_for(compiled, net)?.script_pubkey();
+    let selection = select_coins(coins, &script, amount, params)?;
+    let mut output = vec![TxOut {
+        value: amount.as_sat(),
+        script_pubkey: script,
+    }];
+    if let Some(change) = selection.change {
+        output.push(TxOut {
+            value: change.as_sat(),
+            script_pubkey: params.change.clone(),
+        });
+    }
+    let tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: selection
+            .coins
+            .iter()
+            .map(|c| TxIn {
+                previous_output: c.outpoint,
+                sequence: 0xFFFF_FFFD,
+                ..Default::default()
+            })
+            .collect(),
+        output,
+    };
+    let mut psbt =
+        PartiallySignedTransaction::from_unsigned_tx(tx).expect("the inputs are unsigned");
+    for (input, coin) in psbt.inputs.iter_mut().zip(selection.coins.iter()) {
+        input.witness_utxo = Some(coin.txout.clone());
+    }
+    Ok((psbt, selection))
+}
+
+/// A compiled contract bound to a funding transaction by `bind_with_coins`
+pub struct CoinBound {
+    /// the bound contract, with the funding transaction under `funding`
+    pub bound: RpcBound,
+    /// the funding transaction, for the wallet to sign
+    pub psbt: PartiallySignedTransaction,
+    /// the coins it spends
+    pub selection: Selection,
+}
+
+/// Bind `compiled` to a new transaction funding it on `net` with `amount`
+/// from `coins`, built by `funding_psbt`. The transaction is returned for the
+/// wallet to sign, and as only segwit coins are spent, signing it doesn't
+/// change the outpoint bound.
+pub fn bind_with_coins(
+    compiled: &Compiled,
+    net: Network,
+    coins: &[Utxo],
+    amount: Amount,
+    params: &SelectionParams,
+    emulator: &dyn CTVEmulator,
+) -> Result<CoinBound, Box<dyn Error>> {
+    let (psbt, selection) = funding_psbt(compiled, net, coins, amount, params)?;
+    let bound = bind_funding(compiled, net, psbt.unsigned_tx.clone(), 0, true, emulator)?;
+    Ok(CoinBound {
+        bound,
+        psbt,
+        selection,
+    })
+}
+
+/// a coin listed by `listlockunspent`
+#[derive(Deserialize)]
+struct LockedCoin {
+    txid: Txid,
+    vout: u32,
+}
+
+/// The coins of `client`'s wallet to select from: those it can spend, and
+/// those it has locked, marked as such.
+pub async fn wallet_coins<R: RpcApi + Sync>(client: &R) -> Result<Vec<Utxo>, Box<dyn Error>> {
+    let mut coins: Vec<Utxo> = client
+        .list_unspent(None, None, None, None, None)
+        .await?
+        .into_iter()
+        .filter(|u| u.spendable && u.safe)
+        .map(|u| Utxo {
+            outpoint: OutPoint::new(u.txid, u.vout),
+            txout: TxOut {
+                value: u.amount.as_sat(),
+                script_pubkey: u.script_pub_key,
+            },
+            locked: false,
+        })
+        .collect();
+    let locked: Vec<LockedCoin> = client.call("listlockunspent", &[]).await?;
+    for l in locked {
+        let tx = client.get_transaction(&l.txid, None).await?.transaction()?;
+        if let Some(txout) = tx.output.get(l.vout as usize) {
+            coins.push(Utxo {
+                outpoint: OutPoint::new(l.txid, l.vout),
+                txout: txout.clone(),
+                locked: true,
+            });
+        }
+    }
+    Ok(coins)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{Address, KeyPair, WPubkeyHash, XOnlyPublicKey};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+
+    fn p2wpkh(seed: u8) -> Script {
+        Script::new_v0_p2wpkh(&WPubkeyHash::hash(&[seed]))
+    }
+    fn coin(seed: u8, value: u64) -> Utxo {
+        Utxo {
+            outpoint: OutPoint::new(Txid::from_hash(Hash::from_inner([seed; 32])), 0),
+            txout: TxOut {
+                value,
+                script_pubkey: p2wpkh(seed),
+            },
+            locked: false,
+        }
+    }
+    fn params(seed: u64) -> SelectionParams {
+        SelectionParams {
+            feerate: Amount::from_sat(2),
+            change: p2wpkh(0),
+            seed,
+            dust_to_fee: false,
+        }
+    }
+    fn contract() -> Compiled {
+        let key = KeyPair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+        let key = XOnlyPublicKey::from_keypair(&key).0;
+        let address = Address::p2tr(&Secp256k1::new(), key, None, Network::Regtest);
+        Compiled::from_address(address, None)
+    }
+
+    #[test]
+    fn exact_match_needs_no_change() {
+        let compiled = contract();
+        let amount = Amount::from_sat(50_000);
+        // the first two cover the amount and fees of 2 * (11 + 43 + 2 * 68)
+        // exactly
+        let coins = [
+            coin(1, 30_136),
+            coin(2, 20_244),
+            coin(3, 45_000),
+            coin(4, 80_000),
+        ];
+        let bound = bind_with_coins(
+            &compiled,
+            Network::Regtest,
+            &coins,
+            amount,
+            &params(0),
+            &CTVAvailable,
+        )
+        .unwrap();
+        let selection = &bound.selection;
+        assert_eq!(selection.coins, vec![coins[0].clone(), coins[1].clone()]);
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.fee, Amount::from_sat(380));
+        let tx = &bound.psbt.unsigned_tx;
+        assert_eq!(tx.output.len(), 1);
+        assert_eq!(tx.output[0].value, 50_000);
+        assert!(bound.psbt.inputs.iter().all(|i| i.witness_utxo.is_some()));
+        assert_eq!(
+            bound.bound.program.program[&compiled.root_path].out,
+            OutPoint::new(tx.txid(), 0)
+        );
+
+        let mut locked = coins.clone();
+        locked[3].locked = true;
+        let script = Script::from(compiled.address.clone());
+        let e = select_coins(&locked, &script, Amount::from_sat(150_000), &params(0));
+        assert!(matches!(
+            e,
+            Err(SelectionError::Locked { locked, .. }) if locked == vec![coins[3].outpoint]
+        ));
+        let e = select_coins(&coins, &script, Amount::from_sat(1_000_000), &params(0));
+        assert!(matches!(e, Err(SelectionError::InsufficientFunds { .. })));
+    }
+
+    #[test]
+    fn seeded_selection_is_deterministic() {
+        let script = Script::from(contract().address);
+        let coins: Vec<_> = (1..=20)
+            .map(|i| coin(i, 10_000 + i as u64 * 1_237))
+            .collect();
+        let amount = Amount::from_sat(73_000);
+        let first = select_coins(&coins, &script, amount, &params(7)).unwrap();
+        assert_eq!(
+            first,
+            select_coins(&coins, &script, amount, &params(7)).unwrap()
+        );
+        let change = first.change.unwrap();
+        assert!(change >= p2wpkh(0).dust_value());
+        let spent: u64 = first.coins.iter().map(|c| c.txout.value).sum();
+        assert_eq!(
+            spent,
+            amount.as_sat() + change.as_sat() + first.fee.as_sat()
+        );
+
+        // 300 over the amount and fees, too much to drop without change but
+        // too little for change above the dust limit
+        let coins = [coin(1, 50_000 + 108 + 136 + 300)];
+        let e = select_coins(&coins, &script, Amount::from_sat(50_000), &params(7));
+        assert!(matches!(
+            e,
+            Err(SelectionError::DustChange { change, .. }) if change == Amount::from_sat(238)
+        ));
+        let mut dust_to_fee = params(7);
+        dust_to_fee.dust_to_fee = true;
+        let selection =
+            select_coins(&coins, &script, Amount::from_sat(50_000), &dust_to_fee).unwrap();
+        assert_eq!(selection.change, None);
+        assert_eq!(selection.fee, Amount::from_sat(544));
+    }
+}
@@ -0,0 +1,611 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Broadcasting the transactions of a path through a bound contract as soon
+//! as each one's time locks allow
+use bitcoin::{Transaction, Txid};
+use bitcoincore_rpc_async as rpc;
+use rpc::RpcApi;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// nSequence with this bit set has no relative lock
+const SEQUENCE_DISABLE: u32 = 1 << 31;
+/// nSequence with this bit set is a relative lock in units of 512 seconds
+const SEQUENCE_TYPE_TIME: u32 = 1 << 22;
+/// nLockTime below this is a height, otherwise a time
+const LOCK_TIME_THRESHOLD: u32 = 500_000_000;
+
+/// How a `Sequencer` broadcasts
+#[derive(Debug, Clone, Copy)]
+pub struct SequencerConfig {
+    /// the confirmations every coin a transaction spends must have before it
+    /// is broadcast
+    pub confirmations: u32,
+    /// how long to wait after the first rejection of a transaction, doubled
+    /// after each further one
+    pub initial_backoff: Duration,
+    /// the longest to wait between attempts
+    pub max_backoff: Duration,
+}
+
+impl Default for SequencerConfig {
+    fn default() -> Self {
+        SequencerConfig {
+            confirmations: 1,
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(600),
+        }
+    }
+}
+
+/// What the next transaction is waiting for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Wait {
+    /// a coin's transaction to confirm to the configured depth
+    Parent {
+        /// the transaction
+        txid: Txid,
+        /// how many confirmations it has
+        confirmations: u32,
+    },
+    /// the chain tip to reach this height
+    Height(u32),
+    /// the median time past of the chain tip to reach this time
+    Time(u32),
+}
+
+/// What one `Sequencer::step` did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Step {
+    /// the transaction was broadcast, or the node already had it
+    Sent(Txid),
+    /// the transaction can't be broadcast yet
+    Waiting {
+        /// the transaction
+        txid: Txid,
+        /// what it waits for
+        wait: Wait,
+    },
+    /// the node rejected the transaction, so it is tried again later
+    Rejected {
+        /// the transaction
+        txid: Txid,
+        /// why the node rejected it
+        error: String,
+        /// the unix time to try again at
+        retry_at: u64,
+    },
+    /// the transaction was rejected recently, and is tried again later
+    Backoff {
+        /// the transaction
+        txid: Txid,
+        /// the unix time to try again at
+        retry_at: u64,
+    },
+    /// every transaction was broadcast
+    Done,
+}
+
+/// Why a `Sequencer` couldn't continue
+#[derive(Debug)]
+pub enum SequencerError {
+    /// the persistence file couldn't be read or written
+    Io(std::io::Error),
+    /// the persistence file isn't a sequencer's
+    Json(serde_json::Error),
+    /// the node couldn't be asked about the chain
+    Rpc(rpc::Error),
+}
+
+impl Display for SequencerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SequencerError::Io(e) => write!(f, "{}", e),
+            SequencerError::Json(e) => write!(f, "{}", e),
+            SequencerError::Rpc(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for SequencerError {}
+impl From<std::io::Error> for SequencerError {
+    fn from(e: std::io::Error) -> Self {
+        SequencerError::Io(e)
+    }
+}
+impl From<serde_json::Error> for SequencerError {
+    fn from(e: serde_json::Error) -> Self {
+        SequencerError::Json(e)
+    }
+}
+impl From<rpc::Error> for SequencerError {
+    fn from(e: rpc::Error) -> Self {
+        SequencerError::Rpc(e)
+    }
+}
+
+/// What is saved to resume a `Sequencer`
+#[derive(Serialize, Deserialize)]
+struct Saved {
+    txs: Vec<Transaction>,
+    /// how many of `txs` were broadcast
+    sent: usize,
+    /// how many times the next transaction was rejected
+    attempts: u32,
+    /// the unix time before which the next transaction isn't tried again
+    not_before: u64,
+}
+
+/// Broadcasts transactions in order, each once the coins it spends have
+/// confirmed to the configured depth and its nLockTime and nSequence allow
+/// it into the next block.
+///
+/// The transactions are those of the templates chosen down a bound
+/// contract, finalized, e.g. with `sapio_psbt::finalize`, parents first.
+/// The progress is saved to a file after each change, to `Sequencer::resume`
+/// after a restart.
+pub struct Sequencer {
+    saved: Saved,
+    file: PathBuf,
+    config: SequencerConfig,
+}
+
+/// the median time past of the block at `height`
+async fn median_time<R: RpcApi + Sync>(client: &R, height: u32) -> Result<u32, rpc::Error> {
+    let hash = client.get_block_hash(height as u64).await?;
+    let header = client.get_block_header_info(&hash).await?;
+    Ok(header.median_time.unwrap_or(header.time) as u32)
+}
+
+impl Sequencer {
+    /// Broadcast `txs`, saving progress to `file`
+    pub fn new(
+        txs: Vec<Transaction>,
+        file: PathBuf,
+        config: SequencerConfig,
+    ) -> Result<Self, SequencerError> {
+        let s = Sequencer {
+            saved: Saved {
+                txs,
+                sent: 0,
+                attempts: 0,
+                not_before: 0,
+            },
+            file,
+            config,
+        };
+        s.save()?;
+        Ok(s)
+    }
+
+    /// Continue the sequencer which saved its progress to `file`
+    pub fn resume(file: PathBuf, config: SequencerConfig) -> Result<Self, SequencerError> {
+        let saved = serde_json::from_slice(&std::fs::read(&file)?)?;
+        Ok(Sequencer {
+            saved,
+            file,
+            config,
+        })
+    }
+
+    fn save(&self) -> Result<(), SequencerError> {
+        // written then moved into place, so a crash leaves the old progress
+        let tmp = self.file.with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_vec(&self.saved)?)?;
+        std::fs::rename(&tmp, &self.file)?;
+        Ok(())
+    }
+
+    fn sent(&mut self, txid: Txid) -> Result<Step, SequencerError> {
+        self.saved.sent += 1;
+        self.saved.attempts = 0;
+        self.saved.not_before = 0;
+        self.save()?;
+        Ok(Step::Sent(txid))
+    }
+
+    /// The transactions broadcast so far
+    pub fn broadcast(&self) -> &[Transaction] {
+        &self.saved.txs[..self.saved.sent]
+    }
+
+    /// what `tx` waits for to be in the block after `tip`, if anything
+    async fn wait_for<R: RpcApi + Sync>(
+        &self,
+        client: &R,
+        tx: &Transaction,
+        tip: u32,
+    ) -> Result<Option<Wait>, SequencerError> {
+        let tip_time = median_time(client, tip).await?;
+        for input in tx.input.iter() {
+            let coin = input.previous_output;
+            let parent = coin.txid;
+            // none if its transaction isn't in the mempool or a block yet
+            let confirmations = client
+                .get_tx_out(&parent, coin.vout, Some(true))
+                .await?
+                .map_or(0, |out| out.confirmations);
+            if confirmations < self.config.confirmations.max(1) {
+                return Ok(Some(Wait::Parent {
+                    txid: parent,
+                    confirmations,
+                }));
+            }
+            let s = input.sequence;
+            if tx.version < 2 || s & SEQUENCE_DISABLE != 0 {
+                continue;
+            }
+            let confirmed_at = tip + 1 - confirmations;
+            let value = s & 0xFFFF;
+            if s & SEQUENCE_TYPE_TIME != 0 {
+                // measured from the block before the coin's
+                let from = median_time(client, confirmed_at.saturating_sub(1)).await?;
+                let at = from + value * 512;
+                if tip_time < at {
+                    return Ok(Some(Wait::Time(at)));
+                }
+            } else {
+                let at = (confirmed_at + value).saturating_sub(1);
+                if tip < at {
+                    return Ok(Some(Wait::Height(at)));
+                }
+            }
+        }
+        let lock_time = tx.lock_time;
+        if lock_time != 0 && tx.input.iter().any(|i| i.sequence != 0xFFFF_FFFF) {
+            if lock_time < LOCK_TIME_THRESHOLD {
+                if tip < lock_time {
+                    return Ok(Some(Wait::Height(lock_time)));
+                }
+            } else if tip_time <= lock_time {
+                return Ok(Some(Wait::Time(lock_time + 1)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Try to broadcast the next transaction, at unix time `now`, against
+    /// the node `client`
+    pub async fn step<R: RpcApi + Sync>(
+        &mut self,
+        client: &R,
+        now: u64,
+    ) -> Result<Step, SequencerError> {
+        let tx = match self.saved.txs.get(self.saved.sent) {
+            Some(tx) => tx.clone(),
+            None => return Ok(Step::Done),
+        };
+        let txid = tx.txid();
+        // e.g. broadcast before a restart, or by someone else. Looked up by
+        // its coins, as the node may not index transactions.
+        for vout in 0..tx.output.len() as u32 {
+            if client.get_tx_out(&txid, vout, Some(true)).await?.is_some() {
+                return self.sent(txid);
+            }
+        }
+        if now < self.saved.not_before {
+            return Ok(Step::Backoff {
+                txid,
+                retry_at: self.saved.not_before,
+            });
+        }
+        let tip = client.get_block_count().await? as u32;
+        if let Some(wait) = self.wait_for(client, &tx, tip).await? {
+            return Ok(Step::Waiting { txid, wait });
+        }
+        match client.send_raw_transaction(&tx).await {
+            Ok(_) => self.sent(txid),
+            Err(e) => {
+                let backoff = self
+                    .config
+                    .initial_backoff
+                    .checked_mul(1 << self.saved.attempts.min(16))
+                    .map_or(self.config.max_backoff, |b| b.min(self.config.max_backoff));
+                self.saved.attempts += 1;
+                self.saved.not_before = now + backoff.as_secs();
+                self.save()?;
+                Ok(Step::Rejected {
+                    txid,
+                    error: e.to_string(),
+                    retry_at: self.saved.not_before,
+                })
+            }
+        }
+    }
+
+    /// Step until every transaction is broadcast, checking again every
+    /// `poll` while waiting, calling `on_step` with each step
+    pub async fn run<R: RpcApi + Sync>(
+        &mut self,
+        client: &R,
+        poll: Duration,
+        mut on_step: impl FnMut(&Step),
+    ) -> Result<(), SequencerError> {
+        loop {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let step = self.step(client, now).await?;
+            on_step(&step);
+            match step {
+                Step::Done => return Ok(()),
+                Step::Sent(_) => {}
+                _ => tokio::time::sleep(poll).await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{Amount, BlockHash, KeyPair, Network, OutPoint, TxOut, XOnlyPublicKey};
+    use sapio::contract::{Compilable, Compiled, Contract};
+    use sapio::*;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_base::timelocks::RelHeight;
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    /// the blocks a chain delays a vault's claim by
+    const DELAY: u16 = 10;
+
+    fn key() -> XOnlyPublicKey {
+        let key = KeyPair::from_seckey_slice(&Secp256k1::new(), &[1; 32]).unwrap();
+        XOnlyPublicKey::from_keypair(&key).0
+    }
+    fn less_fee(ctx: &Context) -> Amount {
+        Amount::from_sat(ctx.funds().as_sat() - 1000)
+    }
+
+    /// pays a key its funds `DELAY` blocks after it is created
+    struct Unvaulting;
+    impl Unvaulting {
+        #[then]
+        fn claim(self, ctx: Context) {
+            let amount = less_fee(&ctx);
+            ctx.template()
+                .add_fees(Amount::from_sat(1000))?
+                .set_sequence(0, RelHeight::from(DELAY).into())?
+                .add_output(amount, &key(), None)?
+                .into()
+        }
+    }
+    impl Contract for Unvaulting {
+        declare! {then, Self::claim}
+        declare! {non updatable}
+    }
+
+    struct Vault;
+    impl Vault {
+        #[then]
+        fn unvault(self, ctx: Context) {
+            let amount = less_fee(&ctx);
+            ctx.template()
+                .add_fees(Amount::from_sat(1000))?
+                .add_output(amount, &Unvaulting, None)?
+                .into()
+        }
+    }
+    impl Contract for Vault {
+        declare! {then, Self::unvault}
+        declare! {non updatable}
+    }
+
+    fn compiled() -> Compiled {
+        Vault
+            .compile(Context::new(
+                Network::Regtest,
+                Amount::from_sat(100_000),
+                Arc::new(CTVAvailable),
+                EffectPath::try_from("vault").unwrap(),
+                Arc::new(MapEffectDB::default()),
+            ))
+            .unwrap()
+    }
+
+    /// `object`'s only template, spending `outpoint`
+    fn spend(object: &Compiled, outpoint: OutPoint) -> Transaction {
+        let mut tx = object.ctv_to_tx.values().next().unwrap().tx.clone();
+        tx.input[0].previous_output = outpoint;
+        tx
+    }
+
+    #[derive(Default)]
+    struct Chain {
+        /// the height each transaction confirmed at, if it has
+        txs: BTreeMap<Txid, Option<u32>>,
+        tip: u32,
+        /// the tip each transaction was broadcast at
+        broadcast: Vec<(Txid, u32)>,
+        /// the coins spent by the transactions broadcast
+        spent: std::collections::BTreeSet<OutPoint>,
+        /// how many broadcasts to reject
+        reject: usize,
+        /// fail to look up coins, as a node being restarted might
+        failing: bool,
+    }
+
+    /// An in-memory mock of a node, whose blocks are a minute apart
+    #[derive(Default)]
+    struct Node(Mutex<Chain>);
+    impl Node {
+        fn mine(&self) {
+            let mut chain = self.0.lock().unwrap();
+            chain.tip += 1;
+            let tip = chain.tip;
+            for height in chain.txs.values_mut().filter(|h| h.is_none()) {
+                *height = Some(tip);
+            }
+        }
+        fn hash(height: u32) -> BlockHash {
+            BlockHash::from_hash(Hash::hash(&height.to_be_bytes()))
+        }
+        fn handle(&self, cmd: &str, args: &[Value]) -> Option<Value> {
+            let mut chain = self.0.lock().unwrap();
+            Some(match cmd {
+                "getblockcount" => json!(chain.tip),
+                "getblockhash" => json!(Node::hash(args[0].as_u64()? as u32)),
+                "getblockheader" => {
+                    let height = (0..=chain.tip).find(|h| json!(Node::hash(*h)) == args[0])?;
+                    json!({
+                        "hash": args[0], "confirmations": chain.tip - height + 1,
+                        "height": height, "version": 1,
+                        "merkleroot": bitcoin::TxMerkleNode::default(),
+                        "time": height * 60, "mediantime": height.saturating_sub(5) * 60,
+                        "nonce": 0, "bits": "207fffff", "difficulty": 0.0,
+                        "chainwork": "00", "nTx": 0,
+                    })
+                }
+                "gettxout" if chain.failing => return None,
+                "gettxout" => {
+                    let txid: Txid = serde_json::from_value(args[0].clone()).ok()?;
+                    let vout = args[1].as_u64()? as u32;
+                    match chain.txs.get(&txid) {
+                        Some(height) if !chain.spent.contains(&OutPoint::new(txid, vout)) => {
+                            json!({
+                                "bestblock": Node::hash(chain.tip),
+                                "confirmations": height.map_or(0, |h| chain.tip - h + 1),
+                                "value": 0.001, "coinbase": false,
+                                "scriptPubKey": {"asm": "", "hex": ""},
+                            })
+                        }
+                        _ => Value::Null,
+                    }
+                }
+                "sendrawtransaction" => {
+                    if chain.reject > 0 {
+                        chain.reject -= 1;
+                        return None;
+                    }
+                    let hex: Vec<u8> =
+                        bitcoin::hashes::hex::FromHex::from_hex(args[0].as_str()?).ok()?;
+                    let tx: Transaction = bitcoin::consensus::deserialize(&hex).ok()?;
+                    let tip = chain.tip;
+                    chain.broadcast.push((tx.txid(), tip));
+                    chain
+                        .spent
+                        .extend(tx.input.iter().map(|i| i.previous_output));
+                    chain.txs.insert(tx.txid(), None);
+                    json!(tx.txid())
+                }
+                _ => return None,
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl RpcApi for Node {
+        async fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            args: &[Value],
+        ) -> rpc::Result<T> {
+            let v = self
+                .handle(cmd, args)
+                .ok_or(rpc::Error::UnexpectedStructure)?;
+            serde_json::from_value(v).map_err(rpc::Error::Json)
+        }
+    }
+
+    #[tokio::test]
+    async fn holds_claim_until_delay() {
+        let compiled = compiled();
+        let funding = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: compiled.address.clone().into(),
+            }],
+        };
+        let unvault = spend(&compiled, OutPoint::new(funding.txid(), 0));
+        let unvaulting = &compiled.ctv_to_tx.values().next().unwrap().outputs[0].contract;
+        let claim = spend(unvaulting, OutPoint::new(unvault.txid(), 0));
+        assert_eq!(claim.input[0].sequence, DELAY as u32);
+
+        let node = Node::default();
+        node.0.lock().unwrap().txs.insert(funding.txid(), None);
+        node.0.lock().unwrap().reject = 1;
+        node.mine();
+        let file = std::env::temp_dir().join(format!("sapio-sequencer-{}", std::process::id()));
+        let config = SequencerConfig {
+            confirmations: 1,
+            initial_backoff: Duration::from_secs(10),
+            max_backoff: Duration::from_secs(60),
+        };
+        let mut sequencer =
+            Sequencer::new(vec![unvault.clone(), claim.clone()], file.clone(), config).unwrap();
+
+        // rejected once, then retried after backing off
+        let step = sequencer.step(&node, 0).await.unwrap();
+        assert!(matches!(step, Step::Rejected { retry_at: 10, .. }));
+        assert_eq!(
+            sequencer.step(&node, 5).await.unwrap(),
+            Step::Backoff {
+                txid: unvault.txid(),
+                retry_at: 10
+            }
+        );
+        assert_eq!(
+            sequencer.step(&node, 10).await.unwrap(),
+            Step::Sent(unvault.txid())
+        );
+        // the claim waits for the unvault to confirm, then for the delay
+        assert_eq!(
+            sequencer.step(&node, 10).await.unwrap(),
+            Step::Waiting {
+                txid: claim.txid(),
+                wait: Wait::Parent {
+                    txid: unvault.txid(),
+                    confirmations: 0
+                }
+            }
+        );
+        node.mine();
+        let held = Step::Waiting {
+            txid: claim.txid(),
+            wait: Wait::Height(2 + DELAY as u32 - 1),
+        };
+        assert_eq!(sequencer.step(&node, 10).await.unwrap(), held);
+        while node.0.lock().unwrap().tip < 2 + DELAY as u32 - 1 {
+            assert_eq!(sequencer.step(&node, 10).await.unwrap(), held);
+            node.mine();
+        }
+
+        // errors asking the node aren't mistaken for the unvault not
+        // confirming
+        node.0.lock().unwrap().failing = true;
+        assert!(matches!(
+            sequencer.step(&node, 10).await,
+            Err(SequencerError::Rpc(_))
+        ));
+        node.0.lock().unwrap().failing = false;
+
+        // resumed after a restart
+        drop(sequencer);
+        let mut sequencer = Sequencer::resume(file.clone(), config).unwrap();
+        assert_eq!(sequencer.broadcast(), [unvault.clone()]);
+        assert_eq!(
+            sequencer.step(&node, 10).await.unwrap(),
+            Step::Sent(claim.txid())
+        );
+        assert_eq!(sequencer.step(&node, 10).await.unwrap(), Step::Done);
+        assert_eq!(
+            node.0.lock().unwrap().broadcast,
+            [(unvault.txid(), 1), (claim.txid(), 2 + DELAY as u32 - 1)]
+        );
+        std::fs::remove_file(file).unwrap();
+    }
+}
@@ -0,0 +1,649 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Watching the chain for spends of a bound contract's coins, to learn which
+//! of its branches executed
+use bitcoin::hashes::sha256;
+use bitcoin::{Block, BlockHash, OutPoint, Transaction, Txid};
+use bitcoincore_rpc_async as rpc;
+use rpc::RpcApi;
+use sapio::contract::object::Object;
+use sapio::template::Template;
+use sapio_base::CTVHash;
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Display;
+
+/// What a `Watcher` saw happen to a contract's coins
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// a coin was spent by one of its contract's templates
+    BranchTaken {
+        /// the contract's path and the name of the branch which created the
+        /// template, or its hash if none did
+        path: String,
+        /// the template's hash
+        template: sha256::Hash,
+        /// the spending transaction
+        txid: Txid,
+        /// the height of the block it confirmed in
+        height: u32,
+    },
+    /// a coin was spent by a transaction which is none of its contract's
+    /// templates, e.g. a key path spend
+    UnknownSpend {
+        /// the coin
+        outpoint: OutPoint,
+        /// the spending transaction
+        txid: Txid,
+        /// the height of the block it confirmed in
+        height: u32,
+    },
+    /// the block a spend confirmed in was disconnected, so the coin it spent
+    /// is watched again
+    Reorged {
+        /// the spending transaction
+        txid: Txid,
+        /// the height of the block it had confirmed in
+        height: u32,
+    },
+}
+
+/// Why a `Watcher` couldn't follow the chain
+#[derive(Debug)]
+pub enum WatchError {
+    /// a block doesn't extend the last one connected
+    NotConnected {
+        /// the block's height
+        height: u32,
+        /// the block's hash
+        hash: BlockHash,
+    },
+    /// a block deeper than the reorg depth was disconnected
+    ReorgTooDeep {
+        /// the height of the deepest block which can't be disconnected
+        height: u32,
+    },
+    /// the node couldn't be asked for blocks
+    Rpc(rpc::Error),
+}
+
+impl Display for WatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchError::NotConnected { height, hash } => {
+                write!(f, "block {} at height {} is not connected", hash, height)
+            }
+            WatchError::ReorgTooDeep { height } => {
+                write!(f, "block at height {} was reorged out", height)
+            }
+            WatchError::Rpc(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for WatchError {}
+impl From<rpc::Error> for WatchError {
+    fn from(e: rpc::Error) -> Self {
+        WatchError::Rpc(e)
+    }
+}
+
+/// A coin spent by a connected block, and the coins that spend created
+struct Spend<'a> {
+    outpoint: OutPoint,
+    object: &'a Object,
+    txid: Txid,
+    created: Vec<OutPoint>,
+}
+
+/// A block connected within the reorg depth of the tip, and the spends in it
+struct Connected<'a> {
+    height: u32,
+    hash: BlockHash,
+    spends: Vec<Spend<'a>>,
+}
+
+/// Follows the chain from a contract's funding, calling back with a
+/// `WatchEvent` for each spend of its coins.
+///
+/// Blocks are fed to `Watcher::connect_block` and undone by
+/// `Watcher::disconnect_block`, from any source, or fetched from bitcoind
+/// with `Watcher::sync`. A template is recognized by its txid or by its CTV
+/// hash at the input spending the coin, which doesn't commit to the coins its
+/// other inputs spend, and a suggested template by its outputs' scripts and
+/// amounts. The coins of a template's outputs which are contracts are
+/// watched once it confirms.
+pub struct Watcher<'a> {
+    watched: BTreeMap<OutPoint, &'a Object>,
+    recent: VecDeque<Connected<'a>>,
+    /// the newest block deeper than `reorg_depth`, which is final
+    anchor: Option<(u32, BlockHash)>,
+    next_height: u32,
+    reorg_depth: usize,
+    on_event: Box<dyn FnMut(WatchEvent) + 'a>,
+}
+
+/// the template of `object` which `tx`, spending `outpoint`, is
+fn template_spending<'o>(
+    object: &'o Object,
+    outpoint: OutPoint,
+    tx: &Transaction,
+) -> Option<(&'o sha256::Hash, &'o Template)> {
+    let index = tx
+        .input
+        .iter()
+        .position(|i| i.previous_output == outpoint)? as u32;
+    object
+        .ctv_to_tx
+        .iter()
+        .find(|(h, t)| {
+            let mut expected = t.tx.clone();
+            if let Some(input) = expected.input.get_mut(t.ctv_index as usize) {
+                input.previous_output = outpoint;
+            }
+            expected.txid() == tx.txid() || (t.ctv_index == index && tx.get_ctv_hash(index) == **h)
+        })
+        .or_else(|| {
+            // nothing commits to a suggested template, so it may be signed
+            // with other inputs or lock time, but it pays its outputs' scripts
+            // and amounts
+            object
+                .suggested_txs
+                .iter()
+                .find(|(_, t)| t.tx.output == tx.output)
+        })
+}
+
+impl<'a> Watcher<'a> {
+    /// Watch `object`, funded by `funding`, from the block at `start_height`,
+    /// e.g. the one funding it. Blocks more than `reorg_depth` below the
+    /// tip are final.
+    pub fn new(
+        object: &'a Object,
+        funding: OutPoint,
+        start_height: u32,
+        reorg_depth: usize,
+        on_event: impl FnMut(WatchEvent) + 'a,
+    ) -> Self {
+        Watcher {
+            watched: vec![(funding, object)].into_iter().collect(),
+            recent: VecDeque::new(),
+            anchor: None,
+            next_height: start_height,
+            reorg_depth,
+            on_event: Box::new(on_event),
+        }
+    }
+
+    /// the coins being watched, and the contracts they fund
+    pub fn watched(&self) -> &BTreeMap<OutPoint, &'a Object> {
+        &self.watched
+    }
+
+    /// the height of the next block to connect
+    pub fn next_height(&self) -> u32 {
+        self.next_height
+    }
+
+    /// Process `block`, at `height`, which must extend the last block
+    /// connected.
+    pub fn connect_block(&mut self, height: u32, block: &Block) -> Result<(), WatchError> {
+        let hash = block.block_hash();
+        let last = self
+            .recent
+            .back()
+            .map(|c| (c.height, c.hash))
+            .or(self.anchor);
+        let connects = match last {
+            Some((h, prev)) => h + 1 == height && block.header.prev_blockhash == prev,
+            None => height == self.next_height,
+        };
+        if !connects {
+            return Err(WatchError::NotConnected { height, hash });
+        }
+        let mut spends = vec![];
+        for tx in block.txdata.iter() {
+            let txid = tx.txid();
+            for input in tx.input.iter() {
+                let outpoint = input.previous_output;
+                let object = match self.watched.remove(&outpoint) {
+                    Some(object) => object,
+                    None => continue,
+                };
+                let mut created = vec![];
+                let event = match template_spending(object, outpoint, tx) {
+                    Some((h, template)) => {
+                        // external outputs aren't contracts to follow
+                        for (vout, output) in template
+                            .outputs
+                            .iter()
+                            .enumerate()
+                            .filter(|(_, o)| !o.added_metadata.is_external())
+                        {
+                            let out = OutPoint::new(txid, vout as u32);
+                            self.watched.insert(out, &output.contract);
+                            created.push(out);
+                        }
+                        let branch = object
+                            .branches
+                            .iter()
+                            .find(|(_, hashes)| hashes.contains(h))
+                            .map_or_else(|| h.to_string(), |(name, _)| name.clone());
+                        WatchEvent::BranchTaken {
+                            path: format!(
+                                "{}/{}",
+                                String::from(object.root_path.0.as_ref().clone()),
+                                branch
+                            ),
+                            template: *h,
+                            txid,
+                            height,
+                        }
+                    }
+                    None => WatchEvent::UnknownSpend {
+                        outpoint,
+                        txid,
+                        height,
+                    },
+                };
+                (self.on_event)(event);
+                spends.push(Spend {
+                    outpoint,
+                    object,
+                    txid,
+                    created,
+                });
+            }
+        }
+        self.recent.push_back(Connected {
+            height,
+            hash,
+            spends,
+        });
+        while self.recent.len() > self.reorg_depth {
+            let c = self.recent.pop_front().expect("longer than reorg depth");
+            self.anchor = Some((c.height, c.hash));
+        }
+        self.next_height = height + 1;
+        Ok(())
+    }
+
+    /// Undo the last block connected, watching the coins it spent again.
+    pub fn disconnect_block(&mut self) -> Result<(), WatchError> {
+        let c = match self.recent.pop_back() {
+            Some(c) => c,
+            None => {
+                return Err(WatchError::ReorgTooDeep {
+                    height: self.next_height.saturating_sub(1),
+                })
+            }
+        };
+        for spend in c.spends.into_iter().rev() {
+            for out in spend.created.iter() {
+                self.watched.remove(out);
+            }
+            self.watched.insert(spend.outpoint, spend.object);
+            (self.on_event)(WatchEvent::Reorged {
+                txid: spend.txid,
+                height: c.height,
+            });
+        }
+        self.next_height = c.height;
+        Ok(())
+    }
+
+    /// Follow the chain of the node `client` up to its tip, disconnecting
+    /// any blocks it no longer has first.
+    pub async fn sync<R: RpcApi + Sync>(&mut self, client: &R) -> Result<(), WatchError> {
+        let tip = client.get_block_count().await? as u32;
+        // the node's block at `height`, if it has one
+        let hash_at = |height: u32| async move {
+            match height <= tip {
+                true => client.get_block_hash(height as u64).await.map(Some),
+                false => Ok(None),
+            }
+        };
+        while let Some(c) = self.recent.back() {
+            if hash_at(c.height).await? == Some(c.hash) {
+                break;
+            }
+            self.disconnect_block()?;
+        }
+        if self.recent.is_empty() {
+            if let Some((height, hash)) = self.anchor {
+                if hash_at(height).await? != Some(hash) {
+                    return Err(WatchError::ReorgTooDeep { height });
+                }
+            }
+        }
+        for height in self.next_height..=tip {
+            let hash = client.get_block_hash(height as u64).await?;
+            let block = client.get_block(&hash).await?;
+            self.connect_block(height, &block)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::blockdata::block::BlockHeader;
+    use bitcoin::consensus::encode::serialize_hex;
+    use bitcoin::hashes::Hash;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{Amount, KeyPair, Network, TxIn, TxOut, XOnlyPublicKey};
+    use sapio::contract::{Compilable, Compiled, Contract};
+    use sapio::template::Commitment;
+    use sapio::*;
+    use sapio_base::effects::{EffectPath, MapEffectDB};
+    use sapio_ctv_emulator_trait::CTVAvailable;
+    use serde_json::{json, Value};
+    use std::cell::RefCell;
+    use std::convert::TryFrom;
+    use std::sync::{Arc, Mutex};
+
+    fn key(n: u8) -> XOnlyPublicKey {
+        let key = KeyPair::from_seckey_slice(&Secp256k1::new(), &[n; 32]).unwrap();
+        XOnlyPublicKey::from_keypair(&key).0
+    }
+    fn less_fee(ctx: &Context) -> Amount {
+        Amount::from_sat(ctx.funds().as_sat() - 1000)
+    }
+
+    /// pays its funds less a fee to a key
+    struct Pay;
+    impl Pay {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let amount = less_fee(&ctx);
+            ctx.template()
+                .add_fees(Amount::from_sat(1000))?
+                .add_output(amount, &key(1), None)?
+                .into()
+        }
+    }
+    impl Contract for Pay {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    /// moves its funds to cold storage, paid out later, or to a hot key
+    struct Vault;
+    impl Vault {
+        #[then]
+        fn cold(self, ctx: Context) {
+            let amount = less_fee(&ctx);
+            ctx.template()
+                .add_fees(Amount::from_sat(1000))?
+                .add_output(amount, &Pay, None)?
+                .into()
+        }
+        #[then]
+        fn hot(self, ctx: Context) {
+            let amount = less_fee(&ctx);
+            ctx.template()
+                .add_fees(Amount::from_sat(1000))?
+                .add_output(amount, &key(2), None)?
+                .into()
+        }
+    }
+    impl Contract for Vault {
+        declare! {then, Self::cold, Self::hot}
+        declare! {non updatable}
+    }
+
+    /// pays a hot key, suggesting paying a third key instead
+    struct Advised;
+    impl Advised {
+        #[then]
+        fn pay(self, ctx: Context) {
+            let mut ctx = ctx;
+            let amount = less_fee(&ctx);
+            let mut pay = |n: u8, commitment| {
+                ctx.derive_num(n as u64)?
+                    .template()
+                    .add_fees(Amount::from_sat(1000))?
+                    .add_output(amount, &key(n), None)?
+                    .set_commitment(commitment)
+                    .finalize()
+            };
+            let txtmpls = vec![pay(2, Commitment::Committed), pay(3, Commitment::Suggested)];
+            Ok(Box::new(txtmpls.into_iter()))
+        }
+    }
+    impl Contract for Advised {
+        declare! {then, Self::pay}
+        declare! {non updatable}
+    }
+
+    fn ctx() -> Context {
+        Context::new(
+            Network::Regtest,
+            Amount::from_sat(100_000),
+            Arc::new(CTVAvailable),
+            EffectPath::try_from("vault").unwrap(),
+            Arc::new(MapEffectDB::default()),
+        )
+    }
+
+    fn compiled() -> Compiled {
+        Vault.compile(ctx()).unwrap()
+    }
+
+    /// `object`'s template for `branch`, spending `outpoint`
+    fn spend(object: &Object, branch: &str, outpoint: OutPoint) -> Transaction {
+        let h = object.branches[branch][0];
+        let mut tx = object.ctv_to_tx[&h].tx.clone();
+        tx.input[0].previous_output = outpoint;
+        tx
+    }
+
+    /// An in-memory mock of a node's chain, serving the RPCs `Watcher::sync`
+    /// makes
+    struct Node {
+        blocks: Mutex<Vec<Block>>,
+        /// fail to look up block hashes, as a node being restarted might
+        failing: std::sync::atomic::AtomicBool,
+    }
+    impl Node {
+        fn new() -> Self {
+            Node {
+                blocks: Mutex::new(vec![]),
+                failing: Default::default(),
+            }
+        }
+        /// mine `txdata`, with `nonce` to tell forks apart
+        fn mine(&self, txdata: Vec<Transaction>, nonce: u32) {
+            let mut blocks = self.blocks.lock().unwrap();
+            let prev_blockhash = blocks
+                .last()
+                .map_or_else(Default::default, |b| b.block_hash());
+            blocks.push(Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash,
+                    merkle_root: Default::default(),
+                    time: 0,
+                    bits: 0,
+                    nonce,
+                },
+                txdata,
+            });
+        }
+        fn disconnect(&self) {
+            self.blocks.lock().unwrap().pop();
+        }
+        fn handle(&self, cmd: &str, args: &[Value]) -> Option<Value> {
+            let blocks = self.blocks.lock().unwrap();
+            Some(match cmd {
+                "getblockcount" => json!(blocks.len() - 1),
+                "getblockhash" if self.failing.load(std::sync::atomic::Ordering::Relaxed) => {
+                    return None
+                }
+                "getblockhash" => json!(blocks.get(args[0].as_u64()? as usize)?.block_hash()),
+                "getblock" => {
+                    let hash: BlockHash = serde_json::from_value(args[0].clone()).ok()?;
+                    let block = blocks.iter().find(|b| b.block_hash() == hash)?;
+                    json!(serialize_hex(block))
+                }
+                _ => return None,
+            })
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl RpcApi for Node {
+        async fn call<T: for<'a> serde::de::Deserialize<'a>>(
+            &self,
+            cmd: &str,
+            args: &[Value],
+        ) -> rpc::Result<T> {
+            let v = self
+                .handle(cmd, args)
+                .ok_or(rpc::Error::UnexpectedStructure)?;
+            serde_json::from_value(v).map_err(rpc::Error::Json)
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_branch_through_reorg() {
+        let compiled = compiled();
+        let funding = OutPoint::new(Txid::from_hash(Hash::from_inner([7; 32])), 0);
+        let cold = spend(&compiled, "cold", funding);
+        let cold_out = OutPoint::new(cold.txid(), 0);
+        let pay = &compiled.ctv_to_tx[&compiled.branches["cold"][0]].outputs[0].contract;
+        let paid = spend(pay, "pay", cold_out);
+
+        let node = Node::new();
+        // 0 funds the contract
+        node.mine(vec![], 0);
+        node.mine(vec![cold.clone()], 0);
+        node.mine(vec![paid.clone()], 0);
+        let events = RefCell::new(vec![]);
+        let mut watcher = Watcher::new(&compiled, funding, 1, 2, |e| events.borrow_mut().push(e));
+        watcher.sync(&node).await.unwrap();
+        // the key paid is watched for its owner spending it
+        assert_eq!(
+            watcher.watched().keys().collect::<Vec<_>>(),
+            [&OutPoint::new(paid.txid(), 0)]
+        );
+
+        // the payout is reorged out and the cold coin swept instead
+        node.disconnect();
+        let mut sweep = paid.clone();
+        sweep.output[0].value -= 1;
+        node.mine(vec![sweep.clone()], 1);
+        // a spend of a coin not watched is ignored
+        node.mine(
+            vec![Transaction {
+                version: 2,
+                lock_time: 0,
+                input: vec![TxIn::default()],
+                output: vec![TxOut::default()],
+            }],
+            1,
+        );
+        watcher.sync(&node).await.unwrap();
+        assert_eq!(watcher.next_height(), 4);
+        assert_eq!(
+            events.borrow().as_slice(),
+            [
+                WatchEvent::BranchTaken {
+                    path: "vault/cold".into(),
+                    template: compiled.branches["cold"][0],
+                    txid: cold.txid(),
+                    height: 1,
+                },
+                WatchEvent::BranchTaken {
+                    path: format!("{}/pay", String::from(pay.root_path.0.as_ref().clone())),
+                    template: pay.branches["pay"][0],
+                    txid: paid.txid(),
+                    height: 2,
+                },
+                WatchEvent::Reorged {
+                    txid: paid.txid(),
+                    height: 2,
+                },
+                WatchEvent::UnknownSpend {
+                    outpoint: cold_out,
+                    txid: sweep.txid(),
+                    height: 2,
+                },
+            ]
+        );
+
+        // only the last 2 blocks can be reorged out
+        for _ in 0..3 {
+            node.disconnect();
+        }
+        node.mine(vec![], 2);
+        assert!(matches!(
+            watcher.sync(&node).await,
+            Err(WatchError::ReorgTooDeep { height: 1 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn only_templates_are_branches() {
+        let compiled = compiled();
+        let funding = OutPoint::new(Txid::from_hash(Hash::from_inner([7; 32])), 0);
+        // pays the same outputs as the cold branch, but isn't its template
+        let mut lookalike = spend(&compiled, "cold", funding);
+        lookalike.lock_time += 1;
+        let node = Node::new();
+        node.mine(vec![], 0);
+        node.mine(vec![lookalike.clone()], 0);
+        let events = RefCell::new(vec![]);
+        let mut watcher = Watcher::new(&compiled, funding, 1, 2, |e| events.borrow_mut().push(e));
+        watcher.sync(&node).await.unwrap();
+        assert_eq!(
+            events.borrow().as_slice(),
+            [WatchEvent::UnknownSpend {
+                outpoint: funding,
+                txid: lookalike.txid(),
+                height: 1,
+            }]
+        );
+
+        // the node failing isn't mistaken for a reorg
+        node.failing
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        assert!(matches!(watcher.sync(&node).await, Err(WatchError::Rpc(_))));
+        assert_eq!(watcher.next_height(), 2);
+        assert_eq!(events.borrow().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn suggested_templates_match_by_outputs() {
+        let compiled = Advised.compile(ctx()).unwrap();
+        let funding = OutPoint::new(Txid::from_hash(Hash::from_inner([7; 32])), 0);
+        let (h, suggested) = compiled.suggested_txs.iter().next().unwrap();
+        // signed with another coin and lock time than suggested
+        let mut tx = suggested.tx.clone();
+        tx.input[0].previous_output = funding;
+        tx.input.push(TxIn::default());
+        tx.lock_time += 1;
+        let node = Node::new();
+        node.mine(vec![], 0);
+        node.mine(vec![tx.clone()], 0);
+        let events = RefCell::new(vec![]);
+        let mut watcher = Watcher::new(&compiled, funding, 1, 2, |e| events.borrow_mut().push(e));
+        watcher.sync(&node).await.unwrap();
+        assert_eq!(
+            events.borrow().as_slice(),
+            [WatchEvent::BranchTaken {
+                path: "vault/pay".into(),
+                template: *h,
+                txid: tx.txid(),
+                height: 1,
+            }]
+        );
+        // paying another amount, it's no longer the suggested template
+        let mut underpaid = tx.clone();
+        underpaid.output[0].value -= 1;
+        assert!(template_spending(&compiled, funding, &underpaid).is_none());
+    }
+}
@@ -0,0 +1,583 @@
+// Copyright Judica, Inc 2022
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+//  License, v. 2.0. If a copy of the MPL was not distributed with this
+//  file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Funds contracts from, spends them through the local emulator on, and
+//! watches them on, a real regtest node.
+//!
+//! Ignored by default, run them with a wallet loaded on a regtest bitcoind:
+//!
+//! ```text
+//! SAPIO_REGTEST_URL=http://127.0.0.1:18443/wallet/sapio \
+//! SAPIO_REGTEST_COOKIE=~/.bitcoin/regtest/.cookie \
+//!     cargo test -p sapio-tools --test regtest -- --ignored
+//! ```
+//!
+//! `SAPIO_REGTEST_USER` and `SAPIO_REGTEST_PASS` may be given instead of a
+//! cookie file.
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::psbt::PartiallySignedTransaction;
+use bitcoin::secp256k1::{Message, Secp256k1};
+use bitcoin::util::sighash::{Prevouts, SchnorrSighashType, SighashCache};
+use bitcoin::util::taproot::TapLeafHash;
+use bitcoin::{Amount, KeyPair, Network, OutPoint, SchnorrSig, Transaction, XOnlyPublicKey};
+use bitcoincore_rpc_async::{Auth, Client, RpcApi};
+use emulator_connect::connections::local::LocalEmulator;
+use miniscript::psbt::PsbtExt;
+use sapio::contract::abi::studio::SapioStudioFormat;
+use sapio::contract::{Compilable, Compiled, Contract, TxTmplIt};
+use sapio::*;
+use sapio_base::effects::{EffectPath, MapEffectDB};
+use sapio_base::timelocks::RelHeight;
+use sapio_base::Clause;
+use sapio_ctv_emulator_trait::CTVAvailable;
+use sapio_psbt::finalize::{finalize, SatisfierInputs};
+use sapio_tools::fund::RpcBound;
+use sapio_tools::sequence::{Sequencer, SequencerConfig, Step, Wait};
+use sapio_tools::watch::{WatchEvent, Watcher};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+fn keypair(n: u8) -> KeyPair {
+    KeyPair::from_seckey_slice(&Secp256k1::new(), &[n; 32]).unwrap()
+}
+fn key(n: u8) -> XOnlyPublicKey {
+    XOnlyPublicKey::from_keypair(&keypair(n)).0
+}
+
+/// pays its funds less a fee to a key
+struct Pay;
+impl Pay {
+    #[then]
+    fn pay(self, ctx: Context) {
+        let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amount, &key(1), None)?
+            .into()
+    }
+}
+impl Contract for Pay {
+    declare! {then, Self::pay}
+    declare! {non updatable}
+}
+
+/// moves its funds less a fee to `Pay`, or sweeps them to a hot key
+struct Vault;
+impl Vault {
+    #[then]
+    fn cold(self, ctx: Context) {
+        let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amount, &Pay, None)?
+            .into()
+    }
+    #[then]
+    fn hot(self, ctx: Context) {
+        let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amount, &key(2), None)?
+            .into()
+    }
+}
+impl Contract for Vault {
+    declare! {then, Self::cold, Self::hot}
+    declare! {non updatable}
+}
+
+async fn client() -> Client {
+    let var = |v| std::env::var(v).ok();
+    let url = var("SAPIO_REGTEST_URL").expect("SAPIO_REGTEST_URL is not set");
+    let auth = match (
+        var("SAPIO_REGTEST_COOKIE"),
+        var("SAPIO_REGTEST_USER"),
+        var("SAPIO_REGTEST_PASS"),
+    ) {
+        (Some(cookie), _, _) => Auth::CookieFile(cookie.into()),
+        (None, Some(user), Some(pass)) => Auth::UserPass(user, pass),
+        _ => Auth::None,
+    };
+    Client::new(url, auth).await.unwrap()
+}
+
+/// the blocks an unvaulting coin is claimable after
+const DELAY: u16 = 10;
+
+/// pays a key its funds less a fee `DELAY` blocks after it is created
+struct Unvaulting;
+impl Unvaulting {
+    #[then]
+    fn claim(self, ctx: Context) {
+        let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .set_sequence(0, RelHeight::from(DELAY).into())?
+            .add_output(amount, &key(1), None)?
+            .into()
+    }
+}
+impl Contract for Unvaulting {
+    declare! {then, Self::claim}
+    declare! {non updatable}
+}
+
+/// moves its funds less a fee to `Unvaulting`
+struct TwoStage;
+impl TwoStage {
+    #[then]
+    fn unvault(self, ctx: Context) {
+        let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+        ctx.template()
+            .add_fees(Amount::from_sat(1000))?
+            .add_output(amount, &Unvaulting, None)?
+            .into()
+    }
+}
+impl Contract for TwoStage {
+    declare! {then, Self::unvault}
+    declare! {non updatable}
+}
+
+const PREIMAGE: [u8; 32] = [7; 32];
+
+/// pays to `key(3)`, when signed by `key(1)`, or by `key(2)` with the
+/// preimage of `PREIMAGE`
+struct Escrow;
+fn pay(ctx: Context) -> TxTmplIt {
+    let amount = Amount::from_sat(ctx.funds().as_sat() - 1000);
+    ctx.template()
+        .add_fees(Amount::from_sat(1000))?
+        .add_output(amount, &key(3), None)?
+        .into()
+}
+impl Escrow {
+    #[guard]
+    fn first(self, _ctx: Context) {
+        Clause::Key(key(1))
+    }
+    #[guard]
+    fn second(self, _ctx: Context) {
+        Clause::And(vec![
+            Clause::Key(key(2)),
+            Clause::Sha256(sha256::Hash::hash(&PREIMAGE)),
+        ])
+    }
+    #[then(guarded_by = "[Self::first]")]
+    fn by_first(self, ctx: Context) {
+        pay(ctx)
+    }
+    #[then(guarded_by = "[Self::second]")]
+    fn by_second(self, ctx: Context) {
+        pay(ctx)
+    }
+}
+impl Contract for Escrow {
+    declare! {then, Self::by_first, Self::by_second}
+    declare! {non updatable}
+}
+
+/// pays to `key(3)` once signed by `key(1)`
+struct Cosigned;
+impl Cosigned {
+    #[guard]
+    fn signed(self, _ctx: Context) {
+        Clause::Key(key(1))
+    }
+    #[then(guarded_by = "[Self::signed]")]
+    fn payout(self, ctx: Context) {
+        pay(ctx)
+    }
+}
+impl Contract for Cosigned {
+    declare! {then, Self::payout}
+    declare! {non updatable}
+}
+
+/// a context for `amount`, named `name` so that each test has its own
+/// address
+fn ctx(name: &str, amount: u64) -> Context {
+    Context::new(
+        Network::Regtest,
+        Amount::from_sat(amount),
+        Arc::new(LocalEmulator::for_tests()),
+        EffectPath::try_from(name).unwrap(),
+        Arc::new(MapEffectDB::default()),
+    )
+}
+
+/// `Pay` for `amount`, named `name`
+fn compiled(name: &str, amount: u64) -> Compiled {
+    Pay.compile(ctx(name, amount)).unwrap()
+}
+
+/// Bind `compiled` to a coin from the wallet, broadcasting and mining the
+/// funding if it was created
+async fn fund(client: &Client, compiled: &Compiled) -> RpcBound {
+    let bound = sapio_tools::fund::bind_with_rpc(
+        client,
+        compiled,
+        Network::Regtest,
+        &LocalEmulator::for_tests(),
+    )
+    .await
+    .unwrap();
+    if bound.created {
+        let signed = client
+            .sign_raw_transaction_with_wallet(&bound.funding, None, None)
+            .await
+            .unwrap();
+        assert!(signed.complete);
+        client.send_raw_transaction(&signed.hex).await.unwrap();
+        let mine = client.get_new_address(None, None).await.unwrap();
+        client.generate_to_address(1, &mine).await.unwrap();
+    }
+    bound
+}
+
+/// the transactions of `bound`'s program spending `coin`, finalized
+fn spends_of(bound: &RpcBound, coin: OutPoint) -> Vec<Transaction> {
+    let secp = Secp256k1::new();
+    bound
+        .program
+        .program
+        .values()
+        .flat_map(|o| o.txs.iter())
+        .map(|tx| {
+            let SapioStudioFormat::LinkedPSBT { psbt, .. } = tx;
+            let psbt: PartiallySignedTransaction =
+                bitcoin::consensus::deserialize(&base64::decode(psbt).unwrap()).unwrap();
+            psbt
+        })
+        .filter(|psbt| {
+            psbt.unsigned_tx
+                .input
+                .iter()
+                .any(|i| i.previous_output == coin)
+        })
+        .map(|mut psbt| {
+            psbt.finalize_mut(&secp).unwrap();
+            psbt.extract_tx()
+        })
+        .collect()
+}
+
+/// `keypair(n)`'s signatures of every leaf of the first input of `psbt` with
+/// its key
+fn sign(
+    psbt: &PartiallySignedTransaction,
+    n: u8,
+) -> BTreeMap<(XOnlyPublicKey, TapLeafHash), SchnorrSig> {
+    let secp = Secp256k1::new();
+    let prevouts = [psbt.inputs[0].witness_utxo.clone().unwrap()];
+    let k = key(n).serialize();
+    psbt.inputs[0]
+        .tap_scripts
+        .values()
+        .filter(|(s, _)| s.as_bytes().windows(32).any(|w| w == k))
+        .map(|(s, v)| {
+            let leaf = TapLeafHash::from_script(s, *v);
+            let sighash = SighashCache::new(&psbt.unsigned_tx)
+                .taproot_script_spend_signature_hash(
+                    0,
+                    &Prevouts::All(&prevouts),
+                    leaf,
+                    SchnorrSighashType::All,
+                )
+                .unwrap();
+            let msg = Message::from_digest_slice(&sighash[..]).unwrap();
+            let sig = SchnorrSig {
+                sig: secp.sign_schnorr_no_aux_rand(&msg, &keypair(n)),
+                hash_ty: SchnorrSighashType::All,
+            };
+            ((key(n), leaf), sig)
+        })
+        .collect()
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn bind_with_regtest_wallet() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    client.generate_to_address(101, &mine).await.unwrap();
+    // a new amount on each run, so the contract has no coin yet
+    let height = client.get_block_count().await.unwrap();
+    let compiled = compiled("bind", 70_000 + height);
+    let emulator = LocalEmulator::for_tests();
+
+    // the wallet funds a new coin
+    let bound = sapio_tools::fund::bind_with_rpc(&client, &compiled, Network::Regtest, &emulator)
+        .await
+        .unwrap();
+    assert!(bound.created);
+    let paid = bound.funding.output[bound.vout as usize].value;
+    assert_eq!(paid, compiled.amount_range.max().as_sat());
+    let signed = client
+        .sign_raw_transaction_with_wallet(&bound.funding, None, None)
+        .await
+        .unwrap();
+    assert!(signed.complete);
+    let txid = client.send_raw_transaction(&signed.hex).await.unwrap();
+    client.generate_to_address(1, &mine).await.unwrap();
+
+    // which is used as is once the wallet has it
+    let again = sapio_tools::fund::bind_with_rpc(&client, &compiled, Network::Regtest, &emulator)
+        .await
+        .unwrap();
+    assert!(!again.created);
+    assert_eq!(again.funding.txid(), txid);
+    assert_eq!(again.funding.output[again.vout as usize].value, paid);
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn local_emulator_regtest() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    // mature a coinbase for the wallet to fund the contract with
+    client.generate_to_address(101, &mine).await.unwrap();
+
+    let compiled = compiled("regtest", 100_000);
+    let bound = fund(&client, &compiled).await;
+    let spends = spends_of(&bound, OutPoint::new(bound.funding.txid(), bound.vout));
+    assert_eq!(spends.len(), 1);
+    let spend = &spends[0];
+    let accepted = client.test_mempool_accept(&[spend]).await.unwrap();
+    assert!(accepted[0].allowed, "{:?}", accepted[0].reject_reason);
+    let txid = client.send_raw_transaction(spend).await.unwrap();
+    client.generate_to_address(1, &mine).await.unwrap();
+    assert!(client
+        .get_tx_out(&txid, 0, Some(false))
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn watch_regtest_branch() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    client.generate_to_address(101, &mine).await.unwrap();
+    // a new amount on each run, so the contract has no coin yet
+    let height = client.get_block_count().await.unwrap();
+    let compiled = Vault.compile(ctx("watch", 70_000 + height)).unwrap();
+    let bound = fund(&client, &compiled).await;
+    let funded_at = client.get_block_count().await.unwrap() as u32;
+    let funding = OutPoint::new(bound.funding.txid(), bound.vout);
+
+    let events = RefCell::new(vec![]);
+    let mut watcher = Watcher::new(&compiled, funding, funded_at, 6, |e| {
+        events.borrow_mut().push(e)
+    });
+    watcher.sync(&client).await.unwrap();
+    assert!(events.borrow().is_empty());
+
+    // the coin goes cold
+    let template = compiled.branches["cold"][0];
+    let cold = spends_of(&bound, funding)
+        .into_iter()
+        .find(|tx| tx.output == compiled.ctv_to_tx[&template].tx.output)
+        .unwrap();
+    client.send_raw_transaction(&cold).await.unwrap();
+    let block = client.generate_to_address(1, &mine).await.unwrap()[0];
+    let cold_at = funded_at + 1;
+    watcher.sync(&client).await.unwrap();
+    let taken = WatchEvent::BranchTaken {
+        path: "watch/cold".into(),
+        template,
+        txid: cold.txid(),
+        height: cold_at,
+    };
+    assert_eq!(events.borrow().as_slice(), std::slice::from_ref(&taken));
+    assert_eq!(
+        watcher.watched().keys().collect::<Vec<_>>(),
+        [&OutPoint::new(cold.txid(), 0)]
+    );
+
+    // its block is reorged out, and the spend mined again in another
+    let other = client.get_new_address(None, None).await.unwrap();
+    client.invalidate_block(&block).await.unwrap();
+    watcher.sync(&client).await.unwrap();
+    assert_eq!(watcher.watched().keys().collect::<Vec<_>>(), [&funding]);
+    client.generate_to_address(1, &other).await.unwrap();
+    watcher.sync(&client).await.unwrap();
+    assert_eq!(
+        events.borrow().as_slice(),
+        [
+            taken.clone(),
+            WatchEvent::Reorged {
+                txid: cold.txid(),
+                height: cold_at,
+            },
+            taken,
+        ]
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn sequence_two_stage_vault_regtest() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    client.generate_to_address(101, &mine).await.unwrap();
+    // a new amount on each run, so the contract has no coin yet
+    let height = client.get_block_count().await.unwrap();
+    let compiled = TwoStage.compile(ctx("sequence", 70_000 + height)).unwrap();
+    let bound = fund(&client, &compiled).await;
+    let unvault = spends_of(&bound, OutPoint::new(bound.funding.txid(), bound.vout)).remove(0);
+    let claim = spends_of(&bound, OutPoint::new(unvault.txid(), 0)).remove(0);
+
+    let file = std::env::temp_dir().join(format!("sapio-regtest-sequencer-{}", std::process::id()));
+    let mut sequencer = Sequencer::new(
+        vec![unvault.clone(), claim.clone()],
+        file.clone(),
+        SequencerConfig::default(),
+    )
+    .unwrap();
+    let now = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    assert_eq!(
+        sequencer.step(&client, now()).await.unwrap(),
+        Step::Sent(unvault.txid())
+    );
+    client.generate_to_address(1, &mine).await.unwrap();
+    let unvaulted_at = client.get_block_count().await.unwrap() as u32;
+
+    // the node refuses the claim until the delay is mined, so it's held
+    let claimable_at = unvaulted_at + DELAY as u32 - 1;
+    let held = Step::Waiting {
+        txid: claim.txid(),
+        wait: Wait::Height(claimable_at),
+    };
+    while (client.get_block_count().await.unwrap() as u32) < claimable_at {
+        assert_eq!(sequencer.step(&client, now()).await.unwrap(), held);
+        let accepted = client.test_mempool_accept(&[&claim]).await.unwrap();
+        assert!(!accepted[0].allowed);
+        client.generate_to_address(1, &mine).await.unwrap();
+    }
+    let accepted = client.test_mempool_accept(&[&claim]).await.unwrap();
+    assert!(accepted[0].allowed, "{:?}", accepted[0].reject_reason);
+    assert_eq!(
+        sequencer.step(&client, now()).await.unwrap(),
+        Step::Sent(claim.txid())
+    );
+    assert_eq!(sequencer.step(&client, now()).await.unwrap(), Step::Done);
+    client.get_mempool_entry(&claim.txid()).await.unwrap();
+    std::fs::remove_file(file).unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn finalize_escrow_regtest() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    client.generate_to_address(101, &mine).await.unwrap();
+    // a new amount on each run, so the contract has no coin yet
+    let height = client.get_block_count().await.unwrap();
+    let compiled = Escrow.compile(ctx("finalize", 70_000 + height)).unwrap();
+    let bound = fund(&client, &compiled).await;
+    let coin = OutPoint::new(bound.funding.txid(), bound.vout);
+    let txout = bound.funding.output[bound.vout as usize].clone();
+
+    // without the emulator's signatures, which `finalize` adds
+    let psbts = compiled.export_psbts(coin, txout, &CTVAvailable).unwrap();
+    assert_eq!(psbts.len(), 1);
+    let psbt = psbts[0].1.clone();
+    let inputs = SatisfierInputs {
+        preimages: vec![PREIMAGE.to_vec()],
+        signatures: vec![(0, sign(&psbt, 2))].into_iter().collect(),
+    };
+    let tx = finalize(psbt, &LocalEmulator::for_tests(), &inputs).unwrap();
+    let accepted = client.test_mempool_accept(&[&tx]).await.unwrap();
+    assert!(accepted[0].allowed, "{:?}", accepted[0].reject_reason);
+    let txid = client.send_raw_transaction(&tx).await.unwrap();
+    client.generate_to_address(1, &mine).await.unwrap();
+    assert!(client
+        .get_tx_out(&txid, 0, Some(false))
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn export_psbts_regtest() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    client.generate_to_address(101, &mine).await.unwrap();
+    // a new amount on each run, so the contract has no coin yet
+    let height = client.get_block_count().await.unwrap();
+    let compiled = Escrow.compile(ctx("export", 70_000 + height)).unwrap();
+    let bound = fund(&client, &compiled).await;
+    let coin = OutPoint::new(bound.funding.txid(), bound.vout);
+    let txout = bound.funding.output[bound.vout as usize].clone();
+
+    // with the emulator's signatures, leaving only `key(1)`'s to add
+    let mut psbts = compiled
+        .export_psbts(coin, txout, &LocalEmulator::for_tests())
+        .unwrap();
+    assert_eq!(psbts.len(), 1);
+    let mut psbt = psbts.remove(0).1;
+    let signatures = sign(&psbt, 1);
+    psbt.inputs[0].tap_script_sigs.extend(signatures);
+    psbt.finalize_mut(&Secp256k1::new()).unwrap();
+    let tx = psbt.extract_tx();
+    let accepted = client.test_mempool_accept(&[&tx]).await.unwrap();
+    assert!(accepted[0].allowed, "{:?}", accepted[0].reject_reason);
+    let txid = client.send_raw_transaction(&tx).await.unwrap();
+    client.generate_to_address(1, &mine).await.unwrap();
+    assert!(client
+        .get_tx_out(&txid, 0, Some(false))
+        .await
+        .unwrap()
+        .is_some());
+}
+
+#[tokio::test]
+#[ignore = "needs a regtest bitcoind, see the module docs"]
+async fn weight_estimate_regtest() {
+    let client = client().await;
+    let mine = client.get_new_address(None, None).await.unwrap();
+    client.generate_to_address(101, &mine).await.unwrap();
+    // a new amount on each run, so the contract has no coin yet
+    let height = client.get_block_count().await.unwrap();
+    let compiled = Cosigned.compile(ctx("weight", 70_000 + height)).unwrap();
+    let bound = fund(&client, &compiled).await;
+    let coin = OutPoint::new(bound.funding.txid(), bound.vout);
+    let txout = bound.funding.output[bound.vout as usize].clone();
+    let template = compiled.ctv_to_tx.values().next().unwrap();
+
+    let mut psbt = compiled
+        .export_psbts(coin, txout, &LocalEmulator::for_tests())
+        .unwrap()
+        .remove(0)
+        .1;
+    let signatures = sign(&psbt, 1);
+    psbt.inputs[0].tap_script_sigs.extend(signatures);
+    psbt.finalize_mut(&Secp256k1::new()).unwrap();
+    let tx = psbt.extract_tx();
+    let accepted = client.test_mempool_accept(&[&tx]).await.unwrap();
+    assert!(accepted[0].allowed, "{:?}", accepted[0].reject_reason);
+    let txid = client.send_raw_transaction(&tx).await.unwrap();
+
+    // the estimate bounds the weight the node reports, allowing e.g. for a
+    // sighash type byte after each signature
+    let entry = client.get_mempool_entry(&txid).await.unwrap();
+    let weight = entry.weight.unwrap();
+    assert_eq!(weight, tx.weight() as u64);
+    assert!(template.estimated_weight() >= weight);
+    assert!(template.estimated_weight() - weight <= 16);
+    assert!(template.estimated_vsize() >= entry.vsize);
+    client.generate_to_address(1, &mine).await.unwrap();
+}